@@ -0,0 +1,19 @@
+#ifndef __ASSEMBLER__
+#error "This file should only be included in asm files"
+#endif // __ASSEMBLER__
+
+#ifndef EXTABLE_H_
+#define EXTABLE_H_
+
+/* clang-format off */
+
+/* Macro to record that a data abort on the instruction at \insn resumes execution at \fixup.
+ * Entries are collected in the __ex_table section and looked up by do_sync */
+.macro _asm_extable, insn, fixup
+	.pushsection __ex_table, "a"
+	.align 3
+	.quad \insn, \fixup
+	.popsection
+.endm
+
+#endif // EXTABLE_H_
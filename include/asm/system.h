@@ -5,4 +5,30 @@
 #define CPACR_EL1_FPEN0 (1 << 20)
 #define CPACR_EL1_FPEN1 (1 << 21)
 
+/* CurrentEL - the exception level is in bits [3:2] */
+#define CURRENTEL_EL2 (2 << 2)
+
+/* HCR_EL2 - Hypervisor Configuration Register: EL1 is AArch64 */
+#define HCR_EL2_RW (1 << 31)
+
+/* CNTHCTL_EL2 - EL1 may access the physical counter and timer */
+#define CNTHCTL_EL2_EL1PCTEN (1 << 0)
+#define CNTHCTL_EL2_EL1PCEN (1 << 1)
+
+/* CPTR_EL2 - RES1 bits, nothing trapped to EL2 (FP/SIMD included) */
+#define CPTR_EL2_RES1 0x33ff
+
+/* ID_AA64PFR0_EL1.GIC - GIC system register interface implemented */
+#define ID_AA64PFR0_GIC_SHIFT 24
+
+/* ICC_SRE_EL2 - system register interface for EL2, and for EL1 (Enable) */
+#define ICC_SRE_EL2_SRE (1 << 0)
+#define ICC_SRE_EL2_ENABLE (1 << 3)
+
+/* SCTLR_EL1 - RES1 bits, MMU and caches off, little-endian */
+#define SCTLR_EL1_RES1 ((3 << 28) | (3 << 22) | (1 << 20) | (1 << 11))
+
+/* SPSR_EL2 - return to EL1h with DAIF masked */
+#define SPSR_EL2_EL1H_MASKED 0x3c5
+
 #endif // SYSTEM_H_
@@ -0,0 +1,16 @@
+#ifndef BOARD_H_
+#define BOARD_H_
+
+/*
+ * Load address of the kernel image, selected by the Makefile's BOARD (keep in sync with the
+ * board modules in kernel/board/)
+ */
+#if defined(BOARD_RPI4)
+/* The firmware loads kernel8.img at 0x80000 */
+#define KERNEL_BASE 0x80000
+#else
+/* Kernel loaded at 0x50000000 by bootloader (from ELF parsing) */
+#define KERNEL_BASE 0x50000000
+#endif
+
+#endif // BOARD_H_
@@ -0,0 +1,37 @@
+#!/bin/sh
+# Prints the assembly of the kernel symbol table (see src/kernel/debug/kallsyms.rs), built from
+# the text symbols of a linked kernel
+#
+# usage: kallsyms.sh <nm> <kernel.elf> > kallsyms.S
+#
+# The table is a symbol count, the addresses in increasing order, the offset of each name, then
+# the NUL-terminated names, demangled and without their Rust hash.
+
+export LC_ALL=C
+$1 -n -C --defined-only "$2" | awk '
+BEGIN { n = 0 }
+$2 ~ /^[tTwW]$/ && $3 !~ /^(\$|\.L)/ {
+    addr[n] = $1
+    $1 = ""
+    $2 = ""
+    name = substr($0, 3)
+    sub(/::h[0-9a-f]+$/, "", name)
+    names[n++] = name
+}
+END {
+    print "\t.section .kallsyms, \"a\""
+    print "\t.balign 8"
+    print "\t.quad " n
+    for (i = 0; i < n; i++)
+        print "\t.quad 0x" addr[i]
+    offset = 0
+    for (i = 0; i < n; i++) {
+        print "\t.long " offset
+        offset += length(names[i]) + 1
+    }
+    for (i = 0; i < n; i++) {
+        gsub(/\\/, "\\\\", names[i])
+        gsub(/"/, "\\\"", names[i])
+        print "\t.asciz \"" names[i] "\""
+    }
+}'
@@ -0,0 +1,3 @@
+//! Firmware interface drivers
+
+pub mod psci;
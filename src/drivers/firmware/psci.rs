@@ -0,0 +1,226 @@
+//! Power State Coordination Interface (PSCI) client
+//!
+//! PSCI is the Arm-defined firmware interface for power management: turning CPUs on and off,
+//! resetting and powering off the system. Calls are made by placing a function ID in `x0` (and
+//! arguments in `x1`-`x3`) and issuing either an `HVC` or an `SMC`, depending on which exception
+//! level implements the firmware. The DTB `psci` node tells us which one through its `method`
+//! property.
+//!
+//! On QEMU `virt` with `virtualization=on` the firmware lives at EL3 and the conduit is `smc`.
+//!
+//! The CPU power calls (`cpu_on`, `cpu_off`, `cpu_suspend`, `affinity_info`) take the target
+//! CPU by its MPIDR affinity fields, as found in the `reg` of the DTB `cpu` nodes. `kernel::smp`
+//! builds CPU hotplug on them, and the idle task enters standby through `cpu_suspend`.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::kernel::device::{self, ProbeError};
+use crate::println;
+
+/* --- PSCI 0.2+ function IDs (SMC32/SMC64 calling convention) --- */
+const PSCI_VERSION: u32 = 0x8400_0000;
+const PSCI_CPU_SUSPEND: u32 = 0xc400_0001;
+const PSCI_CPU_OFF: u32 = 0x8400_0002;
+const PSCI_CPU_ON: u32 = 0xc400_0003;
+const PSCI_AFFINITY_INFO: u32 = 0xc400_0004;
+const PSCI_SYSTEM_OFF: u32 = 0x8400_0008;
+const PSCI_SYSTEM_RESET: u32 = 0x8400_0009;
+
+/// Errors returned by the firmware
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PsciError {
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    /// A return code the specification doesn't define
+    Unknown(i64),
+}
+
+impl PsciError {
+    /// Decodes the negative return code `ret`
+    fn from_code(ret: i64) -> Self {
+        match ret {
+            -1 => Self::NotSupported,
+            -2 => Self::InvalidParameters,
+            -3 => Self::Denied,
+            -4 => Self::AlreadyOn,
+            -5 => Self::OnPending,
+            -6 => Self::InternalFailure,
+            -7 => Self::NotPresent,
+            -8 => Self::Disabled,
+            -9 => Self::InvalidAddress,
+            _ => Self::Unknown(ret),
+        }
+    }
+}
+
+/// Returns `Ok(ret)` for the non-negative return codes
+fn check(ret: i64) -> Result<u64, PsciError> {
+    if ret < 0 {
+        Err(PsciError::from_code(ret))
+    } else {
+        Ok(ret as u64)
+    }
+}
+
+/// Power state of a CPU, as returned by `affinity_info`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AffinityState {
+    On,
+    Off,
+    /// `cpu_on` was called and the CPU isn't running yet
+    OnPending,
+}
+
+/// Instruction used to call into the firmware
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Conduit {
+    /// No PSCI node was found in the DTB
+    None = 0,
+    /// Firmware is implemented by a hypervisor at EL2
+    Hvc = 1,
+    /// Firmware is implemented by the secure monitor at EL3
+    Smc = 2,
+}
+
+/// Conduit discovered from the DTB, stored as `Conduit as u8`
+static CONDUIT: AtomicU8 = AtomicU8::new(Conduit::None as u8);
+
+/// Returns the conduit used to reach the firmware
+pub fn conduit() -> Conduit {
+    match CONDUIT.load(Ordering::Relaxed) {
+        1 => Conduit::Hvc,
+        2 => Conduit::Smc,
+        _ => Conduit::None,
+    }
+}
+
+/// Issues a PSCI call with function ID `fid` and up to three arguments
+///
+/// Returns the value left by the firmware in `x0`, or `-1` (`NOT_SUPPORTED`) if no conduit has
+/// been discovered.
+fn invoke(fid: u32, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let mut ret = fid as u64;
+    unsafe {
+        match conduit() {
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inout("x0") ret,
+                inout("x1") arg0 => _,
+                inout("x2") arg1 => _,
+                inout("x3") arg2 => _,
+                options(nostack)
+            ),
+            Conduit::Smc => asm!(
+                "smc #0",
+                inout("x0") ret,
+                inout("x1") arg0 => _,
+                inout("x2") arg1 => _,
+                inout("x3") arg2 => _,
+                options(nostack)
+            ),
+            Conduit::None => return -1,
+        }
+    }
+    ret as i64
+}
+
+/// Returns the PSCI version implemented by the firmware as `(major, minor)`
+pub fn version() -> (u16, u16) {
+    let ver = invoke(PSCI_VERSION, 0, 0, 0) as u32;
+    ((ver >> 16) as u16, ver as u16)
+}
+
+/// Returns the affinity fields of the calling CPU's `MPIDR_EL1`, as the power calls take them
+pub fn this_cpu() -> u64 {
+    let mpidr: u64;
+    unsafe {
+        asm!("mrs {}, MPIDR_EL1", out(reg) mpidr, options(nostack, nomem, preserves_flags));
+    }
+    mpidr & 0xff_00ff_ffff
+}
+
+/// Powers on the CPU `mpidr`, which starts at the physical address `entry` with the MMU off
+/// and `context` in `x0`
+pub fn cpu_on(mpidr: u64, entry: usize, context: u64) -> Result<(), PsciError> {
+    check(invoke(PSCI_CPU_ON, mpidr, entry as u64, context)).map(|_| ())
+}
+
+/// Powers off the calling CPU
+///
+/// Only returns, with the reason, if the firmware refused.
+pub fn cpu_off() -> PsciError {
+    PsciError::from_code(invoke(PSCI_CPU_OFF, 0, 0, 0))
+}
+
+/// Puts the calling CPU in the idle state `power_state` until an interrupt wakes it up
+///
+/// `power_state` is in the firmware's format, as given by the `arm,psci-suspend-param` of the
+/// DTB idle states. A standby state returns like `WFI`; after a power-down state the CPU
+/// restarts at `entry`, as for `cpu_on`, with `context` in `x0`.
+pub fn cpu_suspend(power_state: u32, entry: usize, context: u64) -> Result<(), PsciError> {
+    check(invoke(
+        PSCI_CPU_SUSPEND,
+        power_state as u64,
+        entry as u64,
+        context,
+    ))
+    .map(|_| ())
+}
+
+/// Returns the power state of the CPU `mpidr`
+pub fn affinity_info(mpidr: u64) -> Result<AffinityState, PsciError> {
+    match check(invoke(PSCI_AFFINITY_INFO, mpidr, 0, 0))? {
+        0 => Ok(AffinityState::On),
+        1 => Ok(AffinityState::Off),
+        2 => Ok(AffinityState::OnPending),
+        ret => Err(PsciError::Unknown(ret as i64)),
+    }
+}
+
+/// Asks the firmware to reset the whole system
+///
+/// Only returns if the call is not supported or no conduit is available.
+pub fn system_reset() {
+    invoke(PSCI_SYSTEM_RESET, 0, 0, 0);
+}
+
+/// Asks the firmware to power off the whole system
+///
+/// Only returns if the call is not supported or no conduit is available.
+pub fn system_off() {
+    invoke(PSCI_SYSTEM_OFF, 0, 0, 0);
+}
+
+/// Driver for `arm,psci-0.2` nodes
+pub struct PsciDriver;
+
+impl device::Driver for PsciDriver {
+    /// Sets up the PSCI client from device tree properties
+    ///
+    /// Reads the `method` property (`"hvc"` or `"smc"`) to select the conduit used for every
+    /// subsequent firmware call.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let Some(method_prop) = dev.find_property("method") else {
+            return Err(ProbeError::NoDevice);
+        };
+        let method = unsafe { core::slice::from_raw_parts(method_prop.value, method_prop.len) };
+        let conduit = match method {
+            b"hvc\0" => Conduit::Hvc,
+            b"smc\0" => Conduit::Smc,
+            _ => Conduit::None,
+        };
+        CONDUIT.store(conduit as u8, Ordering::Relaxed);
+        let (major, minor) = version();
+        println!("psci: v{}.{} via {:?}", major, minor, conduit);
+        Ok(())
+    }
+}
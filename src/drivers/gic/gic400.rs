@@ -0,0 +1,261 @@
+//! GIC-400 (GICv2) interrupt controller driver
+//!
+//! The GIC-400 of the Raspberry Pi 4 and of many Cortex-A72 boards implements the GICv2
+//! architecture: like the GICv3, a distributor (GICD) takes the SPIs, but the banked first 32
+//! interrupts of each CPU are also reached through it, and the CPU interface (GICC) is a second
+//! MMIO region instead of system registers. The `reg` property lists the distributor first, then
+//! the CPU interface.
+//!
+//! ## Design
+//!
+//! - The kernel runs in the non-secure world, where `GICD_IGROUPR` is not accessible: the
+//!   firmware puts every interrupt in Group 1, and the CPU interface only enables that group.
+//! - Without affinity routing, an SPI is sent to the CPUs set in its `GICD_ITARGETSR` byte. The
+//!   probe reads the mask of the boot CPU from the banked byte of interrupt 0 and routes every
+//!   configured SPI there. `init_cpu` reads that of every other CPU as it starts.
+//! - An SGI is raised on another CPU by writing its mask and the INTID to `GICD_SGIR`
+//!   (`send_ipi`). The SGIs are banked too; `init_cpu` gives them the highest priority.
+//! - As with the GICv3, the CPU interface runs with `EOImodeNS` set: `GICC_EOIR` drops the
+//!   running priority and `GICC_DIR` deactivates the interrupt.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `irq-gic.c` handles the GIC-400 along with older GICv2 implementations, banked
+//! registers, SGIs for IPIs and the bypass of the CPU interface, and likewise learns each CPU's
+//! mask from `GICD_ITARGETSR` (`gic_get_cpumask`). Only the interrupts drivers request are
+//! handled here, and SPIs all go to the boot CPU.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::ipc::init_cell::InitCell;
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::irq;
+use crate::kernel::irq::of::IrqSpec;
+use crate::kernel::smp::{self, CpuId, MAX_CPUS};
+use crate::utilities::mmio;
+
+use super::IrqChip;
+
+/* --- GICD (Distributor) Constants --- */
+/// Number of SGIs, INTIDs 0 to 15
+const NR_SGIS: u32 = 16;
+/// First SPI INTID, those below being SGIs and PPIs
+const FIRST_SPI: u32 = 32;
+/// Distributor Control Register
+const GICD_CTLR: usize = 0x000;
+/// Enable forwarding of interrupts to the CPU interfaces
+const GICD_CTLR_ENABLE: u32 = 1 << 0;
+/// Interrupt Set-Enable Registers
+const GICD_ISENABLER: usize = 0x100;
+/// Interrupt Priority Registers, one byte per interrupt
+const GICD_IPRIORITYR: usize = 0x400;
+/// Interrupt Processor Targets Registers, one byte per interrupt
+const GICD_ITARGETSR: usize = 0x800;
+/// Interrupt Configuration Registers, two bits per interrupt
+const GICD_ICFGR: usize = 0xC00;
+/// Software Generated Interrupt Register
+const GICD_SGIR: usize = 0xF00;
+/// CPU target list of `GICD_SGIR`
+const GICD_SGIR_TARGET_SHIFT: u32 = 16;
+
+/* --- GICC (CPU interface) Constants --- */
+/// CPU Interface Control Register
+const GICC_CTLR: usize = 0x0000;
+/// Enable signaling of Group 1 interrupts
+const GICC_CTLR_ENABLE: u32 = 1 << 0;
+/// EOImodeNS: EOIR only drops priority, DIR deactivates
+const GICC_CTLR_EOIMODE: u32 = 1 << 9;
+/// Interrupt Priority Mask Register
+const GICC_PMR: usize = 0x0004;
+/// Interrupt Acknowledge Register
+const GICC_IAR: usize = 0x000C;
+/// End of Interrupt Register
+const GICC_EOIR: usize = 0x0010;
+/// Deactivate Interrupt Register
+const GICC_DIR: usize = 0x1000;
+/// INTID field of `GICC_IAR`, the bits above identifying the CPU that sent an SGI
+const GICC_IAR_INTID: u32 = 0x3ff;
+/// First special INTID; 1020-1023 never identify a real interrupt
+const FIRST_SPECIAL_INTID: u32 = 1020;
+
+/// The GIC-400 instance, set by the driver's probe
+static GIC: InitCell<Gic400> = InitCell::new();
+
+/// GIC-400 state
+struct Gic400 {
+    /// Base address of the distributor registers
+    dist_addr: usize,
+    /// Base address of the CPU interface registers
+    cpu_addr: usize,
+    /// `GICD_ITARGETSR` byte selecting the boot CPU
+    target: u8,
+    /// `GICD_ITARGETSR` byte selecting each CPU, 0 until it ran `init_cpu`
+    targets: [AtomicU8; MAX_CPUS],
+}
+
+impl Gic400 {
+    /// Writes `value` to the byte of interrupt `id` in the byte-per-interrupt bank at `bank`
+    fn write_byte(&self, bank: usize, id: u32, value: u8) {
+        let reg = self.dist_addr + bank + (id as usize & !3);
+        let shift = (id % 4) * 8;
+        let val = mmio::read_mmio32(reg, 0) & !(0xff << shift);
+        mmio::write_mmio32(reg, 0, val | (value as u32) << shift);
+    }
+
+    /// Sets the trigger mode of interrupt `id`
+    ///
+    /// SGIs have a fixed configuration, and PPIs may have one too, the write being ignored.
+    fn set_trigger(&self, id: u32, edge: bool) {
+        let reg = self.dist_addr + GICD_ICFGR + (id as usize / 16) * 4;
+        let bit = 1 << ((id % 16) * 2 + 1);
+        let val = mmio::read_mmio32(reg, 0);
+        let val = if edge { val | bit } else { val & !bit };
+        mmio::write_mmio32(reg, 0, val);
+    }
+
+    /// Configures interrupt `id`: trigger mode, highest priority and, for an SPI, the boot CPU
+    fn configure(&self, id: u32, edge: bool) {
+        self.set_trigger(id, edge);
+        self.write_byte(GICD_IPRIORITYR, id, 0x00);
+        if id >= FIRST_SPI {
+            self.write_byte(GICD_ITARGETSR, id, self.target);
+        }
+        unsafe { asm!("dsb sy", options(nostack)) };
+    }
+
+    /// Enables forwarding of interrupt `id` to the CPU interface
+    fn enable(&self, id: u32) {
+        let reg = self.dist_addr + GICD_ISENABLER + (id as usize / 32) * 4;
+        mmio::write_mmio32(reg, 0, 1 << (id % 32));
+        unsafe { asm!("dsb sy", options(nostack)) };
+    }
+
+    /// Enables the distributor, then the boot CPU's interface
+    fn init(&self) {
+        mmio::set_mmio_bits32(self.dist_addr, GICD_CTLR, GICD_CTLR_ENABLE);
+        self.init_cpu();
+    }
+
+    /// Enables the calling CPU's interface, banked, with every priority unmasked
+    ///
+    /// Also notes the CPU's target mask, for `send_ipi`, and gives its SGIs the highest
+    /// priority.
+    fn init_cpu(&self) {
+        // Each CPU reads its own bit in the banked targets of the SGIs and PPIs
+        let target = mmio::read_mmio32(self.dist_addr, GICD_ITARGETSR) as u8;
+        self.targets[smp::this_cpu()].store(target, Ordering::Relaxed);
+        for id in 0..NR_SGIS {
+            self.write_byte(GICD_IPRIORITYR, id, 0x00);
+        }
+        mmio::write_mmio32(self.cpu_addr, GICC_PMR, 0xff);
+        mmio::write_mmio32(
+            self.cpu_addr,
+            GICC_CTLR,
+            GICC_CTLR_ENABLE | GICC_CTLR_EOIMODE,
+        );
+        unsafe { asm!("dsb sy", "isb", options(nostack)) };
+    }
+
+    /// Disables the calling CPU's interface
+    fn disable_cpu(&self) {
+        mmio::write_mmio32(self.cpu_addr, GICC_CTLR, 0);
+        unsafe { asm!("dsb sy", "isb", options(nostack)) };
+    }
+
+    /// Raises SGI `id` on `cpu`, once that has run `init_cpu`
+    fn send_ipi(&self, cpu: CpuId, id: u32) {
+        let target = self.targets[cpu].load(Ordering::Relaxed);
+        if target == 0 {
+            return;
+        }
+        // The caller's stores reach the target before the SGI
+        unsafe { asm!("dsb ishst", options(nostack)) };
+        mmio::write_mmio32(
+            self.dist_addr,
+            GICD_SGIR,
+            (target as u32) << GICD_SGIR_TARGET_SHIFT | (id & 0xf),
+        );
+    }
+
+    /// Handles every pending interrupt
+    ///
+    /// The full `GICC_IAR` value is written back to `GICC_EOIR` and `GICC_DIR`, as the
+    /// architecture requires.
+    fn handle_irq(&self) {
+        let mut handled = false;
+        loop {
+            let iar = mmio::read_mmio32(self.cpu_addr, GICC_IAR);
+            let id = iar & GICC_IAR_INTID;
+            if id >= FIRST_SPECIAL_INTID {
+                if !handled {
+                    irq::note_spurious();
+                }
+                break;
+            }
+            irq::dispatch(id);
+            mmio::write_mmio32(self.cpu_addr, GICC_EOIR, iar);
+            mmio::write_mmio32(self.cpu_addr, GICC_DIR, iar);
+            handled = true;
+        }
+    }
+}
+
+/// Returns the GIC instance, set before it is registered as the interrupt controller
+fn gic() -> &'static Gic400 {
+    GIC.get().expect("gic400: used before probe")
+}
+
+/// Driver for `arm,gic-400` nodes
+pub struct Gic400Driver;
+
+impl device::Driver for Gic400Driver {
+    /// Sets up the GIC-400 from the distributor and CPU interface regions of `reg`
+    ///
+    /// Fails if a GIC was already probed, a single one being supported.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let (Some(gicd), Some(gicc)) = (dev.reg(0), dev.reg(1)) else {
+            return Err(ProbeError::NoDevice);
+        };
+        // Each CPU reads its own bit in the banked targets of the SGIs and PPIs
+        let target = mmio::read_mmio32(gicd.base, GICD_ITARGETSR) as u8;
+        let gic = GIC
+            .set(Gic400 {
+                dist_addr: gicd.base,
+                cpu_addr: gicc.base,
+                target,
+                targets: [const { AtomicU8::new(0) }; MAX_CPUS],
+            })
+            .map_err(|_| ProbeError::NotSupported)?;
+        gic.init();
+        super::register(&Gic400Driver)
+    }
+}
+
+impl IrqChip for Gic400Driver {
+    fn configure(&self, spec: &IrqSpec) -> u32 {
+        let id = spec.intid();
+        gic().configure(id, spec.trigger.is_edge());
+        id
+    }
+
+    fn enable(&self, id: u32) {
+        gic().enable(id);
+    }
+
+    fn handle_irq(&self) {
+        gic().handle_irq();
+    }
+
+    fn init_cpu(&self) {
+        gic().init_cpu();
+    }
+
+    fn disable_cpu(&self) {
+        gic().disable_cpu();
+    }
+
+    fn send_ipi(&self, cpu: CpuId, id: u32) {
+        gic().send_ipi(cpu, id);
+    }
+}
@@ -5,25 +5,85 @@
 //! Redistributor (GICR) for Private Peripheral Interrupts (PPIs) and Software Generated
 //! Interrupts (SGIs).
 //!
-//! The driver uses a global `Gicv3` instance accessed through public wrapper functions.
+//! The driver keeps its `Gicv3` instance in an `InitCell`, set once by the probe and accessed
+//! through public wrapper functions.
 //! Base addresses are discovered from the device tree during boot.
+//!
+//! ## Redistributors
+//!
+//! Every CPU has its own redistributor, a frame of the redistributor region whose `GICR_TYPER`
+//! holds the CPU's affinity. `init_cpu` finds the calling CPU's frame, wakes it up and opens the
+//! CPU interface: the probe runs it for the boot CPU, `smp` for the others as they start. The PPI
+//! and SGI functions act on the calling CPU's redistributor. `disable_cpu` closes the interface
+//! and puts the redistributor back to sleep before the CPU is powered off.
+//!
+//! ## SGIs
+//!
+//! `init_cpu` puts the 16 SGIs in Group 1 at the highest priority. `send_ipi` raises one on
+//! another CPU by writing its affinity to `ICC_SGI1R_EL1`: Aff3.Aff2.Aff1 select a cluster, the
+//! range selector a group of 16 Aff0 values and the target list bit the CPU in it.
+//!
+//! ## Interrupt Handling
+//!
+//! `handle_irq` is called from the IRQ vector. It acknowledges interrupts through
+//! `ICC_IAR1_EL1` until the CPU interface reports a special INTID (1020-1023), dispatching each
+//! one to `kernel::irq`. The CPU interface runs with `ICC_CTLR_EL1.EOImode = 1`: writing
+//! `ICC_EOIR1_EL1` only drops the running priority, and the interrupt is deactivated separately
+//! through `ICC_DIR_EL1`. Keeping both steps apart is what allows a handler to drop priority and
+//! let higher-priority interrupts nest before its own interrupt is deactivated.
+//!
+//! ## LPIs
+//!
+//! Locality-specific peripheral interrupts (INTIDs from 8192) are message-based: the ITS (see
+//! `its`) turns writes from devices into LPIs, which the redistributor looks up in two tables in
+//! memory, their configuration (priority and enable bit) and their pending bits. `enable_lpis`
+//! gives the redistributor these tables. LPIs have no active state, so they aren't deactivated.
 
 use core::arch::asm;
-use core::ptr::addr_of_mut;
 
-use crate::kernel::device;
-use crate::utilities::convert;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::drivers::firmware::psci;
+use crate::ipc::init_cell::InitCell;
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::irq;
+use crate::kernel::irq::of::{IrqKind, IrqSpec};
+use crate::kernel::smp::{self, CpuId, MAX_CPUS};
+use crate::pr_err;
 use crate::utilities::mmio;
 
-/// Maximum number of cells in a GIC interrupt specifier
-///
-/// The GICv3 binding requires `#interrupt-cells` to be at least 4
-/// (see `Documentation/devicetree/bindings/interrupt-controller/arm,gic-v3.yaml`).
-pub const MAX_INTERRUPT_CELLS: usize = 4;
+use super::{IrqChip, its};
+
+/* --- ICC (CPU interface) Constants --- */
+/// First special INTID; 1020-1023 never identify a real interrupt
+pub const FIRST_SPECIAL_INTID: u32 = 1020;
+/// INTID returned by `ICC_IAR1_EL1` when no interrupt is pending
+pub const SPURIOUS_INTID: u32 = 1023;
+/// ICC_CTLR_EL1.EOImode: EOIR only drops priority, DIR deactivates
+const ICC_CTLR_EOIMODE: u64 = 1 << 1;
+/// ICC_SGI1R_EL1 fields
+const ICC_SGI1R_AFF1_SHIFT: u64 = 16;
+const ICC_SGI1R_INTID_SHIFT: u64 = 24;
+const ICC_SGI1R_AFF2_SHIFT: u64 = 32;
+const ICC_SGI1R_RS_SHIFT: u64 = 44;
+const ICC_SGI1R_AFF3_SHIFT: u64 = 48;
 
 /* --- GICD (Distributor) Constants --- */
+/// Number of SGIs, INTIDs 0 to 15
+const NR_SGIS: u32 = 16;
+/// First SPI INTID, those below being SGIs and PPIs
+const FIRST_SPI: u32 = 32;
+/// First LPI INTID
+pub const FIRST_LPI: u32 = 8192;
 /// Distributor Control Register
 const GICD_CTLR: usize = 0x000;
+/// Interrupt Controller Type Register
+const GICD_TYPER: usize = 0x004;
+/// LPIs supported bit
+const GICD_TYPER_LPIS: u32 = 1 << 17;
+/// Number of INTID bits supported, minus one
+const GICD_TYPER_IDBITS_SHIFT: u32 = 19;
+const GICD_TYPER_IDBITS_MASK: u32 = 0x1f;
 /// Enable non secure Group 1 interrupts bit
 const GICD_CTLR_GRP1NS: u32 = 0b10;
 /// Enable secure Group 1 interrupts bit
@@ -42,8 +102,35 @@ const GICD_IGROUPR: usize = 0x080;
 /* --- GICR (Redistributor) Constants --- */
 /// SGI Frame offset
 const GICR_SGI_BASE: usize = 0x10000; // Offset from RD_base to SGI & PPI frame
+/// Redistributor Control Register
+const GICR_CTLR: usize = 0x0000;
+/// Enable LPIs bit, which can't be cleared on every implementation once set
+const GICR_CTLR_ENABLE_LPIS: u32 = 1 << 0;
+/// Redistributor Type Register (64 bits)
+const GICR_TYPER: usize = 0x0008;
+/// The frame has the virtual LPI frames after it (GICv4)
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+/// Last frame of the region
+const GICR_TYPER_LAST: u64 = 1 << 4;
+/// Affinity of the CPU the frame belongs to, Aff3.Aff2.Aff1.Aff0
+const GICR_TYPER_AFFINITY_SHIFT: u64 = 32;
+/// Size of a redistributor's frames: RD_base then SGI_base
+const GICR_FRAME_SIZE: usize = 2 * GICR_SGI_BASE;
+/// Size of a GICv4 redistributor's frames, with VLPI_base and a reserved frame
+const GICR_FRAME_SIZE_VLPIS: usize = 4 * GICR_SGI_BASE;
+/// Processor number of the PE, as ITS commands name it
+const GICR_TYPER_PROCESSOR_SHIFT: u64 = 8;
+const GICR_TYPER_PROCESSOR_MASK: u64 = 0xffff;
 /// Redistributor Wake Register
 const GICR_WAKER: usize = 0x0014;
+/// LPI Configuration Table base address and INTID bits (64 bits)
+const GICR_PROPBASER: usize = 0x0070;
+/// LPI Pending Table base address (64 bits)
+const GICR_PENDBASER: usize = 0x0078;
+/// Inner cacheability of the LPI tables (bits 9:7): non-cacheable, as `dma` maps them
+const GICR_BASER_INNER_NC: u64 = 0b001 << 7;
+/// The pending table is all zeroes (PENDBASER.PTZ)
+const GICR_PENDBASER_PTZ: u64 = 1 << 62;
 /// Processor sleep bit. Indicates whether the Redistributor can assert the **WakeRequest**
 /// signal
 const GICR_WAKER_PSLEEP: u32 = 0b10;
@@ -58,29 +145,66 @@ const GICR_ISENABLER0: usize = 0x100;
 /// Interrupt Configuration Register
 const GICR_ICFGR: usize = 0xC00;
 
-/// Global GICv3 instance holding the distributor and redistributor base addresses
-static mut GIC: Gicv3 = Gicv3::new();
+/// The GICv3 instance, set by the driver's probe
+static GIC: InitCell<Gicv3> = InitCell::new();
 
 /// GICv3 interrupt controller state
 ///
 /// Holds the MMIO base addresses for the GIC Distributor (GICD) and Redistributor (GICR)
-/// regions. These are populated during device tree parsing and used by all GIC operations.
+/// regions. These come from the device tree and are used by all GIC operations.
 struct Gicv3 {
     /// Base address of the GIC Distributor (GICD) registers
     dist_addr: usize,
-    /// Base address of the GIC Redistributor (GICR) registers
-    redist_addr: usize,
+    /// Base address and size of the GIC Redistributor (GICR) region
+    redist_base: usize,
+    redist_size: usize,
+    /// Redistributor (`RD_base`) of each CPU, 0 until `init_cpu` found it
+    redist: [AtomicUsize; MAX_CPUS],
 }
 
 impl Gicv3 {
-    /// Const constructor for static initialization with zeroed addresses
-    pub const fn new() -> Self {
+    /// Creates an instance for the given distributor and redistributor region
+    pub const fn new(dist_addr: usize, redist_base: usize, redist_size: usize) -> Self {
         Self {
-            dist_addr: 0,
-            redist_addr: 0,
+            dist_addr,
+            redist_base,
+            redist_size,
+            redist: [const { AtomicUsize::new(0) }; MAX_CPUS],
         }
     }
 
+    /// Returns the redistributor of `cpu`
+    fn rd(&self, cpu: CpuId) -> usize {
+        self.redist[cpu].load(Ordering::Relaxed)
+    }
+
+    /// Returns the calling CPU's redistributor
+    fn this_rd(&self) -> usize {
+        self.rd(smp::this_cpu())
+    }
+
+    /// Returns the redistributor of the CPU `mpidr`, walking the frames of the region
+    fn find_redistributor(&self, mpidr: u64) -> Option<usize> {
+        let affinity = (mpidr >> 32 & 0xff) << 24 | mpidr & 0xff_ffff;
+        let end = self.redist_base + self.redist_size;
+        let mut frame = self.redist_base;
+        while frame + GICR_FRAME_SIZE <= end {
+            let typer = unsafe { core::ptr::read_volatile((frame + GICR_TYPER) as *const u64) };
+            if typer >> GICR_TYPER_AFFINITY_SHIFT == affinity {
+                return Some(frame);
+            }
+            if typer & GICR_TYPER_LAST != 0 {
+                break;
+            }
+            frame += if typer & GICR_TYPER_VLPIS != 0 {
+                GICR_FRAME_SIZE_VLPIS
+            } else {
+                GICR_FRAME_SIZE
+            };
+        }
+        None
+    }
+
     /// Initializes the GIC Distributor
     pub fn init_gic_distributor(&self) {
         unsafe {
@@ -92,21 +216,64 @@ impl Gicv3 {
             asm!("dsb sy", options(nostack));
         }
     }
-    /// Initializes the GIC Redistributor
+    /// Initializes the calling CPU's GIC Redistributor
     pub fn init_gic_redistributor(&self) {
+        let rd = self.this_rd();
         unsafe {
-            mmio::clear_mmio_bits32(self.redist_addr, GICR_WAKER, GICR_WAKER_PSLEEP);
+            mmio::clear_mmio_bits32(rd, GICR_WAKER, GICR_WAKER_PSLEEP);
             asm!("dsb sy", options(nostack));
-            while (mmio::read_mmio32(self.redist_addr, GICR_WAKER) & GICR_WAKER_CASLEEP) != 0 {}
+            while (mmio::read_mmio32(rd, GICR_WAKER) & GICR_WAKER_CASLEEP) != 0 {}
         }
     }
 
+    /// Puts the calling CPU's GIC Redistributor to sleep, once it has nothing to forward
+    pub fn sleep_gic_redistributor(&self) {
+        let rd = self.this_rd();
+        unsafe {
+            mmio::set_mmio_bits32(rd, GICR_WAKER, GICR_WAKER_PSLEEP);
+            asm!("dsb sy", options(nostack));
+            while (mmio::read_mmio32(rd, GICR_WAKER) & GICR_WAKER_CASLEEP) == 0 {}
+        }
+    }
+
+    /// Finds and wakes up the calling CPU's redistributor, then opens its CPU interface
+    ///
+    /// The SGIs are put in Group 1 at the highest priority, left disabled. The interface accepts
+    /// every priority, in split EOI mode, with Group 1 interrupts enabled. The boot CPU falls
+    /// back to the first frame if none has its affinity.
+    fn init_cpu(&self) {
+        let cpu = smp::this_cpu();
+        let rd = match self.find_redistributor(psci::this_cpu()) {
+            Some(rd) => rd,
+            None if cpu == 0 => self.redist_base,
+            None => {
+                pr_err!("gicv3: no redistributor for CPU {}", cpu);
+                return;
+            }
+        };
+        self.redist[cpu].store(rd, Ordering::Relaxed);
+        self.init_gic_redistributor();
+        for id in 0..NR_SGIS {
+            self.set_ppi_priority(id, 0x00);
+            self.set_ppi_group(id);
+        }
+        set_priority_mask(0xff);
+        enable_split_eoi();
+        enable_grp1_ints();
+    }
+
+    /// Closes the calling CPU's interface and puts its redistributor to sleep
+    fn disable_cpu(&self) {
+        disable_grp1_ints();
+        self.sleep_gic_redistributor();
+    }
+
     /// Sets the priority for the given PPI/SGI
     ///
     /// Sets the priority `prio` to the given PPI/SGI `id`
     pub fn set_ppi_priority(&self, id: u32, prio: u8) {
         unsafe {
-            let sgi_base = self.redist_addr + GICR_SGI_BASE;
+            let sgi_base = self.this_rd() + GICR_SGI_BASE;
             let reg_index = id / 4;
             let reg_offset = (reg_index * 4) as usize;
             let byte_index_in_reg = id % 4;
@@ -126,7 +293,7 @@ impl Gicv3 {
     /// Assigns the PPI/SGI `id` to Group 1
     pub fn set_ppi_group(&self, id: u32) {
         unsafe {
-            mmio::set_mmio_bits32(self.redist_addr + GICR_SGI_BASE, GICR_IGROUPR0, 1 << id);
+            mmio::set_mmio_bits32(self.this_rd() + GICR_SGI_BASE, GICR_IGROUPR0, 1 << id);
             asm!("dsb sy", options(nostack));
         }
     }
@@ -136,7 +303,7 @@ impl Gicv3 {
     /// Enables the PPI/SGI with the given `id`
     pub fn enable_ppi(&self, id: u32) {
         unsafe {
-            mmio::set_mmio_bits32(self.redist_addr + GICR_SGI_BASE, GICR_ISENABLER0, 1 << id);
+            mmio::set_mmio_bits32(self.this_rd() + GICR_SGI_BASE, GICR_ISENABLER0, 1 << id);
             asm!("dsb sy", options(nostack));
         }
     }
@@ -246,7 +413,7 @@ impl Gicv3 {
             let reg_index = id / 16;
             let reg_offset = (reg_index * 4) as usize;
             let bit_shift = (id % 16) * 2;
-            let sgi_base = self.redist_addr + GICR_SGI_BASE;
+            let sgi_base = self.this_rd() + GICR_SGI_BASE;
             let cfg_reg_addr = sgi_base + GICR_ICFGR + reg_offset;
             let mut reg_val = mmio::read_mmio32(cfg_reg_addr, 0);
             let mask: u32 = !(0b11 << bit_shift);
@@ -265,7 +432,7 @@ impl Gicv3 {
             let reg_index = id / 16;
             let reg_offset = (reg_index * 4) as usize;
             let bit_shift = (id % 16) * 2;
-            let sgi_base = self.redist_addr + GICR_SGI_BASE;
+            let sgi_base = self.this_rd() + GICR_SGI_BASE;
             let cfg_reg_addr = sgi_base + GICR_ICFGR + reg_offset;
             let mut reg_val = mmio::read_mmio32(cfg_reg_addr, 0);
             let mask: u32 = !(0b11 << bit_shift);
@@ -278,98 +445,174 @@ impl Gicv3 {
     }
 }
 
-/// Initializes the GIC with the given distributor and redistributor addresses
+/// Errors returned by `enable_lpis`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LpiError {
+    /// The GIC hasn't been probed
+    NoGic,
+    /// The distributor doesn't support LPIs, or not that many INTID bits
+    NotSupported,
+    /// LPIs are enabled already, so their tables can't be changed
+    AlreadyEnabled,
+}
+
+/// Gives the redistributor its LPI tables and enables LPIs
 ///
-/// Stores the base addresses and initializes both the distributor (enables Group 1
-/// interrupts and affinity routing) and redistributor (wakes the PE from sleep).
-fn init_gic(dist_addr: usize, redist_addr: usize) {
+/// `config` is the physical address of the configuration table, one byte per LPI up to INTID
+/// `(1 << id_bits) - 1`; `pending` that of the pending table, one bit per INTID, 64 KiB aligned
+/// and zeroed.
+pub fn enable_lpis(config: usize, id_bits: u32, pending: usize) -> Result<(), LpiError> {
+    let gic = GIC.get().ok_or(LpiError::NoGic)?;
+    let rd = gic.rd(0);
+    let typer = mmio::read_mmio32(gic.dist_addr, GICD_TYPER);
+    let max_bits = ((typer >> GICD_TYPER_IDBITS_SHIFT) & GICD_TYPER_IDBITS_MASK) + 1;
+    if typer & GICD_TYPER_LPIS == 0 || id_bits > max_bits {
+        return Err(LpiError::NotSupported);
+    }
+    if mmio::read_mmio32(rd, GICR_CTLR) & GICR_CTLR_ENABLE_LPIS != 0 {
+        return Err(LpiError::AlreadyEnabled);
+    }
     unsafe {
-        (*addr_of_mut!(GIC)).dist_addr = dist_addr;
-        (*addr_of_mut!(GIC)).redist_addr = redist_addr;
-        (*addr_of_mut!(GIC)).init_gic_distributor();
-        (*addr_of_mut!(GIC)).init_gic_redistributor();
+        let propbaser = (rd + GICR_PROPBASER) as *mut u64;
+        let pendbaser = (rd + GICR_PENDBASER) as *mut u64;
+        core::ptr::write_volatile(
+            propbaser,
+            config as u64 | GICR_BASER_INNER_NC | (id_bits - 1) as u64,
+        );
+        core::ptr::write_volatile(
+            pendbaser,
+            pending as u64 | GICR_BASER_INNER_NC | GICR_PENDBASER_PTZ,
+        );
+        asm!("dsb sy", options(nostack));
     }
+    mmio::set_mmio_bits32(rd, GICR_CTLR, GICR_CTLR_ENABLE_LPIS);
+    unsafe { asm!("dsb sy", options(nostack)) };
+    Ok(())
+}
+
+/// Returns how ITS commands name the boot CPU's redistributor
+///
+/// With `physical` set (GITS_TYPER.PTA) that is its physical address, in units of 64 KiB,
+/// otherwise its processor number.
+pub fn redistributor_target(physical: bool) -> Option<u64> {
+    let rd = GIC.get()?.rd(0);
+    if physical {
+        return Some(rd as u64 >> 16);
+    }
+    let typer = unsafe { core::ptr::read_volatile((rd + GICR_TYPER) as *const u64) };
+    Some((typer >> GICR_TYPER_PROCESSOR_SHIFT) & GICR_TYPER_PROCESSOR_MASK)
+}
+
+/// Initializes the GIC with the given distributor address and redistributor region
+///
+/// Stores the base addresses and initializes both the distributor (enables Group 1
+/// interrupts and affinity routing) and the boot CPU's redistributor and CPU interface (see
+/// `init_cpu`). Fails if a GIC was already initialized, a single one being supported.
+fn init_gic(dist_addr: usize, redist_base: usize, redist_size: usize) -> Result<(), ProbeError> {
+    let gic = GIC
+        .set(Gicv3::new(dist_addr, redist_base, redist_size))
+        .map_err(|_| ProbeError::NotSupported)?;
+    gic.init_gic_distributor();
+    gic.init_cpu();
+    Ok(())
+}
+
+/// Returns the GIC instance
+///
+/// Drivers configuring interrupts are deferred until the GIC is bound (see `device`), so it is
+/// always set by the time these wrappers run.
+fn gic() -> &'static Gicv3 {
+    GIC.get().expect("gicv3: used before probe")
+}
+
+/// Configures the interrupt described by `spec` and returns its INTID
+///
+/// Sets the trigger type, the highest priority and Group 1, and routes an SPI to core 0. The
+/// interrupt is left disabled; `enable_spi` or `enable_ppi` forwards it once a handler is
+/// registered.
+pub fn configure_irq(spec: &IrqSpec) -> u32 {
+    let id = spec.intid();
+    match spec.kind {
+        IrqKind::Spi => {
+            if spec.trigger.is_edge() {
+                set_spi_trigger_edge(id);
+            } else {
+                set_spi_trigger_level(id);
+            }
+            set_spi_priority(id, 0x00);
+            set_spi_group(id);
+            set_spi_routing(id, 0);
+        }
+        IrqKind::Ppi => {
+            if spec.trigger.is_edge() {
+                set_ppi_trigger_edge(id);
+            } else {
+                set_ppi_trigger_level(id);
+            }
+            set_ppi_priority(id, 0x00);
+            set_ppi_group(id);
+        }
+    }
+    id
 }
 
 // Public wrapper functions for SPI (distributor) access
 
 /// Enables forwarding of the SPI `id` in the GIC distributor
 pub fn enable_spi(id: u32) {
-    unsafe {
-        (*addr_of_mut!(GIC)).enable_spi(id);
-    }
+    gic().enable_spi(id);
 }
 
 /// Sets the priority of SPI `id` in the distributor
 pub fn set_spi_priority(id: u32, prio: u8) {
-    unsafe {
-        (*addr_of_mut!(GIC)).set_spi_priority(id, prio);
-    }
+    gic().set_spi_priority(id, prio);
 }
 
 /// Sets level-sensitive trigger mode for SPI `id`
 pub fn set_spi_trigger_level(id: u32) {
-    unsafe {
-        (*addr_of_mut!(GIC)).set_spi_trigger_level(id);
-    }
+    gic().set_spi_trigger_level(id);
 }
 
 /// Sets edge-triggered mode for SPI `id`
 pub fn set_spi_trigger_edge(id: u32) {
-    unsafe {
-        (*addr_of_mut!(GIC)).set_spi_trigger_edge(id);
-    }
+    gic().set_spi_trigger_edge(id);
 }
 
 /// Assigns SPI `id` to Group 1
 pub fn set_spi_group(id: u32) {
-    unsafe {
-        (*addr_of_mut!(GIC)).set_spi_group(id);
-    }
+    gic().set_spi_group(id);
 }
 
 /// Sets the affinity routing for SPI `id`
 pub fn set_spi_routing(id: u32, core_affinity: u64) {
-    unsafe {
-        (*addr_of_mut!(GIC)).set_spi_routing(id, core_affinity);
-    }
+    gic().set_spi_routing(id, core_affinity);
 }
 
 // Public wrapper functions for PPI/SGI (redistributor)
 
 /// Sets the priority of PPI/SGI `id` in the redistributor
 pub fn set_ppi_priority(id: u32, prio: u8) {
-    unsafe {
-        (*addr_of_mut!(GIC)).set_ppi_priority(id, prio);
-    }
+    gic().set_ppi_priority(id, prio);
 }
 
 /// Assigns PPI/SGI `id` to Group 1 in the redistributor
 pub fn set_ppi_group(id: u32) {
-    unsafe {
-        (*addr_of_mut!(GIC)).set_ppi_group(id);
-    }
+    gic().set_ppi_group(id);
 }
 
 /// Enables PPI/SGI `id` in the redistributor
 pub fn enable_ppi(id: u32) {
-    unsafe {
-        (*addr_of_mut!(GIC)).enable_ppi(id);
-    }
+    gic().enable_ppi(id);
 }
 
 /// Sets level-sensitive trigger mode for PPI `id`
 pub fn set_ppi_trigger_level(id: u32) {
-    unsafe {
-        (*addr_of_mut!(GIC)).set_ppi_trigger_level(id);
-    }
+    gic().set_ppi_trigger_level(id);
 }
 
 /// Sets edge-triggered mode for PPI `id`
 pub fn set_ppi_trigger_edge(id: u32) {
-    unsafe {
-        (*addr_of_mut!(GIC)).set_ppi_trigger_edge(id);
-    }
+    gic().set_ppi_trigger_edge(id);
 }
 
 /// Sets an interrupt mask
@@ -397,31 +640,157 @@ pub fn enable_grp1_ints() {
     }
 }
 
-/// Sets up the GICv3 from device tree properties
+/// Disable the Group 1 interrupts
+#[inline(always)]
+pub fn disable_grp1_ints() {
+    unsafe {
+        asm!(
+            "msr ICC_IGRPEN1_EL1, xzr",
+            "isb sy",
+            options(nostack, nomem, preserves_flags)
+        );
+    }
+}
+
+/// Acknowledges the highest priority pending Group 1 interrupt and returns its INTID
 ///
-/// Parses the `reg` property to extract the distributor (GICD) and redistributor (GICR)
-/// base addresses, initializes the GIC hardware, sets the CPU interface priority mask
-/// to accept all priorities, and enables Group 1 interrupts.
-pub fn setup(dev: &device::PlatformDevice) {
-    let mut gicd_addr: usize = 0;
-    let mut gicr_addr: usize = 0;
-    // Get #address-cells and #size_cells
-    let (addr_cells, size_cells) = dev.get_parent_cells();
-    if let Some(reg_prop) = dev.find_property("reg") {
-        for i in 0..addr_cells as usize {
-            let cell = convert::read_be_u32(reg_prop.value, i * 4);
-            gicd_addr = (gicd_addr << 32) | cell as usize;
-        }
+/// Returns a special INTID (see `is_special`) when there is nothing to handle.
+#[inline(always)]
+pub fn acknowledge() -> u32 {
+    let iar: u64;
+    unsafe {
+        asm!("mrs {}, ICC_IAR1_EL1", out(reg) iar, options(nostack, nomem, preserves_flags));
+    }
+    (iar & 0xff_ffff) as u32
+}
 
-        let gicr_off = (addr_cells + size_cells) as usize * 4; // Convert cells to bytes
-        for i in 0..addr_cells as usize {
-            unsafe {
-                let cell = convert::read_be_u32(reg_prop.value.add(gicr_off), i * 4);
-                gicr_addr = (gicr_addr << 32) | cell as usize;
+/// Drops the running priority of the CPU interface, ending the handling of `id`
+#[inline(always)]
+pub fn end_of_interrupt(id: u32) {
+    unsafe {
+        asm!("msr ICC_EOIR1_EL1, {}", in(reg) id as u64, options(nostack, nomem, preserves_flags));
+    }
+}
+
+/// Deactivates `id`, allowing it to be signaled again
+#[inline(always)]
+pub fn deactivate(id: u32) {
+    unsafe {
+        asm!("msr ICC_DIR_EL1, {}", "isb", in(reg) id as u64, options(nostack, nomem, preserves_flags));
+    }
+}
+
+/// Raises SGI `id` on the CPU whose MPIDR affinity fields are `mpidr`
+///
+/// The barrier makes the caller's stores visible to the target before the SGI is.
+pub fn send_sgi(mpidr: u64, id: u32) {
+    let aff0 = mpidr & 0xff;
+    let sgi1r = (mpidr >> 32 & 0xff) << ICC_SGI1R_AFF3_SHIFT
+        | (mpidr >> 16 & 0xff) << ICC_SGI1R_AFF2_SHIFT
+        | (aff0 >> 4) << ICC_SGI1R_RS_SHIFT
+        | (id as u64 & 0xf) << ICC_SGI1R_INTID_SHIFT
+        | (mpidr >> 8 & 0xff) << ICC_SGI1R_AFF1_SHIFT
+        | 1 << (aff0 & 0xf);
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "msr ICC_SGI1R_EL1, {}",
+            "isb",
+            in(reg) sgi1r,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Returns true if `id` is one of the special INTIDs (1020-1023)
+#[inline(always)]
+pub fn is_special(id: u32) -> bool {
+    (FIRST_SPECIAL_INTID..=SPURIOUS_INTID).contains(&id)
+}
+
+/// Splits priority drop and deactivation (EOImode = 1)
+fn enable_split_eoi() {
+    unsafe {
+        let mut ctlr: u64;
+        asm!("mrs {}, ICC_CTLR_EL1", out(reg) ctlr, options(nostack, nomem, preserves_flags));
+        ctlr |= ICC_CTLR_EOIMODE;
+        asm!("msr ICC_CTLR_EL1, {}", "isb", in(reg) ctlr, options(nostack, nomem, preserves_flags));
+    }
+}
+
+/// Handles every pending interrupt, called from the IRQ vector
+///
+/// An IRQ exception that finds nothing to acknowledge is counted as spurious: the interrupt was
+/// withdrawn (e.g. a level-triggered source deasserted) between signaling and acknowledgement.
+pub fn handle_irq() {
+    let mut handled = false;
+    loop {
+        let id = acknowledge();
+        if is_special(id) {
+            if !handled {
+                irq::note_spurious();
             }
+            break;
+        }
+        irq::dispatch(id);
+        end_of_interrupt(id);
+        if id < FIRST_LPI {
+            deactivate(id);
+        }
+        handled = true;
+    }
+}
+
+/// Driver for `arm,gic-v3` nodes
+pub struct GicV3Driver;
+
+impl device::Driver for GicV3Driver {
+    /// Sets up the GICv3 from device tree properties
+    ///
+    /// Takes the distributor (GICD) and redistributor (GICR) regions from the first two `reg`
+    /// entries and initializes the GIC hardware: the distributor, then the boot CPU's
+    /// redistributor and CPU interface, accepting all priorities in split EOI mode with Group 1
+    /// interrupts enabled.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        // The first two regions are the distributor and the redistributors
+        let (Some(gicd), Some(gicr)) = (dev.reg(0), dev.reg(1)) else {
+            return Err(ProbeError::NoDevice);
+        };
+        init_gic(gicd.base, gicr.base, gicr.size)?;
+        super::register(&GicV3Driver)
+    }
+}
+
+impl IrqChip for GicV3Driver {
+    fn configure(&self, spec: &IrqSpec) -> u32 {
+        configure_irq(spec)
+    }
+
+    fn enable(&self, id: u32) {
+        if id < FIRST_SPI {
+            enable_ppi(id);
+        } else if id >= FIRST_LPI {
+            its::enable_lpi(id);
+        } else {
+            enable_spi(id);
+        }
+    }
+
+    fn handle_irq(&self) {
+        handle_irq();
+    }
+
+    fn init_cpu(&self) {
+        gic().init_cpu();
+    }
+
+    fn disable_cpu(&self) {
+        gic().disable_cpu();
+    }
+
+    fn send_ipi(&self, cpu: CpuId, id: u32) {
+        if let Some(mpidr) = smp::mpidr(cpu) {
+            send_sgi(mpidr, id);
         }
-        init_gic(gicd_addr, gicr_addr);
     }
-    set_priority_mask(0xff);
-    enable_grp1_ints();
 }
@@ -0,0 +1,489 @@
+//! GICv3 Interrupt Translation Service (ITS)
+//!
+//! A device signals a message-based interrupt (MSI) by writing an event ID to the ITS's
+//! `GITS_TRANSLATER` register; the bus tags the write with the device's ID (for PCI, its
+//! requester ID, see `irq::of::of_msi_map_id`). The ITS looks the pair up in tables it keeps in
+//! memory: the device table gives the device's interrupt translation table (ITT), which maps the
+//! event to an LPI and a collection, and the collection table names the redistributor the LPI
+//! goes to.
+//!
+//! The driver is programmed through a command queue in memory: `MAPD` gives a device its ITT,
+//! `MAPC` binds a collection to a redistributor, `MAPTI` maps an event to an LPI, `INV` makes the
+//! redistributor reload an LPI's configuration and `SYNC` waits for the previous commands to take
+//! effect. `map_event` hands out an LPI for a device's event; `pci::msi` builds on it.
+//!
+//! ## Design
+//!
+//! - A single ITS and a single collection, bound to the boot CPU's redistributor, which every
+//!   LPI is routed to.
+//! - Flat device and collection tables (`GITS_BASER<n>`), with 4 KiB pages. The device table
+//!   covers up to 65536 device IDs, every PCI requester ID.
+//! - LPIs come from a pool of `MAX_LPIS`, with 14 INTID bits: the configuration table is 8 KiB
+//!   and the pending table 2 KiB. Each device mapped gets an ITT for `MAX_EVENTS` events, kept
+//!   when its events are unmapped.
+//! - All tables and the command queue are `dma::alloc_coherent` buffers, and the ITS and
+//!   redistributor are told they are non-cacheable.
+//! - Commands are waited for by polling `GITS_CREADR`, with a timeout.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `irq-gic-v3-its.c` handles several ITSes, one collection per CPU (moving LPIs for
+//! affinity), two-level device tables, and GICv4 virtual LPIs; LPIs and ITTs are allocated per
+//! device through the `its_msi_prepare` and `irq_domain_alloc_irqs` callbacks of the MSI domain.
+
+use core::arch::asm;
+
+use crate::ipc::init_cell::InitCell;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::mm::dma::{self, DmaBuffer};
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+use crate::kernel::notifier::Deadline;
+use crate::utilities::mmio;
+use crate::{pr_err, println};
+
+use super::gicv3::{self, FIRST_LPI};
+
+/* --- GITS (ITS) Constants --- */
+/// Control Register
+const GITS_CTLR: usize = 0x0000;
+const GITS_CTLR_ENABLED: u32 = 1 << 0;
+const GITS_CTLR_QUIESCENT: u32 = 1 << 31;
+/// Type Register (64 bits)
+const GITS_TYPER: usize = 0x0008;
+/// Size of an ITT entry, minus one (bits 7:4)
+const GITS_TYPER_ITT_ENTRY_SHIFT: u64 = 4;
+const GITS_TYPER_ITT_ENTRY_MASK: u64 = 0xf;
+/// Number of device ID bits, minus one (bits 17:13)
+const GITS_TYPER_DEVBITS_SHIFT: u64 = 13;
+const GITS_TYPER_DEVBITS_MASK: u64 = 0x1f;
+/// Commands name redistributors by physical address rather than processor number
+const GITS_TYPER_PTA: u64 = 1 << 19;
+/// Command queue base address and size (64 bits)
+const GITS_CBASER: usize = 0x0080;
+/// Offset of the next command to write, and of the next one the ITS reads (64 bits)
+const GITS_CWRITER: usize = 0x0088;
+const GITS_CREADR: usize = 0x0090;
+/// The ITS stopped on a command error (CREADR.Stalled)
+const GITS_CREADR_STALLED: u64 = 1 << 0;
+const GITS_CREADR_OFFSET_MASK: u64 = 0xf_ffe0;
+/// Table descriptors (64 bits each)
+const GITS_BASER: usize = 0x0100;
+const GITS_BASER_COUNT: usize = 8;
+/// Register devices write their event IDs to, in the translation frame
+const GITS_TRANSLATER: usize = 0x1_0040;
+
+/// Fields of `GITS_BASER<n>` and `GITS_CBASER`
+const BASER_VALID: u64 = 1 << 63;
+/// Inner cacheability (bits 61:59): non-cacheable, as `dma` maps the tables
+const BASER_INNER_NC: u64 = 0b001 << 59;
+const BASER_TYPE_SHIFT: u64 = 56;
+const BASER_TYPE_MASK: u64 = 0b111;
+const BASER_TYPE_DEVICES: u64 = 1;
+const BASER_ENTRY_SIZE_SHIFT: u64 = 48;
+const BASER_ENTRY_SIZE_MASK: u64 = 0x1f;
+/// Page size (bits 9:8), 0 for 4 KiB
+const BASER_PAGE_SIZE_MASK: u64 = 0b11 << 8;
+/// Number of pages, minus one (bits 7:0)
+const BASER_MAX_PAGES: usize = 256;
+
+/* --- Command Constants --- */
+const CMD_SYNC: u64 = 0x05;
+const CMD_MAPD: u64 = 0x08;
+const CMD_MAPC: u64 = 0x09;
+const CMD_MAPTI: u64 = 0x0a;
+const CMD_INV: u64 = 0x0c;
+const CMD_DISCARD: u64 = 0x0f;
+/// Valid bit of `MAPD` and `MAPC`
+const CMD_VALID: u64 = 1 << 63;
+/// Size of a command
+const CMD_SIZE: usize = 32;
+/// Size of the command queue, a page
+const QUEUE_SIZE: usize = PAGE_SIZE;
+/// Time for the ITS to consume a command
+const CMD_TIMEOUT_MS: u32 = 100;
+
+/* --- LPI Constants --- */
+/// INTID bits of the LPI tables: LPIs 8192 to 16383
+const LPI_ID_BITS: u32 = 14;
+/// LPIs handed out, at most
+pub const MAX_LPIS: usize = 64;
+/// Configuration byte of an LPI: priority 0 (bits 7:2), bit 1 reserved as one
+const LPI_CONFIG: u8 = 0b10;
+const LPI_CONFIG_ENABLE: u8 = 1 << 0;
+/// Devices given an ITT, at most
+const MAX_DEVICES: usize = 8;
+/// Event ID bits of an ITT, so events 0 to 31
+const EVENT_BITS: u32 = 5;
+pub const MAX_EVENTS: u32 = 1 << EVENT_BITS;
+/// The collection every LPI goes to
+const COLLECTION: u64 = 0;
+
+/// Errors of the ITS
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ItsError {
+    /// No ITS was probed
+    NotPresent,
+    /// The device ID is past the device table
+    BadDevice,
+    /// The event ID is `MAX_EVENTS` or more
+    BadEvent,
+    /// The event is mapped already
+    Busy,
+    /// All `MAX_LPIS` LPIs, or all `MAX_DEVICES` ITTs, are in use
+    NoSpace,
+    /// No memory for an ITT
+    NoMemory,
+    /// The ITS didn't consume a command in time, or stalled on one
+    Timeout,
+}
+
+/// An LPI handed out
+#[derive(Clone, Copy)]
+struct Lpi {
+    device_id: u32,
+    event: u32,
+}
+
+/// State changed by the commands
+struct State {
+    /// Command queue
+    queue: DmaBuffer,
+    /// Offset of the next command in `queue`
+    writer: usize,
+    /// LPI `FIRST_LPI + i` is mapped to `lpis[i]`
+    lpis: [Option<Lpi>; MAX_LPIS],
+    /// IDs of the devices given an ITT, which they keep
+    devices: [Option<u32>; MAX_DEVICES],
+}
+
+/// The ITS, set by the driver's probe
+struct Its {
+    base: usize,
+    /// LPI configuration table, one byte per LPI from `FIRST_LPI`
+    config: DmaBuffer,
+    /// Number of device IDs the device table covers
+    device_ids: u32,
+    /// Size of an ITT entry
+    itt_entry_size: usize,
+    /// How the commands name the redistributor, see `gicv3::redistributor_target`
+    target: u64,
+    state: Mutex<State>,
+}
+
+static ITS: InitCell<Its> = InitCell::new();
+
+fn read64(base: usize, offset: usize) -> u64 {
+    unsafe { core::ptr::read_volatile((base + offset) as *const u64) }
+}
+
+fn write64(base: usize, offset: usize, value: u64) {
+    unsafe { core::ptr::write_volatile((base + offset) as *mut u64, value) }
+}
+
+impl State {
+    /// Queues `cmd`, waiting for room in the queue
+    fn send(&mut self, base: usize, cmd: [u64; 4]) -> Result<(), ItsError> {
+        let next = (self.writer + CMD_SIZE) % QUEUE_SIZE;
+        // The queue is full while the ITS hasn't read the slot after the one written
+        let deadline = Deadline::from_ms(CMD_TIMEOUT_MS);
+        while read64(base, GITS_CREADR) & GITS_CREADR_OFFSET_MASK == next as u64 {
+            if deadline.expired() {
+                return Err(ItsError::Timeout);
+            }
+        }
+        let slot = (self.queue.vaddr + self.writer) as *mut u64;
+        for (i, dw) in cmd.iter().enumerate() {
+            unsafe { slot.add(i).write_volatile(*dw) };
+        }
+        unsafe { asm!("dsb sy", options(nostack)) };
+        self.writer = next;
+        write64(base, GITS_CWRITER, next as u64);
+        Ok(())
+    }
+
+    /// Queues `cmd` then a `SYNC`, and waits for the ITS to have done both
+    fn send_sync(&mut self, base: usize, target: u64, cmd: [u64; 4]) -> Result<(), ItsError> {
+        self.send(base, cmd)?;
+        self.send(base, [CMD_SYNC, 0, target << 16, 0])?;
+        let deadline = Deadline::from_ms(CMD_TIMEOUT_MS);
+        loop {
+            let creadr = read64(base, GITS_CREADR);
+            if creadr & GITS_CREADR_STALLED != 0 {
+                return Err(ItsError::Timeout);
+            }
+            if creadr & GITS_CREADR_OFFSET_MASK == self.writer as u64 {
+                return Ok(());
+            }
+            if deadline.expired() {
+                return Err(ItsError::Timeout);
+            }
+        }
+    }
+}
+
+impl Its {
+    /// Sets the configuration byte of `intid`
+    fn set_config(&self, intid: u32, enable: bool) {
+        let byte = LPI_CONFIG | if enable { LPI_CONFIG_ENABLE } else { 0 };
+        let entry = (self.config.vaddr + (intid - FIRST_LPI) as usize) as *mut u8;
+        unsafe {
+            entry.write_volatile(byte);
+            asm!("dsb sy", options(nostack));
+        }
+    }
+
+    /// Gives device `id` an ITT, unless it has one
+    fn map_device(&self, state: &mut State, id: u32) -> Result<(), ItsError> {
+        if state.devices.contains(&Some(id)) {
+            return Ok(());
+        }
+        let slot = state
+            .devices
+            .iter()
+            .position(Option::is_none)
+            .ok_or(ItsError::NoSpace)?;
+        let itt = dma::alloc_coherent(MAX_EVENTS as usize * self.itt_entry_size)
+            .map_err(|_| ItsError::NoMemory)?;
+        let cmd = [
+            CMD_MAPD | (id as u64) << 32,
+            (EVENT_BITS - 1) as u64,
+            CMD_VALID | itt.paddr as u64,
+            0,
+        ];
+        if let Err(e) = state.send_sync(self.base, self.target, cmd) {
+            let _ = dma::free_coherent(itt);
+            return Err(e);
+        }
+        state.devices[slot] = Some(id);
+        Ok(())
+    }
+}
+
+/// Returns true if an ITS was probed
+pub fn is_present() -> bool {
+    ITS.is_set()
+}
+
+/// Returns the physical address devices write their event IDs to
+pub fn doorbell() -> Option<u64> {
+    ITS.get().map(|its| (its.base + GITS_TRANSLATER) as u64)
+}
+
+/// Maps event `event` of device `device_id` to an LPI, and returns its INTID
+///
+/// The LPI is left disabled, like the interrupts `gic::configure_irq` sets up; `gic::enable_irq`
+/// enables it.
+pub fn map_event(device_id: u32, event: u32) -> Result<u32, ItsError> {
+    let its = ITS.get().ok_or(ItsError::NotPresent)?;
+    if device_id >= its.device_ids {
+        return Err(ItsError::BadDevice);
+    }
+    if event >= MAX_EVENTS {
+        return Err(ItsError::BadEvent);
+    }
+    its.state.lock_irqsafe(|state| {
+        let mapped = Some((device_id, event));
+        if state
+            .lpis
+            .iter()
+            .flatten()
+            .any(|lpi| Some((lpi.device_id, lpi.event)) == mapped)
+        {
+            return Err(ItsError::Busy);
+        }
+        let index = state
+            .lpis
+            .iter()
+            .position(Option::is_none)
+            .ok_or(ItsError::NoSpace)?;
+        its.map_device(state, device_id)?;
+        let intid = FIRST_LPI + index as u32;
+        its.set_config(intid, false);
+        let cmd = [
+            CMD_MAPTI | (device_id as u64) << 32,
+            event as u64 | (intid as u64) << 32,
+            COLLECTION,
+            0,
+        ];
+        state.send_sync(its.base, its.target, cmd)?;
+        state.lpis[index] = Some(Lpi { device_id, event });
+        Ok(intid)
+    })
+}
+
+/// Unmaps the LPI `intid` returned by `map_event`, which can then be handed out again
+///
+/// The device's ITT is kept for its other events.
+pub fn unmap_event(intid: u32) -> Result<(), ItsError> {
+    let its = ITS.get().ok_or(ItsError::NotPresent)?;
+    let index = intid.wrapping_sub(FIRST_LPI) as usize;
+    its.state.lock_irqsafe(|state| {
+        let lpi = state
+            .lpis
+            .get(index)
+            .copied()
+            .flatten()
+            .ok_or(ItsError::BadEvent)?;
+        its.set_config(intid, false);
+        let cmd = [
+            CMD_DISCARD | (lpi.device_id as u64) << 32,
+            lpi.event as u64,
+            0,
+            0,
+        ];
+        state.send_sync(its.base, its.target, cmd)?;
+        state.lpis[index] = None;
+        Ok(())
+    })
+}
+
+/// Enables the LPI `intid`, which must have been returned by `map_event`
+pub fn enable_lpi(intid: u32) {
+    let Some(its) = ITS.get() else {
+        return;
+    };
+    let index = intid.wrapping_sub(FIRST_LPI) as usize;
+    its.state.lock_irqsafe(|state| {
+        let Some(lpi) = state.lpis.get(index).copied().flatten() else {
+            return;
+        };
+        its.set_config(intid, true);
+        // The redistributor caches the configuration
+        let cmd = [
+            CMD_INV | (lpi.device_id as u64) << 32,
+            lpi.event as u64,
+            0,
+            0,
+        ];
+        if let Err(e) = state.send_sync(its.base, its.target, cmd) {
+            pr_err!("its: cannot enable LPI {}: {:?}", intid, e);
+        }
+    });
+}
+
+/// Allocates the table `GITS_BASER<index>` asks for, if any, and returns the number of device
+/// IDs it covers (0 for the other tables)
+fn setup_table(base: usize, index: usize, typer: u64) -> Result<u32, ProbeError> {
+    let offset = GITS_BASER + index * 8;
+    let baser = read64(base, offset);
+    let kind = (baser >> BASER_TYPE_SHIFT) & BASER_TYPE_MASK;
+    if kind == 0 {
+        return Ok(0);
+    }
+    let entry_size = ((baser >> BASER_ENTRY_SIZE_SHIFT) & BASER_ENTRY_SIZE_MASK) as usize + 1;
+    // Collections (and whatever else) need a single page: there is one collection
+    let entries = if kind == BASER_TYPE_DEVICES {
+        let bits = ((typer >> GITS_TYPER_DEVBITS_SHIFT) & GITS_TYPER_DEVBITS_MASK) + 1;
+        1usize << bits.min(16)
+    } else {
+        1
+    };
+    let pages = (entries * entry_size)
+        .div_ceil(PAGE_SIZE)
+        .min(BASER_MAX_PAGES);
+    let table = dma::alloc_coherent(pages * PAGE_SIZE).map_err(|_| ProbeError::NoResources)?;
+    let keep =
+        (BASER_TYPE_MASK << BASER_TYPE_SHIFT) | (BASER_ENTRY_SIZE_MASK << BASER_ENTRY_SIZE_SHIFT);
+    write64(
+        base,
+        offset,
+        BASER_VALID | BASER_INNER_NC | (baser & keep) | table.paddr as u64 | (pages - 1) as u64,
+    );
+    if read64(base, offset) & BASER_PAGE_SIZE_MASK != 0 {
+        pr_err!("its: table {} needs pages larger than 4 KiB", index);
+        write64(base, offset, 0);
+        let _ = dma::free_coherent(table);
+        return Err(ProbeError::NotSupported);
+    }
+    if kind == BASER_TYPE_DEVICES {
+        return Ok((pages * PAGE_SIZE / entry_size).min(entries) as u32);
+    }
+    Ok(0)
+}
+
+/// Driver for `arm,gic-v3-its` nodes
+pub struct ItsDriver;
+
+impl device::Driver for ItsDriver {
+    /// Sets up the ITS and enables LPIs in the boot CPU's redistributor
+    ///
+    /// The node sits below the GIC's, which has been probed by then. A single ITS is supported.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        if ITS.is_set() {
+            return Err(ProbeError::NotSupported);
+        }
+        let base = dev.reg(0).ok_or(ProbeError::NoDevice)?.base;
+        let typer = read64(base, GITS_TYPER);
+        let target =
+            gicv3::redistributor_target(typer & GITS_TYPER_PTA != 0).ok_or(ProbeError::Defer)?;
+
+        // The tables can only be set up while the ITS is disabled and quiescent
+        mmio::clear_mmio_bits32(base, GITS_CTLR, GITS_CTLR_ENABLED);
+        let deadline = Deadline::from_ms(CMD_TIMEOUT_MS);
+        while mmio::read_mmio32(base, GITS_CTLR) & GITS_CTLR_QUIESCENT == 0 {
+            if deadline.expired() {
+                return Err(ProbeError::NoDevice);
+            }
+        }
+
+        let config = dma::alloc_coherent((1 << LPI_ID_BITS) - FIRST_LPI as usize)
+            .map_err(|_| ProbeError::NoResources)?;
+        let pending = dma::alloc_coherent_aligned((1 << LPI_ID_BITS) / 8, 64 * 1024)
+            .map_err(|_| ProbeError::NoResources)?;
+        let queue = dma::alloc_coherent(QUEUE_SIZE).map_err(|_| ProbeError::NoResources)?;
+        for i in 0..MAX_LPIS {
+            unsafe { ((config.vaddr + i) as *mut u8).write_volatile(LPI_CONFIG) };
+        }
+        if let Err(e) = gicv3::enable_lpis(config.paddr, LPI_ID_BITS, pending.paddr) {
+            pr_err!("its: cannot enable LPIs: {:?}", e);
+            return Err(ProbeError::NotSupported);
+        }
+
+        let mut device_ids = 0;
+        for index in 0..GITS_BASER_COUNT {
+            device_ids = device_ids.max(setup_table(base, index, typer)?);
+        }
+        write64(
+            base,
+            GITS_CBASER,
+            BASER_VALID | BASER_INNER_NC | queue.paddr as u64 | (QUEUE_SIZE / PAGE_SIZE - 1) as u64,
+        );
+        write64(base, GITS_CWRITER, 0);
+        mmio::set_mmio_bits32(base, GITS_CTLR, GITS_CTLR_ENABLED);
+
+        let its = ITS
+            .set(Its {
+                base,
+                config,
+                device_ids,
+                itt_entry_size: ((typer >> GITS_TYPER_ITT_ENTRY_SHIFT) & GITS_TYPER_ITT_ENTRY_MASK)
+                    as usize
+                    + 1,
+                target,
+                state: Mutex::new(State {
+                    queue,
+                    writer: 0,
+                    lpis: [None; MAX_LPIS],
+                    devices: [None; MAX_DEVICES],
+                }),
+            })
+            .map_err(|_| ProbeError::NotSupported)?;
+        let mapc = [CMD_MAPC, 0, CMD_VALID | target << 16 | COLLECTION, 0];
+        if let Err(e) = its
+            .state
+            .lock_irqsafe(|state| state.send_sync(base, target, mapc))
+        {
+            pr_err!("its: cannot map the collection: {:?}", e);
+            return Err(ProbeError::NoDevice);
+        }
+        println!(
+            "its: at {:#x}, {} device IDs, LPIs {} to {}",
+            base,
+            device_ids,
+            FIRST_LPI,
+            FIRST_LPI + MAX_LPIS as u32 - 1
+        );
+        Ok(())
+    }
+}
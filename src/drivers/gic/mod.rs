@@ -1,3 +1,158 @@
-//! Generic Interrupt Controller (GICv3) driver
+//! Generic Interrupt Controller drivers
+//!
+//! `gicv3` drives the GICv3 of QEMU's `virt` machine; `gic400`, built with the `gic400` feature,
+//! the GICv2 GIC-400 of boards such as the Raspberry Pi 4. The driver of whichever the DTB
+//! describes registers it as the interrupt controller, and the rest of the kernel configures and
+//! enables its interrupts through the functions here, whatever the GIC version. Every CPU has its
+//! own interface to the GIC, which `smp` opens with `init_cpu` when the CPU starts and closes
+//! with `disable_cpu` before powering it off. `its` drives the GICv3's Interrupt Translation
+//! Service, which turns message-signaled interrupts into LPIs.
+//!
+//! ## Design
+//!
+//! - SGIs and PPIs (INTIDs below 32) are banked: every CPU has its own copy of their
+//!   configuration and enable bit. The PPIs configured and the banked interrupts enabled so far
+//!   are recorded, and `init_cpu` replays them on a CPU that starts later, so a driver's per-CPU
+//!   interrupt (e.g. the timer's) fires on every CPU.
+//! - `send_ipi` raises an SGI on another CPU. `IPI_RESCHEDULE` asks it to reschedule (see
+//!   `sched::resched_cpu`).
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `irq_chip` has callbacks for every operation (mask, unmask, set_type, EOI...) and
+//! supports stacked and chained controllers through IRQ domains. A single root controller is
+//! supported here, and drivers only ever configure and enable their interrupts. Per-CPU
+//! interrupts are enabled by each CPU's hotplug callback (`enable_percpu_irq`) rather than
+//! replayed, and IPIs go through `smp_cross_call` with a handful of message types.
 
+#[cfg(feature = "gic400")]
+pub mod gic400;
 pub mod gicv3;
+pub mod its;
+
+use crate::ipc::init_cell::InitCell;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::device::ProbeError;
+use crate::kernel::irq::of::{IrqKind, IrqSpec};
+use crate::kernel::smp::CpuId;
+
+/// SGI asking a CPU to reschedule
+pub const IPI_RESCHEDULE: u32 = 0;
+
+/// Number of banked interrupts, SGIs and PPIs
+const NR_BANKED: usize = 32;
+
+/// Operations of an interrupt controller
+pub trait IrqChip: Sync {
+    /// Configures the interrupt described by `spec`, left disabled, and returns its INTID
+    fn configure(&self, spec: &IrqSpec) -> u32;
+
+    /// Enables forwarding of the interrupt `id` to the CPU
+    fn enable(&self, id: u32);
+
+    /// Handles every pending interrupt, called from the IRQ vector
+    fn handle_irq(&self);
+
+    /// Opens the calling CPU's interface to the controller, on a CPU that just started
+    fn init_cpu(&self);
+
+    /// Closes the calling CPU's interface, on a CPU about to be powered off
+    fn disable_cpu(&self);
+
+    /// Raises the SGI `id` on `cpu`
+    fn send_ipi(&self, cpu: CpuId, id: u32);
+}
+
+/// Banked interrupts to set up on every CPU, see `init_cpu`
+struct Banked {
+    /// PPIs given to `configure_irq`
+    configured: [Option<IrqSpec>; NR_BANKED],
+    /// Bitmap of the SGIs and PPIs given to `enable_irq`
+    enabled: u32,
+}
+
+static BANKED: Mutex<Banked> = Mutex::new(Banked {
+    configured: [None; NR_BANKED],
+    enabled: 0,
+});
+
+/// The interrupt controller, registered by its driver's probe
+static CHIP: InitCell<&'static dyn IrqChip> = InitCell::new();
+
+/// Makes `chip` the interrupt controller
+///
+/// Fails with `ProbeError::NotSupported` if one is already registered.
+pub fn register(chip: &'static dyn IrqChip) -> Result<(), ProbeError> {
+    CHIP.set(chip)
+        .map(|_| ())
+        .map_err(|_| ProbeError::NotSupported)
+}
+
+/// Returns the interrupt controller
+///
+/// Drivers configuring interrupts are deferred until their interrupt parent is bound (see
+/// `device`), so it is registered by the time they call in here.
+fn chip() -> &'static dyn IrqChip {
+    *CHIP.get().expect("gic: no interrupt controller")
+}
+
+/// Configures the interrupt described by `spec` and returns its INTID
+///
+/// Sets the trigger type and the highest priority, and routes an SPI to the boot CPU. The
+/// interrupt is left disabled; `enable_irq` forwards it once a handler is registered.
+pub fn configure_irq(spec: &IrqSpec) -> u32 {
+    let id = chip().configure(spec);
+    if spec.kind == IrqKind::Ppi && (id as usize) < NR_BANKED {
+        BANKED.lock_irqsafe(|banked| banked.configured[id as usize] = Some(*spec));
+    }
+    id
+}
+
+/// Enables forwarding of the interrupt `id`, an SGI, an SPI, a PPI or an LPI
+pub fn enable_irq(id: u32) {
+    chip().enable(id);
+    if (id as usize) < NR_BANKED {
+        BANKED.lock_irqsafe(|banked| banked.enabled |= 1 << id);
+    }
+}
+
+/// Handles every pending interrupt, called from the IRQ vector
+pub fn handle_irq() {
+    if let Some(chip) = CHIP.get() {
+        chip.handle_irq();
+    }
+}
+
+/// Opens the calling CPU's interface to the interrupt controller
+///
+/// Called by a secondary CPU as it starts; the driver's probe does it for the boot CPU. The
+/// banked interrupts configured and enabled on the CPUs started before are set up the same way.
+pub fn init_cpu() {
+    let Some(chip) = CHIP.get() else {
+        return;
+    };
+    chip.init_cpu();
+    let (configured, enabled) = BANKED.lock_irqsafe(|banked| (banked.configured, banked.enabled));
+    for spec in configured.iter().flatten() {
+        chip.configure(spec);
+    }
+    for id in (0..NR_BANKED as u32).filter(|id| enabled & 1 << id != 0) {
+        chip.enable(id);
+    }
+}
+
+/// Closes the calling CPU's interface to the interrupt controller, before it is powered off
+pub fn disable_cpu() {
+    if let Some(chip) = CHIP.get() {
+        chip.disable_cpu();
+    }
+}
+
+/// Raises the SGI `id` on `cpu`, e.g. `IPI_RESCHEDULE`
+///
+/// Stores made before the call are visible to `cpu` when it takes the interrupt.
+pub fn send_ipi(cpu: CpuId, id: u32) {
+    if let Some(chip) = CHIP.get() {
+        chip.send_ipi(cpu, id);
+    }
+}
@@ -0,0 +1,67 @@
+//! IOMMU drivers
+//!
+//! An IOMMU translates the addresses devices use for DMA, so a device can only reach the memory
+//! it has been given. Devices are told apart by their stream ID; every stream attached to an
+//! `IommuDomain` translates through the domain's page table, and a stream attached to none has
+//! its DMA aborted. Drivers `map` the buffers they hand a device for as long as the device owns
+//! them and `unmap` them afterwards.
+//!
+//! The virtio layer does this in its virtqueues for the devices whose DTB node has an `iommus`
+//! property: the rings are mapped when the queue is set up and every buffer while it is on the
+//! queue. Buffers are mapped at their physical address (IOVA = PA), so the addresses given to the
+//! device don't change.
+//!
+//! Only the ARM SMMUv3 is supported. On QEMU's `virt` machine (`iommu=smmuv3`) it sits in front
+//! of the PCIe root complex; virtio-mmio devices bypass it.
+
+pub mod smmuv3;
+
+pub use smmuv3::IommuDomain;
+
+use crate::initcall;
+use crate::kernel::device;
+use crate::utilities::convert;
+
+/// Errors returned by the IOMMU drivers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IommuError {
+    /// No IOMMU was found in the DTB, or it couldn't be brought up
+    NoDevice,
+    /// Not enough free frames for a table
+    NoMemory,
+    /// Every domain, or every ASID/VMID, is in use
+    NoSpace,
+    /// The stream ID is outside the stream table, or IOVA and physical address disagree on the
+    /// offset within a page
+    BadAddress,
+    /// The IOVA or the stream is already in use
+    Exists,
+    /// Nothing is mapped at this IOVA, or the stream isn't attached to this domain
+    NotMapped,
+    /// The domain still has streams attached
+    Busy,
+    /// The IOMMU didn't complete a command in time
+    Timeout,
+}
+
+/// Returns the stream ID of `dev` from its `iommus` property, if it is behind an IOMMU
+///
+/// The IOMMU node is expected to have `#iommu-cells = <1>`, the cell being the stream ID.
+pub fn stream_id(dev: &device::PlatformDevice) -> Option<u32> {
+    let prop = dev.find_property("iommus")?;
+    (prop.len >= 8).then(|| convert::read_be_u32(prop.value, 4))
+}
+
+/// Returns true if an IOMMU is up and translating
+pub fn is_present() -> bool {
+    smmuv3::is_enabled()
+}
+
+/// Brings up the IOMMU found in the DTB, if any
+///
+/// Its tables come from the frame allocator, so this must run after `frame::init`, and before
+/// the drivers of the devices behind it create their domains.
+pub fn init() {
+    smmuv3::init();
+}
+initcall!(Driver, "iommu", init, after = ["frame"]);
@@ -0,0 +1,936 @@
+//! ARM SMMUv3 driver
+//!
+//! The SMMU finds the translation of a stream in its stream table, indexed by stream ID: each
+//! stream table entry (STE) aborts the stream's DMA, lets it through, or translates it with
+//! stage 1 (a context descriptor (CD) pointing to a VMSA page table, like the CPU's) or stage 2
+//! (the page table hangs off the STE directly). Software talks to the SMMU through a command
+//! queue (invalidating cached STEs and TLB entries) and hears about faults on an event queue.
+//!
+//! ## Design
+//!
+//! - `setup` only records the registers and the event queue interrupt: devices are probed before
+//!   the frame allocator is up. `init` allocates the tables and enables the SMMU.
+//! - The stream table is linear, with at most `MAX_STREAMS_LOG2` bits of stream ID (a PCIe bus),
+//!   and every entry aborts until a domain is attached.
+//! - Stage 1 is used when the SMMU implements it, stage 2 otherwise. Each `IommuDomain` has its
+//!   own 4 KiB-granule, 48-bit page table, tagged with an ASID (stage 1) or a VMID (stage 2)
+//!   derived from its slot.
+//! - Mappings are counted per page in the descriptor's software bits: buffers sharing a page
+//!   (a request header and its status byte) can be mapped and unmapped independently.
+//! - Table walks and queue accesses are assumed cache coherent (`IDR0.COHACC`): an SMMU without
+//!   it is left alone.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! `arm-smmu-v3` also supports two-level stream tables, PASIDs, MSIs, ATS and the PRI queue, and
+//! shares the page table code with the other IOMMUs through `io-pgtable`. The DMA API maps
+//! buffers at IOVAs of its choosing; here drivers pick the IOVA, the virtio layer using the
+//! physical address.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::drivers::gic;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::irq::of::{IrqKind, of_irq_parse_by_name};
+use crate::kernel::irq::{self, softirq};
+use crate::kernel::mm::addr_space::MapFlags;
+use crate::kernel::mm::bits::*;
+use crate::kernel::mm::frame;
+use crate::kernel::mm::pgtable::{
+    PAGE_MASK, PAGE_SIZE, Pte, mark_page_desc, mark_table_desc, set_mair_range,
+    set_next_lvl_table_addr,
+};
+use crate::kernel::notifier::Deadline;
+use crate::utilities::mmio;
+use crate::{pr_err, println};
+
+use super::IommuError;
+
+/// Registers, in the first 64 KiB page
+const IDR0: usize = 0x00;
+const IDR1: usize = 0x04;
+const IDR5: usize = 0x14;
+const CR0: usize = 0x20;
+const CR0ACK: usize = 0x24;
+const CR1: usize = 0x28;
+const CR2: usize = 0x2c;
+const IRQ_CTRL: usize = 0x50;
+const IRQ_CTRLACK: usize = 0x54;
+const GERROR: usize = 0x60;
+const GERRORN: usize = 0x64;
+const STRTAB_BASE: usize = 0x80;
+const STRTAB_BASE_CFG: usize = 0x88;
+const CMDQ_BASE: usize = 0x90;
+const CMDQ_PROD: usize = 0x98;
+const CMDQ_CONS: usize = 0x9c;
+const EVENTQ_BASE: usize = 0xa0;
+/// Event queue indexes, in the second page
+const EVENTQ_PROD: usize = 0x100a8;
+const EVENTQ_CONS: usize = 0x100ac;
+
+/// `IDR0` fields
+const IDR0_S2P: u32 = 1 << 0;
+const IDR0_S1P: u32 = 1 << 1;
+const IDR0_TTF_AARCH64: u32 = 1 << 3;
+const IDR0_COHACC: u32 = 1 << 4;
+
+/// `IDR5` fields
+const IDR5_OAS_MASK: u32 = 0x7;
+const IDR5_GRAN4K: u32 = 1 << 4;
+
+/// `CR0` bits
+const CR0_SMMUEN: u32 = 1 << 0;
+const CR0_EVTQEN: u32 = 1 << 2;
+const CR0_CMDQEN: u32 = 1 << 3;
+
+/// `CR1`: tables and queues are inner shareable, write-back cacheable
+const CR1_ATTRS: u32 = (3 << 10) | (1 << 8) | (1 << 6) | (3 << 4) | (1 << 2) | 1;
+
+/// `CR2`: report transactions with an invalid stream ID
+const CR2_RECINVSID: u32 = 1 << 1;
+
+/// `IRQ_CTRL`: enable the event queue interrupt
+const IRQ_CTRL_EVTQ_IRQEN: u32 = 1 << 2;
+
+/// `GERROR` bit of a command queue error
+const GERROR_CMDQ_ERR: u32 = 1 << 0;
+
+/// Read/write allocate hint of the queue and table base registers
+const BASE_ALLOCATE: u64 = 1 << 62;
+/// Address bits [51:5] of a queue base register
+const QUEUE_ADDR_MASK: u64 = 0x000f_ffff_ffff_ffe0;
+/// Address bits [51:6] of `STRTAB_BASE` and of an STE's CD pointer
+const STRTAB_ADDR_MASK: u64 = 0x000f_ffff_ffff_ffc0;
+/// Address bits [51:4] of a CD's `TTB0` and an STE's `S2TTB`
+const TTB_ADDR_MASK: u64 = 0x000f_ffff_ffff_fff0;
+
+/// Error code in `CMDQ_CONS`
+const CMDQ_CONS_ERR_SHIFT: u32 = 24;
+const CMDQ_CONS_ERR_MASK: u32 = 0x7f;
+
+/// Overflow flag of `EVENTQ_PROD`, acknowledged by copying it to `EVENTQ_CONS`
+const EVENTQ_OVERFLOW: u32 = 1 << 31;
+
+/// Commands
+const CMD_CFGI_STE: u64 = 0x03;
+const CMD_CFGI_ALL: u64 = 0x04;
+const CMD_TLBI_NH_ASID: u64 = 0x11;
+const CMD_TLBI_NH_VA: u64 = 0x12;
+const CMD_TLBI_S12_VMALL: u64 = 0x28;
+const CMD_TLBI_NSNH_ALL: u64 = 0x30;
+const CMD_SYNC: u64 = 0x46;
+
+/// STE fields
+const STE_V: u64 = 1 << 0;
+const STE_CONFIG_MASK: u64 = 0x7 << 1;
+const STE_CONFIG_ABORT: u64 = 0;
+const STE_CONFIG_S1: u64 = 0b101 << 1;
+const STE_CONFIG_S2: u64 = 0b110 << 1;
+/// Word 1: CD fetches are write-back cacheable, inner shareable; shareability from the device
+const STE_1_ATTRS: u64 = (1 << 2) | (1 << 4) | (3 << 6) | (1 << 44);
+/// Word 2 (stage 2): AArch64 tables, record faults
+const STE_2_S2AA64: u64 = 1 << 51;
+const STE_2_S2R: u64 = 1 << 58;
+const STE_2_VMID_MASK: u64 = 0xffff;
+
+/// CD word 0: 48-bit input, 4 KiB granule, write-back inner shareable walks, `TTB1` disabled,
+/// AArch64, faults recorded and aborted, ASID not shared with the CPU
+const CD_0_ATTRS: u64 = 16
+    | (1 << 8)
+    | (1 << 10)
+    | (3 << 12)
+    | (1 << 30)
+    | (1 << 31)
+    | (1 << 41)
+    | (1 << 45)
+    | (1 << 46)
+    | (1 << 47);
+const CD_0_IPS_SHIFT: u64 = 32;
+const CD_0_ASID_SHIFT: u64 = 48;
+
+/// `VTCR` of a stage 2 STE: 48-bit input, walk starting at level 0, write-back inner shareable
+/// walks, 4 KiB granule
+const STE_VTCR: u64 = 16 | (2 << 6) | (1 << 8) | (1 << 10) | (3 << 12);
+const STE_VTCR_PS_SHIFT: u64 = 16;
+
+/// Stage 2 page descriptor attributes: normal write-back memory, read (bit 6) and write (bit 7)
+/// permissions, execute never
+const S2_MEMATTR_WB: Pte = 0xf << 2;
+const S2_AP_READ: Pte = 1 << 6;
+const S2_AP_WRITE: Pte = 1 << 7;
+const S2_XN: Pte = 1 << 54;
+
+/// Largest output address size encoding used, 48 bits: the page tables don't go further
+const OAS_48: u64 = 5;
+
+/// Size of the command queue (16-byte entries) and the event queue (32-byte entries), as a
+/// power of two
+const CMDQ_LOG2: u32 = 6;
+const EVTQ_LOG2: u32 = 6;
+
+/// Largest stream table, as a power of two of its entries (64 bytes each)
+const MAX_STREAMS_LOG2: u32 = 8;
+
+/// Maximum number of domains
+const MAX_DOMAINS: usize = 32;
+
+/// Entries in a page table
+const ENTRIES: usize = PAGE_SIZE / core::mem::size_of::<Pte>();
+
+/// Valid bit and output address of a descriptor
+const PTE_VALID: Pte = 1 << 0;
+const PTE_ADDR_MASK: Pte = 0x0000_ffff_ffff_f000;
+
+/// Software bits [58:55]: number of mappings of the page
+const PTE_COUNT_SHIFT: u32 = 55;
+const PTE_COUNT_MASK: Pte = 0xf << PTE_COUNT_SHIFT;
+const PTE_COUNT_MAX: Pte = 0xf;
+
+/// End of the IOVA range
+const IOVA_END: usize = 1 << 48;
+
+/// How long the SMMU gets to acknowledge a register update or complete a command
+const TIMEOUT_MS: u32 = 100;
+
+const _: () = assert!((16 << CMDQ_LOG2) <= PAGE_SIZE && (32 << EVTQ_LOG2) <= PAGE_SIZE);
+
+/// Translation stage used by the domains
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Stage {
+    One,
+    Two,
+}
+
+/// A circular queue shared with the SMMU
+///
+/// Indexes carry a wrap bit above the `log2` index bits, telling a full queue from an empty one.
+struct Queue {
+    base: usize,
+    log2: u32,
+    prod: u32,
+    cons: u32,
+}
+
+impl Queue {
+    /// Mask of the index and wrap bits
+    fn mask(&self) -> u32 {
+        (2 << self.log2) - 1
+    }
+
+    fn slot(&self, index: u32) -> usize {
+        (index & ((1 << self.log2) - 1)) as usize
+    }
+
+    fn next(&self, index: u32) -> u32 {
+        (index + 1) & self.mask()
+    }
+
+    fn is_full(&self) -> bool {
+        self.prod ^ self.cons == 1 << self.log2
+    }
+}
+
+/// The SMMU once enabled
+struct Smmu {
+    base: usize,
+    stage: Stage,
+    /// Output address size, as the `IPS`/`PS` encoding
+    oas: u64,
+    cmdq: Queue,
+    evtq: Queue,
+    strtab: usize,
+    streams_log2: u32,
+    /// Frames holding the stream table and both queues, in one allocation
+    frames: usize,
+    frame_count: usize,
+}
+
+/// Registers and event queue INTID of the SMMU found in the DTB, until `init`
+static FOUND: Mutex<Option<(usize, u32)>> = Mutex::new(None);
+
+static SMMU: Mutex<Option<Smmu>> = Mutex::new(None);
+
+/// Set once the SMMU translates
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Makes writes to normal memory (tables, queue entries) visible to the SMMU before the register
+/// write that follows
+fn wmb() {
+    unsafe { asm!("dsb st", options(nostack, preserves_flags)) };
+}
+
+impl Smmu {
+    fn read(&self, offset: usize) -> u32 {
+        mmio::read_mmio32(self.base, offset)
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        mmio::write_mmio32(self.base, offset, value);
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        unsafe { ((self.base + offset) as *mut u64).write_volatile(value) };
+    }
+
+    /// Writes `value` to `reg` and waits for the SMMU to report it in `ack`
+    fn write_ack(&self, reg: usize, ack: usize, value: u32) -> Result<(), IommuError> {
+        self.write(reg, value);
+        let deadline = Deadline::from_ms(TIMEOUT_MS);
+        while self.read(ack) != value {
+            if deadline.expired() {
+                return Err(IommuError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Queues a command, waiting for room if the queue is full
+    fn submit(&mut self, cmd: [u64; 2]) -> Result<(), IommuError> {
+        let deadline = Deadline::from_ms(TIMEOUT_MS);
+        while self.cmdq.is_full() {
+            self.cmdq.cons = self.read(CMDQ_CONS) & self.cmdq.mask();
+            if deadline.expired() {
+                return Err(IommuError::Timeout);
+            }
+        }
+        let entry = (self.cmdq.base + self.cmdq.slot(self.cmdq.prod) * 16) as *mut [u64; 2];
+        unsafe { entry.write_volatile(cmd) };
+        self.cmdq.prod = self.cmdq.next(self.cmdq.prod);
+        wmb();
+        self.write(CMDQ_PROD, self.cmdq.prod);
+        Ok(())
+    }
+
+    /// Queues a `CMD_SYNC` and waits until the SMMU has consumed every command before it
+    ///
+    /// A command the SMMU rejects is reported and replaced by a `CMD_SYNC`, so the queue moves
+    /// on.
+    fn sync(&mut self) -> Result<(), IommuError> {
+        self.submit([CMD_SYNC, 0])?;
+        let deadline = Deadline::from_ms(TIMEOUT_MS);
+        loop {
+            let cons = self.read(CMDQ_CONS);
+            self.cmdq.cons = cons & self.cmdq.mask();
+            if self.cmdq.cons == self.cmdq.prod {
+                return Ok(());
+            }
+            let gerror = self.read(GERROR);
+            if (gerror ^ self.read(GERRORN)) & GERROR_CMDQ_ERR != 0 {
+                println!(
+                    "smmuv3: command error {:#x}",
+                    (cons >> CMDQ_CONS_ERR_SHIFT) & CMDQ_CONS_ERR_MASK
+                );
+                let entry = (self.cmdq.base + self.cmdq.slot(self.cmdq.cons) * 16) as *mut [u64; 2];
+                unsafe { entry.write_volatile([CMD_SYNC, 0]) };
+                wmb();
+                self.write(GERRORN, self.read(GERRORN) ^ GERROR_CMDQ_ERR);
+            }
+            if deadline.expired() {
+                return Err(IommuError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Queues `cmds` and waits for their completion
+    fn issue(&mut self, cmds: &[[u64; 2]]) -> Result<(), IommuError> {
+        for &cmd in cmds {
+            self.submit(cmd)?;
+        }
+        self.sync()
+    }
+
+    fn ste(&self, sid: u32) -> *mut [u64; 8] {
+        (self.strtab + sid as usize * 64) as *mut [u64; 8]
+    }
+
+    /// Replaces the STE of `sid`, going through an aborting entry so the SMMU never sees half of
+    /// each
+    fn write_ste(&mut self, sid: u32, words: [u64; 8]) -> Result<(), IommuError> {
+        let ste = self.ste(sid) as *mut u64;
+        let invalidate = [CMD_CFGI_STE | (sid as u64) << 32, 0];
+        unsafe { ste.write_volatile(STE_V | STE_CONFIG_ABORT) };
+        wmb();
+        self.issue(&[invalidate])?;
+        for (i, &word) in words.iter().enumerate().skip(1) {
+            unsafe { ste.add(i).write_volatile(word) };
+        }
+        wmb();
+        unsafe { ste.write_volatile(words[0]) };
+        wmb();
+        self.issue(&[invalidate])
+    }
+
+    /// Command invalidating the TLB entries of a domain for the page at `iova`
+    fn tlbi_page(&self, tag: u16, iova: usize) -> [u64; 2] {
+        match self.stage {
+            Stage::One => [
+                CMD_TLBI_NH_VA | (tag as u64) << 48,
+                (iova & PAGE_MASK) as u64 | 1,
+            ],
+            Stage::Two => [CMD_TLBI_S12_VMALL | (tag as u64) << 32, 0],
+        }
+    }
+
+    /// Command invalidating every TLB entry of a domain
+    fn tlbi_domain(&self, tag: u16) -> [u64; 2] {
+        match self.stage {
+            Stage::One => [CMD_TLBI_NH_ASID | (tag as u64) << 48, 0],
+            Stage::Two => [CMD_TLBI_S12_VMALL | (tag as u64) << 32, 0],
+        }
+    }
+
+    /// Prints and consumes the pending events
+    fn drain_events(&mut self) {
+        let prod = self.read(EVENTQ_PROD);
+        self.evtq.prod = prod & self.evtq.mask();
+        while self.evtq.cons != self.evtq.prod {
+            let entry = (self.evtq.base + self.evtq.slot(self.evtq.cons) * 32) as *const [u64; 4];
+            let event = unsafe { entry.read_volatile() };
+            let id = event[0] & 0xff;
+            println!(
+                "smmuv3: {} ({:#x}) from stream {}, address {:#x}",
+                event_name(id),
+                id,
+                event[0] >> 32,
+                event[2]
+            );
+            self.evtq.cons = self.evtq.next(self.evtq.cons);
+        }
+        if prod & EVENTQ_OVERFLOW != 0 {
+            println!("smmuv3: event queue overflowed, events were lost");
+        }
+        self.write(EVENTQ_CONS, self.evtq.cons | (prod & EVENTQ_OVERFLOW));
+    }
+
+    /// Programs the tables and queues and enables translation
+    fn enable(&mut self) -> Result<(), IommuError> {
+        self.write_ack(CR0, CR0ACK, 0)?;
+        self.write(CR1, CR1_ATTRS);
+        self.write(CR2, CR2_RECINVSID);
+
+        self.write64(
+            STRTAB_BASE,
+            BASE_ALLOCATE | (self.strtab as u64 & STRTAB_ADDR_MASK),
+        );
+        // Linear format, `streams_log2` bits of stream ID
+        self.write(STRTAB_BASE_CFG, self.streams_log2);
+
+        self.write64(
+            CMDQ_BASE,
+            BASE_ALLOCATE | (self.cmdq.base as u64 & QUEUE_ADDR_MASK) | self.cmdq.log2 as u64,
+        );
+        self.write(CMDQ_PROD, 0);
+        self.write(CMDQ_CONS, 0);
+        self.write_ack(CR0, CR0ACK, CR0_CMDQEN)?;
+        // Nothing cached from before is valid
+        self.issue(&[[CMD_CFGI_ALL, 31], [CMD_TLBI_NSNH_ALL, 0]])?;
+
+        self.write64(
+            EVENTQ_BASE,
+            BASE_ALLOCATE | (self.evtq.base as u64 & QUEUE_ADDR_MASK) | self.evtq.log2 as u64,
+        );
+        self.write(EVENTQ_PROD, 0);
+        self.write(EVENTQ_CONS, 0);
+        self.write_ack(CR0, CR0ACK, CR0_CMDQEN | CR0_EVTQEN)?;
+        self.write_ack(IRQ_CTRL, IRQ_CTRLACK, IRQ_CTRL_EVTQ_IRQEN)?;
+
+        self.write_ack(CR0, CR0ACK, CR0_CMDQEN | CR0_EVTQEN | CR0_SMMUEN)
+    }
+}
+
+fn event_name(id: u64) -> &'static str {
+    match id {
+        0x01 => "unsupported upstream transaction",
+        0x02 => "bad stream ID",
+        0x04 => "bad STE",
+        0x0a => "bad CD",
+        0x10 => "translation fault",
+        0x11 => "address size fault",
+        0x12 => "access flag fault",
+        0x13 => "permission fault",
+        _ => "event",
+    }
+}
+
+/// Event queue interrupt handler: the events are printed from deferred work
+fn handle_irq(_id: u32, _data: usize) {
+    let _ = softirq::schedule_work(drain_events, 0);
+}
+
+fn drain_events(_arg: usize) {
+    SMMU.lock_irqsafe(|smmu| {
+        if let Some(smmu) = smmu {
+            smmu.drain_events();
+        }
+    });
+}
+
+/// Returns the INTID of the SPI named `name` in the `interrupt-names` of `dev`, configured in the
+/// GIC but not enabled, or 0 if there is none
+fn parse_named_irq(dev: &device::PlatformDevice, name: &str) -> u32 {
+    of_irq_parse_by_name(dev, name)
+        .filter(|spec| spec.kind == IrqKind::Spi)
+        .map_or(0, |spec| gic::configure_irq(&spec))
+}
+
+/// Returns true if the SMMU is enabled
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Driver for `arm,smmu-v3` nodes
+pub struct Smmuv3Driver;
+
+impl device::Driver for Smmuv3Driver {
+    /// Sets up the SMMU from device tree properties
+    ///
+    /// Records the base address from `reg` and the `eventq` interrupt; `init` does the rest.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let base = dev.reg(0).ok_or(ProbeError::NoDevice)?.base;
+        let irq_id = parse_named_irq(dev, "eventq");
+        let first =
+            FOUND.lock_irqsafe(|found| found.is_none() && found.replace((base, irq_id)).is_none());
+        if !first {
+            println!("smmuv3: only one SMMU is supported, ignoring {}", dev.name);
+            return Err(ProbeError::NoResources);
+        }
+        Ok(())
+    }
+}
+
+/// Allocates the stream table and the queues and enables the SMMU found by `setup`
+pub fn init() {
+    let Some((base, irq_id)) = FOUND.lock_irqsafe(|found| *found) else {
+        return;
+    };
+    let idr0 = mmio::read_mmio32(base, IDR0);
+    let idr1 = mmio::read_mmio32(base, IDR1);
+    let idr5 = mmio::read_mmio32(base, IDR5);
+    let stage = if idr0 & IDR0_S1P != 0 && idr0 & IDR0_TTF_AARCH64 != 0 {
+        Stage::One
+    } else if idr0 & IDR0_S2P != 0 {
+        Stage::Two
+    } else {
+        println!("smmuv3: no usable translation stage");
+        return;
+    };
+    if idr0 & IDR0_COHACC == 0 || idr5 & IDR5_GRAN4K == 0 {
+        println!("smmuv3: non-coherent or no 4 KiB granule, not supported");
+        return;
+    }
+    let streams_log2 = (idr1 & 0x3f).min(MAX_STREAMS_LOG2);
+    let cmdq_log2 = ((idr1 >> 21) & 0x1f).min(CMDQ_LOG2);
+    let evtq_log2 = ((idr1 >> 16) & 0x1f).min(EVTQ_LOG2);
+
+    // Stream table (aligned to its size), then a page for each queue
+    let strtab_frames = ((64usize << streams_log2).div_ceil(PAGE_SIZE)).next_power_of_two();
+    let frame_count = strtab_frames + 2;
+    let frames = match frame::alloc_frames_aligned(frame_count, strtab_frames) {
+        Ok(frames) => frames,
+        Err(e) => {
+            println!("smmuv3: no memory for the tables: {:?}", e);
+            return;
+        }
+    };
+    unsafe { core::ptr::write_bytes(frames as *mut u8, 0, frame_count * PAGE_SIZE) };
+    let mut smmu = Smmu {
+        base,
+        stage,
+        oas: ((idr5 & IDR5_OAS_MASK) as u64).min(OAS_48),
+        cmdq: Queue {
+            base: frames + strtab_frames * PAGE_SIZE,
+            log2: cmdq_log2,
+            prod: 0,
+            cons: 0,
+        },
+        evtq: Queue {
+            base: frames + (strtab_frames + 1) * PAGE_SIZE,
+            log2: evtq_log2,
+            prod: 0,
+            cons: 0,
+        },
+        strtab: frames,
+        streams_log2,
+        frames,
+        frame_count,
+    };
+    for sid in 0..1u32 << streams_log2 {
+        unsafe { (smmu.ste(sid) as *mut u64).write_volatile(STE_V | STE_CONFIG_ABORT) };
+    }
+    wmb();
+
+    if let Err(e) = smmu.enable() {
+        pr_err!("smmuv3: cannot enable: {:?}", e);
+        smmu.write(CR0, 0);
+        let _ = frame::free_frames(smmu.frames, smmu.frame_count);
+        return;
+    }
+    SMMU.lock_irqsafe(|s| *s = Some(smmu));
+    ENABLED.store(true, Ordering::Release);
+    if irq_id != 0 && irq::request_irq(irq_id, "smmuv3", handle_irq, 0).is_ok() {
+        gic::enable_irq(irq_id);
+    }
+    println!(
+        "smmuv3: enabled at {:#x}, stage {} translation, {} streams",
+        base,
+        if stage == Stage::One { 1 } else { 2 },
+        1u32 << streams_log2
+    );
+}
+
+/// A domain's page table and its tag
+struct Domain {
+    /// Physical address of the L0 table
+    l0: usize,
+    /// Context descriptor (stage 1 only)
+    cd: usize,
+    /// ASID (stage 1) or VMID (stage 2)
+    tag: u16,
+    /// Number of attached streams
+    streams: usize,
+}
+
+static DOMAINS: Mutex<[Option<Domain>; MAX_DOMAINS]> = Mutex::new([const { None }; MAX_DOMAINS]);
+
+/// Returns the table at physical address `addr`
+///
+/// # Safety
+/// `addr` must be a table page of a domain, with the `DOMAINS` lock held.
+unsafe fn table_at(addr: usize) -> &'static mut [Pte; ENTRIES] {
+    unsafe { &mut *(addr as *mut [Pte; ENTRIES]) }
+}
+
+fn table_index(iova: usize, level: usize) -> usize {
+    (iova >> (39 - 9 * level)) & (ENTRIES - 1)
+}
+
+impl Domain {
+    /// Returns the L3 entry translating `iova`, allocating missing tables if `alloc` is true
+    fn walk(&mut self, iova: usize, alloc: bool) -> Result<&'static mut Pte, IommuError> {
+        if iova >= IOVA_END {
+            return Err(IommuError::BadAddress);
+        }
+        let mut table = self.l0;
+        for level in 0..3 {
+            let entry = &mut unsafe { table_at(table) }[table_index(iova, level)];
+            if *entry & PTE_VALID == 0 {
+                if !alloc {
+                    return Err(IommuError::NotMapped);
+                }
+                let next = frame::alloc_zeroed_frames(1).map_err(|_| IommuError::NoMemory)?;
+                mark_table_desc(entry);
+                set_next_lvl_table_addr(entry, next as *const u64);
+            }
+            table = (*entry & PTE_ADDR_MASK) as usize;
+        }
+        Ok(&mut unsafe { table_at(table) }[table_index(iova, 3)])
+    }
+
+    /// Drops one mapping of the page at `iova`, returning true if the entry was cleared
+    fn put_page(&mut self, iova: usize) -> Result<bool, IommuError> {
+        let entry = self.walk(iova, false)?;
+        if *entry & PTE_VALID == 0 {
+            return Err(IommuError::NotMapped);
+        }
+        let count = (*entry & PTE_COUNT_MASK) >> PTE_COUNT_SHIFT;
+        if count > 1 {
+            *entry = (*entry & !PTE_COUNT_MASK) | (count - 1) << PTE_COUNT_SHIFT;
+            return Ok(false);
+        }
+        *entry = 0;
+        Ok(true)
+    }
+}
+
+/// Builds the page descriptor mapping `pa` with `flags`, counting one mapping
+fn page_pte(stage: Stage, pa: usize, flags: MapFlags) -> Pte {
+    let mut pte: Pte = 0;
+    mark_page_desc(&mut pte);
+    set_next_lvl_table_addr(&mut pte, pa as *const u64);
+    pte |= DESC_AF | DESC_SH_INNER | 1 << PTE_COUNT_SHIFT;
+    let writable = flags.contains(MapFlags::WRITE);
+    match stage {
+        Stage::One => {
+            set_mair_range(&mut pte, MAIR_IDX_NORMAL_WB as u64);
+            let ap = if writable {
+                DESC_AP_RW_ALL
+            } else {
+                DESC_AP_RO_ALL
+            };
+            pte |= ap | DESC_NG | DESC_UXN | DESC_PXN;
+        }
+        Stage::Two => {
+            let ap = if writable {
+                S2_AP_READ | S2_AP_WRITE
+            } else {
+                S2_AP_READ
+            };
+            pte |= S2_MEMATTR_WB | ap | S2_XN;
+        }
+    }
+    pte
+}
+
+fn pte_writable(stage: Stage, pte: Pte) -> bool {
+    match stage {
+        Stage::One => pte & (0b11 << 6) == DESC_AP_RW_ALL,
+        Stage::Two => pte & S2_AP_WRITE != 0,
+    }
+}
+
+/// Frees the tables below the `level` table at `table`
+fn free_tables(table: usize, level: usize) {
+    if level < 3 {
+        for &entry in unsafe { table_at(table) }
+            .iter()
+            .filter(|e| **e & PTE_VALID != 0)
+        {
+            free_tables((entry & PTE_ADDR_MASK) as usize, level + 1);
+        }
+    }
+    let _ = frame::free_frame(table);
+}
+
+/// Reads `MAIR_EL1`, so stage 1 descriptors use the same attribute indexes as the CPU's
+fn read_mair() -> u64 {
+    let mair: u64;
+    unsafe {
+        asm!("mrs {}, mair_el1", out(reg) mair, options(nostack, nomem, preserves_flags));
+    }
+    mair
+}
+
+/// An I/O address space: the streams attached to it translate through its page table
+///
+/// A handle on a slot of the domain table; it must be `free`d explicitly.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IommuDomain(usize);
+
+impl IommuDomain {
+    /// Creates a domain with nothing mapped
+    pub fn new() -> Result<Self, IommuError> {
+        let (stage, oas) = SMMU
+            .lock_irqsafe(|smmu| smmu.as_ref().map(|s| (s.stage, s.oas)))
+            .ok_or(IommuError::NoDevice)?;
+        DOMAINS.lock_irqsafe(|domains| {
+            let index = domains
+                .iter()
+                .position(|d| d.is_none())
+                .ok_or(IommuError::NoSpace)?;
+            let l0 = frame::alloc_zeroed_frames(1).map_err(|_| IommuError::NoMemory)?;
+            let tag = index as u16 + 1;
+            let mut cd = 0;
+            if stage == Stage::One {
+                cd = match frame::alloc_zeroed_frames(1) {
+                    Ok(cd) => cd,
+                    Err(_) => {
+                        let _ = frame::free_frame(l0);
+                        return Err(IommuError::NoMemory);
+                    }
+                };
+                let words = cd as *mut u64;
+                unsafe {
+                    words.add(1).write_volatile(l0 as u64 & TTB_ADDR_MASK);
+                    words.add(3).write_volatile(read_mair());
+                    words.write_volatile(
+                        CD_0_ATTRS | oas << CD_0_IPS_SHIFT | (tag as u64) << CD_0_ASID_SHIFT,
+                    );
+                }
+            }
+            domains[index] = Some(Domain {
+                l0,
+                cd,
+                tag,
+                streams: 0,
+            });
+            Ok(Self(index))
+        })
+    }
+
+    /// Runs `f` on the domain and the SMMU
+    fn with<R>(
+        self,
+        f: impl FnOnce(&mut Domain, &mut Smmu) -> Result<R, IommuError>,
+    ) -> Result<R, IommuError> {
+        DOMAINS.lock_irqsafe(|domains| {
+            let domain = domains[self.0].as_mut().ok_or(IommuError::NotMapped)?;
+            SMMU.lock_irqsafe(|smmu| {
+                let smmu = smmu.as_mut().ok_or(IommuError::NoDevice)?;
+                f(domain, smmu)
+            })
+        })
+    }
+
+    /// Makes the DMA of stream `sid` translate through this domain
+    pub fn attach(self, sid: u32) -> Result<(), IommuError> {
+        self.with(|domain, smmu| {
+            if sid >= 1 << smmu.streams_log2 {
+                return Err(IommuError::BadAddress);
+            }
+            let current = unsafe { smmu.ste(sid).read_volatile() };
+            if current[0] & STE_CONFIG_MASK != STE_CONFIG_ABORT {
+                return Err(IommuError::Exists);
+            }
+            let mut words = [0u64; 8];
+            words[1] = STE_1_ATTRS;
+            match smmu.stage {
+                Stage::One => {
+                    words[0] = STE_V | STE_CONFIG_S1 | (domain.cd as u64 & STRTAB_ADDR_MASK);
+                }
+                Stage::Two => {
+                    words[0] = STE_V | STE_CONFIG_S2;
+                    words[2] = domain.tag as u64
+                        | (STE_VTCR | smmu.oas << STE_VTCR_PS_SHIFT) << 32
+                        | STE_2_S2AA64
+                        | STE_2_S2R;
+                    words[3] = domain.l0 as u64 & TTB_ADDR_MASK;
+                }
+            }
+            smmu.write_ste(sid, words)?;
+            domain.streams += 1;
+            Ok(())
+        })
+    }
+
+    /// Aborts the DMA of stream `sid` again
+    pub fn detach(self, sid: u32) -> Result<(), IommuError> {
+        self.with(|domain, smmu| {
+            if sid >= 1 << smmu.streams_log2 {
+                return Err(IommuError::BadAddress);
+            }
+            let current = unsafe { smmu.ste(sid).read_volatile() };
+            let ours = match (smmu.stage, current[0] & STE_CONFIG_MASK) {
+                (Stage::One, STE_CONFIG_S1) => {
+                    current[0] & STRTAB_ADDR_MASK == domain.cd as u64 & STRTAB_ADDR_MASK
+                }
+                (Stage::Two, STE_CONFIG_S2) => current[2] & STE_2_VMID_MASK == domain.tag as u64,
+                _ => false,
+            };
+            if !ours {
+                return Err(IommuError::NotMapped);
+            }
+            let mut words = [0u64; 8];
+            words[0] = STE_V | STE_CONFIG_ABORT;
+            smmu.write_ste(sid, words)?;
+            domain.streams -= 1;
+            Ok(())
+        })
+    }
+
+    /// Maps `[pa, pa + len)` at `iova` for the attached devices, with the `READ`/`WRITE`
+    /// permissions of `flags`
+    ///
+    /// The range is extended to whole pages. A page already mapped to the same frame is counted
+    /// once more (and made writable if needed); one mapped elsewhere fails the call with `Exists`,
+    /// leaving the domain unchanged.
+    pub fn map(
+        self,
+        iova: usize,
+        pa: usize,
+        len: usize,
+        flags: MapFlags,
+    ) -> Result<(), IommuError> {
+        if (iova ^ pa) & !PAGE_MASK != 0 || len == 0 || iova.saturating_add(len) > IOVA_END {
+            return Err(IommuError::BadAddress);
+        }
+        let first = iova & PAGE_MASK;
+        let pages = (iova + len - first).div_ceil(PAGE_SIZE);
+        let pa = pa & PAGE_MASK;
+        self.with(|domain, smmu| {
+            let mut flush = false;
+            for i in 0..pages {
+                let (iova, pa) = (first + i * PAGE_SIZE, pa + i * PAGE_SIZE);
+                let result = domain.walk(iova, true).and_then(|entry| {
+                    if *entry & PTE_VALID == 0 {
+                        *entry = page_pte(smmu.stage, pa, flags);
+                        return Ok(());
+                    }
+                    let count = (*entry & PTE_COUNT_MASK) >> PTE_COUNT_SHIFT;
+                    if (*entry & PTE_ADDR_MASK) as usize != pa || count == PTE_COUNT_MAX {
+                        return Err(IommuError::Exists);
+                    }
+                    let mut pte = *entry;
+                    if flags.contains(MapFlags::WRITE) && !pte_writable(smmu.stage, pte) {
+                        pte = page_pte(smmu.stage, pa, flags);
+                        flush = true;
+                    }
+                    *entry = (pte & !PTE_COUNT_MASK) | (count + 1) << PTE_COUNT_SHIFT;
+                    Ok(())
+                });
+                if let Err(e) = result {
+                    for j in 0..i {
+                        let _ = domain.put_page(first + j * PAGE_SIZE);
+                    }
+                    wmb();
+                    // Pages made writable keep the permission, harmlessly
+                    if flush {
+                        let _ = smmu.issue(&[smmu.tlbi_domain(domain.tag)]);
+                    }
+                    return Err(e);
+                }
+            }
+            wmb();
+            if flush {
+                smmu.issue(&[smmu.tlbi_domain(domain.tag)])?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Undoes a `map` of `[iova, iova + len)`, once the devices are done with it
+    pub fn unmap(self, iova: usize, len: usize) -> Result<(), IommuError> {
+        if len == 0 || iova.saturating_add(len) > IOVA_END {
+            return Err(IommuError::BadAddress);
+        }
+        let first = iova & PAGE_MASK;
+        let pages = (iova + len - first).div_ceil(PAGE_SIZE);
+        self.with(|domain, smmu| {
+            let mut result = Ok(());
+            for i in 0..pages {
+                let iova = first + i * PAGE_SIZE;
+                match domain.put_page(iova) {
+                    Ok(true) => {
+                        wmb();
+                        let cmd = smmu.tlbi_page(domain.tag, iova);
+                        result = result.and(smmu.submit(cmd));
+                    }
+                    Ok(false) => {}
+                    Err(e) => result = result.and(Err(e)),
+                }
+            }
+            result.and(smmu.sync())
+        })
+    }
+
+    /// Returns the physical address `iova` translates to
+    pub fn iova_to_phys(self, iova: usize) -> Option<usize> {
+        DOMAINS.lock_irqsafe(|domains| {
+            let domain = domains[self.0].as_mut()?;
+            let pte = *domain.walk(iova & PAGE_MASK, false).ok()?;
+            (pte & PTE_VALID != 0).then(|| (pte & PTE_ADDR_MASK) as usize + (iova & !PAGE_MASK))
+        })
+    }
+
+    /// Frees the domain and its tables; every stream must have been detached
+    pub fn free(self) -> Result<(), IommuError> {
+        self.with(|domain, smmu| {
+            if domain.streams != 0 {
+                return Err(IommuError::Busy);
+            }
+            smmu.issue(&[smmu.tlbi_domain(domain.tag)])
+        })?;
+        DOMAINS.lock_irqsafe(|domains| {
+            if let Some(domain) = domains[self.0].take() {
+                free_tables(domain.l0, 0);
+                if domain.cd != 0 {
+                    let _ = frame::free_frame(domain.cd);
+                }
+            }
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+//! BCM2835 mailbox and VideoCore property interface
+//!
+//! On the Raspberry Pi the VideoCore GPU boots first and keeps running the firmware, which owns
+//! clocks, power domains and the display. The ARM cores reach it through the mailbox peripheral
+//! (`brcm,bcm2835-mbox`): a word written to mailbox 1 carries the bus address of a message in
+//! its upper 28 bits and a channel number in the lower 4, and the firmware answers on mailbox 0
+//! with the same word once it has rewritten the message in place.
+//!
+//! Only the property channel (8) is used. A property message is a list of tags, each with an
+//! identifier, the size of its value buffer and the request values, which the firmware replaces
+//! with the response. `Message` builds one and `call` sends it; `firmware_revision`,
+//! `arm_memory`, `clock_rate`, `temperature`, `voltage` and `allocate_framebuffer` wrap the
+//! common requests. The SoC temperature, the core voltage and the ARM and core clocks are
+//! registered as sensors.
+//!
+//! ## Design
+//!
+//! - The message is copied to a page from `dma::alloc_coherent`, allocated by the probe: the
+//!   firmware reads and writes RAM behind the ARM caches. The VideoCore only sees the first GiB,
+//!   through the uncached `0xC000_0000` alias.
+//! - Both mailboxes are polled, with a timeout. Calls are serialized by a `Mutex`, as the single
+//!   buffer is reused; they must not be made from interrupt handlers.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux splits this between the mailbox controller (`bcm2835-mailbox.c`, interrupt driven, in
+//! the generic mailbox framework) and the firmware client (`raspberrypi.c`), whose
+//! `rpi_firmware_property_list` is the equivalent of `call`. The clock, power domain and
+//! framebuffer drivers then sit on top of the client.
+
+use core::arch::asm;
+
+use crate::ipc::init_cell::InitCell;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::mm::bits::SZ_1G;
+use crate::kernel::mm::dma::{self, DmaBuffer};
+use crate::kernel::notifier::Deadline;
+use crate::kernel::sensor::{self, SensorError, SensorKind};
+use crate::utilities::mmio;
+use crate::{pr_warn, println};
+
+/* --- Mailbox Register Constants --- */
+/// Mailbox 0 (VideoCore to ARM) read register
+const MBOX0_READ: usize = 0x00;
+/// Mailbox 0 status register
+const MBOX0_STATUS: usize = 0x18;
+/// Mailbox 1 (ARM to VideoCore) write register
+const MBOX1_WRITE: usize = 0x20;
+/// Mailbox 1 status register
+const MBOX1_STATUS: usize = 0x38;
+const STATUS_FULL: u32 = 1 << 31;
+const STATUS_EMPTY: u32 = 1 << 30;
+
+/// Property channel, ARM to VideoCore
+const CHANNEL_PROPERTY: u32 = 8;
+/// Channel number bits of a mailbox word
+const CHANNEL_MASK: u32 = 0xf;
+
+/// Alias of the first GiB of RAM, uncached, in the VideoCore's bus address space
+const BUS_ALIAS: u32 = 0xc000_0000;
+
+/// Time the firmware has to answer
+const TIMEOUT_MS: u32 = 100;
+
+/* --- Property Message Constants --- */
+/// Maximum size of a property message, in 32-bit words
+pub const MAX_WORDS: usize = 64;
+/// Words of the message header: total size and request/response code
+const HEADER_WORDS: usize = 2;
+/// Words of a tag header: identifier, value buffer size and request/response code
+const TAG_HEADER_WORDS: usize = 3;
+/// Message code of a request
+const CODE_REQUEST: u32 = 0;
+/// Message code of a successful response
+const CODE_SUCCESS: u32 = 0x8000_0000;
+/// Set in a tag's code once the firmware has answered it, along with the response length
+const TAG_RESPONSE: u32 = 1 << 31;
+/// Identifier of the tag ending a message
+const TAG_END: u32 = 0;
+
+/* --- Property Tags --- */
+pub const TAG_FIRMWARE_REVISION: u32 = 0x0000_0001;
+pub const TAG_ARM_MEMORY: u32 = 0x0001_0005;
+pub const TAG_CLOCK_RATE: u32 = 0x0003_0002;
+pub const TAG_VOLTAGE: u32 = 0x0003_0003;
+pub const TAG_TEMPERATURE: u32 = 0x0003_0006;
+pub const TAG_ALLOCATE_BUFFER: u32 = 0x0004_0001;
+pub const TAG_GET_PITCH: u32 = 0x0004_0008;
+pub const TAG_SET_PHYSICAL_SIZE: u32 = 0x0004_8003;
+pub const TAG_SET_VIRTUAL_SIZE: u32 = 0x0004_8004;
+pub const TAG_SET_DEPTH: u32 = 0x0004_8005;
+pub const TAG_SET_PIXEL_ORDER: u32 = 0x0004_8006;
+
+/// Pixel order of `TAG_SET_PIXEL_ORDER`: red in the low byte
+const PIXEL_ORDER_RGB: u32 = 1;
+
+/// Errors returned by the mailbox
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MboxError {
+    /// No mailbox was found in the DTB
+    NoDevice,
+    /// The firmware didn't answer in time
+    Timeout,
+    /// The message doesn't fit in `MAX_WORDS`
+    TooLarge,
+    /// The firmware rejected the message, or didn't answer a tag
+    Failed,
+}
+
+/// Clocks managed by the firmware, as numbered by `TAG_CLOCK_RATE`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum Clock {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    Core = 4,
+    V3d = 5,
+    H264 = 6,
+    Isp = 7,
+    Sdram = 8,
+    Pixel = 9,
+    Pwm = 10,
+    Emmc2 = 12,
+}
+
+/// Voltages managed by the firmware, as numbered by `TAG_VOLTAGE`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum Voltage {
+    Core = 1,
+    SdramC = 2,
+    SdramP = 3,
+    SdramI = 4,
+}
+
+/// Sensors registered by the probe, `(name, kind, reading)`
+const SENSORS: [(&str, SensorKind, Reading); 4] = [
+    ("soc-temp", SensorKind::Temperature, Reading::Temperature),
+    (
+        "core-volt",
+        SensorKind::Voltage,
+        Reading::Voltage(Voltage::Core),
+    ),
+    ("arm-clk", SensorKind::Clock, Reading::Clock(Clock::Arm)),
+    ("core-clk", SensorKind::Clock, Reading::Clock(Clock::Core)),
+];
+
+/// What a firmware sensor reads
+#[derive(Clone, Copy)]
+enum Reading {
+    Temperature,
+    Voltage(Voltage),
+    Clock(Clock),
+}
+
+/// A framebuffer allocated by the firmware
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    /// Physical address, as seen by the ARM cores
+    pub addr: usize,
+    /// Size in bytes
+    pub size: usize,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel
+    pub depth: u32,
+    /// Bytes per line
+    pub pitch: u32,
+}
+
+/// A property message, built tag by tag
+pub struct Message {
+    /// Header and tags, without the end tag
+    words: [u32; MAX_WORDS],
+    /// Words used in `words`
+    len: usize,
+}
+
+/// Position of a tag in a `Message`, returned by `Message::add_tag`
+#[derive(Clone, Copy, Debug)]
+pub struct TagIndex(usize);
+
+impl Message {
+    /// Creates an empty message
+    pub const fn new() -> Self {
+        Self {
+            words: [0; MAX_WORDS],
+            len: HEADER_WORDS,
+        }
+    }
+
+    /// Appends the tag `id` with the values of `request`, leaving room for a response of
+    /// `response_words` words
+    pub fn add_tag(
+        &mut self,
+        id: u32,
+        request: &[u32],
+        response_words: usize,
+    ) -> Result<TagIndex, MboxError> {
+        let size = request.len().max(response_words);
+        let start = self.len + TAG_HEADER_WORDS;
+        // One word is kept for the end tag
+        if start + size >= MAX_WORDS {
+            return Err(MboxError::TooLarge);
+        }
+        self.words[self.len] = id;
+        self.words[self.len + 1] = (size * 4) as u32;
+        self.words[self.len + 2] = CODE_REQUEST;
+        self.words[start..start + request.len()].copy_from_slice(request);
+        self.words[start + request.len()..start + size].fill(0);
+        self.len = start + size;
+        Ok(TagIndex(self.len - size - TAG_HEADER_WORDS))
+    }
+
+    /// Returns the response values of the tag at `tag`, `None` if the firmware didn't answer it
+    pub fn response(&self, tag: TagIndex) -> Option<&[u32]> {
+        let size = self.words[tag.0 + 1] as usize / 4;
+        let code = self.words[tag.0 + 2];
+        if code & TAG_RESPONSE == 0 {
+            return None;
+        }
+        let len = ((code & !TAG_RESPONSE) as usize).div_ceil(4).min(size);
+        let start = tag.0 + TAG_HEADER_WORDS;
+        Some(&self.words[start..start + len])
+    }
+}
+
+impl Default for Message {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A mailbox and its message buffer
+struct Mbox {
+    /// Base address of the mailbox registers
+    base: usize,
+    /// Buffer the messages are exchanged in
+    buf: DmaBuffer,
+    /// Serializes the users of `buf`
+    lock: Mutex<()>,
+}
+
+/// The mailbox, set by the driver's probe
+static MBOX: InitCell<Mbox> = InitCell::new();
+
+impl Mbox {
+    /// Writes the words of `msg` to the buffer, sends it and copies the response back
+    fn call(&self, msg: &mut Message) -> Result<(), MboxError> {
+        self.lock.lock(|_| {
+            let buf = self.buf.vaddr as *mut u32;
+            msg.words[0] = ((msg.len + 1) * 4) as u32;
+            msg.words[1] = CODE_REQUEST;
+            for (i, &word) in msg.words[..msg.len].iter().enumerate() {
+                unsafe { buf.add(i).write_volatile(word) };
+            }
+            unsafe { buf.add(msg.len).write_volatile(TAG_END) };
+            // The message must be in RAM before the firmware is told about it
+            unsafe { asm!("dsb sy", options(nostack)) };
+            let word = (self.buf.paddr as u32 | BUS_ALIAS) | CHANNEL_PROPERTY;
+            self.send(word)?;
+            self.receive(word)?;
+            unsafe { asm!("dsb sy", options(nostack)) };
+            for (i, word) in msg.words[..msg.len].iter_mut().enumerate() {
+                *word = unsafe { buf.add(i).read_volatile() };
+            }
+            if msg.words[1] != CODE_SUCCESS {
+                return Err(MboxError::Failed);
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes `word` to mailbox 1 once it has room
+    fn send(&self, word: u32) -> Result<(), MboxError> {
+        let deadline = Deadline::from_ms(TIMEOUT_MS);
+        while mmio::read_mmio32(self.base, MBOX1_STATUS) & STATUS_FULL != 0 {
+            if deadline.expired() {
+                return Err(MboxError::Timeout);
+            }
+        }
+        mmio::write_mmio32(self.base, MBOX1_WRITE, word);
+        Ok(())
+    }
+
+    /// Waits for the answer to `word` on mailbox 0
+    ///
+    /// Words for other channels are dropped: nothing else is ever sent.
+    fn receive(&self, word: u32) -> Result<(), MboxError> {
+        let deadline = Deadline::from_ms(TIMEOUT_MS);
+        loop {
+            while mmio::read_mmio32(self.base, MBOX0_STATUS) & STATUS_EMPTY != 0 {
+                if deadline.expired() {
+                    return Err(MboxError::Timeout);
+                }
+            }
+            let answer = mmio::read_mmio32(self.base, MBOX0_READ);
+            if answer == word {
+                return Ok(());
+            }
+            if answer & CHANNEL_MASK == CHANNEL_PROPERTY {
+                pr_warn!("mbox: unexpected answer {:#x}", answer);
+            }
+        }
+    }
+}
+
+/// Sends `msg` to the firmware on the property channel and waits for its response
+///
+/// The response values are then read with `Message::response`.
+pub fn call(msg: &mut Message) -> Result<(), MboxError> {
+    MBOX.get().ok_or(MboxError::NoDevice)?.call(msg)
+}
+
+/// Sends a message with the single tag `id` and returns its first `N` response values
+fn query<const N: usize>(id: u32, request: &[u32]) -> Result<[u32; N], MboxError> {
+    let mut msg = Message::new();
+    let tag = msg.add_tag(id, request, N)?;
+    call(&mut msg)?;
+    let response = msg.response(tag).ok_or(MboxError::Failed)?;
+    response
+        .get(..N)
+        .and_then(|values| values.try_into().ok())
+        .ok_or(MboxError::Failed)
+}
+
+/// Returns the revision of the VideoCore firmware, its build date as a Unix timestamp
+pub fn firmware_revision() -> Result<u32, MboxError> {
+    query::<1>(TAG_FIRMWARE_REVISION, &[]).map(|[revision]| revision)
+}
+
+/// Returns the `(base, size)` of the RAM left to the ARM cores by the firmware
+pub fn arm_memory() -> Result<(usize, usize), MboxError> {
+    query::<2>(TAG_ARM_MEMORY, &[]).map(|[base, size]| (base as usize, size as usize))
+}
+
+/// Returns the rate of `clock` in Hz, 0 if the clock doesn't exist
+pub fn clock_rate(clock: Clock) -> Result<u32, MboxError> {
+    query::<2>(TAG_CLOCK_RATE, &[clock as u32]).map(|[_, rate]| rate)
+}
+
+/// Returns the temperature of the SoC in millidegrees Celsius
+pub fn temperature() -> Result<u32, MboxError> {
+    query::<2>(TAG_TEMPERATURE, &[0]).map(|[_, temp]| temp)
+}
+
+/// Returns `voltage` in microvolts
+pub fn voltage(voltage: Voltage) -> Result<u32, MboxError> {
+    query::<2>(TAG_VOLTAGE, &[voltage as u32]).map(|[_, uv]| uv)
+}
+
+/// Read function of the firmware sensors, `data` being the index in `SENSORS`
+fn read_sensor(data: usize) -> Result<i64, SensorError> {
+    let value = match SENSORS[data].2 {
+        Reading::Temperature => temperature(),
+        Reading::Voltage(id) => voltage(id),
+        Reading::Clock(id) => clock_rate(id),
+    };
+    value
+        .map(|v| v as i64)
+        .map_err(|_| SensorError::Unavailable)
+}
+
+/// Has the firmware allocate a `width` x `height` framebuffer of `depth` bits per pixel
+///
+/// The firmware may pick other dimensions or depth, which the returned `Framebuffer` reports.
+pub fn allocate_framebuffer(width: u32, height: u32, depth: u32) -> Result<Framebuffer, MboxError> {
+    let mut msg = Message::new();
+    let physical = msg.add_tag(TAG_SET_PHYSICAL_SIZE, &[width, height], 2)?;
+    msg.add_tag(TAG_SET_VIRTUAL_SIZE, &[width, height], 2)?;
+    let bpp = msg.add_tag(TAG_SET_DEPTH, &[depth], 1)?;
+    msg.add_tag(TAG_SET_PIXEL_ORDER, &[PIXEL_ORDER_RGB], 1)?;
+    let buffer = msg.add_tag(TAG_ALLOCATE_BUFFER, &[4096], 2)?;
+    let pitch = msg.add_tag(TAG_GET_PITCH, &[], 1)?;
+    call(&mut msg)?;
+    let value = |tag, index: usize| {
+        msg.response(tag)
+            .and_then(|values| values.get(index).copied())
+            .ok_or(MboxError::Failed)
+    };
+    let addr = value(buffer, 0)?;
+    if addr == 0 {
+        return Err(MboxError::Failed);
+    }
+    Ok(Framebuffer {
+        addr: (addr & !BUS_ALIAS) as usize,
+        size: value(buffer, 1)? as usize,
+        width: value(physical, 0)?,
+        height: value(physical, 1)?,
+        depth: value(bpp, 0)?,
+        pitch: value(pitch, 0)?,
+    })
+}
+
+/// Driver for `brcm,bcm2835-mbox` nodes
+pub struct Bcm2835MboxDriver;
+
+impl device::Driver for Bcm2835MboxDriver {
+    /// Sets up the mailbox from its `reg` property and allocates the message buffer
+    ///
+    /// Then asks the firmware for its revision, so a firmware that doesn't answer shows up at
+    /// boot rather than when a driver first needs it.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let base = dev.reg(0).ok_or(ProbeError::NoDevice)?.base;
+        let buf = dma::alloc_coherent(MAX_WORDS * 4).map_err(|_| ProbeError::NoResources)?;
+        if buf.paddr + buf.size > SZ_1G {
+            let _ = dma::free_coherent(buf);
+            return Err(ProbeError::NoResources);
+        }
+        let mbox = Mbox {
+            base,
+            buf,
+            lock: Mutex::new(()),
+        };
+        if let Err(mbox) = MBOX.set(mbox) {
+            let _ = dma::free_coherent(mbox.buf);
+            return Err(ProbeError::NotSupported);
+        }
+        match firmware_revision() {
+            Ok(revision) => println!("mbox: VideoCore firmware revision {:#x}", revision),
+            Err(e) => {
+                pr_warn!("mbox: no answer from the firmware: {:?}", e);
+                return Ok(());
+            }
+        }
+        for (index, (name, kind, _)) in SENSORS.iter().enumerate() {
+            if let Err(e) = sensor::register(name, *kind, read_sensor, index) {
+                pr_warn!("mbox: cannot register sensor {}: {:?}", name, e);
+            }
+        }
+        Ok(())
+    }
+}
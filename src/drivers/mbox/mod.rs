@@ -0,0 +1,8 @@
+//! Mailbox drivers
+//!
+//! A mailbox is a hardware FIFO pair for exchanging words with another processor, typically the
+//! one running the platform firmware. `bcm2835`, built with the `bcm2835-mbox` feature, talks to
+//! the VideoCore firmware of the Raspberry Pi.
+
+#[cfg(feature = "bcm2835-mbox")]
+pub mod bcm2835;
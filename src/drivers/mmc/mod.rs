@@ -0,0 +1,8 @@
+//! SD/MMC host controller drivers
+//!
+//! A host controller drives the command, clock and data lines of an SD card slot. `sdhci`
+//! supports controllers following the SD Host Controller specification, the register interface
+//! most SoCs implement (the Raspberry Pi 4's `emmc2` among them). The card found in the slot is
+//! registered as a block device.
+
+pub mod sdhci;
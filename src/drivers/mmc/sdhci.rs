@@ -0,0 +1,701 @@
+//! SD Host Controller Interface (SDHCI) driver
+//!
+//! An SDHCI controller sends SD commands with an argument register and a command register, and
+//! moves data blocks through a 32-bit buffer port or by DMA. The probe identifies the card in
+//! the slot and registers it as a block device named `mmcblk0`, `mmcblk1`, ... in probe order.
+//!
+//! Identification follows the SD Physical Layer specification:
+//!
+//! 1. `CMD0` resets the card, and `CMD8` tells version 2.00 cards (which answer) from older ones.
+//! 2. `ACMD41` is repeated until the card leaves its busy state, and reports whether it is a
+//!    high capacity card (SDHC/SDXC, addressed in sectors) or a standard one (addressed in
+//!    bytes).
+//! 3. `CMD2` and `CMD3` get the card identity and its relative address (RCA), `CMD9` the CSD
+//!    register the capacity is computed from, and `CMD7` selects the card for data transfers.
+//! 4. The bus goes from 400 kHz and one data line to 25 MHz and four lines, if the slot has them.
+//!
+//! ## Design
+//!
+//! - Data goes through the buffer port (PIO): no DMA descriptors to set up, no cache maintenance
+//!   nor bus address limits, at the cost of the CPU copying every word. Reads use `CMD17` or
+//!   `CMD18` and writes `CMD24` or `CMD25`, the controller ending multi-block transfers with an
+//!   automatic `CMD12`.
+//! - The controller is polled during identification. Once the card is registered, the interrupt
+//!   (if the node has one) takes over: the handler acknowledges the status bits and wakes up the
+//!   task waiting for them, which otherwise polls the status register itself.
+//! - Every register is accessed as a 32-bit word, as the BCM2835 family requires; the transfer
+//!   mode and the command, which share a word, are always written together.
+//! - The base clock comes from `clocks`, from the capabilities register or, on the Raspberry Pi,
+//!   from the firmware.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux splits this between the MMC core (`drivers/mmc/core`), which knows the SD, MMC and SDIO
+//! protocols, and host drivers such as `sdhci.c` with its many quirk flags and platform glue
+//! (`sdhci-iproc.c` for the Raspberry Pi 4). It uses ADMA, asynchronous requests, UHS-I speed
+//! modes and card hotplug, none of which exist here: the card must be present at probe time.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use crate::drivers::gic;
+use crate::ipc::init_cell::InitCell;
+use crate::ipc::semaphore::Semaphore;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::kernel::clk::{self, ClkError};
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::irq;
+use crate::kernel::irq::of::{IrqKind, of_irq_parse};
+use crate::kernel::notifier::Deadline;
+use crate::utilities::mmio;
+use crate::{pr_err, pr_warn, println};
+
+/// Maximum number of controllers the driver can manage
+const MAX_HOSTS: usize = 2;
+
+/// Block device names given to the cards, in probe order
+const DISK_NAMES: [&str; MAX_HOSTS] = ["mmcblk0", "mmcblk1"];
+
+/* --- SDHCI Register Constants --- */
+/// Block size (low half) and block count (high half)
+const BLKSIZE_BLKCNT: usize = 0x04;
+const ARGUMENT: usize = 0x08;
+/// Transfer mode (low half) and command (high half); writing the command sends it
+const XFER_CMD: usize = 0x0c;
+const XFER_BLOCK_COUNT_EN: u32 = 1 << 1;
+const XFER_AUTO_CMD12: u32 = 1 << 2;
+const XFER_READ: u32 = 1 << 4;
+const XFER_MULTI: u32 = 1 << 5;
+const CMD_RESP_136: u32 = 1 << 16;
+const CMD_RESP_48: u32 = 2 << 16;
+const CMD_RESP_48_BUSY: u32 = 3 << 16;
+const CMD_CRC_CHECK: u32 = 1 << 19;
+const CMD_INDEX_CHECK: u32 = 1 << 20;
+const CMD_DATA: u32 = 1 << 21;
+const CMD_INDEX_SHIFT: u32 = 24;
+/// Four response words, `RESPONSE + 4 * i`
+const RESPONSE: usize = 0x10;
+/// Buffer data port
+const BUFFER: usize = 0x20;
+const PRESENT_STATE: usize = 0x24;
+const PRESENT_CMD_INHIBIT: u32 = 1 << 0;
+const PRESENT_DAT_INHIBIT: u32 = 1 << 1;
+/// Host control (byte 0) and power control (byte 1)
+const HOST_CTRL: usize = 0x28;
+const HOST_CTRL_4BIT: u32 = 1 << 1;
+/// Bus power on, at 3.3 V
+const POWER_ON_3V3: u32 = 0x0f << 8;
+/// Clock control (low half), data timeout (byte 2) and software reset (byte 3)
+const CLOCK_CTRL: usize = 0x2c;
+const CLOCK_INT_EN: u32 = 1 << 0;
+const CLOCK_INT_STABLE: u32 = 1 << 1;
+const CLOCK_SD_EN: u32 = 1 << 2;
+/// Largest data timeout, 2^27 cycles of the timeout clock
+const CLOCK_TIMEOUT_MAX: u32 = 0xe << 16;
+const RESET_ALL: u32 = 1 << 24;
+const RESET_CMD: u32 = 1 << 25;
+const RESET_DAT: u32 = 1 << 26;
+/// Normal (low half) and error (high half) interrupt status, write 1 to clear
+const INT_STATUS: usize = 0x30;
+const INT_STATUS_ENABLE: usize = 0x34;
+const INT_SIGNAL_ENABLE: usize = 0x38;
+const INT_CMD_COMPLETE: u32 = 1 << 0;
+const INT_XFER_COMPLETE: u32 = 1 << 1;
+const INT_BUF_WRITE_READY: u32 = 1 << 4;
+const INT_BUF_READ_READY: u32 = 1 << 5;
+const INT_CMD_TIMEOUT: u32 = 1 << 16;
+const INT_CMD_CRC: u32 = 1 << 17;
+const INT_DATA_TIMEOUT: u32 = 1 << 20;
+const INT_DATA_CRC: u32 = 1 << 21;
+/// Every error status bit
+const INT_ERROR: u32 = 0xffff_0000;
+/// The status bits the driver waits for
+const INT_MASK: u32 =
+    INT_CMD_COMPLETE | INT_XFER_COMPLETE | INT_BUF_WRITE_READY | INT_BUF_READ_READY | INT_ERROR;
+const CAPABILITIES: usize = 0x40;
+/// Host controller version (high half of the word)
+const HOST_VERSION: usize = 0xfc;
+/// Specification version 3.00, with 10-bit clock dividers and an 8-bit base clock field
+const SPEC_300: u32 = 2;
+
+/* --- SD Protocol Constants --- */
+const CMD_GO_IDLE_STATE: u32 = 0;
+const CMD_ALL_SEND_CID: u32 = 2;
+const CMD_SEND_RELATIVE_ADDR: u32 = 3;
+const CMD_SELECT_CARD: u32 = 7;
+const CMD_SEND_IF_COND: u32 = 8;
+const CMD_SEND_CSD: u32 = 9;
+const CMD_SET_BLOCKLEN: u32 = 16;
+const CMD_READ_SINGLE_BLOCK: u32 = 17;
+const CMD_READ_MULTIPLE_BLOCK: u32 = 18;
+const CMD_WRITE_BLOCK: u32 = 24;
+const CMD_WRITE_MULTIPLE_BLOCK: u32 = 25;
+const CMD_APP_CMD: u32 = 55;
+/// `ACMD6`, sent after `CMD55`
+const ACMD_SET_BUS_WIDTH: u32 = 6;
+/// `ACMD41`, sent after `CMD55`
+const ACMD_SD_SEND_OP_COND: u32 = 41;
+/// `CMD8` argument: 2.7-3.6 V and a check pattern the card echoes
+const IF_COND_3V3: u32 = 0x1aa;
+/// OCR voltage window: 2.7-3.6 V
+const OCR_VOLTAGE_3V3: u32 = 0x00ff_8000;
+/// OCR Card Capacity Status (response) / Host Capacity Support (request)
+const OCR_CCS: u32 = 1 << 30;
+/// OCR power up status, clear while the card is busy initializing
+const OCR_READY: u32 = 1 << 31;
+/// `ACMD6` argument for a 4-bit bus
+const BUS_WIDTH_4: u32 = 2;
+
+/// Identification clock
+const CLOCK_IDENT_HZ: u32 = 400_000;
+/// Default speed mode clock
+const CLOCK_DEFAULT_HZ: u32 = 25_000_000;
+
+/// Time for a command to complete
+const CMD_TIMEOUT_MS: u32 = 100;
+/// Time for a block to be read or written
+const DATA_TIMEOUT_MS: u32 = 1000;
+/// Time for the card to leave its busy state after power-up
+const INIT_TIMEOUT_MS: u32 = 1000;
+
+/// Most blocks moved by one command, limited by the block count register
+const MAX_BLOCKS: usize = u16::MAX as usize;
+
+/// Errors of the SD commands
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MmcError {
+    /// The card didn't answer, or the controller stayed busy
+    Timeout,
+    /// A response or data block failed its CRC check
+    Crc,
+    /// Any other error reported by the controller
+    Io,
+    /// The card doesn't support the host's voltage, or is not an SD card
+    Unsupported,
+}
+
+/// Response format of a command
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Response {
+    None,
+    /// 48-bit response with CRC and index (R1, R6, R7)
+    R1,
+    /// R1 followed by a busy signal on the data line
+    R1b,
+    /// 136-bit CID or CSD register
+    R2,
+    /// 48-bit OCR, without CRC nor index (R3)
+    R3,
+}
+
+impl Response {
+    /// Command register flags of the response
+    fn flags(self) -> u32 {
+        match self {
+            Response::None => 0,
+            Response::R1 => CMD_RESP_48 | CMD_CRC_CHECK | CMD_INDEX_CHECK,
+            Response::R1b => CMD_RESP_48_BUSY | CMD_CRC_CHECK | CMD_INDEX_CHECK,
+            Response::R2 => CMD_RESP_136 | CMD_CRC_CHECK,
+            Response::R3 => CMD_RESP_48,
+        }
+    }
+}
+
+/// An SDHCI controller and the card in its slot
+pub struct Sdhci {
+    /// Name of the card's block device
+    name: &'static str,
+    /// Base address of the controller registers
+    base: usize,
+    /// Base clock frequency in Hz
+    base_clock: u32,
+    /// Specification version from `HOST_VERSION` (0 for 1.00, 1 for 2.00, 2 for 3.00)
+    version: u32,
+    /// Relative card address, assigned by `CMD3`
+    rca: u32,
+    /// Whether the card is addressed in sectors (SDHC/SDXC) rather than bytes
+    high_capacity: bool,
+    /// Capacity of the card in sectors
+    sectors: u64,
+    /// Interrupt ID (INTID) of the controller, 0 if the node has none
+    irq: u32,
+    /// Set once the interrupt handler acknowledges the status bits
+    irq_enabled: AtomicBool,
+    /// Status bits acknowledged but not yet consumed by the waiting task
+    events: AtomicU32,
+    /// Woken by the interrupt handler
+    wait: WaitQueue,
+    /// Held while a request is in flight
+    request: Semaphore,
+}
+
+impl Sdhci {
+    const fn new(name: &'static str, base: usize, base_clock: u32, version: u32) -> Self {
+        Self {
+            name,
+            base,
+            base_clock,
+            version,
+            rca: 0,
+            high_capacity: false,
+            sectors: 0,
+            irq: 0,
+            irq_enabled: AtomicBool::new(false),
+            events: AtomicU32::new(0),
+            wait: WaitQueue::new(),
+            request: Semaphore::new(1),
+        }
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        mmio::read_mmio32(self.base, reg)
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        mmio::write_mmio32(self.base, reg, value);
+    }
+
+    /// Spins until `bits` of `reg` equal `value`
+    fn wait_reg(&self, reg: usize, bits: u32, value: u32, ms: u32) -> Result<(), MmcError> {
+        let deadline = Deadline::from_ms(ms);
+        while self.read(reg) & bits != value {
+            if deadline.expired() {
+                return Err(MmcError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resets the parts of the controller selected by `mask` (`RESET_*`)
+    fn reset(&self, mask: u32) -> Result<(), MmcError> {
+        let clock = self.read(CLOCK_CTRL) & 0x00ff_ffff;
+        self.write(CLOCK_CTRL, clock | mask);
+        self.wait_reg(CLOCK_CTRL, mask, 0, CMD_TIMEOUT_MS)
+    }
+
+    /// Returns the divider field of `CLOCK_CTRL` for a card clock of at most `hz`
+    fn divider(&self, hz: u32) -> u32 {
+        if self.base_clock <= hz {
+            return 0;
+        }
+        if self.version >= SPEC_300 {
+            // 10-bit divided clock mode: base / (2 * n)
+            let n = self.base_clock.div_ceil(2 * hz).min(0x3ff);
+            ((n & 0xff) << 8) | ((n >> 8) << 6)
+        } else {
+            // Power of two dividers up to 256, the field holding half the divider
+            let mut div = 2;
+            while div < 256 && self.base_clock / div > hz {
+                div *= 2;
+            }
+            (div / 2) << 8
+        }
+    }
+
+    /// Sets the card clock to at most `hz`
+    fn set_clock(&self, hz: u32) -> Result<(), MmcError> {
+        self.write(CLOCK_CTRL, CLOCK_TIMEOUT_MAX);
+        let ctrl = CLOCK_TIMEOUT_MAX | self.divider(hz) | CLOCK_INT_EN;
+        self.write(CLOCK_CTRL, ctrl);
+        self.wait_reg(CLOCK_CTRL, CLOCK_INT_STABLE, CLOCK_INT_STABLE, 150)?;
+        self.write(CLOCK_CTRL, ctrl | CLOCK_SD_EN);
+        Ok(())
+    }
+
+    /// Resets the controller, powers the slot and starts the identification clock
+    fn init_host(&self) -> Result<(), MmcError> {
+        self.reset(RESET_ALL)?;
+        self.write(HOST_CTRL, POWER_ON_3V3);
+        self.write(INT_STATUS_ENABLE, INT_MASK);
+        self.write(INT_SIGNAL_ENABLE, 0);
+        self.write(INT_STATUS, u32::MAX);
+        self.set_clock(CLOCK_IDENT_HZ)?;
+        // The card needs 1 ms and 74 clock cycles after power-up before the first command
+        let settle = Deadline::from_ms(2);
+        while !settle.expired() {}
+        Ok(())
+    }
+
+    /// Waits until one of the status bits in `mask`, or an error, is set, then consumes them
+    ///
+    /// With the interrupt enabled, sleeps until the handler has acknowledged them; otherwise
+    /// polls and acknowledges the status register.
+    fn wait_status(&self, mask: u32, ms: u32) -> Result<u32, MmcError> {
+        let mask = mask | INT_ERROR;
+        let timed_out = if self.irq_enabled.load(Ordering::Acquire) {
+            self.wait
+                .wait_event_timeout(ms, || self.events.load(Ordering::Acquire) & mask != 0)
+                .is_err()
+        } else {
+            let deadline = Deadline::from_ms(ms);
+            loop {
+                let status = self.read(INT_STATUS) & mask;
+                if status != 0 {
+                    self.write(INT_STATUS, status);
+                    self.events.fetch_or(status, Ordering::AcqRel);
+                    break false;
+                }
+                if deadline.expired() {
+                    break true;
+                }
+            }
+        };
+        let status = self.events.fetch_and(!mask, Ordering::AcqRel) & mask;
+        if status & INT_ERROR != 0 {
+            return Err(if status & (INT_CMD_TIMEOUT | INT_DATA_TIMEOUT) != 0 {
+                MmcError::Timeout
+            } else if status & (INT_CMD_CRC | INT_DATA_CRC) != 0 {
+                MmcError::Crc
+            } else {
+                MmcError::Io
+            });
+        }
+        if timed_out && status == 0 {
+            return Err(MmcError::Timeout);
+        }
+        Ok(status)
+    }
+
+    /// Sends command `index` and returns its response
+    ///
+    /// `xfer` is the transfer mode of a command moving data, whose blocks the caller then reads
+    /// or writes through the buffer port. The command and data lines are reset after an error.
+    fn command(
+        &self,
+        index: u32,
+        arg: u32,
+        response: Response,
+        xfer: Option<u32>,
+    ) -> Result<[u32; 4], MmcError> {
+        let mut inhibit = PRESENT_CMD_INHIBIT;
+        if xfer.is_some() || response == Response::R1b {
+            inhibit |= PRESENT_DAT_INHIBIT;
+        }
+        self.wait_reg(PRESENT_STATE, inhibit, 0, CMD_TIMEOUT_MS)?;
+        self.events.store(0, Ordering::Release);
+
+        let mut cmd = (index << CMD_INDEX_SHIFT) | response.flags();
+        if xfer.is_some() {
+            cmd |= CMD_DATA;
+        }
+        self.write(ARGUMENT, arg);
+        self.write(XFER_CMD, cmd | xfer.unwrap_or(0));
+
+        let result = self
+            .wait_status(INT_CMD_COMPLETE, CMD_TIMEOUT_MS)
+            .and_then(|_| {
+                if response == Response::R1b {
+                    self.wait_status(INT_XFER_COMPLETE, DATA_TIMEOUT_MS)?;
+                }
+                Ok(core::array::from_fn(|i| self.read(RESPONSE + 4 * i)))
+            });
+        if result.is_err() {
+            let _ = self.reset(RESET_CMD | RESET_DAT);
+        }
+        result
+    }
+
+    /// Sends application command `index`, prefixed by `CMD55`
+    fn app_command(&self, index: u32, arg: u32, response: Response) -> Result<[u32; 4], MmcError> {
+        self.command(CMD_APP_CMD, self.rca << 16, Response::R1, None)?;
+        self.command(index, arg, response, None)
+    }
+
+    /// Identifies the card and brings it to the transfer state, with a `bus_width`-bit bus
+    fn identify(&mut self, bus_width: u32, max_hz: u32) -> Result<(), MmcError> {
+        self.command(CMD_GO_IDLE_STATE, 0, Response::None, None)?;
+        // Cards older than version 2.00 don't answer CMD8
+        let v2 = match self.command(CMD_SEND_IF_COND, IF_COND_3V3, Response::R1, None) {
+            Ok(resp) if resp[0] & 0xfff == IF_COND_3V3 => true,
+            Ok(_) => return Err(MmcError::Unsupported),
+            Err(MmcError::Timeout) => false,
+            Err(e) => return Err(e),
+        };
+
+        let hcs = if v2 { OCR_CCS } else { 0 };
+        let deadline = Deadline::from_ms(INIT_TIMEOUT_MS);
+        let ocr = loop {
+            let ocr =
+                self.app_command(ACMD_SD_SEND_OP_COND, OCR_VOLTAGE_3V3 | hcs, Response::R3)?[0];
+            if ocr & OCR_READY != 0 {
+                break ocr;
+            }
+            if deadline.expired() {
+                return Err(MmcError::Timeout);
+            }
+        };
+        if ocr & OCR_VOLTAGE_3V3 == 0 {
+            return Err(MmcError::Unsupported);
+        }
+        self.high_capacity = ocr & OCR_CCS != 0;
+
+        self.command(CMD_ALL_SEND_CID, 0, Response::R2, None)?;
+        self.rca = self.command(CMD_SEND_RELATIVE_ADDR, 0, Response::R1, None)?[0] >> 16;
+        let csd = self.command(CMD_SEND_CSD, self.rca << 16, Response::R2, None)?;
+        self.sectors = csd_sectors(csd);
+        self.command(CMD_SELECT_CARD, self.rca << 16, Response::R1b, None)?;
+
+        if bus_width >= 4 {
+            self.app_command(ACMD_SET_BUS_WIDTH, BUS_WIDTH_4, Response::R1)?;
+            let host = self.read(HOST_CTRL);
+            self.write(HOST_CTRL, host | HOST_CTRL_4BIT);
+        }
+        if !self.high_capacity {
+            self.command(CMD_SET_BLOCKLEN, SECTOR_SIZE as u32, Response::R1, None)?;
+        }
+        self.set_clock(CLOCK_DEFAULT_HZ.min(max_hz))
+    }
+
+    /// Moves `blocks` sectors starting at `sector` between the card and `buf`
+    fn transfer(
+        &self,
+        sector: u64,
+        buf: *mut u8,
+        blocks: usize,
+        write: bool,
+    ) -> Result<(), MmcError> {
+        let multi = blocks > 1;
+        let index = match (write, multi) {
+            (false, false) => CMD_READ_SINGLE_BLOCK,
+            (false, true) => CMD_READ_MULTIPLE_BLOCK,
+            (true, false) => CMD_WRITE_BLOCK,
+            (true, true) => CMD_WRITE_MULTIPLE_BLOCK,
+        };
+        let mut xfer = XFER_BLOCK_COUNT_EN;
+        if !write {
+            xfer |= XFER_READ;
+        }
+        if multi {
+            xfer |= XFER_MULTI | XFER_AUTO_CMD12;
+        }
+        // Standard capacity cards are addressed in bytes
+        let addr = if self.high_capacity {
+            sector
+        } else {
+            sector * SECTOR_SIZE as u64
+        };
+        self.write(BLKSIZE_BLKCNT, ((blocks as u32) << 16) | SECTOR_SIZE as u32);
+        self.command(index, addr as u32, Response::R1, Some(xfer))?;
+
+        let ready = if write {
+            INT_BUF_WRITE_READY
+        } else {
+            INT_BUF_READ_READY
+        };
+        let result = (0..blocks)
+            .try_for_each(|block| {
+                self.wait_status(ready, DATA_TIMEOUT_MS)?;
+                let data = unsafe { buf.add(block * SECTOR_SIZE) };
+                for word in 0..SECTOR_SIZE / 4 {
+                    // The caller's buffer has no alignment guarantee
+                    let ptr = unsafe { data.add(word * 4) } as *mut [u8; 4];
+                    if write {
+                        self.write(BUFFER, u32::from_le_bytes(unsafe { ptr.read_unaligned() }));
+                    } else {
+                        let value = self.read(BUFFER).to_le_bytes();
+                        unsafe { ptr.write_unaligned(value) };
+                    }
+                }
+                Ok(())
+            })
+            .and_then(|_| {
+                self.wait_status(INT_XFER_COMPLETE, DATA_TIMEOUT_MS)
+                    .map(|_| ())
+            });
+        if result.is_err() {
+            let _ = self.reset(RESET_CMD | RESET_DAT);
+        }
+        result
+    }
+
+    /// Serves a block request, split in commands of at most `MAX_BLOCKS` sectors
+    fn request(
+        &self,
+        sector: u64,
+        buf: *mut u8,
+        len: usize,
+        write: bool,
+    ) -> Result<(), BlockError> {
+        self.request.down();
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < len / SECTOR_SIZE && result.is_ok() {
+            let blocks = (len / SECTOR_SIZE - done).min(MAX_BLOCKS);
+            let data = unsafe { buf.add(done * SECTOR_SIZE) };
+            result = self.transfer(sector + done as u64, data, blocks, write);
+            done += blocks;
+        }
+        self.request.up();
+        result.map_err(|e| {
+            pr_err!(
+                "sdhci: {} I/O error at sector {}: {:?}",
+                self.name,
+                sector,
+                e
+            );
+            BlockError::Io
+        })
+    }
+
+    /// Lets the interrupt handler acknowledge the status bits from now on
+    fn enable_irq(&self) {
+        self.irq_enabled.store(true, Ordering::Release);
+        self.write(INT_SIGNAL_ENABLE, INT_MASK);
+    }
+}
+
+impl BlockDevice for Sdhci {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buf.len())?;
+        self.request(sector, buf.as_mut_ptr(), buf.len(), false)
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buf.len())?;
+        // Only read through in a write request
+        self.request(sector, buf.as_ptr() as *mut u8, buf.len(), true)
+    }
+}
+
+/// Returns the capacity in sectors described by a CSD register, as read by `CMD9`
+///
+/// The controller strips the CRC byte, so bit `n` of the CSD is bit `n - 8` of the response.
+fn csd_sectors(resp: [u32; 4]) -> u64 {
+    let csd = resp
+        .iter()
+        .rev()
+        .fold(0u128, |csd, &word| (csd << 32) | word as u128)
+        << 8;
+    let bits = |hi: u32, lo: u32| ((csd >> lo) & ((1 << (hi - lo + 1)) - 1)) as u64;
+    match bits(127, 126) {
+        // CSD version 1.0: (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) blocks of 2^READ_BL_LEN bytes
+        0 => {
+            let bytes = (bits(73, 62) + 1) << (bits(49, 47) + 2) << bits(83, 80);
+            bytes / SECTOR_SIZE as u64
+        }
+        // CSD version 2.0: (C_SIZE + 1) * 512 KiB
+        _ => (bits(69, 48) + 1) * 1024,
+    }
+}
+
+/// The controllers, in probe order
+static HOSTS: [InitCell<Sdhci>; MAX_HOSTS] = [const { InitCell::new() }; MAX_HOSTS];
+
+/// Number of initialized entries in `HOSTS`
+static HOST_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Acknowledges the status bits and wakes up the waiting task; `data` is the host index
+fn handle_irq(_id: u32, data: usize) {
+    let Some(host) = HOSTS[data].get() else {
+        return;
+    };
+    let status = host.read(INT_STATUS) & INT_MASK;
+    if status != 0 {
+        host.write(INT_STATUS, status);
+        host.events.fetch_or(status, Ordering::AcqRel);
+        host.wait.wake_up();
+    }
+}
+
+/// Reads the single-cell property `name` of `dev`
+fn read_u32(dev: &device::PlatformDevice, name: &str) -> Option<u32> {
+    let prop = dev.find_property(name)?;
+    (prop.len == 4).then(|| prop.read_cells(0, 1) as u32)
+}
+
+/// Returns the base clock of the controller at `base`, in Hz
+///
+/// `clocks` comes first. The capabilities register gives the rate in MHz otherwise, but leaves
+/// it to the platform when it is 0, which on the Raspberry Pi means asking the firmware.
+fn base_clock(dev: &device::PlatformDevice, base: usize) -> Result<u32, ProbeError> {
+    match clk::clk_get(dev, 0) {
+        Ok(clk) => return Ok(clk.get_rate() as u32),
+        Err(ClkError::NotReady) => return Err(ProbeError::Defer),
+        Err(_) => {}
+    }
+    let caps = mmio::read_mmio32(base, CAPABILITIES);
+    let mhz = (caps >> 8) & 0xff;
+    if mhz != 0 {
+        return Ok(mhz * 1_000_000);
+    }
+    #[cfg(feature = "bcm2835-mbox")]
+    {
+        use crate::drivers::mbox::bcm2835;
+        match bcm2835::clock_rate(bcm2835::Clock::Emmc2) {
+            Ok(rate) if rate != 0 => return Ok(rate),
+            Err(bcm2835::MboxError::NoDevice) => return Err(ProbeError::Defer),
+            _ => {}
+        }
+    }
+    pr_warn!("sdhci: unknown base clock for {}", dev.name);
+    Err(ProbeError::NoDevice)
+}
+
+/// Driver for SDHCI-compatible controllers
+pub struct SdhciDriver;
+
+impl device::Driver for SdhciDriver {
+    /// Sets up the controller, identifies the card and registers it as a block device
+    ///
+    /// `bus-width` and `max-frequency` limit the bus; the first `interrupts` entry, which must
+    /// be an SPI, is used once the card is identified. Fails with `ProbeError::NoDevice` if the
+    /// slot is empty or the card doesn't initialize.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let index = HOST_COUNT.load(Ordering::Acquire);
+        if index == MAX_HOSTS {
+            println!("sdhci: no free instance for {}", dev.name);
+            return Err(ProbeError::NoResources);
+        }
+        let base = dev.reg(0).ok_or(ProbeError::NoDevice)?.base;
+        let clock = base_clock(dev, base)?;
+        let version = (mmio::read_mmio32(base, HOST_VERSION) >> 16) & 0xff;
+        let mut host = Sdhci::new(DISK_NAMES[index], base, clock, version);
+
+        let bus_width = read_u32(dev, "bus-width").unwrap_or(1);
+        let max_hz = read_u32(dev, "max-frequency").unwrap_or(u32::MAX);
+        if let Err(e) = host.init_host() {
+            pr_err!("sdhci: {}: controller reset failed: {:?}", dev.name, e);
+            return Err(ProbeError::NoDevice);
+        }
+        if let Err(e) = host.identify(bus_width, max_hz) {
+            println!("sdhci: {}: no usable card: {:?}", dev.name, e);
+            return Err(ProbeError::NoDevice);
+        }
+
+        host.irq = of_irq_parse(dev, 0)
+            .filter(|spec| spec.kind == IrqKind::Spi)
+            .map_or(0, |spec| gic::configure_irq(&spec));
+        let Ok(host) = HOSTS[index].set(host) else {
+            return Err(ProbeError::NoResources);
+        };
+        HOST_COUNT.store(index + 1, Ordering::Release);
+
+        if host.irq != 0 && irq::request_irq(host.irq, host.name, handle_irq, index).is_ok() {
+            host.enable_irq();
+            gic::enable_irq(host.irq);
+        }
+        if let Err(e) = block::register(host) {
+            pr_err!("sdhci: cannot register {}: {:?}", host.name, e);
+            return Ok(());
+        }
+        println!(
+            "sdhci: {} is {} MiB, {} capacity",
+            host.name,
+            host.sectors * SECTOR_SIZE as u64 / (1024 * 1024),
+            if host.high_capacity {
+                "high"
+            } else {
+                "standard"
+            }
+        );
+        Ok(())
+    }
+}
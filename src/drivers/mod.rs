@@ -1,5 +1,15 @@
 //! Device drivers module
 
+pub mod firmware;
 pub mod gic;
+pub mod iommu;
+pub mod mbox;
+pub mod mmc;
+pub mod nvme;
+pub mod pci;
+pub mod rtc;
 pub mod timer;
 pub mod uart;
+pub mod usb;
+pub mod virtio;
+pub mod watchdog;
@@ -0,0 +1,570 @@
+//! NVMe driver
+//!
+//! An NVMe controller is a PCIe function (class `0x010802`) whose BAR 0 holds a few registers and
+//! the queue doorbells. Everything else goes through queue pairs in memory (see `queue`): the
+//! admin queue, set up through registers, creates the I/O queues and identifies the controller
+//! and its namespaces; reads and writes go to an I/O queue. Every active namespace becomes a
+//! block device named `nvme0n1`, `nvme0n2`, ...
+//!
+//! ## Design
+//!
+//! - One admin queue pair and one I/O queue pair, with a single command in flight on each. The
+//!   block layer hands requests over one at a time anyway.
+//! - Data goes through a bounce buffer from `dma::alloc_coherent`, described by a PRP list built
+//!   once at probe time: the block layer's buffers may be on task stacks, outside the identity
+//!   map the controller's addresses come from. Larger requests are split.
+//! - I/O completions raise MSI-X vector 1 (see `pci::msi`), whose handler wakes the task
+//!   waiting for the command. Without MSI-X or an ITS, and on the admin queue (vector 0, left
+//!   masked), completions are polled, the task yielding the CPU between checks. The legacy
+//!   INTx line is never used.
+//! - Only namespaces formatted with 512-byte blocks are registered, the block layer's sector
+//!   size.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `drivers/nvme/host/pci.c` creates one I/O queue pair per CPU, each with its own
+//! MSI-X vector, keeps many commands in flight through blk-mq, and maps the caller's pages
+//! directly into PRP lists or SGLs.
+
+pub mod queue;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ipc::init_cell::InitCell;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::semaphore::Semaphore;
+use crate::ipc::waitqueue::{self, WaitQueue};
+use crate::kernel::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::kernel::device::ProbeError;
+use crate::kernel::mm::dma::{self, DmaBuffer};
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+use crate::kernel::notifier::Deadline;
+use crate::kernel::sched;
+use crate::utilities::mmio;
+use crate::{pr_err, println};
+
+use super::pci::{PciDevice, PciDriver, msi};
+use queue::{Command, Completion, MAX_QUEUE_DEPTH, QueuePair};
+
+/// PCI class code of an NVMe controller
+pub const CLASS_NVME: u32 = 0x01_08_02;
+
+/// Maximum number of namespaces registered
+const MAX_NAMESPACES: usize = 4;
+
+/// Block device names of the namespaces, by namespace ID
+const NAMESPACE_NAMES: [&str; MAX_NAMESPACES] = ["nvme0n1", "nvme0n2", "nvme0n3", "nvme0n4"];
+
+/// Size of the bounce buffer, the largest transfer of one command
+const BOUNCE_SIZE: usize = 64 * 1024;
+
+/// Time for an I/O command to complete
+const IO_TIMEOUT_MS: u32 = 5000;
+
+/* --- Controller Register Constants --- */
+/// Controller capabilities (64 bits)
+const CAP: usize = 0x00;
+const VS: usize = 0x08;
+/// Interrupt mask set
+const INTMS: usize = 0x0c;
+/// Controller configuration
+const CC: usize = 0x14;
+const CC_ENABLE: u32 = 1 << 0;
+/// I/O submission and completion queue entry sizes, as powers of two
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+/// Controller status
+const CSTS: usize = 0x1c;
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+/// Admin queue attributes, and the admin SQ and CQ base addresses (64 bits)
+const AQA: usize = 0x24;
+const ASQ: usize = 0x28;
+const ACQ: usize = 0x30;
+/// First doorbell register
+const DOORBELLS: usize = 0x1000;
+
+/* --- Admin Command Constants --- */
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+/// Identify data structures (CNS)
+const IDENTIFY_NAMESPACE: u32 = 0;
+const IDENTIFY_CONTROLLER: u32 = 1;
+const FEATURE_NUM_QUEUES: u32 = 0x07;
+/// Physically contiguous queue
+const QUEUE_CONTIGUOUS: u32 = 1 << 0;
+/// The completion queue raises the interrupt vector in bits 31:16
+const QUEUE_INTERRUPTS: u32 = 1 << 1;
+/// MSI-X vector of the I/O completion queue; vector 0 is the admin queue's
+const IO_VECTOR: usize = 1;
+
+/* --- NVM Command Constants --- */
+const NVM_FLUSH: u8 = 0x00;
+const NVM_WRITE: u8 = 0x01;
+const NVM_READ: u8 = 0x02;
+
+/// Errors of the NVMe commands
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NvmeError {
+    /// The controller didn't complete the command, or didn't become ready, in time
+    Timeout,
+    /// The command completed with this status (status code type and status code)
+    Status(u16),
+    /// The controller reported a fatal error
+    Fatal,
+    /// No DMA memory for the queues or the bounce buffer
+    NoMemory,
+    /// The controller doesn't support 4 KiB pages or the NVM command set
+    Unsupported,
+}
+
+/// A queue pair and the semaphore keeping a single command in flight on it
+struct Queue {
+    pair: Mutex<QueuePair>,
+    busy: Semaphore,
+    /// Set if completions raise an interrupt, which wakes `wait`
+    irq: bool,
+    wait: WaitQueue,
+}
+
+impl Queue {
+    fn new(pair: QueuePair, irq: bool) -> Self {
+        Self {
+            pair: Mutex::new(pair),
+            busy: Semaphore::new(1),
+            irq,
+            wait: WaitQueue::new(),
+        }
+    }
+}
+
+/// An NVMe controller
+pub struct Nvme {
+    /// Base address of the registers (BAR 0)
+    regs: usize,
+    admin: Queue,
+    /// Set once the I/O queue pair is created
+    io: InitCell<Queue>,
+    /// Data of every command, described by `prp_list` past its first page
+    bounce: DmaBuffer,
+    prp_list: DmaBuffer,
+    /// Held while a request uses the bounce buffer
+    bounce_lock: Semaphore,
+    /// Largest transfer of one command, in bytes, lowered by the controller's MDTS
+    max_transfer: AtomicUsize,
+    /// Time for the controller to become ready, from `CAP.TO`
+    ready_timeout_ms: u32,
+}
+
+impl Nvme {
+    fn write64(&self, reg: usize, value: u64) {
+        mmio::write_mmio32(self.regs, reg, value as u32);
+        mmio::write_mmio32(self.regs, reg + 4, (value >> 32) as u32);
+    }
+
+    /// Waits until `CSTS.RDY` is `ready`
+    fn wait_ready(&self, ready: bool) -> Result<(), NvmeError> {
+        let deadline = Deadline::from_ms(self.ready_timeout_ms);
+        loop {
+            let csts = mmio::read_mmio32(self.regs, CSTS);
+            if csts & CSTS_FATAL != 0 && ready {
+                return Err(NvmeError::Fatal);
+            }
+            if (csts & CSTS_READY != 0) == ready {
+                return Ok(());
+            }
+            if deadline.expired() {
+                return Err(NvmeError::Timeout);
+            }
+        }
+    }
+
+    /// Runs `cmd` on `queue` and returns the completion's result
+    ///
+    /// Sleeps until the queue's interrupt if it has one, otherwise polls the completion queue,
+    /// yielding the CPU between checks.
+    fn execute(&self, queue: &Queue, cmd: Command, timeout_ms: u32) -> Result<u32, NvmeError> {
+        queue.busy.down();
+        let cid = queue.pair.lock_irqsafe(|pair| pair.submit(cmd));
+        let poll = || loop {
+            match queue.pair.lock_irqsafe(|pair| pair.poll()) {
+                Some(entry) if entry.cid == cid => break Some(completion_result(&entry)),
+                // A late completion of a timed out command is skipped
+                Some(_) => continue,
+                None => break None,
+            }
+        };
+        let result = if queue.irq {
+            let mut result = None;
+            let expires = waitqueue::deadline_ms(timeout_ms);
+            let _ = queue.wait.wait_event_until(expires, || {
+                result = poll();
+                result.is_some()
+            });
+            result.unwrap_or(Err(NvmeError::Timeout))
+        } else {
+            let deadline = Deadline::from_ms(timeout_ms);
+            loop {
+                if let Some(result) = poll() {
+                    break result;
+                }
+                if deadline.expired() {
+                    break Err(NvmeError::Timeout);
+                }
+                sched::yield_now();
+            }
+        };
+        queue.busy.up();
+        result
+    }
+
+    /// Runs `cmd` on the admin queue
+    fn admin(&self, cmd: Command) -> Result<u32, NvmeError> {
+        self.execute(&self.admin, cmd, self.ready_timeout_ms)
+    }
+
+    /// Points the PRP entries of `cmd` at the first `len` bytes of the bounce buffer
+    fn set_prps(&self, cmd: &mut Command, len: usize) {
+        cmd.prp1 = self.bounce.paddr as u64;
+        cmd.prp2 = match len.div_ceil(PAGE_SIZE) {
+            0 | 1 => 0,
+            2 => (self.bounce.paddr + PAGE_SIZE) as u64,
+            _ => self.prp_list.paddr as u64,
+        };
+    }
+
+    /// Reads identify data structure `cns` (of namespace `nsid`) into the bounce buffer
+    ///
+    /// Only called during probe, before any request can use the bounce buffer.
+    fn identify(&self, cns: u32, nsid: u32) -> Result<&[u8], NvmeError> {
+        let mut cmd = Command::new(ADMIN_IDENTIFY);
+        cmd.nsid = nsid;
+        cmd.cdw10 = cns;
+        self.set_prps(&mut cmd, PAGE_SIZE);
+        self.admin(cmd)?;
+        Ok(unsafe { core::slice::from_raw_parts(self.bounce.vaddr as *const u8, PAGE_SIZE) })
+    }
+
+    /// Resets the controller and brings it up with the admin queue
+    fn enable(&self) -> Result<(), NvmeError> {
+        if mmio::read_mmio32(self.regs, CC) & CC_ENABLE != 0 {
+            mmio::write_mmio32(self.regs, CC, 0);
+            self.wait_ready(false)?;
+        }
+        let (depth, sq, cq) = self
+            .admin
+            .pair
+            .lock_irqsafe(|pair| (pair.depth as u32, pair.sq.paddr, pair.cq.paddr));
+        mmio::write_mmio32(self.regs, AQA, (depth - 1) << 16 | (depth - 1));
+        self.write64(ASQ, sq as u64);
+        self.write64(ACQ, cq as u64);
+        mmio::write_mmio32(self.regs, CC, CC_ENABLE | CC_IOSQES | CC_IOCQES);
+        self.wait_ready(true)?;
+        mmio::write_mmio32(self.regs, INTMS, u32::MAX);
+        Ok(())
+    }
+
+    /// Creates I/O queue pair 1 with up to `depth` entries, its completions raising MSI-X
+    /// vector `IO_VECTOR` if `irq`
+    fn create_io_queue(&self, depth: u16, stride: usize, irq: bool) -> Result<(), NvmeError> {
+        let mut cmd = Command::new(ADMIN_SET_FEATURES);
+        cmd.cdw10 = FEATURE_NUM_QUEUES;
+        // One submission and one completion queue, both 0-based
+        cmd.cdw11 = 0;
+        self.admin(cmd)?;
+
+        let pair = QueuePair::new(1, depth, self.regs + DOORBELLS, stride)
+            .map_err(|_| NvmeError::NoMemory)?;
+        let size = (pair.depth as u32 - 1) << 16;
+        let mut cmd = Command::new(ADMIN_CREATE_CQ);
+        cmd.prp1 = pair.cq.paddr as u64;
+        cmd.cdw10 = size | pair.qid as u32;
+        cmd.cdw11 = if irq {
+            (IO_VECTOR as u32) << 16 | QUEUE_INTERRUPTS | QUEUE_CONTIGUOUS
+        } else {
+            QUEUE_CONTIGUOUS
+        };
+        let mut created = self.admin(cmd);
+        if created.is_ok() {
+            let mut cmd = Command::new(ADMIN_CREATE_SQ);
+            cmd.prp1 = pair.sq.paddr as u64;
+            cmd.cdw10 = size | pair.qid as u32;
+            cmd.cdw11 = (pair.qid as u32) << 16 | QUEUE_CONTIGUOUS;
+            created = self.admin(cmd);
+        }
+        if let Err(e) = created {
+            pair.free();
+            return Err(e);
+        }
+        let _ = self.io.set(Queue::new(pair, irq));
+        Ok(())
+    }
+
+    /// Runs an I/O command on namespace `nsid`, moving `len` bytes between `buf` and the device
+    ///
+    /// The transfer is split in commands of at most `max_transfer` bytes.
+    fn io(
+        &self,
+        opcode: u8,
+        nsid: u32,
+        sector: u64,
+        buf: *mut u8,
+        len: usize,
+    ) -> Result<(), NvmeError> {
+        let io = self.io.get().ok_or(NvmeError::Unsupported)?;
+        if opcode == NVM_FLUSH {
+            let mut cmd = Command::new(NVM_FLUSH);
+            cmd.nsid = nsid;
+            return self.execute(io, cmd, IO_TIMEOUT_MS).map(|_| ());
+        }
+        let bounce = self.bounce.vaddr as *mut u8;
+        let max_transfer = self.max_transfer.load(Ordering::Relaxed);
+        self.bounce_lock.down();
+        let mut done = 0;
+        let mut result = Ok(());
+        while done < len && result.is_ok() {
+            let chunk = (len - done).min(max_transfer);
+            let lba = sector + (done / SECTOR_SIZE) as u64;
+            let data = unsafe { buf.add(done) };
+            if opcode == NVM_WRITE {
+                unsafe { core::ptr::copy_nonoverlapping(data, bounce, chunk) };
+            }
+            let mut cmd = Command::new(opcode);
+            cmd.nsid = nsid;
+            cmd.cdw10 = lba as u32;
+            cmd.cdw11 = (lba >> 32) as u32;
+            cmd.cdw12 = (chunk / SECTOR_SIZE - 1) as u32;
+            self.set_prps(&mut cmd, chunk);
+            result = self.execute(io, cmd, IO_TIMEOUT_MS).map(|_| ());
+            if result.is_ok() && opcode == NVM_READ {
+                unsafe { core::ptr::copy_nonoverlapping(bounce, data, chunk) };
+            }
+            done += chunk;
+        }
+        self.bounce_lock.up();
+        result
+    }
+}
+
+/// Returns the result of a completion, or its status if the command failed
+fn completion_result(entry: &Completion) -> Result<u32, NvmeError> {
+    match entry.status_code() {
+        0 => Ok(entry.result),
+        status => Err(NvmeError::Status(status)),
+    }
+}
+
+/// A namespace of the controller, registered as a block device
+pub struct Namespace {
+    name: &'static str,
+    ctrl: &'static Nvme,
+    nsid: u32,
+    sectors: u64,
+}
+
+impl Namespace {
+    fn request(&self, opcode: u8, sector: u64, buf: *mut u8, len: usize) -> Result<(), BlockError> {
+        self.ctrl
+            .io(opcode, self.nsid, sector, buf, len)
+            .map_err(|e| {
+                pr_err!(
+                    "nvme: {} I/O error at sector {}: {:?}",
+                    self.name,
+                    sector,
+                    e
+                );
+                BlockError::Io
+            })
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buf.len())?;
+        self.request(NVM_READ, sector, buf.as_mut_ptr(), buf.len())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buf.len())?;
+        // Only read from in a write request
+        self.request(NVM_WRITE, sector, buf.as_ptr() as *mut u8, buf.len())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.request(NVM_FLUSH, 0, core::ptr::null_mut(), 0)
+    }
+}
+
+/// The controller, set by the driver's probe
+static CONTROLLER: InitCell<Nvme> = InitCell::new();
+
+/// The namespaces, by namespace ID - 1
+static NAMESPACES: [InitCell<Namespace>; MAX_NAMESPACES] =
+    [const { InitCell::new() }; MAX_NAMESPACES];
+
+/// Reads the little-endian integer of `N` bytes at `offset` in `data`
+fn read_le<const N: usize>(data: &[u8], offset: usize) -> u64 {
+    data[offset..offset + N]
+        .iter()
+        .rev()
+        .fold(0, |value, &b| (value << 8) | b as u64)
+}
+
+/// Identifies namespace `nsid` and registers it if it is active with 512-byte blocks
+fn add_namespace(ctrl: &'static Nvme, nsid: u32) -> Result<(), NvmeError> {
+    let data = ctrl.identify(IDENTIFY_NAMESPACE, nsid)?;
+    let sectors = read_le::<8>(data, 0);
+    if sectors == 0 {
+        // Inactive namespace
+        return Ok(());
+    }
+    // FLBAS selects the LBA format, whose LBADS is the block size as a power of two
+    let format = (data[26] & 0xf) as usize;
+    let block_shift = data[128 + format * 4 + 2];
+    let name = NAMESPACE_NAMES[nsid as usize - 1];
+    if 1 << block_shift != SECTOR_SIZE {
+        println!(
+            "nvme: {}: {}-byte blocks not supported",
+            name,
+            1u64 << block_shift
+        );
+        return Ok(());
+    }
+    let Ok(ns) = NAMESPACES[nsid as usize - 1].set(Namespace {
+        name,
+        ctrl,
+        nsid,
+        sectors,
+    }) else {
+        return Ok(());
+    };
+    match block::register(ns) {
+        Ok(_) => println!(
+            "nvme: {} is {} MiB",
+            name,
+            sectors * SECTOR_SIZE as u64 / (1024 * 1024)
+        ),
+        Err(e) => pr_err!("nvme: cannot register {}: {:?}", name, e),
+    }
+    Ok(())
+}
+
+/// Wakes the task waiting for an I/O completion
+fn io_interrupt(_id: u32, _data: usize) {
+    if let Some(io) = CONTROLLER.get().and_then(|ctrl| ctrl.io.get()) {
+        io.wait.wake_up();
+    }
+}
+
+/// Brings up `ctrl`, the function `dev` whose capabilities are `cap`, and registers its
+/// namespaces
+fn start(ctrl: &'static Nvme, dev: &PciDevice, cap: u64) -> Result<(), NvmeError> {
+    let stride = 4 << ((cap >> 32) & 0xf);
+    ctrl.enable()?;
+
+    let data = ctrl.identify(IDENTIFY_CONTROLLER, 0)?;
+    let model = core::str::from_utf8(&data[24..64]).unwrap_or("?").trim();
+    let namespaces = read_le::<4>(data, 516) as u32;
+    // MDTS is a power of two of pages, 0 for no limit
+    let mdts = data[77];
+    if mdts != 0 {
+        let limit = PAGE_SIZE << mdts;
+        ctrl.max_transfer.fetch_min(limit, Ordering::Relaxed);
+    }
+    let (major, minor) = {
+        let vs = mmio::read_mmio32(ctrl.regs, VS);
+        (vs >> 16, (vs >> 8) & 0xff)
+    };
+    println!(
+        "nvme: {} (NVMe {}.{}), {} namespace(s)",
+        model, major, minor, namespaces
+    );
+
+    let depth = ((cap & 0xffff) as u16)
+        .saturating_add(1)
+        .min(MAX_QUEUE_DEPTH);
+    let irq = match msi::request_vector(dev, IO_VECTOR, "nvme0", io_interrupt, 0) {
+        Ok(_) => true,
+        Err(e) => {
+            println!("nvme: no MSI-X vector ({:?}), polling completions", e);
+            false
+        }
+    };
+    ctrl.create_io_queue(depth, stride, irq)?;
+    for nsid in 1..=namespaces.min(MAX_NAMESPACES as u32) {
+        if let Err(e) = add_namespace(ctrl, nsid) {
+            pr_err!("nvme: cannot identify namespace {}: {:?}", nsid, e);
+        }
+    }
+    Ok(())
+}
+
+/// Driver for NVMe controllers
+pub struct NvmeDriver;
+
+impl PciDriver for NvmeDriver {
+    /// Sets up the controller behind BAR 0 and registers its namespaces
+    ///
+    /// A single controller is supported.
+    fn probe(&self, dev: &'static PciDevice) -> Result<(), ProbeError> {
+        if CONTROLLER.is_set() {
+            println!("nvme: a single controller is supported");
+            return Err(ProbeError::NoResources);
+        }
+        let regs = dev.bar(0).ok_or(ProbeError::NoDevice)?.base;
+        let cap =
+            mmio::read_mmio32(regs, CAP) as u64 | (mmio::read_mmio32(regs, CAP + 4) as u64) << 32;
+        // NVM command set (CSS bit 0) and 4 KiB pages (MPSMIN 0) are all this driver knows
+        if (cap >> 37) & 1 == 0 || (cap >> 48) & 0xf != 0 {
+            println!("nvme: unsupported controller, CAP {:#x}", cap);
+            return Err(ProbeError::NotSupported);
+        }
+        let stride = 4 << ((cap >> 32) & 0xf);
+
+        let admin = QueuePair::new(0, MAX_QUEUE_DEPTH, regs + DOORBELLS, stride)
+            .map_err(|_| ProbeError::NoResources)?;
+        let (Ok(bounce), Ok(prp_list)) = (
+            dma::alloc_coherent(BOUNCE_SIZE),
+            dma::alloc_coherent(PAGE_SIZE),
+        ) else {
+            admin.free();
+            return Err(ProbeError::NoResources);
+        };
+        // The PRP list covers the pages of the bounce buffer after the first
+        let entries = prp_list.vaddr as *mut u64;
+        for page in 1..BOUNCE_SIZE / PAGE_SIZE {
+            let addr = (bounce.paddr + page * PAGE_SIZE) as u64;
+            unsafe { entries.add(page - 1).write_volatile(addr) };
+        }
+
+        dev.enable_bus_master();
+        let ctrl = CONTROLLER
+            .set(Nvme {
+                regs,
+                admin: Queue::new(admin, false),
+                io: InitCell::new(),
+                bounce,
+                prp_list,
+                bounce_lock: Semaphore::new(1),
+                max_transfer: AtomicUsize::new(BOUNCE_SIZE),
+                // CAP.TO is in 500 ms units
+                ready_timeout_ms: ((cap >> 24) & 0xff).max(1) as u32 * 500,
+            })
+            .map_err(|_| ProbeError::NoResources)?;
+        if let Err(e) = start(ctrl, dev, cap) {
+            pr_err!("nvme: controller initialization failed: {:?}", e);
+            return Err(ProbeError::NoDevice);
+        }
+        Ok(())
+    }
+}
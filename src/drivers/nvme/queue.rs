@@ -0,0 +1,155 @@
+//! NVMe queue pairs
+//!
+//! Commands go in a submission queue (SQ), a ring of 64-byte entries; the controller posts a
+//! 16-byte entry in the paired completion queue (CQ) for each one it completes. Both rings are
+//! in DMA memory and indexed by doorbell registers: the driver writes the SQ tail to hand
+//! commands over and the CQ head to give entries back. The controller flips the phase bit of the
+//! entries it writes on every pass over the CQ, so a new entry is one whose phase bit differs
+//! from the previous pass.
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{Ordering, fence};
+
+use crate::kernel::mm::dma::{self, DmaBuffer, DmaError};
+use crate::utilities::mmio;
+
+/// Largest number of entries of a queue; one page holds either ring
+pub const MAX_QUEUE_DEPTH: u16 = 32;
+
+/// A submission queue entry
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Command {
+    /// Opcode (bits 7:0) and command identifier (bits 31:16)
+    pub cdw0: u32,
+    pub nsid: u32,
+    pub cdw2: u32,
+    pub cdw3: u32,
+    pub mptr: u64,
+    /// Physical Region Page entries: the data buffer, page by page
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+impl Command {
+    /// A command with `opcode` and every other field 0
+    pub fn new(opcode: u8) -> Self {
+        Self {
+            cdw0: opcode as u32,
+            ..Self::default()
+        }
+    }
+}
+
+/// A completion queue entry
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Completion {
+    /// Command-specific result
+    pub result: u32,
+    pub reserved: u32,
+    /// How far the controller has consumed the SQ
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub cid: u16,
+    /// Phase tag (bit 0) and status field
+    pub status: u16,
+}
+
+impl Completion {
+    /// Status code type and status code, 0 on success
+    pub fn status_code(&self) -> u16 {
+        self.status >> 1
+    }
+}
+
+/// A submission queue and its completion queue
+pub struct QueuePair {
+    /// Queue identifier, 0 for the admin queue
+    pub qid: u16,
+    pub depth: u16,
+    pub sq: DmaBuffer,
+    pub cq: DmaBuffer,
+    sq_tail: u16,
+    cq_head: u16,
+    /// Phase bit of the entries of the current pass over the CQ
+    phase: bool,
+    next_cid: u16,
+    sq_doorbell: usize,
+    cq_doorbell: usize,
+}
+
+impl QueuePair {
+    /// Allocates the rings of queue `qid` with `depth` entries
+    ///
+    /// `doorbells` is the address of the first doorbell register and `stride` the distance
+    /// between two of them.
+    pub fn new(qid: u16, depth: u16, doorbells: usize, stride: usize) -> Result<Self, DmaError> {
+        let depth = depth.min(MAX_QUEUE_DEPTH);
+        let sq = dma::alloc_coherent(depth as usize * size_of::<Command>())?;
+        let cq = match dma::alloc_coherent(depth as usize * size_of::<Completion>()) {
+            Ok(cq) => cq,
+            Err(e) => {
+                let _ = dma::free_coherent(sq);
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            qid,
+            depth,
+            sq,
+            cq,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            next_cid: 0,
+            sq_doorbell: doorbells + 2 * qid as usize * stride,
+            cq_doorbell: doorbells + (2 * qid as usize + 1) * stride,
+        })
+    }
+
+    /// Frees the rings; the controller must not use the queue anymore
+    pub fn free(self) {
+        let _ = dma::free_coherent(self.sq);
+        let _ = dma::free_coherent(self.cq);
+    }
+
+    /// Copies `cmd` to the SQ with a fresh command identifier, which is returned, and rings the
+    /// doorbell
+    ///
+    /// The caller keeps fewer than `depth` commands in flight.
+    pub fn submit(&mut self, mut cmd: Command) -> u16 {
+        let cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+        cmd.cdw0 = (cmd.cdw0 & 0xffff) | (cid as u32) << 16;
+        let slot = (self.sq.vaddr as *mut Command).wrapping_add(self.sq_tail as usize);
+        unsafe { write_volatile(slot, cmd) };
+        self.sq_tail = (self.sq_tail + 1) % self.depth;
+        // The entry must reach memory before the controller learns about it
+        fence(Ordering::SeqCst);
+        mmio::write_mmio32(self.sq_doorbell, 0, self.sq_tail as u32);
+        cid
+    }
+
+    /// Returns the next completion, if the controller has posted one, and gives its slot back
+    pub fn poll(&mut self) -> Option<Completion> {
+        let slot = (self.cq.vaddr as *const Completion).wrapping_add(self.cq_head as usize);
+        let entry = unsafe { read_volatile(slot) };
+        if (entry.status & 1 != 0) != self.phase {
+            return None;
+        }
+        self.cq_head += 1;
+        if self.cq_head == self.depth {
+            self.cq_head = 0;
+            self.phase = !self.phase;
+        }
+        mmio::write_mmio32(self.cq_doorbell, 0, self.cq_head as u32);
+        Some(entry)
+    }
+}
@@ -0,0 +1,96 @@
+//! Generic ECAM host bridge
+//!
+//! `pci-host-ecam-generic` nodes describe a host bridge with nothing to set up: `reg` is the
+//! ECAM window, `bus-range` the buses it covers and `ranges` the windows mapping PCI addresses to
+//! CPU ones. Each `ranges` entry has a three-cell PCI address, whose first cell gives the space
+//! (`0b01` I/O, `0b10` 32-bit memory, `0b11` 64-bit memory). `interrupt-map` and
+//! `interrupt-map-mask` route the INTx pins of the functions to GIC SPIs.
+//!
+//! The ECAM window and the memory window must be in a device block of the identity map. QEMU
+//! puts the ECAM window above 256 GiB unless the machine has `highmem-ecam=off`, which `make run`
+//! sets.
+
+use crate::kernel::board::{self, Block};
+use crate::kernel::device::{self, PlatformDevice, ProbeError};
+use crate::kernel::mm::bits::SZ_1G;
+use crate::{pr_warn, println};
+
+use super::Window;
+
+/// Space code of a `ranges` entry (bits 25:24 of the first cell)
+const SPACE_SHIFT: u32 = 24;
+const SPACE_MASK: u32 = 0b11;
+const SPACE_MEM32: u32 = 0b10;
+
+/// Returns true if `[base, base + size)` is MMIO in the identity map
+fn is_mapped_device(base: usize, size: usize) -> bool {
+    let first = base / SZ_1G;
+    let last = (base + size.max(1) - 1) / SZ_1G;
+    (first..=last).all(|block| board::IDMAP.get(block) == Some(&Block::Device))
+}
+
+/// Returns the 32-bit memory window of the host bridge `dev`
+fn mem32_window(dev: &PlatformDevice) -> Option<Window> {
+    let ranges = dev.find_property("ranges")?;
+    let (child_cells, size_cells) = dev.get_cells();
+    let (parent_cells, _) = dev.get_parent_cells();
+    if child_cells != 3 || parent_cells > 2 || size_cells > 2 {
+        return None;
+    }
+    let entry_len = (child_cells + parent_cells + size_cells) as usize * 4;
+    (0..ranges.len / entry_len).find_map(|i| {
+        let offset = i * entry_len;
+        let space = (ranges.read_cells(offset, 1) as u32 >> SPACE_SHIFT) & SPACE_MASK;
+        if space != SPACE_MEM32 {
+            return None;
+        }
+        Some(Window {
+            pci_base: ranges.read_cells(offset + 4, 2),
+            cpu_base: ranges.read_cells(offset + 12, parent_cells) as usize,
+            size: ranges.read_cells(offset + 12 + parent_cells as usize * 4, size_cells) as usize,
+            next: 0,
+        })
+    })
+}
+
+/// Driver for `pci-host-ecam-generic` nodes
+pub struct EcamHostDriver;
+
+impl device::Driver for EcamHostDriver {
+    /// Scans the first bus of `bus-range` (0 by default), allocating BARs from the 32-bit
+    /// memory window
+    ///
+    /// Fails with `ProbeError::NotSupported` if the ECAM or memory window is not mapped.
+    fn probe(&self, dev: &PlatformDevice) -> Result<(), ProbeError> {
+        let ecam = dev.reg(0).ok_or(ProbeError::NoDevice)?;
+        let first_bus = dev
+            .find_property("bus-range")
+            .filter(|prop| prop.len == 8)
+            .map_or(0, |prop| prop.read_cells(0, 1) as u8);
+        let Some(mut window) = mem32_window(dev) else {
+            pr_warn!("pci: {}: no 32-bit memory window", dev.name);
+            return Err(ProbeError::NoDevice);
+        };
+        if !is_mapped_device(ecam.base, ecam.size)
+            || !is_mapped_device(window.cpu_base, window.size)
+        {
+            pr_warn!(
+                "pci: {}: ECAM at {:#x} or window at {:#x} outside the identity map",
+                dev.name,
+                ecam.base,
+                window.cpu_base
+            );
+            return Err(ProbeError::NotSupported);
+        }
+        // With an `iommu-map`, every requester ID goes through the IOMMU
+        let iommu = dev.find_property("iommu-map").is_some();
+        super::scan_bus(ecam.base, first_bus, first_bus, &mut window, iommu, dev);
+        println!(
+            "pci: {}: ECAM at {:#x}, {} KiB of the memory window assigned",
+            dev.name,
+            ecam.base,
+            window.next / 1024
+        );
+        Ok(())
+    }
+}
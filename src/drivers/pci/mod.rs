@@ -0,0 +1,514 @@
+//! PCI Express
+//!
+//! A PCIe host bridge exposes the configuration space of every function below it through a
+//! memory window (ECAM): 4 KiB per function, at an offset made of its bus, device and function
+//! numbers (`BB:DD.F`). The configuration space identifies the function (vendor, device and
+//! class codes) and holds its Base Address Registers (BARs), which place its own registers in
+//! the bridge's memory window.
+//!
+//! `ecam` drives the generic host bridge of QEMU's `virt` machine. When it probes, the root bus
+//! is scanned, every memory BAR is given an address and the functions are recorded as
+//! `PciDevice`s; the `pci` init call then hands each one to the first `PCI_DRIVERS` entry
+//! matching it. `lspci` in the shell lists them.
+//!
+//! ## Design
+//!
+//! - Without firmware enumerating the bus, BARs are assigned here: sized by writing all ones,
+//!   then allocated upwards in the 32-bit memory window of the bridge's `ranges`, aligned to
+//!   their size. 64-bit BARs land there too, with a zero upper half; I/O BARs are left alone.
+//! - Only the root bus is scanned. Functions behind a bridge (such as QEMU's `pcie-root-port`)
+//!   are not reached, since the bridges' bus numbers and windows would have to be programmed.
+//! - A function's legacy interrupt (its INTx pin, from the Interrupt Pin register) is resolved
+//!   at enumeration through the `interrupt-map` of the host bridge (see `irq::of`). Functions on
+//!   the root bus are routed directly; behind a bridge the pin would first be swizzled by slot.
+//!   Drivers configure it in the GIC with `gic::configure_irq` if they don't poll.
+//! - Message-signaled interrupts (see `msi`) go to the GIC's ITS, found through the
+//!   capability list of the function; the ID the ITS knows the function by comes from the host
+//!   bridge's `msi-map`, resolved at enumeration too.
+//! - Drivers are bound once the IOMMU is up, in the `Driver` stage. Devices translated by the
+//!   IOMMU are not bound: their DMA would be aborted without a domain.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's PCI core (`drivers/pci/probe.c`, `setup-bus.c`) scans every bus behind every bridge,
+//! assigns bus numbers, sizes and places the resources of whole bridge hierarchies, and binds
+//! `pci_driver`s by `pci_device_id` tables, with hotplug, power management and quirks besides.
+//! INTx routing is done by `of_irq_parse_and_map_pci` when the device is enabled, swizzling the
+//! pin through the bridges up to the host bridge.
+
+pub mod ecam;
+pub mod msi;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::drivers::iommu;
+use crate::drivers::nvme;
+use crate::drivers::usb::xhci;
+use crate::drivers::virtio;
+use crate::ipc::init_cell::InitCell;
+use crate::kernel::device::{PlatformDevice, ProbeError};
+use crate::kernel::irq::of::{self, IrqSpec};
+use crate::{initcall, pr_err, pr_warn, println};
+
+/// Maximum number of functions recorded
+const MAX_PCI_DEVICES: usize = 32;
+
+/// Number of BARs of a type 0 (endpoint) header
+pub const BAR_COUNT: usize = 6;
+
+/* --- Configuration Space Constants --- */
+const VENDOR_ID: usize = 0x00;
+const DEVICE_ID: usize = 0x02;
+const COMMAND: usize = 0x04;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// The function doesn't assert its INTx pin
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const STATUS: usize = 0x06;
+/// The function has a capability list
+const STATUS_CAP_LIST: u16 = 1 << 4;
+/// Revision ID (byte 0), then the programming interface, subclass and class codes
+const CLASS_REVISION: usize = 0x08;
+const HEADER_TYPE: usize = 0x0e;
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_TYPE_ENDPOINT: u8 = 0;
+const BAR0: usize = 0x10;
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+const BAR_ADDR_MASK: u32 = !0xf;
+/// Offset of the first capability
+const CAP_PTR: usize = 0x34;
+/// Legacy interrupt pin: 0 for none, 1 to 4 for INTA to INTD
+const INTERRUPT_PIN: usize = 0x3d;
+/// Capabilities lie between the type 0 header and offset 256; a longer list loops
+const CAP_START: usize = 0x40;
+const MAX_CAPS: usize = (256 - CAP_START) / 4;
+
+/// Capability IDs
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_MSIX: u8 = 0x11;
+pub const CAP_ID_VENDOR: u8 = 0x09;
+
+/// Vendor ID read from an absent function
+const VENDOR_NONE: u16 = 0xffff;
+/// Devices per bus and functions per device
+const SLOTS: u8 = 32;
+const FUNCTIONS: u8 = 8;
+
+/// A memory BAR, as assigned by the enumeration
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Bar {
+    /// CPU physical address of the region
+    pub base: usize,
+    /// Size in bytes, a power of two
+    pub size: usize,
+    /// Whether reads have no side effects
+    pub prefetchable: bool,
+}
+
+/// A PCI function found by the enumeration
+#[derive(Clone, Copy, Debug)]
+pub struct PciDevice {
+    /// Address of the function's configuration space in the ECAM window
+    config: usize,
+    pub bus: u8,
+    pub slot: u8,
+    pub func: u8,
+    pub vendor: u16,
+    pub device: u16,
+    /// Class code, subclass and programming interface (e.g. `0x010802` for NVMe)
+    pub class: u32,
+    /// Memory BARs; the upper half of a 64-bit BAR has no entry of its own
+    pub bars: [Option<Bar>; BAR_COUNT],
+    /// Set when the device sits behind a translating IOMMU
+    pub behind_iommu: bool,
+    /// Legacy interrupt pin: 0 for none, 1 to 4 for INTA to INTD
+    pub pin: u8,
+    /// Interrupt the pin is routed to by the host bridge
+    pub irq: Option<IrqSpec>,
+    /// Device ID the function's MSIs reach the ITS with, if the host bridge maps them
+    pub msi_id: Option<u32>,
+}
+
+impl PciDevice {
+    pub fn read_config32(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.config + offset) as *const u32) }
+    }
+
+    pub fn read_config16(&self, offset: usize) -> u16 {
+        unsafe { core::ptr::read_volatile((self.config + offset) as *const u16) }
+    }
+
+    pub fn read_config8(&self, offset: usize) -> u8 {
+        unsafe { core::ptr::read_volatile((self.config + offset) as *const u8) }
+    }
+
+    pub fn write_config32(&self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.config + offset) as *mut u32, value) }
+    }
+
+    pub fn write_config16(&self, offset: usize, value: u16) {
+        unsafe { core::ptr::write_volatile((self.config + offset) as *mut u16, value) }
+    }
+
+    /// Returns BAR `index`, if it is a memory BAR that got an address
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        *self.bars.get(index)?
+    }
+
+    /// Lets the function issue DMA
+    pub fn enable_bus_master(&self) {
+        let command = self.read_config16(COMMAND);
+        self.write_config16(COMMAND, command | COMMAND_BUS_MASTER);
+    }
+
+    /// Stops the function from asserting its INTx pin, once it signals interrupts by message
+    pub fn disable_intx(&self) {
+        let command = self.read_config16(COMMAND);
+        self.write_config16(COMMAND, command | COMMAND_INTX_DISABLE);
+    }
+
+    /// Returns the ID and offset of every capability in the configuration space, in list order
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, usize)> + '_ {
+        let mut offset = if self.read_config16(STATUS) & STATUS_CAP_LIST != 0 {
+            (self.read_config8(CAP_PTR) & !0b11) as usize
+        } else {
+            0
+        };
+        let mut remaining = MAX_CAPS;
+        core::iter::from_fn(move || {
+            if offset < CAP_START || remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let cap = (self.read_config8(offset), offset);
+            offset = (self.read_config8(offset + 1) & !0b11) as usize;
+            Some(cap)
+        })
+    }
+
+    /// Returns the offset of capability `id` in the configuration space
+    pub fn find_capability(&self, id: u8) -> Option<usize> {
+        self.capabilities()
+            .find(|&(cap_id, _)| cap_id == id)
+            .map(|(_, offset)| offset)
+    }
+}
+
+/// Identifies the functions a driver supports
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PciId {
+    /// Class code, subclass and programming interface
+    Class(u32),
+    /// Vendor and device IDs
+    Device(u16, u16),
+    /// Any device of a vendor, for drivers telling them apart themselves
+    Vendor(u16),
+}
+
+impl PciId {
+    fn matches(self, dev: &PciDevice) -> bool {
+        match self {
+            PciId::Class(class) => dev.class == class,
+            PciId::Device(vendor, device) => dev.vendor == vendor && dev.device == device,
+            PciId::Vendor(vendor) => dev.vendor == vendor,
+        }
+    }
+}
+
+/// A PCI driver
+pub trait PciDriver: Sync {
+    /// Sets up the function `dev`, whose memory BARs are assigned and decoded
+    fn probe(&self, dev: &'static PciDevice) -> Result<(), ProbeError>;
+}
+
+/// Entry of the PCI driver table
+#[derive(Clone, Copy)]
+pub struct PciMatch {
+    pub id: PciId,
+    pub driver: &'static dyn PciDriver,
+}
+
+/// Drivers the functions are matched against, in order
+pub const PCI_DRIVERS: &[PciMatch] = &[
+    PciMatch {
+        id: PciId::Class(nvme::CLASS_NVME),
+        driver: &nvme::NvmeDriver,
+    },
+    PciMatch {
+        id: PciId::Class(xhci::CLASS_XHCI),
+        driver: &xhci::XhciPciDriver,
+    },
+    PciMatch {
+        id: PciId::Vendor(virtio::pci::VENDOR_VIRTIO),
+        driver: &virtio::pci::VirtioPciDriver,
+    },
+];
+
+/// A window of the host bridge that BARs are allocated from
+#[derive(Clone, Copy, Debug)]
+pub struct Window {
+    /// Address of the window on the PCI side, written to the BARs
+    pub pci_base: u64,
+    /// Address of the window on the CPU side
+    pub cpu_base: usize,
+    pub size: usize,
+    /// Offset of the first free byte
+    pub next: usize,
+}
+
+impl Window {
+    /// Reserves `size` bytes aligned to `size`, returning their offset in the window
+    fn alloc(&mut self, size: usize) -> Option<usize> {
+        let offset = self.next.next_multiple_of(size);
+        (offset.checked_add(size)? <= self.size).then(|| {
+            self.next = offset + size;
+            offset
+        })
+    }
+}
+
+/// Functions found so far, entries `[0, DEVICE_COUNT)` being set
+static DEVICES: [InitCell<PciDevice>; MAX_PCI_DEVICES] =
+    [const { InitCell::new() }; MAX_PCI_DEVICES];
+
+/// Number of initialized entries in `DEVICES`
+static DEVICE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Sizes BAR `index` of `dev` and gives it an address in `window`
+///
+/// Returns the number of BAR registers used, 2 for a 64-bit BAR.
+fn assign_bar(dev: &mut PciDevice, index: usize, window: &mut Window) -> usize {
+    let reg = BAR0 + index * 4;
+    let orig = dev.read_config32(reg);
+    if orig & BAR_IO != 0 {
+        return 1;
+    }
+    let is_64 = orig & BAR_TYPE_MASK == BAR_TYPE_64 && index + 1 < BAR_COUNT;
+    dev.write_config32(reg, u32::MAX);
+    let mut mask = (dev.read_config32(reg) & BAR_ADDR_MASK) as u64;
+    if is_64 {
+        dev.write_config32(reg + 4, u32::MAX);
+        mask |= (dev.read_config32(reg + 4) as u64) << 32;
+    } else {
+        mask |= 0xffff_ffff_0000_0000;
+    }
+    let used = if is_64 { 2 } else { 1 };
+    if mask & BAR_ADDR_MASK as u64 == 0 {
+        // Unimplemented BAR
+        return used;
+    }
+    let size = (!mask).wrapping_add(1) as usize;
+    let Some(offset) = window.alloc(size) else {
+        pr_warn!(
+            "pci: {:02x}:{:02x}.{}: no room for BAR{} ({} KiB)",
+            dev.bus,
+            dev.slot,
+            dev.func,
+            index,
+            size / 1024
+        );
+        return used;
+    };
+    let pci_addr = window.pci_base + offset as u64;
+    dev.write_config32(reg, pci_addr as u32 | (orig & !BAR_ADDR_MASK));
+    if is_64 {
+        dev.write_config32(reg + 4, (pci_addr >> 32) as u32);
+    }
+    dev.bars[index] = Some(Bar {
+        base: window.cpu_base + offset,
+        size,
+        prefetchable: orig & BAR_PREFETCHABLE != 0,
+    });
+    used
+}
+
+/// Returns the interrupt pin `pin` of function `func` of `slot` on `bus` is routed to by `host`
+fn route_intx(host: &PlatformDevice, bus: u8, slot: u8, func: u8, pin: u8) -> Option<IrqSpec> {
+    if pin == 0 {
+        return None;
+    }
+    // PCI unit address: bus, device and function in the high cell, no register offset
+    let devfn = (bus as u32) << 16 | (slot as u32) << 11 | (func as u32) << 8;
+    of::of_irq_parse_map(host, &[devfn, 0, 0], &[pin as u32])
+}
+
+/// Records function `func` of `slot` on `bus`, assigning its BARs from `window`
+///
+/// Its interrupt pin is routed through the `interrupt-map` of `host`.
+fn add_function(
+    config: usize,
+    bus: u8,
+    slot: u8,
+    func: u8,
+    window: &mut Window,
+    iommu: bool,
+    host: &PlatformDevice,
+) {
+    let mut dev = PciDevice {
+        config,
+        bus,
+        slot,
+        func,
+        vendor: 0,
+        device: 0,
+        class: 0,
+        bars: [None; BAR_COUNT],
+        behind_iommu: iommu,
+        pin: 0,
+        irq: None,
+        msi_id: of::of_msi_map_id(host, (bus as u32) << 8 | (slot as u32) << 3 | func as u32),
+    };
+    dev.vendor = dev.read_config16(VENDOR_ID);
+    dev.device = dev.read_config16(DEVICE_ID);
+    dev.class = dev.read_config32(CLASS_REVISION) >> 8;
+    dev.pin = dev.read_config8(INTERRUPT_PIN);
+    if dev.read_config8(HEADER_TYPE) & HEADER_TYPE_MASK != HEADER_TYPE_ENDPOINT {
+        println!(
+            "pci: {:02x}:{:02x}.{}: bridge {:04x}:{:04x} not configured",
+            bus, slot, func, dev.vendor, dev.device
+        );
+        return;
+    }
+
+    // Stop decoding while the BARs hold their sizing pattern
+    let command = dev.read_config16(COMMAND);
+    dev.write_config16(COMMAND, command & !COMMAND_MEMORY);
+    let mut index = 0;
+    while index < BAR_COUNT {
+        index += assign_bar(&mut dev, index, window);
+    }
+    dev.write_config16(COMMAND, command | COMMAND_MEMORY);
+
+    dev.irq = route_intx(host, bus, slot, func, dev.pin);
+    if dev.pin != 0 && dev.irq.is_none() {
+        pr_warn!(
+            "pci: {:02x}:{:02x}.{}: INT{} not in the interrupt-map",
+            bus,
+            slot,
+            func,
+            (b'A' + dev.pin - 1) as char
+        );
+    }
+
+    let slot_index = DEVICE_COUNT.load(Ordering::Acquire);
+    if slot_index == MAX_PCI_DEVICES || DEVICES[slot_index].set(dev).is_err() {
+        pr_warn!("pci: {:02x}:{:02x}.{}: device table full", bus, slot, func);
+        return;
+    }
+    DEVICE_COUNT.store(slot_index + 1, Ordering::Release);
+}
+
+/// Scans `bus` through the ECAM window at `ecam` (which starts at bus `first_bus`)
+///
+/// Memory BARs are allocated from `window`; `iommu` tells whether the bus masters are translated
+/// by the IOMMU. Interrupts are routed through the `interrupt-map` of the host bridge `host`.
+pub fn scan_bus(
+    ecam: usize,
+    first_bus: u8,
+    bus: u8,
+    window: &mut Window,
+    iommu: bool,
+    host: &PlatformDevice,
+) {
+    for slot in 0..SLOTS {
+        for func in 0..FUNCTIONS {
+            let config = ecam
+                + ((bus - first_bus) as usize) * (1 << 20)
+                + (slot as usize) * (1 << 15)
+                + (func as usize) * (1 << 12);
+            let vendor = unsafe { core::ptr::read_volatile((config + VENDOR_ID) as *const u16) };
+            if vendor == VENDOR_NONE {
+                if func == 0 {
+                    break;
+                }
+                continue;
+            }
+            add_function(config, bus, slot, func, window, iommu, host);
+            let header = unsafe { core::ptr::read_volatile((config + HEADER_TYPE) as *const u8) };
+            if func == 0 && header & HEADER_MULTI_FUNCTION == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Returns the function recorded at `index`
+pub fn device(index: usize) -> Option<&'static PciDevice> {
+    DEVICES.get(index)?.get()
+}
+
+/// Calls `f` on every function found, in enumeration order
+pub fn for_each(f: impl FnMut(&'static PciDevice)) {
+    (0..DEVICE_COUNT.load(Ordering::Acquire))
+        .filter_map(device)
+        .for_each(f);
+}
+
+/// Binds every function to the first driver matching it
+///
+/// Runs after `iommu::init`, as devices behind an IOMMU are recognized by then.
+pub fn init() {
+    for_each(|dev| {
+        let Some(entry) = PCI_DRIVERS.iter().find(|entry| entry.id.matches(dev)) else {
+            return;
+        };
+        if dev.behind_iommu && iommu::is_present() {
+            println!(
+                "pci: {:02x}:{:02x}.{}: translated by the IOMMU, not bound",
+                dev.bus, dev.slot, dev.func
+            );
+            return;
+        }
+        if let Err(e) = entry.driver.probe(dev) {
+            pr_err!(
+                "pci: {:02x}:{:02x}.{}: probe failed: {:?}",
+                dev.bus,
+                dev.slot,
+                dev.func,
+                e
+            );
+        }
+    });
+}
+initcall!(Driver, "pci", init, after = ["iommu"]);
+
+/// Prints every function with its IDs, class and memory BARs, like `lspci`
+pub fn dump() {
+    for_each(|dev| {
+        println!(
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:06x}",
+            dev.bus, dev.slot, dev.func, dev.vendor, dev.device, dev.class
+        );
+        for (index, bar) in dev.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                println!(
+                    "        BAR{}: {:#x} ({} KiB{})",
+                    index,
+                    bar.base,
+                    bar.size / 1024,
+                    if bar.prefetchable {
+                        ", prefetchable"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        if let Some(irq) = dev.irq {
+            println!(
+                "        INT{}: IRQ {}",
+                (b'A' + dev.pin - 1) as char,
+                irq.intid()
+            );
+        }
+        match msi::vector_count(dev) {
+            (0, _) => {}
+            (count, true) => println!("        MSI-X: {} vectors", count),
+            (_, false) => println!("        MSI"),
+        }
+    });
+}
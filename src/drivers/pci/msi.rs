@@ -0,0 +1,226 @@
+//! Message-signaled interrupts
+//!
+//! Instead of asserting an INTx pin shared with other functions, a function can signal an
+//! interrupt by writing a value (the message data) to an address (the doorbell). Two capabilities
+//! describe this:
+//!
+//! - MSI, in the configuration space: one address and data pair, and up to 32 vectors varying
+//!   the low bits of the data.
+//! - MSI-X, a table in one of the function's BARs: up to 2048 vectors, each with its own address,
+//!   data and mask bit.
+//!
+//! The doorbell is the GIC ITS's `GITS_TRANSLATER`, and the data the event ID, which the ITS
+//! maps to an LPI (see `gic::its`). `request_vector` sets up a vector and registers its handler,
+//! the way `irq::request_irq` does for a wired interrupt; `setup_vector` only sets it up.
+//!
+//! ## Design
+//!
+//! - Vector `n` is event `n` of the function's device ID, so the message data is the vector
+//!   index. MSI is given a single vector, vector 0.
+//! - MSI-X is preferred when the function has both. Its other vectors stay masked, as they are
+//!   out of reset, so a driver sets up only those it uses.
+//! - INTx is disabled once a vector is set up.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is `pci_alloc_irq_vectors` with `PCI_IRQ_MSIX | PCI_IRQ_MSI` followed by `request_irq`
+//! on `pci_irq_vector`, the ITS MSI domain (`irq-gic-v3-its-pci-msi.c`) allocating the LPIs.
+//! Linux also supports multiple MSI vectors, per-vector masking of MSI and affinity spreading.
+
+use crate::drivers::gic::{self, its};
+use crate::kernel::irq::{self, IrqError, IrqHandler};
+use crate::utilities::mmio;
+
+use super::{CAP_ID_MSI, CAP_ID_MSIX, PciDevice};
+
+/* --- MSI Capability Constants --- */
+/// Message control
+const MSI_CTRL: usize = 0x02;
+const MSI_CTRL_ENABLE: u16 = 1 << 0;
+/// Number of vectors enabled, as a power of two (bits 6:4)
+const MSI_CTRL_MME_MASK: u16 = 0b111 << 4;
+const MSI_CTRL_64BIT: u16 = 1 << 7;
+const MSI_ADDR_LO: usize = 0x04;
+const MSI_ADDR_HI: usize = 0x08;
+/// Message data, after the upper half of the address of a 64-bit capability
+const MSI_DATA_32: usize = 0x08;
+const MSI_DATA_64: usize = 0x0c;
+
+/* --- MSI-X Capability Constants --- */
+/// Message control
+const MSIX_CTRL: usize = 0x02;
+/// Number of vectors, minus one
+const MSIX_CTRL_TABLE_SIZE_MASK: u16 = 0x7ff;
+const MSIX_CTRL_MASK_ALL: u16 = 1 << 14;
+const MSIX_CTRL_ENABLE: u16 = 1 << 15;
+/// Offset of the table in its BAR, whose index is in bits 2:0
+const MSIX_TABLE: usize = 0x04;
+const MSIX_TABLE_BIR_MASK: u32 = 0b111;
+/// Table entries
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDR_LO: usize = 0x0;
+const MSIX_ENTRY_ADDR_HI: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_VECTOR_CTRL: usize = 0xc;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// Errors returned by `setup_vector` and `request_vector`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MsiError {
+    /// The function has neither capability, or its MSI-X table is in a BAR without an address
+    NotSupported,
+    /// There is no ITS, or the host bridge doesn't map the function's MSIs
+    NoController,
+    /// The function has no such vector
+    BadVector,
+    /// The ITS couldn't map the vector to an LPI
+    Its(its::ItsError),
+    /// The handler couldn't be registered
+    Irq(IrqError),
+}
+
+/// How a function signals its vectors
+#[derive(Clone, Copy)]
+enum Capability {
+    /// MSI capability at this offset
+    Msi(usize),
+    /// MSI-X capability at this offset, with its table at `table` and `count` vectors
+    Msix {
+        cap: usize,
+        table: usize,
+        count: usize,
+    },
+}
+
+impl Capability {
+    /// Returns the capability vectors are set up through, MSI-X if the function has it
+    fn find(dev: &PciDevice) -> Result<Self, MsiError> {
+        if let Some(cap) = dev.find_capability(CAP_ID_MSIX) {
+            let count =
+                (dev.read_config16(cap + MSIX_CTRL) & MSIX_CTRL_TABLE_SIZE_MASK) as usize + 1;
+            let table = dev.read_config32(cap + MSIX_TABLE);
+            let bar = dev
+                .bar((table & MSIX_TABLE_BIR_MASK) as usize)
+                .ok_or(MsiError::NotSupported)?;
+            return Ok(Capability::Msix {
+                cap,
+                table: bar.base + (table & !MSIX_TABLE_BIR_MASK) as usize,
+                count,
+            });
+        }
+        dev.find_capability(CAP_ID_MSI)
+            .map(Capability::Msi)
+            .ok_or(MsiError::NotSupported)
+    }
+
+    fn count(self) -> usize {
+        match self {
+            Capability::Msi(_) => 1,
+            Capability::Msix { count, .. } => count,
+        }
+    }
+}
+
+/// Returns the number of vectors `dev` has, and whether they are MSI-X ones
+///
+/// A function without either capability has none.
+pub fn vector_count(dev: &PciDevice) -> (usize, bool) {
+    match Capability::find(dev) {
+        Ok(cap) => (cap.count(), matches!(cap, Capability::Msix { .. })),
+        Err(_) => (0, false),
+    }
+}
+
+/// Points vector `vector` of `dev` at `doorbell`, with the vector index as data, and unmasks it
+fn program(dev: &PciDevice, cap: Capability, vector: usize, doorbell: u64) {
+    match cap {
+        Capability::Msi(cap) => {
+            let ctrl = dev.read_config16(cap + MSI_CTRL) & !(MSI_CTRL_ENABLE | MSI_CTRL_MME_MASK);
+            dev.write_config16(cap + MSI_CTRL, ctrl);
+            dev.write_config32(cap + MSI_ADDR_LO, doorbell as u32);
+            let data = if ctrl & MSI_CTRL_64BIT != 0 {
+                dev.write_config32(cap + MSI_ADDR_HI, (doorbell >> 32) as u32);
+                MSI_DATA_64
+            } else {
+                MSI_DATA_32
+            };
+            dev.write_config16(cap + data, vector as u16);
+            dev.write_config16(cap + MSI_CTRL, ctrl | MSI_CTRL_ENABLE);
+        }
+        Capability::Msix { cap, table, .. } => {
+            let entry = table + vector * MSIX_ENTRY_SIZE;
+            // The function mustn't send a message while the entry is half written
+            mmio::write_mmio32(entry, MSIX_ENTRY_VECTOR_CTRL, MSIX_ENTRY_MASKED);
+            mmio::write_mmio32(entry, MSIX_ENTRY_ADDR_LO, doorbell as u32);
+            mmio::write_mmio32(entry, MSIX_ENTRY_ADDR_HI, (doorbell >> 32) as u32);
+            mmio::write_mmio32(entry, MSIX_ENTRY_DATA, vector as u32);
+            mmio::write_mmio32(entry, MSIX_ENTRY_VECTOR_CTRL, 0);
+            let ctrl = dev.read_config16(cap + MSIX_CTRL);
+            dev.write_config16(
+                cap + MSIX_CTRL,
+                (ctrl | MSIX_CTRL_ENABLE) & !MSIX_CTRL_MASK_ALL,
+            );
+        }
+    }
+}
+
+/// Points vector `vector` of `dev` at an LPI and returns its INTID, configured but not enabled
+///
+/// For drivers registering the handler themselves, like the one of a wired interrupt configured
+/// with `gic::configure_irq`. The vector goes through MSI-X if the function has it, otherwise
+/// MSI, whose only vector is 0. The function's INTx pin is disabled.
+pub fn setup_vector(dev: &PciDevice, vector: usize) -> Result<u32, MsiError> {
+    let doorbell = its::doorbell().ok_or(MsiError::NoController)?;
+    let device_id = dev.msi_id.ok_or(MsiError::NoController)?;
+    let cap = Capability::find(dev)?;
+    if vector >= cap.count() {
+        return Err(MsiError::BadVector);
+    }
+    let intid = its::map_event(device_id, vector as u32).map_err(MsiError::Its)?;
+    program(dev, cap, vector, doorbell);
+    dev.disable_intx();
+    Ok(intid)
+}
+
+/// Sets up vector `vector` of `dev`, calling `handler` with `data` when it fires, and returns
+/// the INTID of its LPI
+///
+/// See `setup_vector`; the LPI is enabled as well.
+pub fn request_vector(
+    dev: &PciDevice,
+    vector: usize,
+    name: &'static str,
+    handler: IrqHandler,
+    data: usize,
+) -> Result<u32, MsiError> {
+    let intid = setup_vector(dev, vector)?;
+    if let Err(e) = irq::request_irq(intid, name, handler, data) {
+        release(dev, vector, intid);
+        return Err(MsiError::Irq(e));
+    }
+    gic::enable_irq(intid);
+    Ok(intid)
+}
+
+/// Masks vector `vector` of `dev` and unmaps its LPI `intid`
+fn release(dev: &PciDevice, vector: usize, intid: u32) {
+    match Capability::find(dev) {
+        Ok(Capability::Msix { table, count, .. }) if vector < count => {
+            let entry = table + vector * MSIX_ENTRY_SIZE;
+            mmio::write_mmio32(entry, MSIX_ENTRY_VECTOR_CTRL, MSIX_ENTRY_MASKED);
+        }
+        Ok(Capability::Msi(cap)) => {
+            let ctrl = dev.read_config16(cap + MSI_CTRL);
+            dev.write_config16(cap + MSI_CTRL, ctrl & !MSI_CTRL_ENABLE);
+        }
+        _ => {}
+    }
+    let _ = its::unmap_event(intid);
+}
+
+/// Masks vector `vector` of `dev`, set up by `request_vector` with LPI `intid`, and removes its
+/// handler
+pub fn free_vector(dev: &PciDevice, vector: usize, intid: u32) {
+    irq::free_irq(intid);
+    release(dev, vector, intid);
+}
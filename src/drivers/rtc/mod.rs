@@ -0,0 +1,28 @@
+//! Real-time clock drivers
+//!
+//! A real-time clock keeps the time of day across resets and power-offs, as seconds since the
+//! Unix epoch (UTC). The kernel reads it once at boot to set `time::walltime`, and writes it
+//! back when the time is set from the network, so the next boot starts from a good value.
+//!
+//! Only the ARM PL031 is supported.
+
+pub mod pl031;
+
+/// Errors returned by the real-time clock drivers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RtcError {
+    /// No real-time clock was found in the DTB
+    NoDevice,
+    /// The time doesn't fit in the counter
+    Invalid,
+}
+
+/// Returns the seconds since the Unix epoch the real-time clock counts
+pub fn read_time() -> Result<u64, RtcError> {
+    pl031::read()
+}
+
+/// Sets the real-time clock to `secs` seconds since the Unix epoch
+pub fn set_time(secs: u64) -> Result<(), RtcError> {
+    pl031::set(secs)
+}
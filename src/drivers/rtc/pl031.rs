@@ -0,0 +1,94 @@
+//! ARM PL031 real-time clock driver
+//!
+//! The PL031 is a 32-bit counter incremented once per second by a 1 Hz clock. `RTCDR` reads the
+//! counter; writing `RTCLR` loads it. The kernel keeps seconds since the Unix epoch in it, as
+//! Linux does, which lasts until 2106. The match interrupt, an alarm, isn't used and is left
+//! masked.
+//!
+//! The device is described in the DTB by an `arm,pl031` node. QEMU's `virt` machine has one,
+//! started at the host's time (UTC unless `-rtc base=localtime`).
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::device::{self, ProbeError};
+use crate::println;
+
+use super::RtcError;
+
+/// Data register, the counter value
+const RTCDR: usize = 0x000;
+/// Load register; a write sets the counter
+const RTCLR: usize = 0x008;
+/// Control register
+const RTCCR: usize = 0x00C;
+/// Interrupt mask register
+const RTCIMSC: usize = 0x010;
+
+/// Starts the counter; it can't be stopped once started
+const CR_START: u32 = 1 << 0;
+
+/// A PL031 found in the DTB
+#[derive(Clone, Copy)]
+struct Pl031 {
+    base: usize,
+}
+
+impl Pl031 {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) };
+    }
+}
+
+/// The device; only one real-time clock is driven
+static DEVICE: Mutex<Option<Pl031>> = Mutex::new(None);
+
+/// Runs `f` on the device
+fn with_device<R>(f: impl FnOnce(&Pl031) -> R) -> Result<R, RtcError> {
+    DEVICE
+        .lock_irqsafe(|device| device.as_ref().map(f))
+        .ok_or(RtcError::NoDevice)
+}
+
+/// Returns the counter, seconds since the Unix epoch
+pub fn read() -> Result<u64, RtcError> {
+    with_device(|rtc| rtc.read(RTCDR) as u64)
+}
+
+/// Loads the counter with `secs` seconds since the Unix epoch
+pub fn set(secs: u64) -> Result<(), RtcError> {
+    let secs = u32::try_from(secs).map_err(|_| RtcError::Invalid)?;
+    with_device(|rtc| rtc.write(RTCLR, secs))
+}
+
+/// Driver for `arm,pl031` nodes
+pub struct Pl031Driver;
+
+impl device::Driver for Pl031Driver {
+    /// Sets up the PL031 from its `reg` property, starting the counter if it isn't running
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        if DEVICE.lock_irqsafe(|device| device.is_some()) {
+            println!("pl031: only one RTC is supported, ignoring {}", dev.name);
+            return Err(ProbeError::NoResources);
+        }
+        let Some(base) = dev.reg(0).map(|region| region.base) else {
+            println!("pl031: {} has no address", dev.name);
+            return Err(ProbeError::NoDevice);
+        };
+        let rtc = Pl031 { base };
+        rtc.write(RTCIMSC, 0);
+        if rtc.read(RTCCR) & CR_START == 0 {
+            rtc.write(RTCCR, CR_START);
+        }
+        DEVICE.lock_irqsafe(|device| *device = Some(rtc));
+        println!("pl031: RTC at {:#x}", base);
+        Ok(())
+    }
+
+    fn remove(&self, _dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        DEVICE.lock_irqsafe(|device| *device = None);
+        Ok(())
+    }
+}
@@ -10,19 +10,47 @@
 //! - `CNTP_TVAL_EL0`: Timer value (countdown from this value)
 //! - `CNTP_CVAL_EL0`: Compare value (fire when counter reaches this)
 //! - `CNTP_CTL_EL0`: Control register (enable, mask, status)
+//!
+//! ## Scheduler Tick
+//!
+//! The tick is programmed with absolute compare values, `TICK_HZ` times per second. In dynamic
+//! tick mode (the default, `nohz=off` on the command line disables it) the idle task stops it
+//! while nothing is ready to run: the timer is then only programmed for the next software timer
+//! deadline (`kernel::time::hrtimer` or the watchdog heartbeat) or before the clock source
+//! wraps, or masked if neither can happen, and the CPU sleeps through the ticks in between. The
+//! tick restarts when the CPU wakes up, the ticks missed meanwhile being skipped.
+//!
+//! Every CPU has its own timer, and so its own tick: the boot CPU starts its tick at boot, the
+//! secondary ones as they come up (`start_cpu_tick`). The timekeeping done on the tick, keeping
+//! the clock source and the watchdog heartbeat going, is left to the boot CPU; the others only
+//! tick the scheduler. The software timers are shared: every CPU programs its timer for the
+//! next one, and whichever takes the interrupt first runs it.
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-use crate::drivers::gic::gicv3;
-use crate::kernel::device;
+use crate::drivers::gic;
+use crate::drivers::watchdog;
+use crate::kernel::device::{self, ProbeError};
 use crate::kernel::dtb;
-use crate::utilities::convert;
+use crate::kernel::irq;
+use crate::kernel::irq::of::{IrqKind, of_irq_parse};
+use crate::kernel::sched;
+use crate::kernel::smp::{self, MAX_CPUS};
+use crate::kernel::time::{clocksource, hrtimer};
+use crate::{initcall, println};
 
 /// CNTP_CTL_EL0 bits
 const CTL_ENABLE: u64 = 1 << 0; // Timer enabled
 const CTL_IMASK: u64 = 1 << 1; // Interrupt masked
 const CTL_ISTATUS: u64 = 1 << 2; // Interrupt status (read-only)
 
+/// Index of the non-secure physical timer in the node's interrupts, after the secure one
+const NS_PHYS_TIMER_IRQ: usize = 1;
+
+/// Frequency of the scheduler tick, in Hz
+pub const TICK_HZ: u64 = 100;
+
 /// Returns the timer frequency in Hz
 #[inline(always)]
 pub fn get_frequency() -> u64 {
@@ -132,71 +160,142 @@ pub fn is_pending() -> bool {
     (get_ctl() & CTL_ISTATUS) != 0
 }
 
-/// Arms the timer to fire after `ticks` counter increments
+/// Counter value at which each CPU's next scheduler tick is due, 0 until it started its tick
+static NEXT_TICK: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Set if the idle task may stop the tick
+static NOHZ: AtomicBool = AtomicBool::new(false);
+
+/// Set while a CPU's tick is stopped, between `tick_nohz_enter` and `tick_nohz_exit`
+static TICK_STOPPED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Returns true on the CPU keeping the time, which the timekeeping deadlines wake up
+fn keeps_time() -> bool {
+    smp::this_cpu() == 0
+}
+
+/// Returns the number of counter increments between two scheduler ticks
+fn tick_period() -> u64 {
+    get_frequency() / TICK_HZ
+}
+
+/// Starts the periodic scheduler tick of the boot CPU, `TICK_HZ` times per second
+///
+/// Dynamic tick mode is enabled unless the command line has `nohz=off`.
+pub fn start_tick() {
+    println!("Starting the scheduler tick ({} Hz)", TICK_HZ);
+    NOHZ.store(dtb::bootarg("nohz") != Some("off"), Ordering::Relaxed);
+    start_cpu_tick();
+}
+initcall!(Late, "tick", start_tick, after = ["sched", "watchdog"]);
+
+/// Starts the calling CPU's scheduler tick
 ///
-/// This enables the timer and unmasks the interrupt.
-pub fn arm(ticks: u32) {
-    set_timer_value(ticks);
-    set_ctl(CTL_ENABLE); // Enable, unmask (IMASK=0)
+/// Called by a secondary CPU as it comes up, once `gic::init_cpu` has enabled the timer's
+/// interrupt there.
+pub fn start_cpu_tick() {
+    let next = get_counter() + tick_period();
+    NEXT_TICK[smp::this_cpu()].store(next, Ordering::Relaxed);
+    TICK_STOPPED[smp::this_cpu()].store(false, Ordering::Relaxed);
+    set_compare_value(next);
+    set_ctl(CTL_ENABLE);
 }
 
-/// Arms the timer to fire after `ms` milliseconds
+/// Stops the scheduler tick until `tick_nohz_exit`, if dynamic tick mode is enabled
 ///
-/// Uses the timer frequency to calculate the appropriate tick count.
-pub fn arm_ms(ms: u32) {
-    let freq = get_frequency();
-    let ticks = (freq / 1000) * ms as u64;
-    arm(ticks as u32);
+/// Called by the idle task with interrupts masked, right before it puts the CPU to sleep. The
+/// timer is programmed for the next software timer deadline, or masked if there is none.
+pub fn tick_nohz_enter() {
+    let cpu = smp::this_cpu();
+    if !NOHZ.load(Ordering::Relaxed) || NEXT_TICK[cpu].load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let next_event = if keeps_time() {
+        // The clock source must be read before it wraps
+        let max_idle = clocksource::ns_to_ticks(clocksource::max_idle_ns());
+        let limit = get_counter().saturating_add(max_idle);
+        [watchdog::next_event(), hrtimer::next_expiry()]
+            .into_iter()
+            .flatten()
+            .fold(limit, u64::min)
+    } else {
+        hrtimer::next_expiry().unwrap_or(u64::MAX)
+    };
+    if next_event == u64::MAX {
+        set_ctl(CTL_ENABLE | CTL_IMASK);
+    } else {
+        set_compare_value(next_event);
+    }
+    TICK_STOPPED[cpu].store(true, Ordering::Relaxed);
+}
+
+/// Restarts the scheduler tick stopped by `tick_nohz_enter`
+///
+/// Called with interrupts masked once the CPU has woken up. The timer is programmed for the tick
+/// that was due when the tick stopped: it is most likely in the past, so the interrupt is taken
+/// as soon as interrupts are unmasked and catches up with the missed ticks.
+pub fn tick_nohz_exit() {
+    let cpu = smp::this_cpu();
+    if TICK_STOPPED[cpu].swap(false, Ordering::Relaxed) {
+        let next = NEXT_TICK[cpu].load(Ordering::Relaxed);
+        set_compare_value(hrtimer::next_expiry().map_or(next, |expires| expires.min(next)));
+        set_ctl(CTL_ENABLE);
+    }
 }
 
-/// Rearms the timer with the same interval
+/// Makes the timer fire at `expires` at the latest, for a high-resolution timer
 ///
-/// Call this in the interrupt handler to set up the next timer tick.
-pub fn rearm(ticks: u32) {
-    set_timer_value(ticks);
+/// Called by `hrtimer` with interrupts masked, on the calling CPU's timer. A deadline in the past
+/// fires right away.
+pub fn program_event(expires: u64) {
+    if TICK_STOPPED[smp::this_cpu()].load(Ordering::Relaxed) {
+        // `tick_nohz_exit` programs the timer from scratch
+        return;
+    }
+    if expires < get_compare_value() {
+        set_compare_value(expires);
+    }
 }
 
-/// Sets up the ARM Generic Timer from device tree properties
+/// Timer interrupt handler: runs the expired high-resolution timers, notifies the scheduler if a
+/// tick is due and programs the timer for whichever comes next
 ///
-/// Parses the `interrupts` property to find the non-secure physical timer interrupt
-/// (second entry in the timer node's interrupt list), then configures it as a PPI
-/// in the GIC redistributor with appropriate trigger mode, priority, and group.
-pub fn setup(dev: &device::PlatformDevice) {
-    let mut interrupt_info: [u32; gicv3::MAX_INTERRUPT_CELLS] = [0; gicv3::MAX_INTERRUPT_CELLS];
-    // Parse interrupts property
-    if let Some(int_prop) = dev.find_property("interrupts") {
-        if let Some(intc) = dtb::find_interrupt_parent(dev) {
-            // Get #interrupt-cells from interrupt controller
-            let mut interrupt_cells: u32 = 3; // Default for GICv3
-            if let Some(cells_prop) = intc.find_property("#interrupt-cells") {
-                interrupt_cells = convert::read_be_u32(cells_prop.value, 0);
-            }
-
-            // Read interrupt specifier cells
-            let ns_offset = interrupt_cells as usize * 4;
-            for i in 0..interrupt_cells.min(gicv3::MAX_INTERRUPT_CELLS as u32) {
-                unsafe {
-                    interrupt_info[i as usize] =
-                        convert::read_be_u32(int_prop.value.add(ns_offset), (i * 4) as usize);
-                }
-            }
-
-            // interrupt_info[0] = irq_type (0 = SPI, 1 = PPI)
-            // interrupt_info[1] = interrupt_number
-            // interrupt_info[2] = flags (trigger type)
-            if interrupt_info[0] == 1 {
-                let ppi_id = 16 + interrupt_info[1];
-                // bits 0-1: edge trigger (1=rising, 2=falling)
-                // bits 2-3: level trigger (4=high, 8=low)
-                if (interrupt_info[2] & 0x3) != 0 {
-                    gicv3::set_ppi_trigger_edge(ppi_id);
-                } else {
-                    gicv3::set_ppi_trigger_level(ppi_id);
-                }
-                gicv3::set_ppi_priority(ppi_id, 0x00);
-                gicv3::set_ppi_group(ppi_id);
-                gicv3::enable_ppi(ppi_id);
-            }
+/// Ticks missed while the tick was stopped are skipped rather than replayed.
+fn handle_irq(_id: u32, _data: usize) {
+    hrtimer::run_expired();
+    let period = tick_period();
+    let now = get_counter();
+    let next_tick = &NEXT_TICK[smp::this_cpu()];
+    let mut next = next_tick.load(Ordering::Relaxed);
+    if now >= next {
+        next += period * ((now - next) / period + 1);
+        next_tick.store(next, Ordering::Relaxed);
+        if keeps_time() {
+            clocksource::update();
+            watchdog::tick();
+        }
+        sched::tick();
+    }
+    set_compare_value(hrtimer::next_expiry().map_or(next, |expires| expires.min(next)));
+}
+
+/// Driver for `arm,armv8-timer` and `arm,armv7-timer` nodes
+pub struct ArchTimerDriver;
+
+impl device::Driver for ArchTimerDriver {
+    /// Sets up the ARM Generic Timer from device tree properties
+    ///
+    /// Looks up the non-secure physical timer interrupt (`NS_PHYS_TIMER_IRQ`) in the node's
+    /// interrupts, then configures it as a PPI in the GIC redistributor with the appropriate
+    /// trigger mode, priority, and group.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let spec = of_irq_parse(dev, NS_PHYS_TIMER_IRQ)
+            .filter(|spec| spec.kind == IrqKind::Ppi)
+            .ok_or(ProbeError::NoDevice)?;
+        let ppi_id = gic::configure_irq(&spec);
+        if irq::request_irq(ppi_id, "arch_timer", handle_irq, 0).is_ok() {
+            gic::enable_irq(ppi_id);
         }
+        Ok(())
     }
 }
@@ -3,4 +3,4 @@
 pub mod arch_timer;
 
 // Re-export commonly used functions for convenience
-pub use arch_timer::setup;
+pub use arch_timer::ArchTimerDriver;
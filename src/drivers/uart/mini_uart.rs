@@ -0,0 +1,185 @@
+//! A driver for the BCM2835 auxiliary mini-UART
+//!
+//! The Raspberry Pi 4 wires its GPIO 14/15 serial port to the mini-UART of the auxiliary
+//! peripherals block when Bluetooth holds the PL011, which makes it the board's console. It is a
+//! cut-down 16550: 8-entry FIFOs, 7 or 8 data bits, no parity, and a baud rate derived from the
+//! VPU core clock.
+//!
+//! ## Design
+//!
+//! - The single instance takes the next `ttyS` name (`ttyS0` on the Raspberry Pi) and is
+//!   registered with the console subsystem like a PL011.
+//! - Both directions are polled. The receive interrupt is shared with the auxiliary SPIs through
+//!   one line of the `brcm,bcm2835-aux` node, which has no driver here.
+//! - The firmware enables the UART (`enable_uart=1`) and sets its divisor for the core clock it
+//!   runs at, which `enable_uart=1` also keeps from scaling with the VPU load. The divisor is only
+//!   reprogrammed when the `clocks` property resolves to a rate.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux drives it with the 8250 core (`8250_bcm2835aux.c`), as a 16550 with a quirky divisor
+//! and with its interrupt. The registers are those of a 16550 spaced by 4 bytes, with the
+//! divisor in its own register.
+
+use crate::ipc::init_cell::InitCell;
+use crate::kernel::clk::{self, ClkError};
+use crate::kernel::console::{self, Console, ConsoleOptions};
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::notifier::Deadline;
+use crate::utilities::mmio;
+use crate::{pr_warn, println};
+
+/* --- Mini-UART Register Constants --- */
+const IO_OFF: usize = 0x00;
+const IER_OFF: usize = 0x04;
+const IIR_OFF: usize = 0x08;
+/// Writing 1 to both bits clears the FIFOs
+const IIR_CLEAR_FIFOS: u32 = 0b11 << 1;
+const LCR_OFF: usize = 0x0c;
+/// 8-bit mode; the bit above is reserved but must also be set on the BCM2835
+const LCR_8BIT: u32 = 0b11;
+const MCR_OFF: usize = 0x10;
+const LSR_OFF: usize = 0x14;
+const LSR_DATA_READY: u32 = 1 << 0;
+const LSR_TX_EMPTY: u32 = 1 << 5;
+const LSR_TX_IDLE: u32 = 1 << 6;
+const CNTL_OFF: usize = 0x20;
+const CNTL_RX_EN: u32 = 1 << 0;
+const CNTL_TX_EN: u32 = 1 << 1;
+const BAUD_OFF: usize = 0x28;
+
+/// The instance, set by the driver's probe
+static PORT: InitCell<MiniUart> = InitCell::new();
+
+/// The mini-UART instance
+pub struct MiniUart {
+    /// tty-style name of the instance
+    name: &'static str,
+    /// The base memory mapped address of the UART registers
+    base_addr: usize,
+    /// The core clock frequency the divisor is computed from, 0 if unknown
+    base_clock: u64,
+    /// The configured baud rate
+    baudrate: u32,
+}
+
+impl MiniUart {
+    /// Creates an instance named `name` for the UART at `base_addr`
+    pub const fn new(name: &'static str, base_addr: usize, base_clock: u64, baudrate: u32) -> Self {
+        Self {
+            name,
+            base_addr,
+            base_clock,
+            baudrate,
+        }
+    }
+
+    /// Configures the UART for 8N1 without interrupts nor flow control
+    ///
+    /// Keeps the divisor programmed by the firmware when the core clock is unknown.
+    pub fn configure(&self) {
+        // Wait for the firmware's last bytes to go out before stopping the transmitter
+        while (mmio::read_mmio32(self.base_addr, LSR_OFF) & LSR_TX_IDLE) == 0 {}
+        mmio::write_mmio32(self.base_addr, CNTL_OFF, 0);
+        mmio::write_mmio32(self.base_addr, IER_OFF, 0);
+        mmio::write_mmio32(self.base_addr, LCR_OFF, LCR_8BIT);
+        mmio::write_mmio32(self.base_addr, MCR_OFF, 0);
+        mmio::write_mmio32(self.base_addr, IIR_OFF, IIR_CLEAR_FIFOS);
+        if self.base_clock != 0 {
+            // baudrate = core clock / (8 * (divisor + 1))
+            let divisor = self.base_clock / (8 * self.baudrate as u64) - 1;
+            mmio::write_mmio32(self.base_addr, BAUD_OFF, divisor as u32 & 0xffff);
+        }
+        mmio::write_mmio32(self.base_addr, CNTL_OFF, CNTL_RX_EN | CNTL_TX_EN);
+    }
+
+    /// Writes a single byte, spinning until the TX FIFO has space
+    pub fn putchar(&self, c: u8) {
+        early_putchar(self.base_addr, c);
+    }
+
+    /// Reads a single byte from the RX FIFO
+    pub fn getchar(&self) -> Option<u8> {
+        if (mmio::read_mmio32(self.base_addr, LSR_OFF) & LSR_DATA_READY) == 0 {
+            return None;
+        }
+        Some(mmio::read_mmio32(self.base_addr, IO_OFF) as u8)
+    }
+
+    /// Waits until the last byte has left the shift register
+    ///
+    /// Returns false if `deadline` expired before the UART went idle.
+    pub fn flush(&self, deadline: &Deadline) -> bool {
+        while (mmio::read_mmio32(self.base_addr, LSR_OFF) & LSR_TX_IDLE) == 0 {
+            if deadline.expired() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Console for MiniUart {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn putchar(&self, c: u8) {
+        MiniUart::putchar(self, c);
+    }
+
+    fn getchar(&self) -> Option<u8> {
+        MiniUart::getchar(self)
+    }
+
+    fn flush(&self, deadline: &Deadline) -> bool {
+        MiniUart::flush(self, deadline)
+    }
+}
+
+/// Writes a single byte to the mini-UART at `base`, without an instance
+///
+/// Used by `console::earlycon`. The firmware is expected to have configured the UART.
+pub fn early_putchar(base: usize, c: u8) {
+    while (mmio::read_mmio32(base, LSR_OFF) & LSR_TX_EMPTY) == 0 {}
+    mmio::write_mmio32(base, IO_OFF, c as u32);
+}
+
+/// Driver for `brcm,bcm2835-aux-uart` nodes
+pub struct MiniUartDriver;
+
+impl device::Driver for MiniUartDriver {
+    /// Sets up the mini-UART from its `reg` and `clocks` properties
+    ///
+    /// The line settings come from `/chosen/stdout-path` if it selects this UART, but only the
+    /// baud rate can be changed.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        if PORT.is_set() {
+            println!(
+                "mini-uart: a single instance is supported, {} ignored",
+                dev.name
+            );
+            return Err(ProbeError::NoResources);
+        }
+        let freq = match clk::clk_get(dev, 0) {
+            Ok(clk) => clk.get_rate(),
+            Err(ClkError::NotReady) => return Err(ProbeError::Defer),
+            Err(e) => {
+                pr_warn!("mini-uart: no clock for {}: {:?}", dev.name, e);
+                0
+            }
+        };
+        let addr = dev.reg(0).ok_or(ProbeError::NoDevice)?.base;
+        let options = console::options_for(dev).unwrap_or(ConsoleOptions::DEFAULT);
+        let name = super::alloc_ttys_name().ok_or(ProbeError::NoResources)?;
+        let port = MiniUart::new(name, addr, freq, options.baud);
+        port.configure();
+        let Ok(port) = PORT.set(port) else {
+            return Err(ProbeError::NoResources);
+        };
+        if console::register(port, dev).is_err() {
+            println!("mini-uart: console registry full, {} not registered", name);
+        }
+        Ok(())
+    }
+}
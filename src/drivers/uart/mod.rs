@@ -1,6 +1,33 @@
-//! PL011 UART driver module
+//! UART drivers module
+//!
+//! `pl011` and `ns16550` are always built; `mini_uart`, the Raspberry Pi's auxiliary UART, needs
+//! the `mini-uart` feature.
+//!
+//! As in Linux, PL011 ports are named `ttyAMA<n>` and 16550-style ports `ttyS<n>`, the latter
+//! numbered across the drivers handling them with `alloc_ttys_name`.
 
+#[cfg(feature = "mini-uart")]
+pub mod mini_uart;
+pub mod ns16550;
 pub mod pl011;
 
-// Re-export commonly used functions for convenience
-pub use pl011::{getchar, putchar};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Maximum number of `ttyS` ports
+const MAX_TTYS: usize = 4;
+
+/// Names of the `ttyS` ports, in probe order
+const TTYS_NAMES: [&str; MAX_TTYS] = ["ttyS0", "ttyS1", "ttyS2", "ttyS3"];
+
+/// Number of `ttyS` names handed out
+static TTYS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Hands out the next free `ttyS<n>` name, `None` once they are all taken
+pub fn alloc_ttys_name() -> Option<&'static str> {
+    TTYS_COUNT
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+            (n < MAX_TTYS).then_some(n + 1)
+        })
+        .ok()
+        .map(|n| TTYS_NAMES[n])
+}
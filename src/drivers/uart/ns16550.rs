@@ -0,0 +1,452 @@
+//! A driver for 16550-compatible UARTs
+//!
+//! The 16550 (and the 8250 it extends) is the most widespread UART design: it is found on x86
+//! PCs, in most SoCs not using a PL011 and in QEMU machines. Its registers are 8 bits wide, one
+//! per address in the original part; SoCs often space them out and access them as 32-bit words,
+//! which the DTB describes with `reg-shift` and `reg-io-width`.
+//!
+//! ## Design
+//!
+//! Every DTB node matching `ns16550a` or `ns16550` gets its own `Ns16550` instance, named
+//! `ttyS0`, `ttyS1`, ... (see `uart::alloc_ttys_name`) and registered with the console subsystem,
+//! as with the PL011:
+//!
+//! - **Transmission (TX)** is polled, waiting for room in the TX FIFO (`LSR.THRE`).
+//! - **Reception (RX)** is interrupt-driven: the "received data available" and "RX timeout"
+//!   interrupts fire once the FIFO reaches its trigger level, or when bytes have sat in it for a
+//!   while. The top half drains the FIFO into a staging ring, and deferred work moves the bytes to
+//!   the RX buffer `getchar` reads, waking up sleepers. A task holding the input channel gets the
+//!   bytes from the top half directly.
+//! - The FIFOs are enabled and flushed at configuration, with the RX trigger at 8 bytes: a
+//!   quarter of the interrupts of a trigger at 1, with room left before the 16-byte FIFO
+//!   overflows.
+//!
+//! The divisor is `clock / (16 * baud)`. The clock comes from `clocks`, or else from the
+//! `clock-frequency` property most 16550 nodes carry; without either, the divisor set by the
+//! firmware is kept.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's 8250 core handles dozens of variants and their errata (`8250_port.c`), with
+//! `of_serial.c` matching the DTB nodes. Only a plain 16550A is supported here, plus the
+//! auto flow control bit of its successors: no modem status interrupts, no DMA.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::drivers::gic;
+use crate::ipc::channel::Channel;
+use crate::ipc::init_cell::InitCell;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::spsc::Ring;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::clk::{self, ClkError};
+use crate::kernel::console::{
+    self, Console, ConsoleOptions, INPUT_QUEUE_SIZE, InputReceiver, Parity,
+};
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::irq::of::{IrqKind, of_irq_parse};
+use crate::kernel::irq::{self, softirq};
+use crate::kernel::notifier::Deadline;
+use crate::utilities::mmio;
+use crate::{pr_warn, println};
+
+/// The size of the RX buffers
+const UART_BUFFER_SIZE: usize = 256;
+
+/// Maximum number of 16550 instances the driver can manage
+const MAX_PORTS: usize = 4;
+
+/// A ring of received bytes, between the interrupt handler, the bottom half and `getchar`
+type UartBuffer = Ring<u8, UART_BUFFER_SIZE>;
+
+/* --- 16550 Register Constants (register numbers, before `reg-shift`) --- */
+/// Receiver Buffer (read) / Transmitter Holding (write) Register; Divisor Latch LSB with DLAB
+const RBR_THR: usize = 0;
+/// Interrupt Enable Register; Divisor Latch MSB with DLAB
+const IER: usize = 1;
+const IER_RDI: u32 = 1 << 0;
+/// Interrupt Identification (read) / FIFO Control (write) Register
+const IIR_FCR: usize = 2;
+const FCR_ENABLE: u32 = 1 << 0;
+const FCR_CLEAR_RX: u32 = 1 << 1;
+const FCR_CLEAR_TX: u32 = 1 << 2;
+/// RX FIFO trigger level of 8 bytes
+const FCR_TRIGGER_8: u32 = 0b10 << 6;
+/// Line Control Register
+const LCR: usize = 3;
+const LCR_PEN: u32 = 1 << 3;
+const LCR_EPS: u32 = 1 << 4;
+/// Divisor Latch Access Bit: registers 0 and 1 become the divisor
+const LCR_DLAB: u32 = 1 << 7;
+/// Modem Control Register
+const MCR: usize = 4;
+const MCR_DTR: u32 = 1 << 0;
+const MCR_RTS: u32 = 1 << 1;
+/// Gates the interrupt output on PC-style boards
+const MCR_OUT2: u32 = 1 << 3;
+/// Auto flow control (16750 and most SoC UARTs): RTS/CTS driven by the FIFO levels
+const MCR_AFE: u32 = 1 << 5;
+/// Line Status Register
+const LSR: usize = 5;
+const LSR_DR: u32 = 1 << 0;
+const LSR_THRE: u32 = 1 << 5;
+const LSR_TEMT: u32 = 1 << 6;
+
+/// A 16550 UART instance
+pub struct Ns16550 {
+    /// tty-style name of the instance (e.g. `ttyS0`)
+    name: &'static str,
+    /// The base memory mapped address of the UART registers
+    base_addr: usize,
+    /// Log2 of the spacing between registers, from `reg-shift`
+    reg_shift: u32,
+    /// Width of the register accesses in bytes (1 or 4), from `reg-io-width`
+    io_width: u32,
+    /// The input clock frequency, 0 if unknown
+    base_clock: u64,
+    /// Line settings
+    options: ConsoleOptions,
+    /// Interrupt ID (INTID) of the UART interrupt, 0 if the device has none
+    irq: u32,
+    /// Bytes drained from the RX FIFO by the interrupt handler, not yet processed
+    rx_staging: UartBuffer,
+    /// Bytes processed by the bottom half and not yet read
+    rx: UartBuffer,
+    /// Serializes the readers of `rx`
+    rx_read: Mutex<()>,
+    /// Tasks sleeping in `getchar_blocking`
+    rx_wait: WaitQueue,
+    /// Received bytes sent by the interrupt handler while a task holds the receiver
+    input: Channel<u8, INPUT_QUEUE_SIZE>,
+}
+
+/// The 16550 instances discovered from the DTB, in probe order
+static PORTS: [InitCell<Ns16550>; MAX_PORTS] = [const { InitCell::new() }; MAX_PORTS];
+
+/// Number of initialized entries in `PORTS`
+static PORT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl Ns16550 {
+    /// Creates an instance named `name` for the UART at `base_addr`
+    pub const fn new(
+        name: &'static str,
+        base_addr: usize,
+        reg_shift: u32,
+        io_width: u32,
+        base_clock: u64,
+    ) -> Self {
+        Self {
+            name,
+            base_addr,
+            reg_shift,
+            io_width,
+            base_clock,
+            options: ConsoleOptions::DEFAULT,
+            irq: 0,
+            rx_staging: UartBuffer::new(),
+            rx: UartBuffer::new(),
+            rx_read: Mutex::new(()),
+            rx_wait: WaitQueue::new(),
+            input: Channel::new(),
+        }
+    }
+
+    /// Applies the line settings in `options`
+    pub fn set_options(&mut self, options: &ConsoleOptions) {
+        self.options = *options;
+    }
+
+    /// Reads register `reg`
+    fn read(&self, reg: usize) -> u32 {
+        let addr = self.base_addr + (reg << self.reg_shift);
+        if self.io_width == 4 {
+            mmio::read_mmio32(addr, 0)
+        } else {
+            unsafe { core::ptr::read_volatile(addr as *const u8) as u32 }
+        }
+    }
+
+    /// Writes `value` to register `reg`
+    fn write(&self, reg: usize, value: u32) {
+        let addr = self.base_addr + (reg << self.reg_shift);
+        if self.io_width == 4 {
+            mmio::write_mmio32(addr, 0, value);
+        } else {
+            unsafe { core::ptr::write_volatile(addr as *mut u8, value as u8) };
+        }
+    }
+
+    /// Configures the line, the FIFOs and the RX interrupt
+    pub fn configure(&self) {
+        // 1. Mask the interrupts and let the TX FIFO drain
+        self.write(IER, 0);
+        while self.read(LSR) & LSR_TEMT == 0 {}
+        // 2. Set speed
+        self.set_speed();
+        // 3. Configure the data frame format
+        let mut lcr = (self.options.data_bits as u32).saturating_sub(5) & 0x3;
+        match self.options.parity {
+            Parity::None => {}
+            Parity::Odd => lcr |= LCR_PEN,
+            Parity::Even => lcr |= LCR_PEN | LCR_EPS,
+        }
+        self.write(LCR, lcr);
+        // 4. Enable and flush the FIFOs
+        self.write(
+            IIR_FCR,
+            FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_TRIGGER_8,
+        );
+        // 5. Assert the modem lines, and OUT2 for boards gating the interrupt with it
+        let mut mcr = MCR_DTR | MCR_RTS | MCR_OUT2;
+        if self.options.flow_control {
+            mcr |= MCR_AFE;
+        }
+        self.write(MCR, mcr);
+        // 6. Enable the RX interrupts (data available and timeout)
+        if self.irq != 0 {
+            self.write(IER, IER_RDI);
+        }
+    }
+
+    /// Programs the divisor latch for the configured baud rate
+    ///
+    /// Without a known input clock, the divisor programmed by the firmware is kept.
+    fn set_speed(&self) {
+        if self.base_clock == 0 {
+            return;
+        }
+        let divisor = (self.base_clock / (16 * self.options.baud as u64)) as u32;
+        let lcr = self.read(LCR);
+        self.write(LCR, lcr | LCR_DLAB);
+        self.write(RBR_THR, divisor & 0xff);
+        self.write(IER, (divisor >> 8) & 0xff);
+        self.write(LCR, lcr);
+    }
+
+    /// Write a single byte
+    ///
+    /// This function will block and spin until the UART's TX FIFO has space
+    pub fn putchar(&self, c: u8) {
+        while self.read(LSR) & LSR_THRE == 0 {}
+        self.write(RBR_THR, c as u32);
+    }
+
+    /// Reads a single byte from the interrupt-driven RX buffer
+    ///
+    /// Without an interrupt, the RX FIFO is polled instead.
+    pub fn getchar(&self) -> Option<u8> {
+        if self.irq == 0 {
+            return (self.read(LSR) & LSR_DR != 0).then(|| self.read(RBR_THR) as u8);
+        }
+        // The only consumer of `rx` is whoever holds `rx_read`
+        self.rx_read.lock(|_| unsafe { self.rx.consumer() }.pop())
+    }
+
+    /// Reads a single byte from the RX buffer, sleeping until one is received
+    pub fn getchar_blocking(&self) -> u8 {
+        loop {
+            self.rx_wait.wait_event(|| !self.rx.is_empty());
+            // Another reader may have taken the byte first
+            if let Some(c) = self.getchar() {
+                return c;
+            }
+        }
+    }
+
+    /// Waits until every byte has left the transmitter
+    ///
+    /// Returns false if `deadline` expired before the UART went idle.
+    pub fn flush(&self, deadline: &Deadline) -> bool {
+        while self.read(LSR) & LSR_TEMT == 0 {
+            if deadline.expired() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// RX top half: drains the RX FIFO, which also clears the interrupt
+    ///
+    /// The bytes go to the input channel if a task holds its receiver, otherwise to the staging
+    /// buffer. Returns true if bytes were staged for the bottom half.
+    fn handle_rx(&self) -> bool {
+        let mut staged = false;
+        // The interrupt handler is the only one sending to the input channel
+        match self.input.sender().filter(|_| self.input.has_receiver()) {
+            Some(mut sender) => {
+                while self.read(LSR) & LSR_DR != 0 {
+                    let c = self.read(RBR_THR) as u8;
+                    if !console::intercept(self.name, c) {
+                        let _ = sender.try_send(c);
+                    }
+                }
+            }
+            None => {
+                // The interrupt handler is the only producer of the staging buffer
+                let mut staging = unsafe { self.rx_staging.producer() };
+                while self.read(LSR) & LSR_DR != 0 {
+                    let c = self.read(RBR_THR) as u8;
+                    if !console::intercept(self.name, c) {
+                        let _ = staging.push(c);
+                        staged = true;
+                    }
+                }
+            }
+        }
+        staged
+    }
+
+    /// RX bottom half: moves the staged bytes to the RX buffer and wakes up the readers
+    fn process_rx(&self) {
+        // Deferred work doesn't run concurrently with itself: the bottom half is the only
+        // consumer of the staging buffer and the only producer of the RX buffer
+        let mut staging = unsafe { self.rx_staging.consumer() };
+        let mut rx = unsafe { self.rx.producer() };
+        while let Some(c) = staging.pop() {
+            let _ = rx.push(c);
+        }
+        self.rx_wait.wake_up();
+    }
+
+    /// Returns the tty-style name of the instance
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the interrupt ID of the UART interrupt (0 if none)
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+}
+
+impl Console for Ns16550 {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn putchar(&self, c: u8) {
+        Ns16550::putchar(self, c);
+    }
+
+    fn getchar(&self) -> Option<u8> {
+        Ns16550::getchar(self)
+    }
+
+    fn getchar_blocking(&self) -> u8 {
+        if self.irq == 0 {
+            // Polled: the default implementation idles between reads
+            loop {
+                if let Some(c) = self.getchar() {
+                    return c;
+                }
+                irq::wait_for_interrupt();
+            }
+        }
+        Ns16550::getchar_blocking(self)
+    }
+
+    fn input(&'static self) -> Option<InputReceiver> {
+        self.input.receiver()
+    }
+
+    fn flush(&self, deadline: &Deadline) -> bool {
+        Ns16550::flush(self, deadline)
+    }
+}
+
+/// Returns the instance probed in position `index`
+pub fn port(index: usize) -> Option<&'static Ns16550> {
+    PORTS.get(index)?.get()
+}
+
+/// Returns the number of instances probed so far
+pub fn port_count() -> usize {
+    PORT_COUNT.load(Ordering::Acquire)
+}
+
+/// UART interrupt handler; `data` is the index of the instance that raised the interrupt
+fn handle_irq(_id: u32, data: usize) {
+    if let Some(port) = port(data)
+        && port.handle_rx()
+        && softirq::schedule_work(rx_work, data).is_err()
+    {
+        // The bytes stay staged until the next interrupt manages to schedule the work
+        println!("ns16550: {} RX work queue full", port.name());
+    }
+}
+
+/// Deferred work scheduled by the RX interrupt, `data` is the instance index
+fn rx_work(data: usize) {
+    if let Some(port) = port(data) {
+        port.process_rx();
+    }
+}
+
+/// Reads the single-cell property `name` of `dev`
+fn read_u32(dev: &device::PlatformDevice, name: &str) -> Option<u32> {
+    let prop = dev.find_property(name)?;
+    (prop.len == 4).then(|| prop.read_cells(0, 1) as u32)
+}
+
+/// Driver for `ns16550a` and `ns16550` nodes
+pub struct Ns16550Driver;
+
+impl device::Driver for Ns16550Driver {
+    /// Sets up a 16550 UART from device tree properties
+    ///
+    /// Takes the base address from `reg`, the register layout from `reg-shift` and
+    /// `reg-io-width`, the input clock from `clocks` or `clock-frequency` and the interrupt from
+    /// the first `interrupts` entry, which must be an SPI. The instance selected by
+    /// `/chosen/stdout-path` gets the line settings of the path suffix, the others 115200 8N1.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let index = PORT_COUNT.load(Ordering::Acquire);
+        if index == MAX_PORTS {
+            println!("ns16550: no free instance for {}", dev.name);
+            return Err(ProbeError::NoResources);
+        }
+        let freq = match clk::clk_get(dev, 0) {
+            Ok(clk) => clk.get_rate(),
+            Err(ClkError::NotReady) => return Err(ProbeError::Defer),
+            Err(e) => match read_u32(dev, "clock-frequency") {
+                Some(freq) => freq as u64,
+                None => {
+                    pr_warn!("ns16550: no clock for {}: {:?}", dev.name, e);
+                    0
+                }
+            },
+        };
+        let addr = dev.reg(0).ok_or(ProbeError::NoDevice)?.base;
+        let reg_shift = read_u32(dev, "reg-shift").unwrap_or(0);
+        let io_width = match read_u32(dev, "reg-io-width").unwrap_or(1) {
+            width @ (1 | 4) => width,
+            width => {
+                pr_warn!("ns16550: {}: unsupported reg-io-width {}", dev.name, width);
+                return Err(ProbeError::NotSupported);
+            }
+        };
+        let name = super::alloc_ttys_name().ok_or(ProbeError::NoResources)?;
+
+        // Only an SPI can be routed to this core
+        let irq_id = of_irq_parse(dev, 0)
+            .filter(|spec| spec.kind == IrqKind::Spi)
+            .map_or(0, |spec| gic::configure_irq(&spec));
+
+        let options = console::options_for(dev).unwrap_or(ConsoleOptions::DEFAULT);
+        let mut port = Ns16550::new(name, addr, reg_shift, io_width, freq);
+        port.set_options(&options);
+        port.irq = irq_id;
+        port.configure();
+        let Ok(port) = PORTS[index].set(port) else {
+            return Err(ProbeError::NoResources);
+        };
+        PORT_COUNT.store(index + 1, Ordering::Release);
+
+        if irq_id != 0 && irq::request_irq(irq_id, name, handle_irq, index).is_ok() {
+            gic::enable_irq(irq_id);
+        }
+        if console::register(port, dev).is_err() {
+            println!("ns16550: console registry full, {} not registered", name);
+        }
+        Ok(())
+    }
+}
@@ -1,151 +1,167 @@
 //! A driver for the PL011 UART serial port
 //!
-//! This module provides functions to initialize, configure, and interact with a PL011 UART device.
+//! This module provides functions to initialize, configure, and interact with PL011 UART devices.
 //!
 //! ## Design
 //!
-//! The driver uses a mixed model for handling communication:
+//! Every DTB node matching `arm,pl011` gets its own `Pl011` instance, with its own registers, RX
+//! buffer and interrupt. Instances are named `ttyAMA0`, `ttyAMA1`, ... in probe order and
+//! registered with the console subsystem, which decides which one is the system console. The
+//! others remain available by name, e.g. as a debug channel.
+//!
+//! Each instance uses a mixed model for handling communication:
 //!
 //! - **Transmission (TX):** Writing characters (`putchar`, `print`) is done via **polling**. The
 //!   code will wait in a loop until the UART's transmit buffer is ready to accept a new character.
 //!
-//! - **Reception (RX):** Receiving characters is **interrupt-driven**. The interrupt handler (defined
-//!   in `exceptions.rs`) reads the incoming byte and `push` it into the global `RX_BUFFER`. The
-//!   `getchar` function then safely reads from this buffer.
+//! - **Reception (RX):** Receiving characters is **interrupt-driven** and split in two halves. The
+//!   interrupt handler only drains the data register into a small staging buffer and clears the
+//!   interrupt; it then schedules deferred work (`kernel::irq::softirq`) which moves the bytes to
+//!   the instance's RX buffer, or hands them to the RX hook. The `getchar` function then safely
+//!   reads from the RX buffer, and `getchar_blocking` sleeps on the instance's wait queue until
+//!   the bottom half has buffered a byte. When a task has claimed the instance's input channel
+//!   (`Console::input`), the interrupt handler sends the bytes straight to it instead.
 //!
 //! ## Concurrency
 //!
-//! The global `RX_BUFFER` is shared between the UART and any kernel code that calls `getchar`. To
-//! prevent race conditions and deadlocks, it is protected by the interrupt safe `Mutex` from
-//! `crate::irq_safe_mutex`
+//! The RX buffers are lock-free single-producer single-consumer rings (`crate::ipc::spsc`): the
+//! staging buffer goes from the interrupt handler to the bottom half, and the RX buffer from the
+//! bottom half to `getchar`. Several tasks may call `getchar`, so its side of the RX buffer is
+//! serialized by a `Mutex`.
 
-use core::ptr::addr_of_mut;
 use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering;
 
-use crate::drivers::gic::gicv3;
+use crate::drivers::gic;
+use crate::ipc::channel::Channel;
+use crate::ipc::init_cell::InitCell;
 use crate::ipc::irq_safe_mutex::Mutex;
-use crate::kernel::device;
-use crate::kernel::dtb;
-use crate::utilities::convert;
+use crate::ipc::spsc::Ring;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::clk::{self, ClkError};
+use crate::kernel::console::{
+    self, Console, ConsoleOptions, INPUT_QUEUE_SIZE, InputReceiver, Parity,
+};
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::irq::of::{IrqKind, of_irq_parse};
+use crate::kernel::irq::{self, softirq};
+use crate::kernel::notifier::Deadline;
 use crate::utilities::mmio;
+use crate::{pr_warn, println};
 
 /// The size of the circular buffer used for receiving UART data
 const UART_BUFFER_SIZE: usize = 256;
 
+/// Maximum number of PL011 instances the driver can manage
+const MAX_PORTS: usize = 4;
+
+/// tty names given to the instances, in probe order
+const PORT_NAMES: [&str; MAX_PORTS] = ["ttyAMA0", "ttyAMA1", "ttyAMA2", "ttyAMA3"];
+
 /// A circular buffer for storing incoming UART data
 ///
-/// This buffer is designed to be written to by the UART interrupt handler and read from the
-/// kernel's main execution context
-pub struct UartBuffer {
-    /// The underlying array for the buffer
-    buffer: [u8; UART_BUFFER_SIZE],
-    /// The index where the next byte will be written
-    head: AtomicUsize,
-    /// The index from which the next byte will be read
-    tail: AtomicUsize,
-}
+/// Written by one side (the interrupt handler or the bottom half) and read by the other, without
+/// a lock.
+type UartBuffer = Ring<u8, UART_BUFFER_SIZE>;
 
-/// Global static instance of the UART RX buffer.
+/// A PL011 UART instance
 ///
-/// This buffer is protected by the interrupt-safe `Mutex` to allow for safe, concurrent access
-/// from both the UART interrupt handler (the producer) and the kernel's character-reading
-/// functions (the consumer)
-pub static RX_BUFFER: Mutex<UartBuffer> = Mutex::new(UartBuffer {
-    buffer: [0; UART_BUFFER_SIZE],
-    head: AtomicUsize::new(0),
-    tail: AtomicUsize::new(0),
-});
-
-impl UartBuffer {
-    /// Pushes a byte into the circular buffer
-    pub fn push(&mut self, byte: u8) -> bool {
-        let head = self.head.load(Ordering::Relaxed);
-        let next_head = (head + 1) % UART_BUFFER_SIZE;
-        if next_head == self.tail.load(Ordering::Relaxed) {
-            return false;
-        }
-
-        self.buffer[head] = byte;
-        self.head.store(next_head, Ordering::Relaxed);
-        true
-    }
-
-    /// Pops a byte from the circular buffer
-    fn pop(&mut self) -> Option<u8> {
-        let byte;
-        let next_tail;
-        let tail = self.tail.load(Ordering::Relaxed);
-        if self.head.load(Ordering::Relaxed) == tail {
-            return None;
-        }
-
-        byte = self.buffer[tail];
-        next_tail = (tail + 1) % UART_BUFFER_SIZE;
-        self.tail.store(next_tail, Ordering::Relaxed);
-        Some(byte)
-    }
-}
-
-// Define the address of the UART device (MMIO)
-// In C: volatile uint8_t *uart = ...
-/// A struct holding the configuration and state of the PL011 UART device
-struct UartPl011 {
+/// Holds the configuration and state of one PL011 device discovered from the DTB.
+pub struct Pl011 {
+    /// tty-style name of the instance (e.g. `ttyAMA0`)
+    name: &'static str,
     /// The base memory mapped address of the UART registers
-    base_addr: *mut u32,
-    /// The base clock frequency of the UART peripheral
-    base_clock: u32,
+    base_addr: usize,
+    /// The base clock frequency of the UART peripheral, 0 if unknown
+    base_clock: u64,
     /// The configured baud rate
     baudrate: u32,
     /// The number of data bits
     data_bits: u8,
     /// The number of stop bits
     stop_bits: u8,
+    /// The parity mode
+    parity: Parity,
+    /// Whether RTS/CTS hardware flow control is enabled
+    flow_control: bool,
+    /// Interrupt ID (INTID) of the RX interrupt, 0 if the device has none
+    irq: u32,
+    /// Bytes drained from the RX FIFO by the interrupt handler, not yet processed
+    ///
+    /// Filled by the top half, drained by the bottom half (directly or through the RX hook).
+    rx_staging: UartBuffer,
+    /// Bytes processed by the bottom half and not yet read
+    ///
+    /// Filled by the bottom half, drained by `getchar` with `rx_read` held.
+    rx: UartBuffer,
+    /// Serializes the readers of `rx`, which may be several tasks
+    rx_read: Mutex<()>,
+    /// Tasks sleeping in `getchar_blocking`
+    rx_wait: WaitQueue,
+    /// Received bytes sent by the interrupt handler while a task holds the receiver
+    input: Channel<u8, INPUT_QUEUE_SIZE>,
+    /// When set, the RX interrupt calls this function instead of filling `rx`
+    rx_hook: Mutex<Option<fn(&Pl011)>>,
 }
 
-/// Early console base address (used before DTB-based driver initialization)
-///
-/// The bootloader/firmware is expected to have already configured the UART
-/// at this address. The early console just writes to it — no hardware setup.
-#[cfg(feature = "qemu-virt")]
-const EARLY_BASE: usize = 0x0900_0000;
-
 /* --- PL011 UART Register Constants --- */
 const DR_OFF: usize = 0x00;
 const FR_OFF: usize = 0x18;
 const FR_BUSY: u32 = 1 << 3;
+const FR_RXFE: u32 = 1 << 4;
 const FR_TXFE: u32 = 1 << 5;
 const IBRD_OFF: usize = 0x24;
 const FBRD_OFF: usize = 0x28;
 const LCR_OFF: usize = 0x2c;
+const LCR_PEN: u32 = 1 << 1;
+const LCR_EPS: u32 = 1 << 2;
 const LCR_FEN: u32 = 1 << 4;
 const LCR_STP2: u32 = 1 << 3;
 const CR_OFF: usize = 0x30;
 const CR_UARTEN: u32 = 1 << 0;
 const CR_RXEN: u32 = 1 << 9;
+const CR_RTSEN: u32 = 1 << 14;
+const CR_CTSEN: u32 = 1 << 15;
 const IMSC_OFF: usize = 0x38;
 const IMSC_RXIM: u32 = 1 << 4;
-pub const ICR_OFF: usize = 0x44;
-pub const ICR_RXIC: u32 = 1 << 4;
+const ICR_OFF: usize = 0x44;
+const ICR_RXIC: u32 = 1 << 4;
 const DMACR_OFF: usize = 0x48;
 
-/// The global, mutable instance representing the system's UART device
-static mut UART: UartPl011 = UartPl011::new();
+/// The PL011 instances discovered from the DTB, in probe order
+///
+/// An instance is configured by the probe before being stored, and only accessed through shared
+/// references afterwards.
+static PORTS: [InitCell<Pl011>; MAX_PORTS] = [const { InitCell::new() }; MAX_PORTS];
+
+/// Number of initialized entries in `PORTS`
+static PORT_COUNT: AtomicUsize = AtomicUsize::new(0);
 
-impl UartPl011 {
+impl Pl011 {
     /// Const constructor for static initialization
     pub const fn new() -> Self {
         Self {
-            base_addr: core::ptr::null_mut(),
+            name: "",
+            base_addr: 0,
             base_clock: 0,
             baudrate: 115200,
             data_bits: 8,
             stop_bits: 1,
+            parity: Parity::None,
+            flow_control: false,
+            irq: 0,
+            rx_staging: UartBuffer::new(),
+            rx: UartBuffer::new(),
+            rx_read: Mutex::new(()),
+            rx_wait: WaitQueue::new(),
+            input: Channel::new(),
+            rx_hook: Mutex::new(None),
         }
     }
 
     /// Initialize with hardware-specific details
-    pub fn init(&mut self, base_addr: *mut u32, base_clock: u32) {
+    pub fn init(&mut self, name: &'static str, base_addr: usize, base_clock: u64) {
+        self.name = name;
         self.base_addr = base_addr;
         self.base_clock = base_clock;
     }
@@ -165,14 +181,32 @@ impl UartPl011 {
         self.stop_bits = stop_bits;
     }
 
+    /// Set parity mode
+    pub fn set_parity(&mut self, parity: Parity) {
+        self.parity = parity;
+    }
+
+    /// Enable or disable RTS/CTS hardware flow control
+    pub fn set_flow_control(&mut self, enabled: bool) {
+        self.flow_control = enabled;
+    }
+
+    /// Applies the line settings in `options`
+    pub fn set_options(&mut self, options: &ConsoleOptions) {
+        self.set_baudrate(options.baud);
+        self.set_data_bits(options.data_bits);
+        self.set_parity(options.parity);
+        self.set_flow_control(options.flow_control);
+    }
+
     /// Configure the UART hardware registers
     pub fn configure(&self) {
         // 1. Disable the UART
-        mmio::write_mmio32(self.base_addr as usize, CR_OFF, 0);
+        mmio::write_mmio32(self.base_addr, CR_OFF, 0);
         // 2. Wait for the end of TX
-        while (mmio::read_mmio32(self.base_addr as usize, FR_OFF) & FR_BUSY) != 0 {}
+        while (mmio::read_mmio32(self.base_addr, FR_OFF) & FR_BUSY) != 0 {}
         // 3. Flush RX/TX fifos
-        mmio::clear_mmio_bits32(self.base_addr as usize, LCR_OFF, LCR_FEN);
+        mmio::clear_mmio_bits32(self.base_addr, LCR_OFF, LCR_FEN);
 
         // 4. Set speed
         self.set_speed();
@@ -185,197 +219,289 @@ impl UartPl011 {
         if self.stop_bits == 2 {
             lcr_val |= LCR_STP2;
         }
+        // 5.3 Parity: bit LCR_PEN enables it, bit LCR_EPS selects even parity
+        match self.parity {
+            Parity::None => {}
+            Parity::Odd => lcr_val |= LCR_PEN,
+            Parity::Even => lcr_val |= LCR_PEN | LCR_EPS,
+        }
         // 6. Enable FIFOs
         lcr_val |= LCR_FEN;
 
-        mmio::write_mmio32(self.base_addr as usize, LCR_OFF, lcr_val);
+        mmio::write_mmio32(self.base_addr, LCR_OFF, lcr_val);
         // 7. Enable RX interrupt
-        mmio::set_mmio_bits32(self.base_addr as usize, IMSC_OFF, IMSC_RXIM);
+        mmio::set_mmio_bits32(self.base_addr, IMSC_OFF, IMSC_RXIM);
         // 8. Disable DMA
-        mmio::write_mmio32(self.base_addr as usize, DMACR_OFF, 0x01);
-        // 9. Enable RX and UART
-        mmio::set_mmio_bits32(self.base_addr as usize, CR_OFF, CR_UARTEN | CR_RXEN);
+        mmio::write_mmio32(self.base_addr, DMACR_OFF, 0x01);
+        // 9. Enable RX and UART (and hardware flow control if requested)
+        let mut cr_val = CR_UARTEN | CR_RXEN;
+        if self.flow_control {
+            cr_val |= CR_RTSEN | CR_CTSEN;
+        }
+        mmio::set_mmio_bits32(self.base_addr, CR_OFF, cr_val);
     }
 
     /// Set baud rate divisor registers
+    ///
+    /// Without a known base clock, the divisor programmed by the firmware is kept.
     fn set_speed(&self) {
-        let baud_div = 4 * self.base_clock / self.baudrate;
-        mmio::write_mmio32(self.base_addr as usize, IBRD_OFF, (baud_div >> 6) & 0xffff);
-        mmio::write_mmio32(self.base_addr as usize, FBRD_OFF, baud_div & 0x3f);
+        if self.base_clock == 0 {
+            return;
+        }
+        let baud_div = (4 * self.base_clock / self.baudrate as u64) as u32;
+        mmio::write_mmio32(self.base_addr, IBRD_OFF, (baud_div >> 6) & 0xffff);
+        mmio::write_mmio32(self.base_addr, FBRD_OFF, baud_div & 0x3f);
     }
 
     /// Write a single byte
     ///
-    /// If the UART has not been initialized yet (base address is null),
-    /// falls back to the early console base address.
+    /// This function will block and spin until the UART's TX FIFO has space
     pub fn putchar(&self, c: u8) {
-        let base = if self.base_addr.is_null() {
-            EARLY_BASE
-        } else {
-            self.base_addr as usize
-        };
-        while (mmio::read_mmio32(base, FR_OFF) & FR_TXFE) != 0 {}
-        mmio::write_mmio32(base, DR_OFF, c as u32);
+        while (mmio::read_mmio32(self.base_addr, FR_OFF) & FR_TXFE) != 0 {}
+        mmio::write_mmio32(self.base_addr, DR_OFF, c as u32);
     }
-}
 
-/// Initializes the global UART struct with hardware-specific details
-fn init_uart(base_addr: *mut u32, base_clock: u32) {
-    unsafe {
-        (*addr_of_mut!(UART)).init(base_addr, base_clock);
+    /// Reads a single byte from the interrupt-driven RX buffer
+    pub fn getchar(&self) -> Option<u8> {
+        // The only consumer of `rx` is whoever holds `rx_read`
+        self.rx_read.lock(|_| unsafe { self.rx.consumer() }.pop())
     }
-}
 
-/// Sets the baud rate (call before configure_uart)
-pub fn set_baudrate(baudrate: u32) {
-    unsafe {
-        (*addr_of_mut!(UART)).set_baudrate(baudrate);
+    /// Reads a single byte from the RX buffer, sleeping until one is received
+    pub fn getchar_blocking(&self) -> u8 {
+        loop {
+            self.rx_wait.wait_event(|| !self.rx.is_empty());
+            // Another reader may have taken the byte first
+            if let Some(c) = self.getchar() {
+                return c;
+            }
+        }
     }
-}
 
-/// Sets the number of data bits (call before configure_uart)
-pub fn set_data_bits(data_bits: u8) {
-    unsafe {
-        (*addr_of_mut!(UART)).set_data_bits(data_bits);
+    /// Waits until every byte in the TX FIFO has left the shift register
+    ///
+    /// Returns false if `deadline` expired before the UART went idle.
+    pub fn flush(&self, deadline: &Deadline) -> bool {
+        while (mmio::read_mmio32(self.base_addr, FR_OFF) & FR_BUSY) != 0 {
+            if deadline.expired() {
+                return false;
+            }
+        }
+        true
     }
-}
 
-/// Sets the number of stop bits (call before configure_uart)
-pub fn set_stop_bits(stop_bits: u8) {
-    unsafe {
-        (*addr_of_mut!(UART)).set_stop_bits(stop_bits);
+    /// Reads a received byte that has not been processed yet, bypassing the RX buffer
+    ///
+    /// Bytes already drained by the interrupt handler are returned first, then the RX FIFO is
+    /// polled. Meant for users that own the port, such as the GDB stub.
+    pub fn poll_getchar(&self) -> Option<u8> {
+        // Runs in the bottom half (RX hook) or with the bottom half stopped (GDB stub), so it is
+        // the only consumer of the staging buffer
+        if let Some(c) = unsafe { self.rx_staging.consumer() }.pop() {
+            return Some(c);
+        }
+        if (mmio::read_mmio32(self.base_addr, FR_OFF) & FR_RXFE) != 0 {
+            return None;
+        }
+        Some(mmio::read_mmio32(self.base_addr, DR_OFF) as u8)
     }
-}
 
-/// Configures the UART hardware registers for operation
-///
-/// This function performs the hardware specific setup sequence for the PL011 UART, including
-/// setting the baud rate, data format and enabling interrupts
-#[unsafe(no_mangle)]
-pub fn configure_uart() {
-    unsafe {
-        (*addr_of_mut!(UART)).configure();
+    /// Installs (or removes, with `None`) a function the RX bottom half calls instead of buffering
+    ///
+    /// The hook is expected to consume the received bytes with `poll_getchar`.
+    pub fn set_rx_hook(&self, hook: Option<fn(&Pl011)>) {
+        self.rx_hook.lock_irqsafe(|h| *h = hook);
     }
-}
 
-/// Writes a single byte to the UART data register
-///
-/// This function will block and spin until the UART's TX FIFO has space
-pub fn putchar(c: u8) {
-    unsafe {
-        (*addr_of_mut!(UART)).putchar(c);
+    /// RX top half: drains the RX FIFO and clears the interrupt
+    ///
+    /// The bytes go to the input channel if a task holds its receiver, otherwise to the staging
+    /// buffer. Returns true if bytes were staged for the bottom half. Runs in interrupt context,
+    /// where interrupts are already masked.
+    fn handle_rx(&self) -> bool {
+        let mut staged = false;
+        // The interrupt handler is the only one sending to the input channel
+        match self.input.sender().filter(|_| self.input.has_receiver()) {
+            Some(mut sender) => {
+                while (mmio::read_mmio32(self.base_addr, FR_OFF) & FR_RXFE) == 0 {
+                    let c = mmio::read_mmio32(self.base_addr, DR_OFF) as u8;
+                    if !console::intercept(self.name(), c) {
+                        // Dropped if the reader is too slow, like a full staging buffer
+                        let _ = sender.try_send(c);
+                    }
+                }
+            }
+            None => {
+                // The interrupt handler is the only producer of the staging buffer
+                let mut staging = unsafe { self.rx_staging.producer() };
+                while (mmio::read_mmio32(self.base_addr, FR_OFF) & FR_RXFE) == 0 {
+                    let c = mmio::read_mmio32(self.base_addr, DR_OFF) as u8;
+                    if !console::intercept(self.name(), c) {
+                        let _ = staging.push(c);
+                        staged = true;
+                    }
+                }
+            }
+        }
+        mmio::write_mmio32(self.base_addr, ICR_OFF, ICR_RXIC);
+        staged
+    }
+
+    /// RX bottom half: hands the staged bytes to the RX hook, or moves them to the RX buffer
+    fn process_rx(&self) {
+        if let Some(hook) = self.rx_hook.lock_irqsafe(|h| *h) {
+            hook(self);
+            return;
+        }
+        // Deferred work doesn't run concurrently with itself: the bottom half is the only
+        // consumer of the staging buffer and the only producer of the RX buffer
+        let mut staging = unsafe { self.rx_staging.consumer() };
+        let mut rx = unsafe { self.rx.producer() };
+        while let Some(c) = staging.pop() {
+            let _ = rx.push(c);
+        }
+        self.rx_wait.wake_up();
     }
-}
 
-/// Reads a single byte from the interrupt-driven RX buffer
-pub fn getchar() -> Option<u8> {
-    return RX_BUFFER.lock_irqsafe(|rx| rx.pop());
+    /// Returns the tty-style name of the instance
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the UART base address
+    pub fn base_addr(&self) -> usize {
+        self.base_addr
+    }
+
+    /// Returns the interrupt ID of the RX interrupt (0 if none)
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
 }
 
-/// Returns the UART base address
-pub fn get_base_addr() -> usize {
-    unsafe { (*addr_of_mut!(UART)).base_addr as usize }
+impl Default for Pl011 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-/// Zero-sized writer that implements `core::fmt::Write` for the PL011 UART
-pub struct UartWriter;
+impl Console for Pl011 {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn putchar(&self, c: u8) {
+        Pl011::putchar(self, c);
+    }
 
-impl core::fmt::Write for UartWriter {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        for &c in s.as_bytes() {
-            putchar(c);
-        }
-        Ok(())
+    fn getchar(&self) -> Option<u8> {
+        Pl011::getchar(self)
+    }
+
+    fn getchar_blocking(&self) -> u8 {
+        Pl011::getchar_blocking(self)
+    }
+
+    fn input(&'static self) -> Option<InputReceiver> {
+        self.input.receiver()
     }
-}
 
-/// Helper function used by the `print!` and `println!` macros
-#[doc(hidden)]
-pub fn _print(args: core::fmt::Arguments) {
-    use core::fmt::Write;
-    UartWriter.write_fmt(args).unwrap();
+    fn flush(&self, deadline: &Deadline) -> bool {
+        Pl011::flush(self, deadline)
+    }
 }
 
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => {
-        $crate::drivers::uart::pl011::_print(format_args!($($arg)*))
-    };
+/// Returns the instance probed in position `index`
+pub fn port(index: usize) -> Option<&'static Pl011> {
+    PORTS.get(index)?.get()
 }
 
-#[macro_export]
-macro_rules! println {
-    () => { $crate::print!("\n") };
-    ($fmt:expr) => { $crate::print!(concat!($fmt, "\n")) };
-    ($fmt:expr, $($arg:tt)*) => { $crate::print!(concat!($fmt, "\n"), $($arg)*) };
+/// Returns the number of instances probed so far
+pub fn port_count() -> usize {
+    PORT_COUNT.load(Ordering::Acquire)
 }
 
-/// Sets up the PL011 UART from device tree properties
+/// Writes a single byte to the UART at `base`, without an instance
 ///
-/// Parses the device's DTB properties to extract:
-/// - Base address from the `reg` property
-/// - Interrupt configuration from the `interrupts` property (configures as SPI in the GIC)
-/// - Clock frequency from the `clocks` property (follows phandle to clock node)
-///
-/// After extracting these values, initializes and configures the UART hardware.
-pub fn setup(dev: &device::PlatformDevice) {
-    let mut addr: u64 = 0;
-    let mut freq: u32 = 0;
-    let mut interrupt_info: [u32; gicv3::MAX_INTERRUPT_CELLS] = [0; gicv3::MAX_INTERRUPT_CELLS];
-    // Get #address-cells from parent (size_cells not needed for UART)
-    let (addr_cells, _) = dev.get_parent_cells();
-    // Parse reg property for base address
-    if let Some(reg_prop) = dev.find_property("reg") {
-        for i in 0..addr_cells as usize {
-            let cell = convert::read_be_u32(reg_prop.value, i * 4);
-            addr = (addr << 32) | cell as u64;
-        }
+/// Used by `console::earlycon` before any instance has been registered as the console. The
+/// bootloader/firmware is expected to have configured the UART.
+pub fn early_putchar(base: usize, c: u8) {
+    while (mmio::read_mmio32(base, FR_OFF) & FR_TXFE) != 0 {}
+    mmio::write_mmio32(base, DR_OFF, c as u32);
+}
+
+/// RX interrupt handler; `data` is the index of the instance that raised the interrupt
+fn handle_irq(_id: u32, data: usize) {
+    if let Some(port) = port(data)
+        && port.handle_rx()
+        && softirq::schedule_work(rx_work, data).is_err()
+    {
+        // The bytes stay staged until the next interrupt manages to schedule the work
+        println!("pl011: {} RX work queue full", port.name());
     }
+}
 
-    // Parse interrupts property
-    if let Some(int_prop) = dev.find_property("interrupts") {
-        if let Some(intc) = dtb::find_interrupt_parent(dev) {
-            // Get #interrupt-cells from interrupt controller
-            let mut interrupt_cells: u32 = 3; // Default for GICv3
-            if let Some(cells_prop) = intc.find_property("#interrupt-cells") {
-                interrupt_cells = convert::read_be_u32(cells_prop.value, 0);
-            }
+/// Deferred work scheduled by the RX interrupt, `data` is the instance index
+fn rx_work(data: usize) {
+    if let Some(port) = port(data) {
+        port.process_rx();
+    }
+}
 
-            // Read interrupt specifier cells
-            for i in 0..interrupt_cells.min(gicv3::MAX_INTERRUPT_CELLS as u32) {
-                interrupt_info[i as usize] = convert::read_be_u32(int_prop.value, (i * 4) as usize);
-            }
+/// Driver for `arm,pl011` nodes
+pub struct Pl011Driver;
 
-            // interrupt_info[0] = irq_type (0 = SPI, 1 = PPI)
-            // interrupt_info[1] = interrupt_number
-            // interrupt_info[2] = flags (trigger type)
-            if interrupt_info[0] == 0 {
-                let spi_id = 32 + interrupt_info[1];
-                // bits 0-1: edge trigger (1=rising, 2=falling)
-                // bits 2-3: level trigger (4=high, 8=low)
-                if (interrupt_info[2] & 0x3) != 0 {
-                    gicv3::set_spi_trigger_edge(spi_id);
-                } else {
-                    gicv3::set_spi_trigger_level(spi_id);
-                }
-                gicv3::set_spi_priority(spi_id, 0x00);
-                gicv3::set_spi_group(spi_id);
-                gicv3::set_spi_routing(spi_id, 0); // Route to core 0
-                gicv3::enable_spi(spi_id);
-            }
+impl device::Driver for Pl011Driver {
+    /// Sets up the PL011 UART from device tree properties
+    ///
+    /// Parses the device's DTB properties to extract:
+    /// - Base address from the `reg` property
+    /// - The first interrupt, which must be an SPI, configured in the GIC
+    /// - Clock frequency from the first `clocks` entry, resolved by `kernel::clk`
+    ///
+    /// After extracting these values, initializes and configures a new instance and registers it
+    /// with the console subsystem. The instance selected by `/chosen/stdout-path` is configured
+    /// with the line settings from the path suffix; the others use 115200 8N1.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let index = PORT_COUNT.load(Ordering::Acquire);
+        if index == MAX_PORTS {
+            println!("pl011: no free instance for {}", dev.name);
+            return Err(ProbeError::NoResources);
         }
-    }
-
-    // Parse clocks property for clock frequency
-    if let Some(clocks_prop) = dev.find_property("clocks") {
-        let phandle_id = convert::read_be_u32(clocks_prop.value, 0);
-        if let Some(clock_node) = dtb::find_device_by_phandle(phandle_id) {
-            if let Some(freq_prop) = clock_node.find_property("clock-frequency") {
-                freq = convert::read_be_u32(freq_prop.value, 0);
+        // The first clock is `uartclk`, which the baud rate divisor is computed from
+        let freq = match clk::clk_get(dev, 0) {
+            Ok(clk) => clk.get_rate(),
+            Err(ClkError::NotReady) => return Err(ProbeError::Defer),
+            Err(e) => {
+                pr_warn!("pl011: no clock for {}: {:?}", dev.name, e);
+                0
             }
+        };
+        let name = PORT_NAMES[index];
+        let addr = dev.reg(0).ok_or(ProbeError::NoDevice)?.base;
+
+        // Only an SPI can be routed to this core
+        let irq_id = of_irq_parse(dev, 0)
+            .filter(|spec| spec.kind == IrqKind::Spi)
+            .map_or(0, |spec| gic::configure_irq(&spec));
+
+        let options = console::options_for(dev).unwrap_or(ConsoleOptions::DEFAULT);
+        let mut port = Pl011::new();
+        port.init(name, addr, freq);
+        port.set_options(&options);
+        port.irq = irq_id;
+        port.configure();
+        let Ok(port) = PORTS[index].set(port) else {
+            return Err(ProbeError::NoResources);
+        };
+        PORT_COUNT.store(index + 1, Ordering::Release);
+
+        if irq_id != 0 && irq::request_irq(irq_id, name, handle_irq, index).is_ok() {
+            gic::enable_irq(irq_id);
         }
+        if console::register(port, dev).is_err() {
+            println!("pl011: console registry full, {} not registered", name);
+        }
+        Ok(())
     }
-
-    init_uart(addr as *mut u32, freq);
-    configure_uart();
 }
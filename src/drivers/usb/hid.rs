@@ -0,0 +1,101 @@
+//! USB HID boot-protocol keyboards
+//!
+//! Keyboards in the boot subclass (interface class 3, subclass 1, protocol 1) can be switched to
+//! the boot protocol, where every report has the same 8-byte layout: a bitmap of the modifier
+//! keys, a reserved byte, then the usage IDs of up to six keys held down. A usage appearing in a
+//! report is a key press, one disappearing a release; both are reported to the input subsystem
+//! with the Linux key code of the usage, and the modifier bits as the modifier keys.
+//!
+//! ## Design
+//!
+//! - Every keyboard is an input device of its own, registered when its endpoint is configured
+//!   and unregistered, releasing its keys, when it goes away.
+//! - A report with the rollover usage in its key slots (too many keys held) is ignored: the keys
+//!   stay as they were.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `usbhid` parses the report descriptor and handles any HID device in report protocol;
+//! `usbkbd` is its boot-protocol counterpart, whose `usb_kbd_keycode` table is used here.
+
+use crate::kernel::input::{self, EventType, InputError, InputHandle};
+
+/// Interface class, subclass and protocol of a boot keyboard
+pub const CLASS_HID: u8 = 3;
+pub const SUBCLASS_BOOT: u8 = 1;
+pub const PROTOCOL_KEYBOARD: u8 = 1;
+
+/* --- Class Request Constants --- */
+pub const SET_IDLE: u8 = 0x0a;
+pub const SET_PROTOCOL: u8 = 0x0b;
+pub const PROTOCOL_BOOT: u16 = 0;
+
+/// Length of a boot protocol report
+pub const REPORT_LEN: usize = 8;
+
+/// Reported in every key slot when too many keys are held
+const USAGE_ROLLOVER: u8 = 0x01;
+
+/// Key codes of the usages, up to the Application key (0 for none)
+const KEYCODES: [u8; 0x66] = [
+    0, 0, 0, 0, 30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, //
+    50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45, 21, 44, 2, 3, //
+    4, 5, 6, 7, 8, 9, 10, 11, 28, 1, 14, 15, 57, 12, 13, 26, //
+    27, 43, 43, 39, 40, 41, 51, 52, 53, 58, 59, 60, 61, 62, 63, 64, //
+    65, 66, 67, 68, 87, 88, 99, 70, 119, 110, 102, 104, 111, 107, 109, 106, //
+    105, 108, 103, 69, 98, 55, 74, 78, 96, 79, 80, 81, 75, 76, 77, 71, //
+    72, 73, 82, 83, 86, 127,
+];
+
+/// Key codes of the modifier bits: left Ctrl, Shift, Alt, Meta, then the right ones
+const MODIFIER_KEYCODES: [u16; 8] = [29, 42, 56, 125, 97, 54, 100, 126];
+
+/// Returns the key code of `usage`
+fn keycode(usage: u8) -> Option<u16> {
+    KEYCODES
+        .get(usage as usize)
+        .filter(|&&code| code != 0)
+        .map(|&code| code as u16)
+}
+
+/// A boot keyboard
+pub struct BootKeyboard {
+    input: InputHandle,
+    previous: [u8; REPORT_LEN],
+}
+
+impl BootKeyboard {
+    /// Registers the keyboard as an input device
+    pub fn new() -> Result<Self, InputError> {
+        Ok(Self {
+            input: input::register("usb-kbd", EventType::Key.bit())?,
+            previous: [0; REPORT_LEN],
+        })
+    }
+
+    /// Handles a report, reporting the keys released and pressed since the previous one
+    pub fn report(&mut self, report: &[u8; REPORT_LEN]) {
+        let (keys, previous) = (&report[2..], &self.previous[2..]);
+        if keys.contains(&USAGE_ROLLOVER) {
+            return;
+        }
+        for &usage in previous.iter().filter(|u| !keys.contains(u)) {
+            if let Some(code) = keycode(usage) {
+                self.input.report_key(code, false);
+            }
+        }
+        let changed = report[0] ^ self.previous[0];
+        for (bit, &code) in MODIFIER_KEYCODES.iter().enumerate() {
+            if changed & (1 << bit) != 0 {
+                self.input.report_key(code, report[0] & (1 << bit) != 0);
+            }
+        }
+        for &usage in keys.iter().filter(|u| !previous.contains(u)) {
+            if let Some(code) = keycode(usage) {
+                self.input.report_key(code, true);
+            }
+        }
+        self.input.sync();
+        self.previous = *report;
+    }
+}
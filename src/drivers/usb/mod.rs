@@ -0,0 +1,205 @@
+//! USB
+//!
+//! A USB host controller talks to the devices plugged into its root hub ports. Every device has
+//! a control endpoint (endpoint 0), through which it is identified with standard requests: its
+//! device descriptor gives the vendor and product, its configuration descriptor lists the
+//! interfaces and their endpoints. A class driver then binds an interface and exchanges data
+//! with its other endpoints.
+//!
+//! `xhci` drives eXtensible Host Controller Interface controllers (USB 3, backwards compatible
+//! with the slower speeds), found on PCIe or in the DTB. `hid` is the only class driver: it
+//! binds boot-protocol keyboards and reports their keys to the input subsystem.
+//!
+//! This module holds what doesn't depend on the controller: the control request layout and the
+//! descriptors.
+
+pub mod hid;
+pub mod xhci;
+
+use crate::kernel::input::InputError;
+
+/* --- Standard Request Constants --- */
+/// `bmRequestType`: direction, type and recipient
+pub const REQ_DIR_IN: u8 = 1 << 7;
+pub const REQ_TYPE_CLASS: u8 = 1 << 5;
+pub const REQ_RECIPIENT_INTERFACE: u8 = 1;
+/// `bRequest` of the standard requests
+pub const GET_DESCRIPTOR: u8 = 6;
+pub const SET_CONFIGURATION: u8 = 9;
+
+/* --- Descriptor Constants --- */
+pub const DESC_DEVICE: u8 = 1;
+pub const DESC_CONFIGURATION: u8 = 2;
+pub const DESC_INTERFACE: u8 = 4;
+pub const DESC_ENDPOINT: u8 = 5;
+/// Length of a device descriptor
+pub const DEVICE_DESC_LEN: usize = 18;
+/// Length of the configuration descriptor itself, without its interfaces
+pub const CONFIG_DESC_LEN: usize = 9;
+/// Endpoint address bit of IN endpoints
+pub const ENDPOINT_IN: u8 = 1 << 7;
+/// Transfer type (`bmAttributes` bits 1:0) of interrupt endpoints
+pub const ENDPOINT_INTERRUPT: u8 = 3;
+
+/// Speed of a device, as reported by the root hub port
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+impl Speed {
+    /// Largest packet of the control endpoint the speed allows, used until the device says
+    pub fn default_max_packet(self) -> u16 {
+        match self {
+            Speed::Low | Speed::Full => 8,
+            Speed::High => 64,
+            Speed::Super => 512,
+        }
+    }
+}
+
+/// Errors of the USB transfers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UsbError {
+    /// The controller didn't complete the transfer or command in time
+    Timeout,
+    /// The transfer or command completed with this controller-specific code
+    Completion(u8),
+    /// The device returned a malformed descriptor
+    BadDescriptor,
+    /// No memory for the device's rings and contexts
+    NoMemory,
+    /// Every device slot is in use
+    NoSlot,
+    /// The port didn't come out of reset enabled
+    PortDisabled,
+    /// The device couldn't be registered with the input subsystem
+    Input(InputError),
+}
+
+/// The setup packet starting a control transfer
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// A `GET_DESCRIPTOR` request for `length` bytes of descriptor `kind`
+    pub fn get_descriptor(kind: u8, length: u16) -> Self {
+        Self {
+            request_type: REQ_DIR_IN,
+            request: GET_DESCRIPTOR,
+            value: (kind as u16) << 8,
+            index: 0,
+            length,
+        }
+    }
+
+    /// The packet as the 8 bytes sent on the wire, little-endian fields in a `u64`
+    pub fn to_u64(self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.request_type & REQ_DIR_IN != 0
+    }
+}
+
+/// The fields of a device descriptor the kernel uses
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DeviceDescriptor {
+    pub class: u8,
+    pub max_packet0: u8,
+    pub vendor: u16,
+    pub product: u16,
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub fn parse(data: &[u8]) -> Result<Self, UsbError> {
+        if data.len() < DEVICE_DESC_LEN || data[1] != DESC_DEVICE {
+            return Err(UsbError::BadDescriptor);
+        }
+        Ok(Self {
+            class: data[4],
+            max_packet0: data[7],
+            vendor: u16::from_le_bytes([data[8], data[9]]),
+            product: u16::from_le_bytes([data[10], data[11]]),
+            num_configurations: data[17],
+        })
+    }
+}
+
+/// An interface of a configuration, with its first interrupt IN endpoint
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Interface {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// Address, largest packet and polling interval of the endpoint
+    pub interrupt_in: Option<(u8, u16, u8)>,
+}
+
+/// Returns the configuration value and total length of the configuration descriptor `data`
+pub fn parse_config_header(data: &[u8]) -> Result<(u8, u16), UsbError> {
+    if data.len() < CONFIG_DESC_LEN || data[1] != DESC_CONFIGURATION {
+        return Err(UsbError::BadDescriptor);
+    }
+    Ok((data[5], u16::from_le_bytes([data[2], data[3]])))
+}
+
+/// Calls `f` on every interface of the configuration descriptor `data`
+///
+/// The descriptors following the configuration descriptor are walked by their length byte;
+/// endpoints belong to the interface before them.
+pub fn for_each_interface(data: &[u8], mut f: impl FnMut(&Interface)) {
+    let mut current: Option<Interface> = None;
+    let mut offset = 0;
+    while offset + 2 <= data.len() {
+        let len = data[offset] as usize;
+        if len < 2 || offset + len > data.len() {
+            break;
+        }
+        let desc = &data[offset..offset + len];
+        match desc[1] {
+            DESC_INTERFACE if len >= 9 => {
+                if let Some(iface) = current.take() {
+                    f(&iface);
+                }
+                current = Some(Interface {
+                    number: desc[2],
+                    class: desc[5],
+                    subclass: desc[6],
+                    protocol: desc[7],
+                    interrupt_in: None,
+                });
+            }
+            DESC_ENDPOINT if len >= 7 => {
+                if let Some(iface) = current.as_mut().filter(|i| i.interrupt_in.is_none())
+                    && desc[2] & ENDPOINT_IN != 0
+                    && desc[3] & 0b11 == ENDPOINT_INTERRUPT
+                {
+                    let max_packet = u16::from_le_bytes([desc[4], desc[5]]) & 0x7ff;
+                    iface.interrupt_in = Some((desc[2], max_packet, desc[6]));
+                }
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    if let Some(iface) = current {
+        f(&iface);
+    }
+}
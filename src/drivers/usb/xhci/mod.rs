@@ -0,0 +1,883 @@
+//! xHCI host controllers
+//!
+//! An xHCI controller exposes four register sets: capabilities (sizes and offsets of the
+//! others), operational registers (run/stop, the root hub ports), runtime registers (the
+//! interrupters and their event rings) and doorbells. Everything else happens in memory shared
+//! with the controller:
+//!
+//! - The Device Context Base Address Array (DCBAA) points at the device context of every slot:
+//!   the controller keeps the state of the device and its endpoints there.
+//! - Commands (enable a slot, address a device, configure endpoints) go through the command
+//!   ring, transfers through one ring per endpoint; see `ring`.
+//! - The controller reports completions and port changes on the event ring.
+//!
+//! A device found on a port is reset, given a slot and an address, then identified through its
+//! control endpoint. Boot-protocol keyboards get their interrupt IN endpoint configured and a
+//! report transfer always queued on it (see `hid`); other devices are listed and left alone.
+//!
+//! ## Design
+//!
+//! - Each controller has a kernel task that owns it: it enumerates the ports, then polls the
+//!   event ring every `POLL_MS`. The controller's interrupts stay off, as PCI interrupts are not
+//!   supported; nothing but this task touches the rings, so there is no locking.
+//! - Devices are set up one at a time. Waiting for a completion goes through the event ring,
+//!   and the events meant for something else (keyboard reports, port changes) are handled
+//!   while waiting.
+//! - Devices must be plugged into a root hub port: hubs are not supported.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `drivers/usb/host/xhci*.c` is driven by the USB core (`drivers/usb/core`), which does
+//! the enumeration through the hub driver, root hub included, and binds interface drivers. It
+//! uses MSI-X interrupts, multi-segment rings, streams and the bandwidth and power management
+//! commands besides.
+
+pub mod ring;
+
+use core::sync::atomic::{AtomicUsize, Ordering, fence};
+
+use crate::drivers::pci::{PciDevice, PciDriver};
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::device::{self, PlatformDevice, ProbeError};
+use crate::kernel::mm::dma::{self, DmaBuffer, DmaError};
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+use crate::kernel::notifier::Deadline;
+use crate::kernel::sched;
+use crate::utilities::mmio;
+use crate::{pr_err, pr_warn, println};
+
+use super::hid::{self, BootKeyboard};
+use super::{
+    CONFIG_DESC_LEN, DESC_CONFIGURATION, DESC_DEVICE, DEVICE_DESC_LEN, DeviceDescriptor, Interface,
+    REQ_RECIPIENT_INTERFACE, REQ_TYPE_CLASS, SET_CONFIGURATION, SetupPacket, Speed, UsbError,
+};
+use ring::{
+    COMPLETION_SHORT_PACKET, COMPLETION_SUCCESS, EventRing, Ring, TRB_ADDRESS_DEVICE,
+    TRB_COMMAND_COMPLETION, TRB_CONFIGURE_ENDPOINT, TRB_DATA, TRB_DIR_IN, TRB_DISABLE_SLOT,
+    TRB_ENABLE_SLOT, TRB_EVALUATE_CONTEXT, TRB_IDT, TRB_IOC, TRB_NORMAL, TRB_PORT_STATUS_CHANGE,
+    TRB_SETUP, TRB_STATUS, TRB_TRANSFER_EVENT, TRB_TRT_IN, TRB_TRT_OUT, Trb,
+};
+
+/// PCI class code of an xHCI controller
+pub const CLASS_XHCI: u32 = 0x0c_03_30;
+
+/// Maximum number of controllers, and of devices per controller
+const MAX_CONTROLLERS: usize = 2;
+const MAX_DEVICES: usize = 8;
+
+/// Ports handled per controller
+const MAX_PORTS: u8 = 64;
+
+/// Names of the controllers' tasks
+const TASK_NAMES: [&str; MAX_CONTROLLERS] = ["xhci0", "xhci1"];
+
+/// Interval between two polls of the event ring
+const POLL_MS: u32 = 10;
+
+/// Time for the controller to halt or reset, for a command, and for a control transfer
+const RESET_TIMEOUT_MS: u32 = 1000;
+const COMMAND_TIMEOUT_MS: u32 = 500;
+const TRANSFER_TIMEOUT_MS: u32 = 500;
+/// Time for a port reset, and the recovery time a device gets after it
+const PORT_RESET_TIMEOUT_MS: u32 = 500;
+const PORT_RECOVERY_MS: u32 = 10;
+
+/// Bytes of a device's data page used by control transfers; the reports come after
+const CONTROL_DATA_LEN: usize = PAGE_SIZE / 2;
+const REPORT_OFFSET: usize = CONTROL_DATA_LEN;
+
+/* --- Capability Register Constants --- */
+/// Length of the capability registers (bits 7:0) and interface version (bits 31:16)
+const CAPLENGTH: usize = 0x00;
+const HCSPARAMS1: usize = 0x04;
+const HCSPARAMS2: usize = 0x08;
+const HCCPARAMS1: usize = 0x10;
+/// 64-byte contexts
+const HCC_CSZ: u32 = 1 << 2;
+const DBOFF: usize = 0x14;
+const RTSOFF: usize = 0x18;
+
+/* --- Operational Register Constants --- */
+const USBCMD: usize = 0x00;
+const CMD_RUN: u32 = 1 << 0;
+const CMD_RESET: u32 = 1 << 1;
+const USBSTS: usize = 0x04;
+const STS_HALTED: u32 = 1 << 0;
+const STS_NOT_READY: u32 = 1 << 11;
+/// Command ring control
+const CRCR: usize = 0x18;
+const DCBAAP: usize = 0x30;
+/// Number of device slots enabled
+const CONFIG: usize = 0x38;
+/// Status and control of port 1; the others follow
+const PORTSC: usize = 0x400;
+const PORT_STRIDE: usize = 0x10;
+const PORT_CONNECTED: u32 = 1 << 0;
+const PORT_ENABLED: u32 = 1 << 1;
+const PORT_RESET: u32 = 1 << 4;
+const PORT_POWER: u32 = 1 << 9;
+const PORT_SPEED_SHIFT: u32 = 10;
+const PORT_RESET_CHANGE: u32 = 1 << 21;
+/// Change bits, written 1 to clear
+const PORT_CHANGES: u32 = 0x7f << 17;
+/// Bits to write back unchanged: not write-1-to-clear, and not the enable bit, which disables
+/// the port when written 1
+const PORT_PRESERVE: u32 =
+    1 << 0 | 1 << 3 | 0xf << 10 | 1 << 30 | 0xf << 5 | 1 << 9 | 0x3 << 14 | 0x7 << 25;
+
+/* --- Runtime Register Constants (Interrupter 0) --- */
+const IR0: usize = 0x20;
+const ERSTSZ: usize = 0x08;
+const ERSTBA: usize = 0x10;
+const ERDP: usize = 0x18;
+/// Event handler busy, written 1 to clear
+const ERDP_BUSY: u64 = 1 << 3;
+
+/* --- Context Constants --- */
+const SLOT_SPEED_SHIFT: u32 = 20;
+const SLOT_ENTRIES_SHIFT: u32 = 27;
+const SLOT_PORT_SHIFT: u32 = 16;
+const EP_INTERVAL_SHIFT: u32 = 16;
+/// Errors tolerated before the endpoint halts
+const EP_ERROR_COUNT: u32 = 3 << 1;
+const EP_TYPE_SHIFT: u32 = 3;
+const EP_TYPE_CONTROL: u32 = 4;
+const EP_TYPE_INTERRUPT_IN: u32 = 7;
+const EP_MAX_PACKET_SHIFT: u32 = 16;
+/// Device context index of the control endpoint
+const DCI_CONTROL: u8 = 1;
+
+/// Returns the speed of a port speed ID
+fn speed_of(id: u32) -> Speed {
+    match id {
+        1 => Speed::Full,
+        2 => Speed::Low,
+        3 => Speed::High,
+        _ => Speed::Super,
+    }
+}
+
+/// A keyboard's interrupt endpoint and state
+struct Keyboard {
+    dci: u8,
+    ring: Ring,
+    state: BootKeyboard,
+}
+
+/// A device with a slot
+struct Device {
+    slot: u8,
+    port: u8,
+    /// Port speed ID, as the slot context wants it
+    speed_id: u32,
+    max_packet0: u16,
+    /// The three pages below
+    mem: DmaBuffer,
+    /// Device context, written by the controller
+    output: DmaBuffer,
+    /// Input context of the commands
+    input: DmaBuffer,
+    /// Control transfer data, then the keyboard reports
+    data: DmaBuffer,
+    ep0: Ring,
+    keyboard: Option<Keyboard>,
+}
+
+impl Device {
+    fn new(slot: u8, port: u8, speed_id: u32) -> Result<Self, DmaError> {
+        let mem = dma::alloc_coherent(3 * PAGE_SIZE)?;
+        let ep0 = Ring::new().inspect_err(|_| {
+            let _ = dma::free_coherent(mem);
+        })?;
+        let page = |i: usize| DmaBuffer {
+            vaddr: mem.vaddr + i * PAGE_SIZE,
+            paddr: mem.paddr + i * PAGE_SIZE,
+            size: PAGE_SIZE,
+        };
+        Ok(Self {
+            slot,
+            port,
+            speed_id,
+            max_packet0: speed_of(speed_id).default_max_packet(),
+            mem,
+            output: page(0),
+            input: page(1),
+            data: page(2),
+            ep0,
+            keyboard: None,
+        })
+    }
+
+    fn free(self) {
+        let _ = dma::free_coherent(self.mem);
+        self.ep0.free();
+        if let Some(keyboard) = self.keyboard {
+            keyboard.ring.free();
+        }
+    }
+
+    /// The first `len` bytes of the control data
+    fn control_data(&self, len: usize) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.data.vaddr as *const u8, len) }
+    }
+}
+
+/// An xHCI controller
+pub struct Xhci {
+    /// Operational, runtime and doorbell registers
+    op: usize,
+    rt: usize,
+    db: usize,
+    /// Size of a context: 32 or 64 bytes
+    ctx_size: usize,
+    max_slots: u8,
+    max_ports: u8,
+    dcbaa: DmaBuffer,
+    commands: Ring,
+    events: EventRing,
+    /// Devices, by slot - 1
+    devices: [Option<Device>; MAX_DEVICES],
+    /// Ports whose connection changed, by bit (port - 1)
+    pending: u64,
+}
+
+impl Xhci {
+    /// Resets the controller at `base` and allocates its DCBAA, rings and scratchpad
+    fn new(base: usize) -> Result<Self, UsbError> {
+        let caplength = mmio::read_mmio32(base, CAPLENGTH) & 0xff;
+        let params1 = mmio::read_mmio32(base, HCSPARAMS1);
+        let params2 = mmio::read_mmio32(base, HCSPARAMS2);
+        let op = base + caplength as usize;
+        reset(op)?;
+
+        let mut allocated: [Option<DmaBuffer>; 3] = [None; 3];
+        let free_all = |allocated: &[Option<DmaBuffer>]| {
+            allocated.iter().flatten().for_each(|&b| {
+                let _ = dma::free_coherent(b);
+            })
+        };
+        let max_slots = ((params1 & 0xff) as u8).min(MAX_DEVICES as u8);
+        let dcbaa =
+            dma::alloc_coherent((max_slots as usize + 1) * 8).map_err(|_| UsbError::NoMemory)?;
+        allocated[0] = Some(dcbaa);
+        // Scratchpad buffer count, split in a high (bits 25:21) and low (bits 31:27) part. The
+        // scratchpad belongs to the controller for good
+        let scratchpad_count = ((params2 >> 21) & 0x1f) << 5 | (params2 >> 27);
+        if scratchpad_count > 0 {
+            let count = scratchpad_count as usize;
+            let (Ok(array), Ok(pages)) = (
+                dma::alloc_coherent(count * 8),
+                dma::alloc_coherent(count * PAGE_SIZE),
+            ) else {
+                free_all(&allocated);
+                return Err(UsbError::NoMemory);
+            };
+            allocated[1] = Some(array);
+            allocated[2] = Some(pages);
+            let entries = array.vaddr as *mut u64;
+            for i in 0..count {
+                unsafe {
+                    entries
+                        .add(i)
+                        .write_volatile((pages.paddr + i * PAGE_SIZE) as u64)
+                };
+            }
+            unsafe { (dcbaa.vaddr as *mut u64).write_volatile(array.paddr as u64) };
+        }
+        let Ok(commands) = Ring::new() else {
+            free_all(&allocated);
+            return Err(UsbError::NoMemory);
+        };
+        let Ok(events) = EventRing::new() else {
+            commands.free();
+            free_all(&allocated);
+            return Err(UsbError::NoMemory);
+        };
+
+        let ctx_size = if mmio::read_mmio32(base, HCCPARAMS1) & HCC_CSZ != 0 {
+            64
+        } else {
+            32
+        };
+        Ok(Self {
+            op,
+            rt: base + (mmio::read_mmio32(base, RTSOFF) & !0x1f) as usize,
+            db: base + (mmio::read_mmio32(base, DBOFF) & !0x3) as usize,
+            ctx_size,
+            max_slots,
+            max_ports: ((params1 >> 24) as u8).min(MAX_PORTS),
+            dcbaa,
+            commands,
+            events,
+            devices: [const { None }; MAX_DEVICES],
+            pending: 0,
+        })
+    }
+
+    fn write64(&self, base: usize, reg: usize, value: u64) {
+        mmio::write_mmio32(base, reg, value as u32);
+        mmio::write_mmio32(base, reg + 4, (value >> 32) as u32);
+    }
+
+    /// Hands the DCBAA and rings to the controller and starts it
+    fn start(&mut self) -> Result<(), UsbError> {
+        mmio::write_mmio32(self.op, CONFIG, self.max_slots as u32);
+        self.write64(self.op, DCBAAP, self.dcbaa.paddr as u64);
+        self.write64(self.op, CRCR, self.commands.dequeue_pointer());
+        let ir = self.rt + IR0;
+        mmio::write_mmio32(ir, ERSTSZ, 1);
+        self.write64(ir, ERDP, self.events.dequeue_pointer());
+        self.write64(ir, ERSTBA, self.events.erst.paddr as u64);
+        mmio::write_mmio32(self.op, USBCMD, CMD_RUN);
+        wait_status(self.op, STS_HALTED, false)?;
+
+        for port in 1..=self.max_ports {
+            let portsc = self.read_port(port);
+            if portsc & PORT_POWER == 0 {
+                self.write_port(port, (portsc & PORT_PRESERVE) | PORT_POWER);
+            }
+            if portsc & PORT_CONNECTED != 0 {
+                self.pending |= 1 << (port - 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_port(&self, port: u8) -> u32 {
+        mmio::read_mmio32(self.op, PORTSC + (port as usize - 1) * PORT_STRIDE)
+    }
+
+    fn write_port(&self, port: u8, value: u32) {
+        mmio::write_mmio32(self.op, PORTSC + (port as usize - 1) * PORT_STRIDE, value);
+    }
+
+    /// Tells the controller there is work for `target` (an endpoint, or 0 for commands) of slot
+    /// `slot` (0 for the command ring)
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        // The TRBs must reach memory first
+        fence(Ordering::SeqCst);
+        mmio::write_mmio32(self.db, slot as usize * 4, target as u32);
+    }
+
+    /// Returns the next event, giving its slot back to the controller
+    fn next_event(&mut self) -> Option<Trb> {
+        let event = self.events.pop()?;
+        self.write64(
+            self.rt + IR0,
+            ERDP,
+            self.events.dequeue_pointer() | ERDP_BUSY,
+        );
+        Some(event)
+    }
+
+    /// Handles an event nobody is waiting for
+    fn handle_event(&mut self, event: Trb) {
+        match event.kind() {
+            TRB_PORT_STATUS_CHANGE => {
+                let port = (event.param >> 24) as u8;
+                if (1..=self.max_ports).contains(&port) {
+                    self.pending |= 1 << (port - 1);
+                }
+            }
+            TRB_TRANSFER_EVENT => self.keyboard_event(event),
+            _ => {}
+        }
+    }
+
+    /// Waits for the event `matches` accepts, handling the others
+    fn wait_event(
+        &mut self,
+        timeout_ms: u32,
+        matches: impl Fn(&Trb) -> bool,
+    ) -> Result<Trb, UsbError> {
+        let deadline = Deadline::from_ms(timeout_ms);
+        loop {
+            while let Some(event) = self.next_event() {
+                if matches(&event) {
+                    return Ok(event);
+                }
+                self.handle_event(event);
+            }
+            if deadline.expired() {
+                return Err(UsbError::Timeout);
+            }
+            sched::yield_now();
+        }
+    }
+
+    /// Runs a command and returns its completion event
+    fn command(&mut self, trb: Trb) -> Result<Trb, UsbError> {
+        let addr = self.commands.push(trb);
+        self.ring_doorbell(0, 0);
+        let event = self.wait_event(COMMAND_TIMEOUT_MS, |event| {
+            event.kind() == TRB_COMMAND_COMPLETION && event.param == addr
+        })?;
+        match event.completion_code() {
+            COMPLETION_SUCCESS => Ok(event),
+            code => Err(UsbError::Completion(code)),
+        }
+    }
+
+    fn device(&self, slot: u8) -> Result<&Device, UsbError> {
+        self.devices
+            .get((slot as usize).wrapping_sub(1))
+            .and_then(Option::as_ref)
+            .ok_or(UsbError::NoSlot)
+    }
+
+    fn device_mut(&mut self, slot: u8) -> Result<&mut Device, UsbError> {
+        self.devices
+            .get_mut((slot as usize).wrapping_sub(1))
+            .and_then(Option::as_mut)
+            .ok_or(UsbError::NoSlot)
+    }
+
+    /// Runs a control transfer on the control endpoint of `slot`
+    ///
+    /// The data stage, if any, uses the first `setup.length` bytes of the device's data page.
+    fn control(&mut self, slot: u8, setup: SetupPacket) -> Result<(), UsbError> {
+        let dev = self.device_mut(slot)?;
+        let len = setup.length as u32;
+        let (transfer_type, data_dir, status_dir) = match (len, setup.is_in()) {
+            (0, _) => (0, 0, TRB_DIR_IN),
+            (_, true) => (TRB_TRT_IN, TRB_DIR_IN, 0),
+            (_, false) => (TRB_TRT_OUT, 0, TRB_DIR_IN),
+        };
+        dev.ep0.push(Trb::new(
+            TRB_SETUP,
+            setup.to_u64(),
+            8,
+            TRB_IDT | transfer_type,
+        ));
+        if len > 0 {
+            dev.ep0
+                .push(Trb::new(TRB_DATA, dev.data.paddr as u64, len, data_dir));
+        }
+        dev.ep0
+            .push(Trb::new(TRB_STATUS, 0, 0, status_dir | TRB_IOC));
+        self.ring_doorbell(slot, DCI_CONTROL);
+        let event = self.wait_event(TRANSFER_TIMEOUT_MS, |event| {
+            event.kind() == TRB_TRANSFER_EVENT
+                && event.slot() == slot
+                && event.endpoint() == DCI_CONTROL
+        })?;
+        match event.completion_code() {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => Ok(()),
+            code => Err(UsbError::Completion(code)),
+        }
+    }
+
+    /// Writes dword `dword` of context `index` of the context page `buf`
+    fn write_context(&self, buf: &DmaBuffer, index: usize, dword: usize, value: u32) {
+        let addr = buf.vaddr + index * self.ctx_size + dword * 4;
+        unsafe { core::ptr::write_volatile(addr as *mut u32, value) };
+    }
+
+    /// Clears the input context of `dev` and sets its add flags (input control context) and
+    /// slot context, the latter with `entries` context entries
+    fn prepare_input(&self, dev: &Device, add: u32, entries: u8) {
+        unsafe { core::ptr::write_bytes(dev.input.vaddr as *mut u8, 0, PAGE_SIZE) };
+        self.write_context(&dev.input, 0, 1, add);
+        self.write_context(
+            &dev.input,
+            1,
+            0,
+            dev.speed_id << SLOT_SPEED_SHIFT | (entries as u32) << SLOT_ENTRIES_SHIFT,
+        );
+        self.write_context(&dev.input, 1, 1, (dev.port as u32) << SLOT_PORT_SHIFT);
+    }
+
+    /// Writes the input endpoint context of `dci`
+    fn write_endpoint(
+        &self,
+        dev: &Device,
+        dci: u8,
+        kind: u32,
+        max_packet: u16,
+        interval: u32,
+        dequeue: u64,
+    ) {
+        // Input contexts start with the input control context, then the slot context
+        let index = dci as usize + 1;
+        let avg_len = if kind == EP_TYPE_CONTROL {
+            8
+        } else {
+            max_packet as u32
+        };
+        let max_esit = if kind == EP_TYPE_CONTROL {
+            0
+        } else {
+            max_packet as u32
+        };
+        self.write_context(&dev.input, index, 0, interval << EP_INTERVAL_SHIFT);
+        self.write_context(
+            &dev.input,
+            index,
+            1,
+            EP_ERROR_COUNT | kind << EP_TYPE_SHIFT | (max_packet as u32) << EP_MAX_PACKET_SHIFT,
+        );
+        self.write_context(&dev.input, index, 2, dequeue as u32);
+        self.write_context(&dev.input, index, 3, (dequeue >> 32) as u32);
+        self.write_context(&dev.input, index, 4, avg_len | max_esit << 16);
+    }
+
+    /// Resets `port` and returns its speed ID once it is enabled
+    fn reset_port(&self, port: u8) -> Result<u32, UsbError> {
+        let portsc = self.read_port(port);
+        self.write_port(port, (portsc & PORT_PRESERVE) | PORT_RESET);
+        let deadline = Deadline::from_ms(PORT_RESET_TIMEOUT_MS);
+        let portsc = loop {
+            let portsc = self.read_port(port);
+            if portsc & PORT_RESET_CHANGE != 0 && portsc & PORT_RESET == 0 {
+                break portsc;
+            }
+            if deadline.expired() {
+                return Err(UsbError::Timeout);
+            }
+            sched::yield_now();
+        };
+        self.write_port(port, (portsc & PORT_PRESERVE) | PORT_RESET_CHANGE);
+        if portsc & PORT_ENABLED == 0 {
+            return Err(UsbError::PortDisabled);
+        }
+        sched::sleep_ms(PORT_RECOVERY_MS);
+        Ok((portsc >> PORT_SPEED_SHIFT) & 0xf)
+    }
+
+    /// Sets up the device connected to `port`
+    fn attach(&mut self, port: u8) -> Result<(), UsbError> {
+        let speed_id = self.reset_port(port)?;
+        let slot = self.command(Trb::new(TRB_ENABLE_SLOT, 0, 0, 0))?.slot();
+        if slot == 0 || slot > self.max_slots {
+            return Err(UsbError::NoSlot);
+        }
+        let dev = match Device::new(slot, port, speed_id) {
+            Ok(dev) => dev,
+            Err(_) => {
+                let _ = self.command(Trb::new(TRB_DISABLE_SLOT, 0, 0, (slot as u32) << 24));
+                return Err(UsbError::NoMemory);
+            }
+        };
+        let entry = (self.dcbaa.vaddr as *mut u64).wrapping_add(slot as usize);
+        unsafe { entry.write_volatile(dev.output.paddr as u64) };
+        self.devices[slot as usize - 1] = Some(dev);
+        self.setup_device(slot).inspect_err(|_| self.detach(slot))
+    }
+
+    /// Addresses and identifies the device of `slot`, then binds the keyboard driver if it is a
+    /// boot keyboard
+    fn setup_device(&mut self, slot: u8) -> Result<(), UsbError> {
+        let dev = self.device(slot)?;
+        let (input, port, max_packet0) = (dev.input.paddr as u64, dev.port, dev.max_packet0);
+        let dequeue = dev.ep0.dequeue_pointer();
+        self.prepare_input(dev, 0b11, 1);
+        self.write_endpoint(dev, DCI_CONTROL, EP_TYPE_CONTROL, max_packet0, 0, dequeue);
+        self.command(Trb::new(TRB_ADDRESS_DEVICE, input, 0, (slot as u32) << 24))?;
+
+        // The first 8 bytes of the device descriptor hold the control endpoint's packet size
+        self.control(slot, SetupPacket::get_descriptor(DESC_DEVICE, 8))?;
+        let dev = self.device_mut(slot)?;
+        let speed = speed_of(dev.speed_id);
+        let reported = dev.control_data(8)[7] as u16;
+        if speed != Speed::Super && reported != 0 && reported != max_packet0 {
+            dev.max_packet0 = reported;
+            let dev = self.device(slot)?;
+            self.prepare_input(dev, 0b10, 1);
+            self.write_endpoint(dev, DCI_CONTROL, EP_TYPE_CONTROL, reported, 0, 0);
+            self.command(Trb::new(
+                TRB_EVALUATE_CONTEXT,
+                input,
+                0,
+                (slot as u32) << 24,
+            ))?;
+        }
+
+        self.control(
+            slot,
+            SetupPacket::get_descriptor(DESC_DEVICE, DEVICE_DESC_LEN as u16),
+        )?;
+        let desc = DeviceDescriptor::parse(self.device(slot)?.control_data(DEVICE_DESC_LEN))?;
+        self.control(
+            slot,
+            SetupPacket::get_descriptor(DESC_CONFIGURATION, CONFIG_DESC_LEN as u16),
+        )?;
+        let (_, total) =
+            super::parse_config_header(self.device(slot)?.control_data(CONFIG_DESC_LEN))?;
+        let len = (total as usize).min(CONTROL_DATA_LEN);
+        self.control(
+            slot,
+            SetupPacket::get_descriptor(DESC_CONFIGURATION, len as u16),
+        )?;
+        let config = self.device(slot)?.control_data(len);
+        let (config_value, _) = super::parse_config_header(config)?;
+        let mut keyboard = None;
+        super::for_each_interface(config, |iface| {
+            if keyboard.is_none()
+                && iface.class == hid::CLASS_HID
+                && iface.subclass == hid::SUBCLASS_BOOT
+                && iface.protocol == hid::PROTOCOL_KEYBOARD
+                && iface.interrupt_in.is_some()
+            {
+                keyboard = Some(*iface);
+            }
+        });
+        println!(
+            "usb: port {}: {:04x}:{:04x}, class {:02x}, {:?} speed",
+            port, desc.vendor, desc.product, desc.class, speed
+        );
+        let Some(iface) = keyboard else {
+            return Ok(());
+        };
+
+        self.control(
+            slot,
+            SetupPacket {
+                request_type: 0,
+                request: SET_CONFIGURATION,
+                value: config_value as u16,
+                index: 0,
+                length: 0,
+            },
+        )?;
+        self.add_keyboard(slot, &iface)?;
+        println!("usb: port {}: boot keyboard", port);
+        Ok(())
+    }
+
+    /// Configures the interrupt endpoint of the keyboard interface `iface` of `slot` and
+    /// queues the first report transfer
+    fn add_keyboard(&mut self, slot: u8, iface: &Interface) -> Result<(), UsbError> {
+        let Some((address, max_packet, b_interval)) = iface.interrupt_in else {
+            return Err(UsbError::BadDescriptor);
+        };
+        let dci = (address & 0xf) * 2 + 1;
+        let state = BootKeyboard::new().map_err(UsbError::Input)?;
+        let ring = Ring::new().map_err(|_| UsbError::NoMemory)?;
+        let dev = self.device(slot)?;
+        let input = dev.input.paddr as u64;
+        // Periods are 2^interval * 125 us; full and low speed devices give theirs in ms
+        let interval = match speed_of(dev.speed_id) {
+            Speed::Low | Speed::Full => {
+                let microframes = (b_interval.max(1) as u32) * 8;
+                (31 - microframes.leading_zeros()).clamp(3, 10)
+            }
+            _ => (b_interval.clamp(1, 16) - 1) as u32,
+        };
+        self.prepare_input(dev, 1 | 1 << dci, dci);
+        self.write_endpoint(
+            dev,
+            dci,
+            EP_TYPE_INTERRUPT_IN,
+            max_packet,
+            interval,
+            ring.dequeue_pointer(),
+        );
+        if let Err(e) = self.command(Trb::new(
+            TRB_CONFIGURE_ENDPOINT,
+            input,
+            0,
+            (slot as u32) << 24,
+        )) {
+            ring.free();
+            return Err(e);
+        }
+        self.device_mut(slot)?.keyboard = Some(Keyboard { dci, ring, state });
+
+        let class_request = |request, value| SetupPacket {
+            request_type: REQ_TYPE_CLASS | REQ_RECIPIENT_INTERFACE,
+            request,
+            value,
+            index: iface.number as u16,
+            length: 0,
+        };
+        self.control(slot, class_request(hid::SET_PROTOCOL, hid::PROTOCOL_BOOT))?;
+        // Reports only when a key changes; keyboards may refuse, which changes nothing here
+        let _ = self.control(slot, class_request(hid::SET_IDLE, 0));
+        self.queue_report(slot);
+        Ok(())
+    }
+
+    /// Queues a report transfer on the keyboard of `slot`
+    fn queue_report(&mut self, slot: u8) {
+        let Ok(dev) = self.device_mut(slot) else {
+            return;
+        };
+        let addr = (dev.data.paddr + REPORT_OFFSET) as u64;
+        let Some(keyboard) = dev.keyboard.as_mut() else {
+            return;
+        };
+        keyboard
+            .ring
+            .push(Trb::new(TRB_NORMAL, addr, hid::REPORT_LEN as u32, TRB_IOC));
+        let dci = keyboard.dci;
+        self.ring_doorbell(slot, dci);
+    }
+
+    /// Hands a completed report transfer to the keyboard and queues the next one
+    fn keyboard_event(&mut self, event: Trb) {
+        let slot = event.slot();
+        let Ok(dev) = self.device_mut(slot) else {
+            return;
+        };
+        let report_addr = dev.data.vaddr + REPORT_OFFSET;
+        let Some(keyboard) = dev.keyboard.as_mut().filter(|k| k.dci == event.endpoint()) else {
+            return;
+        };
+        match event.completion_code() {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => {
+                let report = unsafe { core::ptr::read_volatile(report_addr as *const [u8; 8]) };
+                keyboard.state.report(&report);
+                self.queue_report(slot);
+            }
+            code => {
+                let port = dev.port;
+                pr_warn!("usb: port {}: keyboard transfer failed ({})", port, code);
+            }
+        }
+    }
+
+    /// Releases the slot of a device that went away or couldn't be set up
+    fn detach(&mut self, slot: u8) {
+        let _ = self.command(Trb::new(TRB_DISABLE_SLOT, 0, 0, (slot as u32) << 24));
+        let entry = (self.dcbaa.vaddr as *mut u64).wrapping_add(slot as usize);
+        unsafe { entry.write_volatile(0) };
+        if let Some(dev) = self.devices[slot as usize - 1].take() {
+            dev.free();
+        }
+    }
+
+    /// Attaches the devices plugged in and detaches the devices unplugged since the last call
+    fn handle_ports(&mut self) {
+        while self.pending != 0 {
+            let port = self.pending.trailing_zeros() as u8 + 1;
+            self.pending &= !(1 << (port - 1));
+            let portsc = self.read_port(port);
+            self.write_port(port, (portsc & PORT_PRESERVE) | (portsc & PORT_CHANGES));
+            let attached = self
+                .devices
+                .iter()
+                .flatten()
+                .find(|dev| dev.port == port)
+                .map(|dev| dev.slot);
+            match (portsc & PORT_CONNECTED != 0, attached) {
+                (true, None) => {
+                    if let Err(e) = self.attach(port) {
+                        pr_warn!("usb: port {}: cannot set up the device: {:?}", port, e);
+                    }
+                }
+                (false, Some(slot)) => {
+                    println!("usb: port {}: device disconnected", port);
+                    self.detach(slot);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Handles the events and port changes
+    fn poll(&mut self) {
+        while let Some(event) = self.next_event() {
+            self.handle_event(event);
+        }
+        self.handle_ports();
+    }
+}
+
+/// Waits until `bits` of `USBSTS` are all set (`set`) or all clear
+fn wait_status(op: usize, bits: u32, set: bool) -> Result<(), UsbError> {
+    let deadline = Deadline::from_ms(RESET_TIMEOUT_MS);
+    while (mmio::read_mmio32(op, USBSTS) & bits == bits) != set {
+        if deadline.expired() {
+            return Err(UsbError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+/// Halts and resets the controller whose operational registers are at `op`
+fn reset(op: usize) -> Result<(), UsbError> {
+    let cmd = mmio::read_mmio32(op, USBCMD);
+    mmio::write_mmio32(op, USBCMD, cmd & !CMD_RUN);
+    wait_status(op, STS_HALTED, true)?;
+    mmio::write_mmio32(op, USBCMD, CMD_RESET);
+    let deadline = Deadline::from_ms(RESET_TIMEOUT_MS);
+    while mmio::read_mmio32(op, USBCMD) & CMD_RESET != 0
+        || mmio::read_mmio32(op, USBSTS) & STS_NOT_READY != 0
+    {
+        if deadline.expired() {
+            return Err(UsbError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+/// Controllers set up by probe, until their task takes them over
+static STARTED: [Mutex<Option<Xhci>>; MAX_CONTROLLERS] =
+    [const { Mutex::new(None) }; MAX_CONTROLLERS];
+
+/// Number of controllers probed
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Task owning controller `index`: enumerates its ports, then polls it
+fn controller_task(index: usize) {
+    let Some(mut xhci) = STARTED[index].lock_irqsafe(|xhci| xhci.take()) else {
+        return;
+    };
+    loop {
+        xhci.poll();
+        sched::sleep_ms(POLL_MS);
+    }
+}
+
+/// Starts the controller at `base` and the task handling it
+fn add_controller(base: usize) -> Result<(), ProbeError> {
+    let index = CONTROLLER_COUNT.fetch_add(1, Ordering::AcqRel);
+    if index >= MAX_CONTROLLERS {
+        return Err(ProbeError::NoResources);
+    }
+    let version = mmio::read_mmio32(base, CAPLENGTH) >> 16;
+    let mut xhci = Xhci::new(base).map_err(|e| {
+        pr_err!("xhci: controller at {:#x}: {:?}", base, e);
+        ProbeError::NoDevice
+    })?;
+    if let Err(e) = xhci.start() {
+        pr_err!("xhci: controller at {:#x} doesn't start: {:?}", base, e);
+        return Err(ProbeError::NoDevice);
+    }
+    println!(
+        "xhci: controller at {:#x}, xHCI {:x}.{:02x}, {} ports, {} slots",
+        base,
+        version >> 8,
+        version & 0xff,
+        xhci.max_ports,
+        xhci.max_slots
+    );
+    STARTED[index].lock_irqsafe(|slot| *slot = Some(xhci));
+    sched::spawn(TASK_NAMES[index], controller_task, index)
+        .map(|_| ())
+        .map_err(|e| {
+            pr_err!("xhci: cannot start the controller task: {:?}", e);
+            ProbeError::NoResources
+        })
+}
+
+/// Driver for xHCI controllers on PCIe
+pub struct XhciPciDriver;
+
+impl PciDriver for XhciPciDriver {
+    fn probe(&self, dev: &'static PciDevice) -> Result<(), ProbeError> {
+        let bar = dev.bar(0).ok_or(ProbeError::NoDevice)?;
+        dev.enable_bus_master();
+        add_controller(bar.base)
+    }
+}
+
+/// Driver for `generic-xhci` DTB nodes
+pub struct XhciPlatformDriver;
+
+impl device::Driver for XhciPlatformDriver {
+    fn probe(&self, dev: &PlatformDevice) -> Result<(), ProbeError> {
+        let reg = dev.reg(0).ok_or(ProbeError::NoDevice)?;
+        add_controller(reg.base)
+    }
+}
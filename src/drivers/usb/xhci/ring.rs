@@ -0,0 +1,220 @@
+//! xHCI rings
+//!
+//! The controller and the driver exchange 16-byte Transfer Request Blocks (TRBs) through rings in
+//! DMA memory. Command and transfer rings are written by the driver: their last TRB is a link
+//! back to the start, and the cycle bit of each TRB tells the controller whether it belongs to
+//! the current pass. The event ring is written by the controller, with the same cycle bit
+//! scheme, and read through the Event Ring Segment Table (ERST).
+
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{Ordering, fence};
+
+use crate::kernel::mm::dma::{self, DmaBuffer, DmaError};
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+
+/// Number of TRBs of a ring, filling a page
+pub const RING_SIZE: usize = PAGE_SIZE / size_of::<Trb>();
+
+/* --- TRB Constants --- */
+pub const TRB_CYCLE: u32 = 1 << 0;
+/// Toggle cycle, in a link TRB
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+/// Interrupt on completion: post a transfer event
+pub const TRB_IOC: u32 = 1 << 5;
+/// The parameter holds the data itself
+pub const TRB_IDT: u32 = 1 << 6;
+const TRB_TYPE_SHIFT: u32 = 10;
+/// Direction of data and status stages
+pub const TRB_DIR_IN: u32 = 1 << 16;
+/// Transfer type of a setup stage
+pub const TRB_TRT_OUT: u32 = 2 << 16;
+pub const TRB_TRT_IN: u32 = 3 << 16;
+
+/* --- TRB Types --- */
+pub const TRB_NORMAL: u32 = 1;
+pub const TRB_SETUP: u32 = 2;
+pub const TRB_DATA: u32 = 3;
+pub const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+pub const TRB_ENABLE_SLOT: u32 = 9;
+pub const TRB_DISABLE_SLOT: u32 = 10;
+pub const TRB_ADDRESS_DEVICE: u32 = 11;
+pub const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+pub const TRB_EVALUATE_CONTEXT: u32 = 13;
+pub const TRB_TRANSFER_EVENT: u32 = 32;
+pub const TRB_COMMAND_COMPLETION: u32 = 33;
+pub const TRB_PORT_STATUS_CHANGE: u32 = 34;
+
+/* --- Completion Codes --- */
+pub const COMPLETION_SUCCESS: u8 = 1;
+pub const COMPLETION_SHORT_PACKET: u8 = 13;
+
+/// A Transfer Request Block
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Trb {
+    pub param: u64,
+    pub status: u32,
+    pub control: u32,
+}
+
+impl Trb {
+    /// A TRB of type `kind` with `flags` in its control field
+    pub fn new(kind: u32, param: u64, status: u32, flags: u32) -> Self {
+        Self {
+            param,
+            status,
+            control: kind << TRB_TYPE_SHIFT | flags,
+        }
+    }
+
+    pub fn kind(&self) -> u32 {
+        (self.control >> TRB_TYPE_SHIFT) & 0x3f
+    }
+
+    /// Completion code of an event
+    pub fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    /// Slot of a command completion or transfer event
+    pub fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    /// Device context index of the endpoint of a transfer event
+    pub fn endpoint(&self) -> u8 {
+        ((self.control >> 16) & 0x1f) as u8
+    }
+}
+
+/// A command or transfer ring
+pub struct Ring {
+    pub buf: DmaBuffer,
+    enqueue: usize,
+    /// Cycle bit of the TRBs of the current pass
+    cycle: bool,
+}
+
+impl Ring {
+    /// Allocates a ring whose last TRB links back to the first
+    pub fn new() -> Result<Self, DmaError> {
+        let buf = dma::alloc_coherent(RING_SIZE * size_of::<Trb>())?;
+        let ring = Self {
+            buf,
+            enqueue: 0,
+            cycle: true,
+        };
+        let link = Trb::new(TRB_LINK, buf.paddr as u64, 0, TRB_TOGGLE_CYCLE);
+        unsafe { write_volatile(ring.slot(RING_SIZE - 1), link) };
+        Ok(ring)
+    }
+
+    pub fn free(self) {
+        let _ = dma::free_coherent(self.buf);
+    }
+
+    fn slot(&self, index: usize) -> *mut Trb {
+        (self.buf.vaddr as *mut Trb).wrapping_add(index)
+    }
+
+    /// Address of the first TRB, with the cycle bit the controller starts with
+    pub fn dequeue_pointer(&self) -> u64 {
+        self.buf.paddr as u64 | TRB_CYCLE as u64
+    }
+
+    /// Appends `trb` and returns its address
+    ///
+    /// The control word is written last, with the cycle bit handing the TRB to the controller.
+    pub fn push(&mut self, mut trb: Trb) -> u64 {
+        let addr = (self.buf.paddr + self.enqueue * size_of::<Trb>()) as u64;
+        let control = (trb.control & !TRB_CYCLE) | self.cycle as u32;
+        trb.control = control ^ TRB_CYCLE;
+        let slot = self.slot(self.enqueue);
+        unsafe {
+            write_volatile(slot, trb);
+            fence(Ordering::SeqCst);
+            write_volatile(&raw mut (*slot).control, control);
+        }
+        self.enqueue += 1;
+        if self.enqueue == RING_SIZE - 1 {
+            // Hand the link TRB over too, then start the next pass
+            let link = self.slot(self.enqueue);
+            unsafe {
+                let control = read_volatile(&raw const (*link).control);
+                write_volatile(
+                    &raw mut (*link).control,
+                    (control & !TRB_CYCLE) | self.cycle as u32,
+                );
+            }
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+        addr
+    }
+}
+
+/// An entry of the Event Ring Segment Table
+#[repr(C)]
+struct ErstEntry {
+    base: u64,
+    size: u32,
+    reserved: u32,
+}
+
+/// The event ring of an interrupter, in a single segment
+pub struct EventRing {
+    pub buf: DmaBuffer,
+    /// Holds the segment table
+    pub erst: DmaBuffer,
+    dequeue: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    pub fn new() -> Result<Self, DmaError> {
+        let buf = dma::alloc_coherent(RING_SIZE * size_of::<Trb>())?;
+        let erst = match dma::alloc_coherent(size_of::<ErstEntry>()) {
+            Ok(erst) => erst,
+            Err(e) => {
+                let _ = dma::free_coherent(buf);
+                return Err(e);
+            }
+        };
+        let entry = ErstEntry {
+            base: buf.paddr as u64,
+            size: RING_SIZE as u32,
+            reserved: 0,
+        };
+        unsafe { write_volatile(erst.vaddr as *mut ErstEntry, entry) };
+        Ok(Self {
+            buf,
+            erst,
+            dequeue: 0,
+            cycle: true,
+        })
+    }
+
+    /// Address of the next TRB to read, for the `ERDP` register
+    pub fn dequeue_pointer(&self) -> u64 {
+        (self.buf.paddr + self.dequeue * size_of::<Trb>()) as u64
+    }
+
+    /// Returns the next event, if the controller has written one
+    pub fn pop(&mut self) -> Option<Trb> {
+        let slot = (self.buf.vaddr as *const Trb).wrapping_add(self.dequeue);
+        let control = unsafe { read_volatile(&raw const (*slot).control) };
+        if (control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+        // The rest of the TRB was written before its control word
+        fence(Ordering::SeqCst);
+        let trb = unsafe { read_volatile(slot) };
+        self.dequeue += 1;
+        if self.dequeue == RING_SIZE {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+}
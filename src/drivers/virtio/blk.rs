@@ -0,0 +1,323 @@
+//! virtio-blk driver
+//!
+//! Every virtio block device becomes a `BlockDevice` named `vda`, `vdb`, ... in probe order. A
+//! request is a chain: a header giving the operation and the first sector, the data, and a
+//! status byte the device writes last. Requests are issued one at a time; the caller sleeps
+//! until the completion interrupt.
+//!
+//! The data is cut into segments of at most `size_max` bytes and a request into at most
+//! `seg_max` segments, when the device sets these limits; a transfer needing more segments than
+//! a chain holds (see `VirtQueue::max_chain`) is issued as several requests. With indirect
+//! descriptors, a whole request takes a single descriptor of the ring.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::drivers::gic;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::semaphore::Semaphore;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::block::{self, BlockDevice, BlockError};
+use crate::kernel::irq;
+use crate::{pr_err, println};
+
+use super::Transport;
+use super::queue::{Buffer, MAX_INDIRECT, RING_FEATURES, VirtQueue};
+
+/// Maximum number of disks the driver can manage
+const MAX_DISKS: usize = 4;
+
+/// Feature bits
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+/// Offsets in the configuration space: `capacity` (in 512-byte sectors), then the largest
+/// segment in bytes and the most segments in a request
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_SIZE_MAX: usize = 8;
+const CONFIG_SEG_MAX: usize = 12;
+
+/// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+/// Request status written by the device
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// The only queue of a block device
+const REQUEST_QUEUE: u32 = 0;
+
+/// Header of a request
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// What the device accesses, kept at a fixed address while requests are in flight
+struct DiskState {
+    queue: VirtQueue,
+    header: RequestHeader,
+    status: u8,
+}
+
+/// A virtio block device
+pub struct VirtioBlk {
+    name: &'static str,
+    transport: Mutex<Option<Transport>>,
+    capacity: AtomicUsize,
+    features: AtomicUsize,
+    /// Largest data segment in bytes, 0 for no limit
+    size_max: AtomicUsize,
+    /// Most data segments in a request
+    max_segments: AtomicUsize,
+    state: Mutex<DiskState>,
+    /// Held while a request is in flight
+    request: Semaphore,
+    /// Woken by the completion interrupt
+    done: WaitQueue,
+}
+
+impl VirtioBlk {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            transport: Mutex::new(None),
+            capacity: AtomicUsize::new(0),
+            features: AtomicUsize::new(0),
+            size_max: AtomicUsize::new(0),
+            max_segments: AtomicUsize::new(1),
+            state: Mutex::new(DiskState {
+                queue: VirtQueue::new(),
+                header: RequestHeader {
+                    kind: 0,
+                    reserved: 0,
+                    sector: 0,
+                },
+                status: 0,
+            }),
+            request: Semaphore::new(1),
+            done: WaitQueue::new(),
+        }
+    }
+
+    fn transport(&self) -> Transport {
+        // Set by `probe` before the disk is published
+        self.transport
+            .lock_irqsafe(|transport| *transport)
+            .expect("virtio-blk: disk not probed")
+    }
+
+    /// Issues one request and sleeps until the device completes it
+    ///
+    /// `data` are the segments, none for a flush.
+    fn submit(&self, kind: u32, sector: u64, data: &[Buffer]) -> Result<(), BlockError> {
+        let transport = self.transport();
+        self.request.down();
+        let kick = self.state.lock_irqsafe(|state| {
+            state.header = RequestHeader {
+                kind,
+                reserved: 0,
+                sector,
+            };
+            state.status = 0xff;
+            let header = Buffer {
+                addr: &state.header as *const RequestHeader as usize,
+                len: size_of::<RequestHeader>(),
+                device_writes: false,
+            };
+            let status = Buffer::writable(core::slice::from_mut(&mut state.status));
+            let mut chain = [header; MAX_INDIRECT];
+            chain[1..=data.len()].copy_from_slice(data);
+            chain[data.len() + 1] = status;
+            state.queue.add(&chain[..data.len() + 2])?;
+            Some(state.queue.kick_prepare())
+        });
+        let Some(kick) = kick else {
+            self.request.up();
+            return Err(BlockError::Io);
+        };
+        if kick {
+            transport.notify(REQUEST_QUEUE);
+        }
+
+        self.done
+            .wait_event(|| self.state.lock_irqsafe(|state| state.queue.has_used()));
+        let status = self.state.lock_irqsafe(|state| {
+            state.queue.pop_used();
+            state.status
+        });
+        self.request.up();
+        if status != VIRTIO_BLK_S_OK {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    /// Reads or writes `data` from `sector` on, in as many requests as the limits require
+    ///
+    /// `size_max` being whole sectors, so is every request but the last.
+    fn transfer(&self, kind: u32, sector: u64, data: Buffer) -> Result<(), BlockError> {
+        let size_max = match self.size_max.load(Ordering::Relaxed) {
+            0 => data.len,
+            size_max => size_max,
+        };
+        let max_segments = self.max_segments.load(Ordering::Relaxed);
+        let mut done = 0;
+        while done < data.len {
+            let mut segments = [data; MAX_INDIRECT];
+            let mut count = 0;
+            let mut len = 0;
+            while count < max_segments && done + len < data.len {
+                let segment = size_max.min(data.len - done - len);
+                segments[count] = Buffer {
+                    addr: data.addr + done + len,
+                    len: segment,
+                    device_writes: data.device_writes,
+                };
+                count += 1;
+                len += segment;
+            }
+            let first = sector + (done / block::SECTOR_SIZE) as u64;
+            self.submit(kind, first, &segments[..count])?;
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.capacity.load(Ordering::Relaxed) as u64
+    }
+
+    fn read_only(&self) -> bool {
+        self.features.load(Ordering::Relaxed) as u64 & VIRTIO_BLK_F_RO != 0
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.transfer(VIRTIO_BLK_T_IN, sector, Buffer::writable(buf))
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_request(self, sector, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.transfer(VIRTIO_BLK_T_OUT, sector, Buffer::readable(buf))
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.features.load(Ordering::Relaxed) as u64 & VIRTIO_BLK_F_FLUSH == 0 {
+            // Without the feature the device writes through
+            return Ok(());
+        }
+        self.submit(VIRTIO_BLK_T_FLUSH, 0, &[])
+    }
+}
+
+/// The disks, in probe order; entries `[0, DISK_COUNT)` are initialized
+static DISKS: [VirtioBlk; MAX_DISKS] = [
+    VirtioBlk::new("vda"),
+    VirtioBlk::new("vdb"),
+    VirtioBlk::new("vdc"),
+    VirtioBlk::new("vdd"),
+];
+
+/// Number of disks probed so far
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Completion interrupt: wakes the task waiting for its request
+fn handle_irq(_id: u32, data: usize) {
+    let disk = &DISKS[data];
+    if let Some(transport) = disk.transport.lock_irqsafe(|transport| *transport) {
+        transport.ack_interrupt();
+    }
+    disk.done.wake_up();
+}
+
+/// Initializes the block device behind `transport` and registers it
+pub fn probe(transport: Transport, irq_id: u32) {
+    let index = DISK_COUNT.load(Ordering::Acquire);
+    if index == MAX_DISKS {
+        println!("virtio-blk: no free instance");
+        return;
+    }
+    let disk = &DISKS[index];
+
+    let supported = VIRTIO_BLK_F_SIZE_MAX
+        | VIRTIO_BLK_F_SEG_MAX
+        | VIRTIO_BLK_F_RO
+        | VIRTIO_BLK_F_FLUSH
+        | RING_FEATURES;
+    let features = match transport.begin_init(supported) {
+        Ok(features) => features,
+        Err(e) => {
+            pr_err!("virtio-blk: cannot initialize {}: {:?}", disk.name, e);
+            return;
+        }
+    };
+    let queue_ok = disk.state.lock_irqsafe(|state| {
+        state.queue.init();
+        state.queue.set_features(features)?;
+        transport.setup_queue(REQUEST_QUEUE, &mut state.queue)
+    });
+    if let Err(e) = queue_ok {
+        println!(
+            "virtio-blk: cannot set up the queue of {}: {:?}",
+            disk.name, e
+        );
+        transport.fail();
+        return;
+    }
+    disk.transport.lock_irqsafe(|t| *t = Some(transport));
+    disk.features.store(features as usize, Ordering::Relaxed);
+    if features & VIRTIO_BLK_F_SIZE_MAX != 0 {
+        // Segments end on a sector boundary
+        let size_max = transport.config_u32(CONFIG_SIZE_MAX) as usize / block::SECTOR_SIZE;
+        disk.size_max
+            .store(size_max.max(1) * block::SECTOR_SIZE, Ordering::Relaxed);
+    }
+    // The header and the status take two descriptors of the chain
+    let mut max_segments = disk.state.lock_irqsafe(|state| state.queue.max_chain()) - 2;
+    if features & VIRTIO_BLK_F_SEG_MAX != 0 {
+        max_segments = max_segments.min(transport.config_u32(CONFIG_SEG_MAX) as usize);
+    }
+    disk.max_segments
+        .store(max_segments.max(1), Ordering::Relaxed);
+    disk.capacity.store(
+        transport.config_u64(CONFIG_CAPACITY) as usize,
+        Ordering::Relaxed,
+    );
+    transport.finish_init();
+    DISK_COUNT.store(index + 1, Ordering::Release);
+
+    if irq_id != 0 && irq::request_irq(irq_id, disk.name, handle_irq, index).is_ok() {
+        gic::enable_irq(irq_id);
+    }
+    if let Err(e) = block::register(disk) {
+        pr_err!("virtio-blk: cannot register {}: {:?}", disk.name, e);
+        return;
+    }
+    println!(
+        "virtio-blk: {} is {} MiB{}",
+        disk.name,
+        disk.sector_count() * block::SECTOR_SIZE as u64 / (1024 * 1024),
+        if disk.read_only() { ", read-only" } else { "" }
+    );
+}
@@ -0,0 +1,495 @@
+//! virtio-console driver
+//!
+//! A virtio-console device has one or more ports, each a byte stream with its own receive and
+//! transmit queues. Every port is registered with the console subsystem: console ports as `hvc0`,
+//! `hvc1`, ..., the others (`virtserialport` in QEMU) as `vport0p<port>`. Selecting one as the
+//! system console is the console subsystem's business, e.g. `console=hvc0` on the command line.
+//!
+//! ## Port Discovery
+//!
+//! Without `VIRTIO_CONSOLE_F_MULTIPORT` the device has a single console port, port 0. With it,
+//! ports are announced over a pair of control queues: the driver sends `DEVICE_READY`, the device
+//! answers with a `DEVICE_ADD` per port, the driver acknowledges each with `PORT_READY` and the
+//! device then tells which ports are consoles (`CONSOLE_PORT`). Control messages received later,
+//! such as hot-plugged ports, are handled from the interrupt like received bytes.
+//!
+//! ## Data Path
+//!
+//! - **RX:** each port keeps its receive queue full of small buffers. The interrupt handler only
+//!   acknowledges the device and schedules deferred work, which moves the received bytes to the
+//!   task holding the port's input channel (`Console::input`), or to the port's RX buffer read
+//!   by `getchar`.
+//! - **TX:** `putchar` copies the byte into a free transmit buffer and notifies the device,
+//!   waiting for a buffer to come back when all of them are in flight.
+
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::drivers::gic;
+use crate::ipc::channel::Channel;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::spsc;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::console::{self, Console, INPUT_QUEUE_SIZE, InputReceiver};
+use crate::kernel::device;
+use crate::kernel::irq::{self, softirq};
+use crate::kernel::notifier::Deadline;
+use crate::{pr_err, println};
+
+use super::queue::{BufferRing, QUEUE_SIZE};
+use super::{Transport, VirtioError};
+
+/// Maximum number of ports of the device the driver uses
+const MAX_PORTS: usize = 4;
+
+/// Names given to console ports, in discovery order
+const HVC_NAMES: [&str; MAX_PORTS] = ["hvc0", "hvc1", "hvc2", "hvc3"];
+
+/// Names given to the other ports, by port number
+const VPORT_NAMES: [&str; MAX_PORTS] = ["vport0p0", "vport0p1", "vport0p2", "vport0p3"];
+
+/// The device has control queues and may have several ports
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+/// Offset of `max_nr_ports` in the configuration space
+const CONFIG_MAX_NR_PORTS: usize = 4;
+
+/// Queues of port 0 and control queues; port `n > 0` uses queues `2n + 2` and `2n + 3`
+const CONTROL_RX_QUEUE: u32 = 2;
+const CONTROL_TX_QUEUE: u32 = 3;
+
+/// Control events
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Port number of control messages about the whole device
+const VIRTIO_CONSOLE_BAD_ID: u32 = u32::MAX;
+
+/// Size of a control message header: port number, event and value
+const CONTROL_HEADER_LEN: usize = 8;
+
+/// Size of a control receive buffer, enough for a `PORT_NAME` message with a short name
+const CONTROL_BUFFER_SIZE: usize = 64;
+
+/// Size of a receive buffer of a port
+const RX_BUFFER_SIZE: usize = 64;
+
+/// Size of the buffer of received bytes not yet read
+const INPUT_BUFFER_SIZE: usize = 256;
+
+/// A port of the device
+pub struct VirtioPort {
+    /// Port number on the device
+    id: usize,
+    /// Name registered with the console subsystem, empty until the port is registered
+    name: Mutex<&'static str>,
+    /// Set while the device has the port
+    present: AtomicBool,
+    rx: Mutex<BufferRing<RX_BUFFER_SIZE>>,
+    tx: Mutex<BufferRing<1>>,
+    /// Bytes received and not yet read
+    ///
+    /// Filled by the deferred work, drained by `getchar` with `rx_read` held.
+    received: spsc::Ring<u8, INPUT_BUFFER_SIZE>,
+    /// Serializes the readers of `received`
+    rx_read: Mutex<()>,
+    /// Tasks sleeping in `getchar_blocking`
+    rx_wait: WaitQueue,
+    /// Received bytes sent by the deferred work while a task holds the receiver
+    input: Channel<u8, INPUT_QUEUE_SIZE>,
+}
+
+impl VirtioPort {
+    const fn new(id: usize) -> Self {
+        Self {
+            id,
+            name: Mutex::new(""),
+            present: AtomicBool::new(false),
+            rx: Mutex::new(BufferRing::new()),
+            tx: Mutex::new(BufferRing::new()),
+            received: spsc::Ring::new(),
+            rx_read: Mutex::new(()),
+            rx_wait: WaitQueue::new(),
+            input: Channel::new(),
+        }
+    }
+
+    fn rx_queue(&self) -> u32 {
+        match self.id {
+            0 => 0,
+            id => 2 * id as u32 + 2,
+        }
+    }
+
+    fn tx_queue(&self) -> u32 {
+        self.rx_queue() + 1
+    }
+
+    /// Gives the port's queues to the device, with every receive buffer
+    fn setup(&self, transport: &Transport) -> Result<(), VirtioError> {
+        self.rx.lock_irqsafe(|rx| {
+            rx.init();
+            transport.setup_queue(self.rx_queue(), &mut rx.queue)?;
+            for i in 0..QUEUE_SIZE {
+                rx.post(i, RX_BUFFER_SIZE, true);
+            }
+            Ok(())
+        })?;
+        self.tx.lock_irqsafe(|tx| {
+            tx.init();
+            transport.setup_queue(self.tx_queue(), &mut tx.queue)
+        })
+    }
+
+    /// Moves the received bytes to the input channel or the RX buffer and recycles the buffers
+    ///
+    /// Only called from the deferred work, the only producer of both.
+    fn poll_rx(&self, transport: &Transport) {
+        let name = self.name();
+        let mut received = false;
+        while let Some((index, len)) = self.rx.lock_irqsafe(|rx| rx.pop()) {
+            // The buffer belongs to nobody until it is posted again, so it is read without the
+            // lock
+            let data = self
+                .rx
+                .lock_irqsafe(|rx| addr_of!(rx.buffers[index]) as *const u8);
+            let data = unsafe { core::slice::from_raw_parts(data, len.min(RX_BUFFER_SIZE)) };
+            match self.input.sender().filter(|_| self.input.has_receiver()) {
+                Some(mut sender) => {
+                    for &c in data.iter().filter(|&&c| !console::intercept(name, c)) {
+                        // Dropped if the reader is too slow, like a full RX buffer
+                        let _ = sender.try_send(c);
+                    }
+                }
+                None => {
+                    let mut rx = unsafe { self.received.producer() };
+                    for &c in data.iter().filter(|&&c| !console::intercept(name, c)) {
+                        let _ = rx.push(c);
+                    }
+                }
+            }
+            self.rx
+                .lock_irqsafe(|rx| rx.post(index, RX_BUFFER_SIZE, true));
+            transport.notify(self.rx_queue());
+            received = true;
+        }
+        if received {
+            self.rx_wait.wake_up();
+        }
+    }
+}
+
+impl Console for VirtioPort {
+    fn name(&self) -> &'static str {
+        self.name.lock_irqsafe(|name| *name)
+    }
+
+    /// Bytes written to a port the device has removed are discarded
+    fn putchar(&self, c: u8) {
+        let Some(transport) = DEVICE.transport() else {
+            return;
+        };
+        if !self.present.load(Ordering::Acquire) {
+            return;
+        }
+        while !self.tx.lock_irqsafe(|tx| {
+            let Some(index) = tx.reclaim() else {
+                return false;
+            };
+            tx.buffers[index][0] = c;
+            tx.post(index, 1, false)
+        }) {
+            core::hint::spin_loop();
+        }
+        transport.notify(self.tx_queue());
+    }
+
+    fn getchar(&self) -> Option<u8> {
+        // The only consumer of `received` is whoever holds `rx_read`
+        self.rx_read
+            .lock(|_| unsafe { self.received.consumer() }.pop())
+    }
+
+    fn getchar_blocking(&self) -> u8 {
+        loop {
+            self.rx_wait.wait_event(|| !self.received.is_empty());
+            // Another reader may have taken the byte first
+            if let Some(c) = self.getchar() {
+                return c;
+            }
+        }
+    }
+
+    fn input(&'static self) -> Option<InputReceiver> {
+        self.input.receiver()
+    }
+
+    fn flush(&self, deadline: &Deadline) -> bool {
+        while !self.tx.lock_irqsafe(|tx| {
+            tx.reclaim();
+            tx.idle()
+        }) {
+            if deadline.expired() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The virtio-console device
+struct VirtioConsole {
+    transport: Mutex<Option<Transport>>,
+    /// DTB node of the device, given to `console::register`; none for a PCI function
+    node: Mutex<Option<&'static device::PlatformDevice>>,
+    multiport: AtomicBool,
+    /// Number of ports the driver uses, at most `MAX_PORTS`
+    port_count: AtomicUsize,
+    control_rx: Mutex<BufferRing<CONTROL_BUFFER_SIZE>>,
+    control_tx: Mutex<BufferRing<CONTROL_HEADER_LEN>>,
+    ports: [VirtioPort; MAX_PORTS],
+    /// Number of console ports registered so far
+    hvc_count: AtomicUsize,
+}
+
+impl VirtioConsole {
+    const fn new() -> Self {
+        Self {
+            transport: Mutex::new(None),
+            node: Mutex::new(None),
+            multiport: AtomicBool::new(false),
+            port_count: AtomicUsize::new(0),
+            control_rx: Mutex::new(BufferRing::new()),
+            control_tx: Mutex::new(BufferRing::new()),
+            ports: [
+                VirtioPort::new(0),
+                VirtioPort::new(1),
+                VirtioPort::new(2),
+                VirtioPort::new(3),
+            ],
+            hvc_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn transport(&self) -> Option<Transport> {
+        self.transport.lock_irqsafe(|transport| *transport)
+    }
+
+    fn ports(&'static self) -> &'static [VirtioPort] {
+        &self.ports[..self.port_count.load(Ordering::Acquire)]
+    }
+
+    /// Sends a control message about port `id`
+    fn send_control(&self, id: u32, event: u16, value: u16) {
+        let Some(transport) = self.transport() else {
+            return;
+        };
+        let sent = self.control_tx.lock_irqsafe(|tx| {
+            let Some(index) = tx.reclaim() else {
+                return false;
+            };
+            let message = &mut tx.buffers[index];
+            message[0..4].copy_from_slice(&id.to_le_bytes());
+            message[4..6].copy_from_slice(&event.to_le_bytes());
+            message[6..8].copy_from_slice(&value.to_le_bytes());
+            tx.post(index, CONTROL_HEADER_LEN, false)
+        });
+        if sent {
+            transport.notify(CONTROL_TX_QUEUE);
+        } else {
+            println!("virtio-console: control message {} dropped", event);
+        }
+    }
+
+    /// Handles the control messages received, then registers the ports they announced
+    fn process_control(&'static self) {
+        let Some(transport) = self.transport() else {
+            return;
+        };
+        let mut message = [0u8; CONTROL_BUFFER_SIZE];
+        while let Some(len) = self.control_rx.lock_irqsafe(|rx| {
+            let (index, len) = rx.pop()?;
+            let len = len.min(CONTROL_BUFFER_SIZE);
+            message[..len].copy_from_slice(&rx.buffers[index][..len]);
+            rx.post(index, CONTROL_BUFFER_SIZE, true);
+            Some(len)
+        }) {
+            transport.notify(CONTROL_RX_QUEUE);
+            self.handle_control(&message[..len]);
+        }
+        // A port not announced as a console by now is a plain serial port
+        for port in self.ports() {
+            if port.present.load(Ordering::Acquire) && port.name().is_empty() {
+                self.register(port, false);
+            }
+        }
+    }
+
+    fn handle_control(&'static self, message: &[u8]) {
+        if message.len() < CONTROL_HEADER_LEN {
+            return;
+        }
+        let id = u32::from_le_bytes([message[0], message[1], message[2], message[3]]);
+        let event = u16::from_le_bytes([message[4], message[5]]);
+        // Ports beyond the ones the driver set up have no queues
+        let Some(port) = self.ports().get(id as usize) else {
+            return;
+        };
+        match event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                port.present.store(true, Ordering::Release);
+                self.send_control(id, VIRTIO_CONSOLE_PORT_READY, 1);
+            }
+            VIRTIO_CONSOLE_DEVICE_REMOVE => port.present.store(false, Ordering::Release),
+            VIRTIO_CONSOLE_CONSOLE_PORT => {
+                if port.name().is_empty() {
+                    self.register(port, true);
+                }
+                self.send_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+            }
+            VIRTIO_CONSOLE_PORT_NAME => {
+                let name = &message[CONTROL_HEADER_LEN..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                if let Ok(name) = core::str::from_utf8(name) {
+                    println!("virtio-console: port {} is named {}", id, name);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Registers `port` with the console subsystem
+    ///
+    /// Ports are never unregistered: a port removed by the device keeps its name and gets it
+    /// back if it is added again.
+    fn register(&'static self, port: &'static VirtioPort, is_console: bool) {
+        let name = if is_console {
+            let index = self.hvc_count.fetch_add(1, Ordering::Relaxed);
+            match HVC_NAMES.get(index) {
+                Some(name) => *name,
+                None => return,
+            }
+        } else {
+            VPORT_NAMES[port.id]
+        };
+        port.name.lock_irqsafe(|n| *n = name);
+        if !is_console {
+            self.send_control(port.id as u32, VIRTIO_CONSOLE_PORT_OPEN, 1);
+        }
+        let registered = match self.node.lock_irqsafe(|node| *node) {
+            Some(node) => console::register(port, node),
+            None => console::register_virtual(port),
+        };
+        match registered {
+            Ok(()) => println!("virtio-console: port {} is {}", port.id, name),
+            Err(e) => pr_err!("virtio-console: cannot register {}: {:?}", name, e),
+        }
+    }
+}
+
+/// The device; only one virtio-console is driven
+static DEVICE: VirtioConsole = VirtioConsole::new();
+
+/// Deferred part of the interrupt: control messages and received bytes
+fn rx_work(_data: usize) {
+    let Some(transport) = DEVICE.transport() else {
+        return;
+    };
+    if DEVICE.multiport.load(Ordering::Relaxed) {
+        DEVICE.process_control();
+    }
+    for port in DEVICE.ports() {
+        port.poll_rx(&transport);
+    }
+}
+
+/// Interrupt handler: acknowledges the device and defers the rest
+fn handle_irq(_id: u32, data: usize) {
+    if let Some(transport) = DEVICE.transport() {
+        transport.ack_interrupt();
+    }
+    if softirq::schedule_work(rx_work, data).is_err() {
+        println!("virtio-console: RX work queue full");
+    }
+}
+
+/// Initializes the console device behind `transport`, found at the DTB node `dev` if it has one
+pub fn probe(transport: Transport, irq_id: u32, dev: Option<&device::PlatformDevice>) {
+    if DEVICE.transport().is_some() {
+        println!("virtio-console: only one device is supported");
+        return;
+    }
+    let features = match transport.begin_init(VIRTIO_CONSOLE_F_MULTIPORT) {
+        Ok(features) => features,
+        Err(e) => {
+            pr_err!("virtio-console: cannot initialize the device: {:?}", e);
+            return;
+        }
+    };
+    let multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    let port_count = if multiport {
+        (transport.config_u32(CONFIG_MAX_NR_PORTS) as usize).clamp(1, MAX_PORTS)
+    } else {
+        1
+    };
+
+    let mut queues_ok = DEVICE.ports[0].setup(&transport);
+    if multiport {
+        queues_ok = queues_ok
+            .and_then(|_| {
+                DEVICE.control_rx.lock_irqsafe(|rx| {
+                    rx.init();
+                    transport.setup_queue(CONTROL_RX_QUEUE, &mut rx.queue)?;
+                    for i in 0..QUEUE_SIZE {
+                        rx.post(i, CONTROL_BUFFER_SIZE, true);
+                    }
+                    Ok(())
+                })
+            })
+            .and_then(|_| {
+                DEVICE.control_tx.lock_irqsafe(|tx| {
+                    tx.init();
+                    transport.setup_queue(CONTROL_TX_QUEUE, &mut tx.queue)
+                })
+            });
+    }
+    for port in &DEVICE.ports[1..port_count] {
+        queues_ok = queues_ok.and_then(|_| port.setup(&transport));
+    }
+    if let Err(e) = queues_ok {
+        pr_err!("virtio-console: cannot set up the queues: {:?}", e);
+        transport.fail();
+        return;
+    }
+
+    // DTB nodes are never freed, see `dtb::devices`
+    let node = dev.map(|dev| unsafe { &*(dev as *const device::PlatformDevice) });
+    DEVICE.node.lock_irqsafe(|n| *n = node);
+    DEVICE.multiport.store(multiport, Ordering::Relaxed);
+    DEVICE.port_count.store(port_count, Ordering::Release);
+    DEVICE.transport.lock_irqsafe(|t| *t = Some(transport));
+    transport.finish_init();
+    for port in DEVICE.ports() {
+        transport.notify(port.rx_queue());
+    }
+
+    if multiport {
+        transport.notify(CONTROL_RX_QUEUE);
+        // QEMU answers each control message before the notification returns, so the ports are
+        // known right away; other devices may answer later, through the interrupt
+        DEVICE.send_control(VIRTIO_CONSOLE_BAD_ID, VIRTIO_CONSOLE_DEVICE_READY, 1);
+        DEVICE.process_control();
+    } else {
+        DEVICE.ports[0].present.store(true, Ordering::Release);
+        DEVICE.register(&DEVICE.ports[0], true);
+    }
+
+    if irq_id != 0 && irq::request_irq(irq_id, "virtio-console", handle_irq, 0).is_ok() {
+        gic::enable_irq(irq_id);
+    }
+}
@@ -0,0 +1,442 @@
+//! virtio-gpu driver
+//!
+//! 2D only: the driver asks the device for the size of its first scanout, creates a host resource
+//! of that size in a 32-bit XRGB format, backs it with a linear framebuffer taken from the frame
+//! allocator and shows it on the scanout. The framebuffer is then registered with the framebuffer
+//! console. Drawing happens in guest memory; `flush` copies a rectangle to the host resource
+//! (`TRANSFER_TO_HOST_2D`) and has the display updated (`RESOURCE_FLUSH`).
+//!
+//! Commands are issued one at a time on the control queue and the driver spins until the device
+//! answers: they are short, and the console may flush from a context that can't sleep. The
+//! framebuffer is only allocated by `init`, once the frame allocator is up; `probe` runs before.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::console::fbcon::{self, Display, Framebuffer};
+use crate::kernel::mm::addr_space::MapFlags;
+use crate::kernel::mm::frame;
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+use crate::kernel::notifier::Deadline;
+use crate::{pr_err, println};
+
+use super::Transport;
+use super::queue::{Buffer, VirtQueue};
+
+/// Command types
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+
+/// Response types
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Pixel format: bytes B, G, R, X, i.e. XRGB pixels as little-endian words
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+/// Maximum number of scanouts in a display info response
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+/// The control queue; the cursor queue isn't used
+const CONTROL_QUEUE: u32 = 0;
+
+/// ID of the only resource, the framebuffer
+const RESOURCE_ID: u32 = 1;
+
+/// Scanout showing the framebuffer
+const SCANOUT_ID: u32 = 0;
+
+/// Size used when the device reports no enabled scanout
+const DEFAULT_MODE: (u32, u32) = (1024, 768);
+
+/// How long the device gets to answer a command
+const COMMAND_TIMEOUT_MS: u32 = 1000;
+
+/// Size of the request buffer, enough for every command sent
+const REQUEST_SIZE: usize = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CtrlHeader {
+    kind: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    ring_idx: u8,
+    padding: [u8; 3],
+}
+
+impl CtrlHeader {
+    fn new(kind: u32) -> Self {
+        Self {
+            kind,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct DisplayOne {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+/// Response to `GET_DISPLAY_INFO`, the largest response
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayInfo {
+    header: CtrlHeader,
+    pmodes: [DisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceCreate2d {
+    header: CtrlHeader,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+/// `RESOURCE_ATTACH_BACKING` with its single memory entry
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AttachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32,
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SetScanout {
+    header: CtrlHeader,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TransferToHost2d {
+    header: CtrlHeader,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceFlush {
+    header: CtrlHeader,
+    rect: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+/// Errors returned by a command
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum GpuError {
+    /// The queue is full or the device didn't answer in time
+    Timeout,
+    /// The device answered with this response type
+    Response(u32),
+}
+
+/// What the device accesses, kept at a fixed address while a command is in flight
+struct GpuState {
+    queue: VirtQueue,
+    request: [u8; REQUEST_SIZE],
+    response: DisplayInfo,
+}
+
+/// The virtio-gpu device
+struct VirtioGpu {
+    transport: Mutex<Option<Transport>>,
+    state: Mutex<GpuState>,
+    /// Size of the scanout, read by `probe`
+    mode: Mutex<(u32, u32)>,
+    fb: Mutex<Framebuffer>,
+    /// Set once the framebuffer is on the scanout
+    ready: AtomicBool,
+}
+
+impl VirtioGpu {
+    /// Sends `request` and waits for the answer, returning the response
+    ///
+    /// Fails unless the response type is `expected`.
+    fn command<T: Copy>(&self, request: &T, expected: u32) -> Result<DisplayInfo, GpuError> {
+        let Some(transport) = self.transport.lock_irqsafe(|transport| *transport) else {
+            return Err(GpuError::Timeout);
+        };
+        const { assert!(size_of::<T>() <= REQUEST_SIZE) };
+        // Held throughout, so commands from several contexts don't mix
+        self.state.lock_irqsafe(|state| {
+            let bytes = unsafe {
+                core::slice::from_raw_parts(request as *const T as *const u8, size_of::<T>())
+            };
+            state.request[..bytes.len()].copy_from_slice(bytes);
+            state.response.header = CtrlHeader::default();
+            let buffers = [
+                Buffer::readable(&state.request[..bytes.len()]),
+                Buffer {
+                    addr: &state.response as *const DisplayInfo as usize,
+                    len: size_of::<DisplayInfo>(),
+                    device_writes: true,
+                },
+            ];
+            state.queue.add(&buffers).ok_or(GpuError::Timeout)?;
+            transport.notify(CONTROL_QUEUE);
+
+            let deadline = Deadline::from_ms(COMMAND_TIMEOUT_MS);
+            while !state.queue.has_used() {
+                if deadline.expired() {
+                    // The descriptors stay with the device: give up on it altogether
+                    self.ready.store(false, Ordering::Relaxed);
+                    return Err(GpuError::Timeout);
+                }
+                core::hint::spin_loop();
+            }
+            state.queue.pop_used();
+            let response = unsafe { core::ptr::read_volatile(&state.response) };
+            match response.header.kind {
+                kind if kind == expected => Ok(response),
+                kind => Err(GpuError::Response(kind)),
+            }
+        })
+    }
+
+    /// Returns the size of the first enabled scanout
+    fn display_size(&self) -> Result<(u32, u32), GpuError> {
+        let info = self.command(
+            &CtrlHeader::new(VIRTIO_GPU_CMD_GET_DISPLAY_INFO),
+            VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
+        )?;
+        let mode = info
+            .pmodes
+            .iter()
+            .find(|mode| mode.enabled != 0 && mode.rect.width != 0 && mode.rect.height != 0)
+            .map_or(DEFAULT_MODE, |mode| (mode.rect.width, mode.rect.height));
+        Ok(mode)
+    }
+
+    /// Creates the host resource, backs it with `fb` and shows it on the scanout
+    fn set_framebuffer(&self, fb: &Framebuffer) -> Result<(), GpuError> {
+        let (width, height) = (fb.width as u32, fb.height as u32);
+        self.command(
+            &ResourceCreate2d {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+                resource_id: RESOURCE_ID,
+                format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+                width,
+                height,
+            },
+            VIRTIO_GPU_RESP_OK_NODATA,
+        )?;
+        self.command(
+            &AttachBacking {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+                resource_id: RESOURCE_ID,
+                nr_entries: 1,
+                addr: fb.addr as u64,
+                length: (fb.stride * fb.height) as u32,
+                padding: 0,
+            },
+            VIRTIO_GPU_RESP_OK_NODATA,
+        )?;
+        self.command(
+            &SetScanout {
+                header: CtrlHeader::new(VIRTIO_GPU_CMD_SET_SCANOUT),
+                rect: Rect {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                },
+                scanout_id: SCANOUT_ID,
+                resource_id: RESOURCE_ID,
+            },
+            VIRTIO_GPU_RESP_OK_NODATA,
+        )?;
+        Ok(())
+    }
+}
+
+impl Display for VirtioGpu {
+    fn framebuffer(&self) -> Framebuffer {
+        self.fb.lock_irqsafe(|fb| *fb)
+    }
+
+    fn flush(&self, x: usize, y: usize, width: usize, height: usize) {
+        if !self.ready.load(Ordering::Relaxed) {
+            return;
+        }
+        let fb = self.framebuffer();
+        let rect = Rect {
+            x: x as u32,
+            y: y as u32,
+            width: width as u32,
+            height: height as u32,
+        };
+        let _ = self
+            .command(
+                &TransferToHost2d {
+                    header: CtrlHeader::new(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+                    rect,
+                    offset: (y * fb.stride + x * 4) as u64,
+                    resource_id: RESOURCE_ID,
+                    padding: 0,
+                },
+                VIRTIO_GPU_RESP_OK_NODATA,
+            )
+            .and_then(|_| {
+                self.command(
+                    &ResourceFlush {
+                        header: CtrlHeader::new(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
+                        rect,
+                        resource_id: RESOURCE_ID,
+                        padding: 0,
+                    },
+                    VIRTIO_GPU_RESP_OK_NODATA,
+                )
+            });
+    }
+}
+
+/// The device; only one virtio-gpu is driven
+static GPU: VirtioGpu = VirtioGpu {
+    transport: Mutex::new(None),
+    state: Mutex::new(GpuState {
+        queue: VirtQueue::new(),
+        request: [0; REQUEST_SIZE],
+        response: DisplayInfo {
+            header: CtrlHeader {
+                kind: 0,
+                flags: 0,
+                fence_id: 0,
+                ctx_id: 0,
+                ring_idx: 0,
+                padding: [0; 3],
+            },
+            pmodes: [DisplayOne {
+                rect: Rect {
+                    x: 0,
+                    y: 0,
+                    width: 0,
+                    height: 0,
+                },
+                enabled: 0,
+                flags: 0,
+            }; VIRTIO_GPU_MAX_SCANOUTS],
+        },
+    }),
+    mode: Mutex::new((0, 0)),
+    fb: Mutex::new(Framebuffer {
+        addr: 0,
+        width: 0,
+        height: 0,
+        stride: 0,
+    }),
+    ready: AtomicBool::new(false),
+};
+
+/// Initializes the GPU behind `transport` and reads the size of its display
+pub fn probe(transport: Transport) {
+    if GPU.transport.lock_irqsafe(|transport| transport.is_some()) {
+        println!("virtio-gpu: only one device is supported");
+        return;
+    }
+    if let Err(e) = transport.begin_init(0) {
+        pr_err!("virtio-gpu: cannot initialize the device: {:?}", e);
+        return;
+    }
+    let queue_ok = GPU.state.lock_irqsafe(|state| {
+        state.queue.init();
+        transport.setup_queue(CONTROL_QUEUE, &mut state.queue)
+    });
+    if let Err(e) = queue_ok {
+        pr_err!("virtio-gpu: cannot set up the control queue: {:?}", e);
+        transport.fail();
+        return;
+    }
+    GPU.transport.lock_irqsafe(|t| *t = Some(transport));
+    transport.finish_init();
+
+    match GPU.display_size() {
+        Ok((width, height)) => {
+            GPU.mode.lock_irqsafe(|mode| *mode = (width, height));
+            println!("virtio-gpu: display is {}x{}", width, height);
+        }
+        Err(e) => {
+            pr_err!("virtio-gpu: cannot read the display size: {:?}", e);
+            GPU.transport.lock_irqsafe(|t| *t = None);
+            transport.fail();
+        }
+    }
+}
+
+/// Allocates the framebuffer, shows it and starts the framebuffer console on it
+///
+/// Must run after `frame::init`.
+pub fn init() {
+    let (width, height) = GPU.mode.lock_irqsafe(|mode| *mode);
+    if width == 0 {
+        return;
+    }
+    let (width, height) = (width as usize, height as usize);
+    let stride = width * 4;
+    let frames = (stride * height).div_ceil(PAGE_SIZE);
+    let addr = match frame::alloc_zeroed_frames(frames) {
+        Ok(addr) => addr,
+        Err(e) => {
+            println!("virtio-gpu: no memory for the framebuffer: {:?}", e);
+            return;
+        }
+    };
+    let fb = Framebuffer {
+        addr,
+        width,
+        height,
+        stride,
+    };
+    // Behind an IOMMU, the device reads the framebuffer for as long as it is on the scanout
+    let domain = GPU.transport.lock_irqsafe(|t| t.and_then(|t| t.domain()));
+    if let Some(domain) = domain
+        && let Err(e) = domain.map(addr, addr, frames * PAGE_SIZE, MapFlags::READ)
+    {
+        pr_err!("virtio-gpu: cannot map the framebuffer: {:?}", e);
+        let _ = frame::free_frames(addr, frames);
+        return;
+    }
+    GPU.fb.lock_irqsafe(|f| *f = fb);
+    if let Err(e) = GPU.set_framebuffer(&fb) {
+        pr_err!("virtio-gpu: cannot set up the scanout: {:?}", e);
+        if let Some(domain) = domain {
+            let _ = domain.unmap(addr, frames * PAGE_SIZE);
+        }
+        let _ = frame::free_frames(addr, frames);
+        return;
+    }
+    GPU.ready.store(true, Ordering::Relaxed);
+    fbcon::register(&GPU);
+}
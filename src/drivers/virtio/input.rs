@@ -0,0 +1,202 @@
+//! virtio-input driver
+//!
+//! A virtio input device (QEMU's `virtio-keyboard-device`, `virtio-mouse-device`,
+//! `virtio-tablet-device`) speaks the evdev protocol: it fills the buffers of its event queue
+//! with 8-byte events (type, code, value) as Linux's input layer would report them. The driver
+//! reads the device's name and event types from the configuration space, registers it with
+//! `kernel::input` as `virtio-input0`, `virtio-input1`, ... in probe order, and keeps the event
+//! queue full of buffers. The interrupt handler acknowledges the device and defers the rest: the
+//! work item reports the events of the filled buffers and gives the buffers back.
+//!
+//! The configuration space is a window: the driver writes a selector and sub-selector, then
+//! reads the size and contents of the answer.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::drivers::gic;
+use crate::ipc::init_cell::InitCell;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::input::{self, EventType, InputHandle};
+use crate::kernel::irq::{self, softirq};
+use crate::{pr_err, println};
+
+use super::queue::{BufferRing, QUEUE_SIZE};
+use super::{Transport, VirtioError};
+
+/// Maximum number of input devices the driver can manage
+const MAX_INPUTS: usize = 4;
+
+/* --- Configuration Space --- */
+const CONFIG_SELECT: usize = 0;
+const CONFIG_SUBSEL: usize = 1;
+const CONFIG_SIZE: usize = 2;
+const CONFIG_DATA: usize = 8;
+/// Selectors: the device's name, and the codes it reports for the event type in `subsel`
+const CFG_ID_NAME: u8 = 0x01;
+const CFG_EV_BITS: u8 = 0x11;
+
+/// Size of an event: `le16` type, `le16` code, `le32` value
+const EVENT_SIZE: usize = 8;
+
+/// Length of the names kept for the boot messages
+const NAME_LEN: usize = 32;
+
+const EVENT_QUEUE: u32 = 0;
+
+/// A virtqueue with one event per descriptor
+type Ring = BufferRing<EVENT_SIZE>;
+
+/// A virtio input device
+struct VirtioInput {
+    name: &'static str,
+    /// Slot in `INPUTS`
+    index: usize,
+    transport: Mutex<Option<Transport>>,
+    events: Mutex<Ring>,
+    input: InitCell<InputHandle>,
+}
+
+impl VirtioInput {
+    const fn new(name: &'static str, index: usize) -> Self {
+        Self {
+            name,
+            index,
+            transport: Mutex::new(None),
+            events: Mutex::new(Ring::new()),
+            input: InitCell::new(),
+        }
+    }
+
+    fn transport(&self) -> Option<Transport> {
+        self.transport.lock_irqsafe(|transport| *transport)
+    }
+
+    /// Reports the events the device queued and gives their buffers back
+    fn poll_events(&self) {
+        let (Some(transport), Some(input)) = (self.transport(), self.input.get()) else {
+            return;
+        };
+        while let Some((event, len)) = self.events.lock_irqsafe(|events| {
+            let (index, len) = events.pop()?;
+            let event = events.buffers[index];
+            events.post(index, EVENT_SIZE, true);
+            Some((event, len))
+        }) {
+            transport.notify(EVENT_QUEUE);
+            if len < EVENT_SIZE {
+                continue;
+            }
+            let kind = u16::from_le_bytes([event[0], event[1]]);
+            let code = u16::from_le_bytes([event[2], event[3]]);
+            let value = i32::from_le_bytes([event[4], event[5], event[6], event[7]]);
+            // Other types (EV_MSC scan codes, EV_LED, ...) have no consumer
+            if let Some(kind) = EventType::from_raw(kind) {
+                input.report(kind, code, value);
+            }
+        }
+    }
+}
+
+/// The devices, in probe order; entries `[0, INPUT_COUNT)` are initialized
+static INPUTS: [VirtioInput; MAX_INPUTS] = [
+    VirtioInput::new("virtio-input0", 0),
+    VirtioInput::new("virtio-input1", 1),
+    VirtioInput::new("virtio-input2", 2),
+    VirtioInput::new("virtio-input3", 3),
+];
+
+/// Number of devices probed so far
+static INPUT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Selects `select`/`subsel` in the configuration space, returning the size of the answer
+fn config_select(transport: &Transport, select: u8, subsel: u8) -> usize {
+    transport.write_config_u8(CONFIG_SELECT, select);
+    transport.write_config_u8(CONFIG_SUBSEL, subsel);
+    transport.config_u8(CONFIG_SIZE) as usize
+}
+
+/// Returns the mask of the event types the device reports
+fn event_types(transport: &Transport) -> u32 {
+    [EventType::Key, EventType::Rel, EventType::Abs]
+        .into_iter()
+        .filter(|&kind| config_select(transport, CFG_EV_BITS, kind as u8) != 0)
+        .fold(EventType::Sync.bit(), |types, kind| types | kind.bit())
+}
+
+/// Reads the device's name into `buf`, returning its length
+fn read_name(transport: &Transport, buf: &mut [u8; NAME_LEN]) -> usize {
+    let len = config_select(transport, CFG_ID_NAME, 0).min(NAME_LEN);
+    for (i, byte) in buf[..len].iter_mut().enumerate() {
+        *byte = transport.config_u8(CONFIG_DATA + i);
+    }
+    len
+}
+
+/// Deferred part of the interrupt
+fn event_work(index: usize) {
+    INPUTS[index].poll_events();
+}
+
+/// Interrupt handler: acknowledges the device and defers the event processing
+fn handle_irq(_id: u32, data: usize) {
+    let dev = &INPUTS[data];
+    if let Some(transport) = dev.transport() {
+        transport.ack_interrupt();
+    }
+    if softirq::schedule_work(event_work, dev.index).is_err() {
+        println!("virtio-input: {}: event work dropped", dev.name);
+    }
+}
+
+/// Initializes the input device behind `transport` and registers it
+pub fn probe(transport: Transport, irq_id: u32) {
+    let index = INPUT_COUNT.load(Ordering::Acquire);
+    if index == MAX_INPUTS {
+        println!("virtio-input: no free instance");
+        return;
+    }
+    let dev = &INPUTS[index];
+
+    if let Err(e) = transport.begin_init(0) {
+        pr_err!("virtio-input: cannot initialize {}: {:?}", dev.name, e);
+        return;
+    }
+    let setup: Result<(), VirtioError> = dev.events.lock_irqsafe(|events| {
+        events.init();
+        transport.setup_queue(EVENT_QUEUE, &mut events.queue)?;
+        for i in 0..QUEUE_SIZE {
+            events.post(i, EVENT_SIZE, true);
+        }
+        Ok(())
+    });
+    if let Err(e) = setup {
+        println!(
+            "virtio-input: cannot set up the event queue of {}: {:?}",
+            dev.name, e
+        );
+        transport.fail();
+        return;
+    }
+
+    let mut name = [0; NAME_LEN];
+    let name_len = read_name(&transport, &mut name);
+    let name = core::str::from_utf8(&name[..name_len]).unwrap_or("?");
+    let handle = match input::register(dev.name, event_types(&transport)) {
+        Ok(handle) => handle,
+        Err(e) => {
+            pr_err!("virtio-input: cannot register {}: {:?}", dev.name, e);
+            transport.fail();
+            return;
+        }
+    };
+    let _ = dev.input.set(handle);
+    dev.transport.lock_irqsafe(|t| *t = Some(transport));
+    transport.finish_init();
+    transport.notify(EVENT_QUEUE);
+    INPUT_COUNT.store(index + 1, Ordering::Release);
+
+    if irq_id != 0 && irq::request_irq(irq_id, dev.name, handle_irq, index).is_ok() {
+        gic::enable_irq(irq_id);
+    }
+    println!("virtio-input: {} is {}", dev.name, name);
+}
@@ -0,0 +1,227 @@
+//! virtio-mmio transport
+//!
+//! Register access and device initialization for both register layouts QEMU offers: the legacy
+//! one (version 1, QEMU's default) where the device finds a queue from a page frame number, and
+//! the modern one (version 2) with a 64-bit address per ring. The queue memory layout used here
+//! satisfies both.
+//!
+//! A device behind an IOMMU carries the domain its DMA translates through: the queues set up on
+//! the transport map their rings and buffers in it.
+
+use crate::drivers::iommu::IommuDomain;
+use crate::kernel::notifier::Deadline;
+use crate::utilities::mmio;
+
+use super::queue::VirtQueue;
+use super::{VIRTIO_F_VERSION_1, VirtioError};
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c;
+const QUEUE_PFN: usize = 0x040;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+/// Start of the device-specific configuration space
+const CONFIG: usize = 0x100;
+
+/// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+
+/// Device status bits
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+/// Page size announced to legacy devices, the unit of `QUEUE_PFN`
+const LEGACY_PAGE_SIZE: u32 = 4096;
+
+/// The registers of a virtio-mmio device
+#[derive(Clone, Copy, Debug)]
+pub struct MmioTransport {
+    base: usize,
+    version: u32,
+    domain: Option<IommuDomain>,
+}
+
+impl MmioTransport {
+    /// Checks for a virtio device at `base`
+    ///
+    /// Returns `None` if there is none, or if the slot is empty (device ID 0).
+    pub fn probe(base: usize) -> Option<Self> {
+        if mmio::read_mmio32(base, MAGIC_VALUE) != MAGIC {
+            return None;
+        }
+        let version = mmio::read_mmio32(base, VERSION);
+        let transport = Self {
+            base,
+            version,
+            domain: None,
+        };
+        if !(1..=2).contains(&version) || transport.device_id() == 0 {
+            return None;
+        }
+        Some(transport)
+    }
+
+    /// Makes the queues set up afterwards map their memory in `domain`
+    pub fn set_domain(&mut self, domain: IommuDomain) {
+        self.domain = Some(domain);
+    }
+
+    /// Returns the IOMMU domain of the device, if it is behind an IOMMU
+    pub fn domain(&self) -> Option<IommuDomain> {
+        self.domain
+    }
+
+    pub fn device_id(&self) -> u32 {
+        mmio::read_mmio32(self.base, DEVICE_ID)
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    fn set_status(&self, bits: u32) {
+        let status = mmio::read_mmio32(self.base, STATUS);
+        mmio::write_mmio32(self.base, STATUS, status | bits);
+    }
+
+    /// Resets the device and negotiates features, returning the ones both sides support
+    ///
+    /// `supported` are the features the driver can use; `VIRTIO_F_VERSION_1` is added for
+    /// modern devices. The queues must be set up next, then `finish_init` called.
+    pub fn begin_init(&self, supported: u64) -> Result<u64, VirtioError> {
+        mmio::write_mmio32(self.base, STATUS, 0);
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_DRIVER);
+
+        let mut offered = 0u64;
+        for half in 0..2 {
+            mmio::write_mmio32(self.base, DEVICE_FEATURES_SEL, half);
+            offered |= (mmio::read_mmio32(self.base, DEVICE_FEATURES) as u64) << (32 * half);
+        }
+        let mut supported = supported;
+        if !self.is_legacy() {
+            supported |= VIRTIO_F_VERSION_1;
+        }
+        let features = offered & supported;
+        if !self.is_legacy() && features & VIRTIO_F_VERSION_1 == 0 {
+            self.fail();
+            return Err(VirtioError::Unsupported);
+        }
+        for half in 0..2 {
+            mmio::write_mmio32(self.base, DRIVER_FEATURES_SEL, half);
+            mmio::write_mmio32(self.base, DRIVER_FEATURES, (features >> (32 * half)) as u32);
+        }
+
+        if self.is_legacy() {
+            mmio::write_mmio32(self.base, GUEST_PAGE_SIZE, LEGACY_PAGE_SIZE);
+        } else {
+            self.set_status(STATUS_FEATURES_OK);
+            if mmio::read_mmio32(self.base, STATUS) & STATUS_FEATURES_OK == 0 {
+                self.fail();
+                return Err(VirtioError::Unsupported);
+            }
+        }
+        Ok(features)
+    }
+
+    /// Gives queue `index` to the device
+    pub fn setup_queue(&self, index: u32, queue: &mut VirtQueue) -> Result<(), VirtioError> {
+        if let Some(domain) = self.domain {
+            queue.set_domain(domain).map_err(VirtioError::Iommu)?;
+        }
+        mmio::write_mmio32(self.base, QUEUE_SEL, index);
+        let max = mmio::read_mmio32(self.base, QUEUE_NUM_MAX);
+        if (max as usize) < queue.size() {
+            return Err(VirtioError::QueueTooSmall);
+        }
+        mmio::write_mmio32(self.base, QUEUE_NUM, queue.size() as u32);
+        if self.is_legacy() {
+            mmio::write_mmio32(self.base, QUEUE_ALIGN, LEGACY_PAGE_SIZE);
+            let pfn = queue.desc_addr() / LEGACY_PAGE_SIZE as usize;
+            mmio::write_mmio32(self.base, QUEUE_PFN, pfn as u32);
+        } else {
+            let regs = [
+                (QUEUE_DESC_LOW, QUEUE_DESC_HIGH, queue.desc_addr()),
+                (QUEUE_DRIVER_LOW, QUEUE_DRIVER_HIGH, queue.avail_addr()),
+                (QUEUE_DEVICE_LOW, QUEUE_DEVICE_HIGH, queue.used_addr()),
+            ];
+            for (low, high, addr) in regs {
+                mmio::write_mmio32(self.base, low, addr as u32);
+                mmio::write_mmio32(self.base, high, (addr >> 32) as u32);
+            }
+            mmio::write_mmio32(self.base, QUEUE_READY, 1);
+        }
+        Ok(())
+    }
+
+    /// Tells the device the driver is ready
+    pub fn finish_init(&self) {
+        self.set_status(STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver gave up on it
+    pub fn fail(&self) {
+        self.set_status(STATUS_FAILED);
+    }
+
+    /// Resets the device, returning false if it is still resetting when `deadline` expires
+    pub fn reset(&self, deadline: &Deadline) -> bool {
+        mmio::write_mmio32(self.base, STATUS, 0);
+        while mmio::read_mmio32(self.base, STATUS) != 0 {
+            if deadline.expired() {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        true
+    }
+
+    /// Tells the device that queue `index` has new buffers
+    pub fn notify(&self, index: u32) {
+        mmio::write_mmio32(self.base, QUEUE_NOTIFY, index);
+    }
+
+    /// Acknowledges the pending interrupts, returning their causes
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = mmio::read_mmio32(self.base, INTERRUPT_STATUS);
+        mmio::write_mmio32(self.base, INTERRUPT_ACK, status);
+        status
+    }
+
+    /// Reads a 32-bit field of the device configuration space
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        mmio::read_mmio32(self.base, CONFIG + offset)
+    }
+
+    /// Reads a byte of the device configuration space
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        unsafe { core::ptr::read_volatile((self.base + CONFIG + offset) as *const u8) }
+    }
+
+    /// Writes a byte of the device configuration space
+    pub fn write_config_u8(&self, offset: usize, value: u8) {
+        unsafe { core::ptr::write_volatile((self.base + CONFIG + offset) as *mut u8, value) }
+    }
+}
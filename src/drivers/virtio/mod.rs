@@ -0,0 +1,166 @@
+//! Virtio devices
+//!
+//! QEMU's `virt` machine describes 32 `virtio,mmio` slots in the DTB whether or not a device is
+//! plugged in. `setup` runs for each of them, reads the device ID and hands the populated slots
+//! to the driver for their device type; empty slots and types without a driver are skipped.
+//! Devices can also sit on the PCIe bus (`virtio-*-pci`), where `pci::VirtioPciDriver` binds
+//! them and hands them to the same drivers.
+//!
+//! ## Design
+//!
+//! - `Transport` is the device's transport, `mmio::MmioTransport` or `pci::PciTransport`, both
+//!   wrapping the registers: status handshake, feature negotiation, queue setup, notifications
+//!   and interrupt acknowledgement. Drivers only see `Transport`.
+//! - `queue::VirtQueue` is a split virtqueue living in static memory; buffers are given to the
+//!   device by their identity-mapped address. `blk` and `net` negotiate the ring features
+//!   (indirect descriptors, event index), whose indirect tables are the only allocation.
+//! - Device drivers (`blk`, `console`, `gpu`, `input`, `net`, `rng`) own their queues and expose the device to the rest of the kernel.
+//! - Slots behind an IOMMU (with an `iommus` property) are probed by `init` instead, once the
+//!   IOMMU is up: each device gets a domain of its own, which its queues map their memory in.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux splits this the same way (`virtio_mmio` and `virtio_pci` transports, `virtio_ring`,
+//! `virtio_blk`) but binds drivers through the virtio bus, with `virtio_config_ops` as the
+//! transport interface; here `start_driver` dispatches on the device ID directly.
+
+pub mod blk;
+pub mod console;
+pub mod gpu;
+pub mod input;
+pub mod mmio;
+pub mod net;
+pub mod pci;
+pub mod queue;
+pub mod rng;
+pub mod transport;
+
+use crate::drivers::gic;
+use crate::drivers::iommu::{self, IommuDomain, IommuError};
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::dtb;
+use crate::kernel::irq::of::{IrqKind, of_irq_parse};
+use crate::{initcall, pr_err, println};
+
+use mmio::MmioTransport;
+pub use transport::Transport;
+
+/// Feature bit of modern devices, which must be accepted
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Device IDs
+const VIRTIO_ID_NET: u32 = 1;
+const VIRTIO_ID_BLOCK: u32 = 2;
+const VIRTIO_ID_CONSOLE: u32 = 3;
+const VIRTIO_ID_ENTROPY: u32 = 4;
+const VIRTIO_ID_GPU: u32 = 16;
+const VIRTIO_ID_INPUT: u32 = 18;
+
+/// Errors returned while initializing a device
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VirtioError {
+    /// The device doesn't offer a feature the driver requires
+    Unsupported,
+    /// The device's queue is smaller than `queue::QUEUE_SIZE`
+    QueueTooSmall,
+    /// The queue memory can't be mapped in the device's IOMMU domain
+    Iommu(IommuError),
+    /// The device didn't complete its reset
+    ResetTimeout,
+    /// No memory left for the queue's indirect tables
+    NoMemory,
+}
+
+/// Returns the INTID of the SPI described by the first interrupt of `dev`, configured in the GIC
+/// but not enabled, or 0 if there is none
+fn parse_irq(dev: &device::PlatformDevice) -> u32 {
+    of_irq_parse(dev, 0)
+        .filter(|spec| spec.kind == IrqKind::Spi)
+        .map_or(0, |spec| gic::configure_irq(&spec))
+}
+
+/// Returns the transport of the device in the `virtio,mmio` slot `dev`, if the slot is populated
+fn slot_transport(dev: &device::PlatformDevice) -> Option<MmioTransport> {
+    let region = dev.reg(0).filter(|region| region.base != 0)?;
+    MmioTransport::probe(region.base)
+}
+
+/// Starts the driver for the device behind `transport`
+///
+/// `irq_id` is the device's interrupt, configured but not enabled, or 0 if it has none; `node`
+/// its DTB node, if it has one.
+fn start_driver(transport: Transport, irq_id: u32, node: Option<&device::PlatformDevice>) {
+    match transport.device_id() {
+        VIRTIO_ID_NET => net::probe(transport, irq_id),
+        VIRTIO_ID_BLOCK => blk::probe(transport, irq_id),
+        VIRTIO_ID_CONSOLE => console::probe(transport, irq_id, node),
+        VIRTIO_ID_ENTROPY => rng::probe(transport),
+        VIRTIO_ID_GPU => gpu::probe(transport),
+        VIRTIO_ID_INPUT => input::probe(transport, irq_id),
+        _ => {}
+    }
+}
+
+/// Starts the driver for the device in the `virtio,mmio` slot `dev`
+fn start_mmio_driver(dev: &device::PlatformDevice, transport: MmioTransport) {
+    start_driver(Transport::Mmio(transport), parse_irq(dev), Some(dev));
+}
+
+/// Driver for `virtio,mmio` nodes
+pub struct VirtioMmioDriver;
+
+impl device::Driver for VirtioMmioDriver {
+    /// Probes a `virtio,mmio` slot and starts the driver for the device found there
+    ///
+    /// Slots behind an IOMMU are left to `init`.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        if iommu::stream_id(dev).is_some() {
+            return Ok(());
+        }
+        let transport = slot_transport(dev).ok_or(ProbeError::NoDevice)?;
+        start_mmio_driver(dev, transport);
+        Ok(())
+    }
+}
+
+/// Probes a slot behind an IOMMU, attaching the device to a domain of its own first
+///
+/// The domain stays attached if the driver gives up on the device: nothing else uses the stream.
+fn probe_behind_iommu(dev: &device::PlatformDevice, sid: u32) {
+    let Some(mut transport) = slot_transport(dev) else {
+        return;
+    };
+    // Without a translating IOMMU, the device's DMA reaches memory directly
+    if iommu::is_present() {
+        let domain = match IommuDomain::new() {
+            Ok(domain) => domain,
+            Err(e) => {
+                println!("virtio: no IOMMU domain for {}: {:?}", dev.name, e);
+                return;
+            }
+        };
+        if let Err(e) = domain.attach(sid) {
+            pr_err!("virtio: cannot attach {} to its domain: {:?}", dev.name, e);
+            let _ = domain.free();
+            return;
+        }
+        transport.set_domain(domain);
+    }
+    start_mmio_driver(dev, transport);
+}
+
+/// Second stage of the drivers, once the IOMMU is up
+///
+/// Probes the slots behind an IOMMU, then finishes the drivers' initialization. Must run after
+/// `frame::init` and `iommu::init`.
+pub fn init() {
+    for dev in dtb::devices() {
+        if dtb::matched_driver(dev) == Some("virtio,mmio")
+            && let Some(sid) = iommu::stream_id(dev)
+        {
+            probe_behind_iommu(dev, sid);
+        }
+    }
+    gpu::init();
+}
+initcall!(Driver, "virtio", init, after = ["iommu"]);
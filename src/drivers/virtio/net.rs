@@ -0,0 +1,323 @@
+//! virtio-net driver
+//!
+//! Every virtio network device becomes a `NetDevice` named `eth0`, `eth1`, ... in probe order.
+//! Both queues carry packet buffers from the pool of `net::pktbuf`. The receive queue is kept
+//! full of fresh ones. The interrupt handler only acknowledges the device and defers the rest:
+//! the work item collects the filled buffers, hands each one to `net::receive` as it is, and
+//! gives the device new buffers in their place. A ring left short by an exhausted pool is
+//! topped up again by the next receive or transmit. A frame to send is queued in its own buffer,
+//! released on a later transmit once the device is done with it. With
+//! `VIRTIO_F_EVENT_IDX`, a burst of received frames raises a single interrupt, sent frames none,
+//! and the device is only notified when it asks to be.
+//!
+//! Each buffer starts with the virtio-net header: 10 bytes for legacy devices, 12 for modern
+//! ones. No offload is negotiated, so the header is all zeroes on transmit, pushed in the
+//! frame's headroom, and pulled off and ignored on receive.
+//!
+//! Before a reboot or power-off, a reboot notifier resets the devices: they stop receiving
+//! frames into memory the next kernel or the firmware may be using, and nothing is sent anymore.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::drivers::gic;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::irq::{self, softirq};
+use crate::kernel::net::pktbuf::{self, PktBuf};
+use crate::kernel::net::{self, FRAME_MAX, MacAddr, NetDevice, NetError};
+use crate::kernel::notifier::{Deadline, NotifierBlock, NotifyResult};
+use crate::kernel::power::{self, RebootEvent};
+use crate::{pr_err, println};
+
+use super::queue::{Buffer, QUEUE_SIZE, VIRTIO_F_EVENT_IDX, VirtQueue};
+use super::{Transport, VIRTIO_F_VERSION_1};
+
+/// Maximum number of network devices the driver can manage
+const MAX_NICS: usize = 2;
+
+/// The device has a MAC address in its configuration space
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+/// Offset of `mac` in the configuration space
+const CONFIG_MAC: usize = 0;
+
+/// Size of the virtio-net header
+const HEADER_LEN_LEGACY: usize = 10;
+const HEADER_LEN_MODERN: usize = 12;
+
+// A fresh buffer holds the largest header followed by a whole frame
+const _: () = assert!(HEADER_LEN_MODERN + FRAME_MAX <= pktbuf::BUF_SIZE - pktbuf::HEADROOM);
+
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+
+/// A virtqueue and the packet buffer given to the device with each head descriptor
+struct Ring {
+    queue: VirtQueue,
+    packets: [Option<PktBuf>; QUEUE_SIZE],
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            queue: VirtQueue::new(),
+            packets: [const { None }; QUEUE_SIZE],
+        }
+    }
+
+    /// Resets the queue, releasing the buffers it held
+    fn init(&mut self) {
+        self.queue.init();
+        self.packets.iter_mut().for_each(|packet| *packet = None);
+    }
+
+    /// Gives `packet` to the device, which reads its bytes or fills its tailroom
+    ///
+    /// The buffer is released if the queue is full.
+    fn post(&mut self, mut packet: PktBuf, device_writes: bool) -> bool {
+        let buffer = if device_writes {
+            match packet.tailroom_mut() {
+                Some(room) => Buffer::writable(room),
+                None => return false,
+            }
+        } else {
+            Buffer::readable(packet.data())
+        };
+        match self.queue.add(&[buffer]) {
+            Some(head) => {
+                self.packets[head as usize] = Some(packet);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes back the next buffer the device is done with, with the bytes it wrote
+    fn pop(&mut self) -> Option<(PktBuf, usize)> {
+        let (head, len) = self.queue.pop_used()?;
+        let packet = self.packets[head as usize].take()?;
+        Some((packet, len as usize))
+    }
+
+    /// Gives the device fresh buffers to receive into until the queue is full or the pool
+    /// exhausted, returning true if the device must be notified
+    fn refill(&mut self) -> bool {
+        while self.packets.iter().any(Option::is_none)
+            && let Some(packet) = PktBuf::alloc()
+            && self.post(packet, true)
+        {}
+        self.queue.kick_prepare()
+    }
+}
+
+/// A virtio network device
+pub struct VirtioNet {
+    name: &'static str,
+    /// Slot in `NICS`
+    index: usize,
+    transport: Mutex<Option<Transport>>,
+    mac: Mutex<MacAddr>,
+    header_len: AtomicUsize,
+    /// Interface number given by `net::register`
+    iface: AtomicUsize,
+    rx: Mutex<Ring>,
+    tx: Mutex<Ring>,
+}
+
+impl VirtioNet {
+    const fn new(name: &'static str, index: usize) -> Self {
+        Self {
+            name,
+            index,
+            transport: Mutex::new(None),
+            mac: Mutex::new(MacAddr([0; 6])),
+            header_len: AtomicUsize::new(HEADER_LEN_LEGACY),
+            iface: AtomicUsize::new(0),
+            rx: Mutex::new(Ring::new()),
+            tx: Mutex::new(Ring::new()),
+        }
+    }
+
+    fn transport(&self) -> Option<Transport> {
+        self.transport.lock_irqsafe(|transport| *transport)
+    }
+
+    /// Passes the received frames to the stack and gives the device new buffers in their place
+    fn poll_rx(&self) {
+        let header_len = self.header_len.load(Ordering::Relaxed);
+        let iface = self.iface.load(Ordering::Relaxed);
+        while let Some((mut frame, len)) = self.rx.lock_irqsafe(|rx| rx.pop()) {
+            self.refill_rx();
+            if len > header_len && frame.put(len).is_some() && frame.pull(header_len).is_some() {
+                net::receive(iface, frame);
+            }
+        }
+        self.refill_rx();
+    }
+
+    /// Tops up the receive queue
+    fn refill_rx(&self) {
+        let Some(transport) = self.transport() else {
+            return;
+        };
+        if self.rx.lock_irqsafe(|rx| rx.refill()) {
+            transport.notify(RX_QUEUE);
+        }
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn mac(&self) -> MacAddr {
+        self.mac.lock_irqsafe(|mac| *mac)
+    }
+
+    fn transmit(&self, mut frame: PktBuf) -> Result<(), NetError> {
+        if frame.len() > FRAME_MAX {
+            return Err(NetError::TooBig);
+        }
+        let transport = self.transport().ok_or(NetError::NoDevice)?;
+        let header_len = self.header_len.load(Ordering::Relaxed);
+        // The header goes in front of the frame, which must be copied if that space isn't ours
+        if frame.is_shared() || frame.headroom() < header_len {
+            frame = PktBuf::from_slice(frame.data()).ok_or(NetError::NoBuffer)?;
+        }
+        frame.push(header_len).ok_or(NetError::NoBuffer)?.fill(0);
+        let kick = self.tx.lock_irqsafe(|tx| {
+            // Release the frames the device has sent
+            while tx.pop().is_some() {}
+            tx.post(frame, false).then(|| tx.queue.kick_prepare())
+        });
+        match kick {
+            Some(true) => transport.notify(TX_QUEUE),
+            Some(false) => {}
+            None => return Err(NetError::Busy),
+        }
+        self.refill_rx();
+        Ok(())
+    }
+}
+
+/// The devices, in probe order; entries `[0, NIC_COUNT)` are initialized
+static NICS: [VirtioNet; MAX_NICS] = [VirtioNet::new("eth0", 0), VirtioNet::new("eth1", 1)];
+
+/// Number of devices probed so far
+static NIC_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Deferred part of the interrupt
+fn rx_work(index: usize) {
+    NICS[index].poll_rx();
+}
+
+/// Interrupt handler: acknowledges the device and defers the receive processing
+fn handle_irq(_id: u32, data: usize) {
+    let nic = &NICS[data];
+    if let Some(transport) = nic.transport() {
+        transport.ack_interrupt();
+    }
+    if softirq::schedule_work(rx_work, nic.index).is_err() {
+        println!("virtio-net: {}: receive work dropped", nic.name);
+    }
+}
+
+/// Reboot notifier: stops the devices
+///
+/// A device is forgotten before it is reset, so no transmission notifies it meanwhile. Nothing is
+/// done after a panic, where the code that panicked may hold a device's lock.
+fn reboot_notify(event: RebootEvent, deadline: &Deadline) -> NotifyResult {
+    if event == RebootEvent::Panic {
+        return NotifyResult::Ok;
+    }
+    let mut result = NotifyResult::Ok;
+    for nic in &NICS[..NIC_COUNT.load(Ordering::Acquire)] {
+        let Some(transport) = nic.transport.lock_irqsafe(|transport| transport.take()) else {
+            continue;
+        };
+        if !transport.reset(deadline) {
+            result = NotifyResult::Timeout;
+        }
+    }
+    result
+}
+
+/// Initializes the network device behind `transport` and registers it
+pub fn probe(transport: Transport, irq_id: u32) {
+    let index = NIC_COUNT.load(Ordering::Acquire);
+    if index == MAX_NICS {
+        println!("virtio-net: no free instance");
+        return;
+    }
+    let nic = &NICS[index];
+
+    let features = match transport.begin_init(VIRTIO_NET_F_MAC | VIRTIO_F_EVENT_IDX) {
+        Ok(features) => features,
+        Err(e) => {
+            pr_err!("virtio-net: cannot initialize {}: {:?}", nic.name, e);
+            return;
+        }
+    };
+    let header_len = if features & VIRTIO_F_VERSION_1 != 0 {
+        HEADER_LEN_MODERN
+    } else {
+        HEADER_LEN_LEGACY
+    };
+    nic.header_len.store(header_len, Ordering::Relaxed);
+
+    let rx_ok = nic.rx.lock_irqsafe(|rx| {
+        rx.init();
+        rx.queue.set_features(features)?;
+        transport.setup_queue(RX_QUEUE, &mut rx.queue)?;
+        rx.refill();
+        Ok(())
+    });
+    let tx_ok = nic.tx.lock_irqsafe(|tx| {
+        tx.init();
+        tx.queue.set_features(features)?;
+        // Sent frames are reclaimed by the next transmission, not from the interrupt
+        tx.queue.disable_interrupts();
+        transport.setup_queue(TX_QUEUE, &mut tx.queue)
+    });
+    if let Err(e) = rx_ok.and(tx_ok) {
+        println!(
+            "virtio-net: cannot set up the queues of {}: {:?}",
+            nic.name, e
+        );
+        transport.fail();
+        return;
+    }
+
+    // Without VIRTIO_NET_F_MAC the driver picks the address: a locally administered one
+    let mac = if features & VIRTIO_NET_F_MAC != 0 {
+        let low = transport.config_u32(CONFIG_MAC).to_le_bytes();
+        let high = transport.config_u32(CONFIG_MAC + 4).to_le_bytes();
+        MacAddr([low[0], low[1], low[2], low[3], high[0], high[1]])
+    } else {
+        MacAddr([0x02, 0, 0, 0, 0, index as u8 + 1])
+    };
+    nic.mac.lock_irqsafe(|m| *m = mac);
+    nic.transport.lock_irqsafe(|t| *t = Some(transport));
+    transport.finish_init();
+    transport.notify(RX_QUEUE);
+    NIC_COUNT.store(index + 1, Ordering::Release);
+    if index == 0 {
+        let _ = power::register_reboot_notifier(NotifierBlock {
+            name: "virtio-net",
+            priority: power::PRIO_NETWORK,
+            notifier_call: reboot_notify,
+        });
+    }
+
+    match net::register(nic) {
+        Ok(iface) => nic.iface.store(iface, Ordering::Relaxed),
+        Err(e) => {
+            pr_err!("virtio-net: cannot register {}: {:?}", nic.name, e);
+            return;
+        }
+    }
+    if irq_id != 0 && irq::request_irq(irq_id, nic.name, handle_irq, index).is_ok() {
+        gic::enable_irq(irq_id);
+    }
+    println!("virtio-net: {} is {}", nic.name, mac);
+}
@@ -0,0 +1,340 @@
+//! virtio-pci transport
+//!
+//! A virtio device on the PCIe bus (QEMU's `virtio-*-pci`) is a function of vendor `0x1af4`
+//! whose registers are spread over its memory BARs. Vendor-specific capabilities in the
+//! configuration space tell where each structure lies (BAR, offset and length):
+//!
+//! - **common:** feature negotiation, device status, and the queues, selected one at a time.
+//! - **notify:** where the driver writes a queue's index to notify it, at an offset per queue.
+//! - **ISR:** the interrupt causes, cleared by reading them.
+//! - **device:** the device-specific configuration space, laid out as with virtio-mmio.
+//!
+//! `PciTransport` offers the same operations as `mmio::MmioTransport`, so `VirtioPciDriver`
+//! hands the device to the same drivers.
+//!
+//! ## Design
+//!
+//! - Only the modern interface (virtio 1.0) is driven: `VIRTIO_F_VERSION_1` is always
+//!   negotiated. Transitional devices (device IDs `0x1000` to `0x103f`) offer it besides the
+//!   legacy I/O BAR, which is left alone.
+//! - The notify offsets of the queues are read once at probe, so notifying a queue doesn't need
+//!   `queue_select`, which isn't safe to use from several CPUs.
+//! - The device signals every queue and configuration change through MSI-X vector 0, a single
+//!   LPI the driver registers its handler on like a wired interrupt. Without an ITS, it falls
+//!   back to its INTx pin, which functions on the same pin share: only the first driver gets it.
+//! - Devices behind the IOMMU are not bound (see `pci`), so no queue is mapped in a domain.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is `virtio_pci_modern.c` with `virtio_pci_modern_dev.c`. Linux also drives legacy
+//! devices through their I/O BAR (`virtio_pci_legacy.c`), and gives each queue its own MSI-X
+//! vector when the function has enough of them.
+
+use core::ops::RangeInclusive;
+
+use crate::drivers::gic;
+use crate::drivers::pci::{CAP_ID_VENDOR, PciDevice, PciDriver, msi};
+use crate::kernel::device::ProbeError;
+use crate::kernel::irq::of::IrqKind;
+use crate::kernel::notifier::Deadline;
+use crate::utilities::mmio;
+
+use super::queue::VirtQueue;
+use super::{Transport, VIRTIO_F_VERSION_1, VirtioError};
+
+/// Vendor ID of virtio functions
+pub const VENDOR_VIRTIO: u16 = 0x1af4;
+
+/// Device IDs of modern devices: `0x1040` plus the virtio device ID
+const DEVICE_ID_MODERN: RangeInclusive<u16> = 0x1040..=0x107f;
+/// Device IDs of transitional devices, whose virtio device ID is the subsystem ID
+const DEVICE_ID_TRANSITIONAL: RangeInclusive<u16> = 0x1000..=0x103f;
+const SUBSYSTEM_ID: usize = 0x2e;
+
+/* --- Capability Constants --- */
+const CAP_CFG_TYPE: usize = 3;
+const CAP_BAR: usize = 4;
+const CAP_OFFSET: usize = 8;
+const CAP_LENGTH: usize = 12;
+/// Of the notify capability only
+const CAP_NOTIFY_MULTIPLIER: usize = 16;
+
+/// Structure types
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+/* --- Common Configuration Constants --- */
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0c;
+const CONFIG_MSIX_VECTOR: usize = 0x10;
+const NUM_QUEUES: usize = 0x12;
+const DEVICE_STATUS: usize = 0x14;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_MSIX_VECTOR: usize = 0x1a;
+const QUEUE_ENABLE: usize = 0x1c;
+const QUEUE_NOTIFY_OFF: usize = 0x1e;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+
+/// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// MSI-X vector of the device's interrupts, and the value for none
+const VECTOR: u16 = 0;
+const NO_VECTOR: u16 = 0xffff;
+
+/// Queues whose notify offset is recorded; the console with `MAX_PORTS` ports uses the most
+const MAX_QUEUES: usize = 16;
+
+/// Time the device has to complete a reset
+const RESET_TIMEOUT_MS: u32 = 100;
+
+fn read8(addr: usize) -> u8 {
+    unsafe { core::ptr::read_volatile(addr as *const u8) }
+}
+
+fn write8(addr: usize, value: u8) {
+    unsafe { core::ptr::write_volatile(addr as *mut u8, value) }
+}
+
+fn read16(addr: usize) -> u16 {
+    unsafe { core::ptr::read_volatile(addr as *const u16) }
+}
+
+fn write16(addr: usize, value: u16) {
+    unsafe { core::ptr::write_volatile(addr as *mut u16, value) }
+}
+
+/// Returns the virtio device ID of `dev`, if it is a virtio function
+fn virtio_id(dev: &PciDevice) -> Option<u32> {
+    if dev.vendor != VENDOR_VIRTIO {
+        None
+    } else if DEVICE_ID_MODERN.contains(&dev.device) {
+        Some((dev.device - DEVICE_ID_MODERN.start()) as u32)
+    } else if DEVICE_ID_TRANSITIONAL.contains(&dev.device) {
+        Some(dev.read_config16(SUBSYSTEM_ID) as u32)
+    } else {
+        None
+    }
+}
+
+/// The structures of a virtio-pci device
+#[derive(Clone, Copy, Debug)]
+pub struct PciTransport {
+    device_id: u32,
+    common: usize,
+    notify: usize,
+    notify_multiplier: u32,
+    isr: usize,
+    /// Device-specific configuration, absent for devices without one
+    device: Option<usize>,
+    /// Notify offset of each queue, in units of `notify_multiplier`
+    notify_offsets: [u16; MAX_QUEUES],
+    /// MSI-X vector the device signals with, `NO_VECTOR` when it uses its INTx pin
+    vector: u16,
+}
+
+impl PciTransport {
+    /// Finds the structures of the virtio function `dev`
+    ///
+    /// Returns `None` if it isn't a virtio function, or lacks a structure of the modern interface.
+    pub fn probe(dev: &PciDevice) -> Option<Self> {
+        let device_id = virtio_id(dev).filter(|&id| id != 0)?;
+        let mut structures = [None; CFG_TYPE_DEVICE as usize + 1];
+        let mut notify_multiplier = 0;
+        for (_, cap) in dev.capabilities().filter(|&(id, _)| id == CAP_ID_VENDOR) {
+            let cfg_type = dev.read_config8(cap + CAP_CFG_TYPE);
+            // The first structure of a type is the preferred one
+            let Some(slot @ None) = structures.get_mut(cfg_type as usize) else {
+                continue;
+            };
+            let Some(bar) = dev.bar(dev.read_config8(cap + CAP_BAR) as usize) else {
+                continue;
+            };
+            let offset = dev.read_config32(cap + CAP_OFFSET) as usize;
+            let length = dev.read_config32(cap + CAP_LENGTH) as usize;
+            if offset.checked_add(length).is_none_or(|end| end > bar.size) {
+                continue;
+            }
+            *slot = Some(bar.base + offset);
+            if cfg_type == CFG_TYPE_NOTIFY {
+                notify_multiplier = dev.read_config32(cap + CAP_NOTIFY_MULTIPLIER);
+            }
+        }
+        let mut transport = Self {
+            device_id,
+            common: structures[CFG_TYPE_COMMON as usize]?,
+            notify: structures[CFG_TYPE_NOTIFY as usize]?,
+            notify_multiplier,
+            isr: structures[CFG_TYPE_ISR as usize]?,
+            device: structures[CFG_TYPE_DEVICE as usize],
+            notify_offsets: [0; MAX_QUEUES],
+            vector: NO_VECTOR,
+        };
+        let queues = (read16(transport.common + NUM_QUEUES) as usize).min(MAX_QUEUES);
+        for index in 0..queues {
+            write16(transport.common + QUEUE_SELECT, index as u16);
+            transport.notify_offsets[index] = read16(transport.common + QUEUE_NOTIFY_OFF);
+        }
+        Some(transport)
+    }
+
+    pub fn device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    fn set_status(&self, bits: u8) {
+        let status = read8(self.common + DEVICE_STATUS);
+        write8(self.common + DEVICE_STATUS, status | bits);
+    }
+
+    /// Resets the device and negotiates features, returning the ones both sides support
+    ///
+    /// `VIRTIO_F_VERSION_1` is added to `supported`, and required. The queues must be set up
+    /// next, then `finish_init` called.
+    pub fn begin_init(&self, supported: u64) -> Result<u64, VirtioError> {
+        if !self.reset(&Deadline::from_ms(RESET_TIMEOUT_MS)) {
+            return Err(VirtioError::ResetTimeout);
+        }
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_DRIVER);
+
+        let mut offered = 0u64;
+        for half in 0..2 {
+            mmio::write_mmio32(self.common, DEVICE_FEATURE_SELECT, half);
+            offered |= (mmio::read_mmio32(self.common, DEVICE_FEATURE) as u64) << (32 * half);
+        }
+        let features = offered & (supported | VIRTIO_F_VERSION_1);
+        if features & VIRTIO_F_VERSION_1 == 0 {
+            self.fail();
+            return Err(VirtioError::Unsupported);
+        }
+        for half in 0..2 {
+            mmio::write_mmio32(self.common, DRIVER_FEATURE_SELECT, half);
+            mmio::write_mmio32(
+                self.common,
+                DRIVER_FEATURE,
+                (features >> (32 * half)) as u32,
+            );
+        }
+        self.set_status(STATUS_FEATURES_OK);
+        if read8(self.common + DEVICE_STATUS) & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err(VirtioError::Unsupported);
+        }
+        write16(self.common + CONFIG_MSIX_VECTOR, self.vector);
+        Ok(features)
+    }
+
+    /// Gives queue `index` to the device
+    pub fn setup_queue(&self, index: u32, queue: &mut VirtQueue) -> Result<(), VirtioError> {
+        write16(self.common + QUEUE_SELECT, index as u16);
+        let max = read16(self.common + QUEUE_SIZE);
+        if (max as usize) < queue.size() {
+            return Err(VirtioError::QueueTooSmall);
+        }
+        write16(self.common + QUEUE_SIZE, queue.size() as u16);
+        let regs = [
+            (QUEUE_DESC, queue.desc_addr()),
+            (QUEUE_DRIVER, queue.avail_addr()),
+            (QUEUE_DEVICE, queue.used_addr()),
+        ];
+        for (reg, addr) in regs {
+            mmio::write_mmio32(self.common, reg, addr as u32);
+            mmio::write_mmio32(self.common, reg + 4, (addr >> 32) as u32);
+        }
+        write16(self.common + QUEUE_MSIX_VECTOR, self.vector);
+        write16(self.common + QUEUE_ENABLE, 1);
+        Ok(())
+    }
+
+    /// Tells the device the driver is ready
+    pub fn finish_init(&self) {
+        self.set_status(STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver gave up on it
+    pub fn fail(&self) {
+        self.set_status(STATUS_FAILED);
+    }
+
+    /// Resets the device, returning false if it is still resetting when `deadline` expires
+    pub fn reset(&self, deadline: &Deadline) -> bool {
+        write8(self.common + DEVICE_STATUS, 0);
+        while read8(self.common + DEVICE_STATUS) != 0 {
+            if deadline.expired() {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        true
+    }
+
+    /// Tells the device that queue `index` has new buffers
+    pub fn notify(&self, index: u32) {
+        let Some(&offset) = self.notify_offsets.get(index as usize) else {
+            return;
+        };
+        let addr = self.notify + offset as usize * self.notify_multiplier as usize;
+        write16(addr, index as u16);
+    }
+
+    /// Acknowledges the pending interrupts, returning their causes
+    pub fn ack_interrupt(&self) -> u32 {
+        read8(self.isr) as u32
+    }
+
+    /// Reads a 32-bit field of the device configuration space
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        self.device
+            .map_or(0, |device| mmio::read_mmio32(device, offset))
+    }
+
+    /// Reads a byte of the device configuration space
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        self.device.map_or(0, |device| read8(device + offset))
+    }
+
+    /// Writes a byte of the device configuration space
+    pub fn write_config_u8(&self, offset: usize, value: u8) {
+        if let Some(device) = self.device {
+            write8(device + offset, value);
+        }
+    }
+}
+
+/// Driver for virtio functions
+pub struct VirtioPciDriver;
+
+impl PciDriver for VirtioPciDriver {
+    /// Finds the structures of the function, sets up its interrupt and starts the driver for
+    /// its device type
+    fn probe(&self, dev: &'static PciDevice) -> Result<(), ProbeError> {
+        let mut transport = PciTransport::probe(dev).ok_or(ProbeError::NoDevice)?;
+        dev.enable_bus_master();
+        // The common configuration only knows MSI-X vectors
+        let msix = matches!(msi::vector_count(dev), (count, true) if count > 0);
+        let irq_id = match msix.then(|| msi::setup_vector(dev, VECTOR as usize)) {
+            Some(Ok(intid)) => {
+                transport.vector = VECTOR;
+                intid
+            }
+            _ => dev
+                .irq
+                .filter(|spec| spec.kind == IrqKind::Spi)
+                .map_or(0, |spec| gic::configure_irq(&spec)),
+        };
+        super::start_driver(Transport::Pci(transport), irq_id, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,539 @@
+//! Split virtqueues
+//!
+//! A queue is three rings shared with the device: the descriptor table, where the driver
+//! describes buffers, the available ring, where it hands descriptor chains over, and the used
+//! ring, where the device gives them back with the number of bytes it wrote. The rings are
+//! embedded in the `VirtQueue`, so a queue must stay at the same address once given to the
+//! device: drivers keep theirs in statics.
+//!
+//! The layout is the legacy one (used ring on the page after the descriptors and available
+//! ring), which modern devices accept as well.
+//!
+//! A queue of a device behind an IOMMU maps its rings in the device's domain when it is set up,
+//! and each buffer from the moment it is added until the device gives it back, always at its
+//! physical address. The device can't reach anything else.
+//!
+//! ## Ring Features
+//!
+//! Drivers offer `RING_FEATURES` besides their own and pass the negotiated features to
+//! `set_features` before the queue is set up:
+//!
+//! - `VIRTIO_F_INDIRECT_DESC`: a chain of several buffers takes a single descriptor of the ring,
+//!   pointing at a table of up to `MAX_INDIRECT` descriptors. Each ring descriptor has its table,
+//!   allocated once as coherent DMA memory, so a full queue holds `QUEUE_SIZE` requests whatever
+//!   their length.
+//! - `VIRTIO_F_EVENT_IDX`: each side tells the other which ring index to signal at. `pop_used`
+//!   asks for an interrupt at the next completion only once it has collected them all, so a
+//!   burst costs one interrupt; `kick_prepare` tells whether the device wants a notification
+//!   for the chains added since the last one. `disable_interrupts` stops asking altogether, for
+//!   queues reclaimed by polling.
+//!
+//! Without `VIRTIO_F_EVENT_IDX`, the ring flags (`VIRTQ_AVAIL_F_NO_INTERRUPT`,
+//! `VIRTQ_USED_F_NO_NOTIFY`) give the same hints, all or nothing.
+
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{Ordering, fence};
+
+use crate::drivers::iommu::{IommuDomain, IommuError};
+use crate::kernel::mm::addr_space::MapFlags;
+use crate::kernel::mm::dma::{self, DmaBuffer};
+
+use super::VirtioError;
+
+/// Number of descriptors of a queue
+pub const QUEUE_SIZE: usize = 16;
+
+/// Longest chain an indirect table holds
+pub const MAX_INDIRECT: usize = 32;
+
+/// Ring feature bits
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+/// The ring features this layer implements
+pub const RING_FEATURES: u64 = VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX;
+
+/// Descriptor flags
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// Ring flags, ignored with `VIRTIO_F_EVENT_IDX`
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
+/// Returns true if the index moving from `old` to `new` passed `event`
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+impl Descriptor {
+    /// A descriptor for `buf`, chained to descriptor `next`
+    fn chained(buf: &Buffer, next: u16) -> Self {
+        let write = if buf.device_writes {
+            VIRTQ_DESC_F_WRITE
+        } else {
+            0
+        };
+        Self {
+            addr: buf.addr as u64,
+            len: buf.len as u32,
+            flags: VIRTQ_DESC_F_NEXT | write,
+            next,
+        }
+    }
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// The used ring, page aligned as legacy devices expect
+#[repr(C, align(4096))]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+/// A buffer of a request
+#[derive(Clone, Copy, Debug)]
+pub struct Buffer {
+    /// Physical address, the same as the kernel address under the identity map
+    pub addr: usize,
+    pub len: usize,
+    /// True if the device writes to the buffer, false if it reads it
+    pub device_writes: bool,
+}
+
+impl Buffer {
+    /// A buffer the device reads
+    pub fn readable(data: &[u8]) -> Self {
+        Self {
+            addr: data.as_ptr() as usize,
+            len: data.len(),
+            device_writes: false,
+        }
+    }
+
+    /// A buffer the device writes
+    pub fn writable(data: &mut [u8]) -> Self {
+        Self {
+            addr: data.as_mut_ptr() as usize,
+            len: data.len(),
+            device_writes: true,
+        }
+    }
+}
+
+/// A split virtqueue and the driver's bookkeeping
+#[repr(C, align(4096))]
+pub struct VirtQueue {
+    desc: [Descriptor; QUEUE_SIZE],
+    avail: AvailRing,
+    used: UsedRing,
+    /// First descriptor of the free list, chained through `next`
+    free_head: u16,
+    num_free: u16,
+    /// Used ring index up to which completions have been collected
+    last_used: u16,
+    /// Available ring index at the last `kick_prepare`
+    kicked: u16,
+    /// Domain the buffers are mapped in, if the device is behind an IOMMU
+    domain: Option<IommuDomain>,
+    /// `QUEUE_SIZE` tables of `MAX_INDIRECT` descriptors, once `VIRTIO_F_INDIRECT_DESC` is
+    /// negotiated
+    indirect: Option<DmaBuffer>,
+    use_indirect: bool,
+    event_idx: bool,
+    /// Whether the driver wants interrupts at all
+    interrupts: bool,
+}
+
+impl VirtQueue {
+    /// Const constructor for static initialization; `init` must run before use
+    pub const fn new() -> Self {
+        Self {
+            desc: [Descriptor {
+                addr: 0,
+                len: 0,
+                flags: 0,
+                next: 0,
+            }; QUEUE_SIZE],
+            avail: AvailRing {
+                flags: 0,
+                idx: 0,
+                ring: [0; QUEUE_SIZE],
+                used_event: 0,
+            },
+            used: UsedRing {
+                flags: 0,
+                idx: 0,
+                ring: [UsedElem { id: 0, len: 0 }; QUEUE_SIZE],
+                avail_event: 0,
+            },
+            free_head: 0,
+            num_free: 0,
+            last_used: 0,
+            kicked: 0,
+            domain: None,
+            indirect: None,
+            use_indirect: false,
+            event_idx: false,
+            interrupts: true,
+        }
+    }
+
+    /// Empties the rings and puts every descriptor on the free list
+    pub fn init(&mut self) {
+        for (i, desc) in self.desc.iter_mut().enumerate() {
+            *desc = Descriptor {
+                addr: 0,
+                len: 0,
+                flags: 0,
+                next: (i + 1) as u16,
+            };
+        }
+        self.avail.flags = 0;
+        self.avail.idx = 0;
+        self.avail.used_event = 0;
+        self.used.flags = 0;
+        self.used.idx = 0;
+        self.used.avail_event = 0;
+        self.free_head = 0;
+        self.num_free = QUEUE_SIZE as u16;
+        self.last_used = 0;
+        self.kicked = 0;
+        self.use_indirect = false;
+        self.event_idx = false;
+        self.interrupts = true;
+    }
+
+    /// Uses the ring features negotiated among `features`
+    ///
+    /// Must be called after `init`, before the queue is given to the device. The indirect tables
+    /// are allocated the first time.
+    pub fn set_features(&mut self, features: u64) -> Result<(), VirtioError> {
+        self.event_idx = features & VIRTIO_F_EVENT_IDX != 0;
+        if features & VIRTIO_F_INDIRECT_DESC != 0 && self.indirect.is_none() {
+            let size = QUEUE_SIZE * MAX_INDIRECT * size_of::<Descriptor>();
+            let tables = dma::alloc_coherent(size).map_err(|_| VirtioError::NoMemory)?;
+            if let Some(domain) = self.domain
+                && let Err(e) = domain.map(tables.paddr, tables.paddr, tables.size, MapFlags::READ)
+            {
+                let _ = dma::free_coherent(tables);
+                return Err(VirtioError::Iommu(e));
+            }
+            self.indirect = Some(tables);
+        }
+        self.use_indirect = features & VIRTIO_F_INDIRECT_DESC != 0;
+        Ok(())
+    }
+
+    /// Returns the longest chain `add` takes
+    pub fn max_chain(&self) -> usize {
+        if self.use_indirect {
+            MAX_INDIRECT
+        } else {
+            QUEUE_SIZE
+        }
+    }
+
+    /// Returns the indirect table of ring descriptor `index`
+    fn indirect_table(&self, index: u16) -> Option<(*mut Descriptor, usize)> {
+        let tables = self.indirect?;
+        let offset = index as usize * MAX_INDIRECT * size_of::<Descriptor>();
+        Some((
+            (tables.vaddr + offset) as *mut Descriptor,
+            tables.paddr + offset,
+        ))
+    }
+
+    /// Maps the rings in `domain`, where the buffers added from now on get mapped as well
+    pub fn set_domain(&mut self, domain: IommuDomain) -> Result<(), IommuError> {
+        if self.domain == Some(domain) {
+            return Ok(());
+        }
+        let addr = self as *const Self as usize;
+        domain.map(
+            addr,
+            addr,
+            size_of::<Self>(),
+            MapFlags::READ.union(MapFlags::WRITE),
+        )?;
+        if let Some(tables) = self.indirect {
+            domain.map(tables.paddr, tables.paddr, tables.size, MapFlags::READ)?;
+        }
+        self.domain = Some(domain);
+        Ok(())
+    }
+
+    pub fn size(&self) -> usize {
+        QUEUE_SIZE
+    }
+
+    pub fn desc_addr(&self) -> usize {
+        addr_of!(self.desc) as usize
+    }
+
+    pub fn avail_addr(&self) -> usize {
+        addr_of!(self.avail) as usize
+    }
+
+    pub fn used_addr(&self) -> usize {
+        addr_of!(self.used) as usize
+    }
+
+    /// Chains `bufs` and makes them available to the device, returning the head descriptor
+    ///
+    /// `bufs` is a gather list: the buffers the device reads come first, then those it writes.
+    /// With indirect descriptors, a chain of several buffers takes a single descriptor of the
+    /// ring. Returns `None` if the chain is longer than `max_chain`, if there are not enough
+    /// free descriptors, or if the buffers can't be mapped in the IOMMU domain. The device must
+    /// be notified afterwards if `kick_prepare` says so.
+    pub fn add(&mut self, bufs: &[Buffer]) -> Option<u16> {
+        let indirect = self.use_indirect && bufs.len() > 1;
+        let needed = if indirect { 1 } else { bufs.len() };
+        if bufs.is_empty() || bufs.len() > self.max_chain() || needed > self.num_free as usize {
+            return None;
+        }
+        if let Some(domain) = self.domain {
+            for (i, buf) in bufs.iter().enumerate() {
+                let flags = if buf.device_writes {
+                    MapFlags::READ.union(MapFlags::WRITE)
+                } else {
+                    MapFlags::READ
+                };
+                if buf.len != 0 && domain.map(buf.addr, buf.addr, buf.len, flags).is_err() {
+                    for buf in &bufs[..i] {
+                        let _ = domain.unmap(buf.addr, buf.len);
+                    }
+                    return None;
+                }
+            }
+        }
+        let head = self.free_head;
+        if let Some((table, table_addr)) = self.indirect_table(head).filter(|_| indirect) {
+            for (i, buf) in bufs.iter().enumerate() {
+                let mut desc = Descriptor::chained(buf, (i + 1) as u16);
+                if i + 1 == bufs.len() {
+                    desc.flags &= !VIRTQ_DESC_F_NEXT;
+                }
+                unsafe { write_volatile(table.add(i), desc) };
+            }
+            let desc = &mut self.desc[head as usize];
+            desc.addr = table_addr as u64;
+            desc.len = (bufs.len() * size_of::<Descriptor>()) as u32;
+            desc.flags = VIRTQ_DESC_F_INDIRECT;
+            self.free_head = desc.next;
+        } else {
+            let mut index = head;
+            for (i, buf) in bufs.iter().enumerate() {
+                let desc = &mut self.desc[index as usize];
+                *desc = Descriptor::chained(buf, desc.next);
+                if i + 1 < bufs.len() {
+                    index = desc.next;
+                } else {
+                    desc.flags &= !VIRTQ_DESC_F_NEXT;
+                    self.free_head = desc.next;
+                }
+            }
+        }
+        self.num_free -= needed as u16;
+
+        let idx = self.avail.idx;
+        self.avail.ring[idx as usize % QUEUE_SIZE] = head;
+        // The device must see the descriptors and the ring entry before the new index
+        fence(Ordering::SeqCst);
+        unsafe { write_volatile(addr_of_mut!(self.avail.idx), idx.wrapping_add(1)) };
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Returns true if the device wants a notification for the chains added since the last call
+    ///
+    /// The notification itself is the transport's, made after the queue's lock is dropped.
+    pub fn kick_prepare(&mut self) -> bool {
+        // The new index must be visible before the device's wishes are read
+        fence(Ordering::SeqCst);
+        let new = self.avail.idx;
+        let old = core::mem::replace(&mut self.kicked, new);
+        if self.event_idx {
+            let event = unsafe { read_volatile(addr_of!(self.used.avail_event)) };
+            need_event(event, new, old)
+        } else {
+            unsafe { read_volatile(addr_of!(self.used.flags)) & VIRTQ_USED_F_NO_NOTIFY == 0 }
+        }
+    }
+
+    /// Asks the device not to interrupt for this queue, whose chains are collected by polling
+    pub fn disable_interrupts(&mut self) {
+        self.interrupts = false;
+        if self.event_idx {
+            // Just passed: the device won't reach it again before the index wraps around
+            let event = self.last_used.wrapping_sub(1);
+            unsafe { write_volatile(addr_of_mut!(self.avail.used_event), event) };
+        } else {
+            unsafe { write_volatile(addr_of_mut!(self.avail.flags), VIRTQ_AVAIL_F_NO_INTERRUPT) };
+        }
+    }
+
+    /// Returns true if the device has given back a chain not collected yet
+    pub fn has_used(&self) -> bool {
+        unsafe { read_volatile(addr_of!(self.used.idx)) != self.last_used }
+    }
+
+    /// Collects the next chain the device gave back, returning its head and the bytes written
+    ///
+    /// The chain's descriptors go back to the free list. With `VIRTIO_F_EVENT_IDX`, the device
+    /// is asked to interrupt again once every chain is collected.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            if !self.event_idx || !self.interrupts {
+                return None;
+            }
+            unsafe { write_volatile(addr_of_mut!(self.avail.used_event), self.last_used) };
+            // A chain given back before the device saw the event index raised no interrupt
+            fence(Ordering::SeqCst);
+            if !self.has_used() {
+                return None;
+            }
+        }
+        // Read the entry only after seeing the index move
+        fence(Ordering::SeqCst);
+        let elem = unsafe {
+            read_volatile(addr_of!(
+                self.used.ring[self.last_used as usize % QUEUE_SIZE]
+            ))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = elem.id as u16;
+        let head_desc = self.desc[head as usize];
+        if head_desc.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+            if let (Some(domain), Some((table, _))) = (self.domain, self.indirect_table(head)) {
+                for i in 0..head_desc.len as usize / size_of::<Descriptor>() {
+                    let desc = unsafe { read_volatile(table.add(i)) };
+                    let _ = domain.unmap(desc.addr as usize, desc.len as usize);
+                }
+            }
+            self.num_free += 1;
+            self.desc[head as usize].next = self.free_head;
+            self.free_head = head;
+            return Some((head, elem.len));
+        }
+        let mut index = head;
+        loop {
+            self.num_free += 1;
+            let desc = self.desc[index as usize];
+            if let Some(domain) = self.domain {
+                let _ = domain.unmap(desc.addr as usize, desc.len as usize);
+            }
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                self.desc[index as usize].next = self.free_head;
+                break;
+            }
+            index = desc.next;
+        }
+        self.free_head = head;
+        Some((head, elem.len))
+    }
+}
+
+impl Default for VirtQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A virtqueue with one `SIZE`-byte buffer per descriptor
+///
+/// For devices exchanging small messages or frames rather than requests pointing at the
+/// caller's memory: each buffer is owned by the driver or by the device, never both.
+pub struct BufferRing<const SIZE: usize> {
+    pub queue: VirtQueue,
+    pub buffers: [[u8; SIZE]; QUEUE_SIZE],
+    /// Buffer given to the device with each head descriptor
+    owner: [usize; QUEUE_SIZE],
+    /// Buffers currently owned by the device
+    busy: [bool; QUEUE_SIZE],
+}
+
+impl<const SIZE: usize> BufferRing<SIZE> {
+    /// Const constructor for static initialization; `init` must run before use
+    pub const fn new() -> Self {
+        Self {
+            queue: VirtQueue::new(),
+            buffers: [[0; SIZE]; QUEUE_SIZE],
+            owner: [0; QUEUE_SIZE],
+            busy: [false; QUEUE_SIZE],
+        }
+    }
+
+    pub fn init(&mut self) {
+        self.queue.init();
+        self.busy = [false; QUEUE_SIZE];
+    }
+
+    /// Gives buffer `index` to the device, which fills it or reads its first `len` bytes
+    pub fn post(&mut self, index: usize, len: usize, device_writes: bool) -> bool {
+        let buffer = Buffer {
+            addr: self.buffers[index].as_ptr() as usize,
+            len,
+            device_writes,
+        };
+        match self.queue.add(&[buffer]) {
+            Some(head) => {
+                self.owner[head as usize] = index;
+                self.busy[index] = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes back the next buffer the device is done with, returning it with the bytes written
+    pub fn pop(&mut self) -> Option<(usize, usize)> {
+        let (head, len) = self.queue.pop_used()?;
+        let index = self.owner[head as usize];
+        self.busy[index] = false;
+        Some((index, len as usize))
+    }
+
+    /// Returns a buffer the driver owns, reclaiming the ones the device is done with first
+    pub fn reclaim(&mut self) -> Option<usize> {
+        while self.pop().is_some() {}
+        self.busy.iter().position(|&busy| !busy)
+    }
+
+    /// Returns true if the device owns no buffer
+    pub fn idle(&self) -> bool {
+        !self.busy.contains(&true)
+    }
+}
+
+impl<const SIZE: usize> Default for BufferRing<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,89 @@
+//! virtio-rng driver
+//!
+//! The device fills the buffers it is given with random bytes. The driver keeps a single buffer
+//! posted and registers the device as an entropy source of `kernel::random`. When the generator
+//! reseeds, `read` collects the buffer if the device has filled it, hands the bytes out and posts
+//! the buffer again, so the device works ahead of the next reseed without producing more than the
+//! kernel consumes. Nothing waits for the device, so no interrupt is needed.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::random::{self, EntropySource};
+use crate::{pr_err, println};
+
+use super::queue::BufferRing;
+use super::{Transport, VirtioError};
+
+/// Size of the buffer given to the device
+const BUFFER_SIZE: usize = 64;
+
+/// The only queue of the device
+const REQUEST_QUEUE: u32 = 0;
+
+/// The virtio-rng device
+struct VirtioRng {
+    transport: Mutex<Option<Transport>>,
+    ring: Mutex<BufferRing<BUFFER_SIZE>>,
+}
+
+impl EntropySource for VirtioRng {
+    fn name(&self) -> &'static str {
+        "virtio-rng"
+    }
+
+    /// Bytes of a filled buffer that don't fit in `buf` are dropped
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let Some(transport) = self.transport.lock_irqsafe(|transport| *transport) else {
+            return 0;
+        };
+        let len = self.ring.lock_irqsafe(|ring| {
+            let (index, len) = ring.pop()?;
+            let len = len.min(BUFFER_SIZE).min(buf.len());
+            buf[..len].copy_from_slice(&ring.buffers[index][..len]);
+            ring.post(index, BUFFER_SIZE, true);
+            Some(len)
+        });
+        match len {
+            Some(len) => {
+                transport.notify(REQUEST_QUEUE);
+                len
+            }
+            None => 0,
+        }
+    }
+}
+
+/// The device; only one virtio-rng is driven
+static RNG: VirtioRng = VirtioRng {
+    transport: Mutex::new(None),
+    ring: Mutex::new(BufferRing::new()),
+};
+
+/// Initializes the entropy device behind `transport` and registers it
+pub fn probe(transport: Transport) {
+    if RNG.transport.lock_irqsafe(|transport| transport.is_some()) {
+        println!("virtio-rng: only one device is supported");
+        return;
+    }
+    if let Err(e) = transport.begin_init(0) {
+        pr_err!("virtio-rng: cannot initialize the device: {:?}", e);
+        return;
+    }
+    let queue_ok: Result<(), VirtioError> = RNG.ring.lock_irqsafe(|ring| {
+        ring.init();
+        transport.setup_queue(REQUEST_QUEUE, &mut ring.queue)?;
+        ring.post(0, BUFFER_SIZE, true);
+        Ok(())
+    });
+    if let Err(e) = queue_ok {
+        pr_err!("virtio-rng: cannot set up the queue: {:?}", e);
+        transport.fail();
+        return;
+    }
+    RNG.transport.lock_irqsafe(|t| *t = Some(transport));
+    transport.finish_init();
+    transport.notify(REQUEST_QUEUE);
+
+    if let Err(e) = random::register_source(&RNG) {
+        pr_err!("virtio-rng: cannot register the device: {:?}", e);
+    }
+}
@@ -0,0 +1,131 @@
+//! Transports
+//!
+//! A virtio device is reached through a transport: `mmio` for the `virtio,mmio` slots of the
+//! DTB, `pci` for virtio functions on the PCIe bus. Both offer the same operations (status
+//! handshake, feature negotiation, queue setup, notifications, interrupt acknowledgement and the
+//! device configuration space), which `Transport` forwards to whichever the device uses, so the
+//! device drivers don't depend on it.
+
+use crate::drivers::iommu::IommuDomain;
+use crate::kernel::notifier::Deadline;
+
+use super::VirtioError;
+use super::mmio::MmioTransport;
+use super::pci::PciTransport;
+use super::queue::VirtQueue;
+
+/// The transport of a virtio device
+#[derive(Clone, Copy, Debug)]
+pub enum Transport {
+    Mmio(MmioTransport),
+    Pci(PciTransport),
+}
+
+impl Transport {
+    /// Returns the IOMMU domain of the device, if it is behind an IOMMU
+    pub fn domain(&self) -> Option<IommuDomain> {
+        match self {
+            Transport::Mmio(t) => t.domain(),
+            Transport::Pci(_) => None,
+        }
+    }
+
+    pub fn device_id(&self) -> u32 {
+        match self {
+            Transport::Mmio(t) => t.device_id(),
+            Transport::Pci(t) => t.device_id(),
+        }
+    }
+
+    /// Resets the device and negotiates features, returning the ones both sides support
+    ///
+    /// `supported` are the features the driver can use; `VIRTIO_F_VERSION_1` is added for
+    /// modern devices. The queues must be set up next, then `finish_init` called.
+    pub fn begin_init(&self, supported: u64) -> Result<u64, VirtioError> {
+        match self {
+            Transport::Mmio(t) => t.begin_init(supported),
+            Transport::Pci(t) => t.begin_init(supported),
+        }
+    }
+
+    /// Gives queue `index` to the device
+    pub fn setup_queue(&self, index: u32, queue: &mut VirtQueue) -> Result<(), VirtioError> {
+        match self {
+            Transport::Mmio(t) => t.setup_queue(index, queue),
+            Transport::Pci(t) => t.setup_queue(index, queue),
+        }
+    }
+
+    /// Tells the device the driver is ready
+    pub fn finish_init(&self) {
+        match self {
+            Transport::Mmio(t) => t.finish_init(),
+            Transport::Pci(t) => t.finish_init(),
+        }
+    }
+
+    /// Tells the device the driver gave up on it
+    pub fn fail(&self) {
+        match self {
+            Transport::Mmio(t) => t.fail(),
+            Transport::Pci(t) => t.fail(),
+        }
+    }
+
+    /// Resets the device, which stops using its queues
+    ///
+    /// Returns false if the device is still resetting when `deadline` expires.
+    pub fn reset(&self, deadline: &Deadline) -> bool {
+        match self {
+            Transport::Mmio(t) => t.reset(deadline),
+            Transport::Pci(t) => t.reset(deadline),
+        }
+    }
+
+    /// Tells the device that queue `index` has new buffers
+    pub fn notify(&self, index: u32) {
+        match self {
+            Transport::Mmio(t) => t.notify(index),
+            Transport::Pci(t) => t.notify(index),
+        }
+    }
+
+    /// Acknowledges the pending interrupts, returning their causes
+    pub fn ack_interrupt(&self) -> u32 {
+        match self {
+            Transport::Mmio(t) => t.ack_interrupt(),
+            Transport::Pci(t) => t.ack_interrupt(),
+        }
+    }
+
+    /// Reads a 32-bit field of the device configuration space
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        match self {
+            Transport::Mmio(t) => t.config_u32(offset),
+            Transport::Pci(t) => t.config_u32(offset),
+        }
+    }
+
+    /// Reads a byte of the device configuration space
+    pub fn config_u8(&self, offset: usize) -> u8 {
+        match self {
+            Transport::Mmio(t) => t.config_u8(offset),
+            Transport::Pci(t) => t.config_u8(offset),
+        }
+    }
+
+    /// Writes a byte of the device configuration space
+    pub fn write_config_u8(&self, offset: usize, value: u8) {
+        match self {
+            Transport::Mmio(t) => t.write_config_u8(offset, value),
+            Transport::Pci(t) => t.write_config_u8(offset, value),
+        }
+    }
+
+    /// Reads a 64-bit field of the device configuration space, as two 32-bit accesses
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        let low = self.config_u32(offset) as u64;
+        let high = self.config_u32(offset + 4) as u64;
+        (high << 32) | low
+    }
+}
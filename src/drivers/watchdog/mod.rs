@@ -0,0 +1,139 @@
+//! Watchdog drivers and the kernel heartbeat
+//!
+//! A hardware watchdog resets the system unless it is petted regularly. Once `init` has started
+//! it, the heartbeat task pets it when the scheduler tick wakes it up, once per `HEARTBEAT_MS`:
+//! as long as timer interrupts are taken and tasks get scheduled, the system counts as healthy.
+//! A CPU stuck with interrupts masked, e.g. spinning on a lock held by the code it interrupted,
+//! or kept by a task that never gives it up, stops the heartbeat and gets reset after
+//! `DEFAULT_TIMEOUT_MS`. The heartbeat is a real-time `Fifo` task of the highest priority (see
+//! `sched`), so no other task delays it.
+//!
+//! The panic handler calls `panic`, which stops the heartbeat and shortens the timeout to
+//! `PANIC_TIMEOUT_MS`: the reboot notifiers get to flush the console, then the watchdog resets
+//! the machine rather than leaving it spinning in the panic loop.
+//!
+//! Only the ARM SP805 is supported.
+
+pub mod sp805;
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::drivers::timer::arch_timer;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::sched::{self, MAX_PRIORITY, Policy, SpawnAttrs};
+use crate::{initcall, pr_err, println};
+
+/// Time without a heartbeat after which the system resets
+const DEFAULT_TIMEOUT_MS: u32 = 30_000;
+
+/// Interval between two heartbeats
+const HEARTBEAT_MS: u64 = 1000;
+
+/// Time between a panic and the reset
+const PANIC_TIMEOUT_MS: u32 = 5000;
+
+/// Errors returned by the watchdog drivers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchdogError {
+    /// No watchdog was found in the DTB
+    NoDevice,
+    /// The timeout can't be represented by the counter
+    InvalidTimeout,
+    /// The device is being accessed by the code the caller interrupted
+    Busy,
+}
+
+/// Set once `init` has started the watchdog
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Set by `panic`; the heartbeat stops
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+/// Counter value at which the next heartbeat is due
+static NEXT_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+
+/// Set by the tick when a heartbeat is due, cleared by the heartbeat task
+static HEARTBEAT_DUE: AtomicBool = AtomicBool::new(false);
+
+/// The heartbeat task, waiting for the next heartbeat
+static HEARTBEAT_WAIT: WaitQueue = WaitQueue::new();
+
+/// Returns the number of counter increments between two heartbeats
+fn heartbeat_period() -> u64 {
+    arch_timer::get_frequency() / 1000 * HEARTBEAT_MS
+}
+
+/// Starts the watchdog found in the DTB, if any, with the default timeout
+///
+/// The heartbeat is driven by the scheduler tick, so this should run just before it starts.
+pub fn init() {
+    if !sp805::is_present() {
+        return;
+    }
+    let attrs = SpawnAttrs::realtime(Policy::Fifo, MAX_PRIORITY);
+    if let Err(e) = sched::spawn_with("watchdog", heartbeat_task, 0, attrs) {
+        pr_err!("watchdog: cannot start the heartbeat: {:?}", e);
+        return;
+    }
+    match sp805::start(DEFAULT_TIMEOUT_MS) {
+        Ok(()) => {
+            NEXT_HEARTBEAT.store(
+                arch_timer::get_counter() + heartbeat_period(),
+                Ordering::Relaxed,
+            );
+            RUNNING.store(true, Ordering::Release);
+            println!("watchdog: started, {} ms timeout", DEFAULT_TIMEOUT_MS);
+        }
+        Err(e) => pr_err!("watchdog: cannot start: {:?}", e),
+    }
+}
+initcall!(Late, "watchdog", init, after = ["sched"]);
+
+/// Wakes up the heartbeat task if a heartbeat is due
+///
+/// Called from the timer interrupt handler on every scheduler tick.
+pub fn tick() {
+    if !RUNNING.load(Ordering::Acquire) {
+        return;
+    }
+    let now = arch_timer::get_counter();
+    if now >= NEXT_HEARTBEAT.load(Ordering::Relaxed) {
+        NEXT_HEARTBEAT.store(now + heartbeat_period(), Ordering::Relaxed);
+        // Still set means the previous heartbeat hasn't run: let the watchdog see it
+        HEARTBEAT_DUE.store(true, Ordering::Release);
+        HEARTBEAT_WAIT.wake_up();
+    }
+}
+
+/// Returns the counter value at which the next heartbeat is due, if the watchdog is running
+///
+/// The timer must fire by then even when the scheduler tick is stopped.
+pub fn next_event() -> Option<u64> {
+    (RUNNING.load(Ordering::Acquire) && !PANICKED.load(Ordering::Acquire))
+        .then(|| NEXT_HEARTBEAT.load(Ordering::Relaxed))
+}
+
+/// Body of the heartbeat task: pets the watchdog whenever a heartbeat is due, unless the
+/// kernel has panicked
+fn heartbeat_task(_arg: usize) {
+    loop {
+        HEARTBEAT_WAIT.wait_event(|| HEARTBEAT_DUE.swap(false, Ordering::AcqRel));
+        if !PANICKED.load(Ordering::Acquire) {
+            let _ = sp805::pet();
+        }
+    }
+}
+
+/// Stops the heartbeat and has the watchdog reset the system after `PANIC_TIMEOUT_MS`
+///
+/// Called by the panic handler. Does nothing if the watchdog isn't running.
+pub fn panic() {
+    if PANICKED.swap(true, Ordering::AcqRel) || !RUNNING.load(Ordering::Acquire) {
+        return;
+    }
+    match sp805::start(PANIC_TIMEOUT_MS) {
+        Ok(()) => println!("watchdog: resetting in {} ms", PANIC_TIMEOUT_MS),
+        // The running timeout still applies
+        Err(e) => pr_err!("watchdog: cannot shorten the timeout: {:?}", e),
+    }
+}
@@ -0,0 +1,181 @@
+//! ARM SP805 watchdog driver
+//!
+//! The SP805 counts down from its load value at the rate of its `wdog_clk`. When the counter
+//! reaches zero it raises its interrupt and reloads; if the interrupt hasn't been cleared by the
+//! time it reaches zero again, it asserts the reset output. Clearing the interrupt reloads the
+//! counter, so petting the watchdog is a write to `WDOGINTCLR`, and the reset comes two load
+//! periods after the last pet: `start` loads half the requested timeout.
+//!
+//! The interrupt isn't routed: nothing is done on the first expiry, only the reset matters. The
+//! registers are write-protected by `WDOGLOCK`, every access unlocks and relocks them.
+//!
+//! The device is described in the DTB by an `arm,sp805` node; the first entry of its `clocks`
+//! property is the watchdog clock. QEMU's `virt` machine doesn't have one.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::clk::{self, ClkError};
+use crate::kernel::device::{self, ProbeError};
+use crate::println;
+
+use super::WatchdogError;
+
+/// Load register, the value the counter starts from
+const WDOGLOAD: usize = 0x000;
+/// Control register
+const WDOGCONTROL: usize = 0x008;
+/// Interrupt clear register; any write clears the interrupt and reloads the counter
+const WDOGINTCLR: usize = 0x00C;
+/// Lock register
+const WDOGLOCK: usize = 0xC00;
+
+/// Enables the counter and the interrupt
+const CONTROL_INTEN: u32 = 1 << 0;
+/// Enables the reset output
+const CONTROL_RESEN: u32 = 1 << 1;
+
+/// Value of `WDOGLOCK` giving write access to the other registers; anything else locks them
+const UNLOCK_KEY: u32 = 0x1ACC_E551;
+
+/// An SP805 found in the DTB
+#[derive(Clone, Copy)]
+struct Sp805 {
+    base: usize,
+    /// Frequency of `wdog_clk`, in Hz
+    clock: u32,
+}
+
+impl Sp805 {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) };
+    }
+
+    /// Writes `value` to the register at `offset`, unlocking the registers around the write
+    fn write_unlocked(&self, offset: usize, value: u32) {
+        self.write(WDOGLOCK, UNLOCK_KEY);
+        self.write(offset, value);
+        self.write(WDOGLOCK, 0);
+    }
+}
+
+/// The device; only one watchdog is driven
+static DEVICE: Mutex<Option<Sp805>> = Mutex::new(None);
+
+/// `WDOGCONTROL` saved by `suspend`
+static SUSPENDED_CONTROL: AtomicU32 = AtomicU32::new(0);
+
+/// Runs `f` on the device
+///
+/// The lock is only ever contended by a panic raised while it is held; the panic handler then
+/// gets `Busy` instead of deadlocking.
+fn with_device<R>(f: impl FnOnce(&Sp805) -> R) -> Result<R, WatchdogError> {
+    DEVICE
+        .try_lock_irqsafe(|device| device.as_ref().map(f))
+        .ok_or(WatchdogError::Busy)?
+        .ok_or(WatchdogError::NoDevice)
+}
+
+/// Returns true if a watchdog was found in the DTB
+pub fn is_present() -> bool {
+    DEVICE.lock_irqsafe(|device| device.is_some())
+}
+
+/// Starts the watchdog, or changes its timeout if it is running
+///
+/// The system resets if `pet` isn't called for `timeout_ms` milliseconds.
+pub fn start(timeout_ms: u32) -> Result<(), WatchdogError> {
+    with_device(|wdt| {
+        // The reset comes on the second expiry
+        let load = wdt.clock as u64 * timeout_ms as u64 / 2000;
+        if load == 0 || load > u32::MAX as u64 {
+            return Err(WatchdogError::InvalidTimeout);
+        }
+        wdt.write(WDOGLOCK, UNLOCK_KEY);
+        wdt.write(WDOGLOAD, load as u32);
+        wdt.write(WDOGINTCLR, 1);
+        wdt.write(WDOGCONTROL, CONTROL_INTEN | CONTROL_RESEN);
+        wdt.write(WDOGLOCK, 0);
+        Ok(())
+    })?
+}
+
+/// Reloads the counter, pushing the reset back by a full timeout
+pub fn pet() -> Result<(), WatchdogError> {
+    with_device(|wdt| wdt.write_unlocked(WDOGINTCLR, 1))
+}
+
+/// Stops the counter
+pub fn stop() -> Result<(), WatchdogError> {
+    with_device(|wdt| wdt.write_unlocked(WDOGCONTROL, 0))
+}
+
+/// Driver for `arm,sp805` nodes
+pub struct Sp805Driver;
+
+impl device::Driver for Sp805Driver {
+    /// Sets up the SP805 from device tree properties
+    ///
+    /// Reads the base address from `reg` and the watchdog clock frequency from the first `clocks`
+    /// entry. The watchdog is left stopped; `watchdog::init` starts it.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        if is_present() {
+            println!(
+                "sp805: only one watchdog is supported, ignoring {}",
+                dev.name
+            );
+            return Err(ProbeError::NoResources);
+        }
+        let base = dev.reg(0).map_or(0, |region| region.base);
+        let clock = match clk::clk_get(dev, 0) {
+            Ok(clk) => clk.get_rate() as u32,
+            Err(ClkError::NotReady) => return Err(ProbeError::Defer),
+            Err(_) => 0,
+        };
+        if base == 0 || clock == 0 {
+            println!("sp805: {} has no address or clock", dev.name);
+            return Err(ProbeError::NoDevice);
+        }
+        let wdt = Sp805 { base, clock };
+        // Stopped until the heartbeat is ready to pet it
+        wdt.write_unlocked(WDOGCONTROL, 0);
+        DEVICE.lock_irqsafe(|device| *device = Some(wdt));
+        println!("sp805: watchdog at {:#x}, {} Hz", base, clock);
+        Ok(())
+    }
+
+    /// Stops the watchdog and forgets the device
+    fn remove(&self, _dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let _ = stop();
+        DEVICE.lock_irqsafe(|device| *device = None);
+        Ok(())
+    }
+
+    /// Stops the counter, which would otherwise reset the system while nothing pets it
+    fn suspend(&self, _dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let control = with_device(|wdt| {
+            let control = wdt.read(WDOGCONTROL);
+            wdt.write_unlocked(WDOGCONTROL, 0);
+            control
+        })
+        .map_err(|_| ProbeError::NoDevice)?;
+        SUSPENDED_CONTROL.store(control, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Restarts the counter from a full timeout if it was running
+    fn resume(&self, _dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let control = SUSPENDED_CONTROL.swap(0, Ordering::Relaxed);
+        with_device(|wdt| {
+            wdt.write(WDOGLOCK, UNLOCK_KEY);
+            wdt.write(WDOGINTCLR, 1);
+            wdt.write(WDOGCONTROL, control);
+            wdt.write(WDOGLOCK, 0);
+        })
+        .map_err(|_| ProbeError::NoDevice)
+    }
+}
@@ -0,0 +1,206 @@
+//! Bounded message-passing channels
+//!
+//! A `Channel<T, N>` carries up to `N` messages from one `Sender` to one `Receiver`. The
+//! messages go through a lock-free SPSC ring (`ipc::spsc`), so `try_send` and `try_recv` never
+//! block and are safe from interrupt context; `send` and `recv` sleep on the channel's wait
+//! queues until there is room or a message.
+//!
+//! ## Handles
+//!
+//! Channels are usually statics, so the handles are claimed at runtime: `sender` and `receiver`
+//! each return a handle only if no other handle of the same kind is alive, which keeps the single
+//! producer / single consumer guarantee the ring relies on. Dropping a handle releases it, e.g.
+//! an interrupt handler can claim the sender on every interrupt.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux has no direct equivalent in the kernel (the closest are `kfifo` with a wait queue, or
+//! pipes); the API follows Rust's `std::sync::mpsc::sync_channel`, minus the multiple producers.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::ipc::spsc::{Consumer, Producer, Ring};
+use crate::ipc::waitqueue::{self, WaitError, WaitQueue};
+
+/// A bounded channel of `N` messages of type `T`
+pub struct Channel<T, const N: usize> {
+    /// Messages sent and not yet received
+    ring: Ring<T, N>,
+    /// Tasks waiting in `recv` for a message
+    recv_wait: WaitQueue,
+    /// Tasks waiting in `send` for room
+    send_wait: WaitQueue,
+    /// Set while a `Sender` exists
+    sender_taken: AtomicBool,
+    /// Set while a `Receiver` exists
+    receiver_taken: AtomicBool,
+}
+
+/// The sending half of a `Channel`
+pub struct Sender<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+    producer: Producer<'a, T, N>,
+}
+
+/// The receiving half of a `Channel`
+pub struct Receiver<'a, T, const N: usize> {
+    channel: &'a Channel<T, N>,
+    consumer: Consumer<'a, T, N>,
+}
+
+impl<T, const N: usize> Channel<T, N> {
+    /// Const constructor for static initialization
+    pub const fn new() -> Self {
+        Self {
+            ring: Ring::new(),
+            recv_wait: WaitQueue::new(),
+            send_wait: WaitQueue::new(),
+            sender_taken: AtomicBool::new(false),
+            receiver_taken: AtomicBool::new(false),
+        }
+    }
+
+    /// Claims the sending half, `None` if it is already claimed
+    pub fn sender(&self) -> Option<Sender<'_, T, N>> {
+        if self.sender_taken.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        // Safety: the flag guarantees this is the only producer
+        let producer = unsafe { self.ring.producer() };
+        Some(Sender {
+            channel: self,
+            producer,
+        })
+    }
+
+    /// Claims the receiving half, `None` if it is already claimed
+    pub fn receiver(&self) -> Option<Receiver<'_, T, N>> {
+        if self.receiver_taken.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        // Safety: the flag guarantees this is the only consumer
+        let consumer = unsafe { self.ring.consumer() };
+        Some(Receiver {
+            channel: self,
+            consumer,
+        })
+    }
+
+    /// Returns true while the receiving half is claimed
+    pub fn has_receiver(&self) -> bool {
+        self.receiver_taken.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of messages waiting to be received
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// Returns true if no message is waiting
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+}
+
+impl<T, const N: usize> Default for Channel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Sender<'_, T, N> {
+    /// Sends `msg` if there is room, otherwise gives it back
+    ///
+    /// Never blocks, safe to call from interrupt context.
+    pub fn try_send(&mut self, msg: T) -> Result<(), T> {
+        self.producer.push(msg)?;
+        self.channel.recv_wait.wake_up();
+        Ok(())
+    }
+
+    /// Sends `msg`, sleeping while the channel is full
+    ///
+    /// Must not be called from interrupt context.
+    pub fn send(&mut self, mut msg: T) {
+        loop {
+            match self.try_send(msg) {
+                Ok(()) => return,
+                Err(back) => msg = back,
+            }
+            let ring = &self.channel.ring;
+            self.channel.send_wait.wait_event(|| ring.len() < N);
+        }
+    }
+}
+
+impl<T, const N: usize> Drop for Sender<'_, T, N> {
+    fn drop(&mut self) {
+        self.channel.sender_taken.store(false, Ordering::Release);
+    }
+}
+
+impl<T, const N: usize> Receiver<'_, T, N> {
+    /// Receives a message if one is waiting
+    ///
+    /// Never blocks, safe to call from interrupt context.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let msg = self.consumer.pop()?;
+        self.channel.send_wait.wake_up();
+        Some(msg)
+    }
+
+    /// Receives a message, sleeping until one arrives
+    ///
+    /// Must not be called from interrupt context.
+    pub fn recv(&mut self) -> T {
+        loop {
+            if let Some(msg) = self.try_recv() {
+                return msg;
+            }
+            let ring = &self.channel.ring;
+            self.channel.recv_wait.wait_event(|| !ring.is_empty());
+        }
+    }
+
+    /// Receives a message, sleeping until one arrives or `interrupted` returns true
+    ///
+    /// Returns `None` if interrupted. `interrupted` is checked whenever the task wakes up, so
+    /// whatever makes it true must also wake the task. Must not be called from interrupt
+    /// context.
+    pub fn recv_interruptible(&mut self, interrupted: impl Fn() -> bool) -> Option<T> {
+        loop {
+            if let Some(msg) = self.try_recv() {
+                return Some(msg);
+            }
+            if interrupted() {
+                return None;
+            }
+            let ring = &self.channel.ring;
+            self.channel
+                .recv_wait
+                .wait_event(|| !ring.is_empty() || interrupted());
+        }
+    }
+
+    /// Receives a message, sleeping until one arrives or `timeout_ms` milliseconds have passed
+    ///
+    /// Must not be called from interrupt context.
+    pub fn recv_timeout(&mut self, timeout_ms: u32) -> Result<T, WaitError> {
+        let expires = waitqueue::deadline_ms(timeout_ms);
+        loop {
+            if let Some(msg) = self.try_recv() {
+                return Ok(msg);
+            }
+            let ring = &self.channel.ring;
+            self.channel
+                .recv_wait
+                .wait_event_until(expires, || !ring.is_empty())?;
+        }
+    }
+}
+
+impl<T, const N: usize> Drop for Receiver<'_, T, N> {
+    fn drop(&mut self) {
+        self.channel.receiver_taken.store(false, Ordering::Release);
+    }
+}
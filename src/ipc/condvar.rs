@@ -0,0 +1,81 @@
+//! Condition variable
+//!
+//! A `CondVar` lets a task sleep until the data protected by a `Mutex` reaches some state. The
+//! waiter passes the mutex and a closure: the closure runs with the lock held and either returns
+//! a result, ending the wait, or `None` to sleep until the next notification. Whoever changes
+//! the data calls `notify_one` or `notify_all` after releasing the lock.
+//!
+//! ## Lost Notifications
+//!
+//! The `Mutex` API is closure based, so the lock can't be released and the task put to sleep in
+//! one step as with POSIX condition variables. Instead every notification bumps a sequence
+//! number: the waiter reads it *before* checking the data and only sleeps while it is unchanged,
+//! so a notification sent between the check and the sleep is never missed.
+//!
+//! The mutex is taken with `lock_irqsafe`, which makes it possible to notify from an interrupt
+//! handler updating the same data.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::waitqueue::WaitQueue;
+
+/// A condition variable used together with a `Mutex`
+pub struct CondVar {
+    /// Incremented by every notification
+    seq: AtomicUsize,
+    /// Tasks sleeping in `wait_until`
+    wait: WaitQueue,
+}
+
+impl CondVar {
+    /// Const constructor for static initialization
+    pub const fn new() -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            wait: WaitQueue::new(),
+        }
+    }
+
+    /// Sleeps until `f`, called with `mutex` held, returns `Some`
+    ///
+    /// `f` is called once up front and again after every notification. Must not be called from
+    /// interrupt context.
+    pub fn wait_until<T, R>(&self, mutex: &Mutex<T>, mut f: impl FnMut(&mut T) -> Option<R>) -> R {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if let Some(result) = mutex.lock_irqsafe(&mut f) {
+                return result;
+            }
+            self.wait
+                .wait_event(|| self.seq.load(Ordering::Acquire) != seq);
+        }
+    }
+
+    /// Sleeps while `cond`, called with `mutex` held, returns true
+    pub fn wait_while<T>(&self, mutex: &Mutex<T>, mut cond: impl FnMut(&mut T) -> bool) {
+        self.wait_until(mutex, |data| (!cond(data)).then_some(()));
+    }
+
+    /// Wakes up one waiting task
+    ///
+    /// Safe to call from interrupt context.
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.wait.wake_up_one();
+    }
+
+    /// Wakes up every waiting task
+    ///
+    /// Safe to call from interrupt context.
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        self.wait.wake_up();
+    }
+}
+
+impl Default for CondVar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
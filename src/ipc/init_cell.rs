@@ -0,0 +1,147 @@
+//! Write-once cells for globals initialized at runtime
+//!
+//! Some globals can't be built by a const initializer: the GIC's base addresses and the UART
+//! instances come from the device tree, a CPU feature from an ID register. Rather than a
+//! `static mut` written during boot and read through raw pointers afterwards, such values live
+//! in an `InitCell`, filled once and then only handed out as shared references.
+//!
+//! - `InitCell<T>`: set explicitly with `set`, which fails if the cell is already filled. Readers
+//!   get `None` until then.
+//! - `IrqSafeLazy<T>`: built by its init function on first access. Concurrent first accesses are
+//!   serialized by an irq-safe `Mutex`, so the function runs exactly once even if an interrupt
+//!   handler races with a task.
+//!
+//! ## Memory Ordering
+//!
+//! `state` goes from `EMPTY` to `WRITING` (claimed by one setter), then to `READY` once the value
+//! is written, with `Release`. Readers load it with `Acquire` before touching the value, so they
+//! always see it fully written. The value is never modified nor moved afterwards.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux initializes such globals in `__init` code and marks them `__ro_after_init`, relying on
+//! boot ordering rather than the type system. These are the `no_std` counterparts of the
+//! standard library's `OnceLock` and `LazyLock`, with spinning in place of thread parking.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::ipc::irq_safe_mutex::Mutex;
+
+/// The cell holds no value
+const EMPTY: u8 = 0;
+/// A setter is writing the value
+const WRITING: u8 = 1;
+/// The value is written and may be read
+const READY: u8 = 2;
+
+/// A cell written once, then shared immutably
+pub struct InitCell<T> {
+    /// One of `EMPTY`, `WRITING` and `READY`
+    state: AtomicU8,
+    /// Initialized once `state` is `READY`
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Safety: the value is only written by the setter that claimed the cell, before it is published
+/// through `state`; it is shared afterwards, hence `T: Sync`, and may be set from any CPU
+unsafe impl<T: Send + Sync> Sync for InitCell<T> {}
+
+impl<T> InitCell<T> {
+    /// Creates an empty cell
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Stores `value` and returns a reference to it
+    ///
+    /// Gives `value` back if the cell was already set, or is being set by someone else.
+    pub fn set(&self, value: T) -> Result<&T, T> {
+        if self
+            .state
+            .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(value);
+        }
+        let value = unsafe { (*self.value.get()).write(value) };
+        self.state.store(READY, Ordering::Release);
+        Ok(value)
+    }
+
+    /// Returns the value, `None` until the cell has been set
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == READY {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns true once the value is readable
+    pub fn is_set(&self) -> bool {
+        self.state.load(Ordering::Acquire) == READY
+    }
+}
+
+impl<T> Default for InitCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for InitCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A value built by `init` the first time it is accessed
+pub struct IrqSafeLazy<T> {
+    cell: InitCell<T>,
+    /// Serializes the first accesses; holds `init` until it has run
+    init: Mutex<Option<fn() -> T>>,
+}
+
+impl<T> IrqSafeLazy<T> {
+    /// Creates a value to be built by `init` on first access
+    pub const fn new(init: fn() -> T) -> Self {
+        Self {
+            cell: InitCell::new(),
+            init: Mutex::new(Some(init)),
+        }
+    }
+
+    /// Returns the value, building it first if this is the first access
+    ///
+    /// `init` runs with the lock held and IRQs masked, so it must not sleep nor access this
+    /// value.
+    pub fn get(&self) -> &T {
+        if let Some(value) = self.cell.get() {
+            return value;
+        }
+        self.init.lock_irqsafe(|init| {
+            if let Some(init) = init.take()
+                && self.cell.set(init()).is_err()
+            {
+                unreachable!("IrqSafeLazy set outside of its lock");
+            }
+        });
+        self.cell.get().expect("IrqSafeLazy not initialized")
+    }
+}
+
+impl<T> Deref for IrqSafeLazy<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.get()
+    }
+}
@@ -4,10 +4,17 @@
 //!
 //! It's most critical feature is the `lock_irqsafe` method, which is essential for preventing
 //! deadlocks between main kernel code and Interrupt Service Routines (ISR).
+//!
+//! The lock can be taken in two styles: with a closure (`lock`, `lock_irqsafe`), holding the lock
+//! for the duration of the call, or with a `MutexGuard` (`lock_guard`, `lock_irqsave_guard`) that
+//! releases it when dropped. Guards make it possible to release the lock early, e.g. before
+//! sleeping on a wait queue; the closure API is a thin wrapper over them.
 
 use core::arch::asm;
 use core::cell::UnsafeCell;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::ops::{Deref, DerefMut};
+use crate::ipc::ticket_lock::TicketLock;
+use crate::kernel::sched;
 
 /// Disable IRQs in the CPU
 #[inline(always)]
@@ -33,11 +40,11 @@ fn restore_interrupts(daif: u64) {
 /// A mutually exclusive (Mutex) primitive based on a spinlock
 ///
 /// This Mutex provides safe interior mutability by ensuring that only one thread can access the
-/// contained data at any given time. It uses an atomic boolean flag and a busy-wait loop to
-/// achieve this.
+/// contained data at any given time. It is backed by a `TicketLock`, so contending CPUs get the
+/// lock in arrival order and wait with `wfe` rather than busy-looping.
 pub struct Mutex<T> {
-    /// The atomic flag used to control access. `false` means unlocked, `true` locked
-    lock: AtomicBool,
+    /// The ticket lock used to control access
+    lock: TicketLock,
     /// The data protected by the mutex, wrapped in an `UnsafeCell` to allow mutable access through
     /// a shared reference
     data: UnsafeCell<T>,
@@ -51,16 +58,36 @@ unsafe impl<T> Sync for Mutex<T> {}
 /// and the lock mechanism is thread-safe
 unsafe impl<T> Send for Mutex<T> {}
 
+/// Exclusive access to the data of a `Mutex`, released when dropped
+///
+/// Returned by `lock_guard` and friends. A guard taken with IRQs masked (`lock_irqsave_guard`)
+/// restores the saved `DAIF` state after releasing the lock.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    /// `DAIF` to restore on drop, `None` if the guard didn't mask IRQs
+    daif: Option<u64>,
+}
+
 impl<T> Mutex<T> {
     /// Creates a new `Mutex` in an unlocked state containing the provided data
     pub const fn new(data: T) -> Self {
         Self {
-            lock: AtomicBool::new(false),
+            lock: TicketLock::new(),
             data: UnsafeCell::new(data),
         }
     }
 
-    /// Acquires the lock and provides mutable access to the protected data
+    /// Takes the lock if it is free; disables preemption on success
+    fn try_acquire(&self) -> bool {
+        sched::preempt_disable();
+        if !self.lock.try_lock() {
+            sched::preempt_enable();
+            return false;
+        }
+        true
+    }
+
+    /// Acquires the lock, returning a guard giving mutable access to the protected data
     ///
     /// # Memory Ordering
     /// - **Acquire**: An `Acquire` memory ordering is used when obtaining the lock. This ensures
@@ -69,24 +96,107 @@ impl<T> Mutex<T> {
     /// - **Release**: A `Release` memory ordering is used when releasing the lock. This ensures
     ///     that all memory operations happening *before* releasing the lock are not reordered to after
     ///     it.
-    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
-        while self
-            .lock
-            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
-        {
-            core::hint::spin_loop();
+    ///
+    /// Preemption is disabled while the lock is held: a task spinning on a lock held by a
+    /// preempted task would otherwise waste its whole time slice. Drop the guard before calling
+    /// anything that may sleep.
+    pub fn lock_guard(&self) -> MutexGuard<'_, T> {
+        sched::preempt_disable();
+        self.lock.lock();
+        MutexGuard {
+            mutex: self,
+            daif: None,
         }
-        let result = f(unsafe { &mut *self.data.get() });
-        self.lock.store(false, Ordering::Release);
-        result
+    }
+
+    /// Acquires the lock with IRQs masked until the guard is dropped
+    pub fn lock_irqsave_guard(&self) -> MutexGuard<'_, T> {
+        let daif_state = disable_irq();
+        let mut guard = self.lock_guard();
+        guard.daif = Some(daif_state);
+        guard
+    }
+
+    /// Attempts to acquire the lock without spinning
+    ///
+    /// Returns `None` if the lock is already held.
+    pub fn try_lock_guard(&self) -> Option<MutexGuard<'_, T>> {
+        self.try_acquire().then_some(MutexGuard {
+            mutex: self,
+            daif: None,
+        })
+    }
+
+    /// Attempts to acquire the lock with IRQs masked, without spinning
+    pub fn try_lock_irqsave_guard(&self) -> Option<MutexGuard<'_, T>> {
+        let daif_state = disable_irq();
+        if !self.try_acquire() {
+            restore_interrupts(daif_state);
+            return None;
+        }
+        Some(MutexGuard {
+            mutex: self,
+            daif: Some(daif_state),
+        })
+    }
+
+    /// Acquires the lock and provides mutable access to the protected data
+    ///
+    /// The lock is released when `f` returns. See `lock_guard`.
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.lock_guard())
     }
 
     /// Acquires the lock in an interrupt-safe manner
     pub fn lock_irqsafe<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
-        let daif_state = disable_irq();
-        let result = self.lock(f);
-        restore_interrupts(daif_state);
-        result
+        f(&mut self.lock_irqsave_guard())
+    }
+
+    /// Releases the lock taken by a guard that won't be dropped, and re-enables preemption
+    ///
+    /// # Safety
+    /// The lock must be held, and its guard forgotten or left on a stack that won't resume
+    /// before the lock is taken again: `sched` holds its lock across a switch to a new task,
+    /// which releases it from its first function.
+    pub unsafe fn force_unlock(&self) {
+        self.lock.unlock();
+        sched::preempt_enable();
+    }
+
+    /// Attempts to acquire the lock without spinning
+    ///
+    /// Returns `None` if the lock is already held. Useful in paths such as the panic handler,
+    /// where the current holder may never release it.
+    pub fn try_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.try_lock_guard().map(|mut guard| f(&mut guard))
+    }
+
+    /// Attempts to acquire the lock in an interrupt-safe manner without spinning
+    pub fn try_lock_irqsafe<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.try_lock_irqsave_guard().map(|mut guard| f(&mut guard))
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.lock.unlock();
+        sched::preempt_enable();
+        if let Some(daif_state) = self.daif {
+            restore_interrupts(daif_state);
+        }
     }
 }
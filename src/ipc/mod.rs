@@ -1,3 +1,14 @@
 //! Inter-process communication and synchronization primitives
 
+pub mod channel;
+pub mod condvar;
+pub mod init_cell;
 pub mod irq_safe_mutex;
+pub mod pi_mutex;
+pub mod rwlock;
+pub mod selftest;
+pub mod semaphore;
+pub mod shm;
+pub mod spsc;
+pub mod ticket_lock;
+pub mod waitqueue;
@@ -0,0 +1,137 @@
+//! Priority inheritance mutex
+//!
+//! A `PiMutex` is a sleeping lock for data shared by tasks of different priorities. A task that
+//! finds it held lends its priority to the holder (`sched::pi_boost`) while it sleeps, so the
+//! holder runs at least at the waiter's priority until it releases the lock: a medium priority
+//! task can't keep a low priority holder off the CPU while a high priority task waits
+//! (priority inversion).
+//!
+//! Unlike the IRQ-safe `Mutex`, it sleeps instead of spinning and leaves preemption and
+//! interrupts enabled, so it can be held across calls that sleep. It can't be taken from
+//! interrupt context.
+//!
+//! ## Design
+//!
+//! The owner is an atomic task ID. Waiters sleep on a wait queue until the owner is cleared;
+//! dropping the guard wakes them all and yields, the scheduler then running the highest priority one
+//! first, which takes the lock. The others lend their priority to the new owner and sleep again.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is `rt_mutex` without the priority-sorted waiter tree and without boosting transitively
+//! along a chain of blocked owners.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::sched::{self, TaskId};
+
+/// Value of `owner` while the mutex is free
+const NO_OWNER: usize = usize::MAX;
+
+/// A sleeping mutex with priority inheritance
+pub struct PiMutex<T> {
+    /// Task holding the lock, `NO_OWNER` if free
+    owner: AtomicUsize,
+    /// Number of tasks sleeping in `lock`
+    waiters: AtomicUsize,
+    /// Tasks sleeping in `lock`
+    wait: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for PiMutex<T> {}
+unsafe impl<T: Send> Send for PiMutex<T> {}
+
+/// Exclusive access to the data of a `PiMutex`, released when dropped
+pub struct PiMutexGuard<'a, T> {
+    mutex: &'a PiMutex<T>,
+}
+
+/// Returns the ID the calling task owns the lock under
+///
+/// Before the scheduler starts, the boot context runs alone and becomes task 0.
+fn me() -> TaskId {
+    sched::current().unwrap_or(0)
+}
+
+impl<T> PiMutex<T> {
+    /// Const constructor for static initialization
+    pub const fn new(data: T) -> Self {
+        Self {
+            owner: AtomicUsize::new(NO_OWNER),
+            waiters: AtomicUsize::new(0),
+            wait: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Takes the lock if it is free, without sleeping
+    pub fn try_lock(&self) -> Option<PiMutexGuard<'_, T>> {
+        self.owner
+            .compare_exchange(NO_OWNER, me(), Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| PiMutexGuard { mutex: self })
+    }
+
+    /// Takes the lock, sleeping until it is free
+    ///
+    /// While it sleeps, the calling task lends its priority to the holder. Must not be called
+    /// from interrupt context or with a spinlock held.
+    pub fn lock(&self) -> PiMutexGuard<'_, T> {
+        let me = me();
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            let owner = self.owner.load(Ordering::Relaxed);
+            if owner == NO_OWNER {
+                continue;
+            }
+            debug_assert!(owner != me, "pi_mutex: recursive lock");
+            // Its class and priority, so a real-time waiter lends the real-time class
+            let rank = sched::effective_rank(me).unwrap_or(0);
+            self.waiters.fetch_add(1, Ordering::Relaxed);
+            sched::pi_boost(owner, rank);
+            self.wait
+                .wait_event(|| self.owner.load(Ordering::Relaxed) != owner);
+            sched::pi_unboost(owner, rank);
+            self.waiters.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the ID of the task holding the lock, if any
+    pub fn owner(&self) -> Option<TaskId> {
+        match self.owner.load(Ordering::Relaxed) {
+            NO_OWNER => None,
+            id => Some(id),
+        }
+    }
+}
+
+impl<T> Deref for PiMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for PiMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for PiMutexGuard<'_, T> {
+    /// Releases the lock and, if tasks are waiting, lets the highest priority one take it
+    fn drop(&mut self) {
+        self.mutex.owner.store(NO_OWNER, Ordering::Release);
+        if self.mutex.waiters.load(Ordering::Relaxed) != 0 {
+            self.mutex.wait.wake_up();
+            sched::yield_now();
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! A reader-writer spinlock
+//!
+//! `RwLock` lets any number of readers access the data at the same time, while a writer gets
+//! exclusive access. It suits state that is written once (or rarely) and read often, such as the
+//! device table parsed from the DTB.
+//!
+//! Access goes through guards released on drop. The `_irqsafe` variants also mask IRQs while the
+//! guard is alive and restore the previous `DAIF` state afterwards; they must be used for data
+//! also accessed from interrupt handlers, for the same reason as `Mutex::lock_irqsafe`.
+//!
+//! ## Design
+//!
+//! The whole state is a single atomic word: the top bit is set while a writer holds the lock and
+//! the remaining bits count the readers. A writer waits for the word to be zero; readers only
+//! wait while the writer bit is set. There is no writer preference, so a steady stream of
+//! readers can starve a writer: acceptable for the write-once tables this is meant for.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::kernel::{irq, sched};
+
+/// Bit of `RwLock::state` set while a writer holds the lock
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+/// A reader-writer spinlock
+pub struct RwLock<T> {
+    /// `WRITER` if write-locked, otherwise the number of readers
+    state: AtomicUsize,
+    /// The protected data
+    data: UnsafeCell<T>,
+}
+
+/// Safety: access to `data` is serialized by `state`
+unsafe impl<T> Sync for RwLock<T> {}
+
+/// Safety: the data is owned by the lock
+unsafe impl<T> Send for RwLock<T> {}
+
+/// Shared access to the data of an `RwLock`, released on drop
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    /// `DAIF` to restore on drop, for guards taken with `read_irqsafe`
+    daif: Option<u64>,
+}
+
+/// Exclusive access to the data of an `RwLock`, released on drop
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    /// `DAIF` to restore on drop, for guards taken with `write_irqsafe`
+    daif: Option<u64>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates a new unlocked `RwLock` containing `data`
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Spins until no writer holds the lock, then registers a reader
+    fn acquire_read(&self) {
+        sched::preempt_disable();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & WRITER != 0 {
+                core::hint::spin_loop();
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    /// Spins until the lock is free, then takes it for writing
+    fn acquire_write(&self) {
+        sched::preempt_disable();
+        while self
+            .state
+            .compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Locks for reading
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.acquire_read();
+        RwLockReadGuard {
+            lock: self,
+            daif: None,
+        }
+    }
+
+    /// Locks for reading with IRQs masked until the guard is dropped
+    pub fn read_irqsafe(&self) -> RwLockReadGuard<'_, T> {
+        let daif = irq::local_irq_save();
+        self.acquire_read();
+        RwLockReadGuard {
+            lock: self,
+            daif: Some(daif),
+        }
+    }
+
+    /// Locks for writing
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.acquire_write();
+        RwLockWriteGuard {
+            lock: self,
+            daif: None,
+        }
+    }
+
+    /// Locks for writing with IRQs masked until the guard is dropped
+    pub fn write_irqsafe(&self) -> RwLockWriteGuard<'_, T> {
+        let daif = irq::local_irq_save();
+        self.acquire_write();
+        RwLockWriteGuard {
+            lock: self,
+            daif: Some(daif),
+        }
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        sched::preempt_enable();
+        if let Some(daif) = self.daif {
+            irq::local_irq_restore(daif);
+        }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        sched::preempt_enable();
+        if let Some(daif) = self.daif {
+            irq::local_irq_restore(daif);
+        }
+    }
+}
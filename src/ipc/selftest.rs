@@ -0,0 +1,254 @@
+//! Producer/consumer self-test of the semaphore and condition variable
+//!
+//! Each test hands `ITEMS` items from an interrupt handler to a kernel thread. The producer is a
+//! high resolution timer whose callback, run from the timer interrupt, publishes one item and
+//! re-arms itself every `PERIOD_NS`; the consumer is a joinable task that sleeps until an item
+//! is there. The run passes if the consumer got every item, in order, and nothing is left over.
+//!
+//! - `Semaphore`: the callback bumps a produced count and calls `up`, the consumer calls
+//!   `down_timeout` once per item, so a lost wakeup shows up as a timeout.
+//! - `CondVar`: the callback appends the item's sequence number to a `Mutex`-protected queue
+//!   and calls `notify_one`, the consumer drains the queue with `wait_until`.
+//!
+//! Run from the `ipctest` shell command. Tests can't run concurrently, the state being static.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Plays the part of the lock torture tests (`kernel/locking/locktorture.c`), on a much
+//! smaller scale: a fixed number of items and a single producer and consumer.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::ipc::condvar::CondVar;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::semaphore::Semaphore;
+use crate::kernel::sched::{self, SchedError};
+use crate::kernel::time::hrtimer::{self, HrTimerError};
+
+/// Items produced by each test
+const ITEMS: usize = 64;
+
+/// Interval between two items
+const PERIOD_NS: u64 = 500_000;
+
+/// Longest a consumer waits for a single item before failing the test
+const ITEM_TIMEOUT_MS: u32 = 100;
+
+/// Errors returned by `run`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SelfTestError {
+    /// A test is already running
+    Busy,
+    /// The consumer task couldn't be created or joined
+    Spawn(SchedError),
+    /// The producer timer couldn't be started
+    Timer(HrTimerError),
+    /// The semaphore consumer got `consumed` of `produced` items, `left` permits remaining
+    Semaphore {
+        produced: usize,
+        consumed: usize,
+        left: usize,
+    },
+    /// The condition variable consumer got `consumed` of `produced` items, `bad` out of order
+    CondVar {
+        produced: usize,
+        consumed: usize,
+        bad: usize,
+    },
+}
+
+impl fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SelfTestError::Busy => write!(f, "already running"),
+            SelfTestError::Spawn(err) => write!(f, "consumer task: {:?}", err),
+            SelfTestError::Timer(err) => write!(f, "can't start the producer: {:?}", err),
+            SelfTestError::Semaphore {
+                produced,
+                consumed,
+                left,
+            } => write!(
+                f,
+                "semaphore: {} produced, {} consumed, {} permits left",
+                produced, consumed, left
+            ),
+            SelfTestError::CondVar {
+                produced,
+                consumed,
+                bad,
+            } => write!(
+                f,
+                "condvar: {} produced, {} consumed, {} out of order",
+                produced, consumed, bad
+            ),
+        }
+    }
+}
+
+/// Items handed over by a successful run, per test
+#[derive(Clone, Copy, Debug)]
+pub struct Report {
+    pub semaphore: usize,
+    pub condvar: usize,
+}
+
+/// Set while a run is in progress
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Items produced and consumed by the current test
+static PRODUCED: AtomicUsize = AtomicUsize::new(0);
+static CONSUMED: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once the producer has stopped, after the last item or on a timer failure
+static DONE: AtomicBool = AtomicBool::new(false);
+
+static SEM: Semaphore = Semaphore::new(0);
+
+/// Items published by the producer and not yet taken by the consumer
+struct Queue {
+    /// Sequence numbers of the pending items, oldest first
+    items: [usize; ITEMS],
+    len: usize,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    items: [0; ITEMS],
+    len: 0,
+});
+
+static CV: CondVar = CondVar::new();
+
+/// Items the condition variable consumer got out of sequence
+static OUT_OF_ORDER: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs both tests, returning how many items each one handed over
+///
+/// Blocks for about `ITEMS * PERIOD_NS` per test. Must not be called from interrupt context.
+pub fn run() -> Result<Report, SelfTestError> {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return Err(SelfTestError::Busy);
+    }
+    let result = run_semaphore().and_then(|semaphore| {
+        let condvar = run_condvar()?;
+        Ok(Report { semaphore, condvar })
+    });
+    RUNNING.store(false, Ordering::Release);
+    result
+}
+
+/// Spawns `consumer`, starts `producer` and waits for both to finish
+fn run_one(
+    name: &'static str,
+    consumer: fn(usize),
+    producer: fn(usize),
+) -> Result<(), SelfTestError> {
+    PRODUCED.store(0, Ordering::Relaxed);
+    CONSUMED.store(0, Ordering::Relaxed);
+    DONE.store(false, Ordering::Relaxed);
+    let task = sched::spawn_joinable(name, consumer, 0).map_err(SelfTestError::Spawn)?;
+    let started = hrtimer::start(PERIOD_NS, producer, 0);
+    if started.is_err() {
+        // Lets the consumer give up
+        DONE.store(true, Ordering::Release);
+        CV.notify_one();
+    }
+    sched::join(task).map_err(SelfTestError::Spawn)?;
+    started.map_err(SelfTestError::Timer)?;
+    while !DONE.load(Ordering::Acquire) {
+        sched::sleep_ms(1);
+    }
+    Ok(())
+}
+
+/// Counts an item out and re-arms the producer timer unless it was the last one
+///
+/// A failure to re-arm stops production, which the consumer then reports as missing items.
+fn produced(producer: fn(usize)) {
+    let produced = PRODUCED.fetch_add(1, Ordering::Relaxed) + 1;
+    if produced == ITEMS || hrtimer::start(PERIOD_NS, producer, 0).is_err() {
+        DONE.store(true, Ordering::Release);
+    }
+}
+
+fn run_semaphore() -> Result<usize, SelfTestError> {
+    while SEM.try_down() {}
+    run_one("ipctest-sem", sem_consumer, sem_producer)?;
+    let consumed = CONSUMED.load(Ordering::Relaxed);
+    let left = SEM.count();
+    if consumed != ITEMS || left != 0 {
+        return Err(SelfTestError::Semaphore {
+            produced: PRODUCED.load(Ordering::Relaxed),
+            consumed,
+            left,
+        });
+    }
+    Ok(consumed)
+}
+
+/// Timer callback: publishes one permit
+fn sem_producer(_data: usize) {
+    SEM.up();
+    produced(sem_producer);
+}
+
+/// Consumer task: takes one permit per item
+fn sem_consumer(_arg: usize) {
+    for _ in 0..ITEMS {
+        if SEM.down_timeout(ITEM_TIMEOUT_MS).is_err() {
+            return;
+        }
+        CONSUMED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn run_condvar() -> Result<usize, SelfTestError> {
+    QUEUE.lock_irqsafe(|queue| queue.len = 0);
+    OUT_OF_ORDER.store(0, Ordering::Relaxed);
+    run_one("ipctest-cv", cv_consumer, cv_producer)?;
+    let consumed = CONSUMED.load(Ordering::Relaxed);
+    let bad = OUT_OF_ORDER.load(Ordering::Relaxed);
+    if consumed != ITEMS || bad != 0 {
+        return Err(SelfTestError::CondVar {
+            produced: PRODUCED.load(Ordering::Relaxed),
+            consumed,
+            bad,
+        });
+    }
+    Ok(consumed)
+}
+
+/// Timer callback: queues the next sequence number
+fn cv_producer(_data: usize) {
+    let seq = PRODUCED.load(Ordering::Relaxed);
+    QUEUE.lock_irqsafe(|queue| {
+        queue.items[queue.len] = seq;
+        queue.len += 1;
+    });
+    produced(cv_producer);
+    CV.notify_one();
+}
+
+/// Consumer task: drains the queue until every item has been seen or the producer stopped
+///
+/// A lost notification leaves this task asleep, and `ipctest` with it.
+fn cv_consumer(_arg: usize) {
+    let mut next = 0;
+    while next < ITEMS {
+        let (items, len) = CV.wait_until(&QUEUE, |queue| {
+            let taken = (queue.items, queue.len);
+            queue.len = 0;
+            (taken.1 > 0 || DONE.load(Ordering::Acquire)).then_some(taken)
+        });
+        if len == 0 {
+            return;
+        }
+        for &item in &items[..len] {
+            if item != next {
+                OUT_OF_ORDER.fetch_add(1, Ordering::Relaxed);
+            }
+            next += 1;
+        }
+        CONSUMED.store(next, Ordering::Relaxed);
+    }
+}
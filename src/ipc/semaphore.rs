@@ -0,0 +1,72 @@
+//! Counting semaphore
+//!
+//! A `Semaphore` holds a number of permits. `down` takes one, sleeping on the semaphore's wait
+//! queue while there are none left; `up` gives one back and wakes the longest waiting task.
+//! `up` never blocks, so an interrupt handler can use it to hand work to a kernel thread
+//! (producer/consumer).
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Mirrors `struct semaphore` with `down()`, `down_trylock()`, `down_timeout()` and `up()`. The
+//! count is an atomic instead of being protected by a spinlock, and waiters are woken in FIFO
+//! order by the wait queue rather than being handed the permit directly.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ipc::waitqueue::{WaitError, WaitQueue};
+
+/// A counting semaphore
+pub struct Semaphore {
+    /// Number of available permits
+    count: AtomicUsize,
+    /// Tasks sleeping in `down`
+    wait: WaitQueue,
+}
+
+impl Semaphore {
+    /// Creates a semaphore holding `count` permits
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            wait: WaitQueue::new(),
+        }
+    }
+
+    /// Takes a permit if one is available, without sleeping
+    ///
+    /// Returns false if there was none. Safe to call from interrupt context.
+    pub fn try_down(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Takes a permit, sleeping until one is available
+    ///
+    /// Must not be called from interrupt context.
+    pub fn down(&self) {
+        self.wait.wait_event(|| self.try_down());
+    }
+
+    /// Takes a permit, sleeping until one is available or `timeout_ms` milliseconds have passed
+    ///
+    /// Must not be called from interrupt context.
+    pub fn down_timeout(&self, timeout_ms: u32) -> Result<(), WaitError> {
+        self.wait.wait_event_timeout(timeout_ms, || self.try_down())
+    }
+
+    /// Gives a permit back and wakes up a waiting task
+    ///
+    /// Safe to call from interrupt context.
+    pub fn up(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.wait.wake_up_one();
+    }
+
+    /// Returns the number of available permits
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
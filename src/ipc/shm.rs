@@ -0,0 +1,279 @@
+//! Named shared memory regions
+//!
+//! A region is a run of physical frames, zeroed on creation, that several tasks reach by name.
+//! `create` allocates it and returns its ID, `open` looks an existing one up; both take a
+//! reference that `close` drops, and the frames go back to the frame allocator with the last one.
+//!
+//! Each region has permission flags fixed at creation. `map` returns the address to access the
+//! region through, refusing permissions the region doesn't grant. Tasks still share the kernel's
+//! identity mapping, so the address is the region's physical address; once tasks get their own
+//! address spaces, this is where the frames get mapped into the caller's.
+//!
+//! ## Doorbells
+//!
+//! Shared memory alone gives no way to tell the other side that data is ready. Every region has
+//! a `Doorbell`: `ring` bumps a counter and wakes the waiters, `wait` sleeps until the counter
+//! moves past the last value seen. Higher-level protocols (request/response rings, ...) combine
+//! both.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Comparable to POSIX shared memory (`shm_open` + `mmap`) with an eventfd-like notification,
+//! rather than System V `shmget`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::mm::frame;
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+
+/// Maximum number of regions that can exist at once
+const MAX_REGIONS: usize = 16;
+
+/// Identifier of a region, its index in the region table
+pub type ShmId = usize;
+
+/// Access permissions of a region
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ShmPerms(u8);
+
+impl ShmPerms {
+    pub const READ: Self = Self(1 << 0);
+    pub const WRITE: Self = Self(1 << 1);
+    pub const EXEC: Self = Self(1 << 2);
+    pub const RW: Self = Self(Self::READ.0 | Self::WRITE.0);
+
+    /// Returns true if every permission in `other` is also in `self`
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the union of both sets
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Errors returned by the shared memory API
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ShmError {
+    /// A region with the same name already exists
+    Exists,
+    /// No region has this name or ID
+    NotFound,
+    /// The region table is full
+    NoSpace,
+    /// Not enough free frames for the region
+    NoMemory,
+    /// The requested access is not allowed by the region's permissions
+    Denied,
+    /// The size is zero
+    Invalid,
+    /// The region is still mapped
+    Busy,
+}
+
+/// A shared memory region
+#[derive(Clone, Copy)]
+struct Region {
+    name: &'static str,
+    /// Physical address of the first frame
+    base: usize,
+    /// Number of frames
+    frames: usize,
+    perms: ShmPerms,
+    /// References taken by `create` and `open`
+    refs: usize,
+    /// Active mappings made by `map`
+    maps: usize,
+}
+
+/// Information about a region, returned by `info`
+#[derive(Clone, Copy, Debug)]
+pub struct ShmInfo {
+    pub name: &'static str,
+    /// Size in bytes, a multiple of the page size
+    pub size: usize,
+    pub perms: ShmPerms,
+    pub refs: usize,
+    pub maps: usize,
+}
+
+/// A notification counter tasks can sleep on
+pub struct Doorbell {
+    /// Number of times the doorbell was rung
+    count: AtomicU64,
+    /// Tasks sleeping in `wait`
+    wait: WaitQueue,
+}
+
+impl Doorbell {
+    /// Const constructor for static initialization
+    pub const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            wait: WaitQueue::new(),
+        }
+    }
+
+    /// Rings the doorbell, waking up every waiting task
+    ///
+    /// Safe to call from interrupt context.
+    pub fn ring(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.wait.wake_up();
+    }
+
+    /// Returns the number of times the doorbell was rung
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Sleeps until the doorbell has been rung since `seen` was read with `count`
+    ///
+    /// Returns the new count, to be passed to the next call.
+    pub fn wait(&self, seen: u64) -> u64 {
+        self.wait.wait_event(|| self.count() != seen);
+        self.count()
+    }
+}
+
+impl Default for Doorbell {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static REGIONS: Mutex<[Option<Region>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// Doorbell of each region, indexed by region ID
+static DOORBELLS: [Doorbell; MAX_REGIONS] = [const { Doorbell::new() }; MAX_REGIONS];
+
+/// Creates a region of at least `size` bytes and takes a reference to it
+pub fn create(name: &'static str, size: usize, perms: ShmPerms) -> Result<ShmId, ShmError> {
+    if size == 0 {
+        return Err(ShmError::Invalid);
+    }
+    let frames = size.div_ceil(PAGE_SIZE);
+    // Reserve the slot first, the allocation happens without the table lock
+    let id = REGIONS.lock_irqsafe(|regions| {
+        if regions.iter().flatten().any(|r| r.name == name) {
+            return Err(ShmError::Exists);
+        }
+        let id = regions
+            .iter()
+            .position(|r| r.is_none())
+            .ok_or(ShmError::NoSpace)?;
+        regions[id] = Some(Region {
+            name,
+            base: 0,
+            frames: 0,
+            perms,
+            refs: 1,
+            maps: 0,
+        });
+        Ok(id)
+    })?;
+    let base = match frame::alloc_zeroed_frames(frames) {
+        Ok(base) => base,
+        Err(_) => {
+            REGIONS.lock_irqsafe(|regions| regions[id] = None);
+            return Err(ShmError::NoMemory);
+        }
+    };
+    REGIONS.lock_irqsafe(|regions| {
+        if let Some(region) = regions[id].as_mut() {
+            region.base = base;
+            region.frames = frames;
+        }
+    });
+    Ok(id)
+}
+
+/// Takes a reference to the region called `name`
+pub fn open(name: &str) -> Result<ShmId, ShmError> {
+    REGIONS.lock_irqsafe(|regions| {
+        let id = regions
+            .iter()
+            .position(|r| r.is_some_and(|r| r.name == name && r.frames > 0))
+            .ok_or(ShmError::NotFound)?;
+        if let Some(region) = regions[id].as_mut() {
+            region.refs += 1;
+        }
+        Ok(id)
+    })
+}
+
+/// Drops a reference taken by `create` or `open`, freeing the region with the last one
+///
+/// The last reference can't be dropped while the region is mapped.
+pub fn close(id: ShmId) -> Result<(), ShmError> {
+    let freed = REGIONS.lock_irqsafe(|regions| {
+        let slot = regions.get_mut(id).ok_or(ShmError::NotFound)?;
+        let region = slot.as_mut().ok_or(ShmError::NotFound)?;
+        if region.refs == 1 && region.maps > 0 {
+            return Err(ShmError::Busy);
+        }
+        region.refs -= 1;
+        if region.refs > 0 {
+            return Ok(None);
+        }
+        let region = *region;
+        *slot = None;
+        Ok(Some(region))
+    })?;
+    if let Some(region) = freed {
+        let _ = frame::free_frames(region.base, region.frames);
+    }
+    Ok(())
+}
+
+/// Maps the region with the `perms` access rights, returning the address to use
+pub fn map(id: ShmId, perms: ShmPerms) -> Result<*mut u8, ShmError> {
+    REGIONS.lock_irqsafe(|regions| {
+        let region = regions
+            .get_mut(id)
+            .and_then(|r| r.as_mut())
+            .ok_or(ShmError::NotFound)?;
+        if !region.perms.contains(perms) {
+            return Err(ShmError::Denied);
+        }
+        region.maps += 1;
+        // Identity mapped: the physical address is the virtual one
+        Ok(region.base as *mut u8)
+    })
+}
+
+/// Undoes one `map` of the region
+pub fn unmap(id: ShmId) -> Result<(), ShmError> {
+    REGIONS.lock_irqsafe(|regions| {
+        let region = regions
+            .get_mut(id)
+            .and_then(|r| r.as_mut())
+            .ok_or(ShmError::NotFound)?;
+        if region.maps == 0 {
+            return Err(ShmError::Invalid);
+        }
+        region.maps -= 1;
+        Ok(())
+    })
+}
+
+/// Returns information about a region
+pub fn info(id: ShmId) -> Option<ShmInfo> {
+    REGIONS.lock_irqsafe(|regions| {
+        regions.get(id).copied().flatten().map(|r| ShmInfo {
+            name: r.name,
+            size: r.frames * PAGE_SIZE,
+            perms: r.perms,
+            refs: r.refs,
+            maps: r.maps,
+        })
+    })
+}
+
+/// Returns the doorbell of a region
+pub fn doorbell(id: ShmId) -> Option<&'static Doorbell> {
+    DOORBELLS.get(id)
+}
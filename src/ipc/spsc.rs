@@ -0,0 +1,161 @@
+//! Single-producer single-consumer lock-free ring buffer
+//!
+//! `Ring<T, N>` holds up to `N` elements and needs no lock as long as at most one context pushes
+//! and at most one context pops at any time, e.g. an interrupt handler filling it and a task
+//! draining it, possibly on another CPU.
+//!
+//! ## Memory Ordering
+//!
+//! `head` is only written by the producer and `tail` only by the consumer. The producer writes
+//! the element *then* publishes it by storing `head` with `Release`; the consumer loads `head`
+//! with `Acquire` before reading the element, so it always sees the data. Symmetrically, the
+//! consumer releases a slot by storing `tail` with `Release` after reading it, and the producer
+//! loads `tail` with `Acquire` before overwriting the slot.
+//!
+//! Both indices run freely and wrap around: the number of elements is `head - tail` and the slot
+//! of an index is `index % N`.
+//!
+//! ## Producer and Consumer Handles
+//!
+//! `split` hands out a `Producer` and a `Consumer` borrowing the ring, which the borrow checker
+//! keeps unique. Rings in statics, shared between an interrupt handler and a task, can't be
+//! borrowed mutably: `producer`/`consumer` create the handles there, with the caller guaranteeing
+//! each role is only used from one context at a time.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A bounded single-producer single-consumer queue of `N` elements
+pub struct Ring<T, const N: usize> {
+    /// Element slots, the ones in `[tail, head)` are initialized
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Index of the next slot to write, only written by the producer
+    head: AtomicUsize,
+    /// Index of the next slot to read, only written by the consumer
+    tail: AtomicUsize,
+}
+
+/// Safety: each slot is only accessed by one side at a time, handed over through `head`/`tail`
+unsafe impl<T: Send, const N: usize> Sync for Ring<T, N> {}
+
+/// Safety: the elements are owned by the ring
+unsafe impl<T: Send, const N: usize> Send for Ring<T, N> {}
+
+/// The pushing side of a `Ring`
+pub struct Producer<'a, T, const N: usize> {
+    ring: &'a Ring<T, N>,
+}
+
+/// The popping side of a `Ring`
+pub struct Consumer<'a, T, const N: usize> {
+    ring: &'a Ring<T, N>,
+}
+
+impl<T, const N: usize> Ring<T, N> {
+    /// Const constructor for static initialization
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Splits the ring into its producer and consumer handles
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        (Producer { ring: self }, Consumer { ring: self })
+    }
+
+    /// Returns a producer handle for a shared ring
+    ///
+    /// # Safety
+    ///
+    /// No other producer handle of this ring may be used at the same time.
+    pub unsafe fn producer(&self) -> Producer<'_, T, N> {
+        Producer { ring: self }
+    }
+
+    /// Returns a consumer handle for a shared ring
+    ///
+    /// # Safety
+    ///
+    /// No other consumer handle of this ring may be used at the same time.
+    pub unsafe fn consumer(&self) -> Consumer<'_, T, N> {
+        Consumer { ring: self }
+    }
+
+    /// Returns the number of elements in the ring
+    ///
+    /// Only a snapshot when called from neither side.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        self.head.load(Ordering::Acquire).wrapping_sub(tail)
+    }
+
+    /// Returns true if the ring holds no element
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of elements
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// Appends `value`, or gives it back if the ring is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let ring = self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        if head.wrapping_sub(ring.tail.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+        unsafe { (*ring.slots[head % N].get()).write(value) };
+        ring.head.store(head.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns true if `push` would fail
+    pub fn is_full(&self) -> bool {
+        self.ring.len() == N
+    }
+}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    /// Removes the oldest element
+    pub fn pop(&mut self) -> Option<T> {
+        let ring = self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        if ring.head.load(Ordering::Acquire) == tail {
+            return None;
+        }
+        let value = unsafe { (*ring.slots[tail % N].get()).assume_init_read() };
+        ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Returns a reference to the oldest element without removing it
+    pub fn peek(&self) -> Option<&T> {
+        let ring = self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        if ring.head.load(Ordering::Acquire) == tail {
+            return None;
+        }
+        Some(unsafe { (*ring.slots[tail % N].get()).assume_init_ref() })
+    }
+}
+
+impl<T, const N: usize> Default for Ring<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Ring<T, N> {
+    fn drop(&mut self) {
+        let (_, mut consumer) = self.split();
+        while consumer.pop().is_some() {}
+    }
+}
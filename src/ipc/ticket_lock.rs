@@ -0,0 +1,82 @@
+//! Ticket spinlock
+//!
+//! A test-and-set spinlock lets whichever CPU wins the race take the lock, so a CPU can be
+//! starved, and every waiter keeps writing to the lock's cache line. A ticket lock grants the lock
+//! in arrival order instead: a CPU takes a ticket by incrementing `next`, then waits until
+//! `owner` reaches its ticket. Waiters only read `owner` while spinning.
+//!
+//! ## Waiting with WFE
+//!
+//! Waiters don't busy-loop: they sleep with `wfe` between checks, and `unlock` wakes them with
+//! `sev` after publishing the new owner. An event sent between a waiter's check and its `wfe`
+//! is latched in the event register, so `wfe` returns straight away and no wake-up is lost.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is the arm64 ticket spinlock Linux used before switching to queued spinlocks
+//! (`arch_spinlock_t` with `owner`/`next` halves and `wfe` in the wait loop).
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+/// A raw ticket lock, without data; see `Mutex` for the data-carrying wrapper
+pub struct TicketLock {
+    /// Ticket currently being served
+    owner: AtomicU16,
+    /// Next ticket to hand out
+    next: AtomicU16,
+}
+
+impl TicketLock {
+    /// Creates an unlocked ticket lock
+    pub const fn new() -> Self {
+        Self {
+            owner: AtomicU16::new(0),
+            next: AtomicU16::new(0),
+        }
+    }
+
+    /// Takes a ticket and waits for it to be served
+    pub fn lock(&self) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.owner.load(Ordering::Acquire) != ticket {
+            unsafe {
+                asm!("wfe", options(nostack, nomem, preserves_flags));
+            }
+        }
+    }
+
+    /// Takes the lock only if it is free, without waiting
+    pub fn try_lock(&self) -> bool {
+        let owner = self.owner.load(Ordering::Relaxed);
+        // The lock is free when no ticket is waiting to be served
+        self.next
+            .compare_exchange(
+                owner,
+                owner.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    /// Serves the next ticket and wakes up the waiters
+    pub fn unlock(&self) {
+        self.owner.fetch_add(1, Ordering::Release);
+        unsafe {
+            // Make the new owner visible before the waiters wake up
+            asm!("dsb ishst", "sev", options(nostack, preserves_flags));
+        }
+    }
+
+    /// Returns true if the lock is held
+    pub fn is_locked(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) != self.next.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for TicketLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
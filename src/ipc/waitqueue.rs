@@ -0,0 +1,196 @@
+//! Wait queues
+//!
+//! A `WaitQueue` is where tasks sleep until an event happens: a task calls `wait_event` with a
+//! condition, and whoever makes the condition true (typically an interrupt handler or deferred
+//! work) calls `wake_up` on the same queue.
+//!
+//! ## Lost Wake-ups
+//!
+//! The waiter enqueues itself and marks itself `Blocked` *before* checking the condition one last
+//! time, and the waker sets the condition *before* calling `wake_up`. Whatever the interleaving,
+//! either the waiter sees the condition or the waker finds it in the queue and makes it ready
+//! again, so `schedule` returns straight away.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Same idea as `wait_queue_head_t` with `wait_event()` / `wake_up()` and
+//! `wait_event_timeout()`, with a fixed-size FIFO of task IDs instead of a list of wait entries.
+//! There are no exclusive waiters or interruptible sleeps.
+
+use core::arch::asm;
+
+use crate::drivers::timer::arch_timer;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::sched::{self, MAX_TASKS, TaskId, TaskState};
+use crate::kernel::time::clocksource;
+
+/// Errors returned by the timed waits
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WaitError {
+    /// The condition was still false when the timeout expired
+    Timeout,
+}
+
+/// Returns the counter value `ms` milliseconds from now, for `wait_event_until`
+pub fn deadline_ms(ms: u32) -> u64 {
+    // One more increment, so the wait never ends early
+    arch_timer::get_counter().saturating_add(clocksource::ns_to_ticks(ms as u64 * 1_000_000) + 1)
+}
+
+/// A queue of tasks waiting for an event
+pub struct WaitQueue {
+    /// Waiting tasks, in arrival order
+    waiters: Mutex<[Option<TaskId>; MAX_TASKS]>,
+}
+
+impl WaitQueue {
+    /// Const constructor for static initialization
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new([None; MAX_TASKS]),
+        }
+    }
+
+    /// Adds `id` to the queue, unless it is already there
+    fn add(&self, id: TaskId) {
+        self.waiters.lock_irqsafe(|waiters| {
+            if waiters.contains(&Some(id)) {
+                return;
+            }
+            // A task waits on at most one queue at a time, so there is always room
+            if let Some(slot) = waiters.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(id);
+            }
+        });
+    }
+
+    /// Removes `id` from the queue, keeping the order of the other waiters
+    fn remove(&self, id: TaskId) {
+        self.waiters.lock_irqsafe(|waiters| {
+            if let Some(pos) = waiters.iter().position(|&w| w == Some(id)) {
+                waiters[pos..].rotate_left(1);
+                waiters[MAX_TASKS - 1] = None;
+            }
+        });
+    }
+
+    /// Puts the calling task to sleep until `cond` returns true
+    ///
+    /// `cond` is checked before sleeping and after every wake-up. Before the scheduler is
+    /// started the CPU just idles (`wfi`) between checks. Must not be called from interrupt
+    /// context or with a spinlock held.
+    pub fn wait_event(&self, mut cond: impl FnMut() -> bool) {
+        if cond() {
+            return;
+        }
+        let Some(me) = sched::current() else {
+            while !cond() {
+                unsafe {
+                    asm!("wfi", options(nostack, nomem, preserves_flags));
+                }
+            }
+            return;
+        };
+        loop {
+            self.add(me);
+            sched::set_current_state(TaskState::Blocked);
+            if cond() {
+                sched::set_current_state(TaskState::Running);
+                self.remove(me);
+                return;
+            }
+            sched::schedule();
+            self.remove(me);
+            if cond() {
+                return;
+            }
+        }
+    }
+
+    /// Puts the calling task to sleep until `cond` returns true or `timeout_ms` milliseconds
+    /// have passed
+    ///
+    /// Same rules as `wait_event`. Returns `WaitError::Timeout` if `cond` was still false.
+    pub fn wait_event_timeout(
+        &self,
+        timeout_ms: u32,
+        cond: impl FnMut() -> bool,
+    ) -> Result<(), WaitError> {
+        self.wait_event_until(deadline_ms(timeout_ms), cond)
+    }
+
+    /// Puts the calling task to sleep until `cond` returns true or the counter (`CNTPCT_EL0`)
+    /// reaches `expires`
+    ///
+    /// Useful when a deadline spans several waits, see `deadline_ms`.
+    pub fn wait_event_until(
+        &self,
+        expires: u64,
+        mut cond: impl FnMut() -> bool,
+    ) -> Result<(), WaitError> {
+        let expired = || arch_timer::get_counter() >= expires;
+        if cond() {
+            return Ok(());
+        }
+        let Some(me) = sched::current() else {
+            while !cond() {
+                if expired() {
+                    return Err(WaitError::Timeout);
+                }
+                unsafe {
+                    asm!("wfi", options(nostack, nomem, preserves_flags));
+                }
+            }
+            return Ok(());
+        };
+        loop {
+            self.add(me);
+            sched::set_current_state(TaskState::Sleeping);
+            if cond() || expired() {
+                sched::set_current_state(TaskState::Running);
+                self.remove(me);
+                return if cond() {
+                    Ok(())
+                } else {
+                    Err(WaitError::Timeout)
+                };
+            }
+            sched::schedule_timeout(expires);
+            self.remove(me);
+            if cond() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Wakes up every waiting task
+    ///
+    /// Safe to call from interrupt context.
+    pub fn wake_up(&self) {
+        let waiters = self
+            .waiters
+            .lock_irqsafe(|waiters| core::mem::replace(waiters, [None; MAX_TASKS]));
+        waiters.iter().flatten().for_each(|&id| {
+            sched::wake(id);
+        });
+    }
+
+    /// Wakes up the task that has been waiting the longest
+    ///
+    /// Returns false if the queue was empty.
+    pub fn wake_up_one(&self) -> bool {
+        let first = self.waiters.lock_irqsafe(|waiters| {
+            let first = waiters[0]?;
+            waiters.rotate_left(1);
+            waiters[MAX_TASKS - 1] = None;
+            Some(first)
+        });
+        first.is_some_and(sched::wake)
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
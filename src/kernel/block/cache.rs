@@ -0,0 +1,172 @@
+//! Block cache
+//!
+//! Copies of recently used sectors, shared by every disk. Entries are tagged with the registry
+//! index of the whole disk and the sector number on it, so a partition and its disk hit the
+//! same entries. When the cache is full, the least recently used entry is replaced.
+//!
+//! The cache is write-through: a write reaches the device before the cached copy is updated, so
+//! entries are never dirty and there is nothing to write back at shutdown. Reads only insert
+//! sectors that are not cached yet, so the data of a read that raced with a write to the same
+//! sector can't replace what the write left.
+//!
+//! There is no heap: `init` takes `CACHE_SHARE` of the free frames (within bounds) for the
+//! data, and the tags are a static table sized for the largest cache. Until then, every lookup
+//! misses and nothing is kept.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::mm::frame;
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+use crate::println;
+
+use super::SECTOR_SIZE;
+
+/// Share of the free frames taken by the cache at boot, as a divisor
+const CACHE_SHARE: usize = 64;
+
+/// Bounds on the number of frames of the cache
+const MIN_CACHE_FRAMES: usize = 4;
+const MAX_CACHE_FRAMES: usize = 64;
+
+const SECTORS_PER_FRAME: usize = PAGE_SIZE / SECTOR_SIZE;
+
+/// Largest number of cached sectors
+const MAX_ENTRIES: usize = MAX_CACHE_FRAMES * SECTORS_PER_FRAME;
+
+/// Identifies the sector held by a cache entry
+#[derive(Clone, Copy)]
+struct Tag {
+    disk: usize,
+    sector: u64,
+    /// Value of the cache clock when last used, 0 if the entry is empty
+    used: u64,
+}
+
+struct Cache {
+    /// Address of the data, `capacity` sectors in a row
+    data: usize,
+    capacity: usize,
+    tags: [Tag; MAX_ENTRIES],
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Cache {
+    fn find(&self, disk: usize, sector: u64) -> Option<usize> {
+        self.tags[..self.capacity]
+            .iter()
+            .position(|t| t.used != 0 && t.disk == disk && t.sector == sector)
+    }
+
+    fn data(&mut self, entry: usize) -> &mut [u8] {
+        let addr = self.data + entry * SECTOR_SIZE;
+        unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, SECTOR_SIZE) }
+    }
+
+    fn touch(&mut self, entry: usize) {
+        self.clock += 1;
+        self.tags[entry].used = self.clock;
+    }
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    data: 0,
+    capacity: 0,
+    tags: [Tag {
+        disk: 0,
+        sector: 0,
+        used: 0,
+    }; MAX_ENTRIES],
+    clock: 0,
+    hits: 0,
+    misses: 0,
+});
+
+/// Cache usage, for `block::dump`
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    /// Number of sectors the cache can hold
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Allocates the cache memory
+///
+/// Must run after `mm::frame::init`.
+pub fn init() {
+    let frames = (frame::stats().free / CACHE_SHARE).clamp(MIN_CACHE_FRAMES, MAX_CACHE_FRAMES);
+    let Ok(data) = frame::alloc_frames(frames) else {
+        println!("block: no memory for the cache");
+        return;
+    };
+    CACHE.lock_irqsafe(|cache| {
+        cache.data = data;
+        cache.capacity = frames * SECTORS_PER_FRAME;
+    });
+    println!("block: {} KiB cache", frames * PAGE_SIZE / 1024);
+}
+
+/// Copies sectors `sector..` of `disk` into `buf` if they are all cached
+pub fn read(disk: usize, sector: u64, buf: &mut [u8]) -> bool {
+    CACHE.lock_irqsafe(|cache| {
+        let count = buf.len() / SECTOR_SIZE;
+        let all_cached = (0..count as u64).all(|i| cache.find(disk, sector + i).is_some());
+        if !all_cached {
+            cache.misses += 1;
+            return false;
+        }
+        cache.hits += 1;
+        for (i, chunk) in buf.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            let Some(entry) = cache.find(disk, sector + i as u64) else {
+                continue;
+            };
+            chunk.copy_from_slice(cache.data(entry));
+            cache.touch(entry);
+        }
+        true
+    })
+}
+
+/// Caches `buf` as sectors `sector..` of `disk`
+///
+/// Sectors already cached are only replaced if `replace` is true, which is for data just
+/// written: data just read may be older than what a concurrent write cached.
+pub fn fill(disk: usize, sector: u64, buf: &[u8], replace: bool) {
+    CACHE.lock_irqsafe(|cache| {
+        if cache.capacity == 0 {
+            return;
+        }
+        for (i, chunk) in buf.chunks_exact(SECTOR_SIZE).enumerate() {
+            let sector = sector + i as u64;
+            let entry = match cache.find(disk, sector) {
+                Some(entry) if !replace => {
+                    cache.touch(entry);
+                    continue;
+                }
+                Some(entry) => entry,
+                None => cache.tags[..cache.capacity]
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, t)| t.used)
+                    .map_or(0, |(entry, _)| entry),
+            };
+            cache.tags[entry] = Tag {
+                disk,
+                sector,
+                used: 0,
+            };
+            cache.data(entry).copy_from_slice(chunk);
+            cache.touch(entry);
+        }
+    });
+}
+
+/// Returns the cache usage
+pub fn stats() -> CacheStats {
+    CACHE.lock_irqsafe(|cache| CacheStats {
+        capacity: cache.capacity,
+        hits: cache.hits,
+        misses: cache.misses,
+    })
+}
@@ -0,0 +1,418 @@
+//! Block devices
+//!
+//! Storage drivers implement `BlockDevice` and `register` their disks under a name (`vda`,
+//! `vdb`, ...). The rest of the kernel doesn't call drivers directly: it gets a `Disk` handle
+//! by name and reads and writes through it. User tasks see every disk and partition as
+//! `/dev/<name>`, a file with byte granularity over the device's sectors.
+//!
+//! Transfers are in whole 512-byte sectors and synchronous: `Disk::read` and `Disk::write`
+//! return once the request has been served, the calling task sleeping meanwhile.
+//!
+//! ## Design
+//!
+//! - `queue`: every disk has a request queue; requests from concurrent tasks are handed to the
+//!   driver one at a time, sorted by sector.
+//! - `cache`: an LRU cache of recently used sectors, shared by all disks and sized from free
+//!   memory at boot. It is write-through, so it never holds data the device doesn't have.
+//! - `partition`: MBR and GPT partition tables are parsed when a disk is registered (or by
+//!   `init` for disks registered during early boot); each partition becomes a `Disk` of its
+//!   own (`vda1`, `vda2`, ...), sharing the queue and cache entries of its whole disk.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! The same layering as Linux (`block_device_operations` drivers, a request queue with an
+//! elevator, the buffer cache, `block/partitions/`), much reduced: one request in flight per
+//! disk, no request merging and no asynchronous submission.
+
+mod cache;
+mod partition;
+mod queue;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::fs::devfs;
+use crate::kernel::fs::vfs::{self, FileKind, FsError, Ino, Node, Stat};
+use crate::{initcall, println};
+
+use queue::{Op, RequestQueue};
+
+/// Size of a sector, the unit of every transfer
+pub const SECTOR_SIZE: usize = 512;
+
+/// Maximum number of block devices, disks and partitions together
+const MAX_BLOCK_DEVICES: usize = 32;
+
+/// Errors returned by block devices and the registry
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockError {
+    /// The device reported a failure
+    Io,
+    /// The request goes past the last sector
+    OutOfRange,
+    /// The buffer is not a whole number of sectors
+    BadLength,
+    /// The device can't be written to
+    ReadOnly,
+    /// The device's sectors are not `SECTOR_SIZE` bytes
+    BadSectorSize,
+    /// A device is already registered under this name
+    Exists,
+    /// The registry is full
+    NoSpace,
+}
+
+/// A disk, or anything else addressed in sectors
+pub trait BlockDevice: Sync {
+    /// Name of the device, e.g. `vda`
+    fn name(&self) -> &'static str;
+
+    /// Returns the size of a sector in bytes
+    ///
+    /// Only devices with `SECTOR_SIZE` sectors can be registered for now.
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    /// Returns the number of sectors
+    fn sector_count(&self) -> u64;
+
+    /// Returns true if `write` always fails
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads `buf.len() / SECTOR_SIZE` sectors starting at `sector`
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf.len() / SECTOR_SIZE` sectors starting at `sector`
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Waits until completed writes are on stable storage
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Checks that a transfer of `len` bytes at `sector` fits in `sectors` sectors
+fn check_range(sectors: u64, sector: u64, len: usize) -> Result<(), BlockError> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(BlockError::BadLength);
+    }
+    let end = sector.checked_add((len / SECTOR_SIZE) as u64);
+    if end.is_none_or(|end| end > sectors) {
+        return Err(BlockError::OutOfRange);
+    }
+    Ok(())
+}
+
+/// Checks that a transfer of `len` bytes at `sector` fits in `dev`, for drivers
+pub fn check_request(dev: &dyn BlockDevice, sector: u64, len: usize) -> Result<(), BlockError> {
+    check_range(dev.sector_count(), sector, len)
+}
+
+/// A registered disk or partition
+#[derive(Clone, Copy)]
+struct Entry {
+    name: &'static str,
+    /// Driver of the whole disk
+    dev: &'static dyn BlockDevice,
+    /// Registry index of the whole disk, this entry's own for a disk
+    disk: usize,
+    /// First sector on the whole disk
+    start: u64,
+    sectors: u64,
+}
+
+/// Registered devices; entries are never removed, so indices stay valid
+static DEVICES: Mutex<[Option<Entry>; MAX_BLOCK_DEVICES]> = Mutex::new([None; MAX_BLOCK_DEVICES]);
+
+/// Request queues, indexed like `DEVICES`; only those of whole disks are used
+static QUEUES: [RequestQueue; MAX_BLOCK_DEVICES] =
+    [const { RequestQueue::new() }; MAX_BLOCK_DEVICES];
+
+/// Set by `init`: disks registered from then on are scanned for partitions straight away
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// A registered disk or partition
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Disk(usize);
+
+impl Disk {
+    fn entry(self) -> Entry {
+        DEVICES
+            .lock_irqsafe(|devices| devices[self.0])
+            .expect("block: unregistered disk")
+    }
+
+    /// Name of the disk or partition, as in `/dev`
+    pub fn name(self) -> &'static str {
+        self.entry().name
+    }
+
+    /// Returns the number of sectors
+    pub fn sector_count(self) -> u64 {
+        self.entry().sectors
+    }
+
+    /// Returns true if the disk can't be written to
+    pub fn read_only(self) -> bool {
+        self.entry().dev.read_only()
+    }
+
+    /// Returns the whole disk of a partition, `None` for a disk
+    pub fn parent(self) -> Option<Disk> {
+        let disk = self.entry().disk;
+        (disk != self.0).then_some(Disk(disk))
+    }
+
+    /// Reads `buf.len() / SECTOR_SIZE` sectors starting at `sector`
+    pub fn read(self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let entry = self.entry();
+        check_range(entry.sectors, sector, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let lba = entry.start + sector;
+        if cache::read(entry.disk, lba, buf) {
+            return Ok(());
+        }
+        let (addr, len) = (buf.as_mut_ptr() as usize, buf.len());
+        QUEUES[entry.disk].submit(entry.dev, Op::Read, lba, addr, len)?;
+        cache::fill(entry.disk, lba, buf, false);
+        Ok(())
+    }
+
+    /// Writes `buf.len() / SECTOR_SIZE` sectors starting at `sector`
+    pub fn write(self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let entry = self.entry();
+        if entry.dev.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        check_range(entry.sectors, sector, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let lba = entry.start + sector;
+        let (addr, len) = (buf.as_ptr() as usize, buf.len());
+        QUEUES[entry.disk].submit(entry.dev, Op::Write, lba, addr, len)?;
+        cache::fill(entry.disk, lba, buf, true);
+        Ok(())
+    }
+
+    /// Waits until the writes completed so far are on stable storage
+    pub fn flush(self) -> Result<(), BlockError> {
+        let entry = self.entry();
+        QUEUES[entry.disk].submit(entry.dev, Op::Flush, 0, 0, 0)
+    }
+}
+
+/// Adds a disk, or a partition of `parent` starting at `start`, to the registry and to `/dev`
+fn add(
+    name: &'static str,
+    dev: &'static dyn BlockDevice,
+    parent: Option<Disk>,
+    start: u64,
+    sectors: u64,
+) -> Result<Disk, BlockError> {
+    let index = DEVICES.lock_irqsafe(|devices| {
+        if devices.iter().flatten().any(|e| e.name == name) {
+            return Err(BlockError::Exists);
+        }
+        let index = devices
+            .iter()
+            .position(|e| e.is_none())
+            .ok_or(BlockError::NoSpace)?;
+        devices[index] = Some(Entry {
+            name,
+            dev,
+            disk: parent.map_or(index, |parent| parent.0),
+            start,
+            sectors,
+        });
+        Ok(index)
+    })?;
+    let node = Node {
+        inode: &BLOCK_FILE,
+        ino: index as Ino,
+    };
+    if let Err(e) = devfs::register(name, node) {
+        println!("block: no /dev entry for {}: {:?}", name, e);
+    }
+    Ok(Disk(index))
+}
+
+/// Makes `dev` available to filesystems and as `/dev/<name>`, along with its partitions
+pub fn register(dev: &'static dyn BlockDevice) -> Result<Disk, BlockError> {
+    if dev.sector_size() != SECTOR_SIZE {
+        return Err(BlockError::BadSectorSize);
+    }
+    let disk = add(dev.name(), dev, None, 0, dev.sector_count())?;
+    if INITIALIZED.load(Ordering::Acquire) {
+        partition::scan(disk);
+    }
+    Ok(disk)
+}
+
+/// Registers sectors `[start, start + sectors)` of `disk` as the partition `name`
+fn register_partition(
+    disk: Disk,
+    name: &'static str,
+    start: u64,
+    sectors: u64,
+) -> Result<Disk, BlockError> {
+    let entry = disk.entry();
+    let end = start.checked_add(sectors);
+    if sectors == 0 || end.is_none_or(|end| end > entry.sectors) {
+        return Err(BlockError::OutOfRange);
+    }
+    add(name, entry.dev, Some(disk), entry.start + start, sectors)
+}
+
+/// Sets up the block cache and scans the disks registered so far for partitions
+///
+/// The cache needs `mm::frame`, and the disks registered so far are those probed with the
+/// devices, so this runs after both.
+pub fn init() {
+    cache::init();
+    INITIALIZED.store(true, Ordering::Release);
+    let mut disks = [None; MAX_BLOCK_DEVICES];
+    for_each(|disk| {
+        if disk.parent().is_none() {
+            disks[disk.0] = Some(disk);
+        }
+    });
+    disks
+        .iter()
+        .flatten()
+        .for_each(|&disk| partition::scan(disk));
+}
+initcall!(Driver, "block", init, after = ["virtio"]);
+
+/// Returns the disk or partition registered as `name`
+pub fn find(name: &str) -> Option<Disk> {
+    DEVICES.lock_irqsafe(|devices| {
+        devices
+            .iter()
+            .position(|e| e.is_some_and(|e| e.name == name))
+            .map(Disk)
+    })
+}
+
+/// Calls `f` on every registered disk and partition, in registration order
+pub fn for_each(mut f: impl FnMut(Disk)) {
+    let devices = DEVICES.lock_irqsafe(|devices| *devices);
+    devices
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e.is_some())
+        .for_each(|(index, _)| f(Disk(index)));
+}
+
+/// Prints the disks and partitions with their size, and the cache statistics
+pub fn dump() {
+    for_each(|disk| {
+        let entry = disk.entry();
+        println!(
+            "{:8} {:>8} KiB  {}{}",
+            entry.name,
+            entry.sectors * SECTOR_SIZE as u64 / 1024,
+            if disk.parent().is_some() {
+                "part"
+            } else {
+                "disk"
+            },
+            if entry.dev.read_only() { ", ro" } else { "" }
+        );
+    });
+    let stats = cache::stats();
+    println!(
+        "cache: {} sectors, {} hits, {} misses",
+        stats.capacity, stats.hits, stats.misses
+    );
+}
+
+impl From<BlockError> for FsError {
+    fn from(e: BlockError) -> Self {
+        match e {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            BlockError::OutOfRange | BlockError::BadLength => FsError::Invalid,
+            _ => FsError::Io,
+        }
+    }
+}
+
+/// The `/dev` files of the block devices, the inode number being the registry index
+struct BlockFile;
+
+static BLOCK_FILE: BlockFile = BlockFile;
+
+impl BlockFile {
+    fn disk(&self, ino: Ino) -> Result<Disk, FsError> {
+        let index = ino as usize;
+        DEVICES
+            .lock_irqsafe(|devices| devices.get(index).is_some_and(|e| e.is_some()))
+            .then_some(Disk(index))
+            .ok_or(FsError::NotFound)
+    }
+}
+
+impl vfs::Inode for BlockFile {
+    fn stat(&self, ino: Ino) -> Result<Stat, FsError> {
+        let disk = self.disk(ino)?;
+        Ok(Stat {
+            kind: FileKind::BlockDevice,
+            size: disk.sector_count() as usize * SECTOR_SIZE,
+            mode: if disk.read_only() { 0o440 } else { 0o660 },
+        })
+    }
+
+    fn open(&self, _ino: Ino) -> Result<&'static dyn vfs::File, FsError> {
+        Ok(&BLOCK_FILE)
+    }
+}
+
+impl vfs::File for BlockFile {
+    /// Reads through a one-sector buffer, so any offset and length work
+    fn read(&self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let disk = self.disk(ino)?;
+        let size = disk.sector_count() as usize * SECTOR_SIZE;
+        let len = buf.len().min(size.saturating_sub(offset));
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let skip = pos % SECTOR_SIZE;
+            let chunk = (SECTOR_SIZE - skip).min(len - done);
+            disk.read((pos / SECTOR_SIZE) as u64, &mut sector)?;
+            buf[done..done + chunk].copy_from_slice(&sector[skip..skip + chunk]);
+            done += chunk;
+        }
+        Ok(done)
+    }
+
+    /// Partial sectors are read, patched and written back
+    fn write(&self, ino: Ino, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let disk = self.disk(ino)?;
+        if disk.read_only() {
+            return Err(FsError::ReadOnly);
+        }
+        let size = disk.sector_count() as usize * SECTOR_SIZE;
+        let len = buf.len().min(size.saturating_sub(offset));
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let skip = pos % SECTOR_SIZE;
+            let chunk = (SECTOR_SIZE - skip).min(len - done);
+            let lba = (pos / SECTOR_SIZE) as u64;
+            if chunk < SECTOR_SIZE {
+                disk.read(lba, &mut sector)?;
+            }
+            sector[skip..skip + chunk].copy_from_slice(&buf[done..done + chunk]);
+            disk.write(lba, &sector)?;
+            done += chunk;
+        }
+        Ok(done)
+    }
+}
@@ -0,0 +1,241 @@
+//! Partition tables
+//!
+//! `scan` reads the partition table of a disk and registers each partition as a block device
+//! named after the disk and the partition number: `vda1`, `vda2`, ... (with a `p` in between
+//! when the disk name ends in a digit, as in `mmcblk0p1`). Two formats are understood:
+//!
+//! - MBR: the four primary entries at the end of sector 0. Extended partitions, and the logical
+//!   partitions inside them, are skipped.
+//! - GPT: announced by an MBR entry of type 0xee (the protective MBR). The header in sector 1
+//!   gives the location and size of the entry array; both are checked against their CRC32.
+//!   Partition numbers are entry indices plus one. The backup header at the end of the disk is
+//!   not used.
+//!
+//! A disk formatted without a partition table (a "superfloppy") has a FAT boot sector in sector
+//! 0, which ends with the same 0x55 0xaa signature as an MBR; it is recognized by its BPB and
+//! left alone.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `block/partitions/` knows many more formats, and falls back to the backup GPT header
+//! when the primary one is corrupted.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ipc::init_cell::InitCell;
+use crate::utilities::convert::{read_le_u32, read_le_u64};
+use crate::{pr_err, println};
+
+use super::{Disk, SECTOR_SIZE};
+
+/// Maximum number of partitions, over all disks
+const MAX_PARTITIONS: usize = 16;
+
+/// Maximum length of a partition name
+const NAME_LEN: usize = 16;
+
+/// Highest partition number registered on a disk
+const MAX_PER_DISK: usize = 8;
+
+/// Offset of the partition entries in the MBR, and their size
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+
+/// MBR partition types
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const MBR_TYPE_GPT: u8 = 0xee;
+
+/// Sector of the GPT header
+const GPT_HEADER_LBA: u64 = 1;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Offset of the header CRC32 field, zeroed when computing it
+const GPT_HEADER_CRC: usize = 16;
+
+/// Upper bound on the number of GPT entries read, 128 being the usual count
+const GPT_MAX_ENTRIES: usize = 256;
+
+/// Names of the partitions, handed out as `&'static str`
+///
+/// A slot is reserved through `NAME_COUNT` and set once by `scan`.
+static NAMES: [InitCell<[u8; NAME_LEN]>; MAX_PARTITIONS] =
+    [const { InitCell::new() }; MAX_PARTITIONS];
+
+/// Number of slots of `NAMES` handed out
+static NAME_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the name of partition `number` of `disk`, `None` once every slot is in use
+fn partition_name(disk: &str, number: usize) -> Option<&'static str> {
+    let slot = NAME_COUNT.fetch_add(1, Ordering::Relaxed);
+    if slot >= MAX_PARTITIONS {
+        return None;
+    }
+    let mut name = [0; NAME_LEN];
+    let mut len = 0;
+    let mut push = |c: u8| {
+        if len < NAME_LEN {
+            name[len] = c;
+            len += 1;
+        }
+    };
+    disk.bytes().for_each(&mut push);
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        push(b'p');
+    }
+    if number >= 10 {
+        push(b'0' + (number / 10) as u8);
+    }
+    push(b'0' + (number % 10) as u8);
+    let name = NAMES[slot].set(name).ok()?;
+    core::str::from_utf8(&name[..len]).ok()
+}
+
+/// A partition found in a table
+#[derive(Clone, Copy)]
+struct Partition {
+    number: usize,
+    start: u64,
+    sectors: u64,
+}
+
+/// Standard CRC-32 (reflected, polynomial 0xedb88320), continued from `crc`
+///
+/// Start with `!0` and invert the result.
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    crc
+}
+
+/// Returns true if `sector` is the boot sector of a FAT volume rather than an MBR
+fn is_fat_boot_sector(sector: &[u8]) -> bool {
+    let jump = sector[0] == 0xeb || sector[0] == 0xe9;
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    let fs_type = &sector[54..57] == b"FAT" || &sector[82..87] == b"FAT32";
+    jump && bytes_per_sector.is_power_of_two() && fs_type
+}
+
+/// Calls `f` on every partition of the MBR `mbr`; returns true if it is a protective MBR
+fn parse_mbr(mbr: &[u8], mut f: impl FnMut(Partition)) -> bool {
+    let entries =
+        || mbr[MBR_ENTRIES..MBR_ENTRIES + 4 * MBR_ENTRY_SIZE].chunks_exact(MBR_ENTRY_SIZE);
+    if entries().any(|e| e[4] == MBR_TYPE_GPT) {
+        return true;
+    }
+    for (i, entry) in entries().enumerate() {
+        let kind = entry[4];
+        if kind == MBR_TYPE_EMPTY || MBR_TYPE_EXTENDED.contains(&kind) {
+            continue;
+        }
+        let start = read_le_u32(entry.as_ptr(), 8) as u64;
+        let sectors = read_le_u32(entry.as_ptr(), 12) as u64;
+        if start != 0 && sectors != 0 {
+            f(Partition {
+                number: i + 1,
+                start,
+                sectors,
+            });
+        }
+    }
+    false
+}
+
+/// Calls `f` on every partition of the GPT of `disk`
+///
+/// Returns false if the header or the entry array is invalid.
+fn parse_gpt(disk: Disk, mut f: impl FnMut(Partition)) -> bool {
+    let mut sector = [0u8; SECTOR_SIZE];
+    if disk.read(GPT_HEADER_LBA, &mut sector).is_err() || &sector[..8] != GPT_SIGNATURE {
+        return false;
+    }
+    let ptr = sector.as_ptr();
+    let header_size = read_le_u32(ptr, 12) as usize;
+    let header_crc = read_le_u32(ptr, GPT_HEADER_CRC);
+    let entries_lba = read_le_u64(ptr, 72);
+    let entry_count = read_le_u32(ptr, 80) as usize;
+    let entry_size = read_le_u32(ptr, 84) as usize;
+    let entries_crc = read_le_u32(ptr, 88);
+    if !(92..=SECTOR_SIZE).contains(&header_size)
+        || entry_size < 128
+        || !SECTOR_SIZE.is_multiple_of(entry_size)
+        || entry_count > GPT_MAX_ENTRIES
+    {
+        return false;
+    }
+    sector[GPT_HEADER_CRC..GPT_HEADER_CRC + 4].fill(0);
+    if !crc32_update(!0, &sector[..header_size]) != header_crc {
+        return false;
+    }
+
+    // Partitions are only reported once the whole array has passed its CRC
+    let mut found = [None; MAX_PER_DISK];
+    let mut crc = !0;
+    let sectors = (entry_count * entry_size).div_ceil(SECTOR_SIZE);
+    for i in 0..sectors {
+        if disk.read(entries_lba + i as u64, &mut sector).is_err() {
+            return false;
+        }
+        let bytes = (entry_count * entry_size - i * SECTOR_SIZE).min(SECTOR_SIZE);
+        crc = crc32_update(crc, &sector[..bytes]);
+        for (j, entry) in sector[..bytes].chunks_exact(entry_size).enumerate() {
+            let index = i * SECTOR_SIZE / entry_size + j;
+            // An all-zero type GUID marks an unused entry
+            if index >= MAX_PER_DISK || entry[..16].iter().all(|&b| b == 0) {
+                continue;
+            }
+            let first = read_le_u64(entry.as_ptr(), 32);
+            let last = read_le_u64(entry.as_ptr(), 40);
+            if first != 0 && last >= first {
+                found[index] = Some(Partition {
+                    number: index + 1,
+                    start: first,
+                    sectors: last - first + 1,
+                });
+            }
+        }
+    }
+    if !crc != entries_crc {
+        return false;
+    }
+    found.iter().flatten().for_each(|&p| f(p));
+    true
+}
+
+/// Registers the partitions of `disk`, if it has a partition table
+pub fn scan(disk: Disk) {
+    let mut mbr = [0u8; SECTOR_SIZE];
+    if disk.read(0, &mut mbr).is_err() || mbr[510..512] != [0x55, 0xaa] || is_fat_boot_sector(&mbr)
+    {
+        return;
+    }
+    let mut add = |p: Partition| {
+        if p.number > MAX_PER_DISK {
+            return;
+        }
+        let Some(name) = partition_name(disk.name(), p.number) else {
+            println!(
+                "block: no room for partition {} of {}",
+                p.number,
+                disk.name()
+            );
+            return;
+        };
+        match super::register_partition(disk, name, p.start, p.sectors) {
+            Ok(_) => println!(
+                "block: {} is {} MiB at sector {}",
+                name,
+                p.sectors * SECTOR_SIZE as u64 / (1024 * 1024),
+                p.start
+            ),
+            Err(e) => pr_err!("block: cannot register {}: {:?}", name, e),
+        }
+    };
+    if parse_mbr(&mbr, &mut add) && !parse_gpt(disk, &mut add) {
+        println!("block: invalid GPT on {}", disk.name());
+    }
+}
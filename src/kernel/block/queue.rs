@@ -0,0 +1,215 @@
+//! Request queues
+//!
+//! Each disk has a queue of pending requests, shared with its partitions. Drivers serve one
+//! request at a time, so rather than having tasks take turns on the driver, a task submitting a
+//! request while the disk is idle becomes the dispatcher: it hands the pending requests to the
+//! driver one after the other until the queue is empty, its own among them. Tasks submitting
+//! meanwhile only queue their request and sleep until it has been served.
+//!
+//! The dispatcher picks the pending request with the lowest sector at or after the end of the
+//! previous one, wrapping around to the lowest sector once none is left ahead (a one-way
+//! elevator), so a busy disk sweeps its sectors in order instead of seeking back and forth.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Like blk-mq, requests are dispatched from the context of the submitting tasks rather than
+//! from a dedicated thread. The ordering is the C-LOOK elevator of Linux's early I/O schedulers;
+//! there is no merging of adjacent requests and no deadline.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::sched::MAX_TASKS;
+
+use super::{BlockDevice, BlockError, SECTOR_SIZE};
+
+/// Maximum number of pending requests: a task has at most one
+const QUEUE_DEPTH: usize = MAX_TASKS;
+
+/// What a request asks the driver to do
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Op {
+    Read,
+    Write,
+    Flush,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    /// Waiting for the dispatcher
+    Pending,
+    /// Being served by the driver
+    Dispatched,
+    /// Served; the submitter collects the result and frees the slot
+    Done(Result<(), BlockError>),
+}
+
+/// A request in the queue
+#[derive(Clone, Copy)]
+struct Request {
+    op: Op,
+    sector: u64,
+    /// Buffer of the submitter, which sleeps in `submit` until the request is done
+    addr: usize,
+    len: usize,
+    state: State,
+}
+
+impl Request {
+    /// Passes the request to the driver
+    fn run(&self, dev: &dyn BlockDevice) -> Result<(), BlockError> {
+        // The buffer stays valid until the submitter has seen the request done
+        match self.op {
+            Op::Read => {
+                let buf =
+                    unsafe { core::slice::from_raw_parts_mut(self.addr as *mut u8, self.len) };
+                dev.read(self.sector, buf)
+            }
+            Op::Write => {
+                let buf = unsafe { core::slice::from_raw_parts(self.addr as *const u8, self.len) };
+                dev.write(self.sector, buf)
+            }
+            Op::Flush => dev.flush(),
+        }
+    }
+}
+
+struct Queue {
+    requests: [Option<Request>; QUEUE_DEPTH],
+    /// A task is dispatching requests
+    dispatching: bool,
+    /// Sector following the last request dispatched
+    head: u64,
+}
+
+impl Queue {
+    /// Returns the slot of the pending request to dispatch next
+    fn next(&self) -> Option<usize> {
+        let pending = || {
+            self.requests.iter().enumerate().filter_map(|(slot, r)| {
+                r.filter(|r| r.state == State::Pending)
+                    .map(|r| (slot, r.sector))
+            })
+        };
+        pending()
+            .filter(|&(_, sector)| sector >= self.head)
+            .min_by_key(|&(_, sector)| sector)
+            .or_else(|| pending().min_by_key(|&(_, sector)| sector))
+            .map(|(slot, _)| slot)
+    }
+}
+
+/// The request queue of a disk
+pub struct RequestQueue {
+    queue: Mutex<Queue>,
+    /// Woken when a request is done or a slot frees up
+    events: WaitQueue,
+}
+
+impl RequestQueue {
+    /// Const constructor for static initialization
+    pub const fn new() -> Self {
+        Self {
+            queue: Mutex::new(Queue {
+                requests: [None; QUEUE_DEPTH],
+                dispatching: false,
+                head: 0,
+            }),
+            events: WaitQueue::new(),
+        }
+    }
+
+    /// Queues a request for `dev` and sleeps until it has been served
+    ///
+    /// `addr` and `len` describe the data buffer, empty for a flush. `dev` must be the driver of
+    /// the disk the queue belongs to.
+    pub fn submit(
+        &self,
+        dev: &dyn BlockDevice,
+        op: Op,
+        sector: u64,
+        addr: usize,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        let request = Request {
+            op,
+            sector,
+            addr,
+            len,
+            state: State::Pending,
+        };
+        let mut slot = 0;
+        self.events.wait_event(|| {
+            self.queue.lock_irqsafe(|q| {
+                let free = q.requests.iter().position(Option::is_none);
+                if let Some(free) = free {
+                    q.requests[free] = Some(request);
+                    slot = free;
+                }
+                free.is_some()
+            })
+        });
+
+        loop {
+            let dispatcher = self
+                .queue
+                .lock_irqsafe(|q| !core::mem::replace(&mut q.dispatching, true));
+            if dispatcher {
+                self.dispatch(dev);
+            }
+            // Done, or the dispatcher left before the request was picked up (it was queued
+            // after the dispatcher found the queue empty): take over
+            let mut result = None;
+            self.events.wait_event(|| {
+                self.queue.lock_irqsafe(|q| {
+                    if let Some(Request {
+                        state: State::Done(r),
+                        ..
+                    }) = q.requests[slot]
+                    {
+                        q.requests[slot] = None;
+                        result = Some(r);
+                    }
+                    result.is_some() || !q.dispatching
+                })
+            });
+            if let Some(result) = result {
+                // The slot is free for tasks waiting for one
+                self.events.wake_up();
+                return result;
+            }
+        }
+    }
+
+    /// Serves pending requests until there are none left
+    fn dispatch(&self, dev: &dyn BlockDevice) {
+        loop {
+            let next = self.queue.lock_irqsafe(|q| {
+                let Some(slot) = q.next() else {
+                    q.dispatching = false;
+                    return None;
+                };
+                let request = q.requests[slot].as_mut()?;
+                request.state = State::Dispatched;
+                q.head = request.sector + (request.len / SECTOR_SIZE) as u64;
+                Some((slot, *request))
+            });
+            let Some((slot, request)) = next else {
+                self.events.wake_up();
+                return;
+            };
+            let result = request.run(dev);
+            self.queue.lock_irqsafe(|q| {
+                if let Some(request) = q.requests[slot].as_mut() {
+                    request.state = State::Done(result);
+                }
+            });
+            self.events.wake_up();
+        }
+    }
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
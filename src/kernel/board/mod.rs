@@ -0,0 +1,66 @@
+//! Board configurations
+//!
+//! The kernel is built for one board, selected with a Cargo feature (`make BOARD=rpi4` passes
+//! it on). The board module gives what can't come from the device tree because it is needed
+//! before the DTB has been parsed, or to run at all:
+//!
+//! - `NAME`, printed at boot
+//! - `EARLYCON`: the UART the early console writes to until the command line is read, if any
+//! - `IDMAP`: the kind of memory behind each GiB of the boot identity map
+//!
+//! The load address of the image is set by `include/board.h`, which the Makefile selects with
+//! the same `BOARD`. Drivers only some boards need (`gic400`, `mini-uart`) are features of their
+//! own, enabled by the boards using them.
+//!
+//! | Feature     | Board                               | Extra drivers           |
+//! |-------------|-------------------------------------|-------------------------|
+//! | `qemu-virt` | QEMU `virt` machine, GICv3 (default)| none                    |
+//! | `rpi4`      | Raspberry Pi 4 Model B (BCM2711)    | `gic400`, `mini-uart`   |
+//!
+//! Other boards with a GIC-400 and a PL011 can use `qemu-virt`'s code paths with the `gic400`
+//! feature added; only the early console and the memory map are board-specific.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux builds one image for every arm64 board: the memory map, console and drivers all come
+//! from the device tree, and the image is relocatable. This kernel is linked at a fixed address
+//! and maps memory before parsing `/memory`, hence the per-board builds.
+
+#[cfg(not(any(feature = "qemu-virt", feature = "rpi4")))]
+compile_error!("no board selected: enable the `qemu-virt` or `rpi4` feature");
+#[cfg(all(feature = "qemu-virt", feature = "rpi4"))]
+compile_error!("several boards selected: enable one of `qemu-virt` and `rpi4`");
+
+#[cfg(feature = "qemu-virt")]
+pub mod qemu_virt;
+#[cfg(feature = "rpi4")]
+pub mod rpi4;
+
+#[cfg(feature = "qemu-virt")]
+pub use qemu_virt::*;
+#[cfg(feature = "rpi4")]
+pub use rpi4::*;
+
+use crate::kernel::mm::bits::SZ_1G;
+
+/// Kind of memory of a 1 GiB block of the identity map
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Block {
+    /// MMIO, mapped device nGnRnE and never executable
+    Device,
+    /// RAM, mapped normal write-back
+    Normal,
+    /// Left unmapped
+    Unmapped,
+}
+
+/// Returns the end of the last block of RAM in `IDMAP`
+///
+/// The frame allocator and the initramfs only use memory below it.
+pub const fn mapped_end() -> usize {
+    let mut i = IDMAP.len();
+    while i > 0 && !matches!(IDMAP[i - 1], Block::Normal) {
+        i -= 1;
+    }
+    i * SZ_1G
+}
@@ -0,0 +1,17 @@
+//! QEMU `virt` machine
+//!
+//! Devices sit in the first GiB (the PL011 at `0x0900_0000`), RAM starts at 1 GiB. The image is
+//! loaded at `0x5000_0000` by the bootloader, or by QEMU itself with `make run-kernel`.
+
+use crate::kernel::console::earlycon::EarlyUart;
+
+use super::Block;
+
+/// Board name, printed at boot
+pub const NAME: &str = "QEMU virt";
+
+/// UART of the early console, set up by QEMU
+pub const EARLYCON: Option<(EarlyUart, usize)> = Some((EarlyUart::Pl011, 0x0900_0000));
+
+/// Memory behind each GiB of the identity map, from address 0
+pub const IDMAP: &[Block] = &[Block::Device, Block::Normal];
@@ -0,0 +1,26 @@
+//! Raspberry Pi 4 Model B (BCM2711)
+//!
+//! The firmware loads `kernel8.img` at `0x8_0000` and enters it at EL2, with the DTB address in
+//! `x0`. With the default "low peripheral" mode, RAM starts at 0 and the peripherals (mini-UART,
+//! GIC-400) are in the top 64 MiB of the fourth GiB. RAM beyond the first GiB only exists on the
+//! 2 GiB and larger models; the frame allocator takes whatever `/memory` reports below
+//! `mapped_end`.
+//!
+//! `config.txt` needs `arm_64bit=1` and `enable_uart=1`. The latter puts the mini-UART on GPIO
+//! 14/15 and fixes the core clock, so the divisor the firmware programmed stays valid.
+
+use crate::kernel::console::earlycon::EarlyUart;
+
+use super::Block;
+
+/// Board name, printed at boot
+pub const NAME: &str = "Raspberry Pi 4";
+
+/// UART of the early console: the mini-UART, set up by the firmware with `enable_uart=1`
+pub const EARLYCON: Option<(EarlyUart, usize)> = Some((EarlyUart::MiniUart, 0xfe21_5040));
+
+/// Memory behind each GiB of the identity map, from address 0
+///
+/// On 4 GiB models the third GiB and the bottom of the fourth are RAM too, left out: the frame
+/// allocator manages 1 GiB at most, and the fourth GiB is mapped as a whole for the peripherals.
+pub const IDMAP: &[Block] = &[Block::Normal, Block::Normal, Block::Unmapped, Block::Device];
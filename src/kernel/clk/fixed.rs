@@ -0,0 +1,47 @@
+//! Fixed-rate clocks
+//!
+//! A `fixed-clock` node describes an oscillator or a clock the firmware set up and left running,
+//! e.g. QEMU's `apb-pclk` feeding the PL011. Its rate is the `clock-frequency` property and it
+//! has a single output (`#clock-cells = <0>`). Each one is also a clock sensor, named after
+//! its node.
+
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::sensor::{self, SensorError, SensorKind};
+use crate::pr_warn;
+use crate::utilities::convert;
+
+/// Driver for `fixed-clock` nodes
+pub struct FixedClockDriver;
+
+impl device::Driver for FixedClockDriver {
+    /// Registers the node as a provider of its `clock-frequency`
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let rate = dev
+            .find_property("clock-frequency")
+            .filter(|prop| prop.len >= 4)
+            .ok_or(ProbeError::NoDevice)?;
+        // One cell, or two for rates above 4 GHz
+        let rate = match rate.len {
+            8 => {
+                (convert::read_be_u32(rate.value, 0) as u64) << 32
+                    | convert::read_be_u32(rate.value, 4) as u64
+            }
+            _ => convert::read_be_u32(rate.value, 0) as u64,
+        };
+        super::register_provider(dev, fixed_rate, rate as usize)?;
+        if let Err(e) = sensor::register(dev.name, SensorKind::Clock, read_sensor, rate as usize) {
+            pr_warn!("clk: no sensor for {}: {:?}", dev.name, e);
+        }
+        Ok(())
+    }
+}
+
+/// Rate function of fixed clocks, which keep their rate in `data`
+fn fixed_rate(data: usize, _args: &[u32]) -> u64 {
+    data as u64
+}
+
+/// Read function of the fixed clock sensors
+fn read_sensor(data: usize) -> Result<i64, SensorError> {
+    Ok(data as i64)
+}
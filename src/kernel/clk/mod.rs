@@ -0,0 +1,161 @@
+//! Clock providers
+//!
+//! Devices name their input clocks in the DTB with a `clocks` property: a list of phandles to
+//! clock provider nodes, each followed by as many specifier cells as the provider's
+//! `#clock-cells` says (0 for a provider with a single output). Drivers of providers register a
+//! rate function for their node, and consumers resolve their clocks with `clk_get`, whatever kind
+//! of provider is behind them.
+//!
+//! ## Design
+//!
+//! - `fixed`: the driver for `fixed-clock` nodes, whose rate is the `clock-frequency` property.
+//!   It is the only provider so far.
+//! - A provider is a function turning the specifier cells into a rate, with a `data` word for its
+//!   own use (`fixed` keeps the rate there), like the interrupt handlers of `irq::request_irq`.
+//! - A provider node with a driver is a supplier of its consumers in the driver model (see
+//!   `device`), so their probes are deferred until it is bound. `clk_get` returns
+//!   `ClkError::NotReady` if it isn't, which converts to `ProbeError::Defer`.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's common clock framework (`drivers/clk/clk.c`) models a whole clock tree, with
+//! parents, gates, muxes, rate changes and reference counted enables, behind `of_clk_get`.
+//! Here a clock is only a rate to read: there is nothing to enable or reparent yet.
+
+pub mod fixed;
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::device::{PlatformDevice, ProbeError};
+use crate::kernel::dtb;
+use crate::utilities::convert;
+
+/// Maximum number of registered providers
+const MAX_PROVIDERS: usize = 16;
+
+/// Maximum number of specifier cells after a phandle in `clocks`
+pub const MAX_CLOCK_CELLS: usize = 4;
+
+/// Computes the rate of one of a provider's outputs from its specifier cells and `data`
+pub type RateFn = fn(data: usize, args: &[u32]) -> u64;
+
+/// Errors returned by the clock framework
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClkError {
+    /// The device has no clock at this index
+    NotFound,
+    /// The `clocks` entry is malformed, or references a missing node
+    BadSpecifier,
+    /// The provider node has a driver, which hasn't registered it yet
+    NotReady,
+    /// Nothing will provide this clock: the provider node has no driver
+    NoProvider,
+    /// A provider is already registered for this node
+    Exists,
+    /// The provider registry is full
+    NoSpace,
+}
+
+impl From<ClkError> for ProbeError {
+    fn from(e: ClkError) -> Self {
+        match e {
+            ClkError::NotReady => ProbeError::Defer,
+            ClkError::NoSpace => ProbeError::NoResources,
+            _ => ProbeError::NoDevice,
+        }
+    }
+}
+
+/// A registered provider
+#[derive(Clone, Copy)]
+struct Provider {
+    node: *const PlatformDevice,
+    rate: RateFn,
+    data: usize,
+}
+
+/// Registered providers
+static PROVIDERS: Mutex<[Option<Provider>; MAX_PROVIDERS]> = Mutex::new([None; MAX_PROVIDERS]);
+
+/// A clock resolved from a `clocks` entry
+#[derive(Clone, Copy)]
+pub struct Clk {
+    rate: RateFn,
+    data: usize,
+    args: [u32; MAX_CLOCK_CELLS],
+    arg_count: usize,
+}
+
+impl Clk {
+    /// Returns the rate of the clock, in Hz
+    pub fn get_rate(&self) -> u64 {
+        (self.rate)(self.data, &self.args[..self.arg_count])
+    }
+}
+
+/// Registers `node` as a clock provider, its clocks' rates computed by `rate`
+pub fn register_provider(node: &PlatformDevice, rate: RateFn, data: usize) -> Result<(), ClkError> {
+    PROVIDERS.lock_irqsafe(|providers| {
+        if providers
+            .iter()
+            .flatten()
+            .any(|p| core::ptr::eq(p.node, node))
+        {
+            return Err(ClkError::Exists);
+        }
+        let slot = providers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ClkError::NoSpace)?;
+        *slot = Some(Provider { node, rate, data });
+        Ok(())
+    })
+}
+
+/// Returns the provider registered for `node`
+fn find_provider(node: &PlatformDevice) -> Option<Provider> {
+    PROVIDERS.lock_irqsafe(|providers| {
+        providers
+            .iter()
+            .flatten()
+            .find(|p| core::ptr::eq(p.node, node))
+            .copied()
+    })
+}
+
+/// Resolves the clock at `index` in the `clocks` property of `dev`
+pub fn clk_get(dev: &PlatformDevice, index: usize) -> Result<Clk, ClkError> {
+    let clocks = dev.find_property("clocks").ok_or(ClkError::NotFound)?;
+    let mut offset = 0;
+    let mut i = 0;
+    while offset + 4 <= clocks.len {
+        let node = dtb::find_device_by_phandle(convert::read_be_u32(clocks.value, offset))
+            .ok_or(ClkError::BadSpecifier)?;
+        let cells = node
+            .find_property("#clock-cells")
+            .map_or(0, |p| convert::read_be_u32(p.value, 0) as usize);
+        if cells > MAX_CLOCK_CELLS || offset + (1 + cells) * 4 > clocks.len {
+            return Err(ClkError::BadSpecifier);
+        }
+        if i == index {
+            let Some(provider) = find_provider(node) else {
+                return match dtb::match_driver(node) {
+                    Some(_) => Err(ClkError::NotReady),
+                    None => Err(ClkError::NoProvider),
+                };
+            };
+            let mut args = [0; MAX_CLOCK_CELLS];
+            for (n, arg) in args[..cells].iter_mut().enumerate() {
+                *arg = convert::read_be_u32(clocks.value, offset + (1 + n) * 4);
+            }
+            return Ok(Clk {
+                rate: provider.rate,
+                data: provider.data,
+                args,
+                arg_count: cells,
+            });
+        }
+        offset += (1 + cells) * 4;
+        i += 1;
+    }
+    Err(ClkError::NotFound)
+}
@@ -0,0 +1,108 @@
+//! ANSI/VT100 escape sequences
+//!
+//! Serial terminals (and `fbcon`) interpret the control sequences of ECMA-48, which all start
+//! with `ESC [` (the *CSI*), take optional numeric parameters separated by `;` and end with a
+//! letter naming the command. The few the kernel emits are built here:
+//!
+//! | Sequence     | Effect                                   | Used by                       |
+//! |--------------|------------------------------------------|-------------------------------|
+//! | `CSI n m`    | select graphic rendition (colors, reset) | log levels (`pr_err!`, ...)   |
+//! | `CSI n C/D`  | move the cursor right/left by `n`        | the shell's line editor       |
+//! | `CSI K`      | erase to the end of the line             | the shell's line editor       |
+//! | `CSI 2J`     | erase the display                        | the shell's `clear`           |
+//! | `CSI H`      | move the cursor to the top left corner   | the shell's `clear`           |
+//!
+//! ## Colors
+//!
+//! Colors can be turned off, for dumb terminals or when the output is captured to a file:
+//! `colors=off` on the kernel command line, or `colors off` in the shell. Cursor movement is
+//! still emitted, as line editing can't do without it.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux leaves colors to user space (`dmesg --color`) and only decodes these sequences in the
+//! VT console (`drivers/tty/vt/vt.c`), as `fbcon` does here.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::kernel::dtb;
+use crate::print;
+
+/// The escape character starting every sequence
+pub const ESC: u8 = 0x1b;
+
+/// Erases from the cursor to the end of the line
+pub const CLEAR_LINE: &str = "\x1b[K";
+
+/// Erases the display and moves the cursor to the top left corner
+pub const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Set while colored output is enabled
+static COLORS: AtomicBool = AtomicBool::new(true);
+
+/// The eight colors of the basic palette, in SGR order
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl Color {
+    /// Returns the SGR parameter selecting the color as foreground (30 to 37)
+    pub fn sgr(self) -> u8 {
+        30 + self as u8
+    }
+}
+
+/// Reads `colors=` from the kernel command line
+///
+/// Must run after the DTB has been parsed.
+pub fn init() {
+    if let Some(value) = dtb::bootarg("colors") {
+        set_colors(!matches!(value, "off" | "0" | "no"));
+    }
+}
+
+/// Enables or disables colored output
+pub fn set_colors(enabled: bool) {
+    COLORS.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns true if colored output is enabled
+pub fn colors() -> bool {
+    COLORS.load(Ordering::Relaxed)
+}
+
+/// Prints the following text in `color`, if colors are enabled
+pub fn set_color(color: Color) {
+    if colors() {
+        print!("\x1b[{}m", color.sgr());
+    }
+}
+
+/// Goes back to the default rendition after `set_color`
+pub fn reset_color() {
+    if colors() {
+        print!("\x1b[0m");
+    }
+}
+
+/// Moves the cursor `count` columns to the left
+pub fn cursor_left(count: usize) {
+    if count > 0 {
+        print!("\x1b[{}D", count);
+    }
+}
+
+/// Moves the cursor `count` columns to the right
+pub fn cursor_right(count: usize) {
+    if count > 0 {
+        print!("\x1b[{}C", count);
+    }
+}
@@ -0,0 +1,187 @@
+//! Early boot console
+//!
+//! Until a UART driver has probed its device and registered it as the console, messages are
+//! written straight to the data register of a UART the firmware already configured. Its address
+//! can't come from the device tree while the DTB is still being parsed, so it starts out as the
+//! board's (`board::EARLYCON`), then the kernel command line can change it once `/chosen` is
+//! readable:
+//!
+//! | Parameter                    | Early console                                       |
+//! |------------------------------|-----------------------------------------------------|
+//! | `earlycon=pl011,<addr>`      | the PL011 at `<addr>` (`mmio32,<addr>` also works)  |
+//! | `earlycon=bcm2835aux,<addr>` | the mini-UART at `<addr>` (`mini-uart` feature)     |
+//! | `earlycon`                   | the UART of the `/chosen/stdout-path` node          |
+//! | `earlycon=off`               | none                                                |
+//!
+//! ## Design
+//!
+//! Nothing is buffered here: every message is in the kernel log (see `log::ringbuf`) anyway.
+//! While there is no early console, output is dropped and `DROPPED` remembers it, so the log can
+//! be flushed to the next console to show up, be it an early console selected by `earlycon=` or
+//! the real one. When the driver registers the first console, `handover` retires the early
+//! console; the driver waits for the UART to go idle before reprogramming it, so no byte written
+//! here is lost.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux matches `earlycon=<name>,<options>` against the `EARLYCON_DECLARE` table
+//! (`drivers/tty/serial/earlycon.c`) and unregisters the boot console once a real one with the
+//! same device registers (`printk`'s `CON_BOOT`). Only the PL011 and the mini-UART are supported
+//! here, and the handover happens with the first console, whatever its device.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+#[cfg(feature = "mini-uart")]
+use crate::drivers::uart::mini_uart;
+use crate::drivers::uart::pl011;
+use crate::kernel::{board, dtb};
+
+use super::Console;
+
+/// Value of `BASE` while there is no early console
+const NONE: usize = 0;
+
+/// UART models the early console can write to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum EarlyUart {
+    Pl011,
+    /// The BCM2835 auxiliary mini-UART
+    #[cfg(feature = "mini-uart")]
+    MiniUart,
+}
+
+impl EarlyUart {
+    /// Returns the model matching the `compatible` string of a UART node
+    fn from_compatible(compatible: &str) -> Option<Self> {
+        match compatible {
+            "arm,pl011" => Some(EarlyUart::Pl011),
+            #[cfg(feature = "mini-uart")]
+            "brcm,bcm2835-aux-uart" => Some(EarlyUart::MiniUart),
+            _ => None,
+        }
+    }
+
+    /// Returns the model named `name` in `earlycon=<name>,<addr>`
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pl011" => Some(EarlyUart::Pl011),
+            #[cfg(feature = "mini-uart")]
+            "bcm2835aux" => Some(EarlyUart::MiniUart),
+            _ => None,
+        }
+    }
+
+    /// Writes `c` to the UART at `base`
+    fn putchar(self, base: usize, c: u8) {
+        match self {
+            EarlyUart::Pl011 => pl011::early_putchar(base, c),
+            #[cfg(feature = "mini-uart")]
+            EarlyUart::MiniUart => mini_uart::early_putchar(base, c),
+        }
+    }
+
+    /// Decodes the value stored in `UART`
+    fn from_u8(value: u8) -> Self {
+        match value {
+            #[cfg(feature = "mini-uart")]
+            v if v == EarlyUart::MiniUart as u8 => EarlyUart::MiniUart,
+            _ => EarlyUart::Pl011,
+        }
+    }
+}
+
+/// Base address of the early console's UART, or `NONE`
+static BASE: AtomicUsize = AtomicUsize::new(match board::EARLYCON {
+    Some((_, base)) => base,
+    None => NONE,
+});
+
+/// Model of the early console's UART, an `EarlyUart`
+static UART: AtomicU8 = AtomicU8::new(match board::EARLYCON {
+    Some((uart, _)) => uart as u8,
+    None => EarlyUart::Pl011 as u8,
+});
+
+/// Set when output was dropped for lack of an early console
+static DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// Selects the early console from `earlycon` on the kernel command line
+///
+/// Must run after the DTB has been parsed and before drivers are initialized. If output was
+/// dropped before, the kernel log is flushed to the newly selected early console.
+pub fn init() {
+    let Some((uart, base)) = command_line() else {
+        return;
+    };
+    UART.store(uart as u8, Ordering::Relaxed);
+    BASE.store(base, Ordering::Release);
+    if base == NONE {
+        return;
+    }
+    if DROPPED.swap(false, Ordering::AcqRel) {
+        super::replay(&mut Writer);
+    }
+    crate::println!("console: early console at {:#x}", base);
+}
+
+/// Parses `earlycon`, returning `None` if it is absent or invalid
+fn command_line() -> Option<(EarlyUart, usize)> {
+    let flag = dtb::bootargs()?
+        .split_ascii_whitespace()
+        .any(|arg| arg == "earlycon");
+    if flag {
+        return stdout_base();
+    }
+    let value = dtb::bootarg("earlycon")?;
+    if value == "off" {
+        return Some((EarlyUart::Pl011, NONE));
+    }
+    let mut options = value.split(',');
+    let uart = EarlyUart::from_name(options.next()?)?;
+    let addr = match options.next()? {
+        "mmio32" => options.next()?,
+        addr => addr,
+    };
+    let addr = addr.strip_prefix("0x").unwrap_or(addr);
+    Some((uart, usize::from_str_radix(addr, 16).ok()?))
+}
+
+/// Returns the UART of the `/chosen/stdout-path` node, if it is a supported one
+fn stdout_base() -> Option<(EarlyUart, usize)> {
+    let (dev, _) = dtb::stdout_path()?;
+    let compatible = dev.find_property("compatible")?.as_str()?;
+    let uart = EarlyUart::from_compatible(compatible)?;
+    Some((uart, dev.reg(0)?.base))
+}
+
+/// Writes `bytes` to the early console, or records that they were dropped
+pub fn write(bytes: &[u8]) {
+    match BASE.load(Ordering::Acquire) {
+        NONE => DROPPED.store(true, Ordering::Release),
+        base => {
+            let uart = EarlyUart::from_u8(UART.load(Ordering::Relaxed));
+            bytes.iter().for_each(|&c| uart.putchar(base, c));
+        }
+    }
+}
+
+/// Retires the early console in favor of `con`, the first console registered
+///
+/// Flushes the kernel log to `con` if some output never made it to an early console.
+pub fn handover(con: &'static dyn Console) {
+    BASE.store(NONE, Ordering::Release);
+    if DROPPED.swap(false, Ordering::AcqRel) {
+        super::replay(&mut super::DeviceWriter(con));
+    }
+}
+
+/// Writer for the early console
+struct Writer;
+
+impl core::fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write(s.as_bytes());
+        Ok(())
+    }
+}
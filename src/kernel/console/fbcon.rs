@@ -0,0 +1,353 @@
+//! Framebuffer console
+//!
+//! Renders the console output as text on a display, next to the active console rather than
+//! instead of it: the console module mirrors everything written to the system console here.
+//! Display drivers (virtio-gpu) `register` a linear 32-bit XRGB framebuffer and a `flush`
+//! callback making a rectangle of it visible.
+//!
+//! ## Design
+//!
+//! - Characters are drawn from the built-in 8x8 font, doubled on displays at least 1024 pixels
+//!   wide. Text wraps at the right edge and the screen scrolls up when the last line is full.
+//! - `\r`, `\n`, `\t` and backspace move the cursor. Of the escape sequences (see `ansi`), the
+//!   ones the kernel emits are honoured: colors (`ESC [ n m`, the eight basic ones and their
+//!   bright variants), cursor movement (`ESC [ n A/B/C/D`, `ESC [ row ; col H`) and erasing
+//!   (`ESC [ n K`, `ESC [ n J`). The others are skipped.
+//! - Drawing happens in the framebuffer; the rows touched by a `write` are flushed at its end,
+//!   so a line printed at once costs one flush.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+
+use super::font::{FIRST, GLYPH_SIZE, GLYPHS};
+
+/// Foreground and background colors, as XRGB
+const FOREGROUND: u32 = 0x00aa_aaaa;
+const BACKGROUND: u32 = 0x0000_0000;
+
+/// The colors selected by SGR 30 to 37, then their bright variants (90 to 97), as in VGA text mode
+const PALETTE: [u32; 16] = [
+    0x0000_0000,
+    0x00aa_0000,
+    0x0000_aa00,
+    0x00aa_5500,
+    0x0000_00aa,
+    0x00aa_00aa,
+    0x0000_aaaa,
+    0x00aa_aaaa,
+    0x0055_5555,
+    0x00ff_5555,
+    0x0055_ff55,
+    0x00ff_ff55,
+    0x0055_55ff,
+    0x00ff_55ff,
+    0x0055_ffff,
+    0x00ff_ffff,
+];
+
+/// Displays at least this wide get glyphs at twice their size
+const SCALE_WIDTH: usize = 1024;
+
+/// Columns between tab stops
+const TAB_WIDTH: usize = 8;
+
+/// Longest parameter string of an escape sequence that is kept
+const MAX_ESCAPE_PARAMS: usize = 8;
+
+/// A linear framebuffer with 32-bit XRGB pixels
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    pub addr: usize,
+    pub width: usize,
+    pub height: usize,
+    /// Bytes from the start of a line to the start of the next
+    pub stride: usize,
+}
+
+/// A display the console can draw on
+pub trait Display: Sync {
+    /// Returns the framebuffer of the display
+    fn framebuffer(&self) -> Framebuffer;
+
+    /// Makes the pixels of the given rectangle of the framebuffer visible
+    fn flush(&self, x: usize, y: usize, width: usize, height: usize);
+}
+
+/// Where the parser is in an escape sequence
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// After `ESC`
+    Start,
+    /// After `ESC [`, collecting parameters
+    Csi,
+}
+
+struct FbCon {
+    display: Option<&'static dyn Display>,
+    fb: Framebuffer,
+    /// Size of a character cell, in pixels
+    cell: usize,
+    cols: usize,
+    rows: usize,
+    /// Cursor position, in cells
+    col: usize,
+    row: usize,
+    /// Color the next characters are drawn in
+    color: u32,
+    escape: Escape,
+    params: [u8; MAX_ESCAPE_PARAMS],
+    params_len: usize,
+    /// Rows drawn since the last flush, as a `[first, last]` range
+    dirty: Option<(usize, usize)>,
+}
+
+static FBCON: Mutex<FbCon> = Mutex::new(FbCon {
+    display: None,
+    fb: Framebuffer {
+        addr: 0,
+        width: 0,
+        height: 0,
+        stride: 0,
+    },
+    cell: GLYPH_SIZE,
+    cols: 0,
+    rows: 0,
+    col: 0,
+    row: 0,
+    color: FOREGROUND,
+    escape: Escape::None,
+    params: [0; MAX_ESCAPE_PARAMS],
+    params_len: 0,
+    dirty: None,
+});
+
+impl FbCon {
+    fn pixel(&self, x: usize, y: usize) -> *mut u32 {
+        (self.fb.addr + y * self.fb.stride + x * 4) as *mut u32
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        for y in y..y + height {
+            for x in x..x + width {
+                unsafe { self.pixel(x, y).write_volatile(color) };
+            }
+        }
+    }
+
+    fn mark_dirty(&mut self, first: usize, last: usize) {
+        self.dirty = Some(match self.dirty {
+            Some((a, b)) => (a.min(first), b.max(last)),
+            None => (first, last),
+        });
+    }
+
+    /// Blanks the cells of row `row` from column `from` to the end of the line
+    fn clear_line(&mut self, row: usize, from: usize) {
+        self.clear_cells(row, from, self.cols);
+    }
+
+    /// Blanks the cells of row `row` from column `from` to column `to` (excluded)
+    fn clear_cells(&mut self, row: usize, from: usize, to: usize) {
+        let cell = self.cell;
+        let to = to.min(self.cols);
+        if from >= to {
+            return;
+        }
+        self.fill(
+            from * cell,
+            row * cell,
+            (to - from) * cell,
+            cell,
+            BACKGROUND,
+        );
+        self.mark_dirty(row, row);
+    }
+
+    fn clear_screen(&mut self) {
+        let (width, height) = (self.fb.width, self.fb.height);
+        self.fill(0, 0, width, height, BACKGROUND);
+        self.col = 0;
+        self.row = 0;
+        self.mark_dirty(0, self.rows - 1);
+    }
+
+    fn draw(&mut self, c: u8) {
+        let glyph = match c {
+            FIRST..=b'~' => &GLYPHS[(c - FIRST) as usize],
+            _ => &GLYPHS[(b'?' - FIRST) as usize],
+        };
+        let scale = self.cell / GLYPH_SIZE;
+        let (x0, y0) = (self.col * self.cell, self.row * self.cell);
+        for (y, bits) in glyph.iter().enumerate() {
+            for x in 0..GLYPH_SIZE {
+                let color = if bits & (1 << x) != 0 {
+                    self.color
+                } else {
+                    BACKGROUND
+                };
+                self.fill(x0 + x * scale, y0 + y * scale, scale, scale, color);
+            }
+        }
+        self.mark_dirty(self.row, self.row);
+    }
+
+    /// Moves the cursor to the next line, scrolling if it is on the last one
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+        let line = self.cell * self.fb.stride;
+        unsafe {
+            core::ptr::copy(
+                (self.fb.addr + line) as *const u8,
+                self.fb.addr as *mut u8,
+                (self.rows - 1) * line,
+            );
+        }
+        self.clear_line(self.row, 0);
+        self.mark_dirty(0, self.rows - 1);
+    }
+
+    /// Returns the numeric parameter at `index` of the escape sequence, if given
+    fn param(&self, index: usize) -> Option<usize> {
+        let params = &self.params[..self.params_len];
+        let param = params.split(|&c| c == b';').nth(index)?;
+        core::str::from_utf8(param).ok()?.parse().ok()
+    }
+
+    /// Applies the "select graphic rendition" parameter `n`
+    fn select_rendition(&mut self, n: usize) {
+        match n {
+            0 | 39 => self.color = FOREGROUND,
+            30..=37 => self.color = PALETTE[n - 30],
+            90..=97 => self.color = PALETTE[n - 90 + 8],
+            // Bold, underline, background colors, ...
+            _ => {}
+        }
+    }
+
+    /// Runs the escape sequence ending with `command`
+    fn escape_command(&mut self, command: u8) {
+        // A count of 0 counts as 1, as on a VT100
+        let count = self.param(0).unwrap_or(1).max(1);
+        let last_col = self.cols.saturating_sub(1);
+        match command {
+            b'm' => {
+                let count = self.params[..self.params_len].split(|&c| c == b';').count();
+                for index in 0..count {
+                    self.select_rendition(self.param(index).unwrap_or(0));
+                }
+            }
+            b'A' => self.row = self.row.saturating_sub(count),
+            b'B' => self.row = (self.row + count).min(self.rows.saturating_sub(1)),
+            b'C' => self.col = (self.col + count).min(last_col),
+            b'D' => self.col = self.col.min(last_col).saturating_sub(count),
+            b'H' | b'f' => {
+                // 1-based, the top left corner by default
+                self.row = (self.param(0).unwrap_or(1).max(1) - 1).min(self.rows - 1);
+                self.col = (self.param(1).unwrap_or(1).max(1) - 1).min(last_col);
+            }
+            b'K' => match self.param(0).unwrap_or(0) {
+                0 => self.clear_line(self.row, self.col),
+                1 => self.clear_cells(self.row, 0, self.col + 1),
+                2 => self.clear_line(self.row, 0),
+                _ => {}
+            },
+            b'J' => match self.param(0).unwrap_or(0) {
+                0 => {
+                    self.clear_line(self.row, self.col);
+                    for row in self.row + 1..self.rows {
+                        self.clear_line(row, 0);
+                    }
+                }
+                2 => self.clear_screen(),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn putchar(&mut self, c: u8) {
+        match self.escape {
+            Escape::Start => {
+                self.escape = if c == b'[' { Escape::Csi } else { Escape::None };
+                self.params_len = 0;
+                return;
+            }
+            Escape::Csi => {
+                if (0x40..=0x7e).contains(&c) {
+                    self.escape = Escape::None;
+                    self.escape_command(c);
+                } else if self.params_len < MAX_ESCAPE_PARAMS {
+                    self.params[self.params_len] = c;
+                    self.params_len += 1;
+                }
+                return;
+            }
+            Escape::None => {}
+        }
+        match c {
+            0x1b => self.escape = Escape::Start,
+            b'\n' => self.newline(),
+            b'\r' => self.col = 0,
+            0x08 => self.col = self.col.saturating_sub(1),
+            b'\t' => {
+                self.col = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                if self.col >= self.cols {
+                    self.newline();
+                }
+            }
+            c if c < b' ' || c == 0x7f => {}
+            c => {
+                if self.col == self.cols {
+                    self.newline();
+                }
+                self.draw(c);
+                self.col += 1;
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let (Some(display), Some((first, last))) = (self.display, self.dirty.take()) {
+            let cell = self.cell;
+            display.flush(0, first * cell, self.fb.width, (last - first + 1) * cell);
+        }
+    }
+}
+
+/// Starts drawing the console on `display`, which replaces the previous one, if any
+pub fn register(display: &'static dyn Display) {
+    let fb = display.framebuffer();
+    FBCON.lock_irqsafe(|con| {
+        con.display = Some(display);
+        con.fb = fb;
+        con.cell = if fb.width >= SCALE_WIDTH {
+            2 * GLYPH_SIZE
+        } else {
+            GLYPH_SIZE
+        };
+        con.cols = fb.width / con.cell;
+        con.rows = fb.height / con.cell;
+        con.escape = Escape::None;
+        con.color = FOREGROUND;
+        con.clear_screen();
+        con.flush();
+    });
+}
+
+/// Draws `bytes` on the display, if one is registered
+///
+/// The output is dropped if the console is busy, e.g. when a panic interrupts a write: the
+/// active console has it anyway.
+pub fn write(bytes: &[u8]) {
+    FBCON.try_lock_irqsafe(|con| {
+        if con.display.is_none() || con.rows == 0 {
+            return;
+        }
+        bytes.iter().for_each(|&c| con.putchar(c));
+        con.flush();
+    });
+}
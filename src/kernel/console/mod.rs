@@ -0,0 +1,772 @@
+//! System console and console device registry
+//!
+//! Character devices able to act as a console (UARTs, for now) implement the `Console` trait and
+//! register themselves here under a tty-style name (`ttyAMA0`, `ttyAMA1`, ...). One of them is the
+//! *active* console: the one `print!`/`println!` write to and `getchar` reads from. The others stay
+//! reachable by name, e.g. for a debug channel.
+//!
+//! ## Console Selection
+//!
+//! The DTB tells the kernel which device to use as its console through `/chosen/stdout-path`,
+//! e.g. `serial0:115200n8`. The path (or alias) selects a device node and the optional suffix
+//! after the `:` describes the line settings. This module records that choice before drivers are
+//! probed, so a UART driver finding several matching nodes binds the console to the right one
+//! and configures it with the requested settings. Without a `stdout-path`, the first registered
+//! device becomes the console.
+//!
+//! A `console=<name>` parameter on the kernel command line (e.g. `console=hvc0`) overrides the
+//! DTB: the device registered under that name becomes the active console as soon as it shows up.
+//! Until then the console is chosen as above, so early messages still have somewhere to go, and
+//! the kernel log (see `log`) is replayed on the new console when it takes over.
+//!
+//! Until a console is registered, output goes to the early console (see `earlycon`), which takes
+//! its UART address from the kernel command line rather than from the device tree.
+//!
+//! ## Console File
+//!
+//! `CONSOLE` exposes the consoles through the VFS `Inode` and `File` traits. Inode 0 is the active
+//! console, which is what the standard input and outputs of user tasks are opened on; every
+//! registered device also gets its own inode and a `/dev` entry under its name.
+//!
+//! ## Foreground Task
+//!
+//! The user task last started by `loader::exec` is the console's foreground task. Reads of inode
+//! 0 go through the line discipline (`tty`), which also turns Ctrl-C on the active console into
+//! `SIGINT` for the foreground task instead of queueing the byte; a console read the task is
+//! sleeping in then returns `FsError::Interrupted`.
+//!
+//! ## Mirror
+//!
+//! A console can be attached as the *mirror* of the active one, e.g. a network session (see
+//! `net::telnet`): everything written to the active console is written to it as well, and what
+//! it receives is read as if typed on the active console, by the shell and by reads of inode 0.
+//! Its driver calls `mirror_input_ready` after receiving bytes, which wakes the reader.
+//!
+//! ## Log Levels
+//!
+//! Kernel messages reporting a failure are printed with `pr_err!` (in red) or `pr_warn!` (in
+//! yellow) rather than `println!`, and `pr_info!` marks the informational ones. The colors are
+//! ANSI escape sequences (see `ansi`), which can be turned off.
+//!
+//! ## Framebuffer Console
+//!
+//! When a display driver has registered a framebuffer with `fbcon`, everything written to the
+//! system console (kernel messages, the shell, user tasks writing to inode 0) is also drawn
+//! there. `fbcon` is an output mirror, not a console device: input still comes from the active
+//! console.
+
+pub mod ansi;
+pub mod earlycon;
+pub mod fbcon;
+mod font;
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::ipc::channel::Receiver;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::device;
+use crate::kernel::dtb;
+use crate::kernel::fs::devfs;
+use crate::kernel::fs::vfs::{self, FileKind, FsError, Ino, Node, Stat};
+use crate::kernel::log::{self, LogLevel};
+use crate::kernel::notifier::{Deadline, NotifierBlock, NotifyResult};
+use crate::kernel::power::{self, RebootEvent};
+use crate::kernel::sched::{self, TaskId};
+use crate::kernel::signal;
+use crate::kernel::tty;
+use crate::println;
+
+/// Maximum number of console devices that can be registered
+const MAX_CONSOLES: usize = 8;
+
+/// Value of `ACTIVE` while no console is active
+const NO_CONSOLE: usize = usize::MAX;
+
+/// Value of `FOREGROUND` while there is no foreground task
+const NO_TASK: usize = usize::MAX;
+
+/// Capacity of a console's input channel, in bytes
+pub const INPUT_QUEUE_SIZE: usize = 64;
+
+/// Receiving end of a console's input channel, see `Console::input`
+pub type InputReceiver = Receiver<'static, u8, INPUT_QUEUE_SIZE>;
+
+/// A character device usable as a console
+pub trait Console: Sync {
+    /// tty-style name of the device (e.g. `ttyAMA0`)
+    fn name(&self) -> &'static str;
+
+    /// Writes a single byte, blocking until the device accepts it
+    fn putchar(&self, c: u8);
+
+    /// Reads a single byte if one is available
+    fn getchar(&self) -> Option<u8>;
+
+    /// Reads a single byte, waiting until one is available
+    ///
+    /// The default implementation polls `getchar`, idling the CPU until the next interrupt in
+    /// between. Devices with a receive interrupt should put the calling task to sleep instead.
+    fn getchar_blocking(&self) -> u8 {
+        loop {
+            if let Some(c) = self.getchar() {
+                return c;
+            }
+            unsafe {
+                core::arch::asm!("wfi", options(nostack, nomem, preserves_flags));
+            }
+        }
+    }
+
+    /// Claims the channel delivering received bytes straight from the interrupt handler
+    ///
+    /// Returns `None` if the device has no such channel or it is already claimed. While the
+    /// channel is claimed, `getchar` receives nothing.
+    fn input(&'static self) -> Option<InputReceiver> {
+        None
+    }
+
+    /// Waits until every pending byte has been transmitted
+    ///
+    /// Returns false if `deadline` expired first.
+    fn flush(&self, deadline: &Deadline) -> bool;
+}
+
+/// Errors returned when registering a console device
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConsoleError {
+    /// The registry is full
+    NoSpace,
+    /// Another console is already the mirror, or the console is the active one
+    Busy,
+}
+
+/// Registered console devices; entries are never removed, so indices stay valid
+static CONSOLES: Mutex<[Option<&'static dyn Console>; MAX_CONSOLES]> =
+    Mutex::new([None; MAX_CONSOLES]);
+
+/// Index in `CONSOLES` of the active console, or `NO_CONSOLE`
+static ACTIVE: AtomicUsize = AtomicUsize::new(NO_CONSOLE);
+
+/// ID of the foreground task, or `NO_TASK`
+static FOREGROUND: AtomicUsize = AtomicUsize::new(NO_TASK);
+
+/// Mirror of the active console, see the module documentation
+static MIRROR: Mutex<Option<&'static dyn Console>> = Mutex::new(None);
+
+/// Set by `mirror_input_ready`, cleared before the mirror's input is read
+static MIRROR_INPUT: AtomicBool = AtomicBool::new(false);
+
+/// Task waiting for input in `recv_input`, or `NO_TASK`
+static READER: AtomicUsize = AtomicUsize::new(NO_TASK);
+
+/// Parity setting of a serial line
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+/// Serial line settings parsed from a `stdout-path` suffix
+///
+/// The format follows the Linux `console=` convention: `<baud><parity><bits><flow>`, where
+/// parity is `n`, `o` or `e`, bits is `5`-`8` and flow is `r` for RTS/CTS. Every part is
+/// optional, e.g. `115200`, `115200n8` and `9600e7r` are all valid.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ConsoleOptions {
+    /// Baud rate
+    pub baud: u32,
+    /// Parity mode
+    pub parity: Parity,
+    /// Number of data bits
+    pub data_bits: u8,
+    /// Hardware (RTS/CTS) flow control
+    pub flow_control: bool,
+}
+
+impl ConsoleOptions {
+    /// Settings used when the DTB doesn't provide any: 115200 8N1, no flow control
+    pub const DEFAULT: Self = Self {
+        baud: 115200,
+        parity: Parity::None,
+        data_bits: 8,
+        flow_control: false,
+    };
+
+    /// Parses an options string such as `115200n8`
+    ///
+    /// Parts that are missing or malformed keep their default value.
+    pub fn parse(options: &str) -> Self {
+        let mut result = Self::DEFAULT;
+        let bytes = options.as_bytes();
+        let digits = bytes.iter().take_while(|b| b.is_ascii_digit()).count();
+        if let Some(baud) = options[..digits].parse::<u32>().ok().filter(|&b| b != 0) {
+            result.baud = baud;
+        }
+
+        let mut rest = bytes[digits..].iter();
+        match rest.next() {
+            Some(b'o') => result.parity = Parity::Odd,
+            Some(b'e') => result.parity = Parity::Even,
+            _ => {}
+        }
+        if let Some(bits) = rest.next().filter(|b| (b'5'..=b'8').contains(b)) {
+            result.data_bits = bits - b'0';
+        }
+        result.flow_control = rest.next() == Some(&b'r');
+        result
+    }
+}
+
+impl Default for ConsoleOptions {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// The device selected by `stdout-path` and its line settings
+#[derive(Clone, Copy)]
+struct StdoutBinding {
+    dev: *const device::PlatformDevice,
+    options: ConsoleOptions,
+}
+
+/// Console selected from the DTB, if any
+static STDOUT: Mutex<Option<StdoutBinding>> = Mutex::new(None);
+
+/// Name of the console selected by `console=` on the command line, if any
+static PREFERRED: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Records the console selected by `console=` and `/chosen/stdout-path`
+///
+/// Must run after the DTB has been parsed and before drivers are initialized.
+pub fn select_stdout() {
+    ansi::init();
+    earlycon::init();
+    if let Some(name) = dtb::bootarg("console") {
+        // Line settings after a comma (`ttyAMA0,115200n8`) are left to `stdout-path`
+        let name = name.split(',').next().unwrap_or(name);
+        PREFERRED.lock_irqsafe(|preferred| *preferred = Some(name));
+        println!("console: command line selects {}", name);
+    }
+    let Some((dev, options)) = dtb::stdout_path() else {
+        return;
+    };
+    let options = options.map(ConsoleOptions::parse).unwrap_or_default();
+    STDOUT.lock_irqsafe(|stdout| {
+        *stdout = Some(StdoutBinding {
+            dev: dev as *const device::PlatformDevice,
+            options,
+        })
+    });
+    println!("console: stdout-path selects {} ({:?})", dev.name, options);
+}
+
+/// Points the console selected by `select_stdout` to its entry in the current device table
+///
+/// Called when the unflattened device tree replaces the boot table.
+pub fn rebind_stdout() {
+    let dev = dtb::stdout_path().map(|(dev, _)| dev as *const device::PlatformDevice);
+    STDOUT.lock_irqsafe(|stdout| {
+        if let (Some(binding), Some(dev)) = (stdout.as_mut(), dev) {
+            binding.dev = dev;
+        }
+    });
+}
+
+/// Returns the device selected as the console, if the DTB chose one
+pub fn stdout_device() -> Option<&'static device::PlatformDevice> {
+    STDOUT.lock_irqsafe(|stdout| stdout.map(|binding| unsafe { &*binding.dev }))
+}
+
+/// Returns the line settings to use if `dev` is the device selected as the console
+pub fn options_for(dev: &device::PlatformDevice) -> Option<ConsoleOptions> {
+    STDOUT.lock_irqsafe(|stdout| {
+        stdout
+            .filter(|binding| core::ptr::eq(binding.dev, dev))
+            .map(|binding| binding.options)
+    })
+}
+
+/// Returns true if a console driver may bind `dev` as the system console
+///
+/// Any device is accepted when the DTB doesn't select one, otherwise only the selected device.
+pub fn accepts(dev: &device::PlatformDevice) -> bool {
+    STDOUT.lock_irqsafe(|stdout| stdout.is_none_or(|binding| core::ptr::eq(binding.dev, dev)))
+}
+
+/// Registers a console device discovered from the DTB node `dev`
+///
+/// The device becomes the active console if it is the one selected by `console=`. Otherwise it
+/// does if it is the one selected by `stdout-path`, or if the DTB doesn't select one and no
+/// console is active yet, unless the console selected by `console=` is already active.
+pub fn register(
+    con: &'static dyn Console,
+    dev: &device::PlatformDevice,
+) -> Result<(), ConsoleError> {
+    let selected = options_for(dev).is_some() || (accepts(dev) && active().is_none());
+    add(con, selected)
+}
+
+/// Registers a console device the DTB doesn't describe, such as the keyboard console `tty0`
+///
+/// The device only becomes the active console if it is the one selected by `console=`.
+pub fn register_virtual(con: &'static dyn Console) -> Result<(), ConsoleError> {
+    add(con, false)
+}
+
+/// Adds `con` to the registry, making it the active console if `selected` and `console=` agree
+fn add(con: &'static dyn Console, selected: bool) -> Result<(), ConsoleError> {
+    let index = CONSOLES.lock_irqsafe(|consoles| {
+        let index = consoles
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or(ConsoleError::NoSpace)?;
+        consoles[index] = Some(con);
+        Ok(index)
+    })?;
+
+    if index == 0 {
+        let _ = power::register_reboot_notifier(NotifierBlock {
+            name: "console",
+            priority: power::PRIO_CONSOLE,
+            notifier_call: reboot_notify,
+        });
+    }
+
+    let activate = match PREFERRED.lock_irqsafe(|preferred| *preferred) {
+        Some(name) if con.name() == name => true,
+        Some(name) => selected && active().is_none_or(|active| active.name() != name),
+        None => selected,
+    };
+    if activate {
+        let previous = active();
+        ACTIVE.store(index, Ordering::Release);
+        // The new console missed what the previous one printed
+        match previous {
+            Some(_) => replay(&mut DeviceWriter(con)),
+            None => earlycon::handover(con),
+        }
+    }
+    if let Err(e) = devfs::register(con.name(), CONSOLE.node_of(index)) {
+        println!("console: no /dev entry for {}: {:?}", con.name(), e);
+    }
+    Ok(())
+}
+
+/// Writes the kernel log to `w`, up to the messages already printed
+fn replay(w: &mut impl core::fmt::Write) {
+    let end = log::ringbuf::next_seq();
+    let mut seq = log::ringbuf::first_seq();
+    let mut text = [0; log::ringbuf::MAX_RECORD_LEN];
+    while let Some(record) = log::ringbuf::read(seq, &mut text).filter(|r| r.seq < end) {
+        let _ = log::write_record(w, &record, &text[..record.len]);
+        seq = record.seq + 1;
+    }
+}
+
+/// Returns the registered console named `name`
+pub fn find(name: &str) -> Option<&'static dyn Console> {
+    CONSOLES.lock_irqsafe(|consoles| {
+        consoles
+            .iter()
+            .flatten()
+            .find(|con| con.name() == name)
+            .copied()
+    })
+}
+
+/// Returns the active console, if one has been registered
+///
+/// Doesn't spin on the registry lock, so it is safe to call from the panic path; `None` is
+/// returned if the lock is held.
+pub fn active() -> Option<&'static dyn Console> {
+    let index = ACTIVE.load(Ordering::Acquire);
+    if index == NO_CONSOLE {
+        return None;
+    }
+    CONSOLES
+        .try_lock_irqsafe(|consoles| consoles[index])
+        .flatten()
+}
+
+/// Reads a single byte from the active console
+pub fn getchar() -> Option<u8> {
+    active()?.getchar()
+}
+
+/// Reads a single byte from the active console, waiting until one is available
+///
+/// Waits for a console to be registered if there is none yet.
+pub fn getchar_blocking() -> u8 {
+    loop {
+        if let Some(con) = active() {
+            return con.getchar_blocking();
+        }
+        unsafe {
+            core::arch::asm!("wfi", options(nostack, nomem, preserves_flags));
+        }
+    }
+}
+
+/// Claims the input channel of the active console, see `Console::input`
+pub fn input() -> Option<InputReceiver> {
+    active()?.input()
+}
+
+/// Attaches `con` as the mirror of the active console
+pub fn set_mirror(con: &'static dyn Console) -> Result<(), ConsoleError> {
+    if active().is_some_and(|active| active.name() == con.name()) {
+        return Err(ConsoleError::Busy);
+    }
+    MIRROR.lock_irqsafe(|mirror| match mirror {
+        Some(_) => Err(ConsoleError::Busy),
+        None => {
+            *mirror = Some(con);
+            Ok(())
+        }
+    })
+}
+
+/// Detaches the mirror, if it is `con`
+pub fn clear_mirror(con: &'static dyn Console) {
+    MIRROR.lock_irqsafe(|mirror| {
+        if mirror.is_some_and(|m| m.name() == con.name()) {
+            *mirror = None;
+        }
+    });
+}
+
+/// Returns the mirror, if one is attached
+///
+/// Like `active`, doesn't spin on the lock; `None` is returned if it is held.
+fn mirror() -> Option<&'static dyn Console> {
+    MIRROR.try_lock_irqsafe(|mirror| *mirror).flatten()
+}
+
+/// Writes `bytes` to the mirror and the framebuffer, which follow the active console
+fn write_mirrors(bytes: &[u8]) {
+    if let Some(mirror) = mirror() {
+        bytes.iter().for_each(|&c| mirror.putchar(c));
+    }
+    fbcon::write(bytes);
+}
+
+/// Tells the task reading the active console that the mirror received bytes
+pub fn mirror_input_ready() {
+    MIRROR_INPUT.store(true, Ordering::Release);
+    let reader = READER.load(Ordering::Acquire);
+    if reader != NO_TASK {
+        sched::wake(reader);
+    }
+}
+
+/// Receives the next byte typed on the active console, whose input channel is `input`, or on
+/// the mirror, sleeping until there is one
+///
+/// Returns `None` once `interrupted` returns true; whatever makes it true must also wake the
+/// task, as for `Receiver::recv_interruptible`.
+pub fn recv_input(input: &mut InputReceiver, interrupted: impl Fn() -> bool) -> Option<u8> {
+    loop {
+        // Cleared first, so bytes arriving from now on leave it set
+        MIRROR_INPUT.store(false, Ordering::Release);
+        if let Some(c) = mirror().and_then(|mirror| mirror.getchar()) {
+            return Some(c);
+        }
+        READER.store(sched::current().unwrap_or(NO_TASK), Ordering::Release);
+        let c = input.recv_interruptible(|| interrupted() || MIRROR_INPUT.load(Ordering::Acquire));
+        READER.store(NO_TASK, Ordering::Release);
+        match c {
+            Some(c) => return Some(c),
+            None if interrupted() => return None,
+            None => {}
+        }
+    }
+}
+
+/// Receives the next byte typed on the active console or its mirror, if one is waiting
+pub fn try_recv_input(input: &mut InputReceiver) -> Option<u8> {
+    mirror()
+        .and_then(|mirror| mirror.getchar())
+        .or_else(|| input.try_recv())
+}
+
+/// Makes user task `id` the foreground task, see the module documentation
+pub fn set_foreground(id: TaskId) {
+    FOREGROUND.store(id, Ordering::Release);
+}
+
+/// Returns the foreground task, if any
+pub fn foreground() -> Option<TaskId> {
+    match FOREGROUND.load(Ordering::Acquire) {
+        NO_TASK => None,
+        id => Some(id),
+    }
+}
+
+/// Stops task `id` from being the foreground task, when it exits
+pub fn release_foreground(id: TaskId) {
+    let _ = FOREGROUND.compare_exchange(id, NO_TASK, Ordering::AcqRel, Ordering::Acquire);
+}
+
+/// Handles the signal characters in the input of console `name`, see `tty::intercept`
+///
+/// Called by the drivers of the active console and of the mirror for every received byte, from
+/// interrupt context. Returns true if the byte was consumed, and must not be queued.
+pub fn intercept(name: &str, c: u8) -> bool {
+    let reads_active =
+        |con: Option<&'static dyn Console>| con.is_some_and(|con| con.name() == name);
+    (reads_active(active()) || reads_active(mirror())) && tty::intercept(c)
+}
+
+/// Writes a single byte to the active console, or to the early console if there is none
+pub fn putchar(c: u8) {
+    match active() {
+        Some(con) => con.putchar(c),
+        None => earlycon::write(&[c]),
+    }
+    write_mirrors(&[c]);
+}
+
+/// The consoles as files
+pub struct ConsoleFile;
+
+/// The console files, see the module documentation
+pub static CONSOLE: ConsoleFile = ConsoleFile;
+
+impl ConsoleFile {
+    /// Returns the VFS node of the active console
+    pub fn node(&'static self) -> Node {
+        Node {
+            inode: self,
+            ino: 0,
+        }
+    }
+
+    /// Returns the VFS node of the console registered at `index`
+    fn node_of(&'static self, index: usize) -> Node {
+        Node {
+            inode: self,
+            ino: index as Ino + 1,
+        }
+    }
+
+    /// Returns the console behind inode `ino`
+    fn device(&self, ino: Ino) -> Result<&'static dyn Console, FsError> {
+        let console = match ino {
+            0 => active(),
+            _ => {
+                CONSOLES.lock_irqsafe(|consoles| consoles.get(ino as usize - 1).copied().flatten())
+            }
+        };
+        console.ok_or(FsError::NotFound)
+    }
+}
+
+impl vfs::Inode for ConsoleFile {
+    fn stat(&self, ino: Ino) -> Result<Stat, FsError> {
+        self.device(ino)?;
+        Ok(Stat {
+            kind: FileKind::CharDevice,
+            size: 0,
+            mode: 0o620,
+        })
+    }
+
+    fn open(&self, _ino: Ino) -> Result<&'static dyn vfs::File, FsError> {
+        Ok(&CONSOLE)
+    }
+}
+
+impl vfs::File for ConsoleFile {
+    /// Reads the active console through the line discipline (see `tty`), or waits for a byte
+    /// of another console and returns it with the bytes already received after it
+    ///
+    /// Whoever holds the input channel (e.g. the shell) gets the bytes first. Waiting on the
+    /// channel ends with `FsError::Interrupted` when a signal is pending.
+    fn read(&self, ino: Ino, _offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let con = self.device(ino)?;
+        let mut input = con.input();
+        // The mirror's input is only merged into the active console's
+        let mirror = if ino == 0 { mirror() } else { None };
+        let mut getc = |wait: bool| match (input.as_mut(), wait) {
+            (Some(input), true) if ino == 0 => recv_input(input, signal::has_pending)
+                .map(Some)
+                .ok_or(FsError::Interrupted),
+            (Some(input), true) => input
+                .recv_interruptible(signal::has_pending)
+                .map(Some)
+                .ok_or(FsError::Interrupted),
+            (Some(input), false) => Ok(input
+                .try_recv()
+                .or_else(|| mirror.and_then(|mirror| mirror.getchar()))),
+            (None, true) => Ok(Some(con.getchar_blocking())),
+            (None, false) => Ok(con.getchar()),
+        };
+        if ino == 0 {
+            return tty::read(buf, getc);
+        }
+        let mut len = 0;
+        while len < buf.len() {
+            let Some(byte) = getc(len == 0)? else {
+                break;
+            };
+            buf[len] = byte;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, ino: Ino, _offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let con = self.device(ino)?;
+        buf.iter().for_each(|&c| con.putchar(c));
+        if ino == 0 {
+            write_mirrors(buf);
+        }
+        Ok(buf.len())
+    }
+
+    /// Terminal requests, on the active console only
+    fn ioctl(&self, ino: Ino, cmd: u32, arg: usize) -> Result<usize, FsError> {
+        match ino {
+            0 => tty::ioctl(cmd, arg),
+            _ => Err(FsError::BadIoctl),
+        }
+    }
+
+    fn seekable(&self) -> bool {
+        false
+    }
+}
+
+/// Reboot notifier: make sure the last messages are on the wire before the system resets
+fn reboot_notify(_event: RebootEvent, deadline: &Deadline) -> NotifyResult {
+    let consoles = CONSOLES.lock_irqsafe(|consoles| *consoles);
+    let mut result = NotifyResult::Ok;
+    for con in consoles.iter().flatten() {
+        if !con.flush(deadline) {
+            result = NotifyResult::Timeout;
+        }
+    }
+    result
+}
+
+/// Writer for a single console device
+struct DeviceWriter(&'static dyn Console);
+
+impl core::fmt::Write for DeviceWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        s.bytes().for_each(|c| self.0.putchar(c));
+        Ok(())
+    }
+}
+
+/// Zero-sized writer that implements `core::fmt::Write` for the active console
+///
+/// Unlike `print!`, it leaves the kernel log out.
+pub struct ConsoleWriter;
+
+impl core::fmt::Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // Resolve the console once per string rather than once per byte
+        match active() {
+            Some(con) => s.bytes().for_each(|c| con.putchar(c)),
+            None => earlycon::write(s.as_bytes()),
+        }
+        write_mirrors(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Writer for the console and the kernel log, at a given level
+struct Printer(LogLevel);
+
+impl core::fmt::Write for Printer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        log::ringbuf::write(self.0, s.as_bytes());
+        ConsoleWriter.write_str(s)
+    }
+}
+
+/// Helper function used by the `print!` and `println!` macros
+#[doc(hidden)]
+pub fn _print(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    Printer(LogLevel::Info).write_fmt(args).unwrap();
+}
+
+/// Returns the color messages of `level` are printed in, if any
+fn level_color(level: LogLevel) -> Option<ansi::Color> {
+    match level {
+        LogLevel::Error => Some(ansi::Color::Red),
+        LogLevel::Warning => Some(ansi::Color::Yellow),
+        LogLevel::Info => None,
+    }
+}
+
+/// Helper function used by the `pr_err!`, `pr_warn!` and `pr_info!` macros
+///
+/// Prints the message in the color of `level`, then the newline in the default one.
+#[doc(hidden)]
+pub fn _log(level: LogLevel, args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    let color = level_color(level);
+    if let Some(color) = color {
+        ansi::set_color(color);
+    }
+    let _ = Printer(level).write_fmt(args);
+    if color.is_some() {
+        ansi::reset_color();
+    }
+    let _ = Printer(level).write_str("\n");
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::kernel::console::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => { $crate::print!("\n") };
+    ($fmt:expr) => { $crate::print!(concat!($fmt, "\n")) };
+    ($fmt:expr, $($arg:tt)*) => { $crate::print!(concat!($fmt, "\n"), $($arg)*) };
+}
+
+/// Prints an error message and a newline, see `log::LogLevel`
+#[macro_export]
+macro_rules! pr_err {
+    ($($arg:tt)*) => {
+        $crate::kernel::console::_log(
+            $crate::kernel::log::LogLevel::Error,
+            format_args!($($arg)*),
+        )
+    };
+}
+
+/// Prints a warning and a newline, see `log::LogLevel`
+#[macro_export]
+macro_rules! pr_warn {
+    ($($arg:tt)*) => {
+        $crate::kernel::console::_log(
+            $crate::kernel::log::LogLevel::Warning,
+            format_args!($($arg)*),
+        )
+    };
+}
+
+/// Prints an informational message and a newline, see `log::LogLevel`
+#[macro_export]
+macro_rules! pr_info {
+    ($($arg:tt)*) => {
+        $crate::kernel::console::_log(
+            $crate::kernel::log::LogLevel::Info,
+            format_args!($($arg)*),
+        )
+    };
+}
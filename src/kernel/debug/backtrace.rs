@@ -0,0 +1,77 @@
+//! Stack backtraces
+//!
+//! The kernel is built with frame pointers (`-C force-frame-pointers`): every function pushes a
+//! frame record, the caller's `x29` followed by its return address, and points `x29` at it. The
+//! records form a linked list from the innermost frame outwards, which `print` follows.
+//!
+//! Each record is checked to lie in the kernel image (the boot stack) or in a mapped task stack
+//! before being read, so a corrupted chain ends the backtrace instead of faulting again.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is `dump_backtrace` and the frame record unwinder of `arch/arm64/kernel/stacktrace.c`,
+//! with the functions named from the symbol table (`kallsyms`) when the kernel has one.
+
+use core::arch::asm;
+use core::ptr::addr_of;
+
+use crate::kernel::debug::kallsyms;
+use crate::kernel::mm::{kaslr, kstack};
+use crate::println;
+
+unsafe extern "C" {
+    static __kernel_start: u8;
+    static __stack_top: u8;
+}
+
+/// Maximum number of frames printed
+pub const MAX_DEPTH: usize = 32;
+
+/// Returns true if the frame record at `fp` can be read
+fn valid_record(fp: usize) -> bool {
+    let kernel = addr_of!(__kernel_start) as usize..addr_of!(__stack_top) as usize;
+    fp.is_multiple_of(8)
+        && (kstack::is_stack(fp, 16) || (kernel.contains(&fp) && kernel.contains(&(fp + 15))))
+}
+
+/// Prints a frame of a backtrace, at the link address `addr`
+fn print_address(addr: u64) {
+    match kallsyms::lookup(addr) {
+        Some(symbol) => println!("  [<{:#018x}>] {}", addr, symbol),
+        None => println!("  [<{:#018x}>]", addr),
+    }
+}
+
+/// Prints the return addresses of the call chain starting at `pc`, with frame pointer `fp`
+///
+/// Addresses are printed as linked, to be looked up in the ELF file even with `kaslr=on`.
+pub fn print(pc: u64, fp: u64) {
+    println!("Call trace:");
+    print_address(kaslr::to_link_address(pc));
+    let mut fp = fp as usize;
+    for _ in 0..MAX_DEPTH {
+        if !valid_record(fp) {
+            break;
+        }
+        let (next, lr) = unsafe { (*(fp as *const usize), *((fp + 8) as *const u64)) };
+        if lr == 0 {
+            break;
+        }
+        print_address(kaslr::to_link_address(lr));
+        // The stack grows down, so the callers' records are above
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+}
+
+/// Prints the backtrace of the caller
+#[inline(never)]
+pub fn print_current() {
+    let (pc, fp): (u64, u64);
+    unsafe {
+        asm!("adr {}, .", "mov {}, x29", out(reg) pc, out(reg) fp, options(nostack, nomem));
+    }
+    print(pc, fp);
+}
@@ -0,0 +1,625 @@
+//! GDB remote serial protocol (RSP) stub
+//!
+//! Lets GDB debug the kernel over a dedicated UART, without relying on QEMU's built-in
+//! gdbserver. Start QEMU with a second serial port (e.g. `-serial stdio -serial pty`) and connect
+//! with `target remote /dev/pts/N`.
+//!
+//! ## Design
+//!
+//! The stub claims a PL011 instance that is not the system console. While the kernel runs, the
+//! port's RX interrupt is hooked: a Ctrl-C (`0x03`) or the start of a packet (`$`) makes the
+//! kernel execute a `BRK`, which enters the stub from `do_sync`. Inside the stub, interrupts are
+//! masked and the UART is polled, so the rest of the system is frozen until GDB resumes it.
+//!
+//! Supported packets:
+//! - `?`, `g`/`G`, `p`/`P`: stop reason and register access
+//! - `m`/`M`: memory access
+//! - `c`/`s`: continue and single step (through `MDSCR_EL1.SS`)
+//! - `Z0`/`z0`: software breakpoints, implemented by patching a `BRK` instruction in place
+//! - `D`: detach (removes every breakpoint)
+//!
+//! ## Register Layout
+//!
+//! Without a target description GDB expects the AArch64 general registers in the order
+//! `x0`-`x30`, `sp`, `pc` (64 bits each) and `cpsr` (32 bits), little-endian.
+
+use core::arch::asm;
+
+use crate::drivers::uart::pl011::{self, Pl011};
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::console;
+use crate::kernel::debug;
+use crate::kernel::irq::Regs;
+use crate::kernel::mm::protect;
+use crate::kernel::uaccess;
+use crate::utilities::cache;
+use crate::{initcall, println};
+
+/// Maximum packet payload size, advertised to GDB through `qSupported`
+const MAX_PACKET: usize = 1024;
+
+/// Maximum number of software breakpoints
+const MAX_BREAKPOINTS: usize = 32;
+
+/// `BRK` immediate used by `breakpoint()` and by the RX hook to enter the stub
+const GDB_BREAK_IMM: u64 = 0xf000;
+
+/// `BRK` immediate patched in by `Z0` packets
+const SW_BREAK_IMM: u64 = 0xf001;
+
+/// `BRK #SW_BREAK_IMM` encoding
+const SW_BREAK_INSN: u32 = 0xd420_0000 | ((SW_BREAK_IMM as u32) << 5);
+
+/// Why the stub was entered, reported to GDB as a signal number
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    /// Breakpoint or single step finished (SIGTRAP)
+    Trap,
+    /// GDB sent Ctrl-C (SIGINT)
+    Interrupt,
+    /// GDB started talking to us; it expects replies, not a stop notification
+    Connect,
+}
+
+impl StopReason {
+    /// Signal number reported in the stop reply
+    fn signal(self) -> u8 {
+        match self {
+            StopReason::Interrupt => 2,
+            StopReason::Trap | StopReason::Connect => 5,
+        }
+    }
+}
+
+/// A software breakpoint inserted by GDB
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    /// Address of the patched instruction
+    addr: u64,
+    /// Instruction that was replaced by `BRK`
+    orig: u32,
+}
+
+/// What to do after a packet has been handled
+enum Action {
+    /// Keep reading packets
+    Stay,
+    /// Leave the stub and resume execution
+    Resume,
+}
+
+/// State of the stub
+struct GdbState {
+    /// UART used to talk to GDB, `None` while the stub is not attached
+    port: Option<&'static Pl011>,
+    /// Software breakpoints currently inserted
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// A single step is in progress
+    stepping: bool,
+    /// `SPSR.I` of the stepped context, restored once the step completes
+    step_irq_masked: bool,
+    /// Reason recorded by whoever executed `BRK #GDB_BREAK_IMM`
+    reason: StopReason,
+    /// Byte consumed by the RX hook that must be fed to the packet parser
+    pending: Option<u8>,
+}
+
+static STATE: Mutex<GdbState> = Mutex::new(GdbState {
+    port: None,
+    breakpoints: [None; MAX_BREAKPOINTS],
+    stepping: false,
+    step_irq_masked: false,
+    reason: StopReason::Trap,
+    pending: None,
+});
+
+/// Attaches the stub to the first PL011 instance that is not the system console
+///
+/// Does nothing if the board has a single UART.
+pub fn init() {
+    let console = console::active().map(|con| con.name());
+    let port = (0..pl011::port_count())
+        .filter_map(pl011::port)
+        .find(|port| Some(port.name()) != console);
+    if let Some(port) = port {
+        attach(port);
+    }
+}
+initcall!(Driver, "gdbstub", init);
+
+/// Attaches the stub to `port`
+///
+/// From now on, bytes received on the port are consumed by the stub.
+pub fn attach(port: &'static Pl011) {
+    debug::enable_kernel_debug();
+    STATE.lock_irqsafe(|state| state.port = Some(port));
+    port.set_rx_hook(Some(rx_hook));
+    println!("gdbstub: waiting for GDB on {}", port.name());
+}
+
+/// Returns true if the stub is attached to a UART
+pub fn is_attached() -> bool {
+    STATE.lock_irqsafe(|state| state.port.is_some())
+}
+
+/// Stops the kernel and hands control to GDB, if attached
+pub fn breakpoint() {
+    if is_attached() {
+        trap(StopReason::Trap);
+    }
+}
+
+/// Records `reason` and executes the `BRK` that enters the stub
+fn trap(reason: StopReason) {
+    STATE.lock_irqsafe(|state| state.reason = reason);
+    unsafe {
+        asm!("brk #0xf000", options(nostack));
+    }
+}
+
+/// RX interrupt hook: enters the stub when GDB wants our attention
+fn rx_hook(port: &Pl011) {
+    while let Some(c) = port.poll_getchar() {
+        match c {
+            0x03 => trap(StopReason::Interrupt),
+            b'$' => {
+                STATE.lock_irqsafe(|state| state.pending = Some(c));
+                trap(StopReason::Connect);
+            }
+            // Stray acks and anything else outside a packet are ignored
+            _ => {}
+        }
+    }
+}
+
+/// Handles a `BRK` or software step exception
+///
+/// Returns false if the exception doesn't belong to the stub.
+pub fn handle_exception(regs: &mut Regs, ec: u32) -> bool {
+    let reason = STATE.lock(|state| {
+        state.port?;
+        match ec {
+            debug::EC_BRK64 => match regs.esr & 0xffff {
+                GDB_BREAK_IMM => Some(state.reason),
+                SW_BREAK_IMM => Some(StopReason::Trap),
+                _ => None,
+            },
+            debug::EC_SOFTSTEP_CUR | debug::EC_SOFTSTEP_LOWER if state.stepping => {
+                state.stepping = false;
+                debug::finish_step(regs, state.step_irq_masked);
+                Some(StopReason::Trap)
+            }
+            _ => None,
+        }
+    });
+    let Some(reason) = reason else {
+        return false;
+    };
+    STATE.lock(|state| state.reason = StopReason::Trap);
+
+    if reason != StopReason::Connect {
+        let mut reply = Reply::new();
+        reply.push(b'S');
+        reply.push_hex_u8(reason.signal());
+        send_packet(reply.as_bytes());
+    }
+    let mut buf = [0u8; MAX_PACKET];
+    loop {
+        let len = recv_packet(&mut buf);
+        if let Action::Resume = handle_packet(regs, &buf[..len]) {
+            break;
+        }
+    }
+    true
+}
+
+/// Executes a single packet, sending the reply
+fn handle_packet(regs: &mut Regs, packet: &[u8]) -> Action {
+    let mut reply = Reply::new();
+    let Some((&cmd, args)) = packet.split_first() else {
+        send_packet(b"");
+        return Action::Stay;
+    };
+    match cmd {
+        b'?' => {
+            reply.push(b'S');
+            reply.push_hex_u8(StopReason::Trap.signal());
+        }
+        b'g' => {
+            for n in 0..=30 {
+                reply.push_hex_le(regs.gpr(n), 8);
+            }
+            reply.push_hex_le(regs.sp(), 8);
+            reply.push_hex_le(regs.elr, 8);
+            reply.push_hex_le(regs.spsr, 4);
+        }
+        b'G' => {
+            let mut parser = Parser::new(args);
+            for n in 0..=30 {
+                if let Some(value) = parser.hex_le(8) {
+                    regs.set_gpr(n, value);
+                }
+            }
+            // The stack pointer lives below the frame and can't be changed from here
+            let _ = parser.hex_le(8);
+            if let Some(pc) = parser.hex_le(8) {
+                regs.elr = pc;
+            }
+            if let Some(cpsr) = parser.hex_le(4) {
+                regs.spsr = (regs.spsr & !0xffff_ffff) | cpsr;
+            }
+            reply.push_str(b"OK");
+        }
+        b'p' => match Parser::new(args).hex() {
+            Some(n @ 0..=30) => reply.push_hex_le(regs.gpr(n as usize), 8),
+            Some(31) => reply.push_hex_le(regs.sp(), 8),
+            Some(32) => reply.push_hex_le(regs.elr, 8),
+            Some(33) => reply.push_hex_le(regs.spsr, 4),
+            _ => reply.push_str(b"E01"),
+        },
+        b'P' => {
+            let mut parser = Parser::new(args);
+            let n = parser.hex();
+            parser.expect(b'=');
+            match (n, parser.hex_le(8)) {
+                (Some(n @ 0..=30), Some(value)) => regs.set_gpr(n as usize, value),
+                (Some(32), Some(value)) => regs.elr = value,
+                (Some(33), Some(value)) => regs.spsr = (regs.spsr & !0xffff_ffff) | value,
+                _ => {
+                    send_packet(b"E01");
+                    return Action::Stay;
+                }
+            }
+            reply.push_str(b"OK");
+        }
+        b'm' => {
+            let mut parser = Parser::new(args);
+            let addr = parser.hex();
+            parser.expect(b',');
+            match (addr, parser.hex()) {
+                (Some(addr), Some(len)) if len as usize * 2 <= MAX_PACKET => {
+                    let mut data = [0u8; MAX_PACKET / 2];
+                    let data = &mut data[..len as usize];
+                    // Bad addresses typed in GDB must not take the kernel down
+                    match uaccess::copy_from_nofault(data, addr as usize) {
+                        Ok(()) => data.iter().for_each(|&byte| reply.push_hex_u8(byte)),
+                        Err(_) => reply.push_str(b"E14"),
+                    }
+                }
+                _ => reply.push_str(b"E01"),
+            }
+        }
+        b'M' => {
+            let mut parser = Parser::new(args);
+            let addr = parser.hex();
+            parser.expect(b',');
+            let len = parser.hex();
+            parser.expect(b':');
+            match (addr, len) {
+                (Some(addr), Some(len)) if len as usize <= MAX_PACKET / 2 => {
+                    let mut data = [0u8; MAX_PACKET / 2];
+                    let mut count = 0;
+                    while count < len as usize
+                        && let Some(byte) = parser.hex_byte()
+                    {
+                        data[count] = byte;
+                        count += 1;
+                    }
+                    // Kernel text and rodata are read-only
+                    let written = protect::patch_text(addr as usize, &data[..count]).is_ok()
+                        || uaccess::copy_to_nofault(addr as usize, &data[..count]).is_ok();
+                    if written {
+                        cache::sync_icache_range(addr as usize, count);
+                        reply.push_str(b"OK");
+                    } else {
+                        reply.push_str(b"E14");
+                    }
+                }
+                _ => reply.push_str(b"E01"),
+            }
+        }
+        b'c' | b's' => {
+            skip_compiled_break(regs);
+            if let Some(addr) = Parser::new(args).hex() {
+                regs.elr = addr;
+            }
+            if cmd == b's' {
+                start_step(regs);
+            }
+            return Action::Resume;
+        }
+        b'Z' | b'z' => {
+            let mut parser = Parser::new(args);
+            let kind = parser.hex();
+            parser.expect(b',');
+            let addr = parser.hex();
+            // Only software breakpoints are supported, other kinds get an empty reply
+            if let (Some(0), Some(addr)) = (kind, addr) {
+                let ok = if cmd == b'Z' {
+                    insert_breakpoint(addr)
+                } else {
+                    remove_breakpoint(addr)
+                };
+                reply.push_str(if ok { b"OK" } else { b"E01" });
+            }
+        }
+        b'D' => {
+            remove_all_breakpoints();
+            send_packet(b"OK");
+            skip_compiled_break(regs);
+            return Action::Resume;
+        }
+        b'k' => {
+            remove_all_breakpoints();
+            skip_compiled_break(regs);
+            return Action::Resume;
+        }
+        b'H' | b'T' => reply.push_str(b"OK"),
+        b'q' => {
+            if args.starts_with(b"Supported") {
+                reply.push_str(b"PacketSize=");
+                reply.push_hex(MAX_PACKET as u64);
+            } else if args.starts_with(b"Attached") {
+                reply.push(b'1');
+            } else if args.starts_with(b"C") {
+                reply.push_str(b"QC1");
+            }
+        }
+        // Unsupported packets get an empty reply
+        _ => {}
+    }
+    send_packet(reply.as_bytes());
+    Action::Stay
+}
+
+/// Steps over a compiled-in `BRK #GDB_BREAK_IMM`, which would otherwise trap again
+fn skip_compiled_break(regs: &mut Regs) {
+    if regs.exception_class() == debug::EC_BRK64 && regs.esr & 0xffff == GDB_BREAK_IMM {
+        let insn = unsafe { (regs.elr as *const u32).read_volatile() };
+        if insn == 0xd420_0000 | ((GDB_BREAK_IMM as u32) << 5) {
+            regs.elr += 4;
+        }
+    }
+}
+
+/// Arms a software step of the instruction at `regs.elr` on behalf of GDB
+fn start_step(regs: &mut Regs) {
+    STATE.lock(|state| {
+        state.stepping = true;
+        state.step_irq_masked = debug::start_step(regs);
+    });
+}
+
+/// Writes the instruction `insn` at `addr`
+///
+/// Kernel text is read-only, so it is patched through `protect::patch_text`.
+fn write_insn(addr: u64, insn: u32) -> bool {
+    protect::patch_text(addr as usize, &insn.to_le_bytes()).is_ok()
+        || uaccess::probe_write(addr as usize, insn).is_ok()
+}
+
+/// Patches a `BRK` at `addr`, remembering the original instruction
+fn insert_breakpoint(addr: u64) -> bool {
+    STATE.lock(|state| {
+        if state.breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+            return true;
+        }
+        let Some(slot) = state.breakpoints.iter_mut().find(|bp| bp.is_none()) else {
+            return false;
+        };
+        let Ok(orig) = uaccess::probe_read::<u32>(addr as usize) else {
+            return false;
+        };
+        if !write_insn(addr, SW_BREAK_INSN) {
+            return false;
+        }
+        *slot = Some(Breakpoint { addr, orig });
+        cache::sync_icache_range(addr as usize, 4);
+        true
+    })
+}
+
+/// Restores the instruction replaced by the breakpoint at `addr`
+fn remove_breakpoint(addr: u64) -> bool {
+    STATE.lock(|state| {
+        let Some(slot) = state
+            .breakpoints
+            .iter_mut()
+            .find(|bp| bp.is_some_and(|bp| bp.addr == addr))
+        else {
+            return false;
+        };
+        if let Some(bp) = slot.take() {
+            write_insn(bp.addr, bp.orig);
+            cache::sync_icache_range(bp.addr as usize, 4);
+        }
+        true
+    })
+}
+
+/// Removes every inserted breakpoint
+fn remove_all_breakpoints() {
+    STATE.lock(|state| {
+        for bp in state.breakpoints.iter_mut().filter_map(|bp| bp.take()) {
+            write_insn(bp.addr, bp.orig);
+            cache::sync_icache_range(bp.addr as usize, 4);
+        }
+    });
+}
+
+/// Returns the UART the stub is attached to
+fn port() -> &'static Pl011 {
+    STATE
+        .lock(|state| state.port)
+        .expect("gdbstub used without a port")
+}
+
+/// Reads a byte from GDB, spinning until one arrives
+fn getc() -> u8 {
+    if let Some(c) = STATE.lock(|state| state.pending.take()) {
+        return c;
+    }
+    let port = port();
+    loop {
+        if let Some(c) = port.poll_getchar() {
+            return c;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Receives a packet into `buf`, acknowledging it, and returns the payload length
+fn recv_packet(buf: &mut [u8; MAX_PACKET]) -> usize {
+    loop {
+        while getc() != b'$' {}
+        let mut len = 0;
+        let mut checksum: u8 = 0;
+        let mut c = getc();
+        while c != b'#' {
+            if len < MAX_PACKET {
+                buf[len] = c;
+                len += 1;
+            }
+            checksum = checksum.wrapping_add(c);
+            c = getc();
+        }
+        let expected = (hex_value(getc()), hex_value(getc()));
+        if let (Some(hi), Some(lo)) = expected
+            && (hi << 4 | lo) == checksum
+        {
+            port().putchar(b'+');
+            return len;
+        }
+        port().putchar(b'-');
+    }
+}
+
+/// Sends a packet, retransmitting until GDB acknowledges it
+fn send_packet(data: &[u8]) {
+    let port = port();
+    loop {
+        port.putchar(b'$');
+        let mut checksum: u8 = 0;
+        for &c in data {
+            port.putchar(c);
+            checksum = checksum.wrapping_add(c);
+        }
+        port.putchar(b'#');
+        port.putchar(HEX_DIGITS[(checksum >> 4) as usize]);
+        port.putchar(HEX_DIGITS[(checksum & 0xf) as usize]);
+        match getc() {
+            b'-' => continue,
+            // Ctrl-C while waiting for an ack: we're already stopped
+            _ => break,
+        }
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Converts an ASCII hex digit to its value
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Fixed-size buffer used to build replies
+struct Reply {
+    buf: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Self {
+        Self {
+            buf: [0; MAX_PACKET],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, c: u8) {
+        if self.len < MAX_PACKET {
+            self.buf[self.len] = c;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &[u8]) {
+        s.iter().for_each(|&c| self.push(c));
+    }
+
+    fn push_hex_u8(&mut self, byte: u8) {
+        self.push(HEX_DIGITS[(byte >> 4) as usize]);
+        self.push(HEX_DIGITS[(byte & 0xf) as usize]);
+    }
+
+    /// Pushes the `bytes` low bytes of `value` in target (little-endian) byte order
+    fn push_hex_le(&mut self, value: u64, bytes: usize) {
+        for byte in &value.to_le_bytes()[..bytes] {
+            self.push_hex_u8(*byte);
+        }
+    }
+
+    /// Pushes `value` as a big-endian hex number without leading zeros
+    fn push_hex(&mut self, value: u64) {
+        let digits = (16 - value.leading_zeros() as usize / 4).max(1);
+        for i in (0..digits).rev() {
+            self.push(HEX_DIGITS[((value >> (i * 4)) & 0xf) as usize]);
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Cursor over packet arguments
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Skips `c` if it is the next character
+    fn expect(&mut self, c: u8) {
+        if self.data.get(self.pos) == Some(&c) {
+            self.pos += 1;
+        }
+    }
+
+    /// Parses a big-endian hex number (addresses, lengths, register numbers)
+    fn hex(&mut self) -> Option<u64> {
+        let mut value: u64 = 0;
+        let start = self.pos;
+        while let Some(digit) = self.data.get(self.pos).and_then(|&c| hex_value(c)) {
+            value = (value << 4) | digit as u64;
+            self.pos += 1;
+        }
+        (self.pos > start).then_some(value)
+    }
+
+    /// Parses two hex digits as a byte
+    fn hex_byte(&mut self) -> Option<u8> {
+        let hi = hex_value(*self.data.get(self.pos)?)?;
+        let lo = hex_value(*self.data.get(self.pos + 1)?)?;
+        self.pos += 2;
+        Some(hi << 4 | lo)
+    }
+
+    /// Parses `bytes` bytes in target (little-endian) byte order
+    fn hex_le(&mut self, bytes: usize) -> Option<u64> {
+        let mut value: u64 = 0;
+        for i in 0..bytes {
+            value |= (self.hex_byte()? as u64) << (i * 8);
+        }
+        Some(value)
+    }
+}
@@ -0,0 +1,371 @@
+//! Hardware breakpoints and watchpoints
+//!
+//! The debug architecture provides a small set of breakpoint (DBGBVR/DBGBCR) and watchpoint
+//! (DBGWVR/DBGWCR) register pairs. This module hands them out to kernel code: a caller asks to be
+//! told when an instruction executes or when a memory range is read or written, and its callback
+//! runs from the debug exception with the faulting context. The typical use is catching the code
+//! that scribbles over a kernel data structure:
+//!
+//! ```ignore
+//! hw_break::set_watchpoint(addr_of!(TABLE) as u64, 8, WatchKind::Write, report_write)?;
+//! ```
+//!
+//! ## Design
+//!
+//! Breakpoint and watchpoint exceptions are taken *before* the instruction completes, so
+//! returning straight to `ELR_EL1` would trap again. After the callback runs, the slot is
+//! disabled and the instruction is single stepped (`MDSCR_EL1.SS`); the software step exception
+//! re-enables the slot and resumes normal execution.
+//!
+//! Only EL1 accesses are matched for now, there is no user space to debug yet.
+
+use core::arch::asm;
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::debug;
+use crate::kernel::irq::Regs;
+use crate::{initcall, println};
+
+/// Maximum number of breakpoints or watchpoints the architecture can implement
+const MAX_SLOTS: usize = 16;
+
+/// DBGBCR/DBGWCR.E: enable the slot
+const CTRL_ENABLE: u64 = 1 << 0;
+/// DBGBCR.PMC/DBGWCR.PAC: match EL1 only
+const CTRL_EL1: u64 = 0b01 << 1;
+/// DBGBCR.BAS: match an A64 instruction
+const BCR_BAS_A64: u64 = 0b1111 << 5;
+/// DBGWCR.LSC shift: which accesses trigger the watchpoint
+const WCR_LSC_SHIFT: u64 = 3;
+/// DBGWCR.BAS shift: watched bytes within the doubleword
+const WCR_BAS_SHIFT: u64 = 5;
+/// DBGWCR.MASK shift: log2 of the watched region size, for regions larger than a doubleword
+const WCR_MASK_SHIFT: u64 = 24;
+
+/// ESR_EL1.ISS.WnR for watchpoint exceptions: the access was a write
+const ESR_WNR: u64 = 1 << 6;
+
+/// Accesses a watchpoint triggers on
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    /// DBGWCR.LSC encoding
+    fn lsc(self) -> u64 {
+        match self {
+            WatchKind::Read => 0b01,
+            WatchKind::Write => 0b10,
+            WatchKind::ReadWrite => 0b11,
+        }
+    }
+}
+
+/// Errors returned when installing a breakpoint or watchpoint
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HwBreakError {
+    /// Every slot implemented by the CPU is in use
+    NoSlot,
+    /// The address is not aligned as the hardware requires
+    Unaligned,
+    /// The watched length is not supported (see `set_watchpoint`)
+    BadLength,
+    /// The slot passed to a `clear_*` function is not in use
+    NotFound,
+}
+
+/// Information passed to a breakpoint or watchpoint callback
+#[derive(Clone, Copy, Debug)]
+pub struct HwBreakEvent {
+    /// Slot returned when the breakpoint or watchpoint was installed
+    pub slot: usize,
+    /// Address of the instruction (breakpoints) or of the data access (watchpoints)
+    pub addr: u64,
+    /// True if a watchpoint was triggered by a write
+    pub write: bool,
+}
+
+/// Callback run when a breakpoint or watchpoint triggers
+///
+/// Runs in exception context with interrupts masked. The callback may inspect and modify the
+/// interrupted context through `regs`.
+pub type HwBreakHandler = fn(&mut Regs, &HwBreakEvent);
+
+/// An installed breakpoint or watchpoint
+#[derive(Clone, Copy)]
+struct Slot {
+    /// First address matched
+    addr: u64,
+    /// Number of bytes matched (4 for breakpoints)
+    len: u64,
+    /// Value of the DBGBVR/DBGWVR register
+    value: u64,
+    /// Value of the DBGBCR/DBGWCR register when the slot is enabled
+    ctrl: u64,
+    handler: HwBreakHandler,
+}
+
+/// Which register bank a slot lives in
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Bank {
+    Breakpoint,
+    Watchpoint,
+}
+
+/// State of the hardware debug registers
+struct HwBreakState {
+    breakpoints: [Option<Slot>; MAX_SLOTS],
+    watchpoints: [Option<Slot>; MAX_SLOTS],
+    /// Number of breakpoints implemented by the CPU
+    num_brps: usize,
+    /// Number of watchpoints implemented by the CPU
+    num_wrps: usize,
+    /// Slot disabled while stepping over the instruction that triggered it, and whether
+    /// interrupts were masked in the stepped context
+    stepping: Option<(Bank, usize, bool)>,
+}
+
+impl HwBreakState {
+    /// Returns the slots of `bank` and the number the CPU implements
+    fn bank(&mut self, bank: Bank) -> (&mut [Option<Slot>; MAX_SLOTS], usize) {
+        match bank {
+            Bank::Breakpoint => (&mut self.breakpoints, self.num_brps),
+            Bank::Watchpoint => (&mut self.watchpoints, self.num_wrps),
+        }
+    }
+}
+
+static STATE: Mutex<HwBreakState> = Mutex::new(HwBreakState {
+    breakpoints: [None; MAX_SLOTS],
+    watchpoints: [None; MAX_SLOTS],
+    num_brps: 0,
+    num_wrps: 0,
+    stepping: None,
+});
+
+/// Expands to a `match` on the slot index writing the numbered debug register `$reg`
+///
+/// The register number is part of the instruction encoding, hence one arm per register.
+macro_rules! write_dbg_reg {
+    ($reg:literal, $n:expr, $value:expr) => {
+        write_dbg_reg!(@arms $reg, $n, $value, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15])
+    };
+    (@arms $reg:literal, $n:expr, $value:expr, [$($i:literal),*]) => {
+        match $n {
+            $($i => unsafe {
+                asm!(
+                    concat!("msr ", $reg, stringify!($i), "_el1, {}"),
+                    in(reg) $value,
+                    options(nostack, preserves_flags)
+                )
+            },)*
+            _ => unreachable!(),
+        }
+    };
+}
+
+/// Writes the value and control registers of a slot
+fn write_slot(bank: Bank, n: usize, value: u64, ctrl: u64) {
+    match bank {
+        Bank::Breakpoint => {
+            write_dbg_reg!("dbgbcr", n, 0u64);
+            write_dbg_reg!("dbgbvr", n, value);
+            write_dbg_reg!("dbgbcr", n, ctrl);
+        }
+        Bank::Watchpoint => {
+            write_dbg_reg!("dbgwcr", n, 0u64);
+            write_dbg_reg!("dbgwvr", n, value);
+            write_dbg_reg!("dbgwcr", n, ctrl);
+        }
+    }
+    unsafe {
+        asm!("isb", options(nostack, preserves_flags));
+    }
+}
+
+/// Discovers the debug registers, disables them all and enables debug exceptions at EL1
+pub fn init() {
+    let dfr0: u64;
+    unsafe {
+        asm!("mrs {}, id_aa64dfr0_el1", out(reg) dfr0, options(nostack, nomem, preserves_flags));
+    }
+    let num_brps = ((dfr0 >> 12) & 0xf) as usize + 1;
+    let num_wrps = ((dfr0 >> 20) & 0xf) as usize + 1;
+
+    for n in 0..num_brps {
+        write_slot(Bank::Breakpoint, n, 0, 0);
+    }
+    for n in 0..num_wrps {
+        write_slot(Bank::Watchpoint, n, 0, 0);
+    }
+    STATE.lock_irqsafe(|state| {
+        state.num_brps = num_brps;
+        state.num_wrps = num_wrps;
+    });
+
+    debug::enable_kernel_debug();
+    debug::update_mdscr(debug::MDSCR_MDE, 0);
+    unsafe {
+        // Unmask debug exceptions, they are masked out of reset
+        asm!("msr daifclr, #8", options(nostack, nomem, preserves_flags));
+    }
+    println!(
+        "hw_break: {} breakpoints, {} watchpoints",
+        num_brps, num_wrps
+    );
+}
+initcall!(Driver, "hw_break", init);
+
+/// Installs a breakpoint on the instruction at `addr`
+///
+/// Returns the slot to pass to `clear_breakpoint`.
+pub fn set_breakpoint(addr: u64, handler: HwBreakHandler) -> Result<usize, HwBreakError> {
+    if addr & 0x3 != 0 {
+        return Err(HwBreakError::Unaligned);
+    }
+    let slot = Slot {
+        addr,
+        len: 4,
+        value: addr,
+        ctrl: BCR_BAS_A64 | CTRL_EL1 | CTRL_ENABLE,
+        handler,
+    };
+    install(Bank::Breakpoint, slot)
+}
+
+/// Installs a watchpoint on the `len` bytes at `addr`
+///
+/// `len` is either 1, 2, 4 or 8 with the range contained in a doubleword, or a power of two of
+/// at least 8 with `addr` aligned to it. Returns the slot to pass to `clear_watchpoint`.
+pub fn set_watchpoint(
+    addr: u64,
+    len: u64,
+    kind: WatchKind,
+    handler: HwBreakHandler,
+) -> Result<usize, HwBreakError> {
+    if !len.is_power_of_two() || len > 1 << 31 {
+        return Err(HwBreakError::BadLength);
+    }
+    let (value, bas, mask) = if len <= 8 {
+        let offset = addr & 0x7;
+        if offset + len > 8 {
+            return Err(HwBreakError::Unaligned);
+        }
+        let bas = ((1 << len) - 1) << offset;
+        (addr & !0x7, bas, 0)
+    } else {
+        if addr & (len - 1) != 0 {
+            return Err(HwBreakError::Unaligned);
+        }
+        (addr, 0xff, len.trailing_zeros() as u64)
+    };
+    let slot = Slot {
+        addr,
+        len,
+        value,
+        ctrl: (mask << WCR_MASK_SHIFT)
+            | (bas << WCR_BAS_SHIFT)
+            | (kind.lsc() << WCR_LSC_SHIFT)
+            | CTRL_EL1
+            | CTRL_ENABLE,
+        handler,
+    };
+    install(Bank::Watchpoint, slot)
+}
+
+/// Removes the breakpoint installed in `slot`
+pub fn clear_breakpoint(slot: usize) -> Result<(), HwBreakError> {
+    uninstall(Bank::Breakpoint, slot)
+}
+
+/// Removes the watchpoint installed in `slot`
+pub fn clear_watchpoint(slot: usize) -> Result<(), HwBreakError> {
+    uninstall(Bank::Watchpoint, slot)
+}
+
+/// Claims a free slot in `bank` and programs it
+fn install(bank: Bank, slot: Slot) -> Result<usize, HwBreakError> {
+    STATE.lock_irqsafe(|state| {
+        let (slots, count) = state.bank(bank);
+        let n = slots[..count]
+            .iter()
+            .position(|s| s.is_none())
+            .ok_or(HwBreakError::NoSlot)?;
+        slots[n] = Some(slot);
+        write_slot(bank, n, slot.value, slot.ctrl);
+        Ok(n)
+    })
+}
+
+/// Frees slot `n` in `bank` and disables it
+fn uninstall(bank: Bank, n: usize) -> Result<(), HwBreakError> {
+    STATE.lock_irqsafe(|state| {
+        let (slots, count) = state.bank(bank);
+        if n >= count || slots[n].take().is_none() {
+            return Err(HwBreakError::NotFound);
+        }
+        write_slot(bank, n, 0, 0);
+        Ok(())
+    })
+}
+
+/// Handles a breakpoint or watchpoint exception
+///
+/// Returns false if no installed slot matches the exception.
+pub fn handle_exception(regs: &mut Regs, ec: u32) -> bool {
+    let (bank, addr) = match ec {
+        debug::EC_BREAKPOINT_LOWER | debug::EC_BREAKPOINT_CUR => (Bank::Breakpoint, regs.elr),
+        _ => {
+            let far: u64;
+            unsafe {
+                asm!("mrs {}, far_el1", out(reg) far, options(nostack, nomem, preserves_flags));
+            }
+            (Bank::Watchpoint, far)
+        }
+    };
+
+    let hit = STATE.lock(|state| {
+        let (slots, count) = state.bank(bank);
+        slots[..count].iter().enumerate().find_map(|(n, slot)| {
+            slot.filter(|s| addr >= s.addr && addr < s.addr + s.len)
+                .map(|s| (n, s))
+        })
+    });
+    let Some((n, slot)) = hit else {
+        return false;
+    };
+
+    let event = HwBreakEvent {
+        slot: n,
+        addr,
+        write: bank == Bank::Watchpoint && regs.esr & ESR_WNR != 0,
+    };
+    (slot.handler)(regs, &event);
+
+    // Step over the instruction with the slot disabled, `handle_step` puts it back
+    write_slot(bank, n, 0, 0);
+    let irq_masked = debug::start_step(regs);
+    STATE.lock(|state| state.stepping = Some((bank, n, irq_masked)));
+    true
+}
+
+/// Handles the software step exception that follows `handle_exception`
+///
+/// Returns false if no step over a breakpoint or watchpoint is in progress.
+pub fn handle_step(regs: &mut Regs) -> bool {
+    let Some((bank, n, irq_masked)) = STATE.lock(|state| state.stepping.take()) else {
+        return false;
+    };
+    debug::finish_step(regs, irq_masked);
+    STATE.lock(|state| {
+        // The callback may have removed the slot in the meantime
+        let (slots, _) = state.bank(bank);
+        if let Some(slot) = slots[n] {
+            write_slot(bank, n, slot.value, slot.ctrl);
+        }
+    });
+    true
+}
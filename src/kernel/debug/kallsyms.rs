@@ -0,0 +1,114 @@
+//! Kernel symbol table
+//!
+//! The names and addresses of the kernel's functions, embedded in the image to turn code
+//! addresses into `function+offset` for backtraces and the profiler. The table is the
+//! `.kallsyms` section, generated by `scripts/kallsyms.sh` from the `nm` output of a first link
+//! of the kernel, then linked in a second time:
+//!
+//! ```text
+//! u64 count
+//! u64 addresses[count]     increasing link addresses
+//! u32 name_offsets[count]  into the names
+//!     names                NUL-terminated, demangled
+//! ```
+//!
+//! ## Design
+//!
+//! - The section comes after `.text`, so adding it doesn't move any function: the addresses
+//!   taken from the first link are those of the final image. Only text symbols are kept.
+//! - Addresses are link addresses: callers convert the ones of a randomized image with
+//!   `kaslr::to_link_address`.
+//! - A kernel linked without the table (e.g. by hand) has an empty section, and `lookup` finds
+//!   nothing.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `kallsyms` is built the same way, over two or three links (`scripts/link-vmlinux.sh`
+//! and `scripts/kallsyms.c`), but compresses the names with a token table and also covers data
+//! symbols and modules.
+
+use core::fmt;
+use core::ptr::addr_of;
+
+unsafe extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __kallsyms_start: u8;
+    static __kallsyms_end: u8;
+}
+
+/// The function containing an address
+#[derive(Clone, Copy, Debug)]
+pub struct Symbol {
+    pub name: &'static str,
+    /// Link address of the function
+    pub addr: u64,
+    /// Distance of the address looked up from the start of the function
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// The parts of the `.kallsyms` section
+struct Table {
+    addrs: &'static [u64],
+    name_offsets: &'static [u32],
+    names: &'static [u8],
+}
+
+/// Returns the table, `None` if the kernel was linked without one
+fn table() -> Option<Table> {
+    let start = addr_of!(__kallsyms_start) as usize;
+    let size = addr_of!(__kallsyms_end) as usize - start;
+    if size < 8 {
+        return None;
+    }
+    let count = unsafe { *(start as *const u64) } as usize;
+    let names_start = 8 + count * 8 + count * 4;
+    if names_start > size {
+        return None;
+    }
+    unsafe {
+        Some(Table {
+            addrs: core::slice::from_raw_parts((start + 8) as *const u64, count),
+            name_offsets: core::slice::from_raw_parts((start + 8 + count * 8) as *const u32, count),
+            names: core::slice::from_raw_parts(
+                (start + names_start) as *const u8,
+                size - names_start,
+            ),
+        })
+    }
+}
+
+impl Table {
+    fn name(&self, index: usize) -> &'static str {
+        let names = &self.names[self.name_offsets[index] as usize..];
+        let len = names.iter().position(|&c| c == 0).unwrap_or(names.len());
+        core::str::from_utf8(&names[..len]).unwrap_or("?")
+    }
+}
+
+/// Returns the function containing the link address `addr`
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    let text = addr_of!(__text_start) as u64..addr_of!(__text_end) as u64;
+    if !text.contains(&addr) {
+        return None;
+    }
+    let table = table()?;
+    // The last symbol at or below `addr`
+    let index = table.addrs.partition_point(|&a| a <= addr).checked_sub(1)?;
+    Some(Symbol {
+        name: table.name(index),
+        addr: table.addrs[index],
+        offset: addr - table.addrs[index],
+    })
+}
+
+/// Returns the number of symbols in the table
+pub fn count() -> usize {
+    table().map_or(0, |table| table.addrs.len())
+}
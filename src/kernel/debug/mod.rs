@@ -0,0 +1,110 @@
+//! Kernel debugging facilities
+//!
+//! Debug exceptions (breakpoints, software step, watchpoints and `BRK` instructions) are routed
+//! here from `do_sync`. Each facility decides whether the exception belongs to it; exceptions
+//! nobody claims are reported as unhandled.
+
+pub mod backtrace;
+pub mod gdbstub;
+pub mod hw_break;
+pub mod kallsyms;
+
+use core::arch::asm;
+
+use crate::kernel::irq::Regs;
+
+/// Hardware breakpoint exception from a lower EL
+pub const EC_BREAKPOINT_LOWER: u32 = 0x30;
+/// Hardware breakpoint exception taken without a change in EL
+pub const EC_BREAKPOINT_CUR: u32 = 0x31;
+/// Software step exception from a lower EL
+pub const EC_SOFTSTEP_LOWER: u32 = 0x32;
+/// Software step exception taken without a change in EL
+pub const EC_SOFTSTEP_CUR: u32 = 0x33;
+/// Watchpoint exception from a lower EL
+pub const EC_WATCHPOINT_LOWER: u32 = 0x34;
+/// Watchpoint exception taken without a change in EL
+pub const EC_WATCHPOINT_CUR: u32 = 0x35;
+/// `BRK` instruction executed in AArch64 state
+pub const EC_BRK64: u32 = 0x3c;
+
+/// First exception class routed to `handle_exception`
+pub const EC_FIRST: u32 = EC_BREAKPOINT_LOWER;
+/// Last exception class routed to `handle_exception`
+pub const EC_LAST: u32 = EC_BRK64;
+
+/// MDSCR_EL1.SS: software step enable
+pub const MDSCR_SS: u64 = 1 << 0;
+/// MDSCR_EL1.KDE: enable debug exceptions at the current EL
+pub const MDSCR_KDE: u64 = 1 << 13;
+/// MDSCR_EL1.MDE: enable hardware breakpoints and watchpoints
+pub const MDSCR_MDE: u64 = 1 << 15;
+
+/// SPSR.SS: software step state, makes the first instruction after `eret` step
+pub const SPSR_SS: u64 = 1 << 21;
+/// SPSR.D: debug exception mask
+pub const SPSR_D: u64 = 1 << 9;
+/// SPSR.I: IRQ mask
+pub const SPSR_I: u64 = 1 << 7;
+
+/// Handles a debug exception of class `ec`
+///
+/// Returns true if a debug facility handled the exception, in which case execution resumes at
+/// `regs.elr`.
+pub fn handle_exception(regs: &mut Regs, ec: u32) -> bool {
+    match ec {
+        EC_BREAKPOINT_LOWER | EC_BREAKPOINT_CUR | EC_WATCHPOINT_LOWER | EC_WATCHPOINT_CUR => {
+            hw_break::handle_exception(regs, ec)
+        }
+        // A step may have been armed either to get past a hardware breakpoint or by GDB
+        EC_SOFTSTEP_LOWER | EC_SOFTSTEP_CUR => {
+            hw_break::handle_step(regs) || gdbstub::handle_exception(regs, ec)
+        }
+        EC_BRK64 => gdbstub::handle_exception(regs, ec),
+        _ => false,
+    }
+}
+
+/// Unlocks the OS lock and enables debug exceptions at EL1
+///
+/// Debug exceptions are still subject to `PSTATE.D`, which the caller unmasks when needed.
+pub fn enable_kernel_debug() {
+    unsafe {
+        asm!(
+            "msr oslar_el1, xzr",
+            "isb",
+            options(nostack, preserves_flags)
+        );
+    }
+    update_mdscr(MDSCR_KDE, 0);
+}
+
+/// Sets the bits in `set` and clears the bits in `clear` in `MDSCR_EL1`
+pub fn update_mdscr(set: u64, clear: u64) {
+    unsafe {
+        let mut mdscr: u64;
+        asm!("mrs {}, mdscr_el1", out(reg) mdscr, options(nostack, nomem, preserves_flags));
+        mdscr = (mdscr & !clear) | set;
+        asm!("msr mdscr_el1, {}", "isb", in(reg) mdscr, options(nostack, preserves_flags));
+    }
+}
+
+/// Arms a software step of the instruction at `regs.elr`
+///
+/// Interrupts are masked while stepping, otherwise the step would land in the IRQ vector.
+/// Returns whether they were masked before, to be handed back to `finish_step`.
+pub fn start_step(regs: &mut Regs) -> bool {
+    let irq_masked = regs.spsr & SPSR_I != 0;
+    regs.spsr |= SPSR_SS | SPSR_I;
+    regs.spsr &= !SPSR_D;
+    update_mdscr(MDSCR_SS, 0);
+    irq_masked
+}
+
+/// Disarms software step once the step exception has been taken
+pub fn finish_step(regs: &mut Regs, irq_masked: bool) {
+    update_mdscr(0, MDSCR_SS);
+    if !irq_masked {
+        regs.spsr &= !SPSR_I;
+    }
+}
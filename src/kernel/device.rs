@@ -1,8 +1,9 @@
 //! Platform device abstraction for DTB-discovered hardware.
 //!
-//! This module provides structures for representing devices discovered from the Device Tree Blob.
-//! The design uses a combined match table approach where each entry contains both the matching
-//! criteria (`compatible` string) and the setup function pointer.
+//! This module provides structures for representing devices discovered from the Device Tree Blob,
+//! and the registry of the drivers binding to them. A driver implements the `Driver` trait
+//! (`probe`, `remove`, `suspend`, `resume`) and is registered for a `compatible` string with a
+//! `DeviceMatch` entry.
 //!
 //! # Linux Kernel Comparison
 //!
@@ -11,25 +12,58 @@
 //! - `platform_driver`: probe function + reference to match table
 //!
 //! We combine both into `DeviceMatch` for simplicity, which is sufficient for a learning kernel
-//! with a small number of devices.
+//! with a small number of devices. Deferred probing follows Linux's `-EPROBE_DEFER`, except that
+//! the core also checks the obvious dependencies itself before calling `probe`.
 //!
 //! # Usage
 //!
-//! 1. Define a static match table with `DeviceMatch` entries
-//! 2. During DTB parsing, collect properties into `PlatformDevice`
-//! 3. Check `compatible` property against match table
-//! 4. If matched, call the corresponding `setup_fn`
+//! 1. Add a `DeviceMatch` entry to the built-in table, or call `register_driver` at any time
+//! 2. The DTB is parsed into a tree of nodes, each seen as a `PlatformDevice`
+//! 3. `probe_all` checks every `compatible` property against the driver registry
+//! 4. If matched, the driver's `probe` is called, and the device is bound to it
+//!
+//! # Deferred Probing
+//!
+//! A device isn't probed before its suppliers are bound: its interrupt controller and the
+//! providers of its `clocks` and `iommus`. It is put on the deferred list instead, as it is when
+//! `probe` returns `ProbeError::Defer`, and probed again once other devices have been bound. The
+//! interrupt controller thus gets probed first without being special-cased, wherever it is in the
+//! tree. Drivers registered after boot are probed against the devices still unbound.
+//!
+//! The registry is behind an `RwLock`: it is looked up for every device node (and by `dt` in the
+//! shell) but rarely written.
 
-use crate::drivers::gic::gicv3;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::drivers::firmware::psci;
+#[cfg(feature = "gic400")]
+use crate::drivers::gic::gic400;
+use crate::drivers::gic::{gicv3, its};
+use crate::drivers::iommu::smmuv3;
+#[cfg(feature = "bcm2835-mbox")]
+use crate::drivers::mbox::bcm2835;
+use crate::drivers::mmc::sdhci;
+use crate::drivers::pci::ecam;
+use crate::drivers::rtc::pl031;
 use crate::drivers::timer::arch_timer;
+#[cfg(feature = "mini-uart")]
+use crate::drivers::uart::mini_uart;
+use crate::drivers::uart::ns16550;
 use crate::drivers::uart::pl011;
+use crate::drivers::usb::xhci;
+use crate::drivers::virtio;
+use crate::drivers::watchdog::sp805;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::rwlock::RwLock;
+use crate::kernel::clk;
+use crate::kernel::dtb;
+use crate::kernel::mm::heap;
+use crate::kernel::perf;
 use crate::utilities::convert;
+use crate::{initcall, pr_err, pr_warn};
 
-/// Maximum number of properties per device node.
-/// This is a reasonable limit for typical DTB nodes (most have fewer than 10 properties).
-/// Linux uses a linked list with dynamic allocation; we use a fixed array since we lack
-/// a memory allocator.
-const MAX_PROPS: usize = 16;
+/// Maximum number of drivers in the registry
+const MAX_DRIVERS: usize = 32;
 
 /// A single property from a DTB node.
 ///
@@ -54,6 +88,26 @@ impl Property {
             len: 0,
         }
     }
+
+    /// Interprets the value as a single null-terminated string
+    ///
+    /// Returns `None` if the value is empty or not terminated. For string lists (e.g.
+    /// `compatible`) only the first entry is returned.
+    pub fn as_str(&self) -> Option<&'static str> {
+        if self.value.is_null() || self.len == 0 {
+            return None;
+        }
+        let bytes = unsafe { core::slice::from_raw_parts(self.value, self.len) };
+        let end = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..end]).ok()
+    }
+
+    /// Reads a value of `cells` 32-bit cells at `offset` bytes into the value
+    pub fn read_cells(&self, offset: usize, cells: u32) -> u64 {
+        (0..cells as usize).fold(0, |value, i| {
+            (value << 32) | convert::read_be_u32(self.value, offset + i * 4) as u64
+        })
+    }
 }
 
 impl Default for Property {
@@ -62,6 +116,15 @@ impl Default for Property {
     }
 }
 
+/// A region of memory-mapped registers, decoded from a `reg` entry by `PlatformDevice::reg`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MmioRegion {
+    /// CPU physical address of the first register
+    pub base: usize,
+    /// Size of the region in bytes
+    pub size: usize,
+}
+
 /// A platform device discovered from the DTB.
 ///
 /// Each node in the DTB that matches a supported `compatible` string becomes a `PlatformDevice`.
@@ -72,10 +135,8 @@ pub struct PlatformDevice {
     pub parent: *const PlatformDevice,
     /// Node name from DTB (e.g., "pl011@9000000")
     pub name: &'static str,
-    /// Array of properties belonging to this device
-    pub properties: [Property; MAX_PROPS],
-    /// Number of valid properties in the array
-    pub prop_count: usize,
+    /// Properties of the node, in blob order
+    pub properties: &'static [Property],
 }
 
 impl PlatformDevice {
@@ -84,49 +145,108 @@ impl PlatformDevice {
         Self {
             parent: core::ptr::null(),
             name: "",
-            properties: [Property::new(); MAX_PROPS],
-            prop_count: 0,
+            properties: &[],
         }
     }
 
     /// Find a property by name
     pub fn find_property(&self, name: &str) -> Option<&Property> {
-        for i in 0..self.prop_count {
-            if self.properties[i].name == name {
-                return Some(&self.properties[i]);
-            }
-        }
-        None
+        self.properties.iter().find(|prop| prop.name == name)
     }
 
-    /// Get #address-cells and #size-cells from the device's parent
+    /// Returns false if `status` disables the device
+    ///
+    /// A device without `status` is available, as are `"okay"` and the older `"ok"`.
+    pub fn is_available(&self) -> bool {
+        self.find_property("status")
+            .and_then(Property::as_str)
+            .is_none_or(|status| status == "okay" || status == "ok")
+    }
+
+    /// Get #address-cells and #size-cells of this node, which apply to its children
     /// Returns (address_cells, size_cells), defaults to (2, 1) if not found
-    pub fn get_parent_cells(&self) -> (u32, u32) {
+    pub fn get_cells(&self) -> (u32, u32) {
         let mut addr_cells: u32 = 2; // Default per DTB spec
         let mut size_cells: u32 = 1; // Default per DTB spec
 
-        if self.parent.is_null() {
-            return (addr_cells, size_cells);
-        }
-
-        unsafe {
-            let parent = &*self.parent;
-            for i in 0..parent.prop_count {
-                let prop = &parent.properties[i];
-                match prop.name {
-                    "#address-cells" => {
-                        addr_cells = convert::read_be_u32(prop.value, 0);
-                    }
-                    "#size-cells" => {
-                        size_cells = convert::read_be_u32(prop.value, 0);
-                    }
-                    _ => {}
+        for prop in self.properties {
+            match prop.name {
+                "#address-cells" => {
+                    addr_cells = convert::read_be_u32(prop.value, 0);
+                }
+                "#size-cells" => {
+                    size_cells = convert::read_be_u32(prop.value, 0);
                 }
+                _ => {}
             }
         }
 
         (addr_cells, size_cells)
     }
+
+    /// Get #address-cells and #size-cells from the device's parent
+    /// Returns (address_cells, size_cells), defaults to (2, 1) if not found
+    pub fn get_parent_cells(&self) -> (u32, u32) {
+        if self.parent.is_null() {
+            return (2, 1);
+        }
+        unsafe { (*self.parent).get_cells() }
+    }
+
+    /// Returns the region at `index` in `reg`, translated to a CPU physical address
+    ///
+    /// Addresses and sizes of one or two cells are supported. The address is translated through
+    /// the `ranges` of every bus between the device and the root; `None` is returned if one of
+    /// them doesn't map it, as when a bus has no `ranges` at all.
+    pub fn reg(&self, index: usize) -> Option<MmioRegion> {
+        let reg = self.find_property("reg")?;
+        let (addr_cells, size_cells) = self.get_parent_cells();
+        if !(1..=2).contains(&addr_cells) || size_cells > 2 {
+            return None;
+        }
+        let entry_len = (addr_cells + size_cells) as usize * 4;
+        let offset = index * entry_len;
+        if offset + entry_len > reg.len {
+            return None;
+        }
+        let addr = reg.read_cells(offset, addr_cells);
+        let size = reg.read_cells(offset + addr_cells as usize * 4, size_cells);
+        Some(MmioRegion {
+            base: self.translate(addr)? as usize,
+            size: size as usize,
+        })
+    }
+
+    /// Translates `addr`, in the address space of the device's bus, to a CPU physical address
+    fn translate(&self, mut addr: u64) -> Option<u64> {
+        let mut bus = self.parent;
+        // The root's address space is the CPU's
+        while !bus.is_null() && unsafe { !(*bus).parent.is_null() } {
+            let node = unsafe { &*bus };
+            let ranges = node.find_property("ranges")?;
+            // An empty `ranges` is an identity mapping
+            if ranges.len > 0 {
+                let (child_cells, size_cells) = node.get_cells();
+                let (parent_cells, _) = node.get_parent_cells();
+                if child_cells > 2 || parent_cells > 2 || size_cells > 2 {
+                    return None;
+                }
+                let entry_len = (child_cells + parent_cells + size_cells) as usize * 4;
+                addr = (0..ranges.len / entry_len).find_map(|i| {
+                    let offset = i * entry_len;
+                    let child = ranges.read_cells(offset, child_cells);
+                    let parent = ranges.read_cells(offset + child_cells as usize * 4, parent_cells);
+                    let size = ranges.read_cells(
+                        offset + (child_cells + parent_cells) as usize * 4,
+                        size_cells,
+                    );
+                    (addr >= child && addr - child < size).then(|| parent + (addr - child))
+                })?;
+            }
+            bus = node.parent;
+        }
+        Some(addr)
+    }
 }
 
 impl Default for PlatformDevice {
@@ -135,30 +255,455 @@ impl Default for PlatformDevice {
     }
 }
 
-/// Entry in the device match table.
+/// Entry in the driver registry
 ///
-/// Combines matching criteria with setup function (unlike Linux which separates `of_device_id`
-/// and `platform_driver`). During DTB parsing, the `compatible` property is checked against
-/// each entry; on match, `setup_fn` is called with the collected device properties.
+/// During initialization, the `compatible` property of every node is checked against each entry;
+/// on match, the entry's driver probes the node.
+#[derive(Clone, Copy)]
 pub struct DeviceMatch {
     /// Compatible string to match (e.g., "arm,pl011", "arm,gic-v3")
     pub compatible: &'static str,
-    /// Function to call when a matching device is found
-    pub setup_fn: fn(&PlatformDevice),
+    /// Driver bound to the matching nodes
+    pub driver: &'static dyn Driver,
+}
+
+/// Errors returned by `Driver` callbacks
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProbeError {
+    /// Something the device depends on isn't ready yet, the probe is retried later
+    Defer,
+    /// The node doesn't describe a usable device
+    NoDevice,
+    /// The driver can't take on another device
+    NoResources,
+    /// The driver doesn't implement the operation
+    NotSupported,
 }
 
-/// Table of supported devices, matched against DTB `compatible` strings during initialization
-pub static CONFIGURED_DEVICES: [DeviceMatch; 3] = [
+/// A device driver
+///
+/// Only `probe` is mandatory: a driver keeping the default `remove` can't be unbound, and the
+/// default `suspend` and `resume` leave the device as it is.
+pub trait Driver: Sync {
+    /// Sets up the device described by `dev`
+    ///
+    /// Returning `ProbeError::Defer` puts the device on the deferred list, probed again once
+    /// other devices have been bound.
+    fn probe(&self, dev: &PlatformDevice) -> Result<(), ProbeError>;
+
+    /// Releases the device, which can then be probed again
+    fn remove(&self, _dev: &PlatformDevice) -> Result<(), ProbeError> {
+        Err(ProbeError::NotSupported)
+    }
+
+    /// Quiesces the device before the system goes to sleep
+    fn suspend(&self, _dev: &PlatformDevice) -> Result<(), ProbeError> {
+        Ok(())
+    }
+
+    /// Brings the device back after `suspend`
+    fn resume(&self, _dev: &PlatformDevice) -> Result<(), ProbeError> {
+        Ok(())
+    }
+}
+
+/// Errors returned by `register_driver` and `unregister_driver`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DriverError {
+    /// A driver is already registered for this `compatible` string
+    Exists,
+    /// The registry is full
+    NoSpace,
+    /// No driver is registered for this `compatible` string
+    NotFound,
+    /// A device bound to the driver can't be removed
+    Busy,
+}
+
+/// Drivers built into the kernel, present in the registry from boot
+///
+/// Drivers behind a feature (see `kernel::board`) are only listed when it is enabled.
+pub const CONFIGURED_DEVICES: &[DeviceMatch] = &[
     DeviceMatch {
         compatible: "arm,gic-v3",
-        setup_fn: gicv3::setup,
+        driver: &gicv3::GicV3Driver,
+    },
+    DeviceMatch {
+        compatible: "arm,gic-v3-its",
+        driver: &its::ItsDriver,
+    },
+    #[cfg(feature = "gic400")]
+    DeviceMatch {
+        compatible: "arm,gic-400",
+        driver: &gic400::Gic400Driver,
+    },
+    #[cfg(feature = "gic400")]
+    DeviceMatch {
+        compatible: "arm,cortex-a15-gic",
+        driver: &gic400::Gic400Driver,
     },
     DeviceMatch {
         compatible: "arm,pl011",
-        setup_fn: pl011::setup,
+        driver: &pl011::Pl011Driver,
+    },
+    #[cfg(feature = "mini-uart")]
+    DeviceMatch {
+        compatible: "brcm,bcm2835-aux-uart",
+        driver: &mini_uart::MiniUartDriver,
+    },
+    DeviceMatch {
+        compatible: "ns16550a",
+        driver: &ns16550::Ns16550Driver,
+    },
+    DeviceMatch {
+        compatible: "ns16550",
+        driver: &ns16550::Ns16550Driver,
     },
     DeviceMatch {
         compatible: "arm,armv7-timer",
-        setup_fn: arch_timer::setup,
+        driver: &arch_timer::ArchTimerDriver,
+    },
+    DeviceMatch {
+        compatible: "arm,armv8-timer",
+        driver: &arch_timer::ArchTimerDriver,
+    },
+    DeviceMatch {
+        compatible: "arm,psci-0.2",
+        driver: &psci::PsciDriver,
+    },
+    DeviceMatch {
+        compatible: "virtio,mmio",
+        driver: &virtio::VirtioMmioDriver,
+    },
+    DeviceMatch {
+        compatible: "arm,smmu-v3",
+        driver: &smmuv3::Smmuv3Driver,
+    },
+    DeviceMatch {
+        compatible: "arm,sp805",
+        driver: &sp805::Sp805Driver,
+    },
+    DeviceMatch {
+        compatible: "arm,pl031",
+        driver: &pl031::Pl031Driver,
+    },
+    DeviceMatch {
+        compatible: "arm,armv8-pmuv3",
+        driver: &perf::PmuDriver,
+    },
+    DeviceMatch {
+        compatible: "fixed-clock",
+        driver: &clk::fixed::FixedClockDriver,
+    },
+    #[cfg(feature = "bcm2835-mbox")]
+    DeviceMatch {
+        compatible: "brcm,bcm2835-mbox",
+        driver: &bcm2835::Bcm2835MboxDriver,
+    },
+    DeviceMatch {
+        compatible: "brcm,bcm2711-emmc2",
+        driver: &sdhci::SdhciDriver,
+    },
+    DeviceMatch {
+        compatible: "brcm,bcm2835-sdhci",
+        driver: &sdhci::SdhciDriver,
+    },
+    DeviceMatch {
+        compatible: "pci-host-ecam-generic",
+        driver: &ecam::EcamHostDriver,
+    },
+    DeviceMatch {
+        compatible: "generic-xhci",
+        driver: &xhci::XhciPlatformDriver,
     },
 ];
+
+/// Builds the initial registry contents from `CONFIGURED_DEVICES`
+const fn builtin_drivers() -> [Option<DeviceMatch>; MAX_DRIVERS] {
+    let mut drivers = [None; MAX_DRIVERS];
+    let mut i = 0;
+    while i < CONFIGURED_DEVICES.len() {
+        drivers[i] = Some(CONFIGURED_DEVICES[i]);
+        i += 1;
+    }
+    drivers
+}
+
+/// Driver registry, matched against DTB `compatible` strings during initialization
+static DRIVERS: RwLock<[Option<DeviceMatch>; MAX_DRIVERS]> = RwLock::new(builtin_drivers());
+
+/// State of a device with respect to its driver
+#[derive(Clone, Copy)]
+enum Binding {
+    /// Not probed, or the probe failed
+    Unbound,
+    /// The driver asked to be probed again later
+    Deferred(DeviceMatch),
+    /// The driver has taken the device
+    Bound(DeviceMatch),
+}
+
+/// Binding of every device, indexed like `dtb::devices`, allocated by `probe_all`
+static BINDINGS: Mutex<&'static mut [Binding]> = Mutex::new(&mut []);
+
+/// Set once `probe_all` has run: drivers registered afterwards are probed right away
+static PROBING: AtomicBool = AtomicBool::new(false);
+
+/// What `bind_state` reports about a device
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BindState {
+    Unbound,
+    Deferred,
+    Bound,
+}
+
+/// Adds a driver to the registry
+///
+/// Once devices have been initialized, the unbound devices matching the new driver are probed
+/// straight away.
+pub fn register_driver(entry: DeviceMatch) -> Result<(), DriverError> {
+    {
+        let mut drivers = DRIVERS.write_irqsafe();
+        if drivers
+            .iter()
+            .flatten()
+            .any(|d| d.compatible == entry.compatible)
+        {
+            return Err(DriverError::Exists);
+        }
+        let slot = drivers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(DriverError::NoSpace)?;
+        *slot = Some(entry);
+    }
+    if PROBING.load(Ordering::Acquire) {
+        for (index, dev) in dtb::devices().iter().enumerate() {
+            if matches!(binding(index), Binding::Unbound)
+                && dtb::match_driver(dev).is_some_and(|d| d.compatible == entry.compatible)
+            {
+                probe_device(index, dev, entry);
+            }
+        }
+        retry_deferred();
+    }
+    Ok(())
+}
+
+/// Removes a driver from the registry, unbinding its devices first
+pub fn unregister_driver(compatible: &str) -> Result<(), DriverError> {
+    for (index, dev) in dtb::devices().iter().enumerate() {
+        match binding(index) {
+            Binding::Bound(entry) if entry.compatible == compatible => {
+                unbind(dev).map_err(|_| DriverError::Busy)?;
+            }
+            Binding::Deferred(entry) if entry.compatible == compatible => {
+                set_binding(index, Binding::Unbound);
+            }
+            _ => {}
+        }
+    }
+    let mut drivers = DRIVERS.write_irqsafe();
+    let slot = drivers
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|d| d.compatible == compatible))
+        .ok_or(DriverError::NotFound)?;
+    *slot = None;
+    Ok(())
+}
+
+/// Returns the first registered driver whose `compatible` string satisfies `matches`
+pub fn find_driver(matches: impl Fn(&str) -> bool) -> Option<DeviceMatch> {
+    DRIVERS
+        .read_irqsafe()
+        .iter()
+        .flatten()
+        .find(|d| matches(d.compatible))
+        .copied()
+}
+
+fn binding(index: usize) -> Binding {
+    BINDINGS.lock_irqsafe(|bindings| bindings.get(index).copied().unwrap_or(Binding::Unbound))
+}
+
+fn set_binding(index: usize, binding: Binding) {
+    BINDINGS.lock_irqsafe(|bindings| {
+        if let Some(slot) = bindings.get_mut(index) {
+            *slot = binding;
+        }
+    });
+}
+
+/// Returns the index of `dev` in `dtb::devices`
+fn index_of(dev: &PlatformDevice) -> Option<usize> {
+    dtb::devices().iter().position(|d| core::ptr::eq(d, dev))
+}
+
+/// Returns whether `dev` has been bound to its driver
+pub fn bind_state(dev: &PlatformDevice) -> BindState {
+    match index_of(dev).map(binding) {
+        Some(Binding::Bound(_)) => BindState::Bound,
+        Some(Binding::Deferred(_)) => BindState::Deferred,
+        _ => BindState::Unbound,
+    }
+}
+
+/// Probes every device with a matching driver, then the deferred ones until none gets bound
+///
+/// Runs once, as the `Irq` stage init call. Devices still deferred at the end are reported.
+pub fn probe_all() {
+    match heap::alloc_array(dtb::devices().len(), Binding::Unbound) {
+        Ok(bindings) => BINDINGS.lock_irqsafe(|b| *b = bindings),
+        Err(e) => {
+            pr_err!("devices: no memory for the bindings: {:?}", e);
+            return;
+        }
+    }
+    PROBING.store(true, Ordering::Release);
+    for (index, dev) in dtb::devices().iter().enumerate() {
+        if let Some(entry) = dtb::match_driver(dev) {
+            probe_device(index, dev, entry);
+        }
+    }
+    retry_deferred();
+    for (index, dev) in dtb::devices().iter().enumerate() {
+        if let Binding::Deferred(entry) = binding(index) {
+            pr_warn!("{}: probe by {} still deferred", dev.name, entry.compatible);
+        }
+    }
+}
+initcall!(Irq, "devices", probe_all);
+
+/// Probes the deferred devices again, until a pass binds none of them
+///
+/// Every device bound may be the one another device was waiting for. Runs at the end of
+/// `probe_all` and after `register_driver`, and can be called when something a driver waits for
+/// outside the driver model becomes available.
+pub fn retry_deferred() {
+    let mut progress = true;
+    while progress {
+        progress = false;
+        for (index, dev) in dtb::devices().iter().enumerate() {
+            if let Binding::Deferred(entry) = binding(index) {
+                progress |= probe_device(index, dev, entry);
+            }
+        }
+    }
+}
+
+/// Probes `dev`, the device at `index`, with the driver of `entry`
+///
+/// Returns true if the device got bound.
+fn probe_device(index: usize, dev: &PlatformDevice, entry: DeviceMatch) -> bool {
+    let result = if suppliers_ready(dev) {
+        entry.driver.probe(dev)
+    } else {
+        Err(ProbeError::Defer)
+    };
+    let binding = match result {
+        Ok(()) => Binding::Bound(entry),
+        Err(ProbeError::Defer) => Binding::Deferred(entry),
+        // Not an error: e.g. an empty virtio-mmio slot
+        Err(ProbeError::NoDevice) => Binding::Unbound,
+        Err(e) => {
+            let driver = entry.compatible;
+            pr_err!("{}: probe by {} failed: {:?}", dev.name, driver, e);
+            Binding::Unbound
+        }
+    };
+    set_binding(index, binding);
+    matches!(binding, Binding::Bound(_))
+}
+
+/// Returns true if the devices `dev` depends on are ready
+///
+/// These are its interrupt controllers and the providers listed in its `clocks` and `iommus`
+/// properties. A provider without a driver (e.g. a `fixed-clock`) is always ready.
+fn suppliers_ready(dev: &PlatformDevice) -> bool {
+    let ready = |supplier: &PlatformDevice| {
+        core::ptr::eq(supplier, dev)
+            || dtb::match_driver(supplier).is_none()
+            || bind_state(supplier) == BindState::Bound
+    };
+    let interrupt_parent = dev
+        .find_property("interrupts")
+        .and_then(|_| dtb::find_interrupt_parent(dev));
+    interrupt_parent.is_none_or(ready)
+        && all_suppliers(dev, "interrupts-extended", "#interrupt-cells", ready)
+        && all_suppliers(dev, "clocks", "#clock-cells", ready)
+        && all_suppliers(dev, "iommus", "#iommu-cells", ready)
+}
+
+/// Returns true if `f` holds for every provider referenced by the phandle list `name` of `dev`
+///
+/// Each entry is a phandle followed by as many cells as the provider's `cells` property says.
+fn all_suppliers(
+    dev: &PlatformDevice,
+    name: &str,
+    cells: &str,
+    mut f: impl FnMut(&PlatformDevice) -> bool,
+) -> bool {
+    let Some(prop) = dev.find_property(name) else {
+        return true;
+    };
+    let mut offset = 0;
+    while offset + 4 <= prop.len {
+        let Some(supplier) = dtb::find_device_by_phandle(convert::read_be_u32(prop.value, offset))
+        else {
+            return true;
+        };
+        if !f(supplier) {
+            return false;
+        }
+        let args = supplier
+            .find_property(cells)
+            .map_or(0, |p| convert::read_be_u32(p.value, 0));
+        offset += (1 + args as usize) * 4;
+    }
+    true
+}
+
+/// Unbinds `dev` from its driver, which must implement `remove`
+pub fn unbind(dev: &PlatformDevice) -> Result<(), ProbeError> {
+    let index = index_of(dev).ok_or(ProbeError::NoDevice)?;
+    let Binding::Bound(entry) = binding(index) else {
+        return Err(ProbeError::NoDevice);
+    };
+    entry.driver.remove(dev)?;
+    set_binding(index, Binding::Unbound);
+    Ok(())
+}
+
+/// Suspends every bound device, in the reverse order of the device table
+///
+/// Children come after their parents in the table, so they are suspended first. If a driver
+/// fails, the devices already suspended are resumed and its error is returned.
+pub fn suspend_all() -> Result<(), ProbeError> {
+    let devices = dtb::devices();
+    for index in (0..devices.len()).rev() {
+        if let Binding::Bound(entry) = binding(index)
+            && let Err(e) = entry.driver.suspend(&devices[index])
+        {
+            pr_err!("{}: suspend failed: {:?}", devices[index].name, e);
+            resume_from(index + 1);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Resumes every bound device after `suspend_all`, in the order of the device table
+pub fn resume_all() {
+    resume_from(0);
+}
+
+/// Resumes the bound devices from `first` on
+fn resume_from(first: usize) {
+    for (index, dev) in dtb::devices().iter().enumerate().skip(first) {
+        if let Binding::Bound(entry) = binding(index)
+            && let Err(e) = entry.driver.resume(dev)
+        {
+            pr_err!("{}: resume failed: {:?}", dev.name, e);
+        }
+    }
+}
@@ -6,25 +6,48 @@
 //!
 //! ## Parsing Strategy
 //!
-//! The parser walks the DTB structure block token by token, building a flat device table. Each
-//! DTB node becomes a `PlatformDevice` entry with its properties stored directly in the table.
-//! A depth stack tracks parent-child relationships. After parsing, `init_devices` matches
-//! discovered devices against the configured driver table and calls their setup functions.
+//! The parser walks the DTB structure block token by token. At boot, before memory management,
+//! it builds a fixed boot table: each DTB node becomes a `PlatformDevice` entry whose properties
+//! point into the blob, enough to find the memory, the command line and the console. In the
+//! `Mmu` stage, once the heap works, `unflatten` builds the whole tree from the heap (see `tree`),
+//! applies the overlays (see `overlay`) and makes the lookups use it instead. Later, in the `Irq`
+//! boot stage, `device::probe_all` matches discovered devices against the driver registry and
+//! probes them.
+//!
+//! ## Validation
+//!
+//! The blob's address comes from the bootloader, which may hand over a corrupt or truncated
+//! one. `parse_dtb` checks it all before using any of it: the magic and version, `totalsize`
+//! against the memory mapped there, each block against `totalsize`, then every token, property
+//! length, name and string offset of the structure block against its block, and the nesting of
+//! the nodes. The first problem found is returned as a `DtError` naming its offset in the blob.
 //!
 //! ## Initialization Order
 //!
-//! Device initialization is done in two passes:
-//! 1. **First pass**: Initialize the interrupt controller (GIC), since other devices need it
-//!    to configure their interrupts
-//! 2. **Second pass**: Initialize all remaining devices (UART, timer, etc.)
+//! Devices are probed in structure block order, except that a device is deferred until its
+//! interrupt controller and clock providers are bound (see `device`). The GIC is thus set up
+//! before the devices routing interrupts through it.
 
 use core;
+use core::fmt;
+
+pub mod overlay;
+mod tree;
 
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::rwlock::RwLock;
+use crate::kernel::console;
 use crate::kernel::device;
+use crate::kernel::uaccess;
 use crate::utilities::convert;
+use crate::{initcall, pr_err, pr_info, pr_warn, print, println};
 
 /// DTB magic number (big-endian: 0xd00dfeed)
 const MAGIC: u32 = 0xd00dfeed;
+/// Oldest DTB version understood, the first with the size of the strings block
+const MIN_VERSION: u32 = 16;
+/// DTB version implemented, which blobs must be backwards compatible with
+const VERSION: u32 = 17;
 /// Token marking the start of a node
 const FDT_BEGIN_NODE: u32 = 0x00000001;
 /// Token marking the end of a node
@@ -35,23 +58,41 @@ const FDT_PROP: u32 = 0x00000003;
 const FDT_NOP: u32 = 0x00000004;
 /// Token marking the end of the structure block
 const FDT_END: u32 = 0x00000009;
-/// Maximum number of devices that can be stored in the device table
-const MAX_DEVICES: usize = 256;
-/// Maximum number of phandle entries
-const MAX_HANDLES: usize = 32;
+/// Nodes the boot table holds, at most
+const BOOT_DEVICES: usize = 256;
+/// Properties the boot table holds, at most
+const BOOT_PROPS: usize = 1024;
 
-/// Number of devices discovered during DTB parsing
-static mut DEVICE_COUNT: usize = 0;
-
-/// Flat table of all devices discovered from the DTB
-static mut DEVICE_TABLE: [device::PlatformDevice; MAX_DEVICES] =
-    [device::PlatformDevice::new(); MAX_DEVICES];
+/// Devices discovered from the DTB
+struct DeviceTable {
+    /// Nodes parsed at boot, in structure block order, until the unflattened tree replaces them
+    boot_devices: [device::PlatformDevice; BOOT_DEVICES],
+    /// Properties of the boot nodes, those of a node next to each other
+    boot_props: [device::Property; BOOT_PROPS],
+    boot_count: usize,
+    boot_prop_count: usize,
+    /// Views of the nodes of the unflattened tree, once published
+    tree: Option<&'static [device::PlatformDevice]>,
+    /// Address and size of the blob, which the boot table points into
+    blob: (usize, usize),
+}
 
-/// Number of phandle mappings registered
-static mut PHANDLE_COUNT: usize = 0;
+/// The device table, write-locked by `parse_dtb` and `unflatten` and only read afterwards
+///
+/// Entries are never modified once parsed, so references to them remain valid after the read
+/// lock is released: lookups return `&'static` devices. The boot table stays in place when the
+/// tree replaces it.
+static DEVICE_TABLE: RwLock<DeviceTable> = RwLock::new(DeviceTable {
+    boot_devices: [device::PlatformDevice::new(); BOOT_DEVICES],
+    boot_props: [device::Property::new(); BOOT_PROPS],
+    boot_count: 0,
+    boot_prop_count: 0,
+    tree: None,
+    blob: (0, 0),
+});
 
-/// Lookup table mapping phandle values to device table indices
-static mut PHANDLE_TABLE: [(u32, usize); MAX_HANDLES] = [(0, 0); MAX_HANDLES];
+/// The unflattened tree, from `unflatten` until it is published
+static TREE: Mutex<Option<tree::Tree>> = Mutex::new(None);
 
 /// Flattened Device Tree header
 ///
@@ -116,159 +157,602 @@ impl FdtPropHeader {
     }
 }
 
-/// Get a string from the strings block by offset
-fn get_property_name(dtb_addr: usize, off_dt_strings: usize, offset: u32) -> &'static str {
-    let strings_block = dtb_addr + off_dt_strings;
-    let str_start = (strings_block + offset as usize) as *const u8;
-    let mut len = 0;
-    unsafe {
-        while *str_start.add(len) != 0 {
-            len += 1;
-        }
+/// Errors found in a DTB
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DtError {
+    /// The blob doesn't start with `MAGIC`
+    BadMagic(u32),
+    /// The blob is older than `MIN_VERSION`, or needs a parser newer than `VERSION`
+    BadVersion {
+        version: u32,
+        last_comp_version: u32,
+    },
+    /// `totalsize` is smaller than the header, or larger than the memory holding the blob
+    BadSize { totalsize: usize, available: usize },
+    /// A block doesn't fit within `totalsize`
+    BlockOutOfBounds {
+        block: &'static str,
+        offset: usize,
+        size: usize,
+    },
+    /// An item at `offset` in the structure block runs past its end
+    Truncated { offset: usize },
+    /// Unknown token at `offset` in the structure block
+    BadToken { offset: usize, token: u32 },
+    /// A node name at `offset` in the structure block, or a property name at `offset` in the
+    /// strings block, isn't a NUL-terminated UTF-8 string within the block
+    BadString { block: &'static str, offset: usize },
+    /// Nodes don't nest properly at `offset` in the structure block: a node end or a property
+    /// outside any node, a second root, or `FDT_END` with nodes open
+    BadNesting { offset: usize },
+}
 
-        let slice = core::slice::from_raw_parts(str_start, len);
-        core::str::from_utf8_unchecked(slice)
+impl fmt::Display for DtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            DtError::BadMagic(magic) => {
+                write!(f, "bad magic {:#010x}, expected {:#010x}", magic, MAGIC)
+            }
+            DtError::BadVersion {
+                version,
+                last_comp_version,
+            } => write!(
+                f,
+                "version {} (compatible with {}) not supported, expected {} to {}",
+                version, last_comp_version, MIN_VERSION, VERSION
+            ),
+            DtError::BadSize {
+                totalsize,
+                available,
+            } => write!(
+                f,
+                "totalsize {} bytes, the header needs {} and {} are available",
+                totalsize,
+                size_of::<FdtHeader>(),
+                available
+            ),
+            DtError::BlockOutOfBounds {
+                block,
+                offset,
+                size,
+            } => write!(
+                f,
+                "{} block at offset {:#x} ({} bytes) past totalsize",
+                block, offset, size
+            ),
+            DtError::Truncated { offset } => {
+                write!(f, "structure block truncated at offset {:#x}", offset)
+            }
+            DtError::BadToken { offset, token } => {
+                write!(f, "unknown token {:#x} at offset {:#x}", token, offset)
+            }
+            DtError::BadString { block, offset } => {
+                write!(
+                    f,
+                    "bad string at offset {:#x} of the {} block",
+                    offset, block
+                )
+            }
+            DtError::BadNesting { offset } => {
+                write!(f, "misnested node at offset {:#x}", offset)
+            }
+        }
     }
 }
 
-/// Parses the Flattened Device Tree at address `dtb`
-///
-/// Walks the DTB structure block token by token, creating a `PlatformDevice` for each node
-/// and storing its properties in the global `DEVICE_TABLE`. A depth stack tracks parent-child
-/// relationships so each device can reference its parent. After parsing, calls `init_devices`
-/// to match discovered devices against the driver table and initialize them.
-#[unsafe(no_mangle)]
-pub fn parse_dtb(dtb: usize) {
-    let header = FdtHeader::from_be_bytes(dtb);
-    if header.magic != MAGIC {
-        panic!();
+/// Summary of the DTB returned by `parse_dtb`
+#[derive(Clone, Copy, Debug)]
+pub struct ParsedDt {
+    pub version: u32,
+    /// Size of the blob
+    pub size: usize,
+    pub nodes: usize,
+    pub properties: usize,
+    /// Nodes left out of the boot table for lack of room, until `unflatten`
+    pub deferred: usize,
+}
+
+/// A blob whose header has been checked: its blocks lie within `totalsize`
+#[derive(Clone, Copy)]
+struct Blob {
+    addr: usize,
+    header: FdtHeader,
+    /// Size of the structure block, which version 16 doesn't give
+    struct_size: usize,
+}
+
+impl Blob {
+    /// Checks the header of the blob at `addr`, of which `available` bytes may be read
+    fn check(addr: usize, available: usize) -> Result<Self, DtError> {
+        let header_size = size_of::<FdtHeader>();
+        if available < header_size {
+            return Err(DtError::BadSize {
+                totalsize: 0,
+                available,
+            });
+        }
+        let header = FdtHeader::from_be_bytes(addr);
+        if header.magic != MAGIC {
+            return Err(DtError::BadMagic(header.magic));
+        }
+        if header.version < MIN_VERSION || header.last_comp_version > VERSION {
+            return Err(DtError::BadVersion {
+                version: header.version,
+                last_comp_version: header.last_comp_version,
+            });
+        }
+        let totalsize = header.totalsize as usize;
+        if totalsize < header_size || totalsize > available {
+            return Err(DtError::BadSize {
+                totalsize,
+                available,
+            });
+        }
+        let block = |block, offset: u32, size: usize| {
+            let offset = offset as usize;
+            if offset < header_size || offset.saturating_add(size) > totalsize {
+                return Err(DtError::BlockOutOfBounds {
+                    block,
+                    offset,
+                    size,
+                });
+            }
+            Ok(())
+        };
+        let struct_size = if header.version >= 17 {
+            header.size_dt_struct as usize
+        } else {
+            totalsize.saturating_sub(header.off_dt_struct as usize)
+        };
+        block("structure", header.off_dt_struct, struct_size)?;
+        block(
+            "strings",
+            header.off_dt_strings,
+            header.size_dt_strings as usize,
+        )?;
+        // At least the empty entry ending the list
+        block("memory reservation", header.off_mem_rsvmap, 16)?;
+        Ok(Self {
+            addr,
+            header,
+            struct_size,
+        })
     }
 
-    let structure_block = dtb + header.off_dt_struct as usize;
+    /// Returns the NUL-terminated string at `offset` in the block at `block_offset` of `size`
+    /// bytes
+    fn string(&self, block_offset: u32, size: usize, offset: usize) -> Option<&'static str> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts((self.addr + block_offset as usize) as *const u8, size)
+        };
+        let bytes = bytes.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+}
+
+/// An item of a structure block
+#[derive(Clone, Copy)]
+enum Token {
+    /// Start of a node, with its name
+    BeginNode(&'static str),
+    EndNode,
+    /// Property of the current node, its value pointing into the blob
+    Prop(device::Property),
+}
+
+/// Calls `f` on each item of the structure block of `blob`, in order
+///
+/// Every item is checked to lie within the blob before `f` sees it; the walk stops at the first
+/// error, which `f` may have seen the items before.
+fn walk_structure(blob: &Blob, mut f: impl FnMut(Token)) -> Result<(), DtError> {
+    let structure_block = blob.addr + blob.header.off_dt_struct as usize;
+    let size = blob.struct_size;
+    let read = |offset: usize| {
+        if offset.saturating_add(4) > size {
+            return Err(DtError::Truncated { offset });
+        }
+        Ok(convert::read_be_u32(structure_block as *const u8, offset))
+    };
     let mut off = 0;
-    let mut prop_id = 0;
-    let mut device;
-    let mut stack: [usize; 10] = [0; 10];
-    let mut stack_depth = 0;
+    let mut depth = 0;
+    let mut root_seen = false;
     loop {
-        let token = convert::read_be_u32(structure_block as *const u8, off);
+        let token_offset = off;
+        let token = read(off)?;
         off += 4;
         match token {
             FDT_BEGIN_NODE => {
-                device = device::PlatformDevice::default();
-                prop_id = 0;
-                if stack_depth > 0 {
-                    unsafe {
-                        device.parent =
-                            &DEVICE_TABLE[stack[stack_depth - 1]] as *const device::PlatformDevice;
-                    }
+                if depth == 0 && root_seen {
+                    return Err(DtError::BadNesting {
+                        offset: token_offset,
+                    });
                 }
-
                 // Read null-terminated node name. Name starts after the token FDT_BEGIN_NODE
-                let name_start = (structure_block + off) as *const u8;
-                let mut name_len = 0;
-                unsafe {
-                    while *name_start.add(name_len) != 0 {
-                        name_len += 1;
-                    }
-                }
-
-                // Convert to string. Pick [name_start, name_start + name_len] bytes
-                device.name = unsafe {
-                    let slice = core::slice::from_raw_parts(name_start, name_len);
-                    core::str::from_utf8_unchecked(slice)
-                };
+                let name = blob.string(blob.header.off_dt_struct, size, off).ok_or(
+                    DtError::BadString {
+                        block: "structure",
+                        offset: off,
+                    },
+                )?;
                 // Move offset past name + null terminator
-                off += name_len + 1;
+                off += name.len() + 1;
                 // Align to 4-byte boundary
                 off = (off + 3) & !3;
-                unsafe {
-                    DEVICE_TABLE[DEVICE_COUNT] = device;
-                    stack[stack_depth] = DEVICE_COUNT;
-                    DEVICE_COUNT += 1;
-                }
-                stack_depth += 1;
+                depth += 1;
+                root_seen = true;
+                f(Token::BeginNode(name));
             }
             FDT_END_NODE => {
-                // Store prop_count in table entry, then pop the stack
-                unsafe {
-                    DEVICE_TABLE[stack[stack_depth - 1]].prop_count = prop_id;
+                if depth == 0 {
+                    return Err(DtError::BadNesting {
+                        offset: token_offset,
+                    });
                 }
-                stack_depth -= 1;
+                depth -= 1;
+                f(Token::EndNode);
             }
             FDT_PROP => {
+                if depth == 0 {
+                    return Err(DtError::BadNesting {
+                        offset: token_offset,
+                    });
+                }
                 // Read property data: length and name
-                let prop_header = FdtPropHeader::from_be_bytes(structure_block + off);
-                // Get the name of the property
+                let len = read(off)? as usize;
+                let nameoff = read(off + 4)? as usize;
                 off += 8;
-                let mut prop = device::Property::default();
-                prop.name =
-                    get_property_name(dtb, header.off_dt_strings as usize, prop_header.nameoff);
-                prop.len = prop_header.len as usize;
-                prop.value = (structure_block + off) as *const u8;
-
-                // Store property directly in DEVICE_TABLE entry
-                let dev_idx = stack[stack_depth - 1];
-                unsafe {
-                    if prop.name == "phandle" {
-                        // phandle is always u32, so we can read the id directly
-                        let phandle_value = convert::read_be_u32(prop.value, 0);
-                        PHANDLE_TABLE[PHANDLE_COUNT] = (phandle_value, dev_idx);
-                        PHANDLE_COUNT += 1;
-                    }
-                    DEVICE_TABLE[dev_idx].properties[prop_id] = prop;
+                if off.saturating_add(len) > size {
+                    return Err(DtError::Truncated { offset: off });
                 }
-                prop_id += 1;
+                let name = blob
+                    .string(
+                        blob.header.off_dt_strings,
+                        blob.header.size_dt_strings as usize,
+                        nameoff,
+                    )
+                    .ok_or(DtError::BadString {
+                        block: "strings",
+                        offset: nameoff,
+                    })?;
+                f(Token::Prop(device::Property {
+                    name,
+                    value: (structure_block + off) as *const u8,
+                    len,
+                }));
                 // Align to 4-byte boundary
-                off += prop.len as usize;
+                off += len;
                 off = (off + 3) & !3;
             }
             FDT_NOP => {
                 // Skip
             }
+            FDT_END if depth == 0 && root_seen => return Ok(()),
             FDT_END => {
-                break;
+                return Err(DtError::BadNesting {
+                    offset: token_offset,
+                });
             }
-            _ => {
-                panic!();
+            token => {
+                return Err(DtError::BadToken {
+                    offset: token_offset,
+                    token,
+                });
             }
         }
     }
-    init_devices();
 }
 
-/// Find a device by its phandle value
-pub fn find_device_by_phandle(phandle: u32) -> Option<&'static device::PlatformDevice> {
-    unsafe {
-        for i in 0..PHANDLE_COUNT {
-            let (phandle_val, dev_idx) = PHANDLE_TABLE[i];
-            if phandle_val == phandle {
-                return Some(&DEVICE_TABLE[dev_idx]);
+/// Returns the number of bytes from `dtb` the blob may span: up to `totalsize` if mapped
+///
+/// The address comes straight from x0 at boot, so both ends are probed before being trusted.
+fn mapped_size(dtb: usize) -> Result<usize, DtError> {
+    let magic = uaccess::probe_read::<u32>(dtb).map_err(|_| DtError::BadMagic(0))?;
+    if u32::from_be(magic) != MAGIC {
+        return Err(DtError::BadMagic(u32::from_be(magic)));
+    }
+    let totalsize = uaccess::probe_read::<u32>(dtb + 4)
+        .map(u32::from_be)
+        .map_err(|_| DtError::BadMagic(MAGIC))? as usize;
+    if totalsize == 0 || uaccess::probe_read::<u8>(dtb + totalsize - 1).is_err() {
+        return Err(DtError::BadSize {
+            totalsize,
+            available: 0,
+        });
+    }
+    Ok(totalsize)
+}
+
+/// Parses the Flattened Device Tree at address `dtb`
+///
+/// Checks the whole blob first, then walks the DTB structure block token by token, creating a
+/// `PlatformDevice` for each node in the boot table, its properties pointing into the blob. A
+/// depth stack tracks parent-child relationships so each device can reference its parent. Nodes
+/// past the capacity of the table wait for `unflatten`. Then selects the console from `/chosen`;
+/// the devices are probed later, by `device::probe_all`.
+#[unsafe(no_mangle)]
+pub fn parse_dtb(dtb: usize) -> Result<ParsedDt, DtError> {
+    let blob = Blob::check(dtb, mapped_size(dtb)?)?;
+    let mut parsed = ParsedDt {
+        version: blob.header.version,
+        size: blob.header.totalsize as usize,
+        nodes: 0,
+        properties: 0,
+        deferred: 0,
+    };
+    walk_structure(&blob, |token| match token {
+        Token::BeginNode(_) => parsed.nodes += 1,
+        Token::Prop(_) => parsed.properties += 1,
+        Token::EndNode => {}
+    })?;
+    let mut table = DEVICE_TABLE.write();
+    table.blob = (dtb, parsed.size);
+
+    let mut stack: [usize; 10] = [0; 10];
+    let mut stack_depth = 0;
+    // Index of the first property of the current node in `boot_props`
+    let mut first_prop = 0;
+    let mut truncated = false;
+    walk_structure(&blob, |token| {
+        if truncated {
+            return;
+        }
+        match token {
+            Token::BeginNode(name) => {
+                let index = table.boot_count;
+                if index == BOOT_DEVICES || stack_depth == stack.len() {
+                    truncated = true;
+                    return;
+                }
+                let parent = if stack_depth > 0 {
+                    &table.boot_devices[stack[stack_depth - 1]] as *const _
+                } else {
+                    core::ptr::null()
+                };
+                table.boot_devices[index] = device::PlatformDevice {
+                    name,
+                    parent,
+                    ..Default::default()
+                };
+                table.boot_count += 1;
+                first_prop = table.boot_prop_count;
+                stack[stack_depth] = index;
+                stack_depth += 1;
+            }
+            Token::EndNode => stack_depth -= 1,
+            Token::Prop(prop) => {
+                let count = table.boot_prop_count;
+                if count == BOOT_PROPS {
+                    truncated = true;
+                    return;
+                }
+                table.boot_props[count] = prop;
+                table.boot_prop_count += 1;
+                // A node's properties come before its subnodes, so they are contiguous
+                let properties = unsafe {
+                    core::slice::from_raw_parts(
+                        table.boot_props.as_ptr().add(first_prop),
+                        count + 1 - first_prop,
+                    )
+                };
+                table.boot_devices[stack[stack_depth - 1]].properties = properties;
             }
         }
+    })?;
+    parsed.deferred = parsed.nodes - table.boot_count;
+    // The console lookup reads the table
+    drop(table);
+    if truncated {
+        pr_warn!(
+            "DTB: boot table full, {} nodes come with the unflattened tree",
+            parsed.deferred
+        );
     }
-    None
+    console::select_stdout();
+    Ok(parsed)
+}
+
+/// Builds the unflattened tree, applies the overlays and switches the lookups to it
+///
+/// The boot table stays in use if the heap can't hold the tree.
+fn unflatten() {
+    let (addr, size) = blob_region();
+    // Checked by `parse_dtb`
+    let Ok(blob) = Blob::check(addr, size) else {
+        return;
+    };
+    match tree::Tree::unflatten(&blob) {
+        Ok(tree) => TREE.lock_irqsafe(|t| *t = Some(tree)),
+        Err(e) => {
+            pr_err!("DTB: cannot unflatten: {:?}", e);
+            return;
+        }
+    }
+    overlay::apply_boot_overlays();
+    // Publishing ends the changes to the tree
+    let devices = TREE.lock_irqsafe(|tree| tree.take().map(|tree| tree.publish()));
+    match devices {
+        Some(Ok(devices)) => {
+            DEVICE_TABLE.write().tree = Some(devices);
+            console::rebind_stdout();
+            pr_info!("DTB: {} nodes unflattened", devices.len());
+        }
+        Some(Err(e)) => pr_err!("DTB: cannot unflatten: {:?}", e),
+        None => {}
+    }
+}
+initcall!(Mmu, "unflatten", unflatten, after = ["initramfs"]);
+
+/// Returns the devices discovered so far, in structure block order
+pub fn devices() -> &'static [device::PlatformDevice] {
+    let table = DEVICE_TABLE.read();
+    if let Some(tree) = table.tree {
+        return tree;
+    }
+    // See `DEVICE_TABLE`: parsed entries outlive the lock
+    unsafe { core::slice::from_raw_parts(table.boot_devices.as_ptr(), table.boot_count) }
+}
+
+/// Returns true if `node_name` matches the path component `component`
+///
+/// A component without a unit address (e.g. `pl011`) matches any node with that base name
+/// (`pl011@9000000`), as allowed by the DTB specification.
+fn node_name_matches(node_name: &str, component: &str) -> bool {
+    if node_name == component {
+        return true;
+    }
+    !component.contains('@')
+        && node_name
+            .split_once('@')
+            .is_some_and(|(base, _)| base == component)
+}
+
+/// Find a device by its absolute path (e.g. `/chosen` or `/pl011@9000000`)
+pub fn find_device_by_path(path: &str) -> Option<&'static device::PlatformDevice> {
+    if !path.starts_with('/') {
+        return None;
+    }
+    // The root node is always the first node in the structure block
+    let mut current = devices().first()?;
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        current = devices().iter().find(|dev| {
+            core::ptr::eq(dev.parent, current) && node_name_matches(dev.name, component)
+        })?;
+    }
+    Some(current)
+}
+
+/// Resolves an alias from the `/aliases` node (e.g. `serial0`) to its device
+pub fn find_device_by_alias(alias: &str) -> Option<&'static device::PlatformDevice> {
+    let aliases = find_device_by_path("/aliases")?;
+    let path = aliases.find_property(alias)?.as_str()?;
+    find_device_by_path(path)
+}
+
+/// Returns the device selected by `/chosen/stdout-path` and the options that follow the `:`
+///
+/// The path may be absolute or an alias, e.g. `/pl011@9000000:115200n8` or `serial0:115200n8`.
+/// The legacy `linux,stdout-path` property is used if `stdout-path` is missing.
+pub fn stdout_path() -> Option<(&'static device::PlatformDevice, Option<&'static str>)> {
+    let chosen = find_device_by_path("/chosen")?;
+    let prop = chosen
+        .find_property("stdout-path")
+        .or_else(|| chosen.find_property("linux,stdout-path"))?;
+    let value = prop.as_str()?;
+    let (path, options) = match value.split_once(':') {
+        Some((path, options)) => (path, Some(options)),
+        None => (value, None),
+    };
+    let dev = if path.starts_with('/') {
+        find_device_by_path(path)?
+    } else {
+        find_device_by_alias(path)?
+    };
+    Some((dev, options))
+}
+
+/// Returns the kernel command line, from `/chosen/bootargs`
+///
+/// QEMU fills it from `-append` when it boots the kernel directly.
+pub fn bootargs() -> Option<&'static str> {
+    find_device_by_path("/chosen")?
+        .find_property("bootargs")?
+        .as_str()
+}
+
+/// Returns the value of the first `name=value` parameter of the kernel command line
+pub fn bootarg(name: &str) -> Option<&'static str> {
+    bootargs()?.split_ascii_whitespace().find_map(|arg| {
+        arg.strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+    })
+}
+
+/// Returns the `(base, size)` range of the DTB itself
+///
+/// Devices and properties point into it, so it must be kept for good.
+pub fn blob_region() -> (usize, usize) {
+    DEVICE_TABLE.read().blob
+}
+
+/// Returns the first `(base, size)` range of the `/memory` node
+pub fn memory_region() -> Option<(usize, usize)> {
+    let region = find_device_by_path("/memory")?.reg(0)?;
+    Some((region.base, region.size))
+}
+
+/// Returns the end of the CPU addresses every bus can reach by DMA, from the `dma-ranges`
+/// properties
+///
+/// A bus reaches up to the end of its highest range; the lowest of these ends is returned, or
+/// `None` if no bus restricts DMA (no `dma-ranges`, or empty ones). Parent addresses are taken as
+/// CPU addresses.
+pub fn dma_limit() -> Option<usize> {
+    devices()
+        .iter()
+        .filter_map(|dev| {
+            let ranges = dev.find_property("dma-ranges")?;
+            let (child_cells, size_cells) = dev.get_cells();
+            let (parent_cells, _) = dev.get_parent_cells();
+            let entry_len = (child_cells + parent_cells + size_cells) as usize * 4;
+            if ranges.len == 0 || entry_len == 0 || parent_cells > 2 || size_cells > 2 {
+                return None;
+            }
+            (0..ranges.len / entry_len)
+                .map(|i| {
+                    let offset = i * entry_len + child_cells as usize * 4;
+                    let parent = ranges.read_cells(offset, parent_cells);
+                    let size = ranges.read_cells(offset + parent_cells as usize * 4, size_cells);
+                    parent.saturating_add(size)
+                })
+                .max()
+        })
+        .min()
+        .map(|end| end as usize)
+}
+
+/// Returns the `(start, end)` range of the initrd, from `/chosen/linux,initrd-{start,end}`
+///
+/// Both properties are one or two cells, depending on where the bootloader put the initrd.
+pub fn initrd_region() -> Option<(usize, usize)> {
+    let chosen = find_device_by_path("/chosen")?;
+    let read = |name: &str| {
+        let prop = chosen.find_property(name)?;
+        match prop.len {
+            4 | 8 => Some(prop.read_cells(0, prop.len as u32 / 4) as usize),
+            _ => None,
+        }
+    };
+    let start = read("linux,initrd-start")?;
+    let end = read("linux,initrd-end")?;
+    (end > start).then_some((start, end))
+}
+
+/// Find a device by its phandle value
+pub fn find_device_by_phandle(phandle: u32) -> Option<&'static device::PlatformDevice> {
+    devices().iter().find(|dev| {
+        dev.find_property("phandle")
+            .is_some_and(|prop| prop.len == 4 && convert::read_be_u32(prop.value, 0) == phandle)
+    })
 }
 
 /// Find the interrupt parent for a device by walking up the tree
 /// Returns the interrupt controller device if found
+///
+/// An `interrupt-parent` on the device itself comes first, then the nearest one on an ancestor.
 pub fn find_interrupt_parent(
     dev: &device::PlatformDevice,
 ) -> Option<&'static device::PlatformDevice> {
-    unsafe {
-        let mut current = dev.parent as *const device::PlatformDevice;
-        while current != core::ptr::null() {
-            // Check if current node has interrupt-parent property
-            for i in 0..(*current).prop_count {
-                let prop = (*current).properties[i];
-                if prop.name == "interrupt-parent" {
-                    let phandle = convert::read_be_u32(prop.value, 0);
-                    return find_device_by_phandle(phandle);
-                }
-            }
-            // Walk up to parent
-            current = (*current).parent;
+    let mut current = dev as *const device::PlatformDevice;
+    while !current.is_null() {
+        let node = unsafe { &*current };
+        // Check if current node has interrupt-parent property
+        if let Some(prop) = node.find_property("interrupt-parent") {
+            let phandle = convert::read_be_u32(prop.value, 0);
+            return find_device_by_phandle(phandle);
         }
+        // Walk up to parent
+        current = node.parent;
     }
     None
 }
@@ -300,42 +784,145 @@ fn compatible_matches(prop: &device::Property, target: &str) -> bool {
     false
 }
 
-/// Initializes all discovered devices by matching against the driver table
+/// Returns the registered driver whose `compatible` string matches `dev`
 ///
-/// Runs in two passes:
-/// 1. First initializes the GIC (interrupt controller), since other devices depend on it
-///    to configure their interrupts
-/// 2. Then initializes all remaining devices (UART, timer, etc.)
-pub fn init_devices() {
-    unsafe {
-        // First pass: initialize GIC (interrupt controller must be ready before other devices)
-        for i in 0..DEVICE_COUNT {
-            let dev = &DEVICE_TABLE[i];
-            if let Some(compat_prop) = dev.find_property("compatible") {
-                if compatible_matches(compat_prop, "arm,gic-v3") {
-                    for match_entry in &device::CONFIGURED_DEVICES {
-                        if compatible_matches(compat_prop, match_entry.compatible) {
-                            (match_entry.setup_fn)(dev);
-                            break;
-                        }
-                    }
-                }
-            }
+/// Devices whose `status` disables them match no driver.
+pub fn match_driver(dev: &device::PlatformDevice) -> Option<device::DeviceMatch> {
+    if !dev.is_available() {
+        return None;
+    }
+    let compat_prop = dev.find_property("compatible")?;
+    device::find_driver(|compatible| compatible_matches(compat_prop, compatible))
+}
+
+/// Returns the `compatible` string of the driver matching `dev`, if any
+pub fn matched_driver(dev: &device::PlatformDevice) -> Option<&'static str> {
+    match_driver(dev).map(|driver| driver.compatible)
+}
+
+/// Prints the parsed device tree in a `dtc`-like syntax
+///
+/// `reg` is decoded into address/size pairs using the parent's cell sizes, `interrupts` into
+/// GIC interrupt types and IDs, and string properties are shown as strings. Nodes matching a
+/// driver are flagged with the `compatible` entry that matched, followed by `(deferred)` or
+/// `(unbound)` if the driver hasn't taken them.
+pub fn dump() {
+    if let Some(root) = devices().first() {
+        dump_node(root, 0);
+    }
+}
+
+/// Prints `dev`, at `depth` in the tree, and its children
+///
+/// Children are found through their `parent` link: the nodes added by overlays come after the
+/// others in the table.
+fn dump_node(dev: &device::PlatformDevice, depth: usize) {
+    let name = if dev.parent.is_null() { "/" } else { dev.name };
+    match matched_driver(dev) {
+        Some(driver) => println!(
+            "{:indent$}{} {{    // driver: {}{}",
+            "",
+            name,
+            driver,
+            match device::bind_state(dev) {
+                device::BindState::Bound => "",
+                device::BindState::Deferred => " (deferred)",
+                device::BindState::Unbound => " (unbound)",
+            },
+            indent = depth * 4
+        ),
+        None => println!("{:indent$}{} {{", "", name, indent = depth * 4),
+    }
+    for prop in dev.properties {
+        print!("{:indent$}{}", "", prop.name, indent = depth * 4 + 4);
+        match prop.name {
+            "reg" => dump_reg(dev, prop),
+            "interrupts" => dump_interrupts(dev, prop),
+            _ => dump_value(prop),
         }
+        println!(";");
+    }
+    for child in devices().iter().filter(|d| core::ptr::eq(d.parent, dev)) {
+        dump_node(child, depth + 1);
+    }
+    println!("{:indent$}}};", "", indent = depth * 4);
+}
 
-        // Second pass: initialize all other devices
-        for i in 0..DEVICE_COUNT {
-            let dev = &DEVICE_TABLE[i];
-            if let Some(compat_prop) = dev.find_property("compatible") {
-                if !compatible_matches(compat_prop, "arm,gic-v3") {
-                    for match_entry in &device::CONFIGURED_DEVICES {
-                        if compatible_matches(compat_prop, match_entry.compatible) {
-                            (match_entry.setup_fn)(dev);
-                            break;
-                        }
-                    }
-                }
-            }
+/// Prints `reg` as `<address size>` pairs
+fn dump_reg(dev: &device::PlatformDevice, prop: &device::Property) {
+    let (addr_cells, size_cells) = dev.get_parent_cells();
+    let entry_len = (addr_cells + size_cells) as usize * 4;
+    if entry_len == 0 || !prop.len.is_multiple_of(entry_len) || addr_cells > 2 || size_cells > 2 {
+        return dump_value(prop);
+    }
+    print!(" =");
+    for offset in (0..prop.len).step_by(entry_len) {
+        let addr = prop.read_cells(offset, addr_cells);
+        let size = prop.read_cells(offset + addr_cells as usize * 4, size_cells);
+        print!(" <{:#x} size {:#x}>", addr, size);
+    }
+}
+
+/// Prints `interrupts`, decoding GIC specifiers
+///
+/// GIC bindings use three cells: type (0 = SPI, 1 = PPI), number relative to the type's first
+/// INTID, and flags (trigger type in the low bits).
+fn dump_interrupts(dev: &device::PlatformDevice, prop: &device::Property) {
+    let cells = find_interrupt_parent(dev)
+        .and_then(|p| p.find_property("#interrupt-cells"))
+        .map(|p| convert::read_be_u32(p.value, 0));
+    if cells != Some(3) || !prop.len.is_multiple_of(12) {
+        return dump_value(prop);
+    }
+    print!(" =");
+    for offset in (0..prop.len).step_by(12) {
+        let kind = convert::read_be_u32(prop.value, offset);
+        let number = convert::read_be_u32(prop.value, offset + 4);
+        let flags = convert::read_be_u32(prop.value, offset + 8);
+        let trigger = match flags & 0xf {
+            1 => "edge-rising",
+            2 => "edge-falling",
+            4 => "level-high",
+            8 => "level-low",
+            _ => "unknown",
+        };
+        match kind {
+            0 => print!(" <SPI {} (INTID {}) {}>", number, number + 32, trigger),
+            1 => print!(" <PPI {} (INTID {}) {}>", number, number + 16, trigger),
+            _ => print!(" <{:#x} {:#x} {:#x}>", kind, number, flags),
+        }
+    }
+}
+
+/// Prints a property value as strings, cells or bytes, like `dtc -O dts`
+fn dump_value(prop: &device::Property) {
+    if prop.len == 0 || prop.value.is_null() {
+        return;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(prop.value, prop.len) };
+    let is_string_list = bytes.last() == Some(&0)
+        && bytes[0] != 0
+        && !bytes.windows(2).any(|w| w == [0, 0])
+        && bytes.iter().all(|&b| b == 0 || (0x20..=0x7e).contains(&b));
+    if is_string_list {
+        print!(" =");
+        for (i, s) in bytes[..bytes.len() - 1].split(|&b| b == 0).enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            print!("{}\"{}\"", sep, core::str::from_utf8(s).unwrap_or(""));
+        }
+    } else if bytes.len().is_multiple_of(4) {
+        print!(" = <");
+        for (i, cell) in bytes.chunks_exact(4).enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            print!("{}{:#x}", sep, convert::read_be_u32(cell.as_ptr(), 0));
+        }
+        print!(">");
+    } else {
+        print!(" = [");
+        for (i, b) in bytes.iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            print!("{}{:02x}", sep, b);
         }
+        print!("]");
     }
 }
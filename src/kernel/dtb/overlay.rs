@@ -0,0 +1,182 @@
+//! Device tree overlays
+//!
+//! An overlay is a DTB (`dtc -@ -I dts -O dtb`, from a `/plugin/;` source) whose root holds
+//! fragments, each naming a node of the base tree and carrying what to merge into it:
+//!
+//! ```text
+//! fragment@0 {
+//!     target-path = "/";
+//!     __overlay__ {
+//!         sensor@9100000 {
+//!             compatible = "vendor,sensor";
+//!             reg = <0x0 0x9100000 0x0 0x1000>;
+//!         };
+//!     };
+//! };
+//! ```
+//!
+//! `apply` merges such a blob into the unflattened tree: a property replaces the one of the same
+//! name or is added, a node is merged with the child of the same name or added. With
+//! `dtoverlay=<path>[,<path>...]` on the command line, the overlays at these paths in the
+//! initramfs are applied at boot, before the devices are probed: enough to add a device QEMU
+//! doesn't describe, or enable one with `status = "okay"`, without rebuilding the DTB.
+//!
+//! ## Design
+//!
+//! - Names and values are copied into the tree (see `tree`): the blob can go once applied.
+//! - A fragment targets a node with `target-path`, or `target` holding a phandle value. Labels of
+//!   the base tree (`target = <&uart0>`) need `__fixups__` resolved against the base tree's
+//!   `__symbols__`, which isn't supported: overlays with fixups are rejected.
+//! - Added nodes go after the existing children of their parent, and get probed in tree order.
+//! - Overlays can only be applied to the tree between `unflatten` and its publication, before
+//!   the devices are probed. The `/memory` node and `dma-ranges` have been read by then and
+//!   aren't worth overriding.
+//! - The blob is checked like the base one (see `DtError`) before anything is merged, so a
+//!   malformed overlay changes nothing. Otherwise the tree is modified in place: an overlay
+//!   failing halfway, on a missing target or memory, keeps what it applied.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux applies overlays to its unflattened tree with `of_overlay_fdt_apply`, as a changeset it
+//! can revert, after resolving phandles (`of_resolve_phandles`); bound devices are notified of
+//! the changes. The Raspberry Pi firmware applies the `dtoverlay=` lines of `config.txt` to the
+//! DTB before starting the kernel, which is the case this covers.
+
+use core::fmt;
+
+use crate::kernel::fs::initramfs;
+use crate::utilities::convert;
+use crate::{pr_err, pr_info};
+
+use super::tree::Tree;
+use super::{Blob, DtError, TREE, Token};
+
+/// Deepest nesting of nodes under an `__overlay__` node
+const MAX_DEPTH: usize = 10;
+
+/// Errors returned by `apply`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverlayError {
+    /// The blob isn't a well-formed DTB
+    Malformed(DtError),
+    /// The tree has been published already, or couldn't be built
+    TooLate,
+    /// A fragment has no target, or one not in the tree
+    NoTarget,
+    /// The overlay refers to labels of the base tree (`__fixups__`)
+    Unresolved,
+    /// No memory left for the nodes or properties
+    NoMemory,
+    /// Nodes nested deeper than `MAX_DEPTH`
+    TooDeep,
+}
+
+impl fmt::Display for OverlayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverlayError::Malformed(e) => write!(f, "malformed blob: {}", e),
+            OverlayError::TooLate => write!(f, "device tree already published"),
+            OverlayError::NoTarget => write!(f, "fragment target not found"),
+            OverlayError::Unresolved => write!(f, "unresolved references (__fixups__)"),
+            OverlayError::NoMemory => write!(f, "out of memory"),
+            OverlayError::TooDeep => write!(f, "nodes nested deeper than {}", MAX_DEPTH),
+        }
+    }
+}
+
+/// Merges the overlay blob `data` into the device tree, returning the number of fragments
+pub fn apply(data: &[u8]) -> Result<usize, OverlayError> {
+    let blob = Blob::check(data.as_ptr() as usize, data.len()).map_err(OverlayError::Malformed)?;
+    super::walk_structure(&blob, |_| {}).map_err(OverlayError::Malformed)?;
+    TREE.lock_irqsafe(|tree| merge(tree.as_mut().ok_or(OverlayError::TooLate)?, &blob))
+}
+
+/// Merges the fragments of the checked overlay `blob` into `tree`
+fn merge(tree: &mut Tree, blob: &Blob) -> Result<usize, OverlayError> {
+    let mut result = Ok(0);
+    let mut depth = 0;
+    // Node of the base tree the current fragment targets
+    let mut target = None;
+    // Nodes merged into, from the target down to the current node; empty outside `__overlay__`
+    let mut stack = [core::ptr::null_mut(); MAX_DEPTH];
+    let mut merging = 0;
+    // Walked once already by `apply`
+    let _ = super::walk_structure(blob, |token| {
+        if result.is_err() {
+            return;
+        }
+        match token {
+            Token::BeginNode(name) => {
+                depth += 1;
+                if depth == 2 {
+                    target = None;
+                    if name == "__fixups__" {
+                        result = Err(OverlayError::Unresolved);
+                    }
+                } else if merging == MAX_DEPTH {
+                    result = Err(OverlayError::TooDeep);
+                } else if merging > 0 {
+                    let parent = stack[merging - 1];
+                    let node = match tree.child(parent, name, true) {
+                        Some(node) => Ok(node),
+                        None => tree.add_child(parent, name),
+                    };
+                    match node {
+                        Ok(node) => {
+                            stack[merging] = node;
+                            merging += 1;
+                        }
+                        Err(_) => result = Err(OverlayError::NoMemory),
+                    }
+                } else if depth == 3 && name == "__overlay__" {
+                    match target {
+                        Some(node) => {
+                            stack[0] = node;
+                            merging = 1;
+                            result = result.map(|fragments| fragments + 1);
+                        }
+                        None => result = Err(OverlayError::NoTarget),
+                    }
+                }
+            }
+            Token::EndNode => {
+                merging = merging.saturating_sub(1);
+                depth -= 1;
+            }
+            // Properties come before the subnodes, so the target is known before `__overlay__`
+            Token::Prop(prop) if merging > 0 => {
+                if tree.set_property(stack[merging - 1], &prop).is_err() {
+                    result = Err(OverlayError::NoMemory);
+                }
+            }
+            Token::Prop(prop) if depth == 2 => match prop.name {
+                "target-path" => target = prop.as_str().and_then(|path| tree.find_path(path)),
+                "target" if prop.len == 4 => {
+                    target = tree.find_phandle(convert::read_be_u32(prop.value, 0));
+                }
+                _ => {}
+            },
+            Token::Prop(_) => {}
+        }
+    });
+    result
+}
+
+/// Applies the overlays named by `dtoverlay=` from the initramfs
+///
+/// Called by `unflatten` before it publishes the tree.
+pub(super) fn apply_boot_overlays() {
+    let Some(paths) = super::bootarg("dtoverlay") else {
+        return;
+    };
+    for path in paths.split(',').filter(|p| !p.is_empty()) {
+        let Ok(file) = initramfs::open(path) else {
+            pr_err!("dtoverlay: {} not in the initramfs", path);
+            continue;
+        };
+        match apply(file.data()) {
+            Ok(fragments) => pr_info!("dtoverlay: {}: {} fragments applied", path, fragments),
+            Err(e) => pr_err!("dtoverlay: {}: {}", path, e),
+        }
+    }
+}
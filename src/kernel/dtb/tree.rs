@@ -0,0 +1,266 @@
+//! Unflattened device tree
+//!
+//! The flattened blob is parsed at boot, before the heap exists, into a small fixed table (see
+//! `parse_dtb`). Once the frame allocator is up, `unflatten` builds the whole tree again from the
+//! heap: one `Node` per node, linked to its parent, first child and next sibling, holding copies
+//! of its name and properties. Overlays are applied to it (see `overlay`), then `publish` lays
+//! out a `PlatformDevice` per node, in depth-first order, which replaces the boot table behind
+//! `dtb::devices` and the other lookups.
+//!
+//! ## Design
+//!
+//! - Nodes, names and property values are allocated with `heap::alloc_array` and never freed:
+//!   the tree lasts as long as the kernel, and no longer points into the blob.
+//! - A node's properties are an array that doubles when full, so overlays can add to it.
+//! - `PlatformDevice` stays the view drivers get: a name, a `parent` link and a slice of
+//!   properties, here those of the node. The views are laid out once, after the last change to
+//!   the tree, so references to them stay valid.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is `unflatten_device_tree`, building `struct device_node`s with `parent`, `child` and
+//! `sibling` links and `struct property` lists. Linux points the properties into the blob,
+//! which it keeps, and creates the `platform_device`s separately
+//! (`of_platform_default_populate`); here the views are the platform devices.
+
+use crate::kernel::device::{PlatformDevice, Property};
+use crate::kernel::mm::heap::{self, HeapError};
+use crate::utilities::convert;
+
+use super::{Blob, Token, node_name_matches};
+
+/// Properties a node has room for at first
+const INITIAL_PROPS: usize = 8;
+
+/// A node of the tree
+#[derive(Clone, Copy)]
+pub(super) struct Node {
+    name: &'static str,
+    parent: *mut Node,
+    /// First child, null if none
+    child: *mut Node,
+    /// Next child of the parent, null if last
+    sibling: *mut Node,
+    /// Array of `capacity` properties, the first `prop_count` set
+    properties: *mut Property,
+    capacity: usize,
+    prop_count: usize,
+    /// The view of the node laid out by `publish`
+    view: *const PlatformDevice,
+}
+
+/// A tree built by `unflatten`
+pub(super) struct Tree {
+    root: *mut Node,
+}
+
+/// Safety: the nodes are only reached through the tree, which is behind a lock
+unsafe impl Send for Tree {}
+
+/// Returns a copy of `s` on the heap
+fn copy_str(s: &str) -> Result<&'static str, HeapError> {
+    let bytes = heap::alloc_array(s.len(), 0u8)?;
+    bytes.copy_from_slice(s.as_bytes());
+    Ok(unsafe { core::str::from_utf8_unchecked(bytes) })
+}
+
+/// Returns a copy of `prop`, name and value, on the heap
+fn copy_property(prop: &Property) -> Result<Property, HeapError> {
+    let value = heap::alloc_array(prop.len, 0u8)?;
+    if prop.len != 0 {
+        value.copy_from_slice(unsafe { core::slice::from_raw_parts(prop.value, prop.len) });
+    }
+    Ok(Property {
+        name: copy_str(prop.name)?,
+        value: if prop.len == 0 {
+            core::ptr::null()
+        } else {
+            value.as_ptr()
+        },
+        len: prop.len,
+    })
+}
+
+/// Returns the node after `node` in depth-first order, null after the last one
+fn next(node: *mut Node) -> *mut Node {
+    unsafe {
+        if !(*node).child.is_null() {
+            return (*node).child;
+        }
+        let mut node = node;
+        while !node.is_null() {
+            if !(*node).sibling.is_null() {
+                return (*node).sibling;
+            }
+            node = (*node).parent;
+        }
+    }
+    core::ptr::null_mut()
+}
+
+impl Node {
+    fn properties(&self) -> &'static [Property] {
+        if self.prop_count == 0 {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.properties, self.prop_count) }
+    }
+}
+
+impl Tree {
+    /// Builds the tree of the checked `blob`
+    pub(super) fn unflatten(blob: &Blob) -> Result<Self, HeapError> {
+        let mut tree = Tree {
+            root: core::ptr::null_mut(),
+        };
+        let mut current: *mut Node = core::ptr::null_mut();
+        let mut result = Ok(());
+        // Checked by `parse_dtb`: only the heap can fail
+        let _ = super::walk_structure(blob, |token| {
+            if result.is_err() {
+                return;
+            }
+            result = match token {
+                Token::BeginNode(name) if current.is_null() => {
+                    tree.new_node(core::ptr::null_mut(), name).map(|root| {
+                        tree.root = root;
+                        current = root;
+                    })
+                }
+                Token::BeginNode(name) => tree.add_child(current, name).map(|node| {
+                    current = node;
+                }),
+                Token::EndNode => {
+                    current = unsafe { (*current).parent };
+                    Ok(())
+                }
+                Token::Prop(prop) => tree.set_property(current, &prop),
+            };
+        });
+        result.map(|()| tree)
+    }
+
+    /// Allocates a node called `name` under `parent`, not linked to it yet
+    fn new_node(&mut self, parent: *mut Node, name: &str) -> Result<*mut Node, HeapError> {
+        let node = Node {
+            name: copy_str(name)?,
+            parent,
+            child: core::ptr::null_mut(),
+            sibling: core::ptr::null_mut(),
+            properties: core::ptr::null_mut(),
+            capacity: 0,
+            prop_count: 0,
+            view: core::ptr::null(),
+        };
+        Ok(heap::alloc_array(1, node)?.as_mut_ptr())
+    }
+
+    /// Adds a node called `name` after the last child of `parent`
+    pub(super) fn add_child(
+        &mut self,
+        parent: *mut Node,
+        name: &str,
+    ) -> Result<*mut Node, HeapError> {
+        let node = self.new_node(parent, name)?;
+        unsafe {
+            let mut link = &mut (*parent).child;
+            while !link.is_null() {
+                link = &mut (**link).sibling;
+            }
+            *link = node;
+        }
+        Ok(node)
+    }
+
+    /// Returns the child of `parent` called `name`
+    ///
+    /// Unless `exact`, `name` is a path component: see `node_name_matches`.
+    pub(super) fn child(&self, parent: *mut Node, name: &str, exact: bool) -> Option<*mut Node> {
+        let mut child = unsafe { (*parent).child };
+        while !child.is_null() {
+            let child_name = unsafe { (*child).name };
+            if child_name == name || (!exact && node_name_matches(child_name, name)) {
+                return Some(child);
+            }
+            child = unsafe { (*child).sibling };
+        }
+        None
+    }
+
+    /// Returns the node at the absolute `path`
+    pub(super) fn find_path(&self, path: &str) -> Option<*mut Node> {
+        if !path.starts_with('/') || self.root.is_null() {
+            return None;
+        }
+        let mut current = self.root;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            current = self.child(current, component, false)?;
+        }
+        Some(current)
+    }
+
+    /// Returns the node with the given phandle
+    pub(super) fn find_phandle(&self, phandle: u32) -> Option<*mut Node> {
+        let mut node = self.root;
+        while !node.is_null() {
+            let found = unsafe { (*node).properties() }.iter().any(|p| {
+                p.name == "phandle" && p.len == 4 && convert::read_be_u32(p.value, 0) == phandle
+            });
+            if found {
+                return Some(node);
+            }
+            node = next(node);
+        }
+        None
+    }
+
+    /// Gives `node` a copy of `prop`, replacing the property with the same name
+    pub(super) fn set_property(
+        &mut self,
+        node: *mut Node,
+        prop: &Property,
+    ) -> Result<(), HeapError> {
+        let prop = copy_property(prop)?;
+        let node = unsafe { &mut *node };
+        if let Some(index) = node.properties().iter().position(|p| p.name == prop.name) {
+            unsafe { *node.properties.add(index) = prop };
+            return Ok(());
+        }
+        if node.prop_count == node.capacity {
+            let capacity = (node.capacity * 2).max(INITIAL_PROPS);
+            let properties = heap::alloc_array(capacity, Property::new())?;
+            properties[..node.prop_count].copy_from_slice(node.properties());
+            if !node.properties.is_null() {
+                let _ = heap::kfree(node.properties as usize);
+            }
+            node.properties = properties.as_mut_ptr();
+            node.capacity = capacity;
+        }
+        unsafe { *node.properties.add(node.prop_count) = prop };
+        node.prop_count += 1;
+        Ok(())
+    }
+
+    /// Lays out a `PlatformDevice` per node, in depth-first order
+    pub(super) fn publish(&self) -> Result<&'static [PlatformDevice], HeapError> {
+        let mut count = 0;
+        let mut node = self.root;
+        while !node.is_null() {
+            count += 1;
+            node = next(node);
+        }
+        let devices = heap::alloc_array(count, PlatformDevice::new())?;
+        let mut node = self.root;
+        for dev in devices.iter_mut() {
+            let node_ref = unsafe { &mut *node };
+            dev.name = node_ref.name;
+            dev.properties = node_ref.properties();
+            if !node_ref.parent.is_null() {
+                dev.parent = unsafe { (*node_ref.parent).view };
+            }
+            node_ref.view = dev;
+            node = next(node);
+        }
+        Ok(devices)
+    }
+}
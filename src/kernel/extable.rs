@@ -0,0 +1,59 @@
+//! Exception fixup table
+//!
+//! Some accesses are expected to fault: probing an address handed over by the DTB or, later, a
+//! buffer passed by user space. The assembly routines doing such accesses list each instruction
+//! that may fault, together with the address to resume at, in the `__ex_table` section (see
+//! `_asm_extable` in `asm/extable.h`). When a data abort is taken from one of those
+//! instructions, `do_sync` redirects `ELR_EL1` to the fixup instead of panicking, and the routine
+//! reports the failure to its caller.
+
+use core::ptr::addr_of;
+
+use crate::kernel::irq::Regs;
+use crate::kernel::mm::kaslr;
+
+/// An entry in the `__ex_table` section, as emitted by `_asm_extable`
+#[repr(C)]
+struct ExceptionTableEntry {
+    /// Address of the instruction allowed to fault
+    insn: u64,
+    /// Address to resume at when it does
+    fixup: u64,
+}
+
+unsafe extern "C" {
+    static __ex_table_start: ExceptionTableEntry;
+    static __ex_table_end: ExceptionTableEntry;
+}
+
+/// Returns the entries of the fixup table
+fn entries() -> &'static [ExceptionTableEntry] {
+    unsafe {
+        let start = addr_of!(__ex_table_start);
+        let end = addr_of!(__ex_table_end);
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Returns the fixup address registered for the instruction at `addr`, if any
+pub fn search(addr: u64) -> Option<u64> {
+    entries()
+        .iter()
+        .find(|entry| entry.insn == addr)
+        .map(|entry| entry.fixup)
+}
+
+/// Redirects a faulting context to its fixup
+///
+/// Returns false if the faulting instruction has no entry in the table, i.e. the fault is a bug.
+/// The table holds linked addresses, so an access made from the randomized copy of the image
+/// (see `kaslr`) resumes in the identity-mapped one.
+pub fn fixup_exception(regs: &mut Regs) -> bool {
+    match search(kaslr::to_link_address(regs.elr)) {
+        Some(fixup) => {
+            regs.elr = fixup;
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,178 @@
+//! Device filesystem
+//!
+//! A flat directory, mounted at `/dev`, listing the devices drivers have made available. A driver
+//! registers a name and the VFS node of its device; the node's `Inode` and `File` implementations
+//! are the device's `stat`, `read`, `write` and `ioctl` hooks, so nothing here knows about
+//! particular devices. Consoles appear under their tty names (`/dev/ttyAMA0`), `/dev/console` is
+//! whichever console is active, and `/dev/null` and `/dev/zero` are always there.
+//!
+//! Devices can register before `init` mounts the filesystem; entries are never removed.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is closer to the old static devtmpfs than to udev: entries are created by the kernel as
+//! drivers bind, there are no device numbers, and names can't be changed from user space.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::console;
+use crate::kernel::fs::vfs::{self, DirEntry, FileKind, FsError, Ino, Node, Stat};
+use crate::{initcall, pr_err};
+
+/// Maximum number of device files
+const MAX_DEVICES: usize = 32;
+
+/// Inode number of the `/dev` directory
+const ROOT_INO: Ino = 0;
+
+/// Inode numbers of the memory devices, Linux's minor numbers
+const NULL_INO: Ino = 3;
+const ZERO_INO: Ino = 5;
+
+/// Errors returned by `register`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DevfsError {
+    /// A device is already registered under this name
+    Exists,
+    /// The device table is full
+    NoSpace,
+}
+
+/// A device file
+#[derive(Clone, Copy)]
+struct Device {
+    name: &'static str,
+    node: Node,
+}
+
+static DEVICES: Mutex<[Option<Device>; MAX_DEVICES]> = Mutex::new([None; MAX_DEVICES]);
+
+/// Makes `node` available as `/dev/<name>`
+pub fn register(name: &'static str, node: Node) -> Result<(), DevfsError> {
+    DEVICES.lock_irqsafe(|devices| {
+        if devices.iter().flatten().any(|d| d.name == name) {
+            return Err(DevfsError::Exists);
+        }
+        let slot = devices
+            .iter_mut()
+            .find(|d| d.is_none())
+            .ok_or(DevfsError::NoSpace)?;
+        *slot = Some(Device { name, node });
+        Ok(())
+    })
+}
+
+/// Registers the built-in devices and mounts the filesystem at `/dev`
+pub fn init() {
+    let builtin = [
+        ("console", console::CONSOLE.node()),
+        ("null", MEM.node(NULL_INO)),
+        ("zero", MEM.node(ZERO_INO)),
+    ];
+    for (name, node) in builtin {
+        if let Err(e) = register(name, node) {
+            pr_err!("devfs: cannot register {}: {:?}", name, e);
+        }
+    }
+    if let Err(e) = vfs::mount("/dev", &DEVFS) {
+        pr_err!("devfs: cannot mount: {:?}", e);
+    }
+}
+initcall!(Driver, "devfs", init, after = ["initramfs"]);
+
+/// The `/dev` directory
+pub struct Devfs;
+
+/// The filesystem `init` mounts at `/dev`
+pub static DEVFS: Devfs = Devfs;
+
+impl vfs::FileSystem for Devfs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Node {
+        Node {
+            inode: &DEVFS,
+            ino: ROOT_INO,
+        }
+    }
+}
+
+impl vfs::Inode for Devfs {
+    fn stat(&self, _ino: Ino) -> Result<Stat, FsError> {
+        Ok(Stat {
+            kind: FileKind::Directory,
+            size: 0,
+            mode: 0o755,
+        })
+    }
+
+    fn lookup(&self, _dir: Ino, name: &str) -> Result<Node, FsError> {
+        DEVICES.lock_irqsafe(|devices| {
+            devices
+                .iter()
+                .flatten()
+                .find(|d| d.name == name)
+                .map(|d| d.node)
+                .ok_or(FsError::NotFound)
+        })
+    }
+
+    fn read_dir(&self, _dir: Ino, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let device = DEVICES.lock_irqsafe(|devices| devices.iter().flatten().nth(index).copied());
+        match device {
+            Some(device) => Ok(Some(DirEntry::new(device.name, device.node.stat()?.kind))),
+            None => Ok(None),
+        }
+    }
+
+    fn open(&self, _ino: Ino) -> Result<&'static dyn vfs::File, FsError> {
+        Ok(&DEVFS)
+    }
+}
+
+impl vfs::File for Devfs {
+    fn read(&self, _ino: Ino, _offset: usize, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::IsDirectory)
+    }
+}
+
+/// `/dev/null` and `/dev/zero`, told apart by their inode number
+struct MemDevice;
+
+static MEM: MemDevice = MemDevice;
+
+impl MemDevice {
+    fn node(&'static self, ino: Ino) -> Node {
+        Node { inode: self, ino }
+    }
+}
+
+impl vfs::Inode for MemDevice {
+    fn stat(&self, _ino: Ino) -> Result<Stat, FsError> {
+        Ok(Stat {
+            kind: FileKind::CharDevice,
+            size: 0,
+            mode: 0o666,
+        })
+    }
+
+    fn open(&self, _ino: Ino) -> Result<&'static dyn vfs::File, FsError> {
+        Ok(&MEM)
+    }
+}
+
+impl vfs::File for MemDevice {
+    fn read(&self, ino: Ino, _offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        if ino == NULL_INO {
+            return Ok(0);
+        }
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    /// Both discard what is written
+    fn write(&self, _ino: Ino, _offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
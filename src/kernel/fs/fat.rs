@@ -0,0 +1,611 @@
+//! FAT32 filesystem (read-only)
+//!
+//! Reads FAT32 volumes from a block device: files and directories are cluster chains followed
+//! through the first FAT, and directory entries carry both an 8.3 short name and, when the name
+//! doesn't fit it, a long file name (LFN) spread over the entries preceding it. Lookups are
+//! case-insensitive, as on every FAT implementation.
+//!
+//! ## Design
+//!
+//! - A mounted volume is a `FatVolume`, which is both the `FileSystem` and the `Inode`/`File`
+//!   implementation of its files. Volumes live in a static table, up to `MAX_VOLUMES`.
+//! - Inode numbers locate the short directory entry of a file: the sector holding it and the
+//!   entry's index in that sector. The root directory, which has no entry, is inode 0 (sector 0
+//!   is the boot sector, so no entry can be there). Everything else (first cluster, size,
+//!   attributes) is read back from the entry when needed.
+//! - Sectors are read one at a time through the block cache, so walking a directory or a FAT
+//!   only goes to the device for sectors not used recently.
+//!
+//! Only 512-byte sectors are supported. A volume can fill a whole disk or be on a partition.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `vfat` goes through the buffer cache and supports FAT12/16, writes and code pages;
+//! this driver only decodes LFNs to UTF-8 and leaves short names in their raw 8-bit encoding,
+//! replacing non-ASCII bytes.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::block::{self, BlockError, Disk, SECTOR_SIZE};
+use crate::kernel::fs::vfs::{self, DirEntry, FileKind, FsError, Ino, Node, Stat};
+use crate::utilities::convert::{read_le_u16, read_le_u32};
+use crate::{initcall, println};
+
+/// Maximum number of mounted volumes
+const MAX_VOLUMES: usize = 4;
+
+/// Size of a directory entry
+const DIR_ENTRY_SIZE: usize = 32;
+
+/// Maximum length of a long file name, in UTF-16 code units
+const LFN_MAX: usize = 255;
+
+/// UTF-16 code units stored in one LFN entry
+const LFN_CHARS_PER_ENTRY: usize = 13;
+
+/// Inode number of the root directory
+const ROOT_INO: Ino = 0;
+
+/// Directory entry attributes
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// First byte of a deleted entry, and of the entry ending a directory
+const ENTRY_DELETED: u8 = 0xe5;
+const ENTRY_END: u8 = 0x00;
+
+/// Flag of the LFN entry holding the end of the name, stored first
+const LFN_LAST: u8 = 0x40;
+
+/// Flags in byte 12 of a short entry: the base name or the extension is lower case
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+/// FAT entries at or above this value end a chain
+const FAT_EOC: u32 = 0x0fff_fff8;
+/// Only the low 28 bits of a FAT32 entry are used
+const FAT_MASK: u32 = 0x0fff_ffff;
+
+/// Errors returned by `mount`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FatError {
+    /// The device doesn't hold a FAT32 volume
+    NotFat32,
+    /// The volume uses sectors of another size than `SECTOR_SIZE`
+    BadSectorSize,
+    /// Every volume slot is in use
+    NoSpace,
+    /// The device failed
+    Io(BlockError),
+    /// The mount point is taken
+    Mount(FsError),
+}
+
+/// Geometry of a mounted volume, from its boot sector
+#[derive(Clone, Copy)]
+struct Geometry {
+    disk: Disk,
+    sectors_per_cluster: u64,
+    /// First sector of the first FAT
+    fat_start: u64,
+    /// First sector of cluster 2
+    data_start: u64,
+    root_cluster: u32,
+    /// Number of data clusters, the highest valid cluster being `cluster_count + 1`
+    cluster_count: u32,
+}
+
+impl Geometry {
+    /// Parses and checks the boot sector of `disk`
+    fn read(disk: Disk) -> Result<Self, FatError> {
+        let mut boot = [0u8; SECTOR_SIZE];
+        disk.read(0, &mut boot).map_err(FatError::Io)?;
+        let ptr = boot.as_ptr();
+        if boot[510..512] != [0x55, 0xaa] {
+            return Err(FatError::NotFat32);
+        }
+        let bytes_per_sector = read_le_u16(ptr, 11) as usize;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = read_le_u16(ptr, 14) as u64;
+        let fats = boot[16] as u64;
+        let root_entries = read_le_u16(ptr, 17);
+        let total16 = read_le_u16(ptr, 19) as u64;
+        let fat_size16 = read_le_u16(ptr, 22);
+        let total32 = read_le_u32(ptr, 32) as u64;
+        let fat_size = read_le_u32(ptr, 36) as u64;
+        let root_cluster = read_le_u32(ptr, 44);
+
+        // FAT32 is told apart by its BPB: no fixed root directory, no 16-bit FAT size
+        if root_entries != 0 || fat_size16 != 0 || fat_size == 0 || fats == 0 {
+            return Err(FatError::NotFat32);
+        }
+        if bytes_per_sector != SECTOR_SIZE {
+            return Err(FatError::BadSectorSize);
+        }
+        if !sectors_per_cluster.is_power_of_two() {
+            return Err(FatError::NotFat32);
+        }
+        let total = if total16 != 0 { total16 } else { total32 };
+        let data_start = reserved + fats * fat_size;
+        let cluster_count = total.saturating_sub(data_start) / sectors_per_cluster;
+        if total > disk.sector_count() || cluster_count == 0 || root_cluster < 2 {
+            return Err(FatError::NotFat32);
+        }
+        Ok(Self {
+            disk,
+            sectors_per_cluster,
+            fat_start: reserved,
+            data_start,
+            root_cluster,
+            cluster_count: cluster_count.min(FAT_MASK as u64 - 1) as u32,
+        })
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    /// Returns the first sector of `cluster`
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+}
+
+/// A short directory entry, with the location it was read from
+#[derive(Clone, Copy)]
+struct ShortEntry {
+    attr: u8,
+    cluster: u32,
+    size: u32,
+    ino: Ino,
+}
+
+impl ShortEntry {
+    fn parse(raw: &[u8], ino: Ino) -> Self {
+        let ptr = raw.as_ptr();
+        let high = read_le_u16(ptr, 20) as u32;
+        let low = read_le_u16(ptr, 26) as u32;
+        Self {
+            attr: raw[11],
+            cluster: (high << 16) | low,
+            size: read_le_u32(ptr, 28),
+            ino,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    fn kind(&self) -> FileKind {
+        if self.is_dir() {
+            FileKind::Directory
+        } else {
+            FileKind::File
+        }
+    }
+}
+
+/// Inode number of the `index`-th entry of `sector`
+fn entry_ino(sector: u64, index: usize) -> Ino {
+    (sector << 8) | index as Ino
+}
+
+/// Checksum of a short name, stored in the LFN entries belonging to it
+fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// Builds a file name from directory entries
+struct NameBuffer {
+    /// Long name being collected, in UTF-16
+    lfn: [u16; LFN_MAX],
+    /// Checksum of the short name the long name belongs to
+    lfn_checksum: u8,
+    /// Sequence number of the next LFN entry expected, 0 when no long name is pending
+    lfn_next: u8,
+    /// Long name complete up to the short entry
+    lfn_done: bool,
+    /// The name returned to callers, in UTF-8
+    name: [u8; LFN_MAX * 3],
+    name_len: usize,
+}
+
+impl NameBuffer {
+    fn new() -> Self {
+        Self {
+            lfn: [0; LFN_MAX],
+            lfn_checksum: 0,
+            lfn_next: 0,
+            lfn_done: false,
+            name: [0; LFN_MAX * 3],
+            name_len: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.lfn_next = 0;
+        self.lfn_done = false;
+    }
+
+    /// Collects an LFN entry; entries come last part first, numbered down to 1
+    fn add_lfn(&mut self, raw: &[u8]) {
+        let seq = raw[0] & 0x1f;
+        if raw[0] & LFN_LAST != 0 {
+            self.lfn.fill(0xffff);
+            self.lfn_checksum = raw[13];
+            self.lfn_next = seq;
+            self.lfn_done = false;
+        }
+        if seq == 0 || seq != self.lfn_next || raw[13] != self.lfn_checksum {
+            self.reset();
+            return;
+        }
+        let base = (seq as usize - 1) * LFN_CHARS_PER_ENTRY;
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (i, off) in offsets.enumerate() {
+            if let Some(slot) = self.lfn.get_mut(base + i) {
+                *slot = read_le_u16(raw.as_ptr(), off);
+            }
+        }
+        self.lfn_next = seq - 1;
+        self.lfn_done = self.lfn_next == 0;
+    }
+
+    /// Sets the name from the short entry `raw`, using the pending long name if it matches
+    fn finish(&mut self, raw: &[u8]) -> &str {
+        self.name_len = 0;
+        if self.lfn_done && lfn_checksum(&raw[..11]) == self.lfn_checksum {
+            let units = self
+                .lfn
+                .iter()
+                .copied()
+                .take_while(|&c| c != 0 && c != 0xffff);
+            for c in char::decode_utf16(units) {
+                let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+                if self.name_len + c.len_utf8() > self.name.len() {
+                    break;
+                }
+                self.name_len += c.encode_utf8(&mut self.name[self.name_len..]).len();
+            }
+        } else {
+            self.short_name(raw);
+        }
+        self.reset();
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("?")
+    }
+
+    /// Formats the 8.3 name of `raw` as `BASE.EXT`, honouring the lower-case flags
+    fn short_name(&mut self, raw: &[u8]) {
+        let lower_base = raw[12] & NT_LOWER_BASE != 0;
+        let lower_ext = raw[12] & NT_LOWER_EXT != 0;
+        let trim = |part: &[u8]| part.len() - part.iter().rev().take_while(|&&c| c == b' ').count();
+        let base = &raw[..trim(&raw[..8])];
+        let ext = &raw[8..8 + trim(&raw[8..11])];
+        let mut push = |c: u8, lower: bool| {
+            let c = match c {
+                // 0x05 stands for a leading 0xe5, which marks deleted entries
+                0x05 => b'?',
+                c if !c.is_ascii() => b'?',
+                c if lower => c.to_ascii_lowercase(),
+                c => c,
+            };
+            self.name[self.name_len] = c;
+            self.name_len += 1;
+        };
+        base.iter().for_each(|&c| push(c, lower_base));
+        if !ext.is_empty() {
+            push(b'.', false);
+            ext.iter().for_each(|&c| push(c, lower_ext));
+        }
+    }
+}
+
+/// A mounted FAT32 volume
+pub struct FatVolume {
+    /// Slot in `VOLUMES`
+    index: usize,
+    geometry: Mutex<Option<Geometry>>,
+}
+
+impl FatVolume {
+    const fn new(index: usize) -> Self {
+        Self {
+            index,
+            geometry: Mutex::new(None),
+        }
+    }
+
+    /// Returns the volume with the `'static` lifetime nodes need
+    fn this(&self) -> &'static FatVolume {
+        &VOLUMES[self.index]
+    }
+
+    fn geometry(&self) -> Result<Geometry, FsError> {
+        self.geometry
+            .lock_irqsafe(|geometry| *geometry)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<(), FsError> {
+        Ok(self.geometry()?.disk.read(sector, buf)?)
+    }
+
+    /// Returns the cluster following `cluster` in its chain, `None` at the end of the chain
+    fn next_cluster(&self, geo: &Geometry, cluster: u32) -> Result<Option<u32>, FsError> {
+        let offset = cluster as u64 * 4;
+        let mut sector = [0u8; SECTOR_SIZE];
+        self.read_sector(geo.fat_start + offset / SECTOR_SIZE as u64, &mut sector)?;
+        let next = read_le_u32(sector.as_ptr(), offset as usize % SECTOR_SIZE) & FAT_MASK;
+        if next >= FAT_EOC {
+            return Ok(None);
+        }
+        if !geo.valid_cluster(next) {
+            // A free or bad cluster in a chain: the volume is corrupted
+            return Err(FsError::Io);
+        }
+        Ok(Some(next))
+    }
+
+    /// Returns the `index`-th cluster of the chain starting at `first`, `None` past its end
+    fn nth_cluster(
+        &self,
+        geo: &Geometry,
+        first: u32,
+        index: usize,
+    ) -> Result<Option<u32>, FsError> {
+        if !geo.valid_cluster(first) {
+            return Ok(None);
+        }
+        let mut cluster = first;
+        for _ in 0..index {
+            match self.next_cluster(geo, cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(cluster))
+    }
+
+    /// Reads the short entry with inode number `ino`
+    fn entry(&self, ino: Ino) -> Result<ShortEntry, FsError> {
+        let geo = self.geometry()?;
+        if ino == ROOT_INO {
+            return Ok(ShortEntry {
+                attr: ATTR_DIRECTORY,
+                cluster: geo.root_cluster,
+                size: 0,
+                ino,
+            });
+        }
+        let mut sector = [0u8; SECTOR_SIZE];
+        self.read_sector(ino >> 8, &mut sector)?;
+        let offset = (ino & 0xff) as usize * DIR_ENTRY_SIZE;
+        Ok(ShortEntry::parse(
+            &sector[offset..offset + DIR_ENTRY_SIZE],
+            ino,
+        ))
+    }
+
+    /// Returns the directory with inode number `ino`
+    fn dir(&self, ino: Ino) -> Result<ShortEntry, FsError> {
+        let dir = self.entry(ino)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotDirectory);
+        }
+        Ok(dir)
+    }
+
+    /// Calls `f` on every file of the directory `dir` with its name, stopping early if it
+    /// returns true
+    ///
+    /// Deleted entries, the volume label and the `.` and `..` entries are skipped.
+    fn walk_dir(
+        &self,
+        dir: &ShortEntry,
+        mut f: impl FnMut(&str, &ShortEntry) -> bool,
+    ) -> Result<(), FsError> {
+        let geo = self.geometry()?;
+        let mut names = NameBuffer::new();
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut cluster = if dir.cluster == 0 {
+            // `..` entries pointing to the root store cluster 0
+            geo.root_cluster
+        } else {
+            dir.cluster
+        };
+        // Bounds the walk should the chain loop
+        for _ in 0..geo.cluster_count {
+            if !geo.valid_cluster(cluster) {
+                return Err(FsError::Io);
+            }
+            let first = geo.cluster_sector(cluster);
+            for lba in first..first + geo.sectors_per_cluster {
+                self.read_sector(lba, &mut sector)?;
+                for (index, raw) in sector.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                    match raw[0] {
+                        ENTRY_END => return Ok(()),
+                        ENTRY_DELETED => names.reset(),
+                        _ if raw[11] & ATTR_LONG_NAME == ATTR_LONG_NAME => names.add_lfn(raw),
+                        _ if raw[11] & ATTR_VOLUME_ID != 0 || raw[0] == b'.' => names.reset(),
+                        _ => {
+                            let entry = ShortEntry::parse(raw, entry_ino(lba, index));
+                            if f(names.finish(raw), &entry) {
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            }
+            match self.next_cluster(&geo, cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(()),
+            }
+        }
+        Err(FsError::Io)
+    }
+}
+
+/// The volumes, mounted in slot order
+static VOLUMES: [FatVolume; MAX_VOLUMES] = [
+    FatVolume::new(0),
+    FatVolume::new(1),
+    FatVolume::new(2),
+    FatVolume::new(3),
+];
+
+/// Mounts the FAT32 volume on `disk` at `path`
+pub fn mount(disk: Disk, path: &'static str) -> Result<(), FatError> {
+    let geometry = Geometry::read(disk)?;
+    let volume = VOLUMES
+        .iter()
+        .find(|v| {
+            v.geometry.lock_irqsafe(|g| {
+                let free = g.is_none();
+                if free {
+                    *g = Some(geometry);
+                }
+                free
+            })
+        })
+        .ok_or(FatError::NoSpace)?;
+    if let Err(e) = vfs::mount(path, volume) {
+        volume.geometry.lock_irqsafe(|g| *g = None);
+        return Err(FatError::Mount(e));
+    }
+    println!(
+        "fat: {} mounted at {} ({} clusters of {} bytes)",
+        disk.name(),
+        path,
+        geometry.cluster_count,
+        geometry.cluster_size()
+    );
+    Ok(())
+}
+
+/// Mounts the first FAT32 volume found on the disks and partitions
+///
+/// It becomes the root filesystem if nothing is mounted at `/` yet (no initramfs), and goes to
+/// `/mnt` otherwise.
+pub fn init() {
+    let mut mounted = false;
+    block::for_each(|disk| {
+        if mounted {
+            return;
+        }
+        mounted = match mount(disk, "/") {
+            Ok(()) => true,
+            Err(FatError::Mount(FsError::Busy)) => mount(disk, "/mnt").is_ok(),
+            Err(_) => false,
+        };
+    });
+}
+initcall!(Driver, "fat", init, after = ["block", "devfs"]);
+
+impl vfs::FileSystem for FatVolume {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Node {
+        Node {
+            inode: self.this(),
+            ino: ROOT_INO,
+        }
+    }
+}
+
+impl vfs::Inode for FatVolume {
+    fn stat(&self, ino: Ino) -> Result<Stat, FsError> {
+        let entry = self.entry(ino)?;
+        Ok(Stat {
+            kind: entry.kind(),
+            size: if entry.is_dir() {
+                0
+            } else {
+                entry.size as usize
+            },
+            mode: if entry.is_dir() { 0o555 } else { 0o444 },
+        })
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Node, FsError> {
+        let dir = self.dir(dir)?;
+        let mut found = None;
+        self.walk_dir(&dir, |entry_name, entry| {
+            if entry_name.eq_ignore_ascii_case(name) {
+                found = Some(entry.ino);
+            }
+            found.is_some()
+        })?;
+        let ino = found.ok_or(FsError::NotFound)?;
+        Ok(Node {
+            inode: self.this(),
+            ino,
+        })
+    }
+
+    fn read_dir(&self, dir: Ino, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let dir = self.dir(dir)?;
+        let mut seen = 0;
+        let mut found = None;
+        self.walk_dir(&dir, |name, entry| {
+            if seen == index {
+                found = Some(DirEntry::new(name, entry.kind()));
+            }
+            seen += 1;
+            found.is_some()
+        })?;
+        Ok(found)
+    }
+
+    fn open(&self, _ino: Ino) -> Result<&'static dyn vfs::File, FsError> {
+        Ok(self.this())
+    }
+}
+
+impl vfs::File for FatVolume {
+    fn read(&self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let entry = self.entry(ino)?;
+        if entry.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        let geo = self.geometry()?;
+        let size = entry.size as usize;
+        let len = buf.len().min(size.saturating_sub(offset));
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let cluster_size = geo.cluster_size();
+        let Some(mut cluster) = self.nth_cluster(&geo, entry.cluster, offset / cluster_size)?
+        else {
+            return Err(FsError::Io);
+        };
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let in_cluster = pos % cluster_size;
+            let lba = geo.cluster_sector(cluster) + (in_cluster / SECTOR_SIZE) as u64;
+            let skip = pos % SECTOR_SIZE;
+            let chunk = (SECTOR_SIZE - skip).min(len - done);
+            self.read_sector(lba, &mut sector)?;
+            buf[done..done + chunk].copy_from_slice(&sector[skip..skip + chunk]);
+            done += chunk;
+            if done < len && (offset + done).is_multiple_of(cluster_size) {
+                cluster = self.next_cluster(&geo, cluster)?.ok_or(FsError::Io)?;
+            }
+        }
+        Ok(done)
+    }
+}
@@ -0,0 +1,422 @@
+//! Read-only filesystem on the initrd
+//!
+//! The bootloader (or QEMU's `-initrd`) loads a cpio archive in RAM and passes its location in
+//! `/chosen/linux,initrd-start` and `linux,initrd-end`. `init` finds and checks it, then keeps the
+//! memory for good: files are served straight from the archive, without copying.
+//!
+//! ## Archive Format
+//!
+//! Only the "new ASCII" (newc) format is understood, the one `cpio -H newc` writes and Linux
+//! requires. Each member is a 110-byte header of hexadecimal fields, the NUL-terminated path and
+//! the file data, the last two padded to 4 bytes; a member called `TRAILER!!!` ends the archive.
+//! Paths are stored relative (`bin/sh`, sometimes `./bin/sh`) and looked up with or without a
+//! leading `/`.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux unpacks the archive into a tmpfs (`populate_rootfs`); this kernel has no writable
+//! filesystem, so the archive itself is the filesystem. It is mounted at `/` in the VFS, inode
+//! numbers being the offsets of the members in the archive.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::board;
+use crate::kernel::dtb;
+use crate::kernel::fs::vfs::{self, DirEntry, FileKind, FsError, Ino, Node, Stat};
+use crate::kernel::mm::frame;
+use crate::{initcall, pr_err, println};
+
+/// Size of a newc header
+const HEADER_SIZE: usize = 110;
+
+/// Magic of the newc format, without and with checksums
+const MAGIC_NEWC: &[u8] = b"070701";
+const MAGIC_CRC: &[u8] = b"070702";
+
+/// Name of the member ending the archive
+const TRAILER: &str = "TRAILER!!!";
+
+/// File type bits of `mode`
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+
+/// Inode number of the root directory, which doesn't always have a member of its own
+const ROOT_INO: Ino = Ino::MAX;
+
+/// Highest address the initrd can end at, the end of the identity-mapped normal memory
+const MAPPED_END: usize = board::mapped_end();
+
+/// Errors returned by the initramfs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InitramfsError {
+    /// No initrd was passed, or it was rejected by `init`
+    NoInitramfs,
+    /// A header has a bad magic number or a malformed field
+    BadHeader,
+    /// A member extends past the end of the initrd
+    Truncated,
+    /// No member has this path
+    NotFound,
+    /// The path is a directory
+    IsDirectory,
+    /// The path is not a directory
+    NotDirectory,
+}
+
+/// A member of the archive
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    /// Path in the archive, without a leading `/` or `./`
+    pub name: &'static str,
+    /// Type and permission bits, as in `st_mode`
+    pub mode: u32,
+    /// Contents of a regular file, the link target of a symbolic link
+    pub data: &'static [u8],
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    /// Returns the last component of the path
+    pub fn file_name(&self) -> &'static str {
+        self.name.rsplit('/').next().unwrap_or(self.name)
+    }
+
+    /// Returns true if the entry is directly in the directory `dir`
+    fn is_child_of(&self, dir: &str) -> bool {
+        let parent = self.name.rsplit_once('/').map_or("", |(parent, _)| parent);
+        !self.name.is_empty() && parent == dir
+    }
+
+    fn kind(&self) -> FileKind {
+        match self.mode & S_IFMT {
+            S_IFDIR => FileKind::Directory,
+            S_IFLNK => FileKind::Symlink,
+            S_IFCHR => FileKind::CharDevice,
+            S_IFBLK => FileKind::BlockDevice,
+            _ => FileKind::File,
+        }
+    }
+}
+
+/// The entry of the root directory when the archive has none
+const ROOT: Entry = Entry {
+    name: "",
+    mode: S_IFDIR | 0o755,
+    data: &[],
+};
+
+/// An open regular file
+pub struct File {
+    entry: Entry,
+    /// Offset the next `read` starts at
+    pos: usize,
+}
+
+impl File {
+    /// Copies the bytes at the current offset into `buf`, returning how many were read
+    ///
+    /// Returns 0 at the end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let data = &self.entry.data[self.pos.min(self.entry.data.len())..];
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.pos += len;
+        len
+    }
+
+    /// Moves the offset the next `read` starts at
+    pub fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    /// Returns the size of the file
+    pub fn size(&self) -> usize {
+        self.entry.data.len()
+    }
+
+    /// Returns the whole contents of the file
+    pub fn data(&self) -> &'static [u8] {
+        self.entry.data
+    }
+}
+
+/// The archive, set by `init`
+static ARCHIVE: Mutex<Option<&'static [u8]>> = Mutex::new(None);
+
+/// Parses a hexadecimal header field
+fn hex_field(header: &[u8], index: usize) -> Result<u32, InitramfsError> {
+    let start = 6 + index * 8;
+    let field =
+        core::str::from_utf8(&header[start..start + 8]).map_err(|_| InitramfsError::BadHeader)?;
+    u32::from_str_radix(field, 16).map_err(|_| InitramfsError::BadHeader)
+}
+
+/// Parses the member at `offset`, returning it and the offset of the next one
+///
+/// Returns `None` for the trailer.
+fn parse_member(
+    archive: &'static [u8],
+    offset: usize,
+) -> Result<Option<(Entry, usize)>, InitramfsError> {
+    let header = archive
+        .get(offset..offset + HEADER_SIZE)
+        .ok_or(InitramfsError::Truncated)?;
+    if &header[..6] != MAGIC_NEWC && &header[..6] != MAGIC_CRC {
+        return Err(InitramfsError::BadHeader);
+    }
+    let mode = hex_field(header, 1)?;
+    let file_size = hex_field(header, 6)? as usize;
+    let name_size = hex_field(header, 11)? as usize;
+
+    let name_start = offset + HEADER_SIZE;
+    let name = archive
+        .get(name_start..name_start + name_size)
+        .ok_or(InitramfsError::Truncated)?;
+    // The size counts the terminating NUL
+    let name = name.strip_suffix(&[0]).ok_or(InitramfsError::BadHeader)?;
+    let name = core::str::from_utf8(name).map_err(|_| InitramfsError::BadHeader)?;
+    if name == TRAILER {
+        return Ok(None);
+    }
+
+    let data_start = (name_start + name_size).next_multiple_of(4);
+    let data = archive
+        .get(data_start..data_start + file_size)
+        .ok_or(InitramfsError::Truncated)?;
+    let entry = Entry {
+        name: normalize(name),
+        mode,
+        data,
+    };
+    Ok(Some((entry, (data_start + file_size).next_multiple_of(4))))
+}
+
+/// Strips the leading `/`, `./` and trailing `/` of a path
+fn normalize(path: &str) -> &str {
+    let path = path.trim_start_matches("./").trim_start_matches('/');
+    let path = path.trim_end_matches('/');
+    if path == "." { "" } else { path }
+}
+
+/// Calls `f` on every member of the archive and its offset, stopping early if it returns true
+fn walk(
+    archive: &'static [u8],
+    mut f: impl FnMut(usize, &Entry) -> bool,
+) -> Result<(), InitramfsError> {
+    let mut offset = 0;
+    while let Some((entry, next)) = parse_member(archive, offset)? {
+        if f(offset, &entry) {
+            break;
+        }
+        offset = next;
+    }
+    Ok(())
+}
+
+fn archive() -> Result<&'static [u8], InitramfsError> {
+    ARCHIVE
+        .lock_irqsafe(|archive| *archive)
+        .ok_or(InitramfsError::NoInitramfs)
+}
+
+/// Finds the initrd, checks the whole archive and reserves its memory
+///
+/// Must run after `frame::init`. The kernel runs fine without an initrd.
+pub fn init() {
+    let Some((start, end)) = dtb::initrd_region() else {
+        return;
+    };
+    if end > MAPPED_END {
+        println!("initramfs: {:#x}-{:#x} is not mapped, ignored", start, end);
+        return;
+    }
+    frame::reserve(start, end - start);
+    let archive = unsafe { core::slice::from_raw_parts(start as *const u8, end - start) };
+    let mut files = 0;
+    if let Err(e) = walk(archive, |_, _| {
+        files += 1;
+        false
+    }) {
+        println!("initramfs: bad archive at {:#x}: {:?}", start, e);
+        return;
+    }
+    ARCHIVE.lock_irqsafe(|a| *a = Some(archive));
+    if let Err(e) = vfs::mount("/", &INITRAMFS) {
+        pr_err!("initramfs: cannot mount: {:?}", e);
+    }
+    println!(
+        "initramfs: {} entries, {} KiB at {:#x}",
+        files,
+        (end - start).div_ceil(1024),
+        start
+    );
+}
+initcall!(Mmu, "initramfs", init, after = ["frame"]);
+
+/// Returns the member at `path` and its offset in the archive
+fn find(path: &str) -> Result<(Ino, Entry), InitramfsError> {
+    let path = normalize(path);
+    let mut found = None;
+    walk(archive()?, |offset, entry| {
+        if entry.name == path {
+            found = Some((offset as Ino, *entry));
+        }
+        found.is_some()
+    })?;
+    match found {
+        Some(found) => Ok(found),
+        None if path.is_empty() => Ok((ROOT_INO, ROOT)),
+        None => Err(InitramfsError::NotFound),
+    }
+}
+
+/// Returns the member at `path`
+pub fn lookup(path: &str) -> Result<Entry, InitramfsError> {
+    find(path).map(|(_, entry)| entry)
+}
+
+/// Opens the regular file at `path`
+pub fn open(path: &str) -> Result<File, InitramfsError> {
+    let entry = lookup(path)?;
+    if entry.is_dir() {
+        return Err(InitramfsError::IsDirectory);
+    }
+    Ok(File { entry, pos: 0 })
+}
+
+/// Calls `f` on every entry of the directory at `path`, in archive order
+pub fn read_dir(path: &str, mut f: impl FnMut(&Entry)) -> Result<(), InitramfsError> {
+    if !lookup(path)?.is_dir() {
+        return Err(InitramfsError::NotDirectory);
+    }
+    let dir = normalize(path);
+    walk(archive()?, |_, entry| {
+        if entry.is_child_of(dir) {
+            f(entry);
+        }
+        false
+    })
+}
+
+/// The initramfs as a VFS filesystem
+pub struct Initramfs;
+
+/// The filesystem `init` mounts at `/`
+pub static INITRAMFS: Initramfs = Initramfs;
+
+impl From<InitramfsError> for FsError {
+    fn from(e: InitramfsError) -> Self {
+        match e {
+            InitramfsError::NotFound | InitramfsError::NoInitramfs => FsError::NotFound,
+            InitramfsError::IsDirectory => FsError::IsDirectory,
+            InitramfsError::NotDirectory => FsError::NotDirectory,
+            InitramfsError::BadHeader | InitramfsError::Truncated => FsError::Io,
+        }
+    }
+}
+
+impl Initramfs {
+    /// Returns the member with inode number `ino`
+    fn entry(&self, ino: Ino) -> Result<Entry, FsError> {
+        if ino == ROOT_INO {
+            return Ok(ROOT);
+        }
+        match parse_member(archive()?, ino as usize)? {
+            Some((entry, _)) => Ok(entry),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    /// Returns the directory with inode number `ino`
+    fn dir(&self, ino: Ino) -> Result<Entry, FsError> {
+        let dir = self.entry(ino)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotDirectory);
+        }
+        Ok(dir)
+    }
+}
+
+impl vfs::FileSystem for Initramfs {
+    fn name(&self) -> &'static str {
+        "initramfs"
+    }
+
+    fn root(&self) -> Node {
+        let ino = find("").map_or(ROOT_INO, |(ino, _)| ino);
+        Node {
+            inode: &INITRAMFS,
+            ino,
+        }
+    }
+}
+
+impl vfs::Inode for Initramfs {
+    fn stat(&self, ino: Ino) -> Result<Stat, FsError> {
+        let entry = self.entry(ino)?;
+        Ok(Stat {
+            kind: entry.kind(),
+            size: entry.data.len(),
+            mode: entry.mode & !S_IFMT,
+        })
+    }
+
+    fn lookup(&self, dir: Ino, name: &str) -> Result<Node, FsError> {
+        let dir = self.dir(dir)?;
+        let mut found = None;
+        walk(archive()?, |offset, entry| {
+            if entry.is_child_of(dir.name) && entry.file_name() == name {
+                found = Some(offset as Ino);
+            }
+            found.is_some()
+        })?;
+        let ino = found.ok_or(FsError::NotFound)?;
+        Ok(Node {
+            inode: &INITRAMFS,
+            ino,
+        })
+    }
+
+    fn read_dir(&self, dir: Ino, index: usize) -> Result<Option<DirEntry>, FsError> {
+        let dir = self.dir(dir)?;
+        let mut seen = 0;
+        let mut found = None;
+        walk(archive()?, |_, entry| {
+            if entry.is_child_of(dir.name) {
+                if seen == index {
+                    found = Some(DirEntry::new(entry.file_name(), entry.kind()));
+                }
+                seen += 1;
+            }
+            found.is_some()
+        })?;
+        Ok(found)
+    }
+
+    fn open(&self, _ino: Ino) -> Result<&'static dyn vfs::File, FsError> {
+        Ok(&INITRAMFS)
+    }
+}
+
+impl vfs::File for Initramfs {
+    fn read(&self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let entry = self.entry(ino)?;
+        if entry.is_dir() {
+            return Err(FsError::IsDirectory);
+        }
+        let data = &entry.data[offset.min(entry.data.len())..];
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+}
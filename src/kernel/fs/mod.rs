@@ -0,0 +1,6 @@
+//! Filesystems
+
+pub mod devfs;
+pub mod fat;
+pub mod initramfs;
+pub mod vfs;
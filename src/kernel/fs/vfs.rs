@@ -0,0 +1,548 @@
+//! Virtual filesystem
+//!
+//! Gives every filesystem the same interface, so paths and file descriptors work the same
+//! whether a file comes from the initramfs, a device or a disk.
+//!
+//! ## Design
+//!
+//! - A filesystem implements `FileSystem`, handing out its root `Node`. A node is a file of a
+//!   filesystem: an `Inode` implementation (the operations, usually a static shared by all the
+//!   filesystem's files) and an `Ino` telling the implementation which file it is.
+//! - Opening a node returns its `File` operations (`read`, `write`). The open file keeps the
+//!   node, the offset and the access mode, in a per-task file descriptor table. Files with
+//!   state of their own (sockets) count their descriptors through `retain` and `release`.
+//! - `mount` attaches a filesystem at an absolute path. Paths are resolved lexically (`.` and
+//!   `..` are folded first), from the filesystem mounted at the longest matching prefix. There is
+//!   no working directory yet: relative paths start at `/`.
+//!
+//! Nothing here allocates: mounts and descriptors live in fixed tables and filesystems return
+//! `'static` operations.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! `Inode` and `File` play the roles of `inode_operations` and `file_operations`, and a `Node`
+//! of a (dentry, inode) pair without a cache. There is a single mount namespace, no
+//! reference-counted `struct file` shared between tasks (`retain` and `release` stand in for
+//! its reference count), and no `dup`.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::sched::{self, MAX_TASKS, TaskId};
+
+/// Maximum number of mounted filesystems
+const MAX_MOUNTS: usize = 8;
+
+/// Number of file descriptors of a task
+pub const MAX_FDS: usize = 16;
+
+/// Maximum length of a path
+pub const MAX_PATH: usize = 256;
+
+/// Maximum length of a file name returned by `read_dir`
+pub const MAX_NAME: usize = 64;
+
+/// Maximum number of components in a path
+const MAX_DEPTH: usize = 16;
+
+/// Identifies a file within its filesystem, meaningful only to the filesystem
+pub type Ino = u64;
+
+/// A file descriptor
+pub type Fd = usize;
+
+/// Errors returned by the VFS and the filesystems
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FsError {
+    /// No such file or directory
+    NotFound,
+    /// A path component is not a directory
+    NotDirectory,
+    /// The operation is not possible on a directory
+    IsDirectory,
+    /// The filesystem can't be written to
+    ReadOnly,
+    /// The file was not opened with the access mode the operation needs
+    BadMode,
+    /// The file descriptor is not open
+    BadFd,
+    /// Every file descriptor of the task is in use
+    TooManyOpen,
+    /// The path or a component is too long, or has too many components
+    NameTooLong,
+    /// The file can't be repositioned (e.g. a terminal)
+    NotSeekable,
+    /// A filesystem is already mounted there, or the mount table is full
+    Busy,
+    /// The argument is not valid for this file
+    Invalid,
+    /// The underlying device failed
+    Io,
+    /// The file doesn't support this `ioctl` request
+    BadIoctl,
+    /// A signal arrived while waiting
+    Interrupted,
+    /// A user address passed to the request is not accessible
+    BadAddress,
+}
+
+/// Type of a file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FileKind {
+    File,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Symlink,
+    Socket,
+}
+
+/// File attributes, returned by `Inode::stat`
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub kind: FileKind,
+    /// Size in bytes, 0 for devices
+    pub size: usize,
+    /// Permission bits
+    pub mode: u32,
+}
+
+/// A directory entry, returned by `Inode::read_dir`
+#[derive(Clone, Copy)]
+pub struct DirEntry {
+    name: [u8; MAX_NAME],
+    name_len: usize,
+    pub kind: FileKind,
+}
+
+impl DirEntry {
+    /// Creates an entry, truncating `name` to `MAX_NAME` bytes
+    pub fn new(name: &str, kind: FileKind) -> Self {
+        let mut entry = Self {
+            name: [0; MAX_NAME],
+            name_len: name.len().min(MAX_NAME),
+            kind,
+        };
+        entry.name[..entry.name_len].copy_from_slice(&name.as_bytes()[..entry.name_len]);
+        entry
+    }
+
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("?")
+    }
+}
+
+/// Operations on the files of a filesystem
+pub trait Inode: Sync {
+    /// Returns the attributes of the file
+    fn stat(&self, ino: Ino) -> Result<Stat, FsError>;
+
+    /// Returns the entry called `name` in the directory `dir`
+    fn lookup(&self, _dir: Ino, _name: &str) -> Result<Node, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Returns the `index`-th entry of the directory `dir`, `None` past the last one
+    fn read_dir(&self, _dir: Ino, _index: usize) -> Result<Option<DirEntry>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Returns the operations on the file once opened
+    fn open(&self, ino: Ino) -> Result<&'static dyn File, FsError>;
+}
+
+/// Operations on an open file
+pub trait File: Sync {
+    /// Reads at `offset` into `buf`, returning the number of bytes read, 0 at the end of file
+    ///
+    /// Devices may ignore the offset and block until data is available.
+    fn read(&self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes `buf` at `offset`, returning the number of bytes written
+    fn write(&self, _ino: Ino, _offset: usize, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Runs the device-specific request `cmd`, returning its result
+    ///
+    /// `arg` is passed through untouched; it is a user address for requests taking a structure.
+    fn ioctl(&self, _ino: Ino, _cmd: u32, _arg: usize) -> Result<usize, FsError> {
+        Err(FsError::BadIoctl)
+    }
+
+    /// Returns false for files without a position, like terminals
+    fn seekable(&self) -> bool {
+        true
+    }
+
+    /// Called when a descriptor of the file is copied, e.g. by `fork`
+    fn retain(&self, _ino: Ino) {}
+
+    /// Called when a descriptor of the file is closed, outside of the descriptor table's lock
+    fn release(&self, _ino: Ino) {}
+}
+
+/// A mountable filesystem
+pub trait FileSystem: Sync {
+    /// Name shown in the mount table
+    fn name(&self) -> &'static str;
+
+    /// Returns the root directory
+    fn root(&self) -> Node;
+}
+
+/// A file of a filesystem
+#[derive(Clone, Copy)]
+pub struct Node {
+    pub inode: &'static dyn Inode,
+    pub ino: Ino,
+}
+
+impl Node {
+    pub fn stat(&self) -> Result<Stat, FsError> {
+        self.inode.stat(self.ino)
+    }
+}
+
+/// Access mode of an open file
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OpenMode {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl OpenMode {
+    fn can_read(self) -> bool {
+        self != OpenMode::Write
+    }
+
+    fn can_write(self) -> bool {
+        self != OpenMode::Read
+    }
+}
+
+/// Origin of a `seek`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Whence {
+    /// From the start of the file
+    Set,
+    /// From the current offset
+    Current,
+    /// From the end of the file
+    End,
+}
+
+/// An entry of a file descriptor table
+#[derive(Clone, Copy)]
+struct OpenFile {
+    node: Node,
+    file: &'static dyn File,
+    mode: OpenMode,
+    offset: usize,
+}
+
+/// A filesystem attached to the tree
+#[derive(Clone, Copy)]
+struct Mount {
+    /// Absolute path, without a trailing `/`; the root is the empty path
+    path: &'static str,
+    fs: &'static dyn FileSystem,
+}
+
+/// Information about a mount, returned by `for_each_mount`
+#[derive(Clone, Copy)]
+pub struct MountInfo {
+    pub path: &'static str,
+    pub fs: &'static str,
+}
+
+static MOUNTS: Mutex<[Option<Mount>; MAX_MOUNTS]> = Mutex::new([None; MAX_MOUNTS]);
+
+/// File descriptor tables, indexed by task ID
+static FILES: Mutex<[[Option<OpenFile>; MAX_FDS]; MAX_TASKS]> =
+    Mutex::new([[None; MAX_FDS]; MAX_TASKS]);
+
+/// Components of a path, with `.` and `..` folded
+struct Components<'a> {
+    parts: [&'a str; MAX_DEPTH],
+    len: usize,
+}
+
+impl<'a> Components<'a> {
+    fn parse(path: &'a str) -> Result<Self, FsError> {
+        if path.len() > MAX_PATH {
+            return Err(FsError::NameTooLong);
+        }
+        let mut components = Self {
+            parts: [""; MAX_DEPTH],
+            len: 0,
+        };
+        for part in path.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => components.len = components.len.saturating_sub(1),
+                _ if components.len == MAX_DEPTH || part.len() > MAX_NAME => {
+                    return Err(FsError::NameTooLong);
+                }
+                _ => {
+                    components.parts[components.len] = part;
+                    components.len += 1;
+                }
+            }
+        }
+        Ok(components)
+    }
+
+    fn as_slice(&self) -> &[&'a str] {
+        &self.parts[..self.len]
+    }
+}
+
+/// Attaches `fs` at the absolute path `path`
+///
+/// The mount point doesn't need to exist in the parent filesystem.
+pub fn mount(path: &'static str, fs: &'static dyn FileSystem) -> Result<(), FsError> {
+    let path = path.trim_end_matches('/');
+    MOUNTS.lock_irqsafe(|mounts| {
+        if mounts.iter().flatten().any(|m| m.path == path) {
+            return Err(FsError::Busy);
+        }
+        let slot = mounts
+            .iter_mut()
+            .find(|m| m.is_none())
+            .ok_or(FsError::Busy)?;
+        *slot = Some(Mount { path, fs });
+        Ok(())
+    })
+}
+
+/// Calls `f` on every mount, in mount order
+pub fn for_each_mount(mut f: impl FnMut(&MountInfo)) {
+    let mounts = MOUNTS.lock_irqsafe(|mounts| *mounts);
+    for mount in mounts.iter().flatten() {
+        f(&MountInfo {
+            path: if mount.path.is_empty() {
+                "/"
+            } else {
+                mount.path
+            },
+            fs: mount.fs.name(),
+        });
+    }
+}
+
+/// Returns the node at `path`
+pub fn lookup(path: &str) -> Result<Node, FsError> {
+    let components = Components::parse(path)?;
+    let components = components.as_slice();
+
+    // The filesystem mounted at the longest prefix of the path
+    let mounts = MOUNTS.lock_irqsafe(|mounts| *mounts);
+    let (depth, fs) = mounts
+        .iter()
+        .flatten()
+        .filter_map(|m| {
+            let mut depth = 0;
+            for part in m.path.split('/').filter(|p| !p.is_empty()) {
+                if components.get(depth) != Some(&part) {
+                    return None;
+                }
+                depth += 1;
+            }
+            Some((depth, m.fs))
+        })
+        .max_by_key(|&(depth, _)| depth)
+        .ok_or(FsError::NotFound)?;
+
+    let mut node = fs.root();
+    for name in &components[depth..] {
+        node = node.inode.lookup(node.ino, name)?;
+    }
+    Ok(node)
+}
+
+/// Runs `f` on the descriptor table of the running task
+fn with_files<R>(f: impl FnOnce(&mut [Option<OpenFile>; MAX_FDS]) -> R) -> R {
+    let task = sched::current().unwrap_or(0);
+    FILES.lock_irqsafe(|files| f(&mut files[task]))
+}
+
+/// Returns a copy of the open file `fd` of the running task
+fn get(fd: Fd) -> Result<OpenFile, FsError> {
+    with_files(|files| files.get(fd).copied().flatten().ok_or(FsError::BadFd))
+}
+
+/// Opens `node` in the running task, returning the lowest free descriptor
+pub fn open_node(node: Node, mode: OpenMode) -> Result<Fd, FsError> {
+    let stat = node.stat()?;
+    if stat.kind == FileKind::Directory && mode.can_write() {
+        return Err(FsError::IsDirectory);
+    }
+    let file = node.inode.open(node.ino)?;
+    let open = OpenFile {
+        node,
+        file,
+        mode,
+        offset: 0,
+    };
+    with_files(|files| {
+        let fd = files
+            .iter()
+            .position(|f| f.is_none())
+            .ok_or(FsError::TooManyOpen)?;
+        files[fd] = Some(open);
+        Ok(fd)
+    })
+}
+
+/// Opens the file at `path` in the running task
+pub fn open(path: &str, mode: OpenMode) -> Result<Fd, FsError> {
+    open_node(lookup(path)?, mode)
+}
+
+/// Closes a file descriptor of the running task
+pub fn close(fd: Fd) -> Result<(), FsError> {
+    let open = with_files(|files| {
+        files
+            .get_mut(fd)
+            .and_then(|f| f.take())
+            .ok_or(FsError::BadFd)
+    })?;
+    open.file.release(open.node.ino);
+    Ok(())
+}
+
+/// Closes every file descriptor of a task, when it exits
+pub fn close_all(task: TaskId) {
+    let closed = FILES.lock_irqsafe(|files| {
+        files.get_mut(task).map_or([None; MAX_FDS], |files| {
+            core::mem::replace(files, [None; MAX_FDS])
+        })
+    });
+    for open in closed.iter().flatten() {
+        open.file.release(open.node.ino);
+    }
+}
+
+/// Gives task `to` the same open files as task `from`, for `fork`
+///
+/// Each descriptor is copied with its current offset; the two tasks don't share offsets.
+pub fn copy_files(from: TaskId, to: TaskId) {
+    let copied = FILES.lock_irqsafe(|files| {
+        if from < files.len() && to < files.len() {
+            files[to] = files[from];
+            files[to]
+        } else {
+            [None; MAX_FDS]
+        }
+    });
+    for open in copied.iter().flatten() {
+        open.file.retain(open.node.ino);
+    }
+}
+
+/// Moves the offset of `fd` by `delta` bytes
+fn advance(fd: Fd, delta: usize) {
+    with_files(|files| {
+        if let Some(Some(open)) = files.get_mut(fd) {
+            open.offset += delta;
+        }
+    });
+}
+
+/// Reads from `fd` at its offset, returning the number of bytes read
+///
+/// No lock is held while the filesystem reads, so a device can block.
+pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, FsError> {
+    let open = get(fd)?;
+    if !open.mode.can_read() {
+        return Err(FsError::BadMode);
+    }
+    let len = open.file.read(open.node.ino, open.offset, buf)?;
+    advance(fd, len);
+    Ok(len)
+}
+
+/// Writes to `fd` at its offset, returning the number of bytes written
+pub fn write(fd: Fd, buf: &[u8]) -> Result<usize, FsError> {
+    let open = get(fd)?;
+    if !open.mode.can_write() {
+        return Err(FsError::BadMode);
+    }
+    let len = open.file.write(open.node.ino, open.offset, buf)?;
+    advance(fd, len);
+    Ok(len)
+}
+
+/// Repositions the offset of `fd`, returning the new offset
+pub fn seek(fd: Fd, offset: i64, whence: Whence) -> Result<usize, FsError> {
+    let open = get(fd)?;
+    if !open.file.seekable() {
+        return Err(FsError::NotSeekable);
+    }
+    let base = match whence {
+        Whence::Set => 0,
+        Whence::Current => open.offset,
+        Whence::End => open.node.stat()?.size,
+    };
+    let new = base
+        .checked_add_signed(offset as isize)
+        .ok_or(FsError::Invalid)?;
+    with_files(|files| match files.get_mut(fd) {
+        Some(Some(open)) => {
+            open.offset = new;
+            Ok(new)
+        }
+        _ => Err(FsError::BadFd),
+    })
+}
+
+/// Runs the device-specific request `cmd` on `fd`
+pub fn ioctl(fd: Fd, cmd: u32, arg: usize) -> Result<usize, FsError> {
+    let open = get(fd)?;
+    open.file.ioctl(open.node.ino, cmd, arg)
+}
+
+/// Returns the next entry of the directory open as `fd`, `None` after the last one
+///
+/// The offset of a directory counts the entries already returned.
+pub fn read_dir(fd: Fd) -> Result<Option<DirEntry>, FsError> {
+    let open = get(fd)?;
+    let entry = open.node.inode.read_dir(open.node.ino, open.offset)?;
+    if entry.is_some() {
+        advance(fd, 1);
+    }
+    Ok(entry)
+}
+
+/// Returns the file open as `fd` and its access mode, e.g. to map it in memory
+pub fn fd_node(fd: Fd) -> Result<(Node, OpenMode), FsError> {
+    get(fd).map(|open| (open.node, open.mode))
+}
+
+/// Returns the attributes of the file open as `fd`
+pub fn fstat(fd: Fd) -> Result<Stat, FsError> {
+    get(fd)?.node.stat()
+}
+
+/// Sets a descriptor of `task` to `node`, replacing whatever was open
+///
+/// Used to give a new task its standard input and outputs.
+pub fn install(task: TaskId, fd: Fd, node: Node, mode: OpenMode) -> Result<(), FsError> {
+    let file = node.inode.open(node.ino)?;
+    let replaced = FILES.lock_irqsafe(|files| -> Result<_, FsError> {
+        let slot = files
+            .get_mut(task)
+            .and_then(|files| files.get_mut(fd))
+            .ok_or(FsError::BadFd)?;
+        Ok(slot.replace(OpenFile {
+            node,
+            file,
+            mode,
+            offset: 0,
+        }))
+    })?;
+    if let Some(open) = replaced {
+        open.file.release(open.node.ino);
+    }
+    Ok(())
+}
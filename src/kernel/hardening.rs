@@ -0,0 +1,64 @@
+//! Stack smashing protection
+//!
+//! With the stack protector enabled (`make STACK_PROTECTOR=1`, which builds with a nightly
+//! compiler and `-Z stack-protector=strong`), functions with local arrays or address-taken locals
+//! store a copy of `__stack_chk_guard` between their locals and the saved frame record, and check
+//! it before returning. A mismatch means something wrote past the end of a local buffer, e.g. a
+//! driver copying a device-supplied length: the function calls `__stack_chk_fail` instead of
+//! returning through a possibly overwritten link register.
+//!
+//! Without the flag these symbols are simply unused.
+//!
+//! ## Design
+//!
+//! - The guard is random, from `random::get_random_u64`, so an overflow can't write the expected
+//!   value back by chance or design. Its low byte is cleared: a string overflow stops at the NUL
+//!   instead of copying past the canary.
+//! - Frames created before `init` hold the old guard and would fail their check when returning,
+//!   so `init` is inlined into `kmain`, which never returns.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Same as `CONFIG_STACKPROTECTOR_STRONG` with the global guard (`boot_init_stack_canary`); arm64
+//! Linux can also use a per-task guard loaded through `sp_el0`, which this kernel doesn't do.
+
+use crate::kernel::debug::backtrace;
+use crate::kernel::random;
+use crate::kernel::sched;
+use crate::println;
+
+/// Canary value checked by instrumented functions
+///
+/// Written once by `init`, read by every instrumented function prologue and epilogue.
+#[unsafe(no_mangle)]
+pub static mut __stack_chk_guard: u64 = 0x00de_adbe_ef5a_fe00;
+
+/// Sets a random canary value
+///
+/// Must run once, early in `kmain` after `random::init`.
+#[inline(always)]
+pub fn init() {
+    let guard = random::get_random_u64() & !0xff;
+    unsafe { core::ptr::addr_of_mut!(__stack_chk_guard).write_volatile(guard) };
+}
+
+/// Called by an instrumented function whose canary was overwritten
+///
+/// The stack of the current task can't be trusted anymore, so this reports it and panics.
+#[unsafe(no_mangle)]
+pub extern "C" fn __stack_chk_fail() -> ! {
+    let current = sched::current();
+    let mut name = "boot";
+    sched::for_each_task(|task| {
+        if Some(task.id) == current {
+            name = task.name;
+        }
+    });
+    println!(
+        "stack smashing detected in task {} ({})",
+        current.unwrap_or(0),
+        name
+    );
+    backtrace::print_current();
+    panic!("stack-protector: kernel stack is corrupted");
+}
@@ -0,0 +1,184 @@
+//! Staged kernel initialization
+//!
+//! Subsystems register their init function with `initcall!`, next to the function itself, instead
+//! of being called from `kmain`. Boot goes through a fixed sequence of stages, and each stage runs
+//! the init calls registered for it:
+//!
+//! | Stage    | Runs                                                                      |
+//! |----------|---------------------------------------------------------------------------|
+//! | `Early`  | with the DTB parsed, before memory management: clocks, entropy            |
+//! | `Mmu`    | the memory management setup, from the MAIR to the kernel address space    |
+//! | `Irq`    | the driver model, which binds the GIC before the devices using interrupts |
+//! | `Driver` | subsystems built on the probed devices: IOMMU, virtio, block, filesystems |
+//! | `Late`   | the scheduler and everything needing tasks, up to the shell               |
+//!
+//! Within a stage, a call runs after the ones it names in `after`, which must belong to the same
+//! stage or an earlier one. Calls without such a constraint run in link order, which is not to be
+//! relied upon.
+//!
+//! ## Design
+//!
+//! - `initcall!` places an `InitCall` in the `.initcalls` section, which the linker script
+//!   gathers between `__initcall_start` and `__initcall_end`, so the table is built at link time
+//!   without a registry. The Makefile links the whole Rust archive: the linker would otherwise
+//!   drop the objects nothing but the table refers to.
+//! - Before the first stage, `check` validates the whole table: unique names, and `after` naming
+//!   existing calls of the same or an earlier stage. A cycle shows up when a stage can't make
+//!   progress. Both are bugs in the table and panic, naming the calls involved.
+//! - `STAGE` counts the completed stages: `run` panics if stages are run out of order, and
+//!   `reached` lets code check whether a stage is done.
+//! - `hardening::init` is not an init call: it must be inlined into `kmain` (see `hardening`).
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `early_initcall` ... `late_initcall` macros put function pointers in one section per
+//! level (`.initcall<n>.init`), run in link order by `do_initcalls`; dependencies within a level
+//! are expressed by link order or by moving to another level. Here dependencies are named and
+//! checked, and the table is kept after boot.
+
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Maximum number of init calls in the table
+const MAX_INITCALLS: usize = 64;
+
+/// A boot stage, in execution order
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[repr(u8)]
+pub enum Stage {
+    Early,
+    Mmu,
+    Irq,
+    Driver,
+    Late,
+}
+
+/// An init function registered with `initcall!`
+pub struct InitCall {
+    /// Name other calls refer to in their `after`
+    pub name: &'static str,
+    pub stage: Stage,
+    /// Calls that must have run before this one
+    pub after: &'static [&'static str],
+    pub func: fn(),
+}
+
+/// Registers `$func` to run during `$stage`, under `$name`
+///
+/// `after = [...]` lists the names of the calls that must run first.
+#[macro_export]
+macro_rules! initcall {
+    ($stage:ident, $name:literal, $func:path) => {
+        $crate::initcall!($stage, $name, $func, after = []);
+    };
+    ($stage:ident, $name:literal, $func:path, after = [$($dep:literal),* $(,)?]) => {
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = ".initcalls")]
+            static INITCALL: $crate::kernel::init::InitCall = $crate::kernel::init::InitCall {
+                name: $name,
+                stage: $crate::kernel::init::Stage::$stage,
+                after: &[$($dep),*],
+                func: $func,
+            };
+        };
+    };
+}
+
+// Only their addresses matter: `InitCall` has no C layout
+unsafe extern "C" {
+    static __initcall_start: u8;
+    static __initcall_end: u8;
+}
+
+/// Number of stages completed
+static STAGE: AtomicU8 = AtomicU8::new(0);
+
+/// Set once the call at the same index in `calls` has returned
+static DONE: [AtomicBool; MAX_INITCALLS] = [const { AtomicBool::new(false) }; MAX_INITCALLS];
+
+/// Returns the init call table
+fn calls() -> &'static [InitCall] {
+    let calls = unsafe {
+        let start = addr_of!(__initcall_start) as *const InitCall;
+        let end = addr_of!(__initcall_end) as *const InitCall;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    };
+    if calls.len() > MAX_INITCALLS {
+        panic!(
+            "init: {} init calls, at most {}",
+            calls.len(),
+            MAX_INITCALLS
+        );
+    }
+    calls
+}
+
+/// Returns the index of the call named `name`
+fn find(name: &str) -> Option<usize> {
+    calls().iter().position(|call| call.name == name)
+}
+
+/// Returns true once `stage` has completed
+pub fn reached(stage: Stage) -> bool {
+    STAGE.load(Ordering::Acquire) > stage as u8
+}
+
+/// Validates the names and dependencies of the table
+fn check() {
+    let calls = calls();
+    for (index, call) in calls.iter().enumerate() {
+        if find(call.name) != Some(index) {
+            panic!("init: {} registered twice", call.name);
+        }
+        for &dep in call.after {
+            match find(dep) {
+                None => panic!("init: {} runs after unknown {}", call.name, dep),
+                Some(i) if calls[i].stage > call.stage => panic!(
+                    "init: {} ({:?}) runs after {} of a later stage",
+                    call.name, call.stage, dep
+                ),
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+/// Runs the init calls of `stage`, each after those it depends on
+///
+/// Stages must be run once each, in order.
+pub fn run(stage: Stage) {
+    let completed = STAGE.load(Ordering::Acquire);
+    if completed != stage as u8 {
+        panic!("init: stage {:?} run after {} stages", stage, completed);
+    }
+    if stage == Stage::Early {
+        check();
+    }
+    let calls = calls();
+    let ready = |call: &InitCall| {
+        call.after
+            .iter()
+            .all(|&dep| find(dep).is_some_and(|i| DONE[i].load(Ordering::Relaxed)))
+    };
+    let mut progress = true;
+    while progress {
+        progress = false;
+        for (index, call) in calls.iter().enumerate() {
+            if call.stage == stage && !DONE[index].load(Ordering::Relaxed) && ready(call) {
+                (call.func)();
+                DONE[index].store(true, Ordering::Relaxed);
+                progress = true;
+            }
+        }
+    }
+    if let Some(call) = calls
+        .iter()
+        .enumerate()
+        .find(|&(index, call)| call.stage == stage && !DONE[index].load(Ordering::Relaxed))
+        .map(|(_, call)| call)
+    {
+        panic!("init: {} waits on a dependency cycle", call.name);
+    }
+    STAGE.store(stage as u8 + 1, Ordering::Release);
+}
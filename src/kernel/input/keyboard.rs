@@ -0,0 +1,248 @@
+//! Keyboard handler and the `tty0` console
+//!
+//! `tty0` stands for the screen and keyboards, as Linux's current virtual terminal does: output
+//! is drawn by `fbcon`, input comes from every input device reporting keys. The handler task
+//! subscribes to key events and turns the presses into the bytes a terminal would send (US
+//! layout, ANSI escape sequences for the cursor keys), delivered to `tty0`'s input channel. It
+//! becomes the system console with `console=tty0` on the command line.
+//!
+//! ## Design
+//!
+//! - The handler is started, and `tty0` registered, when the first device with keys registers.
+//! - The state of the modifiers and Caps Lock is shared by all keyboards. The Caps Lock LED is
+//!   left alone.
+//! - Key repeat is done here, by waking up the handler: the last key pressed repeats while it
+//!   is held. Repeats reported by the devices themselves are ignored.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's VT keyboard handler (`drivers/tty/vt/keyboard.c`) translates key codes through a
+//! keymap loaded from user space, with dead keys, Num Lock and per-VT modes such as raw key
+//! codes; the input core does the autorepeat.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::codes::*;
+use super::{EventType, InputEvent, KEY_PRESSED, KEY_RELEASED};
+use crate::ipc::channel::Channel;
+use crate::ipc::waitqueue;
+use crate::kernel::console::{self, Console, INPUT_QUEUE_SIZE, InputReceiver, fbcon};
+use crate::kernel::notifier::Deadline;
+use crate::kernel::sched;
+use crate::pr_err;
+
+/// Delay before a held key repeats, and between repeats
+const REPEAT_DELAY_MS: u32 = 500;
+const REPEAT_RATE_MS: u32 = 33;
+
+/* --- Modifier Bits --- */
+const MOD_SHIFT: u8 = 0b11;
+const MOD_CTRL: u8 = 0b1100;
+const MOD_ALT: u8 = 0b11_0000;
+
+/// Bytes of the key codes up to the keypad, unshifted and shifted (US layout)
+const KEYMAP: &[u8; 84] =
+    b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\r\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 \0\
+    \0\0\0\0\0\0\0\0\0\0\0\x00789-456+1230.";
+const KEYMAP_SHIFT: &[u8; 84] =
+    b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\r\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 \0\
+    \0\0\0\0\0\0\0\0\0\0\0\x00789-456+1230.";
+
+/// Escape sequences of the navigation keys
+const ESCAPES: [(u16, &[u8]); 10] = [
+    (KEY_INSERT, b"\x1b[2~"),
+    (KEY_HOME, b"\x1b[H"),
+    (KEY_PAGEUP, b"\x1b[5~"),
+    (KEY_DELETE, b"\x1b[3~"),
+    (KEY_END, b"\x1b[F"),
+    (KEY_PAGEDOWN, b"\x1b[6~"),
+    (KEY_RIGHT, b"\x1b[C"),
+    (KEY_LEFT, b"\x1b[D"),
+    (KEY_DOWN, b"\x1b[B"),
+    (KEY_UP, b"\x1b[A"),
+];
+
+/// The keyboard console
+pub struct KeyboardConsole {
+    input: Channel<u8, INPUT_QUEUE_SIZE>,
+}
+
+/// `tty0`, registered when the first keyboard shows up
+pub static TTY0: KeyboardConsole = KeyboardConsole {
+    input: Channel::new(),
+};
+
+/// Set once the handler is started
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Registers `tty0` and starts the handler, for the first device with keys
+pub fn attach() {
+    if STARTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    if let Err(e) = console::register_virtual(&TTY0) {
+        pr_err!("keyboard: cannot register tty0: {:?}", e);
+    }
+    if let Err(e) = sched::spawn("kbd", task, 0) {
+        pr_err!("keyboard: cannot start the handler: {:?}", e);
+    }
+}
+
+impl KeyboardConsole {
+    /// Delivers `bytes` typed on a keyboard
+    ///
+    /// Bytes are dropped while the channel is full or claimed by another sender.
+    fn receive(&self, bytes: &[u8]) {
+        let Some(mut sender) = self.input.sender() else {
+            return;
+        };
+        for &c in bytes {
+            if !console::intercept(self.name(), c) {
+                let _ = sender.try_send(c);
+            }
+        }
+    }
+}
+
+impl Console for KeyboardConsole {
+    fn name(&self) -> &'static str {
+        "tty0"
+    }
+
+    /// Draws `c` on the framebuffer console, unless `tty0` is the system console, whose output
+    /// `fbcon` already mirrors
+    fn putchar(&self, c: u8) {
+        if console::active().is_none_or(|con| con.name() != self.name()) {
+            fbcon::write(&[c]);
+        }
+    }
+
+    fn getchar(&self) -> Option<u8> {
+        self.input.receiver()?.try_recv()
+    }
+
+    fn input(&'static self) -> Option<InputReceiver> {
+        self.input.receiver()
+    }
+
+    fn flush(&self, _deadline: &Deadline) -> bool {
+        true
+    }
+}
+
+/// Returns the modifier bit of key `code`, 0 if it isn't a modifier
+fn modifier_bit(code: u16) -> u8 {
+    match code {
+        KEY_LEFTSHIFT => 1 << 0,
+        KEY_RIGHTSHIFT => 1 << 1,
+        KEY_LEFTCTRL => 1 << 2,
+        KEY_RIGHTCTRL => 1 << 3,
+        KEY_LEFTALT => 1 << 4,
+        KEY_RIGHTALT => 1 << 5,
+        _ => 0,
+    }
+}
+
+/// Calls `f` with the bytes of a press of key `code`, given the modifiers and Caps Lock state
+fn translate(code: u16, modifiers: u8, caps_lock: bool, mut f: impl FnMut(&[u8])) {
+    if let Some((_, seq)) = ESCAPES.iter().find(|(c, _)| *c == code) {
+        f(seq);
+        return;
+    }
+    let mut c = match code {
+        KEY_KPENTER => b'\r',
+        KEY_KPSLASH => b'/',
+        _ => {
+            let Some(&plain) = KEYMAP.get(code as usize).filter(|&&c| c != 0) else {
+                return;
+            };
+            let shift = (modifiers & MOD_SHIFT != 0) != (plain.is_ascii_lowercase() && caps_lock);
+            if shift {
+                KEYMAP_SHIFT[code as usize]
+            } else {
+                plain
+            }
+        }
+    };
+    if modifiers & MOD_CTRL != 0 && (b'@'..=b'~').contains(&c) {
+        c &= 0x1f;
+    }
+    // Alt sends the key prefixed with Escape, like the meta key of a terminal
+    if modifiers & MOD_ALT != 0 {
+        f(&[0x1b, c]);
+    } else {
+        f(&[c]);
+    }
+}
+
+/// State of the handler
+struct Keyboard {
+    /// Modifier keys held down, see `modifier_bit`
+    modifiers: u8,
+    caps_lock: bool,
+    /// The key repeating and the counter value at which it next repeats
+    repeat: Option<(u16, u64)>,
+}
+
+impl Keyboard {
+    /// Delivers the bytes of a press of `code` to `tty0`
+    fn press(&self, code: u16) {
+        translate(code, self.modifiers, self.caps_lock, |bytes| {
+            TTY0.receive(bytes)
+        });
+    }
+
+    fn handle(&mut self, event: &InputEvent) {
+        if event.kind != EventType::Key || !matches!(event.value, KEY_PRESSED | KEY_RELEASED) {
+            return;
+        }
+        let pressed = event.value == KEY_PRESSED;
+        let modifier = modifier_bit(event.code);
+        if modifier != 0 {
+            if pressed {
+                self.modifiers |= modifier;
+            } else {
+                self.modifiers &= !modifier;
+            }
+        } else if !pressed {
+            if self.repeat.is_some_and(|(code, _)| code == event.code) {
+                self.repeat = None;
+            }
+        } else if event.code == KEY_CAPSLOCK {
+            self.caps_lock = !self.caps_lock;
+        } else {
+            self.press(event.code);
+            self.repeat = Some((event.code, waitqueue::deadline_ms(REPEAT_DELAY_MS)));
+        }
+    }
+}
+
+/// Body of the handler task: reads the key events and repeats the key held
+fn task(_arg: usize) {
+    let subscription = match super::subscribe(EventType::Key.bit()) {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            pr_err!("keyboard: cannot subscribe to key events: {:?}", e);
+            return;
+        }
+    };
+    let mut keyboard = Keyboard {
+        modifiers: 0,
+        caps_lock: false,
+        repeat: None,
+    };
+    loop {
+        let event = match keyboard.repeat {
+            Some((code, expires)) => match subscription.recv_until(expires) {
+                Ok(event) => event,
+                Err(_) => {
+                    keyboard.press(code);
+                    keyboard.repeat = Some((code, waitqueue::deadline_ms(REPEAT_RATE_MS)));
+                    continue;
+                }
+            },
+            None => subscription.recv(),
+        };
+        keyboard.handle(&event);
+    }
+}
@@ -0,0 +1,359 @@
+//! Input event subsystem
+//!
+//! Keyboards, mice and tablets are input devices: whatever bus they sit on, their drivers
+//! describe what happens as `InputEvent`s (a key pressed or released, an axis moving), each
+//! group of them closed by a `Sync` event, and report them here through the `InputHandle` that
+//! `register` returned. Consumers `subscribe` to the event types they care about and read the
+//! events from a queue of their own, without knowing which device produced them.
+//!
+//! `keyboard` is the consumer behind the `tty0` console: it turns key events into the bytes a
+//! terminal sends, so the shell and user tasks reading the console get the same input from a
+//! USB, virtio or GPIO keyboard. Serial lines stay consoles of their own, as they carry
+//! characters rather than keys.
+//!
+//! ## Design
+//!
+//! - Event types, codes (see `codes`) and values are those of Linux's evdev protocol.
+//! - The core keeps track of the keys each device holds down: a press of a key already down, or
+//!   the release of a key that is up, is dropped, and a device going away releases its keys, so
+//!   subscribers never see a key stuck down.
+//! - Every subscriber has a queue of `QUEUE_LEN` events. Events arriving while it is full are
+//!   dropped and counted. `Sync` events only go to the subscribers of one of the types the
+//!   device reports.
+//! - Events can be reported from interrupt context.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's input core (`drivers/input/input.c`) connects `input_dev`s to the `input_handler`s
+//! (evdev, the VT keyboard, joydev, ...) matching their capabilities, each handler getting the
+//! events through a callback; evdev gives every open file of `/dev/input/eventN` its own
+//! buffer. Key autorepeat is done by the core there, by the keyboard handler here.
+
+pub mod codes;
+pub mod keyboard;
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::waitqueue::{WaitError, WaitQueue};
+use crate::kernel::time::clocksource;
+use crate::println;
+
+/// Maximum number of input devices and of subscribers
+const MAX_DEVICES: usize = 8;
+const MAX_SUBSCRIBERS: usize = 4;
+
+/// Events queued per subscriber
+pub const QUEUE_LEN: usize = 64;
+
+/// Words of the key state bitmap of a device
+const KEY_WORDS: usize = (codes::KEY_MAX as usize + 1).div_ceil(64);
+
+/// Values of key events
+pub const KEY_RELEASED: i32 = 0;
+pub const KEY_PRESSED: i32 = 1;
+/// Repeat of a key held down, by devices doing their own autorepeat
+pub const KEY_REPEAT: i32 = 2;
+
+/// Type of an event
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u16)]
+pub enum EventType {
+    /// End of a group of events (`EV_SYN`)
+    Sync = 0,
+    /// Key or button, pressed (1) or released (0) (`EV_KEY`)
+    Key = 1,
+    /// Relative axis motion (`EV_REL`)
+    Rel = 2,
+    /// Absolute axis position (`EV_ABS`)
+    Abs = 3,
+}
+
+impl EventType {
+    /// Bit of the type in the masks given to `register` and `subscribe`
+    pub const fn bit(self) -> u32 {
+        1 << self as u16
+    }
+
+    /// Returns the type whose evdev value is `raw`
+    pub fn from_raw(raw: u16) -> Option<Self> {
+        match raw {
+            0 => Some(Self::Sync),
+            1 => Some(Self::Key),
+            2 => Some(Self::Rel),
+            3 => Some(Self::Abs),
+            _ => None,
+        }
+    }
+}
+
+/// An input event
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InputEvent {
+    /// Time of the report, in nanoseconds since boot
+    pub time_ns: u64,
+    /// Device that reported it, see `InputHandle::id`
+    pub device: usize,
+    pub kind: EventType,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    const EMPTY: Self = Self {
+        time_ns: 0,
+        device: 0,
+        kind: EventType::Sync,
+        code: 0,
+        value: 0,
+    };
+}
+
+/// Errors of the input subsystem
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InputError {
+    /// The device or subscriber table is full
+    NoSpace,
+}
+
+/// A registered device
+#[derive(Clone, Copy)]
+struct Device {
+    /// `None` if the slot is free
+    name: Option<&'static str>,
+    /// Mask of the event types reported
+    types: u32,
+    /// Keys held down, by code
+    keys: [u64; KEY_WORDS],
+}
+
+static DEVICES: Mutex<[Device; MAX_DEVICES]> = Mutex::new(
+    [Device {
+        name: None,
+        types: 0,
+        keys: [0; KEY_WORDS],
+    }; MAX_DEVICES],
+);
+
+/// A subscriber's queue
+struct Queue {
+    /// Mask of the event types subscribed to, 0 if the slot is free
+    types: u32,
+    events: [InputEvent; QUEUE_LEN],
+    /// `count` events starting at `head`
+    head: usize,
+    count: usize,
+    dropped: usize,
+}
+
+static QUEUES: Mutex<[Queue; MAX_SUBSCRIBERS]> = Mutex::new(
+    [const {
+        Queue {
+            types: 0,
+            events: [InputEvent::EMPTY; QUEUE_LEN],
+            head: 0,
+            count: 0,
+            dropped: 0,
+        }
+    }; MAX_SUBSCRIBERS],
+);
+
+/// Woken when an event is queued for the subscriber in the same slot
+static WAITERS: [WaitQueue; MAX_SUBSCRIBERS] = [const { WaitQueue::new() }; MAX_SUBSCRIBERS];
+
+/// A registered input device; dropping it unregisters the device
+pub struct InputHandle {
+    id: usize,
+}
+
+/// Registers the device `name`, which reports the event types in the mask `types`
+///
+/// The keyboard handler and `tty0` are started with the first device reporting keys.
+pub fn register(name: &'static str, types: u32) -> Result<InputHandle, InputError> {
+    let id = DEVICES.lock_irqsafe(|devices| {
+        let id = devices
+            .iter()
+            .position(|dev| dev.name.is_none())
+            .ok_or(InputError::NoSpace)?;
+        devices[id] = Device {
+            name: Some(name),
+            types,
+            keys: [0; KEY_WORDS],
+        };
+        Ok(id)
+    })?;
+    if types & EventType::Key.bit() != 0 {
+        keyboard::attach();
+    }
+    println!("input: {} registered as input{}", name, id);
+    Ok(InputHandle { id })
+}
+
+/// Queues `event` for every subscriber of its type, or of one of the types of the device
+/// (`types`) for a `Sync` event
+fn deliver(event: InputEvent, types: u32) {
+    let wanted = match event.kind {
+        EventType::Sync => types,
+        kind => kind.bit(),
+    };
+    let mut woken = 0u32;
+    QUEUES.lock_irqsafe(|queues| {
+        for (slot, queue) in queues.iter_mut().enumerate() {
+            if queue.types & wanted == 0 {
+                continue;
+            }
+            if queue.count == QUEUE_LEN {
+                queue.dropped += 1;
+                continue;
+            }
+            queue.events[(queue.head + queue.count) % QUEUE_LEN] = event;
+            queue.count += 1;
+            woken |= 1 << slot;
+        }
+    });
+    for (slot, waiters) in WAITERS.iter().enumerate() {
+        if woken & (1 << slot) != 0 {
+            waiters.wake_up();
+        }
+    }
+}
+
+impl InputHandle {
+    /// Returns the device's number, found in its events
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Reports an event
+    ///
+    /// Key presses and releases that don't change the state of the key are dropped, as are
+    /// repeats of a key that is up.
+    pub fn report(&self, kind: EventType, code: u16, value: i32) {
+        let types = DEVICES.lock_irqsafe(|devices| {
+            let dev = &mut devices[self.id];
+            if kind == EventType::Key {
+                let (word, bit) = (code as usize / 64, 1u64 << (code % 64));
+                let slot = dev.keys.get_mut(word)?;
+                let down = *slot & bit != 0;
+                match value {
+                    KEY_RELEASED if down => *slot &= !bit,
+                    KEY_REPEAT if down => {}
+                    KEY_RELEASED | KEY_REPEAT => return None,
+                    _ if down => return None,
+                    _ => *slot |= bit,
+                }
+            }
+            Some(dev.types)
+        });
+        let Some(types) = types else {
+            return;
+        };
+        let event = InputEvent {
+            time_ns: clocksource::now_ns(),
+            device: self.id,
+            kind,
+            code,
+            value,
+        };
+        deliver(event, types);
+    }
+
+    /// Reports key `code` pressed or released
+    pub fn report_key(&self, code: u16, pressed: bool) {
+        let value = if pressed { KEY_PRESSED } else { KEY_RELEASED };
+        self.report(EventType::Key, code, value);
+    }
+
+    /// Closes the group of events reported so far
+    pub fn sync(&self) {
+        self.report(EventType::Sync, codes::SYN_REPORT, 0);
+    }
+}
+
+impl Drop for InputHandle {
+    /// Releases the keys still held down and frees the device's slot
+    fn drop(&mut self) {
+        let keys = DEVICES.lock_irqsafe(|devices| devices[self.id].keys);
+        let held = keys.iter().enumerate().flat_map(|(word, &bits)| {
+            (0..64)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| (word * 64 + bit) as u16)
+        });
+        let mut released = false;
+        for code in held {
+            self.report_key(code, false);
+            released = true;
+        }
+        if released {
+            self.sync();
+        }
+        DEVICES.lock_irqsafe(|devices| devices[self.id].name = None);
+    }
+}
+
+/// A subscription to some event types; dropping it unsubscribes
+pub struct Subscription {
+    /// Slot in `QUEUES`
+    slot: usize,
+}
+
+/// Subscribes to the events of the types in the mask `types`, from every device
+pub fn subscribe(types: u32) -> Result<Subscription, InputError> {
+    QUEUES.lock_irqsafe(|queues| {
+        let slot = queues
+            .iter()
+            .position(|queue| queue.types == 0)
+            .ok_or(InputError::NoSpace)?;
+        let queue = &mut queues[slot];
+        queue.types = types;
+        queue.head = 0;
+        queue.count = 0;
+        queue.dropped = 0;
+        Ok(Subscription { slot })
+    })
+}
+
+impl Subscription {
+    /// Returns the oldest event queued, if any
+    pub fn try_recv(&self) -> Option<InputEvent> {
+        QUEUES.lock_irqsafe(|queues| {
+            let queue = &mut queues[self.slot];
+            if queue.count == 0 {
+                return None;
+            }
+            let event = queue.events[queue.head];
+            queue.head = (queue.head + 1) % QUEUE_LEN;
+            queue.count -= 1;
+            Some(event)
+        })
+    }
+
+    /// Sleeps until an event is queued, and returns it
+    pub fn recv(&self) -> InputEvent {
+        let mut event = None;
+        WAITERS[self.slot].wait_event(|| {
+            event = self.try_recv();
+            event.is_some()
+        });
+        event.unwrap_or(InputEvent::EMPTY)
+    }
+
+    /// Sleeps until an event is queued or the counter (`CNTPCT_EL0`) reaches `expires`
+    pub fn recv_until(&self, expires: u64) -> Result<InputEvent, WaitError> {
+        let mut event = None;
+        WAITERS[self.slot].wait_event_until(expires, || {
+            event = self.try_recv();
+            event.is_some()
+        })?;
+        event.ok_or(WaitError::Timeout)
+    }
+
+    /// Returns the number of events dropped because the queue was full
+    pub fn dropped(&self) -> usize {
+        QUEUES.lock_irqsafe(|queues| queues[self.slot].dropped)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        QUEUES.lock_irqsafe(|queues| queues[self.slot].types = 0);
+    }
+}
@@ -0,0 +1,689 @@
+//! Exception handling module
+
+pub mod of;
+pub mod serror;
+pub mod softirq;
+pub mod vectors;
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use crate::drivers::gic;
+use crate::drivers::timer::arch_timer;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::debug::{self, backtrace};
+use crate::kernel::mm::{addr_space, kstack, vma};
+use crate::kernel::smp::{self, MAX_CPUS};
+use crate::kernel::trace::latency;
+use crate::kernel::{extable, sched, signal, syscall};
+use crate::{pr_err, pr_warn, print, println, trace_event};
+
+/// Maximum number of interrupt handlers that can be registered
+pub const MAX_IRQ_ACTIONS: usize = 32;
+
+/// Function called when a registered interrupt fires
+///
+/// Receives the interrupt ID and the `data` value given at registration time, which drivers use
+/// to find the device instance that raised the interrupt.
+pub type IrqHandler = fn(id: u32, data: usize);
+
+/// A handler registered for an interrupt ID
+#[derive(Clone, Copy)]
+struct IrqAction {
+    /// Interrupt ID (INTID) the handler is attached to
+    id: u32,
+    /// Name of the device or driver owning the interrupt
+    name: &'static str,
+    /// Function called when the interrupt fires
+    handler: IrqHandler,
+    /// Opaque value passed back to the handler
+    data: usize,
+    /// Number of times the interrupt has fired
+    count: u64,
+    /// Counter value (`CNTPCT_EL0`) when the interrupt last fired, 0 if it never did
+    last: u64,
+}
+
+/// Statistics of a registered interrupt
+#[derive(Clone, Copy, Debug)]
+pub struct IrqStat {
+    /// Interrupt ID (INTID)
+    pub id: u32,
+    /// Name given to `request_irq`
+    pub name: &'static str,
+    /// Number of times the interrupt has fired
+    pub count: u64,
+    /// Counter value (`CNTPCT_EL0`) when the interrupt last fired, 0 if it never did
+    pub last: u64,
+}
+
+/// Snapshot of the interrupt statistics returned by `stats`
+pub struct IrqStats {
+    /// One entry per registered interrupt
+    pub irqs: [Option<IrqStat>; MAX_IRQ_ACTIONS],
+    /// Acknowledgements that returned a special INTID (1020-1023) instead of an interrupt
+    pub spurious: u64,
+    /// Interrupts that fired without a registered handler
+    pub unhandled: u64,
+}
+
+/// Errors returned by `request_irq`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IrqError {
+    /// A handler is already registered for this interrupt ID
+    Busy,
+    /// The handler table is full
+    NoSpace,
+}
+
+/// Unmasks IRQs on the current CPU
+#[inline(always)]
+pub fn local_irq_enable() {
+    unsafe {
+        asm!("msr daifclr, #2", options(nostack, nomem, preserves_flags));
+    }
+}
+
+/// Masks IRQs on the current CPU
+#[inline(always)]
+pub fn local_irq_disable() {
+    unsafe {
+        asm!("msr daifset, #2", options(nostack, nomem, preserves_flags));
+    }
+}
+
+/// Masks IRQs and returns the previous `DAIF` value, to be given to `local_irq_restore`
+#[inline(always)]
+pub fn local_irq_save() -> u64 {
+    let daif: u64;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif, options(nostack, nomem, preserves_flags));
+    }
+    local_irq_disable();
+    daif
+}
+
+/// Restores the interrupt mask saved by `local_irq_save`
+#[inline(always)]
+pub fn local_irq_restore(daif: u64) {
+    unsafe {
+        asm!("msr daif, {}", in(reg) daif, options(nostack, nomem, preserves_flags));
+    }
+}
+
+/// Puts the current CPU in a low-power state until an interrupt is pending (`WFI`)
+///
+/// A pending interrupt wakes the CPU even if it is masked; it is then only taken once unmasked.
+#[inline(always)]
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("wfi", options(nostack, preserves_flags));
+    }
+}
+
+/// Number of spurious acknowledgements, see `IrqStats::spurious`
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Number of interrupts without a handler, see `IrqStats::unhandled`
+static UNHANDLED_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Registers saved by the IRQ exception each CPU is handling, null outside of one
+static IRQ_REGS: [AtomicPtr<Regs>; MAX_CPUS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CPUS];
+
+/// Table of registered interrupt handlers
+static IRQ_ACTIONS: Mutex<[Option<IrqAction>; MAX_IRQ_ACTIONS]> =
+    Mutex::new([None; MAX_IRQ_ACTIONS]);
+
+/// Registers `handler` for interrupt `id`
+///
+/// Only the handler is registered; the driver is still responsible for configuring and enabling
+/// the interrupt in the GIC.
+pub fn request_irq(
+    id: u32,
+    name: &'static str,
+    handler: IrqHandler,
+    data: usize,
+) -> Result<(), IrqError> {
+    IRQ_ACTIONS.lock_irqsafe(|actions| {
+        if actions.iter().flatten().any(|action| action.id == id) {
+            return Err(IrqError::Busy);
+        }
+        let slot = actions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::NoSpace)?;
+        *slot = Some(IrqAction {
+            id,
+            name,
+            handler,
+            data,
+            count: 0,
+            last: 0,
+        });
+        Ok(())
+    })
+}
+
+/// Returns the name given when the handler for interrupt `id` was registered
+pub fn irq_name(id: u32) -> Option<&'static str> {
+    IRQ_ACTIONS.lock_irqsafe(|actions| {
+        actions
+            .iter()
+            .flatten()
+            .find(|action| action.id == id)
+            .map(|action| action.name)
+    })
+}
+
+/// Returns a snapshot of the interrupt statistics
+pub fn stats() -> IrqStats {
+    let irqs = IRQ_ACTIONS.lock_irqsafe(|actions| {
+        actions.map(|action| {
+            action.map(|action| IrqStat {
+                id: action.id,
+                name: action.name,
+                count: action.count,
+                last: action.last,
+            })
+        })
+    });
+    IrqStats {
+        irqs,
+        spurious: SPURIOUS_COUNT.load(Ordering::Relaxed),
+        unhandled: UNHANDLED_COUNT.load(Ordering::Relaxed),
+    }
+}
+
+/// Removes the handler registered for interrupt `id`
+pub fn free_irq(id: u32) {
+    IRQ_ACTIONS.lock_irqsafe(|actions| {
+        for slot in actions.iter_mut() {
+            if slot.is_some_and(|action| action.id == id) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+/// CPU register state at the time of an exception
+///
+/// This struct captures all general-purpose registers (x0-x30) and special
+/// system registers when an exception occurs. The layout matches the order
+/// in which registers are saved by the exception entry code (`save_regs` in
+/// `vectors`), which takes its offsets from this struct: x29/x30, pushed first by
+/// the vector, end up at the highest address.
+///
+/// # Fields
+///
+/// - `x0-x30`: General-purpose registers
+/// - `esr`: Exception Syndrome Register - describes the exception cause
+/// - `elr`: Exception Link Register - return address
+/// - `spsr`: Saved Program Status Register - saved processor state
+/// - `sp_el0`: User stack pointer, restored on exit for exceptions taken from EL0
+/// - `_pad1`: `xzr` slot pushed to keep the register pairs aligned
+///
+/// Handlers may modify the registers: `exception_exit` restores them before `eret`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Regs {
+    pub elr: u64,
+    pub spsr: u64,
+    pub esr: u64,
+    pub sp_el0: u64,
+    pub x0: u64,
+    _pad1: u64,
+    pub x1: u64,
+    pub x2: u64,
+    pub x3: u64,
+    pub x4: u64,
+    pub x5: u64,
+    pub x6: u64,
+    pub x7: u64,
+    pub x8: u64,
+    pub x9: u64,
+    pub x10: u64,
+    pub x11: u64,
+    pub x12: u64,
+    pub x13: u64,
+    pub x14: u64,
+    pub x15: u64,
+    pub x16: u64,
+    pub x17: u64,
+    pub x18: u64,
+    pub x19: u64,
+    pub x20: u64,
+    pub x21: u64,
+    pub x22: u64,
+    pub x23: u64,
+    pub x24: u64,
+    pub x25: u64,
+    pub x26: u64,
+    pub x27: u64,
+    pub x28: u64,
+    pub x29: u64,
+    pub x30: u64,
+}
+
+impl Regs {
+    /// Register names for iteration
+    const NAMES: [&'static str; 34] = [
+        "x0 ", "x1 ", "x2 ", "x3 ", "x4 ", "x5 ", "x6 ", "x7 ", "x8 ", "x9 ", "x10", "x11", "x12",
+        "x13", "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25",
+        "x26", "x27", "x28", "x29", "x30", "esr", "elr", "spsr",
+    ];
+
+    /// Convert registers to an array for easy iteration
+    pub fn as_array(&self) -> [u64; 34] {
+        [
+            self.x0, self.x1, self.x2, self.x3, self.x4, self.x5, self.x6, self.x7, self.x8,
+            self.x9, self.x10, self.x11, self.x12, self.x13, self.x14, self.x15, self.x16,
+            self.x17, self.x18, self.x19, self.x20, self.x21, self.x22, self.x23, self.x24,
+            self.x25, self.x26, self.x27, self.x28, self.x29, self.x30, self.esr, self.elr,
+            self.spsr,
+        ]
+    }
+
+    /// Returns general-purpose register `xn` (0-30)
+    pub fn gpr(&self, n: usize) -> u64 {
+        match n {
+            0 => self.x0,
+            // x1-x30 are contiguous in the frame
+            1..=30 => unsafe { *(&self.x1 as *const u64).add(n - 1) },
+            _ => 0,
+        }
+    }
+
+    /// Sets general-purpose register `xn` (0-30) to `value`
+    pub fn set_gpr(&mut self, n: usize, value: u64) {
+        match n {
+            0 => self.x0 = value,
+            1..=30 => unsafe { *(&mut self.x1 as *mut u64).add(n - 1) = value },
+            _ => {}
+        }
+    }
+
+    /// Returns the exception class (`ESR_EL1.EC`) of the exception
+    pub fn exception_class(&self) -> u32 {
+        ((self.esr >> 26) & 0x3f) as u32
+    }
+
+    /// Returns the stack pointer at the time of the exception
+    ///
+    /// Only meaningful for exceptions taken from EL1 on `SP_EL1`: the frame is pushed right
+    /// below the interrupted stack pointer.
+    pub fn sp(&self) -> u64 {
+        self as *const Regs as u64 + core::mem::size_of::<Regs>() as u64
+    }
+
+    /// Returns an iterator over (name, value) pairs for all registers
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> {
+        self.as_array()
+            .into_iter()
+            .zip(Self::NAMES.iter().copied())
+            .map(|(val, name)| (name, val))
+    }
+
+    /// Print all registers to UART
+    pub fn print(&self) {
+        println!("\nRegisters:");
+        for (name, value) in self.iter() {
+            println!("{}: 0x{:016x}", name, value);
+        }
+    }
+}
+
+/// Prints the faulting instruction at the exception address
+///
+/// Reads and displays the 32-bit instruction at the address stored in the
+/// Exception Link Register (ELR), which points to the instruction that
+/// caused the exception.
+fn print_faulting_instr(elr: u64) {
+    let addr = (elr & !3) as *const u32;
+    let opcode: u32;
+    unsafe {
+        opcode = addr.read_volatile();
+    }
+
+    print!("Faulting instruction at 0x{:016x}: ", elr);
+    for i in 0..4u32 {
+        let byte = (opcode >> (i * 8)) as u8;
+        if i == 0 {
+            print!("[{:02X}]", byte);
+        } else {
+            print!("{:02X}", byte);
+        }
+        if i < 3 {
+            print!(" ");
+        }
+    }
+    println!();
+}
+
+/// Prints all CPU registers from the saved register state
+#[inline(always)]
+fn print_regs(regs: &Regs) {
+    // Print register dump
+    regs.print();
+}
+
+/// Handles synchronous exceptions from an unexpected exception level
+///
+/// This "bad mode" handler is called when a synchronous exception occurs
+/// from an exception level that should not normally generate exceptions.
+/// It prints diagnostic information and panics.
+#[unsafe(no_mangle)]
+pub extern "C" fn do_bad_sync(regs: &Regs) -> ! {
+    println!("Bad mode in Synchronous Exception handler");
+    print_faulting_instr(regs.elr);
+    print_regs(regs);
+    panic!();
+}
+
+/// Handles IRQ (Interrupt Request) from an unexpected exception level
+///
+/// This "bad mode" handler is called when an IRQ occurs from an exception
+/// level that should not normally generate interrupts. It prints diagnostic
+/// information and panics.
+#[unsafe(no_mangle)]
+pub extern "C" fn do_bad_irq(regs: &Regs) -> ! {
+    println!("Bad mode in IRQ handler");
+    print_faulting_instr(regs.elr);
+    print_regs(regs);
+    panic!();
+}
+
+/// Handles FIQ (Fast Interrupt Request) from an unexpected exception level
+///
+/// This "bad mode" handler is called when an FIQ occurs from an exception
+/// level that should not normally generate fast interrupts. It prints
+/// diagnostic information and panics.
+#[unsafe(no_mangle)]
+pub extern "C" fn do_bad_fiq(regs: &Regs) -> ! {
+    println!("Bad mode in FIQ handler");
+    print_faulting_instr(regs.elr);
+    print_regs(regs);
+    panic!();
+}
+
+/// Handles SError (System Error) from an unexpected exception level
+///
+/// This "bad mode" handler is called when a system error occurs from an
+/// exception level that should not normally generate SErrors. It prints
+/// diagnostic information and panics.
+#[unsafe(no_mangle)]
+pub extern "C" fn do_bad_serror(regs: &Regs) -> ! {
+    println!("Bad mode in SError handler");
+    print_faulting_instr(regs.elr);
+    print_regs(regs);
+    panic!();
+}
+
+/// Exception class of an SVC instruction executed in AArch64 state
+const EC_SVC64: u32 = 0x15;
+/// Data abort from a lower EL
+const EC_DABT_LOWER: u32 = 0x24;
+/// Data abort taken without a change in EL
+const EC_DABT_CUR: u32 = 0x25;
+/// Instruction abort taken from a lower EL
+const EC_IABT_LOWER: u32 = 0x20;
+/// FP/SIMD access trapped by `CPACR_EL1.FPEN`
+const EC_FP_ACCESS: u32 = 0x07;
+
+/// SPSR.I: IRQs were masked in the interrupted context
+const SPSR_I: u64 = 1 << 7;
+/// SPSR.M[3:0]: exception level and stack pointer of the interrupted context, 0 for EL0
+pub const SPSR_M: u64 = 0b1111;
+
+/// Synchronous exception handler
+///
+/// Decodes the exception class from `ESR_EL1` and dispatches to the subsystem handling it. Any
+/// modification to `regs` (e.g. a syscall return value in `x0`, or a new `elr`) is applied when
+/// the exception returns. Exceptions nobody handles are reported and the kernel panics, as
+/// returning would just re-execute the faulting instruction.
+#[unsafe(no_mangle)]
+pub extern "C" fn do_sync(regs: &mut Regs) {
+    let ec = regs.exception_class();
+    match ec {
+        EC_SVC64 => regs.x0 = do_syscall(regs.x8 as u32),
+        EC_DABT_CUR if user_fault(regs) => {}
+        EC_DABT_LOWER | EC_DABT_CUR if extable::fixup_exception(regs) => {}
+        EC_DABT_CUR if let Some(id) = kstack::guard_owner(read_far() as usize) => {
+            kernel_stack_overflow(regs, id, read_far())
+        }
+        debug::EC_BRK64 if vectors::handle_self_check(regs) => {}
+        debug::EC_FIRST..=debug::EC_LAST if debug::handle_exception(regs, ec) => {}
+        _ => {
+            unimplemented_sync(ec);
+            print_faulting_instr(regs.elr);
+            print_regs(regs);
+            panic!();
+        }
+    }
+}
+
+/// Maps the missing user page the kernel faulted on, e.g. in `copy_to_user`
+///
+/// Runs with IRQs unmasked if the interrupted code had them unmasked, as filling the page may
+/// sleep. Returns true if the access can be retried.
+fn user_fault(regs: &Regs) -> bool {
+    let addr = read_far() as usize;
+    if !addr_space::is_user_range(addr, 1) || regs.spsr & SPSR_I != 0 {
+        return false;
+    }
+    local_irq_enable();
+    let handled = vma::handle_fault(addr, regs.esr);
+    local_irq_disable();
+    handled
+}
+
+/// Returns the faulting address of the last abort (`FAR_EL1`)
+fn read_far() -> u64 {
+    let far: u64;
+    unsafe {
+        asm!("mrs {}, far_el1", out(reg) far, options(nostack, nomem, preserves_flags));
+    }
+    far
+}
+
+/// Reports an overflow of the kernel stack of task `id`, detected at address `addr`, and panics
+fn kernel_stack_overflow(regs: &Regs, id: sched::TaskId, addr: u64) -> ! {
+    println!("kernel stack overflow in task {} (address {:#x})", id, addr);
+    print_faulting_instr(regs.elr);
+    print_regs(regs);
+    backtrace::print(regs.elr, regs.x29);
+    panic!("kernel stack overflow");
+}
+
+/// Synchronous exception handler, called on the overflow stack when the exception was taken with
+/// the stack pointer too close to a guard area to push the frame (see `vectors`)
+///
+/// The interrupted stack pointer is passed in `regs.sp_el0`.
+#[unsafe(no_mangle)]
+pub extern "C" fn do_kernel_stack_overflow(regs: &Regs) -> ! {
+    let far = read_far();
+    match kstack::guard_owner(far as usize) {
+        Some(id) if regs.exception_class() == EC_DABT_CUR => kernel_stack_overflow(regs, id, far),
+        // Not a fault on the guard yet, but pushing the exception frame would have been one
+        _ => {
+            let sp = regs.sp_el0;
+            let id = kstack::guard_owner(sp as usize - kstack::KSTACK_MARGIN).unwrap_or(0);
+            kernel_stack_overflow(regs, id, sp)
+        }
+    }
+}
+
+/// System call handler
+fn do_syscall(nr: u32) -> u64 {
+    println!("Requested syscall: {}", nr);
+    0
+}
+
+/// Synchronous exception handler for exceptions taken from EL0
+///
+/// System calls run with interrupts unmasked, like any other kernel code. Any other exception is
+/// a fault of the user task, which is reported and terminated. On the way back to EL0, the task
+/// gives up the CPU if a reschedule is pending.
+#[unsafe(no_mangle)]
+pub extern "C" fn do_el0_sync(regs: &mut Regs) {
+    let ec = regs.exception_class();
+    local_irq_enable();
+    if ec == EC_SVC64 {
+        syscall::dispatch(regs);
+    } else if ec == EC_FP_ACCESS && sched::fpsimd::first_use() {
+        // The task has FP/SIMD state now, the instruction runs again with access enabled
+    } else if matches!(ec, EC_DABT_LOWER | EC_IABT_LOWER)
+        && vma::handle_fault(read_far() as usize, regs.esr)
+    {
+        // Page mapped, the instruction runs again
+    } else {
+        pr_err!(
+            "task {}: fault at {:#x} (address {:#x}), killed",
+            sched::current().unwrap_or(0),
+            regs.elr,
+            read_far()
+        );
+        unimplemented_sync(ec);
+        sched::exit(-1);
+    }
+    signal::do_signal(regs);
+    local_irq_disable();
+    sched::preempt_irq_exit();
+}
+
+/// IRQ exception handler, called from the IRQ vector
+///
+/// Acknowledgement and end of interrupt are handled by the interrupt controller driver, which
+/// calls back into `dispatch` for every pending interrupt. Work deferred by the handlers runs
+/// afterwards, with interrupts unmasked, and finally the scheduler gets a chance to switch tasks.
+/// A user task interrupted at EL0 then acts upon its pending signals.
+#[unsafe(no_mangle)]
+pub extern "C" fn do_irq(regs: &mut Regs) {
+    let outer = latency::irq_enter();
+    let irq_regs = &IRQ_REGS[smp::this_cpu()];
+    let outer_regs = irq_regs.swap(regs, Ordering::Relaxed);
+    gic::handle_irq();
+    irq_regs.store(outer_regs, Ordering::Relaxed);
+    latency::irq_exit(outer);
+    softirq::irq_exit();
+    sched::preempt_irq_exit();
+    if regs.spsr & SPSR_M == 0 {
+        local_irq_enable();
+        signal::do_signal(regs);
+        local_irq_disable();
+    }
+}
+
+/// Calls `f` on the registers of the code the IRQ being handled interrupted
+///
+/// Returns `None` outside of an interrupt handler.
+pub fn irq_regs<R>(f: impl FnOnce(&Regs) -> R) -> Option<R> {
+    let regs = IRQ_REGS[smp::this_cpu()].load(Ordering::Relaxed);
+    // Set by `do_irq` for as long as it runs, on its own stack frame
+    unsafe { regs.as_ref() }.map(f)
+}
+
+/// Calls the handler registered for the interrupt `id`
+///
+/// The handler is copied out of the table before being called, so the table lock is not held
+/// while it runs.
+pub fn dispatch(id: u32) {
+    let now = arch_timer::get_counter();
+    let action = IRQ_ACTIONS.lock(|actions| {
+        let action = actions.iter_mut().flatten().find(|a| a.id == id)?;
+        action.count += 1;
+        action.last = now;
+        Some(*action)
+    });
+    match action {
+        Some(action) => {
+            latency::irq_dispatch(id, now);
+            trace_event!(irq, "irq {} entry", id);
+            (action.handler)(id, action.data);
+            trace_event!(irq, "irq {} exit", id);
+        }
+        None => {
+            UNHANDLED_COUNT.fetch_add(1, Ordering::Relaxed);
+            pr_warn!("Unhandled IRQ: {}", id);
+        }
+    }
+}
+
+/// Records an IRQ exception for which the controller had no interrupt to report
+pub fn note_spurious() {
+    SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Handler for unimplemented synchronous exceptions
+pub fn unimplemented_sync(exception_class: u32) {
+    let kind;
+
+    match exception_class {
+        1 => kind = "Trapped WF",
+        3 => kind = "Trapped MCR (coproc==0b1111). EC value 0b000000",
+        4 => kind = "Trapped MCRR or MRRC (coproc=0b1111). EC value 0b000000",
+        5 => kind = "Trapped MCR (coproc==0b1110)",
+        6 => kind = "Trapped LDC",
+        7 => kind = "SIMD not implemented. EC value 0b000000",
+        10 => kind = "Trapped execution of any instruction not covered by other EC values",
+        12 => kind = "Trapped MRRC (coproc=0b1110)",
+        13 => kind = "Branch Target Exception",
+        14 => kind = "Illegal Execution State",
+        17 => kind = "SVC in AArch32 state",
+        20 => {
+            kind = "Trapped MSRR, MRRS or System instruction execution in AArch64 state. EC value 0b000000"
+        }
+        21 => kind = "SVC in AArch64 state",
+        24 => {
+            kind = "Trapped MSRR, MRRS or System instruction execution in AArch64 state. EC values 0b000000, 0b000001, 0b000111"
+        }
+        25 => kind = "Access to SVE functionality. EC value 0b000000",
+        27 => kind = "TSTART instruction at EL0",
+        28 => kind = "PAC fail",
+        29 => kind = "Access to SME functionality. EC value 0b000000",
+        32 => kind = "Instruction abort from lower EL",
+        33 => kind = "Instruction abort taken without a change in EL",
+        34 => kind = "PC alignement fault",
+        36 => kind = "Data abort from lower EL",
+        37 => kind = "Data abort taken without a change in EL",
+        38 => kind = "SP alignement fault",
+        39 => kind = "Memory operation exception",
+        40 => kind = "FPE from AArch32 state",
+        44 => kind = "FPE from AArch64 state",
+        45 => kind = "GCS exception",
+        47 => kind = "SError exception",
+        48 => kind = "Breakpoint exception from a lower EL",
+        49 => kind = "Breakpoint exception taken without a change in EL",
+        50 => kind = "Software step exception from a lower EL",
+        51 => kind = "Software step exception exception taken without a change in EL",
+        52 => kind = "Watchpoint exception from a lower EL",
+        53 => kind = "Watchpoint exception exception taken without a change in EL",
+        56 => kind = "BKPT instruction in AArch32 state",
+        60 => kind = "BRK instruction in AArch64 state",
+        61 => kind = "Profiling exception",
+        _ => kind = "Unknown reason",
+    }
+    println!("Unimplemented synchronous exception: {}", kind);
+}
+
+/// Handles FIQ (Fast Interrupt Request) from the current exception level
+///
+/// Called when a fast interrupt request is received. Prints diagnostic
+/// information and panics (as FIQ handling is not yet implemented).
+#[unsafe(no_mangle)]
+pub extern "C" fn do_fiq(regs: &Regs) -> ! {
+    println!("FIQ handler");
+    print_faulting_instr(regs.elr);
+    print_regs(regs);
+    panic!();
+}
+
+/// Handles SError (System Error) from the current exception level or from EL0
+///
+/// Called when a system error occurs (e.g., asynchronous external abort). `serror` decodes it
+/// and its policy decides whether the interrupted code resumes, the task is killed or the
+/// kernel panics.
+#[unsafe(no_mangle)]
+pub extern "C" fn do_serror(regs: &mut Regs) {
+    serror::handle(regs);
+}
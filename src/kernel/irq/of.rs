@@ -0,0 +1,225 @@
+//! Interrupt specifiers from the device tree
+//!
+//! A device lists its interrupts in `interrupts`, as specifiers in the format of its interrupt
+//! controller: `#interrupt-cells` cells each, the controller being named by the nearest
+//! `interrupt-parent`, on the device itself or an ancestor. `interrupts-extended` instead pairs
+//! every specifier with the phandle of its controller, so a device can be wired to several of
+//! them; it takes precedence over `interrupts`. `interrupt-names` names the entries.
+//!
+//! Only the GIC format is decoded: type (0 = SPI, 1 = PPI), number relative to the first INTID
+//! of the type, and flags whose low bits are the trigger type. A fourth cell (the PPI partition)
+//! is ignored.
+//!
+//! Devices that aren't in the tree, such as PCI functions, are wired through a nexus node: the
+//! PCIe host bridge, whose `interrupt-map` lists, for each child unit address and interrupt
+//! specifier (a PCI address and an INTx pin), the controller and specifier they route to. Both
+//! are first masked with `interrupt-map-mask`, so that one entry covers, say, all functions of a
+//! slot. `of_irq_parse_map` resolves them.
+//!
+//! Message-signaled interrupts reach an MSI controller (the GIC's ITS) tagged with a device ID.
+//! A host bridge's `msi-map` gives the device IDs of the requester IDs below it, in ranges of
+//! (requester ID, controller phandle, device ID, length); `of_msi_map_id` applies it.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Equivalent to `of_irq_parse_one`, which Linux follows with `irq_create_of_mapping` to get a
+//! virtual IRQ number from the controller's IRQ domain. There is a single controller here, whose
+//! INTIDs are used directly. `of_irq_parse_map` is the `interrupt-map` walk of `of_irq_parse_raw`
+//! (reached from PCI through `of_irq_parse_and_map_pci`), limited to a single level: the entry
+//! must lead to the controller, not to another nexus.
+
+use crate::kernel::device::{PlatformDevice, Property};
+use crate::kernel::dtb;
+use crate::utilities::convert;
+
+/// `#interrupt-cells` assumed when the controller doesn't say, that of the GIC
+const DEFAULT_INTERRUPT_CELLS: usize = 3;
+
+/// Kind of a GIC interrupt
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IrqKind {
+    /// Shared peripheral interrupt, INTIDs 32 to 1019
+    Spi,
+    /// Private peripheral interrupt, INTIDs 16 to 31
+    Ppi,
+}
+
+/// Trigger type of an interrupt
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Trigger {
+    EdgeRising,
+    EdgeFalling,
+    LevelHigh,
+    LevelLow,
+}
+
+impl Trigger {
+    /// Decodes the flags cell of a specifier (bits 0-1: edge, bits 2-3: level)
+    ///
+    /// Flags without a trigger type are taken as level-high.
+    fn from_flags(flags: u32) -> Self {
+        if flags & 0x1 != 0 {
+            Trigger::EdgeRising
+        } else if flags & 0x2 != 0 {
+            Trigger::EdgeFalling
+        } else if flags & 0x8 != 0 {
+            Trigger::LevelLow
+        } else {
+            Trigger::LevelHigh
+        }
+    }
+
+    /// Returns true for the edge-triggered types
+    pub fn is_edge(self) -> bool {
+        matches!(self, Trigger::EdgeRising | Trigger::EdgeFalling)
+    }
+}
+
+/// A decoded interrupt specifier
+#[derive(Clone, Copy, Debug)]
+pub struct IrqSpec {
+    pub kind: IrqKind,
+    /// Number relative to the first INTID of `kind`
+    pub number: u32,
+    pub trigger: Trigger,
+}
+
+impl IrqSpec {
+    /// Returns the INTID of the interrupt
+    pub fn intid(&self) -> u32 {
+        match self.kind {
+            IrqKind::Spi => 32 + self.number,
+            IrqKind::Ppi => 16 + self.number,
+        }
+    }
+}
+
+/// Decodes the interrupt at `index` in `interrupts-extended` or `interrupts` of `dev`
+///
+/// Returns `None` if there is no such entry, its controller can't be found or the specifier
+/// isn't in the GIC format.
+pub fn of_irq_parse(dev: &PlatformDevice, index: usize) -> Option<IrqSpec> {
+    if let Some(prop) = dev.find_property("interrupts-extended") {
+        let mut offset = 0;
+        let mut i = 0;
+        while offset + 4 <= prop.len {
+            let phandle = convert::read_be_u32(prop.value, offset);
+            let cells = interrupt_cells(dtb::find_device_by_phandle(phandle)?);
+            if i == index {
+                return decode(prop, offset + 4, cells);
+            }
+            offset += (1 + cells) * 4;
+            i += 1;
+        }
+        return None;
+    }
+    let prop = dev.find_property("interrupts")?;
+    let cells = interrupt_cells(dtb::find_interrupt_parent(dev)?);
+    decode(prop, index * cells * 4, cells)
+}
+
+/// Decodes the interrupt named `name` in the `interrupt-names` of `dev`
+pub fn of_irq_parse_by_name(dev: &PlatformDevice, name: &str) -> Option<IrqSpec> {
+    let names = dev.find_property("interrupt-names")?;
+    let names = unsafe { core::slice::from_raw_parts(names.value, names.len) };
+    let index = names
+        .split(|&b| b == 0)
+        .position(|n| n == name.as_bytes())?;
+    of_irq_parse(dev, index)
+}
+
+/// Resolves an interrupt of a child of the nexus `nexus` through its `interrupt-map`
+///
+/// `unit` is the child's unit address, in the `#address-cells` of the nexus, and `spec` its
+/// interrupt specifier, in the `#interrupt-cells` of the nexus. Returns `None` if they don't
+/// have these sizes, no entry matches them, or the entry's controller can't be found or isn't
+/// in the GIC format.
+pub fn of_irq_parse_map(nexus: &PlatformDevice, unit: &[u32], spec: &[u32]) -> Option<IrqSpec> {
+    let map = nexus.find_property("interrupt-map")?;
+    let (addr_cells, _) = nexus.get_cells();
+    let addr_cells = addr_cells as usize;
+    let int_cells = interrupt_cells(nexus);
+    if unit.len() != addr_cells || spec.len() != int_cells {
+        return None;
+    }
+    let child_cells = addr_cells + int_cells;
+    let mask = nexus.find_property("interrupt-map-mask");
+    let mask_cell = |i: usize| match mask {
+        Some(mask) if (i + 1) * 4 <= mask.len => convert::read_be_u32(mask.value, i * 4),
+        _ => u32::MAX,
+    };
+    let child = |i: usize| {
+        if i < addr_cells {
+            unit[i]
+        } else {
+            spec[i - addr_cells]
+        }
+    };
+
+    let mut offset = 0;
+    while offset + (child_cells + 1) * 4 <= map.len {
+        let matches = (0..child_cells)
+            .all(|i| convert::read_be_u32(map.value, offset + i * 4) == child(i) & mask_cell(i));
+        let phandle = convert::read_be_u32(map.value, offset + child_cells * 4);
+        let parent = dtb::find_device_by_phandle(phandle)?;
+        // Parent unit addresses are only there to match a nested map, and usually empty
+        let parent_addr_cells = parent
+            .find_property("#address-cells")
+            .map_or(0, |p| convert::read_be_u32(p.value, 0) as usize);
+        let parent_spec = offset + (child_cells + 1 + parent_addr_cells) * 4;
+        let parent_int_cells = interrupt_cells(parent);
+        if matches {
+            return decode(map, parent_spec, parent_int_cells);
+        }
+        offset = parent_spec + parent_int_cells * 4;
+    }
+    None
+}
+
+/// Returns the device ID that MSIs of requester `rid`, below the host bridge `bridge`, carry
+///
+/// `rid` is masked with `msi-map-mask` and looked up in `msi-map`. A bridge with an
+/// `msi-parent` instead passes requester IDs through unchanged. Returns `None` if neither maps
+/// `rid`. A single MSI controller being supported, the phandles aren't checked.
+pub fn of_msi_map_id(bridge: &PlatformDevice, rid: u32) -> Option<u32> {
+    let Some(map) = bridge.find_property("msi-map") else {
+        return bridge.find_property("msi-parent").map(|_| rid);
+    };
+    let mask = bridge
+        .find_property("msi-map-mask")
+        .filter(|p| p.len == 4)
+        .map_or(u32::MAX, |p| convert::read_be_u32(p.value, 0));
+    let rid = rid & mask;
+    (0..map.len / 16).find_map(|i| {
+        let base = convert::read_be_u32(map.value, i * 16);
+        let out = convert::read_be_u32(map.value, i * 16 + 8);
+        let len = convert::read_be_u32(map.value, i * 16 + 12);
+        (rid >= base && rid - base < len).then(|| out + (rid - base))
+    })
+}
+
+/// Returns the `#interrupt-cells` of `controller`
+fn interrupt_cells(controller: &PlatformDevice) -> usize {
+    controller
+        .find_property("#interrupt-cells")
+        .map_or(DEFAULT_INTERRUPT_CELLS, |p| {
+            convert::read_be_u32(p.value, 0) as usize
+        })
+}
+
+/// Decodes the specifier of `cells` cells at `offset` in `prop`
+fn decode(prop: &Property, offset: usize, cells: usize) -> Option<IrqSpec> {
+    if cells < 3 || offset + cells * 4 > prop.len {
+        return None;
+    }
+    let kind = match convert::read_be_u32(prop.value, offset) {
+        0 => IrqKind::Spi,
+        1 => IrqKind::Ppi,
+        _ => return None,
+    };
+    Some(IrqSpec {
+        kind,
+        number: convert::read_be_u32(prop.value, offset + 4),
+        trigger: Trigger::from_flags(convert::read_be_u32(prop.value, offset + 8)),
+    })
+}
@@ -0,0 +1,333 @@
+//! SError diagnosis
+//!
+//! An SError is an asynchronous abort: a bus error on a posted write, a memory ECC error, a
+//! fault in a device... reported some time after the access that caused it, so `elr` only says
+//! what was running. With FEAT_RAS (Armv8.2) the CPU says more: the syndrome of `ESR_EL1` gives
+//! the error's severity, `DISR_EL1` records an error deferred by an `ESB` instruction, and the
+//! error records (`ERRSELR_EL1`, `ERX*_EL1`) of the nodes that detected it hold the status and
+//! often the physical address.
+//!
+//! `handle` collects all of that in a `SerrorInfo`, prints it and asks the policy what to do:
+//! resume the interrupted code, kill the user task that took the error, or panic. `set_policy`
+//! replaces the default policy, e.g. to treat every error as fatal when debugging a board.
+//!
+//! ## Design
+//!
+//! - Without FEAT_RAS the syndrome is implementation defined and nothing says whether the state
+//!   is intact: the severity is `Unknown`, which the default policy treats as fatal.
+//! - The error records are read once per SError, and the valid ones are cleared after being
+//!   reported so that the next error starts from a clean state. The worst severity among the
+//!   syndrome and the records wins.
+//! - The policy runs in the exception handler, with IRQs masked: it must not sleep.
+//! - The RAS registers are reached by their encodings: the assembler only knows their names
+//!   with the `ras` extension enabled.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `do_serror` (`arch/arm64/kernel/traps.c`) calls `arm64_is_fatal_ras_serror`, which
+//! classifies the `ESR_ELx.AET` field the same way: corrected and restartable errors are
+//! ignored, recoverable ones kill the user task, anything else panics. Firmware-first
+//! platforms report the error records through APEI/GHES, and the kernel-first path
+//! (`CONFIG_ARM64_RAS_EXTN`) reads them through EDAC drivers rather than in the handler.
+
+use core::arch::asm;
+use core::fmt;
+
+use super::{Regs, SPSR_M, local_irq_enable, print_faulting_instr, print_regs};
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::sched;
+use crate::{pr_err, pr_warn};
+
+/// `ESR_EL1.ISS.IDS`: the syndrome is implementation defined
+const ESR_IDS: u64 = 1 << 24;
+/// `ESR_EL1.ISS.AET` (and `DISR_EL1.AET`): asynchronous error type
+const AET_SHIFT: u64 = 10;
+const AET_MASK: u64 = 0b111;
+/// `DISR_EL1.A`: an SError was deferred by an `ESB`
+const DISR_A: u64 = 1 << 31;
+
+/* --- ERXSTATUS_EL1 Bits --- */
+const STATUS_AV: u64 = 1 << 31;
+const STATUS_V: u64 = 1 << 30;
+const STATUS_UE: u64 = 1 << 29;
+const STATUS_CE_SHIFT: u64 = 24;
+const STATUS_UET_SHIFT: u64 = 20;
+const STATUS_SERR_MASK: u64 = 0xff;
+
+/// Error records looked at, at most
+const MAX_RECORDS: usize = 64;
+
+/// Severity of an error, from the least to the most serious
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity {
+    /// Corrected by the hardware (CE)
+    Corrected,
+    /// Not corrected, but the interrupted code can go on (UEO)
+    Restartable,
+    /// Not corrected, the interrupted context must be abandoned (UER)
+    Recoverable,
+    /// Not corrected, the state of the CPU is intact but data was lost (UEU)
+    Unrecoverable,
+    /// Not corrected and possibly propagated (UC)
+    Uncontainable,
+    /// No architected syndrome to tell
+    Unknown,
+}
+
+impl Severity {
+    /// Decodes the `AET` field of an `ESR_EL1` or `DISR_EL1` syndrome
+    fn from_aet(aet: u64) -> Self {
+        match aet {
+            0b000 => Self::Uncontainable,
+            0b001 => Self::Unrecoverable,
+            0b010 => Self::Restartable,
+            0b011 => Self::Recoverable,
+            0b110 => Self::Corrected,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Decodes an `ERXSTATUS_EL1` value with the valid bit set
+    fn from_status(status: u64) -> Self {
+        if status & STATUS_UE == 0 {
+            return Self::Corrected;
+        }
+        match (status >> STATUS_UET_SHIFT) & 0b11 {
+            0b00 => Self::Uncontainable,
+            0b01 => Self::Unrecoverable,
+            0b10 => Self::Restartable,
+            _ => Self::Recoverable,
+        }
+    }
+}
+
+/// What to do after an SError
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    /// Return to the interrupted code
+    Resume,
+    /// Kill the user task that took the error, panic if it was the kernel
+    KillTask,
+    Panic,
+}
+
+/// A valid error record
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorRecord {
+    /// Index of the record (`ERRSELR_EL1`)
+    pub index: usize,
+    /// `ERXSTATUS_EL1`
+    pub status: u64,
+    /// Physical address of the error (`ERXADDR_EL1`), if recorded
+    pub addr: Option<u64>,
+    /// `ERXMISC0_EL1`, implementation defined
+    pub misc0: u64,
+}
+
+impl ErrorRecord {
+    pub fn severity(&self) -> Severity {
+        Severity::from_status(self.status)
+    }
+}
+
+impl fmt::Display for ErrorRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "record {}: {:?}, status {:#x} (SERR {:#x}, CE {}), misc0 {:#x}",
+            self.index,
+            self.severity(),
+            self.status,
+            self.status & STATUS_SERR_MASK,
+            (self.status >> STATUS_CE_SHIFT) & 0b11,
+            self.misc0
+        )?;
+        if let Some(addr) = self.addr {
+            write!(f, ", address {:#x}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// What is known of an SError
+#[derive(Clone, Copy, Debug)]
+pub struct SerrorInfo {
+    /// `ESR_EL1` of the exception
+    pub esr: u64,
+    /// `DISR_EL1`, if it recorded a deferred error
+    pub disr: Option<u64>,
+    /// The first valid error record, and the number of valid ones
+    pub record: Option<ErrorRecord>,
+    pub records: usize,
+    /// The worst severity of the syndrome and of the records
+    pub severity: Severity,
+    /// The error was taken from EL0
+    pub from_user: bool,
+}
+
+/// Decides what to do after an SError
+pub type PolicyFn = fn(info: &SerrorInfo) -> Action;
+
+static POLICY: Mutex<PolicyFn> = Mutex::new(default_policy);
+
+/// Ignores corrected and restartable errors, kills the task for a recoverable one and panics on
+/// anything worse
+pub fn default_policy(info: &SerrorInfo) -> Action {
+    match info.severity {
+        Severity::Corrected | Severity::Restartable => Action::Resume,
+        Severity::Recoverable => Action::KillTask,
+        _ => Action::Panic,
+    }
+}
+
+/// Replaces the policy, returning the previous one
+pub fn set_policy(policy: PolicyFn) -> PolicyFn {
+    POLICY.lock_irqsafe(|current| core::mem::replace(current, policy))
+}
+
+/// Expands to a read of the system register encoded as `$reg`
+macro_rules! read_ras_reg {
+    ($reg:literal) => {{
+        let value: u64;
+        unsafe {
+            asm!(
+                concat!("mrs {}, ", $reg),
+                out(reg) value,
+                options(nostack, preserves_flags)
+            )
+        };
+        value
+    }};
+}
+
+/// Expands to a write of `$value` to the system register encoded as `$reg`
+macro_rules! write_ras_reg {
+    ($reg:literal, $value:expr) => {
+        unsafe {
+            asm!(
+                concat!("msr ", $reg, ", {}"),
+                "isb",
+                in(reg) $value as u64,
+                options(nostack, preserves_flags)
+            )
+        }
+    };
+}
+
+/// Returns true if the CPU implements FEAT_RAS (`ID_AA64PFR0_EL1.RAS`)
+pub fn has_ras() -> bool {
+    let pfr0 = read_ras_reg!("id_aa64pfr0_el1");
+    (pfr0 >> 28) & 0xf != 0
+}
+
+/// Reads and clears `DISR_EL1`, returning it if it recorded an error
+fn take_disr() -> Option<u64> {
+    let disr = read_ras_reg!("s3_0_c12_c1_1");
+    if disr & DISR_A == 0 {
+        return None;
+    }
+    write_ras_reg!("s3_0_c12_c1_1", 0);
+    Some(disr)
+}
+
+/// Reads the valid error records, clearing them, and calls `f` on each
+fn take_records(mut f: impl FnMut(ErrorRecord)) {
+    // ERRIDR_EL1.NUM
+    let count = (read_ras_reg!("s3_0_c5_c3_0") & 0xffff) as usize;
+    for index in 0..count.min(MAX_RECORDS) {
+        // ERRSELR_EL1, then ERXSTATUS_EL1
+        write_ras_reg!("s3_0_c5_c3_1", index);
+        let status = read_ras_reg!("s3_0_c5_c4_2");
+        if status & STATUS_V == 0 {
+            continue;
+        }
+        let addr = (status & STATUS_AV != 0).then(|| read_ras_reg!("s3_0_c5_c4_3"));
+        let misc0 = read_ras_reg!("s3_0_c5_c5_0");
+        // The status bits are write-one-to-clear
+        write_ras_reg!("s3_0_c5_c4_2", status);
+        f(ErrorRecord {
+            index,
+            status,
+            addr,
+            misc0,
+        });
+    }
+}
+
+/// Gathers what the CPU knows of the SError described by `regs`
+fn diagnose(regs: &Regs) -> SerrorInfo {
+    let mut info = SerrorInfo {
+        esr: regs.esr,
+        disr: None,
+        record: None,
+        records: 0,
+        severity: Severity::Unknown,
+        from_user: regs.spsr & SPSR_M == 0,
+    };
+    if !has_ras() {
+        return info;
+    }
+    let aet = |syndrome: u64| Severity::from_aet((syndrome >> AET_SHIFT) & AET_MASK);
+    let mut severity = if regs.esr & ESR_IDS == 0 {
+        aet(regs.esr)
+    } else {
+        Severity::Unknown
+    };
+    info.disr = take_disr();
+    if let Some(disr) = info.disr.filter(|disr| disr & ESR_IDS == 0) {
+        severity = severity.max(aet(disr));
+    }
+    take_records(|record| {
+        pr_err!("SError: {}", record);
+        // A record can only make a known severity worse: Unknown stays the worst
+        severity = severity.max(record.severity());
+        info.record.get_or_insert(record);
+        info.records += 1;
+    });
+    info.severity = severity;
+    info
+}
+
+/// Handles an SError taken from EL1 on `SP_EL1` or from EL0
+///
+/// Returns if the policy lets the interrupted code go on.
+pub fn handle(regs: &mut Regs) {
+    let info = diagnose(regs);
+    let action = POLICY.lock_irqsafe(|policy| *policy)(&info);
+    let culprit = if info.from_user { "user" } else { "kernel" };
+    match action {
+        Action::Resume => {
+            pr_warn!(
+                "SError: {:?} error in {} code at {:#x} (esr {:#x}), resuming",
+                info.severity,
+                culprit,
+                regs.elr,
+                info.esr
+            );
+        }
+        Action::KillTask if info.from_user => {
+            let id = sched::current().unwrap_or(0);
+            pr_err!(
+                "SError: {:?} error in task {} at {:#x} (esr {:#x}), killed",
+                info.severity,
+                id,
+                regs.elr,
+                info.esr
+            );
+            local_irq_enable();
+            sched::exit(-1);
+        }
+        Action::KillTask | Action::Panic => {
+            pr_err!(
+                "SError: {:?} error in {} code (esr {:#x}, disr {:#x})",
+                info.severity,
+                culprit,
+                info.esr,
+                info.disr.unwrap_or(0)
+            );
+            print_faulting_instr(regs.elr);
+            print_regs(regs);
+            panic!("fatal SError");
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Deferred work (bottom halves)
+//!
+//! Interrupt handlers run with interrupts masked, so anything slow they do delays every other
+//! interrupt. Handlers should only do the minimum with the hardware (read the data, clear the
+//! interrupt) and defer the rest with `schedule_work`. Queued work runs in FIFO order:
+//!
+//! - on IRQ exit, with interrupts unmasked, once the interrupt controller has been told the
+//!   interrupt is done (see `irq_exit`), at most `MAX_WORK_PER_IRQ` items
+//! - in the worker task, `softirqd`, woken up when IRQ exit leaves work behind
+//! - from any thread context calling `run_pending`
+//!
+//! The worker is a real-time `Fifo` task (see `sched`): the leftover work still runs before any
+//! normal task, but an interrupt storm can no longer keep the CPU in interrupt context.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This plays the role of tasklets: a work item is a function and an argument, and scheduling an
+//! item that is already pending is a no-op, so a busy device can't flood the queue. There are no
+//! softirq vectors or per-CPU queues: one CPU at a time drains the queue. `softirqd` is
+//! `ksoftirqd`, which Linux runs in the normal class instead.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::irq;
+use crate::kernel::sched::{self, MAX_PRIORITY, Policy, Priority, SpawnAttrs};
+use crate::kernel::smp;
+use crate::{initcall, pr_err};
+
+/// Maximum number of work items that can be pending at once
+const MAX_PENDING_WORK: usize = 32;
+
+/// Work items run on IRQ exit before leaving the rest to the worker
+const MAX_WORK_PER_IRQ: usize = 8;
+
+/// Real-time priority of the worker, below the watchdog heartbeat's
+const WORKER_PRIORITY: Priority = MAX_PRIORITY - 1;
+
+/// Function run as deferred work, receiving the argument given to `schedule_work`
+pub type WorkFn = fn(arg: usize);
+
+/// A pending work item
+#[derive(Clone, Copy)]
+struct Work {
+    func: WorkFn,
+    arg: usize,
+}
+
+impl Work {
+    fn same_as(&self, other: &Work) -> bool {
+        core::ptr::fn_addr_eq(self.func, other.func) && self.arg == other.arg
+    }
+}
+
+/// Errors returned by `schedule_work`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SoftirqError {
+    /// The queue is full, the work was not scheduled
+    Full,
+}
+
+/// FIFO of pending work: `count` items starting at `head`
+struct WorkQueue {
+    items: [Option<Work>; MAX_PENDING_WORK],
+    head: usize,
+    count: usize,
+}
+
+static QUEUE: Mutex<WorkQueue> = Mutex::new(WorkQueue {
+    items: [None; MAX_PENDING_WORK],
+    head: 0,
+    count: 0,
+});
+
+/// Number plus one of the CPU draining the queue, 0 if none is, so a nested IRQ exit or another
+/// CPU doesn't drain it again
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once the worker runs; IRQ exit drains the whole queue until then
+static WORKER: AtomicBool = AtomicBool::new(false);
+
+/// The worker, waiting for work left by IRQ exit
+static WORKER_WAIT: WaitQueue = WaitQueue::new();
+
+/// Starts the worker
+pub fn init() {
+    let attrs = SpawnAttrs::realtime(Policy::Fifo, WORKER_PRIORITY);
+    match sched::spawn_with("softirqd", worker, 0, attrs) {
+        Ok(_) => WORKER.store(true, Ordering::Release),
+        Err(e) => pr_err!("softirq: cannot start the worker: {:?}", e),
+    }
+}
+initcall!(Late, "softirq", init, after = ["sched"]);
+
+/// Body of the worker: runs the work IRQ exit left behind
+fn worker(_arg: usize) {
+    loop {
+        WORKER_WAIT.wait_event(has_pending);
+        run_pending();
+    }
+}
+
+/// Queues `func(arg)` to run outside of interrupt context
+///
+/// Does nothing if the same function is already pending with the same argument.
+pub fn schedule_work(func: WorkFn, arg: usize) -> Result<(), SoftirqError> {
+    let work = Work { func, arg };
+    QUEUE.lock_irqsafe(|queue| {
+        let pending = (0..queue.count)
+            .filter_map(|i| queue.items[(queue.head + i) % MAX_PENDING_WORK])
+            .any(|w| w.same_as(&work));
+        if pending {
+            return Ok(());
+        }
+        if queue.count == MAX_PENDING_WORK {
+            return Err(SoftirqError::Full);
+        }
+        queue.items[(queue.head + queue.count) % MAX_PENDING_WORK] = Some(work);
+        queue.count += 1;
+        Ok(())
+    })
+}
+
+/// Returns true if work is waiting to run
+pub fn has_pending() -> bool {
+    QUEUE.lock_irqsafe(|queue| queue.count > 0)
+}
+
+/// Removes the oldest pending work item
+fn pop() -> Option<Work> {
+    QUEUE.lock_irqsafe(|queue| {
+        if queue.count == 0 {
+            return None;
+        }
+        let work = queue.items[queue.head].take();
+        queue.head = (queue.head + 1) % MAX_PENDING_WORK;
+        queue.count -= 1;
+        work
+    })
+}
+
+/// Runs pending work until the queue is empty
+///
+/// Work scheduled while draining (e.g. by an interrupt) runs in the same pass. Returns
+/// immediately if the queue is already being drained further up the stack.
+pub fn run_pending() {
+    run(usize::MAX);
+}
+
+/// Runs up to `max` pending work items, see `run_pending`
+fn run(max: usize) {
+    // Not moved to another CPU between reading its number and owning the queue
+    let daif = irq::local_irq_save();
+    let owner = smp::this_cpu() + 1;
+    let owned = RUNNING
+        .compare_exchange(0, owner, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok();
+    irq::local_irq_restore(daif);
+    if !owned {
+        return;
+    }
+    for _ in 0..max {
+        let Some(work) = pop() else {
+            break;
+        };
+        (work.func)(work.arg);
+    }
+    RUNNING.store(0, Ordering::Release);
+}
+
+/// Runs pending work at the end of an IRQ exception, waking up the worker for what is left
+///
+/// Interrupts are unmasked while the work runs, so a new interrupt can preempt it; the exception
+/// frame already holds the interrupted `ELR_EL1`/`SPSR_EL1`, making the nesting safe.
+pub fn irq_exit() {
+    if RUNNING.load(Ordering::Relaxed) != 0 || !has_pending() {
+        return;
+    }
+    let worker = WORKER.load(Ordering::Acquire);
+    irq::local_irq_enable();
+    run(if worker { MAX_WORK_PER_IRQ } else { usize::MAX });
+    irq::local_irq_disable();
+    if worker && has_pending() {
+        WORKER_WAIT.wake_up();
+    }
+}
+
+/// Returns true while this CPU is running deferred work
+pub fn in_softirq() -> bool {
+    RUNNING.load(Ordering::Relaxed) == smp::this_cpu() + 1
+}
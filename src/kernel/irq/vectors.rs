@@ -0,0 +1,416 @@
+//! Exception vector table
+//!
+//! The 16 vectors of `VBAR_EL1` (synchronous, IRQ, FIQ and SError exceptions, taken from the
+//! current EL on `SP_EL0` or `SP_ELx`, or from a lower EL in AArch64 or AArch32 state) and the
+//! code saving and restoring the interrupted state around the Rust handlers. Every vector pushes
+//! `x29`/`x30`, calls `save_regs`, which builds the rest of a `Regs` frame below them and passes
+//! it in `x0`, then calls or branches to its handler; `exception_exit` restores the frame and
+//! returns with `eret`.
+//!
+//! The assembly is generated with `global_asm!`, its frame offsets taken from `Regs` itself
+//! (`offset_of!`) and its stack constants from `kstack`, so there is a single definition of
+//! each. `init` installs the table before anything can fault; `self_check` then takes an
+//! exception on purpose and checks that the frame the handler saw, and the registers restored
+//! after it, are the ones the assembly is expected to produce.
+//!
+//! ## Design
+//!
+//! - The table is 2 KiB aligned, as `VBAR_EL1` requires, in a section of its own; the code
+//!   reached from the vectors follows it.
+//! - The synchronous `SP_ELx` vector checks the stack pointer before pushing anything, and moves
+//!   to `overflow_stack` if it is in or close to a guard area (see `kstack`).
+//! - On an exception from EL0, `save_regs` also saves the task's FP/SIMD registers if it has
+//!   any (see `sched::fpsimd`), and `exception_exit` loads them back before returning there.
+//! - Exceptions from AArch32 and on `SP_EL0` at EL1 never happen in a working kernel: they go
+//!   to the `do_bad_*` handlers, which report them and panic.
+//! - The self-check uses a `brk` with an immediate of its own, handled by `handle_self_check`
+//!   before the debugger sees it.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux writes its table in `arch/arm64/kernel/entry.S` with the `kernel_ventry` macro and
+//! generates the frame offsets (`S_X0`, `S_PC`, ...) from `struct pt_regs` with `asm-offsets.c`,
+//! a build step producing a header for the assembly; `const` operands of `global_asm!` give the
+//! same without one. Linux doesn't check the frame at boot.
+
+use core::arch::{asm, global_asm};
+use core::mem::{offset_of, size_of};
+use core::ptr::addr_of;
+
+use super::{Regs, SPSR_M};
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::debug::EC_BRK64;
+use crate::kernel::mm::kstack::{
+    KSTACK_AREA_BIT, KSTACK_MARGIN, KSTACK_SHIFT, OVERFLOW_STACK_SIZE,
+};
+
+/// Alignment of the table required by `VBAR_EL1`
+pub const VECTORS_ALIGN: usize = 2048;
+
+/// Immediate of the `brk` taken by `self_check`
+const SELF_CHECK_IMM: u16 = 0x5e1f;
+
+/// Top half of the values `self_check` loads in the registers, the bottom half is their number
+const SELF_CHECK_TAG: u16 = 0xfa11;
+
+/// Registers loaded with a known value by `self_check`: `x18` is left alone, `x19` and `x29` are
+/// reserved by the compiler, `x28` holds the stack pointer and `x30` the result buffer
+const SELF_CHECK_GPRS: [usize; 26] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 20, 21, 22, 23, 24, 25, 26, 27,
+];
+
+/// `ESR_EL1.ISS` bits holding the `brk` immediate
+const ESR_BRK_IMM: u64 = 0xffff;
+
+/// `SPSR_EL1.M` value of an exception taken from EL1 on `SP_EL1`
+const SPSR_M_EL1H: u64 = 0b0101;
+
+/// Encoding of `brk #0`, the immediate is in bits [20:5]
+const BRK_INSN: u32 = 0xd420_0000;
+
+// `save_regs` and `exception_exit` move x1-x30 in pairs and the frame ends with x29/x30, pushed
+// by the vector before `save_regs` runs
+const _: () = {
+    assert!(offset_of!(Regs, _pad1) == offset_of!(Regs, x0) + 8);
+    assert!(offset_of!(Regs, x30) == offset_of!(Regs, x1) + 29 * 8);
+    assert!(offset_of!(Regs, x29) + 16 == size_of::<Regs>());
+    assert!(offset_of!(Regs, spsr) == offset_of!(Regs, elr) + 8);
+    assert!(offset_of!(Regs, sp_el0) == offset_of!(Regs, esr) + 8);
+    assert!(size_of::<Regs>().is_multiple_of(16));
+};
+
+global_asm!(
+    r#"
+    .pushsection .text.vectors, "ax", %progbits
+
+    // A vector calling `\handler`, then returning from the exception
+    .macro ventry_call handler
+    .balign 128
+    stp x29, x30, [sp, #-16]!
+    bl save_regs
+    bl \handler
+    b exception_exit
+    .endm
+
+    // A vector branching to `\label`, which returns from the exception itself
+    .macro ventry_jump label
+    .balign 128
+    stp x29, x30, [sp, #-16]!
+    bl save_regs
+    b \label
+    .endm
+
+    .global evt
+    .balign 2048
+evt:
+    // Current EL with SP_EL0
+    ventry_call do_bad_sync
+    ventry_call do_bad_irq
+    ventry_call do_bad_fiq
+    ventry_call do_bad_serror
+
+    // Current EL with SP_ELx
+    .balign 128
+    // Nothing can be pushed on an overflowed task stack: check the stack pointer first, without
+    // a free register. sp holds sp + x0 meanwhile, x0 the interrupted sp
+    add sp, sp, x0
+    sub x0, sp, x0
+    tbz x0, #{kstack_area_bit}, 1f
+    sub x0, x0, #{kstack_margin}
+    tbz x0, #{kstack_shift}, kernel_stack_overflow
+    add x0, x0, #{kstack_margin}
+1:  sub x0, sp, x0
+    sub sp, sp, x0
+    stp x29, x30, [sp, #-16]!
+    bl save_regs
+    b sync_handler
+    ventry_jump irq_handler
+    ventry_call do_fiq
+    ventry_call do_serror
+
+    // Lower EL in AArch64 state
+    ventry_jump el0_sync_handler
+    ventry_jump irq_handler
+    ventry_call do_fiq
+    ventry_call do_serror
+
+    // Lower EL in AArch32 state: user tasks never run in it
+    ventry_call do_bad_sync
+    ventry_call do_bad_irq
+    ventry_call do_bad_fiq
+    ventry_call do_bad_serror
+
+    // The code reached from the vectors, outside of the table
+    .org evt + 0x800
+
+    // x0: pointer to the frame
+sync_handler:
+    // do_sync decodes esr_el1 and may update the saved registers (e.g. x0 for syscalls)
+    bl do_sync
+    b exception_exit
+
+    // The stack pointer is within KSTACK_MARGIN of a guard area, or in it
+    // x0: interrupted sp - KSTACK_MARGIN, sp: interrupted sp + interrupted x0
+kernel_stack_overflow:
+    add x0, x0, #{kstack_margin}
+    // Reported in the sp_el0 slot of the frame, the task won't return to EL0 anyway
+    msr sp_el0, x0
+    sub sp, sp, x0
+    // sp holds the interrupted x0 while x0 points to the overflow stack
+    ldr x0, =overflow_stack + {overflow_stack_size}
+    add sp, sp, x0
+    sub x0, sp, x0
+    sub sp, sp, x0
+    stp x29, x30, [sp, #-16]!
+    bl save_regs
+    bl do_kernel_stack_overflow
+    b .
+
+    // x0: pointer to the frame
+el0_sync_handler:
+    // do_el0_sync handles system calls and faults of user tasks
+    bl do_el0_sync
+    b exception_exit
+
+    // x0: pointer to the frame
+irq_handler:
+    // do_irq acknowledges, dispatches and ends every pending interrupt
+    bl do_irq
+exception_exit:
+    ldr x0, [sp, #{spsr}]
+    tst x0, #{spsr_m}
+    b.ne 2f
+    bl fpsimd_restore_user
+2:  ldp x3, x2, [sp, #{elr}]
+    ldr x1, [sp, #{sp_el0}]
+    msr spsr_el1, x2
+    msr elr_el1, x3
+    msr sp_el0, x1
+    ldr x0, [sp, #{x0}]
+    ldp x1, x2, [sp, #{x1}]
+    ldp x3, x4, [sp, #({x1} + 16)]
+    ldp x5, x6, [sp, #({x1} + 32)]
+    ldp x7, x8, [sp, #({x1} + 48)]
+    ldp x9, x10, [sp, #({x1} + 64)]
+    ldp x11, x12, [sp, #({x1} + 80)]
+    ldp x13, x14, [sp, #({x1} + 96)]
+    ldp x15, x16, [sp, #({x1} + 112)]
+    ldp x17, x18, [sp, #({x1} + 128)]
+    ldp x19, x20, [sp, #({x1} + 144)]
+    ldp x21, x22, [sp, #({x1} + 160)]
+    ldp x23, x24, [sp, #({x1} + 176)]
+    ldp x25, x26, [sp, #({x1} + 192)]
+    ldp x27, x28, [sp, #({x1} + 208)]
+    ldp x29, x30, [sp, #{x29}]
+    add sp, sp, #{frame_size}
+    eret
+
+    // Enters EL0 for the first time
+    // x0: user entry point, x1: user stack pointer
+    // x2: kernel stack pointer for the exceptions taken from EL0
+    .global ret_to_user
+    .type ret_to_user, %function
+ret_to_user:
+    msr DAIFSet, #0b0010
+    mov sp, x2
+    msr elr_el1, x0
+    msr sp_el0, x1
+    // EL0t with every exception unmasked
+    msr spsr_el1, xzr
+    msr tpidr_el0, xzr
+    // Don't leak kernel values to user space
+    .irp n, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30
+    mov x\n, xzr
+    .endr
+    eret
+    .size ret_to_user, . - ret_to_user
+
+    // Returns to EL0 in a task created by fork, through a copy of the parent's exception frame
+    // x0: the frame, at the top of the task's kernel stack
+    .global ret_from_fork
+    .type ret_from_fork, %function
+ret_from_fork:
+    msr DAIFSet, #0b0010
+    mov sp, x0
+    b exception_exit
+    .size ret_from_fork, . - ret_from_fork
+
+    // Saves the interrupted state below x29/x30, returning the frame in x0
+    .type save_regs, %function
+save_regs:
+    sub sp, sp, #{x29}
+    stp x0, xzr, [sp, #{x0}]
+    stp x1, x2, [sp, #{x1}]
+    stp x3, x4, [sp, #({x1} + 16)]
+    stp x5, x6, [sp, #({x1} + 32)]
+    stp x7, x8, [sp, #({x1} + 48)]
+    stp x9, x10, [sp, #({x1} + 64)]
+    stp x11, x12, [sp, #({x1} + 80)]
+    stp x13, x14, [sp, #({x1} + 96)]
+    stp x15, x16, [sp, #({x1} + 112)]
+    stp x17, x18, [sp, #({x1} + 128)]
+    stp x19, x20, [sp, #({x1} + 144)]
+    stp x21, x22, [sp, #({x1} + 160)]
+    stp x23, x24, [sp, #({x1} + 176)]
+    stp x25, x26, [sp, #({x1} + 192)]
+    stp x27, x28, [sp, #({x1} + 208)]
+    // Printed if the exception can't be handled
+    mrs x1, esr_el1
+    mrs x2, elr_el1
+    mrs x3, spsr_el1
+    // The user stack pointer of a task interrupted in EL0
+    mrs x4, sp_el0
+    stp x1, x4, [sp, #{esr}]
+    stp x2, x3, [sp, #{elr}]
+    mov x0, sp
+    // The user's FP/SIMD registers, before the handler's code can use them
+    tst x3, #{spsr_m}
+    b.eq fpsimd_save_user
+    ret
+    .size save_regs, . - save_regs
+
+    .popsection
+    "#,
+    kstack_area_bit = const KSTACK_AREA_BIT,
+    kstack_shift = const KSTACK_SHIFT,
+    kstack_margin = const KSTACK_MARGIN,
+    overflow_stack_size = const OVERFLOW_STACK_SIZE,
+    elr = const offset_of!(Regs, elr),
+    spsr = const offset_of!(Regs, spsr),
+    spsr_m = const SPSR_M,
+    esr = const offset_of!(Regs, esr),
+    sp_el0 = const offset_of!(Regs, sp_el0),
+    x0 = const offset_of!(Regs, x0),
+    x1 = const offset_of!(Regs, x1),
+    x29 = const offset_of!(Regs, x29),
+    frame_size = const size_of::<Regs>(),
+);
+
+unsafe extern "C" {
+    static evt: u8;
+}
+
+/// Frame saved by the exception taken by `self_check`
+static SELF_CHECK_FRAME: Mutex<Option<Regs>> = Mutex::new(None);
+
+/// Returns the linked address of the table
+pub fn base() -> usize {
+    addr_of!(evt) as usize
+}
+
+/// Points `VBAR_EL1` to the table at `base`, the linked address or an alias of it
+pub fn install(base: usize) {
+    assert!(
+        base.is_multiple_of(VECTORS_ALIGN),
+        "misaligned exception vectors at {:#x}",
+        base
+    );
+    unsafe {
+        asm!(
+            "msr vbar_el1, {base}",
+            "isb",
+            base = in(reg) base,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Installs the table, first thing at boot
+pub fn init() {
+    install(base());
+}
+
+/// Value `self_check` loads in register `xn`
+fn pattern(n: usize) -> u64 {
+    (SELF_CHECK_TAG as u64) << 48 | n as u64
+}
+
+/// Handles the `brk` of `self_check`, returning false for any other breakpoint
+///
+/// Records the frame, then changes every register `self_check` loaded, so that it can tell
+/// whether `exception_exit` restores the frame the handlers see.
+pub fn handle_self_check(regs: &mut Regs) -> bool {
+    if regs.exception_class() != EC_BRK64 || regs.esr & ESR_BRK_IMM != SELF_CHECK_IMM as u64 {
+        return false;
+    }
+    SELF_CHECK_FRAME.lock_irqsafe(|frame| *frame = Some(*regs));
+    for n in SELF_CHECK_GPRS.into_iter().chain([28]) {
+        regs.set_gpr(n, !regs.gpr(n));
+    }
+    regs.elr += 4;
+    true
+}
+
+/// Checks the frame `save_regs` builds and `exception_exit` restores against `Regs`
+///
+/// Takes a `brk` with known values in the registers. Panics if the handler didn't see them in
+/// the fields of `Regs` they belong to, or if the changes it made aren't the values the
+/// registers hold afterwards.
+pub fn self_check() {
+    let mut after = [0u64; 29];
+    let sp_el0: u64;
+    unsafe {
+        asm!(
+            ".irp n, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 20, 21, 22, 23, 24, 25, 26, 27",
+            "mov x\\n, #\\n",
+            "movk x\\n, #{tag}, lsl #48",
+            ".endr",
+            "mov x28, sp",
+            "brk #{imm}",
+            ".irp n, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 20, 21, 22, 23, 24, 25, 26, 27, 28",
+            "str x\\n, [x30, #(8 * \\n)]",
+            ".endr",
+            tag = const SELF_CHECK_TAG,
+            imm = const SELF_CHECK_IMM,
+            in("x30") after.as_mut_ptr(),
+            clobber_abi("C"),
+            out("x20") _,
+            out("x21") _,
+            out("x22") _,
+            out("x23") _,
+            out("x24") _,
+            out("x25") _,
+            out("x26") _,
+            out("x27") _,
+            out("x28") _,
+        );
+        asm!("mrs {}, sp_el0", out(reg) sp_el0, options(nostack, nomem, preserves_flags));
+    }
+    let Some(frame) = SELF_CHECK_FRAME.lock_irqsafe(|frame| frame.take()) else {
+        panic!("vectors: the self-check exception wasn't handled");
+    };
+
+    for n in SELF_CHECK_GPRS {
+        if frame.gpr(n) != pattern(n) {
+            panic!("vectors: x{} saved as {:#x}", n, frame.gpr(n));
+        }
+        if after[n] != !pattern(n) {
+            panic!("vectors: x{} restored as {:#x}", n, after[n]);
+        }
+    }
+    if frame.sp() != frame.x28 || after[28] != !frame.x28 {
+        panic!(
+            "vectors: frame at {:#x} below sp {:#x}",
+            frame.sp(),
+            frame.x28
+        );
+    }
+    if frame.x30 != after.as_ptr() as u64 {
+        panic!("vectors: x30 saved as {:#x}", frame.x30);
+    }
+    if frame.exception_class() != EC_BRK64
+        || frame.esr & ESR_BRK_IMM != SELF_CHECK_IMM as u64
+        || frame.spsr & 0xf != SPSR_M_EL1H
+        || frame.sp_el0 != sp_el0
+    {
+        panic!(
+            "vectors: esr {:#x}, spsr {:#x}, sp_el0 {:#x} saved",
+            frame.esr, frame.spsr, frame.sp_el0
+        );
+    }
+    // The exception returned right after the `brk`, so `elr` must point to it
+    let insn = unsafe { (frame.elr as *const u32).read_volatile() };
+    if insn != BRK_INSN | (SELF_CHECK_IMM as u32) << 5 {
+        panic!("vectors: elr {:#x} isn't the brk", frame.elr);
+    }
+}
@@ -0,0 +1,210 @@
+//! ELF64 executable parsing and loading
+//!
+//! Only static AArch64 executables (`ET_EXEC`) are supported: no interpreter, no relocations.
+//! Their `PT_LOAD` segments must lie in the user address range (see `mm::addr_space`), so
+//! programs are linked at `USER_START` or above, e.g. with `-Ttext-segment=0x8000000000`.
+//! Every other program header is ignored.
+
+use crate::kernel::mm::addr_space::{self, AddressSpace, MapFlags};
+use crate::kernel::mm::pgtable::{PAGE_MASK, PAGE_SIZE};
+use crate::utilities::convert::{read_le_u16, read_le_u32, read_le_u64};
+
+use super::ExecError;
+
+/// Size of the ELF64 file header
+const EHDR_SIZE: usize = 64;
+/// Size of an ELF64 program header
+const PHDR_SIZE: usize = 56;
+
+const ELFMAG: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_AARCH64: u16 = 183;
+
+const PT_LOAD: u32 = 1;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+/// Reasons an image is rejected
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ElfError {
+    /// The image is smaller than the headers it describes
+    Truncated,
+    /// No ELF magic number
+    BadMagic,
+    /// Not a 64-bit little-endian ELF file of the current version
+    BadFormat,
+    /// Not an executable (e.g. a shared object or a relocatable file)
+    NotExecutable,
+    /// Built for another architecture
+    BadMachine,
+    /// A loadable segment is malformed or outside the user address range
+    BadSegment,
+    /// The entry point is not in an executable segment
+    BadEntry,
+}
+
+/// A program header
+#[derive(Clone, Copy, Debug)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    /// Offset of the segment's data in the image
+    pub offset: usize,
+    /// Address the segment is loaded at
+    pub vaddr: usize,
+    /// Number of bytes taken from the image
+    pub filesz: usize,
+    /// Size in memory, the bytes after `filesz` being zeroed (the BSS)
+    pub memsz: usize,
+}
+
+impl ProgramHeader {
+    /// Page permissions requested by the segment
+    fn map_flags(&self) -> MapFlags {
+        let mut flags = MapFlags::READ;
+        if self.flags & PF_W != 0 {
+            flags = flags.union(MapFlags::WRITE);
+        }
+        if self.flags & PF_X != 0 {
+            flags = flags.union(MapFlags::EXEC);
+        }
+        flags
+    }
+
+    /// Checks that the segment's data is in the image and its pages are in user space
+    fn validate(&self, image_len: usize) -> Result<(), ElfError> {
+        let in_image = self
+            .offset
+            .checked_add(self.filesz)
+            .is_some_and(|end| end <= image_len);
+        if self.filesz > self.memsz
+            || !in_image
+            || !addr_space::is_user_range(self.vaddr, self.memsz)
+            || self.flags & (PF_R | PF_W | PF_X) == 0
+        {
+            return Err(ElfError::BadSegment);
+        }
+        Ok(())
+    }
+}
+
+/// A validated ELF executable
+pub struct Elf<'a> {
+    image: &'a [u8],
+    entry: u64,
+    phoff: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the file header and the program headers of `image`
+    pub fn parse(image: &'a [u8]) -> Result<Self, ElfError> {
+        if image.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if image[..4] != ELFMAG {
+            return Err(ElfError::BadMagic);
+        }
+        if image[4] != ELFCLASS64 || image[5] != ELFDATA2LSB || image[6] != EV_CURRENT {
+            return Err(ElfError::BadFormat);
+        }
+        if read_le_u16(image.as_ptr(), 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_le_u16(image.as_ptr(), 18) != EM_AARCH64 {
+            return Err(ElfError::BadMachine);
+        }
+        if read_le_u16(image.as_ptr(), 54) as usize != PHDR_SIZE {
+            return Err(ElfError::BadFormat);
+        }
+        let elf = Self {
+            image,
+            entry: read_le_u64(image.as_ptr(), 24),
+            phoff: read_le_u64(image.as_ptr(), 32) as usize,
+            phnum: read_le_u16(image.as_ptr(), 56) as usize,
+        };
+        let phdrs_end = elf
+            .phnum
+            .checked_mul(PHDR_SIZE)
+            .and_then(|size| size.checked_add(elf.phoff));
+        if phdrs_end.is_none_or(|end| end > image.len()) {
+            return Err(ElfError::Truncated);
+        }
+
+        let mut entry_ok = false;
+        for phdr in elf.segments() {
+            phdr.validate(image.len())?;
+            let entry = elf.entry as usize;
+            entry_ok |=
+                phdr.flags & PF_X != 0 && entry >= phdr.vaddr && entry - phdr.vaddr < phdr.memsz;
+        }
+        if !entry_ok {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(elf)
+    }
+
+    /// Returns the address execution starts at
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Returns the program headers
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.phnum).map(|i| {
+            // In bounds, checked by `parse`
+            let ptr = self.image.as_ptr();
+            let off = self.phoff + i * PHDR_SIZE;
+            ProgramHeader {
+                kind: read_le_u32(ptr, off),
+                flags: read_le_u32(ptr, off + 4),
+                offset: read_le_u64(ptr, off + 8) as usize,
+                vaddr: read_le_u64(ptr, off + 16) as usize,
+                filesz: read_le_u64(ptr, off + 32) as usize,
+                memsz: read_le_u64(ptr, off + 40) as usize,
+            }
+        })
+    }
+
+    /// Returns the `PT_LOAD` program headers
+    pub fn segments(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        self.program_headers().filter(|phdr| phdr.kind == PT_LOAD)
+    }
+
+    /// Maps the loadable segments in `mm` and copies their data, returning the entry point
+    ///
+    /// Pages come zeroed from the frame allocator, which takes care of the BSS. A page shared by
+    /// two segments gets the permissions of both. Each segment is also recorded as an area (see
+    /// `mm::vma`); segments are sorted by address, as the ELF specification requires.
+    pub fn load(&self, mm: &mut AddressSpace) -> Result<u64, ExecError> {
+        let mut mapped_end = 0;
+        for phdr in self.segments() {
+            let flags = phdr.map_flags();
+            let start = phdr.vaddr & PAGE_MASK;
+            let end = (phdr.vaddr + phdr.memsz).next_multiple_of(PAGE_SIZE);
+            // A page shared with the previous segment already belongs to its area
+            let area = start.max(mapped_end);
+            if area < end {
+                mm.map_anon(Some(area), end - area, flags)
+                    .map_err(ExecError::Map)?;
+            }
+            mapped_end = mapped_end.max(end);
+            for page in (start..end).step_by(PAGE_SIZE) {
+                match mm.lookup(page) {
+                    Some((_, old)) => mm.protect(page, old.union(flags)),
+                    None => mm.alloc_page(page, flags).map(|_| ()),
+                }
+                .map_err(ExecError::Map)?;
+            }
+            let data = &self.image[phdr.offset..phdr.offset + phdr.filesz];
+            mm.write(phdr.vaddr, data).map_err(ExecError::Map)?;
+        }
+        addr_space::sync_icache();
+        Ok(self.entry)
+    }
+}
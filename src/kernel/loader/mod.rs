@@ -0,0 +1,115 @@
+//! User program loader
+//!
+//! `exec` turns an executable image in memory into a running user task: a fresh address space
+//! receives the program's segments (see `elf`) and a stack, then a task is created to run it at
+//! EL0. `exec_file` does the same for a file of any mounted filesystem, reading it into
+//! temporary frames first.
+//!
+//! ## Initial Stack
+//!
+//! The stack is an area of `USER_STACK_SIZE` bytes ending at the top of the user address range,
+//! whose pages are mapped on first access (see `mm::vma`). As on
+//! Linux, the stack pointer points to `argc`, followed by the NULL-terminated `argv` and `envp`
+//! arrays and the auxiliary vector; all of them are empty for now, so the program sees `argc` = 0
+//! and an `AT_NULL` auxiliary vector.
+//!
+//! ## Standard Files
+//!
+//! The task starts with the console open as file descriptors 0 (read), 1 and 2 (write), and
+//! becomes the console's foreground task, the one Ctrl-C interrupts.
+
+pub mod elf;
+
+use crate::kernel::console;
+use crate::kernel::fs::vfs::{self, FsError, OpenMode};
+use crate::kernel::mm::addr_space::{AddressSpace, MapFlags, USER_END, VmError};
+use crate::kernel::mm::frame;
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+use crate::kernel::sched::{self, SchedError, TaskId};
+use crate::kernel::signal;
+
+use elf::{Elf, ElfError};
+
+/// Size of the stack of a user task
+pub const USER_STACK_SIZE: usize = 64 * 1024;
+
+/// Highest address of the user stack
+pub const USER_STACK_TOP: usize = USER_END;
+
+/// Size of the initial stack contents: `argc`, `argv[0]`, `envp[0]` and `AT_NULL`
+const INITIAL_STACK_SIZE: usize = 4 * 8;
+
+/// Errors returned by `exec`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExecError {
+    /// The image is not a valid executable
+    Elf(ElfError),
+    /// Setting up the address space failed
+    Map(VmError),
+    /// The task could not be created
+    Sched(SchedError),
+    /// The file could not be read, or the standard files could not be opened
+    Fs(FsError),
+}
+
+/// Creates the user stack area, returning the initial stack pointer
+fn setup_stack(mm: &mut AddressSpace) -> Result<u64, VmError> {
+    let bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    let flags = MapFlags::READ.union(MapFlags::WRITE);
+    mm.map_anon(Some(bottom), USER_STACK_SIZE, flags)?;
+    // The initial contents are all zeroes, as a demand-zero page is
+    Ok((USER_STACK_TOP - INITIAL_STACK_SIZE) as u64)
+}
+
+/// Loads the executable `image` and starts it as a user task called `name`
+///
+/// The image is copied, so it can be freed once this returns.
+pub fn exec(name: &'static str, image: &[u8]) -> Result<TaskId, ExecError> {
+    let elf = Elf::parse(image).map_err(ExecError::Elf)?;
+    let mut mm = AddressSpace::new().map_err(ExecError::Map)?;
+    let entry = elf.load(&mut mm)?;
+    let sp = setup_stack(&mut mm).map_err(ExecError::Map)?;
+    signal::map_sigpage(&mut mm).map_err(ExecError::Map)?;
+
+    // The task must not run before its standard files are in place
+    sched::preempt_disable();
+    let ret = sched::spawn_user(name, mm, entry, sp)
+        .map_err(ExecError::Sched)
+        .and_then(|id| {
+            let stdio = [OpenMode::Read, OpenMode::Write, OpenMode::Write];
+            for (fd, mode) in stdio.into_iter().enumerate() {
+                vfs::install(id, fd, console::CONSOLE.node(), mode).map_err(ExecError::Fs)?;
+            }
+            console::set_foreground(id);
+            Ok(id)
+        });
+    sched::preempt_enable();
+    ret
+}
+
+/// Loads the executable at `path` and starts it as a user task called `name`
+pub fn exec_file(name: &'static str, path: &str) -> Result<TaskId, ExecError> {
+    let node = vfs::lookup(path).map_err(ExecError::Fs)?;
+    let size = node.stat().map_err(ExecError::Fs)?.size;
+    let pages = size.div_ceil(PAGE_SIZE).max(1);
+    let addr = frame::alloc_frames(pages).map_err(|_| ExecError::Map(VmError::NoMemory))?;
+    let image = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, size) };
+
+    let ret = read_file(node, image).and_then(|()| exec(name, image));
+    let _ = frame::free_frames(addr, pages);
+    ret
+}
+
+/// Fills `buf` with the start of the file `node`
+fn read_file(node: vfs::Node, buf: &mut [u8]) -> Result<(), ExecError> {
+    let file = node.inode.open(node.ino).map_err(ExecError::Fs)?;
+    let mut done = 0;
+    while done < buf.len() {
+        match file.read(node.ino, done, &mut buf[done..]) {
+            Ok(0) => return Err(ExecError::Fs(FsError::Io)),
+            Ok(len) => done += len,
+            Err(e) => return Err(ExecError::Fs(e)),
+        }
+    }
+    Ok(())
+}
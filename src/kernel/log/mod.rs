@@ -0,0 +1,85 @@
+//! Kernel log
+//!
+//! Everything printed with `print!`, `println!` and the `pr_*!` macros goes to the active
+//! console and is also kept in `ringbuf`, a fixed-size buffer of the latest lines with their
+//! sequence number, time and level. The log is read back with the shell's `dmesg` and the
+//! `syslog` system call, and replayed on a console that takes over from another one, so the
+//! messages printed before a console was ready are not lost.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is the `printk` ring buffer (`kernel/printk/printk_ringbuffer.c`) in a simpler form: one
+//! lock instead of lockless reservations, and the levels of `pr_err`, `pr_warn` and `pr_info`
+//! only. Records are formatted as the `syslog` system call (and `dmesg -r`) returns them:
+//! `<level>[seconds.micros] text`.
+
+pub mod ringbuf;
+
+use core::fmt;
+
+use crate::kernel::time::clocksource;
+
+use ringbuf::Record;
+
+/// Severity of a kernel message, see `console`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Error,
+    Warning,
+    Info,
+}
+
+impl LogLevel {
+    /// Returns the Linux `KERN_*` number of the level
+    pub fn syslog(self) -> u8 {
+        match self {
+            LogLevel::Error => 3,
+            LogLevel::Warning => 4,
+            LogLevel::Info => 6,
+        }
+    }
+}
+
+/// Longest line `syslog_line` formats
+pub const SYSLOG_LINE_MAX: usize = ringbuf::MAX_RECORD_LEN + 32;
+
+/// Writer filling a byte slice, failing once it is full
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Formats `record` and its `text` as the `syslog` system call returns it, returning the length
+pub fn syslog_line(record: &Record, text: &[u8], buf: &mut [u8; SYSLOG_LINE_MAX]) -> usize {
+    use fmt::Write;
+    let mut w = SliceWriter { buf, len: 0 };
+    // Can't fail: the prefix and the time fit in the 32 spare bytes
+    let _ = write!(w, "<{}>", record.level.syslog());
+    let _ = write_record(&mut w, record, text);
+    w.len
+}
+
+/// Writes the time of `record` and its `text` as a line, as `dmesg` shows it
+pub fn write_record(w: &mut impl fmt::Write, record: &Record, text: &[u8]) -> fmt::Result {
+    let us = clocksource::ticks_to_ns(record.timestamp) / 1000;
+    write!(w, "[{:5}.{:06}] ", us / 1_000_000, us % 1_000_000)?;
+    // A line split at `MAX_RECORD_LEN` may end in the middle of a character
+    let text = match core::str::from_utf8(text) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&text[..e.valid_up_to()]).unwrap_or(""),
+    };
+    w.write_str(text)?;
+    w.write_char('\n')
+}
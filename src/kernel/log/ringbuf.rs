@@ -0,0 +1,217 @@
+//! Kernel log ring buffer
+//!
+//! Printed text is cut into lines, each stored as a record: a descriptor (sequence number,
+//! timestamp, level, position and length) in a ring of `MAX_RECORDS`, and the text in a byte ring
+//! of `TEXT_SIZE`. When either ring is full, the oldest records are dropped to make room.
+//! Sequence numbers keep increasing, so a reader can tell how many records it missed.
+//!
+//! ## Design
+//!
+//! - A record's text is never split: when it doesn't fit before the end of the byte ring, it
+//!   starts again at the beginning, as in Linux's data ring. Positions in the ring are counted
+//!   from boot and only reduced modulo `TEXT_SIZE` to index it, which makes "has this text been
+//!   overwritten" a comparison.
+//! - Escape sequences (colors, cursor movement) and carriage returns are dropped, and lines
+//!   longer than `MAX_RECORD_LEN` are split.
+//! - Timestamps are raw counter values (`CNTPCT_EL0`), converted when the log is read: messages
+//!   printed before the clock source is set up still get their time.
+//! - Writers never wait for the lock: output printed while it is held (by a panic interrupting
+//!   a write, or by a reader) is left out of the log, the console still gets it.
+
+use crate::drivers::timer::arch_timer;
+use crate::ipc::irq_safe_mutex::Mutex;
+
+use super::LogLevel;
+
+/// Size of the text ring, in bytes
+pub const TEXT_SIZE: usize = 32 * 1024;
+
+/// Number of records the descriptor ring holds
+pub const MAX_RECORDS: usize = 1024;
+
+/// Longest record, in bytes; longer lines are split
+pub const MAX_RECORD_LEN: usize = 256;
+
+const ESC: u8 = 0x1b;
+
+/// A record of the log, its text aside
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub seq: u64,
+    /// Counter value when the line was started
+    pub timestamp: u64,
+    pub level: LogLevel,
+    /// Length of the text
+    pub len: usize,
+}
+
+/// Where the writer is in an escape sequence
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// After `ESC`
+    Start,
+    /// After `ESC [`, until the final byte
+    Csi,
+}
+
+/// A record's descriptor
+#[derive(Clone, Copy)]
+struct Desc {
+    record: Record,
+    /// Position of the text, counted from boot
+    pos: usize,
+}
+
+struct RingBuf {
+    text: [u8; TEXT_SIZE],
+    descs: [Desc; MAX_RECORDS],
+    /// Sequence number of the oldest record kept
+    first: u64,
+    /// Sequence number of the next record
+    next: u64,
+    /// Position the text of the next record goes to, counted from boot
+    head: usize,
+    /// Line being printed, not yet a record
+    line: [u8; MAX_RECORD_LEN],
+    line_len: usize,
+    line_level: LogLevel,
+    line_timestamp: u64,
+    escape: Escape,
+}
+
+static LOG: Mutex<RingBuf> = Mutex::new(RingBuf {
+    text: [0; TEXT_SIZE],
+    descs: [Desc {
+        record: Record {
+            seq: 0,
+            timestamp: 0,
+            level: LogLevel::Info,
+            len: 0,
+        },
+        pos: 0,
+    }; MAX_RECORDS],
+    first: 0,
+    next: 0,
+    head: 0,
+    line: [0; MAX_RECORD_LEN],
+    line_len: 0,
+    line_level: LogLevel::Info,
+    line_timestamp: 0,
+    escape: Escape::None,
+});
+
+impl RingBuf {
+    /// Adds the printed byte `c` to the line
+    fn push(&mut self, level: LogLevel, c: u8) {
+        match self.escape {
+            Escape::Start => {
+                self.escape = if c == b'[' { Escape::Csi } else { Escape::None };
+                return;
+            }
+            Escape::Csi => {
+                if (0x40..=0x7e).contains(&c) {
+                    self.escape = Escape::None;
+                }
+                return;
+            }
+            Escape::None => {}
+        }
+        if c == ESC {
+            self.escape = Escape::Start;
+            return;
+        }
+        if c == b'\r' {
+            return;
+        }
+        if self.line_len == 0 {
+            self.line_level = level;
+            self.line_timestamp = arch_timer::get_counter();
+        }
+        match c {
+            b'\n' => self.commit(),
+            c => {
+                self.line[self.line_len] = c;
+                self.line_len += 1;
+                if self.line_len == MAX_RECORD_LEN {
+                    self.commit();
+                }
+            }
+        }
+    }
+
+    /// Turns the line into a record, dropping the oldest ones to make room
+    fn commit(&mut self) {
+        let len = core::mem::take(&mut self.line_len);
+        let mut pos = self.head;
+        if pos % TEXT_SIZE + len > TEXT_SIZE {
+            pos = pos.next_multiple_of(TEXT_SIZE);
+        }
+        self.head = pos + len;
+        if self.next - self.first == MAX_RECORDS as u64 {
+            self.first += 1;
+        }
+        // Records whose text starts before the new end minus the ring size are overwritten
+        let oldest = self.head.saturating_sub(TEXT_SIZE);
+        while self.first < self.next && self.desc(self.first).pos < oldest {
+            self.first += 1;
+        }
+        let start = pos % TEXT_SIZE;
+        self.text[start..start + len].copy_from_slice(&self.line[..len]);
+        let seq = self.next;
+        self.descs[seq as usize % MAX_RECORDS] = Desc {
+            record: Record {
+                seq,
+                timestamp: self.line_timestamp,
+                level: self.line_level,
+                len,
+            },
+            pos,
+        };
+        self.next += 1;
+    }
+
+    fn desc(&self, seq: u64) -> &Desc {
+        &self.descs[seq as usize % MAX_RECORDS]
+    }
+}
+
+/// Adds printed text at `level` to the log
+///
+/// Dropped if the log is busy, see the module documentation.
+pub fn write(level: LogLevel, bytes: &[u8]) {
+    LOG.try_lock_irqsafe(|log| bytes.iter().for_each(|&c| log.push(level, c)));
+}
+
+/// Copies the text of record `seq`, or of the oldest record after it still kept, to `buf`
+///
+/// Returns the record, or `None` if there is none from `seq` on. The text is truncated to the
+/// size of `buf`.
+pub fn read(seq: u64, buf: &mut [u8]) -> Option<Record> {
+    LOG.lock_irqsafe(|log| {
+        let seq = seq.max(log.first);
+        if seq >= log.next {
+            return None;
+        }
+        let desc = *log.desc(seq);
+        let len = desc.record.len.min(buf.len());
+        let start = desc.pos % TEXT_SIZE;
+        buf[..len].copy_from_slice(&log.text[start..start + len]);
+        Some(desc.record)
+    })
+}
+
+/// Returns the sequence number of the oldest record kept
+pub fn first_seq() -> u64 {
+    LOG.lock_irqsafe(|log| log.first)
+}
+
+/// Returns the sequence number the next record will get
+pub fn next_seq() -> u64 {
+    LOG.lock_irqsafe(|log| log.next)
+}
+
+/// Drops every record
+pub fn clear() {
+    LOG.lock_irqsafe(|log| log.first = log.next);
+}
@@ -0,0 +1,528 @@
+//! User address spaces
+//!
+//! Every user task runs on its own translation table, loaded in `TTBR0_EL1` while the task runs.
+//! The first L0 entry, covering the low 512 GiB, is copied from the kernel's identity map: the
+//! kernel keeps running unchanged whichever table is loaded, and EL0 can't reach any of it. User
+//! mappings live above, in `USER_START..USER_END`, with 4 KiB pages and a four-level walk.
+//!
+//! ## Design
+//!
+//! - Tables and the frames backing user pages come from the frame allocator and are accessed
+//!   through their physical address, valid thanks to the identity map.
+//! - Pages mapped with `alloc_page` own their frame (software bit 55 of the descriptor) and free
+//!   it when unmapped or when the address space is dropped. Frames mapped with `map_page` (shared
+//!   memory, ...) belong to someone else and are left alone.
+//! - User entries are non-global and tagged with an ASID allocated per address space, so switching
+//!   tables needs no TLB flush. The ASID's entries are flushed when the address space is dropped.
+//! - `fork` shares the owned frames of both address spaces instead of copying them: writable
+//!   pages become read-only with the copy-on-write bit (software bit 56) in both, and the first
+//!   write to one of them faults and gets a private copy (`break_cow`). Frames count their owners
+//!   (see `frame::share_frame`), so the last one writing just takes the frame back.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is the page table and ASID part of `mm_struct`. Linux maps the kernel through
+//! `TTBR1_EL1`, leaving all of `TTBR0_EL1` to user space. Here only the kernel stacks and the
+//! KASLR alias live in that half (`kspace`): the image is linked in the low identity map, hence
+//! the shared L0 entry and user space starting at 512 GiB.
+
+use core::arch::asm;
+use core::ptr::addr_of;
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::utilities::cache;
+
+use super::bits::*;
+use super::frame;
+use super::meminfo;
+use super::pgtable::{
+    PAGE_MASK, PAGE_SIZE, Pte, mark_page_desc, mark_table_desc, set_block_attrs, set_mair_range,
+    set_next_lvl_table_addr,
+};
+use super::vma::VmaList;
+
+unsafe extern "C" {
+    static __idmap_l0: u8;
+}
+
+/// Lowest user address, the start of the second L0 entry
+pub const USER_START: usize = 1 << 39;
+
+/// End of the user address range, the top of the 48-bit TTBR0 range
+pub const USER_END: usize = 1 << 48;
+
+/// Number of entries in a table
+const ENTRIES: usize = PAGE_SIZE / core::mem::size_of::<Pte>();
+
+/// Valid bit of a descriptor
+const PTE_VALID: Pte = 1 << 0;
+
+/// Output address bits [47:12] of a descriptor
+const PTE_ADDR_MASK: Pte = 0x0000_ffff_ffff_f000;
+
+/// Software-defined bit: the frame was allocated for this mapping and is freed with it
+const PTE_OWNED: Pte = 1 << 55;
+
+/// Software-defined bit: the page is writable but mapped read-only, its frame being shared with
+/// another address space until the next write copies it
+const PTE_COW: Pte = 1 << 56;
+
+/// Access permission bits: read-only at EL0 and EL1
+const PTE_AP_RO: Pte = DESC_AP_RO_ALL;
+
+/// Permission bits of a user page descriptor
+const PTE_PERM_MASK: Pte = DESC_UXN | (0b11 << 6);
+
+/// Number of ASIDs with 8-bit ASIDs (`TCR_EL1.AS` = 0)
+const NUM_ASIDS: usize = 256;
+
+/// Access permissions of a user page
+///
+/// Pages are always readable from EL0 and never executable from EL1.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MapFlags(u8);
+
+impl MapFlags {
+    pub const READ: Self = Self(1 << 0);
+    pub const WRITE: Self = Self(1 << 1);
+    pub const EXEC: Self = Self(1 << 2);
+
+    /// Returns true if every permission in `other` is also in `self`
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the union of both sets
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Descriptor permission bits for these flags
+    fn pte_bits(self) -> Pte {
+        let ap = if self.contains(Self::WRITE) {
+            DESC_AP_RW_ALL
+        } else {
+            DESC_AP_RO_ALL
+        };
+        let xn = if self.contains(Self::EXEC) {
+            0
+        } else {
+            DESC_UXN
+        };
+        ap | xn
+    }
+
+    /// Decodes the permission bits of a descriptor
+    fn from_pte(pte: Pte) -> Self {
+        let mut flags = Self::READ;
+        if pte & (0b11 << 6) == DESC_AP_RW_ALL {
+            flags = flags.union(Self::WRITE);
+        }
+        if pte & DESC_UXN == 0 {
+            flags = flags.union(Self::EXEC);
+        }
+        flags
+    }
+}
+
+/// Errors returned by address space operations
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VmError {
+    /// Not enough free frames for the page or a table
+    NoMemory,
+    /// Every ASID is in use
+    NoAsid,
+    /// The address is outside the user range or not page aligned
+    BadAddress,
+    /// A page is already mapped at this address
+    Exists,
+    /// Nothing is mapped at this address
+    NotMapped,
+    /// No free area slot, or no gap large enough for the area (see `vma`)
+    NoSpace,
+}
+
+/// Allocated ASIDs, ASID 0 being reserved for the kernel's own table
+static ASIDS: Mutex<[u64; NUM_ASIDS / 64]> = Mutex::new([1, 0, 0, 0]);
+
+fn alloc_asid() -> Option<u16> {
+    ASIDS.lock_irqsafe(|asids| {
+        let asid = (1..NUM_ASIDS).find(|&a| asids[a / 64] & (1 << (a % 64)) == 0)?;
+        asids[asid / 64] |= 1 << (asid % 64);
+        Some(asid as u16)
+    })
+}
+
+fn free_asid(asid: u16) {
+    let asid = asid as usize;
+    ASIDS.lock_irqsafe(|asids| asids[asid / 64] &= !(1 << (asid % 64)));
+}
+
+/// Returns true if `[addr, addr + len)` lies in the user address range
+pub fn is_user_range(addr: usize, len: usize) -> bool {
+    addr >= USER_START && addr.checked_add(len).is_some_and(|end| end <= USER_END)
+}
+
+/// Returns the `TTBR0_EL1` value of the kernel's identity map, used by kernel tasks
+pub fn kernel_ttbr0() -> u64 {
+    addr_of!(__idmap_l0) as u64
+}
+
+/// Loads a translation table in `TTBR0_EL1`, as returned by `ttbr0` or `kernel_ttbr0`
+///
+/// Entries are ASID-tagged, so nothing needs flushing.
+pub fn activate(ttbr0: u64) {
+    unsafe {
+        asm!(
+            "msr ttbr0_el1, {}",
+            "isb",
+            in(reg) ttbr0,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Makes instructions written through the data side visible to instruction fetches
+///
+/// The data cache must have been cleaned first, as `AddressSpace::write` does.
+pub fn sync_icache() {
+    cache::invalidate_icache_all_is();
+}
+
+/// Returns the table at physical address `addr`
+///
+/// # Safety
+/// `addr` must be a table page owned by the caller's address space.
+unsafe fn table_at(addr: usize) -> &'static mut [Pte; ENTRIES] {
+    unsafe { &mut *(addr as *mut [Pte; ENTRIES]) }
+}
+
+/// Index of `va` in its table at `level` (0 to 3)
+fn table_index(va: usize, level: usize) -> usize {
+    (va >> (39 - 9 * level)) & (ENTRIES - 1)
+}
+
+/// The translation table of a user task
+pub struct AddressSpace {
+    /// Physical address of the L0 table
+    l0: usize,
+    asid: u16,
+    /// Valid user ranges, mapped on demand
+    pub vmas: VmaList,
+    /// Number of user pages mapped
+    resident: usize,
+}
+
+impl AddressSpace {
+    /// Creates an address space with no user mappings
+    pub fn new() -> Result<Self, VmError> {
+        let asid = alloc_asid().ok_or(VmError::NoAsid)?;
+        let Ok(l0) = frame::alloc_zeroed_frames(1) else {
+            free_asid(asid);
+            return Err(VmError::NoMemory);
+        };
+        // Share the kernel's identity map
+        unsafe {
+            table_at(l0)[0] = *(kernel_ttbr0() as *const Pte);
+        }
+        Ok(Self {
+            l0,
+            asid,
+            vmas: VmaList::new(),
+            resident: 0,
+        })
+    }
+
+    /// Returns the value to load in `TTBR0_EL1` to run on this address space
+    pub fn ttbr0(&self) -> u64 {
+        self.l0 as u64 | (self.asid as u64) << 48
+    }
+
+    /// Returns the number of user pages mapped, shared ones included
+    pub fn resident_pages(&self) -> usize {
+        self.resident
+    }
+
+    /// Returns the size of the user areas, mapped or not
+    pub fn virtual_size(&self) -> usize {
+        self.vmas.iter().map(|vma| vma.len).sum()
+    }
+
+    /// Returns the L3 entry mapping `va`
+    ///
+    /// Missing intermediate tables are allocated if `alloc` is true, otherwise the lookup fails
+    /// with `NotMapped`.
+    fn walk(&mut self, va: usize, alloc: bool) -> Result<&'static mut Pte, VmError> {
+        if !is_user_range(va, PAGE_SIZE) {
+            return Err(VmError::BadAddress);
+        }
+        let mut table = self.l0;
+        for level in 0..3 {
+            let entry = &mut unsafe { table_at(table) }[table_index(va, level)];
+            if *entry & PTE_VALID == 0 {
+                if !alloc {
+                    return Err(VmError::NotMapped);
+                }
+                let next = frame::alloc_zeroed_frames(1).map_err(|_| VmError::NoMemory)?;
+                mark_table_desc(entry);
+                set_next_lvl_table_addr(entry, next as *const u64);
+            }
+            table = (*entry & PTE_ADDR_MASK) as usize;
+        }
+        Ok(&mut unsafe { table_at(table) }[table_index(va, 3)])
+    }
+
+    /// Fills in the L3 entry for `va`, which must be unused
+    fn set_page(
+        &mut self,
+        va: usize,
+        pa: usize,
+        flags: MapFlags,
+        owned: bool,
+    ) -> Result<(), VmError> {
+        if !va.is_multiple_of(PAGE_SIZE) || !pa.is_multiple_of(PAGE_SIZE) {
+            return Err(VmError::BadAddress);
+        }
+        let entry = self.walk(va, true)?;
+        if *entry & PTE_VALID != 0 {
+            return Err(VmError::Exists);
+        }
+        let mut pte: Pte = 0;
+        mark_page_desc(&mut pte);
+        set_mair_range(&mut pte, MAIR_IDX_NORMAL_WB as u64);
+        set_block_attrs(
+            &mut pte,
+            DESC_AF | DESC_SH_INNER | DESC_NG | DESC_PXN | flags.pte_bits(),
+        );
+        set_next_lvl_table_addr(&mut pte, pa as *const u64);
+        if owned {
+            pte |= PTE_OWNED;
+            meminfo::anon_page_added();
+        }
+        *entry = pte;
+        self.resident += 1;
+        Ok(())
+    }
+
+    /// Maps the frame at `pa` at the user address `va`
+    ///
+    /// The frame stays owned by the caller: it isn't freed when unmapped.
+    pub fn map_page(&mut self, va: usize, pa: usize, flags: MapFlags) -> Result<(), VmError> {
+        self.set_page(va, pa, flags, false)
+    }
+
+    /// Maps a new zeroed frame at the user address `va`, returning its physical address
+    pub fn alloc_page(&mut self, va: usize, flags: MapFlags) -> Result<usize, VmError> {
+        let pa = frame::alloc_zeroed_frames(1).map_err(|_| VmError::NoMemory)?;
+        if let Err(e) = self.set_page(va, pa, flags, true) {
+            let _ = frame::free_frame(pa);
+            return Err(e);
+        }
+        Ok(pa)
+    }
+
+    /// Maps the frame at `pa` at the user address `va`, taking ownership of it
+    ///
+    /// The frame is freed when unmapped, as if `alloc_page` had allocated it.
+    pub fn map_owned_page(&mut self, va: usize, pa: usize, flags: MapFlags) -> Result<(), VmError> {
+        self.set_page(va, pa, flags, true)
+    }
+
+    /// Removes the page mapped at `va`, freeing its frame if `alloc_page` allocated it
+    pub fn unmap_page(&mut self, va: usize) -> Result<(), VmError> {
+        let entry = self.walk(va & PAGE_MASK, false)?;
+        let pte = *entry;
+        if pte & PTE_VALID == 0 {
+            return Err(VmError::NotMapped);
+        }
+        *entry = 0;
+        self.resident -= 1;
+        self.flush_page(va);
+        if pte & PTE_OWNED != 0 {
+            meminfo::put_anon_page((pte & PTE_ADDR_MASK) as usize);
+        }
+        Ok(())
+    }
+
+    /// Changes the permissions of the page mapped at `va`
+    pub fn protect(&mut self, va: usize, flags: MapFlags) -> Result<(), VmError> {
+        let entry = self.walk(va & PAGE_MASK, false)?;
+        if *entry & PTE_VALID == 0 {
+            return Err(VmError::NotMapped);
+        }
+        let mut pte = (*entry & !PTE_PERM_MASK) | flags.pte_bits();
+        if pte & PTE_COW != 0 {
+            // Stays read-only until the next write copies the frame
+            pte |= PTE_AP_RO;
+        }
+        *entry = pte;
+        self.flush_page(va);
+        Ok(())
+    }
+
+    /// Returns the physical address `va` translates to and the permissions of its page
+    pub fn lookup(&mut self, va: usize) -> Option<(usize, MapFlags)> {
+        let pte = *self.walk(va & PAGE_MASK, false).ok()?;
+        if pte & PTE_VALID == 0 {
+            return None;
+        }
+        let pa = (pte & PTE_ADDR_MASK) as usize + (va & !PAGE_MASK);
+        let mut flags = MapFlags::from_pte(pte);
+        if pte & PTE_COW != 0 {
+            flags = flags.union(MapFlags::WRITE);
+        }
+        Some((pa, flags))
+    }
+
+    /// Copies `data` to the user address `va`, whatever the page permissions
+    ///
+    /// Goes through the physical addresses, so the address space doesn't need to be active. The
+    /// data cache is cleaned so the bytes can be executed after `sync_icache`. Copy-on-write pages
+    /// are copied first.
+    pub fn write(&mut self, va: usize, data: &[u8]) -> Result<(), VmError> {
+        let mut done = 0;
+        while done < data.len() {
+            let addr = va + done;
+            match self.break_cow(addr) {
+                Ok(_) | Err(VmError::NotMapped) => {}
+                Err(e) => return Err(e),
+            }
+            let (pa, _) = self.lookup(addr).ok_or(VmError::NotMapped)?;
+            let len = (PAGE_SIZE - (addr & !PAGE_MASK)).min(data.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[done..].as_ptr(), pa as *mut u8, len);
+            }
+            cache::clean_dcache_range_pou(pa, len);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Gives the copy-on-write page at `va` a frame of its own and makes it writable
+    ///
+    /// Returns false if the page isn't copy-on-write, which leaves it unchanged.
+    pub fn break_cow(&mut self, va: usize) -> Result<bool, VmError> {
+        let entry = self.walk(va & PAGE_MASK, false)?;
+        let pte = *entry;
+        if pte & PTE_VALID == 0 {
+            return Err(VmError::NotMapped);
+        }
+        if pte & PTE_COW == 0 {
+            return Ok(false);
+        }
+        let old = (pte & PTE_ADDR_MASK) as usize;
+        let mut new_pte = (pte & !(PTE_COW | PTE_AP_RO)) | DESC_AP_RW_ALL;
+        // The other owners may have dropped the frame meanwhile: no need to copy it then
+        if frame::frame_owners(old) != Ok(1) {
+            let new = frame::alloc_frame().map_err(|_| VmError::NoMemory)?;
+            unsafe { core::ptr::copy_nonoverlapping(old as *const u8, new as *mut u8, PAGE_SIZE) };
+            cache::clean_dcache_range_pou(new, PAGE_SIZE);
+            meminfo::anon_page_added();
+            meminfo::put_anon_page(old);
+            new_pte = (new_pte & !PTE_ADDR_MASK) | new as Pte;
+        }
+        *entry = new_pte;
+        self.flush_page(va);
+        Ok(true)
+    }
+
+    /// Returns a copy of the address space, sharing its pages copy-on-write
+    ///
+    /// Owned pages become read-only in both address spaces, their frames owned by both. Pages
+    /// mapped with `map_page` are mapped at the same place in the copy, and the areas are copied.
+    pub fn fork(&mut self) -> Result<AddressSpace, VmError> {
+        let mut child = AddressSpace::new()?;
+        child.vmas = self.vmas.clone();
+        let mut result = Ok(());
+        self.for_each_page(|va, entry| {
+            if result.is_err() {
+                return;
+            }
+            let mut pte = *entry;
+            if pte & PTE_OWNED != 0 {
+                if frame::share_frame((pte & PTE_ADDR_MASK) as usize).is_err() {
+                    result = Err(VmError::NoMemory);
+                    return;
+                }
+                if pte & (0b11 << 6) == DESC_AP_RW_ALL {
+                    pte = (pte & !(0b11 << 6)) | PTE_AP_RO | PTE_COW;
+                    *entry = pte;
+                }
+            }
+            result = child.walk(va, true).map(|child_entry| *child_entry = pte);
+            if result.is_ok() {
+                child.resident += 1;
+            }
+            if result.is_err() && pte & PTE_OWNED != 0 {
+                let _ = frame::free_frame((pte & PTE_ADDR_MASK) as usize);
+            }
+        });
+        // The parent's pages may have become read-only
+        cache::tlb_flush_asid(self.asid);
+        result.map(|()| child)
+    }
+
+    /// Calls `f` with the address and the L3 entry of every mapped user page
+    fn for_each_page(&mut self, mut f: impl FnMut(usize, &mut Pte)) {
+        let l0 = unsafe { table_at(self.l0) };
+        // Entry 0 is the kernel's
+        for (i0, l0e) in l0.iter().enumerate().skip(1) {
+            if *l0e & PTE_VALID == 0 {
+                continue;
+            }
+            let l1 = unsafe { table_at((*l0e & PTE_ADDR_MASK) as usize) };
+            for (i1, l1e) in l1.iter().enumerate().filter(|(_, e)| **e & PTE_VALID != 0) {
+                let l2 = unsafe { table_at((*l1e & PTE_ADDR_MASK) as usize) };
+                for (i2, l2e) in l2.iter().enumerate().filter(|(_, e)| **e & PTE_VALID != 0) {
+                    let l3 = unsafe { table_at((*l2e & PTE_ADDR_MASK) as usize) };
+                    for (i3, pte) in l3.iter_mut().enumerate() {
+                        if *pte & PTE_VALID != 0 {
+                            f(i0 << 39 | i1 << 30 | i2 << 21 | i3 << 12, pte);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Invalidates the TLB entries for the page at `va`
+    fn flush_page(&self, va: usize) {
+        cache::tlb_flush_page_asid(va, self.asid);
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the tables, the owned frames and the ASID
+    ///
+    /// The address space must not be loaded in `TTBR0_EL1` anymore.
+    fn drop(&mut self) {
+        let l0 = unsafe { table_at(self.l0) };
+        // Entry 0 is the kernel's
+        for &l0e in l0[1..].iter().filter(|e| **e & PTE_VALID != 0) {
+            let l1 = (l0e & PTE_ADDR_MASK) as usize;
+            for &l1e in unsafe { table_at(l1) }
+                .iter()
+                .filter(|e| **e & PTE_VALID != 0)
+            {
+                let l2 = (l1e & PTE_ADDR_MASK) as usize;
+                for &l2e in unsafe { table_at(l2) }
+                    .iter()
+                    .filter(|e| **e & PTE_VALID != 0)
+                {
+                    let l3 = (l2e & PTE_ADDR_MASK) as usize;
+                    for &pte in unsafe { table_at(l3) }.iter() {
+                        if pte & (PTE_VALID | PTE_OWNED) == PTE_VALID | PTE_OWNED {
+                            meminfo::put_anon_page((pte & PTE_ADDR_MASK) as usize);
+                        }
+                    }
+                    let _ = frame::free_frame(l3);
+                }
+                let _ = frame::free_frame(l2);
+            }
+            let _ = frame::free_frame(l1);
+        }
+        let _ = frame::free_frame(self.l0);
+        cache::tlb_flush_asid(self.asid);
+        free_asid(self.asid);
+    }
+}
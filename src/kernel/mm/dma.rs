@@ -0,0 +1,228 @@
+//! DMA memory
+//!
+//! Devices that don't snoop the CPU caches see memory as it is in RAM, not as the CPU sees it.
+//! Drivers have two ways to share buffers with them:
+//!
+//! - `alloc_coherent` hands out physically contiguous memory mapped non-cacheable, for
+//!   descriptor rings and other structures both sides update all along. CPU and device always
+//!   agree on their contents, at the cost of uncached accesses. `alloc_coherent_aligned` also
+//!   aligns the physical address, for devices wanting their tables aligned to their size. Both
+//!   take their frames from the DMA32 zone, which every device reaches.
+//! - Ordinary (cacheable) memory can be used for data buffers if the driver hands it over
+//!   explicitly: `sync_for_device` before the device accesses it (cleaning dirty lines to RAM),
+//!   `sync_for_cpu` before the CPU reads what the device wrote (invalidating stale lines).
+//!
+//! ## Design
+//!
+//! - Non-cacheable mappings live in a window of the kernel's translation table, one L1 entry
+//!   (1 GiB) at `WINDOW_BASE`, reached from every address space through the shared L0 entry. A
+//!   frame is always mapped at the same offset in the window as in the 1-2 GiB block the frame
+//!   allocator manages, so no virtual address allocator is needed.
+//! - The frames stay mapped cacheable in the identity map. They are cleaned and invalidated
+//!   before being mapped non-cacheable and again when freed, so no cache line of the cacheable
+//!   alias outlives the non-cacheable use.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is `dma_alloc_coherent` and `dma_sync_single_for_{device,cpu}` on arm64, which likewise
+//! remaps the pages non-cacheable for non-coherent devices (but hands out cacheable memory to
+//! coherent ones) and cleans on `for_device`, invalidating on `for_cpu`.
+
+use core::arch::asm;
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::utilities::cache;
+
+use super::addr_space::kernel_ttbr0;
+use super::bits::*;
+use super::frame::{self, AllocFlags};
+use super::pgtable::{
+    PAGE_MASK, PAGE_SIZE, Pte, mark_page_desc, mark_table_desc, set_block_attrs, set_mair_range,
+    set_next_lvl_table_addr, set_table_attrs,
+};
+
+/// L1 entry of the kernel table holding the non-cacheable window
+const WINDOW_L1_INDEX: usize = 256;
+
+/// Start of the non-cacheable window
+const WINDOW_BASE: usize = WINDOW_L1_INDEX * SZ_1G;
+
+/// Entries in a table
+const ENTRIES: usize = PAGE_SIZE / core::mem::size_of::<Pte>();
+
+/// Valid bit and output address of a descriptor
+const PTE_VALID: Pte = 1 << 0;
+const PTE_ADDR_MASK: Pte = 0x0000_ffff_ffff_f000;
+
+/// Who accesses a streaming buffer, from the device's side
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DmaDirection {
+    /// The device reads the buffer
+    ToDevice,
+    /// The device writes the buffer
+    FromDevice,
+    /// The device reads and writes the buffer
+    Bidirectional,
+}
+
+/// Errors returned by `alloc_coherent` and `free_coherent`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DmaError {
+    /// Not enough free frames for the buffer or a table
+    NoMemory,
+    /// The buffer wasn't returned by `alloc_coherent`
+    BadAddress,
+}
+
+/// A buffer returned by `alloc_coherent`
+#[derive(Clone, Copy, Debug)]
+pub struct DmaBuffer {
+    /// Address the CPU accesses the buffer at, mapped non-cacheable
+    pub vaddr: usize,
+    /// Address to give the device
+    pub paddr: usize,
+    /// Size in bytes, a multiple of the page size
+    pub size: usize,
+}
+
+/// Physical address of the L2 table of the window, 0 until the first allocation
+static WINDOW: Mutex<usize> = Mutex::new(0);
+
+/// Returns the table at physical address `addr`
+///
+/// # Safety
+/// `addr` must be a table of the window, with the `WINDOW` lock held.
+unsafe fn table_at(addr: usize) -> &'static mut [Pte; ENTRIES] {
+    unsafe { &mut *(addr as *mut [Pte; ENTRIES]) }
+}
+
+/// Address in the window of the frame at `paddr`
+fn window_addr(paddr: usize) -> usize {
+    WINDOW_BASE + (paddr & (SZ_1G - 1))
+}
+
+/// Returns the L3 entry mapping `vaddr` in the window whose L2 table is `l2`
+fn walk(l2: usize, vaddr: usize, alloc: bool) -> Result<&'static mut Pte, DmaError> {
+    let entry = &mut unsafe { table_at(l2) }[(vaddr >> 21) & (ENTRIES - 1)];
+    if *entry & PTE_VALID == 0 {
+        if !alloc {
+            return Err(DmaError::BadAddress);
+        }
+        let l3 = frame::alloc_zeroed_frames(1).map_err(|_| DmaError::NoMemory)?;
+        mark_table_desc(entry);
+        set_next_lvl_table_addr(entry, l3 as *const u64);
+    }
+    let l3 = (*entry & PTE_ADDR_MASK) as usize;
+    Ok(&mut unsafe { table_at(l3) }[(vaddr >> 12) & (ENTRIES - 1)])
+}
+
+/// Returns the L2 table of the window, hooking it in the kernel's L1 table on first use
+fn window_l2(window: &mut usize) -> Result<usize, DmaError> {
+    if *window == 0 {
+        let l2 = frame::alloc_zeroed_frames(1).map_err(|_| DmaError::NoMemory)?;
+        let l0 = kernel_ttbr0() as *const Pte;
+        let l1 = unsafe { (*l0 & PTE_ADDR_MASK) as usize };
+        let entry = &mut unsafe { table_at(l1) }[WINDOW_L1_INDEX];
+        mark_table_desc(entry);
+        set_table_attrs(entry, TABLE_UXNTABLE | TABLE_APTABLE0);
+        set_next_lvl_table_addr(entry, l2 as *const u64);
+        *window = l2;
+    }
+    Ok(*window)
+}
+
+/// Removes the window mappings of the `pages` pages at `vaddr`
+fn unmap(l2: usize, vaddr: usize, pages: usize) {
+    for i in 0..pages {
+        let va = vaddr + i * PAGE_SIZE;
+        if let Ok(entry) = walk(l2, va, false) {
+            *entry = 0;
+            cache::tlb_flush_page(va);
+        }
+    }
+}
+
+/// Allocates `size` bytes (rounded up to whole pages) of zeroed, physically contiguous memory,
+/// mapped non-cacheable
+pub fn alloc_coherent(size: usize) -> Result<DmaBuffer, DmaError> {
+    alloc_coherent_aligned(size, PAGE_SIZE)
+}
+
+/// Same as `alloc_coherent`, with the physical address aligned to `align` bytes (a power of two,
+/// at least a page)
+pub fn alloc_coherent_aligned(size: usize, align: usize) -> Result<DmaBuffer, DmaError> {
+    let pages = size.max(1).div_ceil(PAGE_SIZE);
+    let flags = AllocFlags::DMA32.union(AllocFlags::ZERO);
+    let paddr = frame::alloc_frames_flags(pages, align.max(PAGE_SIZE) / PAGE_SIZE, flags)
+        .map_err(|_| DmaError::NoMemory)?;
+    let size = pages * PAGE_SIZE;
+    // The zeroes must be in RAM, and no line of the cacheable alias may be written back later
+    cache::clean_invalidate_dcache_range(paddr, size);
+    let vaddr = window_addr(paddr);
+    let mapped = WINDOW.lock_irqsafe(|window| {
+        let l2 = window_l2(window)?;
+        for i in 0..pages {
+            let entry = match walk(l2, vaddr + i * PAGE_SIZE, true) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    unmap(l2, vaddr, i);
+                    return Err(e);
+                }
+            };
+            let mut pte: Pte = 0;
+            mark_page_desc(&mut pte);
+            set_mair_range(&mut pte, MAIR_IDX_NORMAL_NC as u64);
+            set_block_attrs(
+                &mut pte,
+                DESC_AF | DESC_SH_INNER | DESC_AP_RW_EL1 | DESC_UXN | DESC_PXN,
+            );
+            set_next_lvl_table_addr(&mut pte, (paddr + i * PAGE_SIZE) as *const u64);
+            *entry = pte;
+        }
+        unsafe { asm!("dsb ishst", "isb", options(nostack, preserves_flags)) };
+        Ok(())
+    });
+    if let Err(e) = mapped {
+        let _ = frame::free_frames(paddr, pages);
+        return Err(e);
+    }
+    Ok(DmaBuffer { vaddr, paddr, size })
+}
+
+/// Unmaps and frees a buffer returned by `alloc_coherent`
+pub fn free_coherent(buf: DmaBuffer) -> Result<(), DmaError> {
+    if buf.vaddr != window_addr(buf.paddr) || buf.size & !PAGE_MASK != 0 {
+        return Err(DmaError::BadAddress);
+    }
+    let pages = buf.size / PAGE_SIZE;
+    WINDOW.lock_irqsafe(|window| {
+        if *window == 0 {
+            return Err(DmaError::BadAddress);
+        }
+        unmap(*window, buf.vaddr, pages);
+        Ok(())
+    })?;
+    // Lines speculatively loaded through the cacheable alias are stale
+    cache::clean_invalidate_dcache_range(buf.paddr, buf.size);
+    frame::free_frames(buf.paddr, pages).map_err(|_| DmaError::BadAddress)
+}
+
+/// Hands `[addr, addr + len)` of cacheable memory over to the device
+///
+/// Dirty lines are written back, so the device reads what the CPU wrote. This is done whatever
+/// the direction: a dirty line evicted later would overwrite what the device wrote.
+pub fn sync_for_device(addr: usize, len: usize, _dir: DmaDirection) {
+    if len != 0 {
+        cache::clean_dcache_range(addr, len);
+    }
+}
+
+/// Takes `[addr, addr + len)` of cacheable memory back from the device
+///
+/// Unless the device only read it, the cached lines are dropped, so the CPU reads what the device
+/// wrote.
+pub fn sync_for_cpu(addr: usize, len: usize, dir: DmaDirection) {
+    if len != 0 && dir != DmaDirection::ToDevice {
+        cache::invalidate_dcache_range(addr, len);
+    }
+}
@@ -0,0 +1,535 @@
+//! Physical frame allocator
+//!
+//! Hands out 4 KiB physical page frames from the RAM described by the DTB `/memory` node, with a
+//! buddy system: free memory is kept as blocks of `2^order` frames aligned to their size, one free
+//! list per order. An allocation splits the smallest free block large enough, and a freed block
+//! merges with its buddy (the other half of the block of the next order) whenever that one is free
+//! too, so contiguous runs survive for buffers larger than a page.
+//!
+//! `alloc_pages` takes a block of a given order. `alloc_frames` takes any number of frames: the
+//! block of the next order up is split and the frames past the end go back to the free lists.
+//! `alloc_frames_aligned` adds a physical alignment, for devices and tables that need one.
+//!
+//! ## Zones
+//!
+//! Some devices can't reach all of RAM by DMA: 32-bit masters, or devices behind a bus whose
+//! `dma-ranges` only cover part of it. The frames below 4 GiB, and below the lowest end of the
+//! `dma-ranges` of the DTB (see `dtb::dma_limit`), form `Zone::Dma32`; the rest is `Zone::Normal`.
+//! `AllocFlags::DMA32` restricts an allocation to `Zone::Dma32`. Other allocations are served from
+//! `Zone::Normal` first, keeping the low frames for the devices that need them.
+//!
+//! A frame can have several owners, e.g. a page shared copy-on-write between two address spaces
+//! after `fork`: `share_frame` adds an owner and `free_frame` only frees the frame once the last
+//! one lets go of it.
+//!
+//! ## Design
+//!
+//! - Frames are numbered from an origin aligned to the largest block, so a block aligned in frame
+//!   numbers is aligned in physical addresses too. The frames between the origin and the first
+//!   managed one are never free.
+//! - The free lists are bitmaps, one bit per block of each order, set while the block is free. An
+//!   allocation takes the free block at the lowest address, and the state of a block's buddy is
+//!   a bit test. Nothing is written in the free frames themselves.
+//! - A second bitmap, one bit per frame, tells the allocated frames, to reject bad frees.
+//! - Blocks never straddle the zone boundary: freed blocks only merge within their zone, so each
+//!   zone works as a buddy allocator of its own.
+//!
+//! ## Usable Memory
+//!
+//! Only RAM above the kernel image and boot stack (`__stack_top`) is managed, so the bootloader,
+//! the DTB and the kernel itself are never handed out. The range is also capped to what the
+//! identity mapping covers (the 1-2 GiB block), as frames are accessed through their physical
+//! address.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is the buddy allocator of `mm/page_alloc.c` (`alloc_pages`, `alloc_pages_exact` for the
+//! frames past the end), with `ZONE_DMA32` and `ZONE_NORMAL` only, without migrate types, and
+//! with the whole managed range as the largest order rather than `MAX_PAGE_ORDER`.
+
+use core::ptr::addr_of;
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::board;
+use crate::kernel::dtb;
+use crate::{initcall, println};
+
+use super::bits::SZ_1G;
+use super::pgtable::PAGE_SIZE;
+
+/// Maximum number of frames managed, 1 GiB worth
+const MAX_FRAMES: usize = SZ_1G / PAGE_SIZE;
+
+/// Largest block order, a block of this order spans all the managed frames
+pub const MAX_ORDER: usize = MAX_FRAMES.trailing_zeros() as usize;
+
+/// Bits of the free lists, `MAX_FRAMES >> order` for each order (see `area_bit`)
+const AREA_BITS: usize = 2 * MAX_FRAMES;
+
+/// Highest address covered by the identity mapping for normal memory
+const MAPPED_END: usize = board::mapped_end();
+
+/// Highest end of `Zone::Dma32`, what 32-bit DMA masters reach
+const DMA32_LIMIT: usize = 1 << 32;
+
+/// Number of zones
+pub const ZONES: usize = 2;
+
+unsafe extern "C" {
+    static __stack_top: u8;
+}
+
+/// Errors returned by the frame allocator
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FrameError {
+    /// No free run of frames is large enough
+    NoMemory,
+    /// The address is not a frame managed by the allocator, or is already free
+    BadAddress,
+}
+
+/// Ranges of physical memory with different DMA reachability, from the lowest addresses up
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Zone {
+    /// Frames every device can reach by DMA
+    Dma32,
+    /// Frames above `Zone::Dma32`
+    Normal,
+}
+
+/// Flags of an allocation
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct AllocFlags(u8);
+
+impl AllocFlags {
+    /// No constraint
+    pub const NONE: Self = Self(0);
+    /// Frames from `Zone::Dma32` only
+    pub const DMA32: Self = Self(1 << 0);
+    /// Frames filled with zeroes
+    pub const ZERO: Self = Self(1 << 1);
+
+    /// Returns true if every flag in `other` is also in `self`
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns the union of both sets
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Frame usage returned by `stats`
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    /// Physical address of the first managed frame
+    pub base: usize,
+    /// Number of managed frames
+    pub total: usize,
+    /// Number of free frames
+    pub free: usize,
+    /// Number of free blocks of each order
+    pub free_blocks: [usize; MAX_ORDER + 1],
+    /// Physical address of the end of `Zone::Dma32`
+    pub dma32_end: usize,
+    /// Number of free frames in each zone, indexed by `Zone`
+    pub zone_free: [usize; ZONES],
+}
+
+/// Buddy allocator state
+struct FrameAllocator {
+    /// One bit per frame, set when allocated
+    bitmap: [u64; MAX_FRAMES / 64],
+    /// Free lists, one bit per block of each order, set when the block is free
+    areas: [u64; AREA_BITS / 64],
+    /// Number of free blocks of each order
+    nr_free: [usize; MAX_ORDER + 1],
+    /// Number of owners of each allocated frame besides the first one
+    shares: [u8; MAX_FRAMES],
+    /// Physical address of frame 0, aligned to a block of `MAX_ORDER`
+    origin: usize,
+    /// First managed frame
+    first: usize,
+    /// Frame after the last managed one
+    end: usize,
+    /// First frame of `Zone::Normal`
+    dma32_end: usize,
+    /// Number of free frames in each zone
+    free: [usize; ZONES],
+}
+
+/// Returns the bit of `areas` telling whether the block of `order` at `frame` is free
+const fn area_bit(order: usize, frame: usize) -> usize {
+    AREA_BITS - (AREA_BITS >> order) + (frame >> order)
+}
+
+impl FrameAllocator {
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        if used {
+            self.bitmap[frame / 64] |= 1 << (frame % 64);
+        } else {
+            self.bitmap[frame / 64] &= !(1 << (frame % 64));
+        }
+    }
+
+    fn is_free_block(&self, frame: usize, order: usize) -> bool {
+        let bit = area_bit(order, frame);
+        self.areas[bit / 64] & (1 << (bit % 64)) != 0
+    }
+
+    /// Adds the block of `order` at `frame` to its free list, or removes it
+    fn set_free_block(&mut self, frame: usize, order: usize, free: bool) {
+        let bit = area_bit(order, frame);
+        let zone = self.zone(frame) as usize;
+        if free {
+            self.areas[bit / 64] |= 1 << (bit % 64);
+            self.nr_free[order] += 1;
+            self.free[zone] += 1 << order;
+        } else {
+            self.areas[bit / 64] &= !(1 << (bit % 64));
+            self.nr_free[order] -= 1;
+            self.free[zone] -= 1 << order;
+        }
+    }
+
+    fn zone(&self, frame: usize) -> Zone {
+        if frame < self.dma32_end {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    /// Returns true if the block of `order` at `frame` has frames in both zones
+    fn straddles(&self, frame: usize, order: usize) -> bool {
+        frame < self.dma32_end && frame + (1 << order) > self.dma32_end
+    }
+
+    /// Returns the `[from, to)` frames of `zone`
+    fn zone_range(&self, zone: Zone) -> (usize, usize) {
+        match zone {
+            Zone::Dma32 => (0, self.dma32_end),
+            Zone::Normal => (self.dma32_end, self.end),
+        }
+    }
+
+    fn total_free(&self) -> usize {
+        self.free.iter().sum()
+    }
+
+    /// Frees the block of `order` at `frame`, merging it with its buddy as long as that is free
+    /// and in the same zone
+    fn free_block(&mut self, mut frame: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if self.straddles(frame & !(1 << order), order + 1) || !self.is_free_block(buddy, order)
+            {
+                break;
+            }
+            self.set_free_block(buddy, order, false);
+            frame &= !(1 << order);
+            order += 1;
+        }
+        self.set_free_block(frame, order, true);
+    }
+
+    /// Returns the allocated frames `[from, to)` to the free lists
+    ///
+    /// The range is cut into the largest aligned blocks it holds within a zone.
+    fn release(&mut self, from: usize, to: usize) {
+        let mut frame = from;
+        while frame < to {
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER);
+            while frame + (1 << order) > to || self.straddles(frame, order) {
+                order -= 1;
+            }
+            (frame..frame + (1 << order)).for_each(|f| self.set_used(f, false));
+            self.free_block(frame, order);
+            frame += 1 << order;
+        }
+    }
+
+    /// Finds the lowest free block of at least `order` in `zone` whose physical frame number is a
+    /// multiple of `align`, returning its first frame and its order
+    fn find_block(&self, order: usize, align: usize, zone: Zone) -> Option<(usize, usize)> {
+        let origin_pfn = self.origin / PAGE_SIZE;
+        let (from, to) = self.zone_range(zone);
+        (order..=MAX_ORDER)
+            .filter(|&o| self.nr_free[o] > 0)
+            .find_map(|o| {
+                // Blocks don't straddle zones, so their first frame tells their zone
+                let start = area_bit(o, 0);
+                let end = start + to.div_ceil(1 << o);
+                let mut bit = start + from.div_ceil(1 << o);
+                while bit < end {
+                    let word = self.areas[bit / 64] >> (bit % 64);
+                    if word == 0 {
+                        bit = (bit / 64 + 1) * 64;
+                        continue;
+                    }
+                    bit += word.trailing_zeros() as usize;
+                    let frame = (bit - start) << o;
+                    if bit < end && (origin_pfn + frame).is_multiple_of(align) {
+                        return Some((frame, o));
+                    }
+                    bit += 1;
+                }
+                None
+            })
+    }
+
+    /// Takes the free block of `order` at `frame` off its free list and allocates its first
+    /// `2^want` frames, the upper halves split off going back to the lower free lists
+    fn split(&mut self, frame: usize, mut order: usize, want: usize) {
+        self.set_free_block(frame, order, false);
+        while order > want {
+            order -= 1;
+            self.set_free_block(frame + (1 << order), order, true);
+        }
+        (frame..frame + (1 << want)).for_each(|f| self.set_used(f, true));
+    }
+
+    /// Allocates the free frame `frame`, splitting the free block holding it
+    fn carve(&mut self, frame: usize) {
+        let Some(order) = (0..=MAX_ORDER).find(|&o| self.is_free_block(frame & !((1 << o) - 1), o))
+        else {
+            return;
+        };
+        let mut head = frame & !((1 << order) - 1);
+        self.set_free_block(head, order, false);
+        for o in (0..order).rev() {
+            let upper = head + (1 << o);
+            if frame >= upper {
+                self.set_free_block(head, o, true);
+                head = upper;
+            } else {
+                self.set_free_block(upper, o, true);
+            }
+        }
+        self.set_used(frame, true);
+    }
+
+    /// Physical address of `frame`
+    fn addr(&self, frame: usize) -> usize {
+        self.origin + frame * PAGE_SIZE
+    }
+
+    /// Returns the index of the allocated frame at `addr`
+    fn index(&self, addr: usize) -> Result<usize, FrameError> {
+        if addr < self.addr(self.first) || !(addr - self.origin).is_multiple_of(PAGE_SIZE) {
+            return Err(FrameError::BadAddress);
+        }
+        let frame = (addr - self.origin) / PAGE_SIZE;
+        if frame >= self.end || !self.is_used(frame) {
+            return Err(FrameError::BadAddress);
+        }
+        Ok(frame)
+    }
+}
+
+static FRAMES: Mutex<FrameAllocator> = Mutex::new(FrameAllocator {
+    bitmap: [0; MAX_FRAMES / 64],
+    areas: [0; AREA_BITS / 64],
+    nr_free: [0; MAX_ORDER + 1],
+    shares: [0; MAX_FRAMES],
+    origin: 0,
+    first: 0,
+    end: 0,
+    dma32_end: 0,
+    free: [0; ZONES],
+});
+
+/// Sets up the allocator from the DTB `/memory` node
+///
+/// Must run after `dtb::parse_dtb`.
+pub fn init() {
+    let Some((mem_base, mem_size)) = dtb::memory_region() else {
+        println!("frame: no /memory node, no frames available");
+        return;
+    };
+    let kernel_end = addr_of!(__stack_top) as usize;
+    let start = mem_base.max(kernel_end).next_multiple_of(PAGE_SIZE);
+    let end = (mem_base + mem_size).min(MAPPED_END) & !(PAGE_SIZE - 1);
+    let origin = start & !((PAGE_SIZE << MAX_ORDER) - 1);
+    let first = (start - origin) / PAGE_SIZE;
+    let last = (end.saturating_sub(origin) / PAGE_SIZE).clamp(first, MAX_FRAMES);
+    let dma_end = dtb::dma_limit().unwrap_or(usize::MAX).min(DMA32_LIMIT);
+    let dma32_end = (dma_end.saturating_sub(origin) / PAGE_SIZE).clamp(first, last);
+    FRAMES.lock_irqsafe(|frames| {
+        frames.origin = origin;
+        frames.first = first;
+        frames.end = last;
+        frames.dma32_end = dma32_end;
+        frames.release(first, last);
+    });
+    // The firmware may have put the DTB above the kernel (e.g. on the Raspberry Pi)
+    let (dtb, dtb_size) = dtb::blob_region();
+    reserve(dtb, dtb_size);
+    println!(
+        "frame: {:#x}-{:#x}, {} frames, {} in DMA32",
+        start,
+        origin + last * PAGE_SIZE,
+        last - first,
+        dma32_end - first
+    );
+}
+initcall!(Mmu, "frame", init, after = ["idmap"]);
+
+/// Allocates `count` contiguous frames starting at a physical address aligned to `align` frames
+/// (a power of two), from the zones allowed by `flags`
+///
+/// Returns the physical address of the first frame. The frames are zeroed with
+/// `AllocFlags::ZERO` only.
+pub fn alloc_frames_flags(
+    count: usize,
+    align: usize,
+    flags: AllocFlags,
+) -> Result<usize, FrameError> {
+    let zones: &[Zone] = if flags.contains(AllocFlags::DMA32) {
+        &[Zone::Dma32]
+    } else {
+        &[Zone::Normal, Zone::Dma32]
+    };
+    let addr = FRAMES.lock_irqsafe(|frames| {
+        if count == 0 || count > frames.total_free() {
+            return Err(FrameError::NoMemory);
+        }
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return Err(FrameError::NoMemory);
+        }
+        let (first, found) = zones
+            .iter()
+            .find_map(|&zone| frames.find_block(order, align.max(1), zone))
+            .ok_or(FrameError::NoMemory)?;
+        frames.split(first, found, order);
+        // Only `count` frames are needed out of the block
+        frames.release(first + count, first + (1 << order));
+        Ok(frames.addr(first))
+    })?;
+    if flags.contains(AllocFlags::ZERO) {
+        unsafe { core::ptr::write_bytes(addr as *mut u8, 0, count * PAGE_SIZE) };
+    }
+    Ok(addr)
+}
+
+/// Allocates `count` contiguous frames starting at a physical address aligned to `align` frames
+/// (a power of two)
+///
+/// Returns the physical address of the first frame. The frames are not zeroed.
+pub fn alloc_frames_aligned(count: usize, align: usize) -> Result<usize, FrameError> {
+    alloc_frames_flags(count, align, AllocFlags::NONE)
+}
+
+/// Allocates `count` contiguous frames, returning the physical address of the first one
+pub fn alloc_frames(count: usize) -> Result<usize, FrameError> {
+    alloc_frames_aligned(count, 1)
+}
+
+/// Allocates a block of `2^order` frames, aligned to its size
+pub fn alloc_pages(order: usize) -> Result<usize, FrameError> {
+    if order > MAX_ORDER {
+        return Err(FrameError::NoMemory);
+    }
+    alloc_frames_aligned(1 << order, 1 << order)
+}
+
+/// Allocates a single frame
+pub fn alloc_frame() -> Result<usize, FrameError> {
+    alloc_frames(1)
+}
+
+/// Allocates `count` contiguous frames filled with zeroes
+pub fn alloc_zeroed_frames(count: usize) -> Result<usize, FrameError> {
+    alloc_frames_flags(count, 1, AllocFlags::ZERO)
+}
+
+/// Frees `count` frames starting at `addr`, as returned by `alloc_frames`
+///
+/// The frames must have a single owner. Any part of an allocation can be freed on its own.
+pub fn free_frames(addr: usize, count: usize) -> Result<(), FrameError> {
+    FRAMES.lock_irqsafe(|frames| {
+        let first = frames.index(addr)?;
+        if first + count > frames.end || (first..first + count).any(|f| !frames.is_used(f)) {
+            return Err(FrameError::BadAddress);
+        }
+        frames.release(first, first + count);
+        Ok(())
+    })
+}
+
+/// Frees a block returned by `alloc_pages`
+pub fn free_pages(addr: usize, order: usize) -> Result<(), FrameError> {
+    if order > MAX_ORDER {
+        return Err(FrameError::BadAddress);
+    }
+    free_frames(addr, 1 << order)
+}
+
+/// Drops an owner of the frame at `addr`, freeing it if it was the last one
+///
+/// Returns true if the frame was freed.
+pub fn free_frame(addr: usize) -> Result<bool, FrameError> {
+    FRAMES.lock_irqsafe(|frames| {
+        let frame = frames.index(addr)?;
+        if frames.shares[frame] > 0 {
+            frames.shares[frame] -= 1;
+            return Ok(false);
+        }
+        frames.release(frame, frame + 1);
+        Ok(true)
+    })
+}
+
+/// Adds an owner to the allocated frame at `addr`, which `free_frame` then frees once per owner
+pub fn share_frame(addr: usize) -> Result<(), FrameError> {
+    FRAMES.lock_irqsafe(|frames| {
+        let frame = frames.index(addr)?;
+        let shares = &mut frames.shares[frame];
+        *shares = shares.checked_add(1).ok_or(FrameError::NoMemory)?;
+        Ok(())
+    })
+}
+
+/// Returns the number of owners of the allocated frame at `addr`
+pub fn frame_owners(addr: usize) -> Result<usize, FrameError> {
+    FRAMES.lock_irqsafe(|frames| Ok(frames.shares[frames.index(addr)?] as usize + 1))
+}
+
+/// Marks the frames overlapping `[addr, addr + len)` as allocated, for good
+///
+/// Used for memory handed over by the bootloader (e.g. the initrd) that lies in the managed
+/// range. Parts of the range outside it are ignored.
+pub fn reserve(addr: usize, len: usize) {
+    FRAMES.lock_irqsafe(|frames| {
+        let start = addr.max(frames.addr(frames.first));
+        let end = (addr + len).min(frames.addr(frames.end));
+        if start >= end {
+            return;
+        }
+        let first = (start - frames.origin) / PAGE_SIZE;
+        let last = (end - frames.origin).div_ceil(PAGE_SIZE);
+        for f in first..last {
+            if !frames.is_used(f) {
+                frames.carve(f);
+            }
+        }
+    });
+}
+
+/// Returns the frame usage
+pub fn stats() -> FrameStats {
+    FRAMES.lock_irqsafe(|frames| FrameStats {
+        base: frames.addr(frames.first),
+        total: frames.end - frames.first,
+        free: frames.total_free(),
+        free_blocks: frames.nr_free,
+        dma32_end: frames.addr(frames.dma32_end),
+        zone_free: frames.free,
+    })
+}
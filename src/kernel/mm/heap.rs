@@ -0,0 +1,652 @@
+//! Kernel heap
+//!
+//! `kmalloc(size)` returns `size` bytes of kernel memory, `kfree(addr)` gives them back. Small
+//! blocks come from slabs: frames cut into chunks of a size class (32 bytes to 2 KiB, headers
+//! included), kept on a free list per class. Larger blocks take whole frames from the frame
+//! allocator. Addresses are physical, like those of `frame`, and 16-byte aligned.
+//!
+//! Each CPU keeps a magazine per class: a stack of up to `MAGAZINE_SIZE` free chunks it
+//! allocates from and frees to without taking any lock shared with the other CPUs. An empty
+//! magazine is refilled with `MAGAZINE_BATCH` chunks from the shared free lists (the depot), a
+//! full one gives half of its chunks back. Every `REBALANCE_NS`, the magazines a CPU hasn't
+//! used since the previous pass are emptied into the depot, so chunks don't stay stuck on a CPU
+//! that stopped allocating while the others take new frames.
+//!
+//! Every block starts with a 16-byte header giving its class, requested size and the call site
+//! that allocated it, which makes the debugging aids cheap:
+//!
+//! - allocations and frees are counted per call site (`kmalloc` is `#[track_caller]`), so a site
+//!   whose live count keeps growing is leaking;
+//! - the bytes in use and their high-water mark are tracked;
+//! - with `heap_poison=on` on the command line (or `set_poison`), a new block is filled with
+//!   `0xaa` and a freed one with `0x55`: reading uninitialized or freed memory returns a
+//!   recognizable pattern, and a freed block that no longer holds the pattern when it is reused
+//!   was written after being freed, which is reported.
+//!
+//! With `kasan=on`, the heap also keeps the shadow of the address sanitizer (`kasan`) and puts
+//! freed blocks in its quarantine before reusing them.
+//!
+//! The `memstats` shell command prints all of it.
+//!
+//! ## Design
+//!
+//! - Slabs are never given back to the frame allocator: the memory of a class stays available
+//!   to it. The number of frames held is part of the statistics.
+//! - A block is checked on `kfree`: a header without the live magic (a double free, or a pointer
+//!   `kmalloc` didn't return) is reported with `kwarn!` and the block left alone.
+//! - Call sites beyond `MAX_SITES` are counted together under the last entry.
+//! - Counters are kept per CPU and summed when read: a block freed on another CPU than the one
+//!   that allocated it makes the counts of each CPU meaningless alone, not their sum. Only the
+//!   bytes in use and their high-water mark are shared atomics, the peak needing a global view.
+//!   Call sites are registered once in a lock-free table.
+//! - The per-CPU state is still behind a `Mutex`, taken by its own CPU in the common case and by
+//!   the rebalancing and the statistics otherwise: it masks interrupts around the magazine,
+//!   which interrupt handlers allocate from too.
+//! - The header magic is changed atomically by `kfree`, so a block freed twice at once on two
+//!   CPUs is still caught.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `kmalloc` is built on the slab allocator (SLUB) with the same power-of-two caches,
+//! but keeps its metadata outside the objects. Its per-CPU caches are the SLUB per-CPU slabs
+//! and partial lists; the magazine and depot layers come from Bonwick's Vmem paper and
+//! Solaris, and are what Linux's newer `sheaves` add in front of SLUB. `slub_debug=P` poisons
+//! objects with `0x6b` when freed and `0xa5` at the end, `slub_debug=U` records the allocating
+//! call site, and `/proc/slabinfo` shows the caches; `kmemleak` finds leaks by scanning memory
+//! for references rather than by counting.
+
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::dtb;
+use crate::kernel::irq::softirq;
+use crate::kernel::smp::{self, MAX_CPUS};
+use crate::kernel::time::hrtimer;
+use crate::{initcall, kwarn, pr_warn};
+
+use super::frame::{self, FrameError};
+use super::kasan;
+use super::pgtable::PAGE_SIZE;
+
+/// Size of the block header
+const HEADER_SIZE: usize = 16;
+
+/// Chunk sizes of the slab classes, headers included
+const CLASS_SIZES: [usize; 7] = [32, 64, 128, 256, 512, 1024, 2048];
+
+/// Number of slab classes
+const CLASSES: usize = CLASS_SIZES.len();
+
+/// Largest block served from a slab
+pub const MAX_SLAB_SIZE: usize = CLASS_SIZES[CLASSES - 1] - HEADER_SIZE;
+
+/// `Header::class` of a block made of whole frames
+const CLASS_LARGE: u8 = 0xff;
+
+/// Free chunks a magazine holds, at most
+pub const MAGAZINE_SIZE: usize = 16;
+
+/// Chunks moved at once between a magazine and the depot
+pub const MAGAZINE_BATCH: usize = MAGAZINE_SIZE / 2;
+
+/// Period of the rebalancing of the magazines, 1 s
+pub const REBALANCE_NS: u64 = 1_000_000_000;
+
+/// Number of call sites counted separately
+pub const MAX_SITES: usize = 64;
+
+/// Fill of newly allocated blocks when poisoning
+pub const POISON_ALLOC: u8 = 0xaa;
+/// Fill of freed blocks when poisoning
+pub const POISON_FREE: u8 = 0x55;
+
+const MAGIC_LIVE: u32 = 0x6b6d_616c;
+const MAGIC_FREE: u32 = 0x6b66_7265;
+
+/// Errors returned by the heap
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HeapError {
+    /// No frames left for the block
+    NoMemory,
+    /// The size is 0
+    BadSize,
+    /// The address isn't that of a live block
+    BadAddress,
+}
+
+impl From<FrameError> for HeapError {
+    fn from(_: FrameError) -> Self {
+        HeapError::NoMemory
+    }
+}
+
+/// Header of every block, just before the address returned by `kmalloc`
+#[repr(C, align(16))]
+struct Header {
+    magic: AtomicU32,
+    /// Requested size
+    size: u32,
+    /// Index in `SITES`
+    site: u16,
+    /// Index in `CLASS_SIZES`, or `CLASS_LARGE`
+    class: u8,
+    /// The free block was filled with `POISON_FREE`
+    poisoned: bool,
+}
+
+const _: () = assert!(size_of::<Header>() == HEADER_SIZE);
+
+/// Allocation counts of a call site
+#[derive(Clone, Copy, Debug)]
+pub struct SiteStats {
+    pub location: &'static Location<'static>,
+    pub allocs: u64,
+    pub frees: u64,
+    /// Bytes of the blocks allocated here and not freed yet
+    pub live_bytes: usize,
+}
+
+impl SiteStats {
+    /// Blocks allocated here and not freed yet
+    pub fn live(&self) -> u64 {
+        self.allocs - self.frees
+    }
+}
+
+/// Heap usage returned by `stats`
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    /// Bytes requested by the live blocks
+    pub used: usize,
+    /// Highest value of `used` so far
+    pub peak: usize,
+    pub allocs: u64,
+    pub frees: u64,
+    /// Allocations refused for lack of memory
+    pub failures: u64,
+    /// Frames held by the slabs
+    pub slab_frames: usize,
+    /// Frames held by large blocks
+    pub large_frames: usize,
+    /// Free chunks held in the magazines of the CPUs
+    pub cached: usize,
+    /// Freed blocks found modified when reused (use after free)
+    pub corrupted: u64,
+}
+
+/// Call sites, in the order they first allocated
+static SITES: [AtomicPtr<Location<'static>>; MAX_SITES] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_SITES];
+
+/// Counts of a call site on one CPU
+///
+/// A CPU may free more than it allocated: `live_bytes` wraps, the sum over the CPUs doesn't.
+#[derive(Clone, Copy)]
+struct SiteCounts {
+    allocs: u64,
+    frees: u64,
+    live_bytes: usize,
+}
+
+impl SiteCounts {
+    const ZERO: Self = Self {
+        allocs: 0,
+        frees: 0,
+        live_bytes: 0,
+    };
+}
+
+/// Free chunks of a class cached by a CPU
+struct Magazine {
+    chunks: [usize; MAGAZINE_SIZE],
+    len: usize,
+    /// Allocated from or freed to since the last rebalancing
+    used: bool,
+}
+
+/// State of a CPU
+struct Cpu {
+    magazines: [Magazine; CLASSES],
+    sites: [SiteCounts; MAX_SITES],
+    allocs: u64,
+    frees: u64,
+    failures: u64,
+    corrupted: u64,
+}
+
+static CPUS: [Mutex<Cpu>; MAX_CPUS] = [const {
+    Mutex::new(Cpu {
+        magazines: [const {
+            Magazine {
+                chunks: [0; MAGAZINE_SIZE],
+                len: 0,
+                used: false,
+            }
+        }; CLASSES],
+        sites: [SiteCounts::ZERO; MAX_SITES],
+        allocs: 0,
+        frees: 0,
+        failures: 0,
+        corrupted: 0,
+    })
+}; MAX_CPUS];
+
+/// Free chunks shared by the CPUs
+struct Depot {
+    /// First free chunk of each class, 0 if none
+    free_lists: [usize; CLASSES],
+    slab_frames: usize,
+}
+
+static DEPOT: Mutex<Depot> = Mutex::new(Depot {
+    free_lists: [0; CLASSES],
+    slab_frames: 0,
+});
+
+static USED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static LARGE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+static POISON: AtomicBool = AtomicBool::new(false);
+
+/// Returns the header of the block at `addr`
+fn header(addr: usize) -> *mut Header {
+    (addr - HEADER_SIZE) as *mut Header
+}
+
+/// Returns the bytes of a block after the free list link, those a free block is poisoned in
+fn poisoned_part(chunk: usize, class: usize) -> (*mut u8, usize) {
+    let start = chunk + HEADER_SIZE + size_of::<usize>();
+    (
+        start as *mut u8,
+        CLASS_SIZES[class] - HEADER_SIZE - size_of::<usize>(),
+    )
+}
+
+/// Returns the length of the chunk of a block of `size` bytes in `class`, header included
+fn block_len(class: Option<usize>, size: usize) -> usize {
+    match class {
+        Some(class) => CLASS_SIZES[class],
+        None => (size + HEADER_SIZE).div_ceil(PAGE_SIZE) * PAGE_SIZE,
+    }
+}
+
+/// Returns the index of the call site `location` in `SITES`, adding it if new
+fn site(location: &'static Location<'static>) -> usize {
+    for (i, slot) in SITES.iter().enumerate() {
+        let mut current = slot.load(Ordering::Acquire);
+        if current.is_null() {
+            let new = ptr::from_ref(location).cast_mut();
+            match slot.compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return i,
+                // Another CPU took the slot, maybe for the same site
+                Err(other) => current = other,
+            }
+        }
+        let current = unsafe { &*current };
+        if current.file() == location.file() && current.line() == location.line() {
+            return i;
+        }
+    }
+    MAX_SITES - 1
+}
+
+impl Depot {
+    /// Adds a frame cut into chunks to the free list of `class`
+    fn grow(&mut self, class: usize) -> Result<(), HeapError> {
+        let slab = frame::alloc_frame()?;
+        self.slab_frames += 1;
+        kasan::poison(slab, PAGE_SIZE, kasan::SHADOW_REDZONE);
+        for chunk in (slab..slab + PAGE_SIZE).step_by(CLASS_SIZES[class]).rev() {
+            unsafe {
+                (chunk as *mut Header).write(Header {
+                    magic: AtomicU32::new(MAGIC_FREE),
+                    size: 0,
+                    site: 0,
+                    class: class as u8,
+                    poisoned: false,
+                });
+            }
+            self.push(class, chunk);
+        }
+        Ok(())
+    }
+
+    /// Puts the free `chunk` on the free list of `class`
+    fn push(&mut self, class: usize, chunk: usize) {
+        unsafe { ((chunk + HEADER_SIZE) as *mut usize).write(self.free_lists[class]) };
+        self.free_lists[class] = chunk;
+    }
+
+    /// Takes a chunk of `class`, growing the class if it has none
+    fn pop(&mut self, class: usize) -> Result<usize, HeapError> {
+        if self.free_lists[class] == 0 {
+            self.grow(class)?;
+        }
+        let chunk = self.free_lists[class];
+        self.free_lists[class] = unsafe { ((chunk + HEADER_SIZE) as *const usize).read() };
+        Ok(chunk)
+    }
+}
+
+impl Magazine {
+    /// Takes a chunk of `class`, refilling the magazine from the depot if empty
+    fn pop(&mut self, class: usize) -> Result<usize, HeapError> {
+        self.used = true;
+        if self.len == 0 {
+            DEPOT.lock_irqsafe(|depot| {
+                // The first chunk must be there, the others are a bonus
+                self.chunks[0] = depot.pop(class)?;
+                self.len = 1;
+                while self.len < MAGAZINE_BATCH {
+                    let Ok(chunk) = depot.pop(class) else {
+                        break;
+                    };
+                    self.chunks[self.len] = chunk;
+                    self.len += 1;
+                }
+                Ok::<(), HeapError>(())
+            })?;
+        }
+        self.len -= 1;
+        Ok(self.chunks[self.len])
+    }
+
+    /// Puts a free chunk of `class`, moving a batch to the depot if the magazine is full
+    fn push(&mut self, class: usize, chunk: usize) {
+        self.used = true;
+        if self.len == MAGAZINE_SIZE {
+            self.flush(class, MAGAZINE_BATCH);
+        }
+        self.chunks[self.len] = chunk;
+        self.len += 1;
+    }
+
+    /// Moves `count` chunks of `class` to the depot
+    fn flush(&mut self, class: usize, count: usize) {
+        DEPOT.lock_irqsafe(|depot| {
+            for _ in 0..count.min(self.len) {
+                self.len -= 1;
+                depot.push(class, self.chunks[self.len]);
+            }
+        });
+    }
+}
+
+impl Cpu {
+    /// Checks that the free `chunk` of `class` wasn't written to since it was freed
+    fn check_poison(&mut self, chunk: usize, class: usize) {
+        if !unsafe { (*(chunk as *const Header)).poisoned } {
+            return;
+        }
+        let (start, len) = poisoned_part(chunk, class);
+        let bytes = unsafe { core::slice::from_raw_parts(start, len) };
+        if let Some(offset) = bytes.iter().position(|&b| b != POISON_FREE) {
+            self.corrupted += 1;
+            kwarn!(
+                "heap: freed {}-byte block at {:#x} modified at offset {}",
+                CLASS_SIZES[class] - HEADER_SIZE,
+                chunk + HEADER_SIZE,
+                offset + size_of::<usize>()
+            );
+        }
+    }
+
+    /// Gives back the free `chunk`: to the magazine of its class, or its frames to the frame
+    /// allocator
+    fn release(&mut self, chunk: usize) -> Result<(), HeapError> {
+        let header = chunk as *mut Header;
+        let (size, class) = unsafe { ((*header).size as usize, (*header).class) };
+        if class == CLASS_LARGE {
+            let frames = (size + HEADER_SIZE).div_ceil(PAGE_SIZE);
+            LARGE_FRAMES.fetch_sub(frames, Ordering::Relaxed);
+            // The frames leave the heap
+            kasan::unpoison(chunk, frames * PAGE_SIZE);
+            return frame::free_frames(chunk, frames).map_err(|_| HeapError::BadAddress);
+        }
+        let class = class as usize;
+        let poison = POISON.load(Ordering::Relaxed);
+        if poison {
+            let (start, len) = poisoned_part(chunk, class);
+            unsafe { core::ptr::write_bytes(start, POISON_FREE, len) };
+        }
+        unsafe { (*header).poisoned = poison };
+        self.magazines[class].push(class, chunk);
+        Ok(())
+    }
+
+    fn account_alloc(&mut self, site: usize, size: usize) {
+        self.allocs += 1;
+        let used = USED.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK.fetch_max(used, Ordering::Relaxed);
+        let site = &mut self.sites[site];
+        site.allocs += 1;
+        site.live_bytes = site.live_bytes.wrapping_add(size);
+    }
+
+    fn account_free(&mut self, site: usize, size: usize) {
+        self.frees += 1;
+        USED.fetch_sub(size, Ordering::Relaxed);
+        let site = &mut self.sites[site];
+        site.frees += 1;
+        site.live_bytes = site.live_bytes.wrapping_sub(size);
+    }
+}
+
+/// Allocates `size` bytes, returning their address
+///
+/// The memory isn't zeroed (see `kzalloc`); with poisoning on it is filled with `POISON_ALLOC`.
+#[track_caller]
+pub fn kmalloc(size: usize) -> Result<usize, HeapError> {
+    if size == 0 || size > u32::MAX as usize {
+        return Err(HeapError::BadSize);
+    }
+    let site = site(Location::caller());
+    let poison = POISON.load(Ordering::Relaxed);
+    let class = CLASS_SIZES.iter().position(|&c| size + HEADER_SIZE <= c);
+    let chunk = CPUS[smp::this_cpu()].lock_irqsafe(|cpu| {
+        let chunk = match class {
+            Some(class) => cpu.magazines[class]
+                .pop(class)
+                .inspect(|&chunk| cpu.check_poison(chunk, class)),
+            None => {
+                let frames = (size + HEADER_SIZE).div_ceil(PAGE_SIZE);
+                frame::alloc_frames(frames)
+                    .map_err(HeapError::from)
+                    .inspect(|_| {
+                        LARGE_FRAMES.fetch_add(frames, Ordering::Relaxed);
+                    })
+            }
+        };
+        let Ok(chunk) = chunk else {
+            cpu.failures += 1;
+            return chunk;
+        };
+        cpu.account_alloc(site, size);
+        unsafe {
+            (chunk as *mut Header).write(Header {
+                magic: AtomicU32::new(MAGIC_LIVE),
+                size: size as u32,
+                site: site as u16,
+                class: class.map_or(CLASS_LARGE, |c| c as u8),
+                poisoned: false,
+            });
+        }
+        kasan::poison(chunk, block_len(class, size), kasan::SHADOW_REDZONE);
+        kasan::unpoison(chunk + HEADER_SIZE, size);
+        Ok(chunk)
+    })?;
+    let addr = chunk + HEADER_SIZE;
+    if poison {
+        unsafe { core::ptr::write_bytes(addr as *mut u8, POISON_ALLOC, size) };
+    }
+    Ok(addr)
+}
+
+/// Allocates `size` bytes filled with zeroes
+#[track_caller]
+pub fn kzalloc(size: usize) -> Result<usize, HeapError> {
+    let addr = kmalloc(size)?;
+    unsafe { core::ptr::write_bytes(addr as *mut u8, 0, size) };
+    Ok(addr)
+}
+
+/// Allocates an array of `len` copies of `value`, never freed
+///
+/// For tables sized at boot that live as long as the kernel, like the device tree.
+#[track_caller]
+pub fn alloc_array<T: Copy>(len: usize, value: T) -> Result<&'static mut [T], HeapError> {
+    const { assert!(align_of::<T>() <= HEADER_SIZE) };
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    let size = len.checked_mul(size_of::<T>()).ok_or(HeapError::BadSize)?;
+    let addr = kmalloc(size)?;
+    let array = unsafe { core::slice::from_raw_parts_mut(addr as *mut T, len) };
+    array.fill(value);
+    Ok(array)
+}
+
+/// Frees the block at `addr`, as returned by `kmalloc`
+pub fn kfree(addr: usize) -> Result<(), HeapError> {
+    if addr < HEADER_SIZE || !addr.is_multiple_of(HEADER_SIZE) {
+        return Err(HeapError::BadAddress);
+    }
+    let header = header(addr);
+    if let Err(magic) = unsafe { &(*header).magic }.compare_exchange(
+        MAGIC_LIVE,
+        MAGIC_FREE,
+        Ordering::AcqRel,
+        Ordering::Relaxed,
+    ) {
+        let what = if magic == MAGIC_FREE {
+            "double free"
+        } else {
+            "free of an address not allocated"
+        };
+        kwarn!("heap: {} at {:#x}", what, addr);
+        return Err(HeapError::BadAddress);
+    }
+    // The block is ours again
+    let (size, site, class) = unsafe {
+        (
+            (*header).size as usize,
+            (*header).site as usize,
+            (*header).class,
+        )
+    };
+    let chunk = addr - HEADER_SIZE;
+    let class = (class != CLASS_LARGE).then_some(class as usize);
+    CPUS[smp::this_cpu()].lock_irqsafe(|cpu| {
+        cpu.account_free(site, size);
+        if kasan::enabled() {
+            let len = block_len(class, size) - HEADER_SIZE;
+            kasan::quarantine(addr, len, |addr| {
+                // Live blocks when freed, so their frames are ours
+                let _ = cpu.release(addr - HEADER_SIZE);
+            });
+            return Ok(());
+        }
+        cpu.release(chunk)
+    })
+}
+
+/// Empties the magazines unused since the previous call into the depot
+///
+/// Run as deferred work every `REBALANCE_NS`.
+fn rebalance(_arg: usize) {
+    for cpu in &CPUS {
+        cpu.lock_irqsafe(|cpu| {
+            for (class, magazine) in cpu.magazines.iter_mut().enumerate() {
+                if !magazine.used {
+                    magazine.flush(class, MAGAZINE_SIZE);
+                }
+                magazine.used = false;
+            }
+        });
+    }
+}
+
+/// Timer callback: queues the rebalancing and starts the next period
+fn rebalance_timer(_data: usize) {
+    let _ = softirq::schedule_work(rebalance, 0);
+    if hrtimer::start(REBALANCE_NS, rebalance_timer, 0).is_err() {
+        pr_warn!("heap: no timer left, magazines no longer rebalanced");
+    }
+}
+
+/// Turns poisoning on or off
+///
+/// Blocks freed while it was off aren't checked when reused.
+pub fn set_poison(on: bool) {
+    POISON.store(on, Ordering::Relaxed);
+}
+
+/// Returns true if poisoning is on
+pub fn poison() -> bool {
+    POISON.load(Ordering::Relaxed)
+}
+
+/// Returns the heap usage
+pub fn stats() -> HeapStats {
+    let mut stats = HeapStats {
+        used: USED.load(Ordering::Relaxed),
+        peak: PEAK.load(Ordering::Relaxed),
+        allocs: 0,
+        frees: 0,
+        failures: 0,
+        slab_frames: DEPOT.lock_irqsafe(|depot| depot.slab_frames),
+        large_frames: LARGE_FRAMES.load(Ordering::Relaxed),
+        cached: 0,
+        corrupted: 0,
+    };
+    for cpu in &CPUS {
+        cpu.lock_irqsafe(|cpu| {
+            stats.allocs += cpu.allocs;
+            stats.frees += cpu.frees;
+            stats.failures += cpu.failures;
+            stats.corrupted += cpu.corrupted;
+            stats.cached += cpu.magazines.iter().map(|m| m.len).sum::<usize>();
+        });
+    }
+    stats
+}
+
+/// Calls `f` on the counts of every call site, in the order they first allocated
+pub fn for_each_site(mut f: impl FnMut(&SiteStats)) {
+    let mut counts = [SiteCounts::ZERO; MAX_SITES];
+    for cpu in &CPUS {
+        cpu.lock_irqsafe(|cpu| {
+            for (total, site) in counts.iter_mut().zip(&cpu.sites) {
+                total.allocs += site.allocs;
+                total.frees += site.frees;
+                total.live_bytes = total.live_bytes.wrapping_add(site.live_bytes);
+            }
+        });
+    }
+    for (slot, counts) in SITES.iter().zip(&counts) {
+        let location = slot.load(Ordering::Acquire);
+        if location.is_null() {
+            break;
+        }
+        f(&SiteStats {
+            location: unsafe { &*location },
+            allocs: counts.allocs,
+            frees: counts.frees,
+            live_bytes: counts.live_bytes,
+        });
+    }
+}
+
+/// Applies `heap_poison=on` from the command line and starts rebalancing the magazines
+fn init() {
+    if dtb::bootarg("heap_poison") == Some("on") {
+        set_poison(true);
+    }
+    if hrtimer::start(REBALANCE_NS, rebalance_timer, 0).is_err() {
+        pr_warn!("heap: no timer, magazines won't be rebalanced");
+    }
+}
+initcall!(Late, "heap", init, after = ["tick"]);
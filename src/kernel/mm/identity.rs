@@ -1,32 +1,24 @@
 use core::arch::asm;
-use core::ptr::{addr_of, addr_of_mut};
+use core::ptr::addr_of_mut;
 
-use crate::println;
+use crate::kernel::board::{self, Block};
+use crate::{initcall, println};
 
 use super::bits::*;
 use super::pgtable::{
-    L1_SIZE_PER_ENTRY, Pte, mark_block_desc, mark_table_desc, set_block_attrs, set_mair_range,
+    Pte, mark_block_desc, mark_table_desc, set_block_attrs, set_mair_range,
     set_next_lvl_table_addr, set_table_attrs,
 };
 
 unsafe extern "C" {
-    static __kernel_start: u8;
-    static __stack_top: u8;
     static mut __idmap_l0: u8;
     static mut __idmap_l1: u8;
 }
 
 pub fn setup_identity_mapping() {
-    // As kernel is mapped at 0x50000000, and MMIO is at 0x8000000-0x90000000, we use L0 and L1
-    // descriptors, so we cover the entire space by using huge pages
+    // We use L0 and L1 descriptors, so we cover the first GiBs of the address space with huge
+    // pages. Which GiB holds RAM and which MMIO depends on the board (see `board::IDMAP`)
     unsafe {
-        // We will use those symbols to setup pages accordingly
-        let kernel_code_start = addr_of!(__kernel_start) as usize;
-        let kernel_stack_top = addr_of!(__stack_top) as usize;
-        let kernel_addr_range = kernel_stack_top - kernel_code_start;
-        // For now we hardcode mmio range
-        let mmio_addr_range: usize = 0x09000000 - 0x08000000;
-        let pages = (kernel_addr_range + mmio_addr_range) / L1_SIZE_PER_ENTRY;
         let idmap_l0_ptr = addr_of_mut!(__idmap_l0) as *mut u8;
         println!("idmap_l0 addr {:?}", idmap_l0_ptr);
         // 1. Mark the entry as Table Descriptor, it will cover 512 GiB
@@ -37,41 +29,37 @@ pub fn setup_identity_mapping() {
         println!("idmap_l1 addr {:?}", idmap_l1_ptr);
         // 3. Set next level entry
         set_next_lvl_table_addr(idmap_l0_ptr as *mut Pte, idmap_l1_ptr as *const u64);
-        let ranges: [u64; 2] = [MAIR_IDX_DEVICE as u64, MAIR_IDX_NORMAL_WB as u64];
-        let mut j = 0;
-        // We will set up at least 2 ranges, (NGNRNE and CACHEABLE). If we have to use more
-        // than two ranges, the remaining will use CACHEABLE range. When setting up the final
-        // mapping, we will use each configured MAIR range
-        for i in 0..pages.max(2) {
-            let off = (idmap_l1_ptr as *mut Pte).offset(i as isize);
+        for (i, block) in board::IDMAP.iter().enumerate() {
+            let off = (idmap_l1_ptr as *mut Pte).add(i);
             // 0. Clear the descriptor
             *off = 0;
+            if *block == Block::Unmapped {
+                continue;
+            }
             // 1. Mark as block descriptor
             mark_block_desc(off);
-            // 2. Setup MAIR range
-            set_mair_range(off, ranges[j]);
-            // 3. Set attributes
-            if j == 0 {
+            // 2. Setup MAIR range and 3. set attributes
+            if *block == Block::Device {
                 // MMIO desc
+                set_mair_range(off, MAIR_IDX_DEVICE as u64);
                 set_block_attrs(
                     off,
                     DESC_UXN | DESC_PXN | DESC_AF | DESC_SH_NONE | DESC_AP_RW_EL1,
                 );
             } else {
                 // Normal desc
+                set_mair_range(off, MAIR_IDX_NORMAL_WB as u64);
                 set_block_attrs(off, DESC_UXN | DESC_AF | DESC_SH_INNER | DESC_AP_RW_EL1);
             }
             // 4. Set output address (identity map: VA = PA = i * 1 GiB)
             set_next_lvl_table_addr(off, (i * SZ_1G) as *const u64);
-            if j < 1 {
-                j += 1;
-            }
         }
         load_ttbr0(idmap_l0_ptr as *const u64);
     }
     // We can now safely enable MMU
     enable_mmu();
 }
+initcall!(Mmu, "idmap", setup_identity_mapping, after = ["mair"]);
 
 #[inline(always)]
 fn configure_tcr() {
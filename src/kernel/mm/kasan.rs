@@ -0,0 +1,364 @@
+//! Heap address sanitizer
+//!
+//! With `kasan=on` on the command line, every 16-byte granule of the managed memory gets a shadow
+//! byte telling how much of it may be accessed, and the kernel heap keeps it up to date: a block
+//! returned by `kmalloc` is accessible up to its requested size, its header and the slack of its
+//! chunk are a redzone, and a freed block is marked freed. Driver code reaches heap memory
+//! through the checked accessors (`read`, `write`, `copy`, `fill`) or checks an access itself
+//! with `check_read` and `check_write`: touching a redzone or a freed block is reported with the
+//! address of the offending instruction, named from the symbol table, and a backtrace.
+//!
+//! Freed blocks aren't reused at once: they wait in a quarantine of `QUARANTINE_BLOCKS` blocks
+//! and `QUARANTINE_BYTES` bytes at most, filled with `heap::POISON_FREE`. A block leaving the
+//! quarantine that no longer holds the pattern was written after being freed by code that
+//! didn't go through the accessors, which is reported too. The longer a freed block stays
+//! unused, the more likely a stale pointer to it is caught.
+//!
+//! ## Design
+//!
+//! - Shadow values: 0 for a fully accessible granule, 1 to 15 for one whose first bytes only
+//!   are, `SHADOW_REDZONE` and `SHADOW_FREED` otherwise. Memory outside the managed frames, and
+//!   managed memory the heap never touched, reads as accessible: only heap blocks are checked.
+//! - The shadow takes 1/16 of the managed memory, allocated once from the frame allocator when
+//!   enabled. It can't be turned on later: blocks allocated before would have no shadow.
+//! - Reports are rate limited; the offending address is the return address of the accessor,
+//!   read from its frame record, so it points into the caller.
+//! - The checks are explicit calls rather than compiler instrumentation: code that dereferences
+//!   heap pointers directly is only covered by the quarantine check.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is generic KASAN (`CONFIG_KASAN_GENERIC`) without the compiler: Linux instruments every
+//! load and store with `__asan_load*`/`__asan_store*` calls and shadows the whole kernel address
+//! space, using the same granule, encoding and redzone codes (`KASAN_SLAB_REDZONE`,
+//! `KASAN_SLAB_FREE`). Its quarantine (`mm/kasan/quarantine.c`) likewise delays the reuse of
+//! freed objects, sized as a fraction of the memory; SLUB's `slub_debug=P` checks the poison on
+//! reuse like `check_quarantine` here.
+
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ipc::init_cell::InitCell;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::debug::{backtrace, kallsyms};
+use crate::kernel::dtb;
+use crate::kernel::uaccess::Pod;
+use crate::utilities::bug::{RATELIMIT_BURST, RATELIMIT_INTERVAL_NS, RateLimit};
+use crate::{initcall, pr_err, pr_info, pr_warn};
+
+use super::heap::POISON_FREE;
+use super::pgtable::PAGE_SIZE;
+use super::{frame, kaslr};
+
+/// Bytes described by a shadow byte
+pub const GRANULE: usize = 16;
+
+/// Shadow of a heap header or of the slack after a block
+pub const SHADOW_REDZONE: u8 = 0xfc;
+/// Shadow of a freed block
+pub const SHADOW_FREED: u8 = 0xfb;
+
+/// Freed blocks held in the quarantine, at most
+pub const QUARANTINE_BLOCKS: usize = 256;
+/// Bytes of freed blocks held in the quarantine, at most
+pub const QUARANTINE_BYTES: usize = 1 << 20;
+
+/// The shadow of the managed memory
+struct Shadow {
+    /// Physical address of the first managed frame
+    base: usize,
+    /// End of the managed memory
+    end: usize,
+    /// Physical address of the shadow bytes
+    bytes: usize,
+}
+
+static SHADOW: InitCell<Shadow> = InitCell::new();
+
+/// Freed blocks waiting to be reused, oldest first
+struct Quarantine {
+    /// Address and length of each block, from `head` on
+    blocks: [(usize, usize); QUARANTINE_BLOCKS],
+    head: usize,
+    len: usize,
+    bytes: usize,
+}
+
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine {
+    blocks: [(0, 0); QUARANTINE_BLOCKS],
+    head: 0,
+    len: 0,
+    bytes: 0,
+});
+
+/// Bad accesses found, reported or not
+static REPORTS: AtomicU64 = AtomicU64::new(0);
+
+static LIMIT: RateLimit = RateLimit::new(RATELIMIT_INTERVAL_NS, RATELIMIT_BURST);
+
+/// Sanitizer state returned by `stats`
+#[derive(Clone, Copy, Debug)]
+pub struct KasanStats {
+    /// Freed blocks in the quarantine
+    pub quarantined: usize,
+    /// Bytes of the freed blocks in the quarantine
+    pub quarantined_bytes: usize,
+    /// Bad accesses found
+    pub reports: u64,
+}
+
+/// Reads the return address of the calling function from its frame record
+macro_rules! return_address {
+    () => {{
+        let lr: u64;
+        unsafe { asm!("ldr {}, [x29, #8]", out(reg) lr, options(nostack, readonly)) };
+        lr
+    }};
+}
+
+impl Shadow {
+    /// Returns the shadow byte of the granule holding `addr`, `None` outside the managed memory
+    fn byte(&self, addr: usize) -> Option<*mut u8> {
+        (self.base..self.end)
+            .contains(&addr)
+            .then(|| (self.bytes + (addr - self.base) / GRANULE) as *mut u8)
+    }
+
+    /// Sets the shadow of the granules from `start` to `end` to `value`
+    fn set(&self, start: usize, end: usize, value: u8) {
+        let start = start.max(self.base);
+        let end = end.min(self.end);
+        if start >= end {
+            return;
+        }
+        let first = self.bytes + (start - self.base) / GRANULE;
+        let count = (end - start).div_ceil(GRANULE);
+        unsafe { ptr::write_bytes(first as *mut u8, value, count) };
+    }
+
+    /// Returns the first byte from `addr` to `addr + len` that may not be accessed, with its
+    /// shadow
+    fn first_bad(&self, addr: usize, len: usize) -> Option<(usize, u8)> {
+        let end = addr.checked_add(len)?;
+        let mut granule = addr & !(GRANULE - 1);
+        while granule < end {
+            let from = addr.max(granule);
+            let to = end.min(granule + GRANULE);
+            if let Some(byte) = self.byte(granule) {
+                let value = unsafe { *byte };
+                if value != 0 {
+                    let valid = granule + (value as usize).min(GRANULE);
+                    if value as usize >= GRANULE || from >= valid {
+                        return Some((from, value));
+                    }
+                    if to > valid {
+                        return Some((valid, value));
+                    }
+                }
+            }
+            granule += GRANULE;
+        }
+        None
+    }
+}
+
+/// Returns true if the sanitizer is on
+pub fn enabled() -> bool {
+    SHADOW.get().is_some()
+}
+
+/// Marks the `len` bytes at the granule-aligned `addr` with the shadow `value`
+pub(super) fn poison(addr: usize, len: usize, value: u8) {
+    if let Some(shadow) = SHADOW.get() {
+        shadow.set(addr, addr + len, value);
+    }
+}
+
+/// Marks the `size` bytes at the granule-aligned `addr` accessible
+///
+/// The last granule is accessible only up to `size`.
+pub(super) fn unpoison(addr: usize, size: usize) {
+    let Some(shadow) = SHADOW.get() else {
+        return;
+    };
+    let full = size & !(GRANULE - 1);
+    shadow.set(addr, addr + full, 0);
+    if full < size
+        && let Some(byte) = shadow.byte(addr + full)
+    {
+        unsafe { *byte = (size - full) as u8 };
+    }
+}
+
+/// Reports the access of `len` bytes at `addr` by the instruction at `pc`, which touched `bad`
+#[cold]
+fn report(addr: usize, len: usize, write: bool, pc: u64, bad: usize, value: u8) {
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+    if !LIMIT.allow() {
+        return;
+    }
+    let kind = if value == SHADOW_FREED {
+        "use-after-free"
+    } else {
+        "out-of-bounds"
+    };
+    pr_err!(
+        "BUG: KASAN: {} {} of {} bytes at {:#x}",
+        kind,
+        if write { "write" } else { "read" },
+        len,
+        addr
+    );
+    let pc = kaslr::to_link_address(pc);
+    match kallsyms::lookup(pc) {
+        Some(symbol) => pr_err!("  by [<{:#018x}>] {}", pc, symbol),
+        None => pr_err!("  by [<{:#018x}>]", pc),
+    }
+    pr_err!("  first bad byte at {:#x}, shadow {:#04x}", bad, value);
+    backtrace::print_current();
+}
+
+/// Checks an access of `len` bytes at `addr` made by the instruction at `pc`
+fn check(addr: usize, len: usize, write: bool, pc: u64) -> bool {
+    let Some(shadow) = SHADOW.get() else {
+        return true;
+    };
+    match shadow.first_bad(addr, len) {
+        Some((bad, value)) => {
+            report(addr, len, write, pc, bad, value);
+            false
+        }
+        None => true,
+    }
+}
+
+/// Checks that the caller may read `len` bytes at `addr`, reporting it otherwise
+///
+/// Returns true if the access is valid, or the sanitizer off.
+#[inline(never)]
+pub fn check_read(addr: usize, len: usize) -> bool {
+    check(addr, len, false, return_address!())
+}
+
+/// Checks that the caller may write `len` bytes at `addr`, reporting it otherwise
+///
+/// Returns true if the access is valid, or the sanitizer off.
+#[inline(never)]
+pub fn check_write(addr: usize, len: usize) -> bool {
+    check(addr, len, true, return_address!())
+}
+
+/// Reads a `T` at `addr`, checked
+///
+/// A bad access is reported, then made anyway.
+///
+/// # Safety
+/// `addr` must be mapped and aligned for `T`.
+#[inline(never)]
+pub unsafe fn read<T: Pod>(addr: usize) -> T {
+    check(addr, size_of::<T>(), false, return_address!());
+    unsafe { ptr::read(addr as *const T) }
+}
+
+/// Writes `value` at `addr`, checked
+///
+/// # Safety
+/// `addr` must be mapped and aligned for `T`, and not hold memory Rust code borrows.
+#[inline(never)]
+pub unsafe fn write<T: Pod>(addr: usize, value: T) {
+    check(addr, size_of::<T>(), true, return_address!());
+    unsafe { ptr::write(addr as *mut T, value) };
+}
+
+/// Copies `len` bytes from `src` to `dst`, checked
+///
+/// # Safety
+/// Both ranges must be mapped and must not overlap, `dst` not holding memory Rust code borrows.
+#[inline(never)]
+pub unsafe fn copy(dst: usize, src: usize, len: usize) {
+    let pc = return_address!();
+    check(src, len, false, pc);
+    check(dst, len, true, pc);
+    unsafe { ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, len) };
+}
+
+/// Fills `len` bytes at `addr` with `value`, checked
+///
+/// # Safety
+/// The range must be mapped and not hold memory Rust code borrows.
+#[inline(never)]
+pub unsafe fn fill(addr: usize, value: u8, len: usize) {
+    check(addr, len, true, return_address!());
+    unsafe { ptr::write_bytes(addr as *mut u8, value, len) };
+}
+
+/// Reports the freed block of `len` bytes at `addr` if it was modified in the quarantine
+fn check_quarantine(addr: usize, len: usize) {
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    if let Some(offset) = bytes.iter().position(|&b| b != POISON_FREE) {
+        REPORTS.fetch_add(1, Ordering::Relaxed);
+        if LIMIT.allow() {
+            pr_err!(
+                "BUG: KASAN: freed {}-byte block at {:#x} modified at offset {} in quarantine",
+                len,
+                addr,
+                offset
+            );
+        }
+    }
+}
+
+/// Puts the block of `len` bytes at `addr`, just freed, in the quarantine
+///
+/// The blocks pushed out of it to make room are checked and given to `release`, oldest first.
+pub(super) fn quarantine(addr: usize, len: usize, mut release: impl FnMut(usize)) {
+    poison(addr, len, SHADOW_FREED);
+    unsafe { ptr::write_bytes(addr as *mut u8, POISON_FREE, len) };
+    QUARANTINE.lock_irqsafe(|q| {
+        while q.len == QUARANTINE_BLOCKS || (q.len > 0 && q.bytes + len > QUARANTINE_BYTES) {
+            let (old, old_len) = q.blocks[q.head];
+            q.head = (q.head + 1) % QUARANTINE_BLOCKS;
+            q.len -= 1;
+            q.bytes -= old_len;
+            check_quarantine(old, old_len);
+            release(old);
+        }
+        q.blocks[(q.head + q.len) % QUARANTINE_BLOCKS] = (addr, len);
+        q.len += 1;
+        q.bytes += len;
+    });
+}
+
+/// Returns the state of the sanitizer
+pub fn stats() -> KasanStats {
+    let (quarantined, quarantined_bytes) = QUARANTINE.lock_irqsafe(|q| (q.len, q.bytes));
+    KasanStats {
+        quarantined,
+        quarantined_bytes,
+        reports: REPORTS.load(Ordering::Relaxed),
+    }
+}
+
+/// Allocates the shadow if `kasan=on` is on the command line
+fn init() {
+    if dtb::bootarg("kasan") != Some("on") {
+        return;
+    }
+    let frames = frame::stats();
+    let Ok(bytes) = frame::alloc_zeroed_frames(frames.total.div_ceil(GRANULE)) else {
+        pr_warn!("kasan: no memory for the shadow, disabled");
+        return;
+    };
+    let shadow = Shadow {
+        base: frames.base,
+        end: frames.base + frames.total * PAGE_SIZE,
+        bytes,
+    };
+    let _ = SHADOW.set(shadow);
+    pr_info!(
+        "kasan: enabled, {} KiB of shadow",
+        frames.total.div_ceil(GRANULE) * PAGE_SIZE / 1024
+    );
+}
+initcall!(Mmu, "kasan", init, after = ["frame"]);
@@ -0,0 +1,111 @@
+//! Randomized kernel mapping (KASLR)
+//!
+//! With `kaslr=on` on the command line, `init` maps the kernel image a second time in the upper
+//! half of the address space (see `kspace`), at a base chosen at random on every boot, and
+//! points `VBAR_EL1` at the randomized copy of the exception vectors. Exception handlers, and
+//! everything they call, then run from addresses an attacker can't know in advance: a return
+//! address or a function pointer leaked from a handler's frame is useless on the next boot.
+//!
+//! ```text
+//! kaslr: kernel mapped at 0xffff80e86e000000 (offset 0xffff80e81e000000)
+//! ```
+//!
+//! ## Design
+//!
+//! - The base is 2 MiB aligned in the `KIMAGE_REGION` 512 GiB window, with the image kept
+//!   inside one GiB: 18 bits of entropy, from
+//!   `random::get_random_u64` (`RNDR` when available, counter jitter otherwise).
+//! - The randomized mapping has the same per-page permissions as the identity one (see
+//!   `protect`). Both map the same frames, so data is shared between them.
+//! - Code runs at the randomized address only after entering through the vectors: the image is
+//!   linked at a fixed address without relocations, so absolute addresses (function pointers,
+//!   literal pools, the fixup table) still point to the identity mapping, which stays in place.
+//!   `to_link_address` turns an address of the copy back into the linked one, for the fixup
+//!   table and backtraces.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux (`CONFIG_RANDOMIZE_BASE`) builds a position-independent image, applies its
+//! `R_AARCH64_RELATIVE` relocations in `__relocate_kernel` and runs entirely at the random
+//! address, with no identity alias left once boot is over. Here only the exception paths move,
+//! which shows the mechanism without a relocatable build.
+
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::kernel::irq::vectors;
+use crate::kernel::{dtb, random};
+use crate::{initcall, println};
+
+use super::bits::*;
+use super::frame::FrameError;
+use super::kspace;
+use super::pgtable::PAGE_SIZE;
+use super::protect;
+
+unsafe extern "C" {
+    static __kernel_start: u8;
+    static __stack_top: u8;
+}
+
+/// Window the image is placed in: the 512 GiB covered by entry 256 of the `TTBR1_EL1` L0 table
+pub const KIMAGE_REGION: usize = 0xffff_8000_0000_0000;
+
+/// Alignment of the randomized base
+const KIMAGE_ALIGN: usize = 1 << 21;
+
+/// Number of GiB in the window
+const REGION_GIBS: usize = 512;
+
+/// Randomized address minus linked address, 0 while disabled
+static OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Maps the image at a random address and moves the exception vectors there, if the command line
+/// has `kaslr=on`
+///
+/// Must run after `random::init`, `protect::init` and `kspace::init`.
+pub fn init() {
+    if dtb::bootarg("kaslr") != Some("on") {
+        return;
+    }
+    let start = addr_of!(__kernel_start) as usize;
+    let end = addr_of!(__stack_top) as usize;
+    let span = (end - start).next_multiple_of(KIMAGE_ALIGN);
+    let slots = (SZ_1G - span) / KIMAGE_ALIGN + 1;
+    let seed = random::get_random_u64() as usize;
+    let gib = (seed >> 32) % REGION_GIBS;
+    let base = KIMAGE_REGION + gib * SZ_1G + (seed % slots) * KIMAGE_ALIGN;
+    if map_image(start, end, base).is_err() {
+        println!("kaslr: no memory for the tables, disabled");
+        return;
+    }
+    let offset = base.wrapping_sub(start);
+    OFFSET.store(offset, Ordering::Relaxed);
+    vectors::install(vectors::base().wrapping_add(offset));
+    println!("kaslr: kernel mapped at {:#x} (offset {:#x})", base, offset);
+}
+initcall!(Mmu, "kaslr", init, after = ["kspace"]);
+
+/// Maps the image `[start, end)` at `base` in the kernel half
+fn map_image(start: usize, end: usize, base: usize) -> Result<(), FrameError> {
+    for pa in (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+        kspace::map_page(base + (pa - start), pa, protect::section_prot(pa))?;
+    }
+    kspace::sync();
+    Ok(())
+}
+
+/// Returns the randomized address minus the linked address, 0 if KASLR is off
+pub fn offset() -> usize {
+    OFFSET.load(Ordering::Relaxed)
+}
+
+/// Returns the linked address of `addr`, which may be in the randomized copy of the image
+pub fn to_link_address(addr: u64) -> u64 {
+    let offset = offset() as u64;
+    let image = addr_of!(__kernel_start) as u64..addr_of!(__stack_top) as u64;
+    match addr.wrapping_sub(offset) {
+        link if offset != 0 && image.contains(&link) => link,
+        _ => addr,
+    }
+}
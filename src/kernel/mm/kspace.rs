@@ -0,0 +1,165 @@
+//! Kernel half of the address space
+//!
+//! With 48-bit virtual addresses, the addresses whose top 16 bits are set translate through
+//! `TTBR1_EL1` and the others through `TTBR0_EL1`. Each user task loads its own table in
+//! `TTBR0_EL1` when it is switched to (see `addr_space`), while the `TTBR1_EL1` table built here
+//! is loaded once and shared by every task. Kernel-only mappings live in it:
+//!
+//! | Range                                       | Contents                                   |
+//! |---------------------------------------------|--------------------------------------------|
+//! | `KSTACK_AREA` (`0xffff_4000_0000_0000`)     | task stacks and their guards (`kstack`)    |
+//! | `KIMAGE_REGION` (`0xffff_8000_0000_0000`)   | randomized copy of the image (`kaslr`)     |
+//!
+//! The kernel image, the boot stack and MMIO are still reached through the identity map in the
+//! low 512 GiB, which every user table shares as its L0 entry 0: the image is linked and loaded
+//! at its physical address. Linking it in the upper half as well would free the whole lower
+//! half for user space.
+//!
+//! ## Design
+//!
+//! - Mappings are 4 KiB pages, with tables allocated on demand from the frame allocator and never
+//!   freed. Entries are global, so switching `TTBR0_EL1` (and ASIDs) leaves them in the TLB.
+//! - Permissions are the kernel's `protect::Prot`: nothing here is reachable from EL0.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux maps everything kernel-side through `TTBR1_EL1` (`swapper_pg_dir`): the image, the
+//! linear map of RAM, vmalloc and the fixmap, leaving `TTBR0_EL1` to user space alone.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::initcall;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::utilities::cache;
+
+use super::frame::{self, FrameError};
+use super::pgtable::{PAGE_SIZE, Pte, mark_table_desc, set_next_lvl_table_addr};
+use super::protect::{self, Prot};
+
+/// Lowest address translated through `TTBR1_EL1`
+pub const KERNEL_HALF_START: usize = 0xffff_0000_0000_0000;
+
+/// Number of entries in a table
+const ENTRIES: usize = PAGE_SIZE / core::mem::size_of::<Pte>();
+
+/// Valid bit of a descriptor
+const PTE_VALID: Pte = 1 << 0;
+
+/// Output address bits [47:12] of a descriptor
+const PTE_ADDR_MASK: Pte = 0x0000_ffff_ffff_f000;
+
+/// TCR_EL1.EPD1: disables `TTBR1_EL1` walks
+const TCR_EPD1: u64 = 1 << 23;
+/// TCR_EL1.{IRGN1,ORGN1,SH1}: write-back walks, inner shareable, as for `TTBR0_EL1`
+const TCR_TTBR1_WALK: u64 = 0b01 << 24 | 0b01 << 26 | 0b11 << 28;
+
+/// Physical address of the L0 table, 0 before `init`
+static L0_TABLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes table updates
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Returns the table at physical address `addr`
+///
+/// # Safety
+/// `addr` must be a table of the kernel half.
+unsafe fn table_at(addr: usize) -> &'static mut [Pte; ENTRIES] {
+    unsafe { &mut *(addr as *mut [Pte; ENTRIES]) }
+}
+
+/// Index of `va` in its table at `level` (0 to 3)
+fn table_index(va: usize, level: usize) -> usize {
+    (va >> (39 - 9 * level)) & (ENTRIES - 1)
+}
+
+/// Creates the `TTBR1_EL1` table and enables walks from it
+///
+/// Must run after `frame::init` and before any other function of this module.
+pub fn init() {
+    let Ok(l0) = frame::alloc_zeroed_frames(1) else {
+        panic!("kspace: no memory for the kernel table");
+    };
+    L0_TABLE.store(l0, Ordering::Release);
+    // TTBR1 walks were disabled, so no TLB entry can be stale
+    unsafe {
+        asm!(
+            "msr ttbr1_el1, {l0}",
+            "isb",
+            "mrs {tcr}, tcr_el1",
+            "bic {tcr}, {tcr}, {epd1}",
+            "orr {tcr}, {tcr}, {walk}",
+            "msr tcr_el1, {tcr}",
+            "isb",
+            l0 = in(reg) l0,
+            tcr = out(reg) _,
+            epd1 = in(reg) TCR_EPD1,
+            walk = in(reg) TCR_TTBR1_WALK,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+initcall!(Mmu, "kspace", init, after = ["protect"]);
+
+/// Returns the L3 entry mapping `va`, allocating the missing tables if `alloc` is true
+fn walk(va: usize, alloc: bool) -> Result<Option<&'static mut Pte>, FrameError> {
+    let mut table = L0_TABLE.load(Ordering::Acquire);
+    if table == 0 || va < KERNEL_HALF_START {
+        return Ok(None);
+    }
+    for level in 0..3 {
+        let entry = &mut unsafe { table_at(table) }[table_index(va, level)];
+        if *entry & PTE_VALID == 0 {
+            if !alloc {
+                return Ok(None);
+            }
+            let next = frame::alloc_zeroed_frames(1)?;
+            mark_table_desc(entry);
+            set_next_lvl_table_addr(entry, next as *const u64);
+        }
+        table = (*entry & PTE_ADDR_MASK) as usize;
+    }
+    Ok(Some(&mut unsafe { table_at(table) }[table_index(va, 3)]))
+}
+
+/// Maps the page at physical address `pa` at `va` with permissions `prot`
+///
+/// A page already mapped at `va` is replaced.
+pub fn map_page(va: usize, pa: usize, prot: Prot) -> Result<(), FrameError> {
+    LOCK.lock_irqsafe(|_| {
+        let entry = walk(va, true)?.ok_or(FrameError::NoMemory)?;
+        let replaced = *entry & PTE_VALID != 0;
+        *entry = protect::page_desc(pa, prot);
+        if replaced {
+            cache::tlb_flush_page(va);
+        }
+        Ok(())
+    })
+}
+
+/// Removes the page mapped at `va`, returning its physical address
+pub fn unmap_page(va: usize) -> Option<usize> {
+    LOCK.lock_irqsafe(|_| {
+        let entry = walk(va, false).ok()??;
+        let pte = *entry;
+        if pte & PTE_VALID == 0 {
+            return None;
+        }
+        *entry = 0;
+        cache::tlb_flush_page(va);
+        Some((pte & PTE_ADDR_MASK) as usize)
+    })
+}
+
+/// Returns the physical address `va` translates to, if it is mapped
+///
+/// Takes no lock, since tables are never freed: usable from exception handlers.
+pub fn lookup(va: usize) -> Option<usize> {
+    let pte = *walk(va & !(PAGE_SIZE - 1), false).ok()??;
+    (pte & PTE_VALID != 0).then_some((pte & PTE_ADDR_MASK) as usize + va % PAGE_SIZE)
+}
+
+/// Makes new mappings visible to the table walker before they are used
+pub fn sync() {
+    unsafe { asm!("dsb ishst", "isb", options(nostack, preserves_flags)) };
+}
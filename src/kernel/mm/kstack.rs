@@ -0,0 +1,133 @@
+//! Kernel stacks with guard pages
+//!
+//! Spawned tasks run on stacks allocated from the frame allocator and mapped in a dedicated area
+//! of the kernel half of the address space, `KSTACK_AREA` (see `kspace`). Each task slot gets
+//! twice the stack size of virtual space: the stack in the upper half and an unmapped guard area
+//! in the lower half, so running off the bottom of a stack faults instead of silently corrupting
+//! whatever lies below.
+//!
+//! ## Design
+//!
+//! - The stacks are mapped through `TTBR1_EL1`, which stays loaded when a user task's table is
+//!   switched in, so every task's stack is reachable whichever address space is current.
+//! - The exception entry can't push anything on an overflowed stack. The synchronous vector
+//!   checks, without touching memory, whether the interrupted stack pointer lies in the area and
+//!   less than `KSTACK_MARGIN` bytes above a guard, and switches to `overflow_stack` if so (see
+//!   `irq::vectors`). The layout makes this a couple of bit tests: the area is the only place a
+//!   kernel stack pointer can have bit `KSTACK_AREA_BIT` set (the boot stack is in the identity
+//!   map, below 512 GiB), and a guard is the half of a slot with bit `KSTACK_SHIFT` clear.
+//! - The boot stack of task 0 lives in the kernel image and has no guard.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is `CONFIG_VMAP_STACK`: Linux allocates stacks in the vmalloc area, whose guard pages
+//! come from the holes between allocations, and `kernel_ventry` tests the stack pointer the same
+//! way before switching to a per-CPU overflow stack.
+
+use crate::kernel::sched::{MAX_TASKS, TASK_STACK_SIZE, TaskId};
+
+use super::frame::{self, FrameError};
+use super::kspace::{self, KERNEL_HALF_START};
+use super::pgtable::PAGE_SIZE;
+use super::protect::Prot;
+
+/// Bit set in the addresses of the stack area and in no other kernel stack pointer
+pub const KSTACK_AREA_BIT: usize = 46;
+
+/// Start of the stack area
+pub const KSTACK_AREA: usize = KERNEL_HALF_START | 1 << KSTACK_AREA_BIT;
+
+/// log2 of the stack size, the bit telling a stack from its guard
+pub const KSTACK_SHIFT: usize = 14;
+
+/// Size of a task stack, and of the guard below it
+pub const KSTACK_SIZE: usize = 1 << KSTACK_SHIFT;
+
+/// An exception taken with the stack pointer less than this above a guard runs on the overflow
+/// stack: the exception frame alone would not fit
+pub const KSTACK_MARGIN: usize = 1024;
+
+/// Size of the stack the exception handlers switch to on overflow
+pub const OVERFLOW_STACK_SIZE: usize = 8 * 1024;
+
+/// Virtual space taken by a task: its guard, then its stack
+const SLOT_SIZE: usize = 2 * KSTACK_SIZE;
+
+/// Pages of a stack
+const STACK_PAGES: usize = KSTACK_SIZE / PAGE_SIZE;
+
+// The slots don't reach the next area (`kaslr::KIMAGE_REGION`)
+const _: () = assert!(MAX_TASKS * SLOT_SIZE <= 1 << KSTACK_AREA_BIT);
+const _: () = assert!(KSTACK_SIZE == TASK_STACK_SIZE);
+// The vector tests `sp - KSTACK_MARGIN` without crossing into the previous slot's stack
+const _: () = assert!(KSTACK_MARGIN < KSTACK_SIZE);
+
+/// Stack used by the exception handlers once a stack overflow has been detected
+#[repr(C, align(16))]
+pub struct OverflowStack([u8; OVERFLOW_STACK_SIZE]);
+
+#[unsafe(no_mangle)]
+static mut overflow_stack: OverflowStack = OverflowStack([0; OVERFLOW_STACK_SIZE]);
+
+/// Returns the address right above the stack of task `id`, its initial stack pointer
+pub fn top(id: TaskId) -> usize {
+    KSTACK_AREA + (id + 1) * SLOT_SIZE
+}
+
+/// Returns the physical address of the frames backing the stack of task `id`, if mapped
+fn stack_frames(id: TaskId) -> Option<usize> {
+    kspace::lookup(top(id) - KSTACK_SIZE)
+}
+
+/// Backs the stack of task `id` with frames, if not done already
+///
+/// Returns the top of the stack.
+pub fn alloc(id: TaskId) -> Result<usize, FrameError> {
+    let base = top(id) - KSTACK_SIZE;
+    if stack_frames(id).is_none() {
+        let pa = frame::alloc_frames(STACK_PAGES)?;
+        for i in 0..STACK_PAGES {
+            let mapped =
+                kspace::map_page(base + i * PAGE_SIZE, pa + i * PAGE_SIZE, Prot::ReadWrite);
+            if let Err(err) = mapped {
+                for page in 0..i {
+                    kspace::unmap_page(base + page * PAGE_SIZE);
+                }
+                let _ = frame::free_frames(pa, STACK_PAGES);
+                return Err(err);
+            }
+        }
+        kspace::sync();
+    }
+    Ok(top(id))
+}
+
+/// Unmaps the stack of task `id` and frees its frames
+///
+/// The task must not run anymore.
+pub fn free(id: TaskId) {
+    let Some(pa) = stack_frames(id) else {
+        return;
+    };
+    let base = top(id) - KSTACK_SIZE;
+    for i in 0..STACK_PAGES {
+        kspace::unmap_page(base + i * PAGE_SIZE);
+    }
+    let _ = frame::free_frames(pa, STACK_PAGES);
+}
+
+/// Returns the task whose guard area contains `addr`
+pub fn guard_owner(addr: usize) -> Option<TaskId> {
+    let offset = addr.checked_sub(KSTACK_AREA)?;
+    let id = offset / SLOT_SIZE;
+    (id < MAX_TASKS && offset % SLOT_SIZE < KSTACK_SIZE).then_some(id)
+}
+
+/// Returns true if `[addr, addr + len)` lies in a mapped stack
+pub fn is_stack(addr: usize, len: usize) -> bool {
+    let (Some(offset), Some(end)) = (addr.checked_sub(KSTACK_AREA), addr.checked_add(len)) else {
+        return false;
+    };
+    let id = offset / SLOT_SIZE;
+    id < MAX_TASKS && guard_owner(addr).is_none() && end <= top(id) && stack_frames(id).is_some()
+}
@@ -1,5 +1,6 @@
 use core::arch::asm;
 
+use crate::initcall;
 use crate::kernel::mm::bits::{
     MAIR_DEVICE_NGNRNE, MAIR_IDX_DEVICE, MAIR_IDX_NORMAL_NC, MAIR_IDX_NORMAL_WB, MAIR_NORMAL_NC,
     MAIR_NORMAL_WB,
@@ -29,3 +30,4 @@ pub fn setup_mair_ranges() {
     // Normal non-cacheable: outer non-cacheable, inner non-cacheable
     configure_mair_range(MAIR_NORMAL_NC, MAIR_IDX_NORMAL_NC);
 }
+initcall!(Mmu, "mair", setup_mair_ranges);
@@ -0,0 +1,105 @@
+//! Memory usage and page fault statistics
+//!
+//! `meminfo` tells where the managed frames went: free, held by the kernel heap (slabs and large
+//! blocks) or backing user pages. It also returns the counts of the user page faults handled by
+//! `vma::handle_fault`, by kind. The `free` shell command prints it all, and user tasks get the
+//! totals through the `sysinfo` system call.
+//!
+//! ## Design
+//!
+//! - Anonymous pages are the frames user pages own (`AddressSpace::alloc_page`, demand fills,
+//!   copy-on-write copies), counted once however many address spaces share them after `fork`. A
+//!   frame is counted out when its last owner drops it.
+//! - The counters are relaxed atomics: each is exact, but a snapshot may mix counts from before
+//!   and after a concurrent fault.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is a small part of `/proc/meminfo` (`MemTotal`, `MemFree`, `Slab`, `AnonPages`) and of
+//! `/proc/vmstat` (`pgfault`, `cow_fault`, ...). Linux counts translation and permission faults
+//! together as `pgfault` and splits them into minor and major ones instead.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::frame;
+use super::heap;
+
+/// Kinds of user page fault events, indexes of `FAULTS`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultEvent {
+    /// Access to an unmapped page
+    Translation,
+    /// Access the page's permissions don't allow
+    Permission,
+    /// Write to a copy-on-write page, given a frame of its own
+    CowBreak,
+    /// Missing anonymous page mapped with a zeroed frame
+    ZeroFill,
+}
+
+/// Number of `FaultEvent` kinds
+const FAULT_EVENTS: usize = 4;
+
+/// User page fault counts returned by `meminfo`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FaultStats {
+    pub translation: u64,
+    pub permission: u64,
+    pub cow_breaks: u64,
+    pub zero_fills: u64,
+}
+
+/// Memory usage returned by `meminfo`, in frames
+#[derive(Clone, Copy, Debug)]
+pub struct MemInfo {
+    /// Frames managed by the frame allocator
+    pub total: usize,
+    /// Free frames
+    pub free: usize,
+    /// Frames of the kernel heap
+    pub slab: usize,
+    /// Frames owned by user pages
+    pub anon: usize,
+    pub faults: FaultStats,
+}
+
+static FAULTS: [AtomicU64; FAULT_EVENTS] = [const { AtomicU64::new(0) }; FAULT_EVENTS];
+
+/// Number of frames owned by user pages
+static ANON_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a user page fault event
+pub fn count_fault(event: FaultEvent) {
+    FAULTS[event as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a frame newly owned by a user page
+pub(super) fn anon_page_added() {
+    ANON_PAGES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Drops an owner of the frame of a user page, counting the frame out if it was freed
+pub(super) fn put_anon_page(addr: usize) {
+    if frame::free_frame(addr) == Ok(true) {
+        ANON_PAGES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the memory usage and the page fault counts
+pub fn meminfo() -> MemInfo {
+    let frames = frame::stats();
+    let heap = heap::stats();
+    let fault = |event: FaultEvent| FAULTS[event as usize].load(Ordering::Relaxed);
+    MemInfo {
+        total: frames.total,
+        free: frames.free,
+        slab: heap.slab_frames + heap.large_frames,
+        anon: ANON_PAGES.load(Ordering::Relaxed),
+        faults: FaultStats {
+            translation: fault(FaultEvent::Translation),
+            permission: fault(FaultEvent::Permission),
+            cow_breaks: fault(FaultEvent::CowBreak),
+            zero_fills: fault(FaultEvent::ZeroFill),
+        },
+    }
+}
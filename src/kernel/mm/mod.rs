@@ -1,7 +1,19 @@
+pub mod addr_space;
 pub mod bits;
+pub mod dma;
+pub mod frame;
+pub mod heap;
 pub mod identity;
+pub mod kasan;
+pub mod kaslr;
+pub mod kspace;
+pub mod kstack;
 pub mod mair;
+pub mod meminfo;
 pub mod pgtable;
+pub mod protect;
+pub mod vma;
 
 pub use identity::setup_identity_mapping;
 pub use mair::setup_mair_ranges;
+pub use meminfo::meminfo;
@@ -0,0 +1,283 @@
+//! Kernel memory permissions (W^X)
+//!
+//! The boot identity map covers RAM with a 1 GiB block that is writable and executable at EL1.
+//! `init` replaces the block holding the kernel with 4 KiB pages for the image and 2 MiB blocks
+//! for the rest, using the section symbols of the linker script:
+//!
+//! | Range                               | Permissions  |
+//! |-------------------------------------|--------------|
+//! | `.text`                             | read-execute |
+//! | `.rodata`, `__ex_table`             | read-only    |
+//! | `.data`, `.bss`, boot stack, frames | read-write   |
+//!
+//! No kernel page is ever writable and executable at the same time, so a stray write can't
+//! modify code and injected data can't run. `protect` changes the permissions of image pages
+//! afterwards, and `patch_text` writes to read-only or executable pages (breakpoints) through a
+//! short-lived writable mapping.
+//!
+//! ## Design
+//!
+//! - Turning a block into a table needs break-before-make, but the block maps the code doing the
+//!   switch. The MMU is turned off for the few instructions that swap the L1 entry and flush the
+//!   TLB, which is safe since the map is an identity one.
+//! - Only the pages of the image have L3 entries, so `protect` and `patch_text` are limited to
+//!   them: the rest of RAM is read-write and never executable.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is `map_kernel` with `CONFIG_STRICT_KERNEL_RWX` and `set_memory_ro`/`set_memory_x`. Linux
+//! patches text through a fixmap alias (`aarch64_insn_patch_text`) rather than by changing the
+//! permissions of the text mapping itself.
+
+use core::arch::asm;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::irq;
+use crate::utilities::cache;
+use crate::{initcall, println};
+
+use super::bits::*;
+use super::frame;
+use super::pgtable::{
+    PAGE_SIZE, Pte, mark_block_desc, mark_page_desc, mark_table_desc, set_block_attrs,
+    set_mair_range, set_next_lvl_table_addr,
+};
+
+unsafe extern "C" {
+    static __kernel_start: u8;
+    static __rodata_start: u8;
+    static __rw_start: u8;
+    static __stack_top: u8;
+    static mut __idmap_l1: u8;
+}
+
+/// Size mapped by an L2 block
+const SZ_2M: usize = 1 << 21;
+
+/// Number of entries in a table
+const ENTRIES: usize = PAGE_SIZE / core::mem::size_of::<Pte>();
+
+/// Descriptor type bits: table (L1/L2) or page (L3) when both set
+const PTE_TYPE_MASK: Pte = 0b11;
+
+/// Output address bits [47:12] of a descriptor
+const PTE_ADDR_MASK: Pte = 0x0000_ffff_ffff_f000;
+
+/// Access permission and execute-never bits
+const PTE_PERM_MASK: Pte = DESC_UXN | DESC_PXN | (0b11 << 6);
+
+/// Permissions of a kernel page
+///
+/// There is no read-write-execute combination.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Prot {
+    ReadOnly,
+    ReadWrite,
+    ReadExec,
+}
+
+impl Prot {
+    /// Descriptor permission bits for these permissions, EL0 having no access
+    fn pte_bits(self) -> Pte {
+        match self {
+            Prot::ReadOnly => DESC_AP_RO_EL1 | DESC_UXN | DESC_PXN,
+            Prot::ReadWrite => DESC_AP_RW_EL1 | DESC_UXN | DESC_PXN,
+            Prot::ReadExec => DESC_AP_RO_EL1 | DESC_UXN,
+        }
+    }
+}
+
+/// Errors returned by `protect` and `patch_text`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProtectError {
+    /// The range isn't entirely in the kernel image, or `init` has not run
+    BadAddress,
+}
+
+/// Physical address of the L2 table mapping the kernel's GiB, 0 before `init`
+static KERNEL_L2: AtomicUsize = AtomicUsize::new(0);
+
+/// Serializes permission changes
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Returns the table at physical address `addr`
+///
+/// # Safety
+/// `addr` must be one of the tables built by `init`.
+unsafe fn table_at(addr: usize) -> &'static mut [Pte; ENTRIES] {
+    unsafe { &mut *(addr as *mut [Pte; ENTRIES]) }
+}
+
+/// Returns a normal memory descriptor for `pa` with permissions `prot`, without the type bits
+fn normal_desc(pa: usize, prot: Prot) -> Pte {
+    let mut desc: Pte = 0;
+    set_mair_range(&mut desc, MAIR_IDX_NORMAL_WB as u64);
+    set_block_attrs(&mut desc, DESC_AF | DESC_SH_INNER | prot.pte_bits());
+    set_next_lvl_table_addr(&mut desc, pa as *const u64);
+    desc
+}
+
+/// Returns an L3 descriptor mapping the normal memory page at `pa` with permissions `prot`
+pub fn page_desc(pa: usize, prot: Prot) -> Pte {
+    let mut desc = normal_desc(pa, prot);
+    mark_page_desc(&mut desc);
+    desc
+}
+
+/// Returns the permissions the linker script gives to the image page at `addr`
+pub fn section_prot(addr: usize) -> Prot {
+    if addr < addr_of!(__rodata_start) as usize {
+        Prot::ReadExec
+    } else if addr < addr_of!(__rw_start) as usize {
+        Prot::ReadOnly
+    } else {
+        Prot::ReadWrite
+    }
+}
+
+/// Maps the kernel's GiB with per-section permissions
+///
+/// Must run after `frame::init`, which provides the new tables.
+pub fn init() {
+    let image = addr_of!(__kernel_start) as usize..addr_of!(__stack_top) as usize;
+    let gib = image.start & !(SZ_1G - 1);
+    let Ok(l2) = frame::alloc_zeroed_frames(1) else {
+        println!("protect: no memory for the tables, kernel stays writable and executable");
+        return;
+    };
+    let l2_table = unsafe { table_at(l2) };
+    for (i, entry) in l2_table.iter_mut().enumerate() {
+        let base = gib + i * SZ_2M;
+        if base + SZ_2M <= image.start || base >= image.end {
+            *entry = normal_desc(base, Prot::ReadWrite);
+            mark_block_desc(entry);
+            continue;
+        }
+        let Ok(l3) = frame::alloc_frames(1) else {
+            println!("protect: no memory for the tables, kernel stays writable and executable");
+            return;
+        };
+        for (j, pte) in unsafe { table_at(l3) }.iter_mut().enumerate() {
+            let addr = base + j * PAGE_SIZE;
+            let prot = if image.contains(&addr) {
+                section_prot(addr)
+            } else {
+                Prot::ReadWrite
+            };
+            *pte = page_desc(addr, prot);
+        }
+        *entry = 0;
+        mark_table_desc(entry);
+        set_next_lvl_table_addr(entry, l3 as *const u64);
+    }
+    let mut desc: Pte = 0;
+    mark_table_desc(&mut desc);
+    set_next_lvl_table_addr(&mut desc, l2 as *const u64);
+    let l1e = unsafe { (addr_of_mut!(__idmap_l1) as *mut Pte).add(gib / SZ_1G) };
+    replace_l1_entry(l1e, desc);
+    KERNEL_L2.store(l2, Ordering::Release);
+    println!(
+        "protect: text r-x {:#x}-{:#x}, rodata r-- up to {:#x}, data rw- up to {:#x}",
+        image.start,
+        addr_of!(__rodata_start) as usize,
+        addr_of!(__rw_start) as usize,
+        image.end
+    );
+}
+initcall!(Mmu, "protect", init, after = ["frame"]);
+
+/// Swaps the L1 entry at `entry`, which maps the running code, for `desc`
+fn replace_l1_entry(entry: *mut Pte, desc: Pte) {
+    // Written with the MMU off, so the entry goes straight to memory: drop any cached copy
+    cache::clean_invalidate_dcache_range(entry as usize, core::mem::size_of::<Pte>());
+    let daif = irq::local_irq_save();
+    unsafe {
+        asm!(
+            "mrs {tmp}, sctlr_el1",
+            "bic {tmp}, {tmp}, #1",
+            "msr sctlr_el1, {tmp}",
+            "isb",
+            "str {desc}, [{entry}]",
+            "dsb sy",
+            "tlbi vmalle1is",
+            "dsb ish",
+            "orr {tmp}, {tmp}, #1",
+            "msr sctlr_el1, {tmp}",
+            "isb",
+            tmp = out(reg) _,
+            desc = in(reg) desc,
+            entry = in(reg) entry,
+            options(nostack, preserves_flags)
+        );
+    }
+    irq::local_irq_restore(daif);
+}
+
+/// Returns the L3 entry mapping the image page at `addr`
+fn pte_of(addr: usize) -> Option<&'static mut Pte> {
+    let l2 = KERNEL_L2.load(Ordering::Acquire);
+    let start = addr_of!(__kernel_start) as usize;
+    if l2 == 0 || !(start..addr_of!(__stack_top) as usize).contains(&addr) {
+        return None;
+    }
+    let l2e = unsafe { table_at(l2) }[(addr >> 21) & (ENTRIES - 1)];
+    if l2e & PTE_TYPE_MASK != PTE_TYPE_MASK {
+        return None;
+    }
+    let l3 = (l2e & PTE_ADDR_MASK) as usize;
+    Some(&mut unsafe { table_at(l3) }[(addr >> 12) & (ENTRIES - 1)])
+}
+
+/// Returns the first address of every page overlapping `[addr, addr + len)`
+fn pages(addr: usize, len: usize) -> impl Iterator<Item = usize> {
+    let first = addr & !(PAGE_SIZE - 1);
+    (first..addr + len).step_by(PAGE_SIZE)
+}
+
+/// Gives the image pages overlapping `[addr, addr + len)` the permissions `prot`
+pub fn protect(addr: usize, len: usize, prot: Prot) -> Result<(), ProtectError> {
+    LOCK.lock_irqsafe(|_| {
+        if pages(addr, len).any(|page| pte_of(page).is_none()) {
+            return Err(ProtectError::BadAddress);
+        }
+        for pte in pages(addr, len).filter_map(pte_of) {
+            *pte = (*pte & !PTE_PERM_MASK) | prot.pte_bits();
+        }
+        cache::tlb_flush_range(addr, len);
+        if prot == Prot::ReadExec {
+            cache::sync_icache_range(addr, len);
+        }
+        Ok(())
+    })
+}
+
+/// Copies `data` to the image at `addr` whatever the page permissions, e.g. to patch code
+///
+/// The pages are writable only while copying. Instruction fetches see the new bytes afterwards.
+pub fn patch_text(addr: usize, data: &[u8]) -> Result<(), ProtectError> {
+    let end = addr + data.len();
+    LOCK.lock_irqsafe(|_| {
+        if data.is_empty() || pages(addr, data.len()).any(|page| pte_of(page).is_none()) {
+            return Err(ProtectError::BadAddress);
+        }
+        for page in pages(addr, data.len()) {
+            let Some(pte) = pte_of(page) else {
+                continue;
+            };
+            let saved = *pte;
+            *pte = (saved & !PTE_PERM_MASK) | Prot::ReadWrite.pte_bits();
+            cache::tlb_flush_page(page);
+            let (start, stop) = (addr.max(page), end.min(page + PAGE_SIZE));
+            let chunk = &data[start - addr..stop - addr];
+            unsafe {
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), start as *mut u8, chunk.len())
+            };
+            *pte = saved;
+            cache::tlb_flush_page(page);
+        }
+        cache::sync_icache_range(addr, data.len());
+        Ok(())
+    })
+}
@@ -0,0 +1,394 @@
+//! Virtual memory areas
+//!
+//! Each address space records which ranges of user addresses are valid, and how: start, length,
+//! permissions and backing. The pages of an area don't need to be mapped up front. The first
+//! access to a missing page faults, and `handle_fault` fills it in if the access fits the area's
+//! permissions: with a zeroed frame for an anonymous area, with the file's contents for a
+//! file-backed one. A write to a copy-on-write page left by `fork` gets a private copy of it. An
+//! access outside every area is a real fault and kills the task.
+//!
+//! `AddressSpace::map_anon`, `map_file` and `unmap` create and remove areas; user tasks reach
+//! them through the `mmap` and `munmap` system calls.
+//!
+//! ## Design
+//!
+//! - The areas are a small fixed array in the address space, `MAX_VMAS` entries, not sorted: no
+//!   heap is needed and a linear search is cheap at this size.
+//! - Areas never overlap. `unmap` may cut an area in two, which takes a free slot.
+//! - Without a fixed address, a mapping goes to the lowest gap big enough above `MMAP_BASE`.
+//! - File-backed pages are private copies: writes are never written back to the file.
+//! - The frame is allocated and filled without holding the scheduler lock, since reading a file
+//!   may sleep; the page is mapped afterwards unless another fault got there first.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is `vm_area_struct` with `do_mmap`/`do_munmap`, and `handle_mm_fault` reduced to
+//! `do_anonymous_page` and `do_read_fault` for `MAP_PRIVATE` mappings. Linux keeps the areas in
+//! a maple tree and supports shared mappings, `mprotect` and merging of neighbouring areas.
+
+use crate::kernel::fs::vfs::Node;
+use crate::kernel::sched;
+use crate::utilities::cache;
+
+use super::addr_space::{self, AddressSpace, MapFlags, USER_END, USER_START, VmError};
+use super::frame;
+use super::meminfo::{self, FaultEvent};
+use super::pgtable::{PAGE_MASK, PAGE_SIZE};
+
+/// Maximum number of areas in an address space
+pub const MAX_VMAS: usize = 32;
+
+/// Lowest address given to mappings made without a fixed address
+pub const MMAP_BASE: usize = 1 << 47;
+
+/// Data abort and instruction abort ISS: fault status code
+const ISS_FSC_MASK: u64 = 0x3f;
+/// Fault status codes of translation faults, levels 0 to 3
+const FSC_TRANSLATION: u64 = 0b00_0100;
+/// Fault status codes of permission faults, levels 0 to 3
+const FSC_PERMISSION: u64 = 0b00_1100;
+/// Data abort ISS: write not read
+const ISS_WNR: u64 = 1 << 6;
+/// Instruction abort taken from EL0
+const EC_IABT_LOWER: u64 = 0x20;
+
+/// Contents of the pages of an area
+#[derive(Clone, Copy)]
+pub enum Backing {
+    /// Zero-filled memory
+    Anonymous,
+    /// The file `node` from byte `offset`, zero-filled past its end
+    File { node: Node, offset: usize },
+}
+
+/// A range of valid user addresses
+#[derive(Clone, Copy)]
+pub struct Vma {
+    pub start: usize,
+    pub len: usize,
+    pub flags: MapFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end()).contains(&addr)
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.start < end && start < self.end()
+    }
+
+    /// Returns the part of the area from `start` on, with the file offset moved accordingly
+    fn tail(&self, start: usize) -> Vma {
+        let backing = match self.backing {
+            Backing::File { node, offset } => Backing::File {
+                node,
+                offset: offset + (start - self.start),
+            },
+            Backing::Anonymous => Backing::Anonymous,
+        };
+        Vma {
+            start,
+            len: self.end() - start,
+            flags: self.flags,
+            backing,
+        }
+    }
+}
+
+/// The areas of an address space
+#[derive(Clone)]
+pub struct VmaList {
+    areas: [Option<Vma>; MAX_VMAS],
+}
+
+impl VmaList {
+    pub const fn new() -> Self {
+        Self {
+            areas: [None; MAX_VMAS],
+        }
+    }
+
+    /// Returns the area containing `addr`
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.iter().find(|vma| vma.contains(addr))
+    }
+
+    /// Returns true if `[addr, addr + len)` lies entirely in areas allowing `flags`
+    pub fn covers(&self, addr: usize, len: usize, flags: MapFlags) -> bool {
+        let Some(end) = addr.checked_add(len) else {
+            return false;
+        };
+        let mut addr = addr;
+        while addr < end {
+            match self.find(addr) {
+                Some(vma) if vma.flags.contains(flags) => addr = vma.end(),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.iter().flatten()
+    }
+
+    /// Adds `vma`, which must not overlap an existing area
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmError> {
+        if vma.len == 0
+            || !vma.start.is_multiple_of(PAGE_SIZE)
+            || !vma.len.is_multiple_of(PAGE_SIZE)
+            || !addr_space::is_user_range(vma.start, vma.len)
+        {
+            return Err(VmError::BadAddress);
+        }
+        if self.iter().any(|area| area.overlaps(vma.start, vma.end())) {
+            return Err(VmError::Exists);
+        }
+        let slot = self.areas.iter_mut().find(|a| a.is_none());
+        *slot.ok_or(VmError::NoSpace)? = Some(vma);
+        Ok(())
+    }
+
+    /// Removes `[start, end)` from the areas, cutting the ones it only partly covers
+    fn remove(&mut self, start: usize, end: usize) -> Result<(), VmError> {
+        let splits = self
+            .iter()
+            .filter(|vma| vma.start < start && end < vma.end())
+            .count();
+        if splits > self.areas.iter().filter(|a| a.is_none()).count() {
+            return Err(VmError::NoSpace);
+        }
+        for i in 0..MAX_VMAS {
+            let Some(vma) = self.areas[i].filter(|vma| vma.overlaps(start, end)) else {
+                continue;
+            };
+            self.areas[i] = None;
+            if vma.start < start {
+                self.areas[i] = Some(Vma {
+                    len: start - vma.start,
+                    ..vma
+                });
+            }
+            if end < vma.end() {
+                self.insert(vma.tail(end))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the lowest address from `MMAP_BASE` where `len` bytes fit between the areas
+    fn find_gap(&self, len: usize) -> Option<usize> {
+        core::iter::once(MMAP_BASE)
+            .chain(self.iter().map(Vma::end).filter(|&end| end >= MMAP_BASE))
+            .filter(|&start| {
+                start.checked_add(len).is_some_and(|end| {
+                    end <= USER_END && !self.iter().any(|v| v.overlaps(start, end))
+                })
+            })
+            .min()
+    }
+}
+
+impl Default for VmaList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AddressSpace {
+    /// Creates an area of `len` bytes of zeroed memory, returning its address
+    ///
+    /// The area starts at `addr` if given, which must not overlap another area, and in the first
+    /// large enough gap otherwise. No page is mapped until it is accessed.
+    pub fn map_anon(
+        &mut self,
+        addr: Option<usize>,
+        len: usize,
+        flags: MapFlags,
+    ) -> Result<usize, VmError> {
+        self.map_area(addr, len, flags, Backing::Anonymous)
+    }
+
+    /// Creates an area of `len` bytes showing the file `node` from byte `offset`
+    ///
+    /// Like `map_anon`, pages are read on first access. `offset` must be page aligned.
+    pub fn map_file(
+        &mut self,
+        addr: Option<usize>,
+        len: usize,
+        flags: MapFlags,
+        node: Node,
+        offset: usize,
+    ) -> Result<usize, VmError> {
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(VmError::BadAddress);
+        }
+        self.map_area(addr, len, flags, Backing::File { node, offset })
+    }
+
+    fn map_area(
+        &mut self,
+        addr: Option<usize>,
+        len: usize,
+        flags: MapFlags,
+        backing: Backing,
+    ) -> Result<usize, VmError> {
+        let len = len
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(VmError::BadAddress)?;
+        let start = match addr {
+            Some(addr) => addr,
+            None => self.vmas.find_gap(len).ok_or(VmError::NoSpace)?,
+        };
+        self.vmas.insert(Vma {
+            start,
+            len,
+            flags,
+            backing,
+        })?;
+        Ok(start)
+    }
+
+    /// Removes the pages and areas in `[addr, addr + len)`
+    ///
+    /// Parts of the range outside every area are ignored.
+    pub fn unmap(&mut self, addr: usize, len: usize) -> Result<(), VmError> {
+        let len = len
+            .checked_next_multiple_of(PAGE_SIZE)
+            .ok_or(VmError::BadAddress)?;
+        if !addr.is_multiple_of(PAGE_SIZE) || len == 0 || !addr_space::is_user_range(addr, len) {
+            return Err(VmError::BadAddress);
+        }
+        self.vmas.remove(addr, addr + len)?;
+        for page in (addr..addr + len).step_by(PAGE_SIZE) {
+            match self.unmap_page(page) {
+                Ok(()) | Err(VmError::NotMapped) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a fault needs to fill a page, found under the scheduler lock
+struct Fill {
+    page: usize,
+    flags: MapFlags,
+    backing: Backing,
+}
+
+/// Resolves a fault at the user address `addr` of the current task
+///
+/// `esr` is the syndrome of the abort. A translation fault maps the missing page, a write
+/// permission fault copies a copy-on-write page. Returns true if the access can be retried,
+/// false if it is invalid. May sleep for file-backed areas.
+pub fn handle_fault(addr: usize, esr: u64) -> bool {
+    if addr < USER_START {
+        return false;
+    }
+    let access = if (esr >> 26) & 0x3f == EC_IABT_LOWER {
+        MapFlags::EXEC
+    } else if esr & ISS_WNR != 0 {
+        MapFlags::WRITE
+    } else {
+        MapFlags::READ
+    };
+    match esr & ISS_FSC_MASK & !0b11 {
+        FSC_TRANSLATION => {
+            meminfo::count_fault(FaultEvent::Translation);
+            fill_page(addr, access)
+        }
+        FSC_PERMISSION => {
+            meminfo::count_fault(FaultEvent::Permission);
+            let broken = access == MapFlags::WRITE
+                && sched::with_current_mm(|mm| {
+                    mm.vmas
+                        .find(addr)
+                        .is_some_and(|vma| vma.flags.contains(access))
+                        && mm.break_cow(addr) == Ok(true)
+                })
+                .unwrap_or(false);
+            if broken {
+                meminfo::count_fault(FaultEvent::CowBreak);
+            }
+            broken
+        }
+        _ => false,
+    }
+}
+
+/// Maps the missing page at `addr` if the area holding it allows `access`
+fn fill_page(addr: usize, access: MapFlags) -> bool {
+    let page = addr & PAGE_MASK;
+    let fill = sched::with_current_mm(|mm| {
+        let vma = mm.vmas.find(addr)?;
+        vma.flags.contains(access).then_some(Fill {
+            page,
+            flags: vma.flags,
+            backing: vma.tail(page).backing,
+        })
+    });
+    let Some(Some(fill)) = fill else {
+        return false;
+    };
+    let Ok(pa) = frame::alloc_zeroed_frames(1) else {
+        return false;
+    };
+    if let Backing::File { node, offset } = fill.backing
+        && read_page(node, offset, pa).is_err()
+    {
+        let _ = frame::free_frame(pa);
+        return false;
+    }
+    let mapped = sched::with_current_mm(|mm| {
+        // The area may have been unmapped meanwhile, or the page filled by another fault
+        match mm.vmas.find(fill.page) {
+            Some(_) => mm.map_owned_page(fill.page, pa, fill.flags),
+            None => Err(VmError::NotMapped),
+        }
+    });
+    match mapped {
+        Some(Ok(())) => {
+            if matches!(fill.backing, Backing::Anonymous) {
+                meminfo::count_fault(FaultEvent::ZeroFill);
+            }
+            if fill.flags.contains(MapFlags::EXEC) {
+                addr_space::sync_icache();
+            }
+            true
+        }
+        Some(Err(VmError::Exists)) => {
+            let _ = frame::free_frame(pa);
+            true
+        }
+        _ => {
+            let _ = frame::free_frame(pa);
+            false
+        }
+    }
+}
+
+/// Reads the page of the file `node` at byte `offset` into the frame at `pa`
+///
+/// The part past the end of the file stays zeroed.
+fn read_page(node: Node, offset: usize, pa: usize) -> Result<(), VmError> {
+    let size = node.stat().map_err(|_| VmError::NotMapped)?.size;
+    let len = size.saturating_sub(offset).min(PAGE_SIZE);
+    let buf = unsafe { core::slice::from_raw_parts_mut(pa as *mut u8, len) };
+    let file = node.inode.open(node.ino).map_err(|_| VmError::NotMapped)?;
+    let mut done = 0;
+    while done < len {
+        match file.read(node.ino, offset + done, &mut buf[done..]) {
+            Ok(0) | Err(_) => return Err(VmError::NotMapped),
+            Ok(read) => done += read,
+        }
+    }
+    cache::clean_dcache_range_pou(pa, len);
+    Ok(())
+}
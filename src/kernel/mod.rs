@@ -1,6 +1,34 @@
 //! Core kernel functionality
 
+pub mod block;
+pub mod board;
+pub mod clk;
+pub mod console;
+pub mod debug;
 pub mod device;
 pub mod dtb;
+pub mod extable;
+pub mod fs;
+pub mod hardening;
+pub mod init;
+pub mod input;
 pub mod irq;
+pub mod loader;
+pub mod log;
 pub mod mm;
+pub mod net;
+pub mod notifier;
+pub mod perf;
+pub mod power;
+pub mod process;
+pub mod random;
+pub mod sched;
+pub mod sensor;
+pub mod shell;
+pub mod signal;
+pub mod smp;
+pub mod syscall;
+pub mod time;
+pub mod trace;
+pub mod tty;
+pub mod uaccess;
@@ -0,0 +1,185 @@
+//! Address Resolution Protocol
+//!
+//! Maps the IPv4 addresses of neighbours to their Ethernet addresses. The cache learns from
+//! every request or reply addressed to us, and requests for our address are answered.
+//!
+//! Sending to a neighbour whose address isn't known yet broadcasts a request and keeps the
+//! packet in the cache entry; it goes out when the reply arrives. Only the latest packet is
+//! kept per neighbour, an older one being dropped, and an entry never expires.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's neighbour subsystem queues several packets per entry, retries requests on a timer
+//! and ages entries out; without timers, a lost request is only retried by the next send.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+
+use super::ethernet::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use super::{Ipv4Addr, MTU, MacAddr, NetError, PktBuf, config, device};
+
+/// Number of cached neighbours
+const CACHE_SIZE: usize = 8;
+
+/// Size of an ARP packet for IPv4 over Ethernet
+const PACKET_LEN: usize = 28;
+
+const HTYPE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+
+/// A neighbour, resolved or being resolved
+struct Entry {
+    iface: usize,
+    ip: Ipv4Addr,
+    mac: Option<MacAddr>,
+    /// Value of the cache clock when last used, 0 if the entry is empty
+    used: u64,
+    /// Packet waiting for the resolution
+    pending: Option<PktBuf>,
+}
+
+struct Cache {
+    entries: [Entry; CACHE_SIZE],
+    clock: u64,
+}
+
+impl Cache {
+    /// Returns the entry for `ip`, replacing the least recently used one if there is none
+    fn entry(&mut self, iface: usize, ip: Ipv4Addr) -> &mut Entry {
+        self.clock += 1;
+        let clock = self.clock;
+        let index = match self
+            .entries
+            .iter()
+            .position(|e| e.used != 0 && e.iface == iface && e.ip == ip)
+        {
+            Some(index) => index,
+            None => {
+                let index = self
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, e)| e.used)
+                    .map_or(0, |(index, _)| index);
+                let entry = &mut self.entries[index];
+                entry.iface = iface;
+                entry.ip = ip;
+                entry.mac = None;
+                entry.pending = None;
+                index
+            }
+        };
+        let entry = &mut self.entries[index];
+        entry.used = clock;
+        entry
+    }
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    entries: [const {
+        Entry {
+            iface: 0,
+            ip: Ipv4Addr::UNSPECIFIED,
+            mac: None,
+            used: 0,
+            pending: None,
+        }
+    }; CACHE_SIZE],
+    clock: 0,
+});
+
+/// Sends an ARP packet on interface `iface`
+fn send(
+    iface: usize,
+    op: u16,
+    dst: MacAddr,
+    target_mac: MacAddr,
+    target_ip: Ipv4Addr,
+) -> Result<(), NetError> {
+    let dev = device(iface).ok_or(NetError::NoDevice)?;
+    let sender_ip = config(iface).map_or(Ipv4Addr::UNSPECIFIED, |c| c.addr);
+    let mut buf = PktBuf::alloc().ok_or(NetError::NoBuffer)?;
+    let packet = buf.put(PACKET_LEN).ok_or(NetError::NoBuffer)?;
+    packet[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+    packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet[4] = 6;
+    packet[5] = 4;
+    packet[6..8].copy_from_slice(&op.to_be_bytes());
+    packet[8..14].copy_from_slice(&dev.mac().0);
+    packet[14..18].copy_from_slice(&sender_ip.0);
+    packet[18..24].copy_from_slice(&target_mac.0);
+    packet[24..28].copy_from_slice(&target_ip.0);
+    ethernet::send(iface, dst, ETHERTYPE_ARP, buf)
+}
+
+/// Handles an ARP packet received on interface `iface`
+pub fn receive(iface: usize, packet: PktBuf) {
+    let packet = packet.data();
+    if packet.len() < PACKET_LEN
+        || u16::from_be_bytes([packet[0], packet[1]]) != HTYPE_ETHERNET
+        || u16::from_be_bytes([packet[2], packet[3]]) != ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let Some(our) = config(iface) else {
+        return;
+    };
+    let op = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac = MacAddr(packet[8..14].try_into().unwrap_or_default());
+    let sender_ip = Ipv4Addr(packet[14..18].try_into().unwrap_or_default());
+    let target_ip = Ipv4Addr(packet[24..28].try_into().unwrap_or_default());
+    if target_ip != our.addr || sender_ip == Ipv4Addr::UNSPECIFIED {
+        return;
+    }
+
+    // Learn the sender, and take the packet that was waiting for it
+    let pending = CACHE.lock_irqsafe(|cache| {
+        let entry = cache.entry(iface, sender_ip);
+        entry.mac = Some(sender_mac);
+        entry.pending.take()
+    });
+    if let Some(pending) = pending {
+        let _ = ethernet::send(iface, sender_mac, ETHERTYPE_IPV4, pending);
+    }
+
+    if op == OP_REQUEST {
+        let _ = send(iface, OP_REPLY, sender_mac, sender_mac, sender_ip);
+    }
+}
+
+/// Sends the IPv4 `packet` to the neighbour `next_hop` on interface `iface`
+///
+/// If its Ethernet address isn't known yet, a request is broadcast and the packet waits for the
+/// reply; this still counts as sent.
+pub fn send_ipv4(iface: usize, next_hop: Ipv4Addr, packet: PktBuf) -> Result<(), NetError> {
+    let broadcast =
+        next_hop == Ipv4Addr::BROADCAST || config(iface).is_some_and(|c| next_hop == c.broadcast());
+    if broadcast {
+        return ethernet::send(iface, MacAddr::BROADCAST, ETHERTYPE_IPV4, packet);
+    }
+    if packet.len() > MTU {
+        return Err(NetError::TooBig);
+    }
+    let ready = CACHE.lock_irqsafe(|cache| {
+        let entry = cache.entry(iface, next_hop);
+        match entry.mac {
+            Some(mac) => Some((mac, packet)),
+            None => {
+                entry.pending = Some(packet);
+                None
+            }
+        }
+    });
+    match ready {
+        Some((mac, packet)) => ethernet::send(iface, mac, ETHERTYPE_IPV4, packet),
+        None => send(
+            iface,
+            OP_REQUEST,
+            MacAddr::BROADCAST,
+            MacAddr([0; 6]),
+            next_hop,
+        ),
+    }
+}
@@ -0,0 +1,463 @@
+//! DHCP client
+//!
+//! Configures an interface from a DHCP server (RFC 2131), QEMU's user networking having one
+//! built in: the client broadcasts a DISCOVER from the unconfigured interface, requests the
+//! address of the first OFFER, and configures the interface with the address, netmask, gateway
+//! and DNS server of the ACK. The lease is renewed with the server halfway through, and again
+//! halfway through what is left while the server doesn't answer; an expired or refused lease
+//! removes the configuration and the client starts over.
+//!
+//! The `ip=` parameter of the kernel command line, in Linux's format
+//! (`ip=<client>:<server>:<gateway>:<netmask>:<hostname>:<device>:<autoconf>:<dns>`, empty
+//! fields allowed), is the fallback: if no server answers, the interface gets that static
+//! configuration instead and the client stops. `ip=off` leaves the interface unconfigured.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's in-kernel autoconfiguration (`net/ipv4/ipconfig.c`) only runs at boot, with `ip=`
+//! choosing between it and the static configuration, and leaves lease renewal to a user space
+//! client such as dhclient or systemd-networkd. Here the kernel keeps the lease itself, and the
+//! client ignores everything but the options it asks for (no hostname, domain, MTU or routes).
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::waitqueue::deadline_ms;
+use crate::kernel::time::clocksource;
+use crate::kernel::{dtb, random, sched};
+use crate::{pr_err, println};
+
+use super::udp::{self, UdpSocket};
+use super::{
+    Ipv4Addr, Ipv4Config, MAX_INTERFACES, MacAddr, PktBuf, Route, configure, deconfigure, device,
+    print_config, route,
+};
+
+/// UDP ports
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+/// `op` field
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+
+/// `htype` field for Ethernet
+const HTYPE_ETHERNET: u8 = 1;
+
+/// `flags` field: asks the server to broadcast its replies
+const FLAG_BROADCAST: u16 = 0x8000;
+
+/// Offsets in a message
+const XID: usize = 4;
+const FLAGS: usize = 10;
+const CIADDR: usize = 12;
+const YIADDR: usize = 16;
+const CHADDR: usize = 28;
+const MAGIC: usize = 236;
+const OPTIONS: usize = 240;
+
+/// Magic cookie in front of the options
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+/// Size of a sent message: the minimum of BOOTP, the options padded
+const MESSAGE_LEN: usize = 300;
+
+/// Options
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_ADDR: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETERS: u8 = 55;
+const OPT_END: u8 = 255;
+
+/// Message types
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// Wait for the first reply; it doubles at every attempt
+const TIMEOUT_MS: u32 = 1000;
+
+/// Times a message is sent before giving up
+const ATTEMPTS: usize = 4;
+
+/// Wait before discovering again when no server answered and there is no fallback
+const RETRY_S: u64 = 60;
+
+/// Shortest wait between renewal attempts
+const MIN_RENEW_S: u64 = 60;
+
+/// Netmask when the server doesn't give one
+const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+/// Lease duration when the server doesn't give one
+const DEFAULT_LEASE_S: u32 = 3600;
+
+/// Longest `sched::sleep_ms` in seconds
+const MAX_SLEEP_S: u64 = u32::MAX as u64 / 1000;
+
+/// A configuration obtained from a server
+#[derive(Clone, Copy, Debug)]
+pub struct Lease {
+    pub config: Ipv4Config,
+    pub server: Ipv4Addr,
+    pub duration_s: u32,
+    /// `clocksource::now_ns` when the server acknowledged it
+    acquired_ns: u64,
+}
+
+impl Lease {
+    /// Returns the seconds left before the lease expires
+    pub fn remaining_s(&self) -> u64 {
+        let elapsed_s = (clocksource::now_ns() - self.acquired_ns) / 1_000_000_000;
+        (self.duration_s as u64).saturating_sub(elapsed_s)
+    }
+}
+
+/// Leases of the interfaces, by interface number
+static LEASES: Mutex<[Option<Lease>; MAX_INTERFACES]> = Mutex::new([None; MAX_INTERFACES]);
+
+/// Returns the lease interface `iface` is configured from, if any
+pub fn lease(iface: usize) -> Option<Lease> {
+    LEASES.lock_irqsafe(|leases| leases.get(iface).copied().flatten())
+}
+
+/// What the `ip=` parameter asks for
+enum IpParam {
+    /// No configuration at all
+    Off,
+    /// DHCP only
+    Dhcp,
+    /// DHCP, falling back to this configuration
+    Static(Ipv4Config),
+}
+
+/// Parses the `ip=` parameter of the kernel command line
+fn ip_param() -> IpParam {
+    let Some(value) = dtb::bootarg("ip") else {
+        return IpParam::Dhcp;
+    };
+    match value {
+        "off" | "none" => return IpParam::Off,
+        "" | "on" | "any" | "dhcp" | "bootp" | "both" => return IpParam::Dhcp,
+        _ => {}
+    }
+    let mut fields = [""; 8];
+    for (field, value) in fields.iter_mut().zip(value.split(':')) {
+        *field = value;
+    }
+    let addr = |field: &str| Ipv4Addr::parse(field).unwrap_or(Ipv4Addr::UNSPECIFIED);
+    let Some(client) = Ipv4Addr::parse(fields[0]) else {
+        pr_err!("dhcp: ignoring ip={}", value);
+        return IpParam::Dhcp;
+    };
+    IpParam::Static(Ipv4Config {
+        addr: client,
+        netmask: Ipv4Addr::parse(fields[3]).unwrap_or(DEFAULT_NETMASK),
+        gateway: addr(fields[2]),
+        dns: addr(fields[7]),
+    })
+}
+
+/// Starts the client for interface `iface`, unless the command line says `ip=off`
+pub fn start(iface: usize) {
+    if matches!(ip_param(), IpParam::Off) {
+        println!("dhcp: disabled by ip=off");
+        return;
+    }
+    if let Err(e) = sched::spawn("dhcp", client_task, iface) {
+        pr_err!("dhcp: cannot start the client: {:?}", e);
+    }
+}
+
+/// Appends option `code` with value `value` at `pos` of `options`
+fn put_option(options: &mut [u8], pos: &mut usize, code: u8, value: &[u8]) {
+    options[*pos] = code;
+    options[*pos + 1] = value.len() as u8;
+    options[*pos + 2..*pos + 2 + value.len()].copy_from_slice(value);
+    *pos += 2 + value.len();
+}
+
+/// Builds a message of type `kind`
+///
+/// `ciaddr` is the client's address when renewing; `requested` the address asked for and the
+/// server it was offered by, when requesting an offer.
+fn message(
+    kind: u8,
+    xid: u32,
+    mac: MacAddr,
+    ciaddr: Ipv4Addr,
+    requested: Option<(Ipv4Addr, Ipv4Addr)>,
+) -> Option<PktBuf> {
+    let mut pkt = PktBuf::alloc()?;
+    let msg = pkt.put(MESSAGE_LEN)?;
+    msg.fill(0);
+    msg[0] = BOOTREQUEST;
+    msg[1] = HTYPE_ETHERNET;
+    msg[2] = mac.0.len() as u8;
+    msg[XID..XID + 4].copy_from_slice(&xid.to_be_bytes());
+    // Without an address, the client can't take unicast replies before ARP knows it
+    if ciaddr == Ipv4Addr::UNSPECIFIED {
+        msg[FLAGS..FLAGS + 2].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    }
+    msg[CIADDR..CIADDR + 4].copy_from_slice(&ciaddr.0);
+    msg[CHADDR..CHADDR + 6].copy_from_slice(&mac.0);
+    msg[MAGIC..OPTIONS].copy_from_slice(&MAGIC_COOKIE);
+    let options = &mut msg[OPTIONS..];
+    let mut pos = 0;
+    put_option(options, &mut pos, OPT_MESSAGE_TYPE, &[kind]);
+    if let Some((addr, server)) = requested {
+        put_option(options, &mut pos, OPT_REQUESTED_ADDR, &addr.0);
+        put_option(options, &mut pos, OPT_SERVER_ID, &server.0);
+    }
+    let parameters = [OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS, OPT_LEASE_TIME];
+    put_option(options, &mut pos, OPT_PARAMETERS, &parameters);
+    options[pos] = OPT_END;
+    Some(pkt)
+}
+
+/// What a reply carries
+struct Reply {
+    kind: u8,
+    /// Address offered or acknowledged
+    addr: Ipv4Addr,
+    server: Ipv4Addr,
+    netmask: Option<Ipv4Addr>,
+    gateway: Option<Ipv4Addr>,
+    dns: Option<Ipv4Addr>,
+    lease_s: Option<u32>,
+}
+
+impl Reply {
+    /// Returns the lease an ACK grants
+    fn lease(&self) -> Lease {
+        let unspecified = Ipv4Addr::UNSPECIFIED;
+        Lease {
+            config: Ipv4Config {
+                addr: self.addr,
+                netmask: self.netmask.unwrap_or(DEFAULT_NETMASK),
+                gateway: self.gateway.unwrap_or(unspecified),
+                dns: self.dns.unwrap_or(unspecified),
+            },
+            server: self.server,
+            duration_s: self.lease_s.unwrap_or(DEFAULT_LEASE_S),
+            acquired_ns: clocksource::now_ns(),
+        }
+    }
+}
+
+/// Parses a reply from `src` to transaction `xid` of the client `mac`
+fn parse(data: &[u8], src: Ipv4Addr, xid: u32, mac: MacAddr) -> Option<Reply> {
+    if data.len() < OPTIONS
+        || data[0] != BOOTREPLY
+        || data[XID..XID + 4] != xid.to_be_bytes()
+        || data[CHADDR..CHADDR + 6] != mac.0
+        || data[MAGIC..OPTIONS] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    let addr = |value: &[u8]| Some(Ipv4Addr(value.get(..4)?.try_into().ok()?));
+    let mut reply = Reply {
+        kind: 0,
+        addr: Ipv4Addr(data[YIADDR..YIADDR + 4].try_into().ok()?),
+        server: src,
+        netmask: None,
+        gateway: None,
+        dns: None,
+        lease_s: None,
+    };
+    let mut options = &data[OPTIONS..];
+    while let [code, rest @ ..] = options {
+        match *code {
+            OPT_PAD => {
+                options = rest;
+                continue;
+            }
+            OPT_END => break,
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        let value = rest.get(..len as usize)?;
+        match *code {
+            OPT_MESSAGE_TYPE => reply.kind = *value.first()?,
+            OPT_SUBNET_MASK => reply.netmask = addr(value),
+            OPT_ROUTER => reply.gateway = addr(value),
+            OPT_DNS => reply.dns = addr(value),
+            OPT_SERVER_ID => reply.server = addr(value)?,
+            OPT_LEASE_TIME => reply.lease_s = Some(u32::from_be_bytes(value.try_into().ok()?)),
+            _ => {}
+        }
+        options = &rest[len as usize..];
+    }
+    Some(reply)
+}
+
+/// Sends the message `build` makes to the server port of `dst` along `route`, until a reply
+/// `accept` takes arrives
+///
+/// The message is sent `ATTEMPTS` times, the wait doubling each time. Returns `None` if no
+/// reply was accepted.
+fn exchange(
+    socket: &UdpSocket,
+    route: Route,
+    dst: Ipv4Addr,
+    (xid, mac): (u32, MacAddr),
+    build: impl Fn() -> Option<PktBuf>,
+    accept: impl Fn(&Reply) -> bool,
+) -> Option<Reply> {
+    let mut timeout = TIMEOUT_MS;
+    for _ in 0..ATTEMPTS {
+        if let Some(msg) = build() {
+            let _ = socket.send_packet_via(route, msg, dst, SERVER_PORT);
+        }
+        let expires = deadline_ms(timeout);
+        while let Some((pkt, src, _)) = socket.recv_packet_until(expires) {
+            if let Some(reply) = parse(pkt.data(), src, xid, mac)
+                && accept(&reply)
+            {
+                return Some(reply);
+            }
+        }
+        timeout *= 2;
+    }
+    None
+}
+
+/// Obtains a lease for interface `iface`, whose Ethernet address is `mac`
+fn discover(socket: &UdpSocket, iface: usize, mac: MacAddr) -> Option<Lease> {
+    let xid = random::get_random_u64() as u32;
+    let route = Route {
+        iface,
+        src: Ipv4Addr::UNSPECIFIED,
+        next_hop: Ipv4Addr::BROADCAST,
+    };
+    let unspecified = Ipv4Addr::UNSPECIFIED;
+    let offer = exchange(
+        socket,
+        route,
+        Ipv4Addr::BROADCAST,
+        (xid, mac),
+        || message(DHCPDISCOVER, xid, mac, unspecified, None),
+        |reply| reply.kind == DHCPOFFER && reply.addr != unspecified,
+    )?;
+    let server = offer.server;
+    let ack = exchange(
+        socket,
+        route,
+        Ipv4Addr::BROADCAST,
+        (xid, mac),
+        || {
+            message(
+                DHCPREQUEST,
+                xid,
+                mac,
+                unspecified,
+                Some((offer.addr, server)),
+            )
+        },
+        |reply| matches!(reply.kind, DHCPACK | DHCPNAK) && reply.server == server,
+    )?;
+    (ack.kind == DHCPACK).then(|| ack.lease())
+}
+
+/// Asks the server of `lease` to extend it
+///
+/// Returns the new lease, `Some(None)` if the server refused, `None` if it didn't answer.
+fn renew(socket: &UdpSocket, lease: &Lease, mac: MacAddr) -> Option<Option<Lease>> {
+    let xid = random::get_random_u64() as u32;
+    let route = route(lease.server)?;
+    let addr = lease.config.addr;
+    let reply = exchange(
+        socket,
+        route,
+        lease.server,
+        (xid, mac),
+        || message(DHCPREQUEST, xid, mac, addr, None),
+        |reply| matches!(reply.kind, DHCPACK | DHCPNAK),
+    )?;
+    Some((reply.kind == DHCPACK && reply.addr == addr).then(|| reply.lease()))
+}
+
+/// Sleeps for `secs` seconds
+fn sleep_s(mut secs: u64) {
+    while secs > 0 {
+        let chunk = secs.min(MAX_SLEEP_S);
+        sched::sleep_ms((chunk * 1000) as u32);
+        secs -= chunk;
+    }
+}
+
+/// Configures interface `iface` from `lease`
+fn bind(iface: usize, lease: Lease) {
+    let changed = self::lease(iface).is_none_or(|old| old.config != lease.config);
+    LEASES.lock_irqsafe(|leases| leases[iface] = Some(lease));
+    if configure(iface, lease.config).is_ok() && changed {
+        print_config(iface, &lease.config, "dhcp");
+    }
+}
+
+/// Removes the configuration of interface `iface`, its lease being over
+fn unbind(iface: usize) {
+    LEASES.lock_irqsafe(|leases| leases[iface] = None);
+    deconfigure(iface);
+    println!("dhcp: lease of interface {} lost", iface);
+}
+
+/// Configures interface `iface` with the `ip=` configuration, if there is one
+fn fall_back(iface: usize, fallback: Option<Ipv4Config>) {
+    if let Some(config) = fallback
+        && configure(iface, config).is_ok()
+    {
+        print_config(iface, &config, "ip=");
+    }
+}
+
+/// The client of interface `iface`
+fn client_task(iface: usize) {
+    let Some(mac) = device(iface).map(|dev| dev.mac()) else {
+        return;
+    };
+    let fallback = match ip_param() {
+        IpParam::Static(config) => Some(config),
+        _ => None,
+    };
+    let socket = match udp::bind(CLIENT_PORT) {
+        Ok(socket) => socket,
+        Err(e) => {
+            pr_err!("dhcp: cannot bind the client port: {:?}", e);
+            fall_back(iface, fallback);
+            return;
+        }
+    };
+    loop {
+        let Some(mut lease) = discover(&socket, iface, mac) else {
+            if fallback.is_some() {
+                fall_back(iface, fallback);
+                return;
+            }
+            sleep_s(RETRY_S);
+            continue;
+        };
+        bind(iface, lease);
+        loop {
+            let left = lease.remaining_s();
+            sleep_s((left / 2).max(MIN_RENEW_S).min(left));
+            if lease.remaining_s() == 0 {
+                break;
+            }
+            match renew(&socket, &lease, mac) {
+                Some(Some(renewed)) => {
+                    lease = renewed;
+                    bind(iface, lease);
+                }
+                Some(None) => break,
+                None => {}
+            }
+        }
+        unbind(iface);
+    }
+}
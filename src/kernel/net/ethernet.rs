@@ -0,0 +1,53 @@
+//! Ethernet II framing
+//!
+//! A frame is the destination and source addresses and an EtherType, followed by the payload.
+//! Frames not addressed to the interface (or broadcast) are dropped, as are EtherTypes other
+//! than IPv4 and ARP.
+
+use super::{FRAME_MAX, MacAddr, NetError, PktBuf, arp, device, ipv4};
+
+/// Size of the header
+pub const HEADER_LEN: usize = 14;
+
+/// Payload types
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Handles a frame received on interface `iface`
+pub fn receive(iface: usize, mut frame: PktBuf) {
+    let Some(dev) = device(iface) else {
+        return;
+    };
+    let Some(header) = frame.pull(HEADER_LEN) else {
+        return;
+    };
+    let dst = MacAddr(header[0..6].try_into().unwrap_or_default());
+    let ethertype = u16::from_be_bytes([header[12], header[13]]);
+    if dst != dev.mac() && dst != MacAddr::BROADCAST {
+        return;
+    }
+    match ethertype {
+        ETHERTYPE_ARP => arp::receive(iface, frame),
+        ETHERTYPE_IPV4 => ipv4::receive(iface, frame),
+        _ => {}
+    }
+}
+
+/// Sends `payload` of type `ethertype` to `dst` on interface `iface`, pushing the header in
+/// front of it
+pub fn send(
+    iface: usize,
+    dst: MacAddr,
+    ethertype: u16,
+    mut payload: PktBuf,
+) -> Result<(), NetError> {
+    let dev = device(iface).ok_or(NetError::NoDevice)?;
+    if HEADER_LEN + payload.len() > FRAME_MAX {
+        return Err(NetError::TooBig);
+    }
+    let header = payload.push(HEADER_LEN).ok_or(NetError::NoBuffer)?;
+    header[0..6].copy_from_slice(&dst.0);
+    header[6..12].copy_from_slice(&dev.mac().0);
+    header[12..14].copy_from_slice(&ethertype.to_be_bytes());
+    dev.transmit(payload)
+}
@@ -0,0 +1,34 @@
+//! ICMP
+//!
+//! Only echo requests are handled: each one is answered with an echo reply carrying the same
+//! identifier, sequence number and data, which is what `ping` needs. Everything else is ignored.
+//! The reply is the request turned around in its own buffer.
+
+use super::{Ipv4Addr, PktBuf, ipv4};
+
+/// Message types
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+
+/// Size of the header of an echo message (type, code, checksum, identifier, sequence)
+const ECHO_HEADER_LEN: usize = 8;
+
+/// Handles an ICMP message sent from `src` to `dst`
+pub fn receive(src: Ipv4Addr, dst: Ipv4Addr, mut message: PktBuf) {
+    let data = message.data();
+    if data.len() < ECHO_HEADER_LEN || ipv4::checksum(data) != 0 {
+        return;
+    }
+    // Pings to 255.255.255.255 are not answered, as on Linux by default
+    if data[0] != ECHO_REQUEST || data[1] != 0 || dst == Ipv4Addr::BROADCAST {
+        return;
+    }
+    let Some(reply) = message.data_mut() else {
+        return;
+    };
+    reply[0] = ECHO_REPLY;
+    reply[2..4].fill(0);
+    let sum = ipv4::checksum(reply);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    let _ = ipv4::send(src, ipv4::PROTO_ICMP, message);
+}
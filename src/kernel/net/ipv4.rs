@@ -0,0 +1,129 @@
+//! IPv4
+//!
+//! Received packets are checked (version, header checksum, length) and passed to ICMP, UDP or TCP
+//! when they are addressed to the interface or broadcast. Fragments and packets for other hosts
+//! are dropped: the kernel doesn't reassemble or forward. An interface without a configuration
+//! only takes UDP, whatever the destination, for DHCP's replies to the address being offered.
+//! Sent packets get a 20-byte header without options and the Don't Fragment flag.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::{Ipv4Addr, MTU, NetError, PktBuf, Route, arp, config, icmp, route, tcp, udp};
+
+/// Size of a header without options
+pub const HEADER_LEN: usize = 20;
+
+/// Protocol numbers
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_TCP: u8 = 6;
+pub const PROTO_UDP: u8 = 17;
+
+/// Time to live of sent packets
+const DEFAULT_TTL: u8 = 64;
+
+/// Flags and fragment offset field
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+
+/// Identification of the next packet sent
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Adds `data` to the one's complement sum `sum`, as 16-bit big-endian words
+///
+/// An odd trailing byte is padded with zero. Finish with `checksum_finish`.
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Folds the carries of `sum` and complements it
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Internet checksum of `data` (RFC 1071); 0 when checking data that includes its checksum
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+/// Handles an IPv4 packet received on interface `iface`
+pub fn receive(iface: usize, mut packet: PktBuf) {
+    let data = packet.data();
+    if data.len() < HEADER_LEN || data[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = (data[0] & 0xf) as usize * 4;
+    let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+    if header_len < HEADER_LEN || total_len < header_len || total_len > data.len() {
+        return;
+    }
+    if checksum(&data[..header_len]) != 0 {
+        return;
+    }
+    let fragment = u16::from_be_bytes([data[6], data[7]]);
+    if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK) != 0 {
+        return;
+    }
+    let protocol = data[9];
+    let src = Ipv4Addr(data[12..16].try_into().unwrap_or_default());
+    let dst = Ipv4Addr(data[16..20].try_into().unwrap_or_default());
+    let accepted = match config(iface) {
+        Some(our) => dst == our.addr || dst == our.broadcast() || dst == Ipv4Addr::BROADCAST,
+        None => protocol == PROTO_UDP,
+    };
+    if !accepted {
+        return;
+    }
+    // Anything after `total_len` is Ethernet padding
+    packet.trim(total_len);
+    packet.pull(header_len);
+    match protocol {
+        PROTO_ICMP => icmp::receive(src, dst, packet),
+        PROTO_UDP => udp::receive(src, dst, packet),
+        PROTO_TCP => tcp::receive(src, dst, packet),
+        _ => {}
+    }
+}
+
+/// Sends `payload` to `dst` as a packet of protocol `protocol`, pushing the header in front of it
+pub fn send(dst: Ipv4Addr, protocol: u8, payload: PktBuf) -> Result<(), NetError> {
+    send_via(route(dst).ok_or(NetError::NoRoute)?, dst, protocol, payload)
+}
+
+/// Sends `payload` to `dst` along `route` rather than the one `route` picks, e.g. from an
+/// interface without a configuration
+pub fn send_via(
+    route: Route,
+    dst: Ipv4Addr,
+    protocol: u8,
+    mut payload: PktBuf,
+) -> Result<(), NetError> {
+    let len = HEADER_LEN + payload.len();
+    if len > MTU {
+        return Err(NetError::TooBig);
+    }
+    let header = payload.push(HEADER_LEN).ok_or(NetError::NoBuffer)?;
+    header.fill(0);
+    header[0] = 0x45;
+    header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    header[4..6].copy_from_slice(&id.to_be_bytes());
+    header[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    header[8] = DEFAULT_TTL;
+    header[9] = protocol;
+    header[12..16].copy_from_slice(&route.src.0);
+    header[16..20].copy_from_slice(&dst.0);
+    let sum = checksum(header);
+    header[10..12].copy_from_slice(&sum.to_be_bytes());
+    arp::send_ipv4(route.iface, route.next_hop, payload)
+}
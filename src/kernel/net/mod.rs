@@ -0,0 +1,337 @@
+//! Networking
+//!
+//! A minimal IPv4 stack: enough to answer ARP requests and pings, to exchange UDP datagrams and
+//! to open TCP connections, which is what QEMU's user networking needs. Kernel tasks use the
+//! `udp` and `tcp` APIs directly; user tasks reach TCP through the socket system calls (see
+//! `socket`).
+//!
+//! ## Design
+//!
+//! - Network drivers implement `NetDevice` and `register` their devices as interfaces. Received
+//!   frames come in through `receive`, from the driver's deferred interrupt work; everything
+//!   up to the socket queues and buffers is handled there, replies included.
+//! - Layers are modules calling each other directly: `ethernet` → `arp` / `ipv4` → `icmp` /
+//!   `udp` / `tcp`. Packets travel between them as `pktbuf::PktBuf`s, owned by one layer at a time:
+//!   a received frame stays in the buffer the device wrote it to, each layer pulling its header
+//!   off, and a packet to send gets each layer's header pushed in front of it.
+//! - Routing is the simplest there is: a destination on the subnet of an interface goes
+//!   there directly, anything else goes to the gateway of the first configured interface.
+//! - `init` starts the DHCP client on the first interface (see `dhcp`), which falls back to the
+//!   `ip=` parameter of the kernel command line, the UDP and TCP echo services on port 7, the
+//!   telnet console on port 23 (see `telnet`) and the SNTP client (see `sntp`).
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux has `net_device`, `sk_buff` and NAPI polling; here `PktBuf` is a much reduced
+//! `sk_buff` and the receive path is a chain of function calls. There is no fragmentation, no
+//! IP options and no routing table, and TCP is the bare protocol (see `tcp`).
+
+pub mod arp;
+pub mod dhcp;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod pktbuf;
+pub mod sntp;
+pub mod socket;
+pub mod tcp;
+pub mod telnet;
+pub mod udp;
+
+use core::fmt;
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::sched;
+use crate::{initcall, pr_err, println};
+
+pub use pktbuf::PktBuf;
+
+/// Largest IP packet sent or received
+pub const MTU: usize = 1500;
+
+/// Largest Ethernet frame, without the frame check sequence
+pub const FRAME_MAX: usize = ethernet::HEADER_LEN + MTU;
+
+/// Maximum number of interfaces
+const MAX_INTERFACES: usize = 4;
+
+/// An Ethernet address
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// An IPv4 address
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([0xff; 4]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Self([a, b, c, d])
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn from_u32(addr: u32) -> Self {
+        Self(addr.to_be_bytes())
+    }
+
+    /// Parses dotted-decimal notation, e.g. `10.0.2.15`
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut addr = [0; 4];
+        let mut parts = s.split('.');
+        for byte in addr.iter_mut() {
+            *byte = parts.next()?.parse().ok()?;
+        }
+        parts.next().is_none().then_some(Self(addr))
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+/// Errors returned by the network stack
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NetError {
+    /// The interface doesn't exist or its device is gone
+    NoDevice,
+    /// No interface can reach the destination
+    NoRoute,
+    /// The device has no free transmit buffer
+    Busy,
+    /// The packet doesn't fit in a frame
+    TooBig,
+    /// The port is already bound
+    AddrInUse,
+    /// A table (interfaces, sockets) is full
+    NoSpace,
+    /// No packet buffer is free, or the packet has no room left for a header
+    NoBuffer,
+    /// The peer refused the connection
+    Refused,
+    /// The peer reset the connection
+    Reset,
+    /// The peer stopped acknowledging what was sent
+    TimedOut,
+    /// The connection is closed, or closing on our side
+    NotConnected,
+    /// The operation doesn't apply to the socket in its current state
+    Invalid,
+    /// A signal interrupted the wait
+    Interrupted,
+}
+
+/// A network device
+pub trait NetDevice: Sync {
+    /// Name of the device, e.g. `eth0`
+    fn name(&self) -> &'static str;
+
+    /// Returns the Ethernet address of the device
+    fn mac(&self) -> MacAddr;
+
+    /// Queues `frame` (an Ethernet frame without its FCS) for transmission
+    ///
+    /// The device owns the buffer until it is sent.
+    fn transmit(&self, frame: PktBuf) -> Result<(), NetError>;
+}
+
+/// IPv4 configuration of an interface
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Ipv4Config {
+    pub addr: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    /// `UNSPECIFIED` for none
+    pub gateway: Ipv4Addr,
+    /// DNS server, `UNSPECIFIED` for none; only recorded, the kernel has no resolver
+    pub dns: Ipv4Addr,
+}
+
+impl Ipv4Config {
+    /// Returns true if `addr` is on the interface's subnet
+    pub fn on_link(&self, addr: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_u32();
+        addr.to_u32() & mask == self.addr.to_u32() & mask
+    }
+
+    /// Returns the broadcast address of the subnet
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.addr.to_u32() | !self.netmask.to_u32())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Interface {
+    dev: &'static dyn NetDevice,
+    config: Option<Ipv4Config>,
+}
+
+/// Registered interfaces, numbered by their index; entries are never removed
+static INTERFACES: Mutex<[Option<Interface>; MAX_INTERFACES]> = Mutex::new([None; MAX_INTERFACES]);
+
+/// Makes `dev` an interface, returning its number
+pub fn register(dev: &'static dyn NetDevice) -> Result<usize, NetError> {
+    INTERFACES.lock_irqsafe(|interfaces| {
+        let index = interfaces
+            .iter()
+            .position(|i| i.is_none())
+            .ok_or(NetError::NoSpace)?;
+        interfaces[index] = Some(Interface { dev, config: None });
+        Ok(index)
+    })
+}
+
+/// Sets the IPv4 configuration of interface `iface`
+pub fn configure(iface: usize, config: Ipv4Config) -> Result<(), NetError> {
+    INTERFACES.lock_irqsafe(|interfaces| {
+        let interface = interfaces
+            .get_mut(iface)
+            .and_then(|i| i.as_mut())
+            .ok_or(NetError::NoDevice)?;
+        interface.config = Some(config);
+        Ok(())
+    })
+}
+
+/// Removes the IPv4 configuration of interface `iface`
+pub fn deconfigure(iface: usize) {
+    INTERFACES.lock_irqsafe(|interfaces| {
+        if let Some(interface) = interfaces.get_mut(iface).and_then(|i| i.as_mut()) {
+            interface.config = None;
+        }
+    });
+}
+
+/// Returns the IPv4 configuration of interface `iface`, if it has one
+pub fn config(iface: usize) -> Option<Ipv4Config> {
+    INTERFACES.lock_irqsafe(|interfaces| interfaces.get(iface).copied().flatten()?.config)
+}
+
+/// Returns the device of interface `iface`
+fn device(iface: usize) -> Option<&'static dyn NetDevice> {
+    INTERFACES.lock_irqsafe(|interfaces| Some(interfaces.get(iface).copied().flatten()?.dev))
+}
+
+/// Where to send a packet
+#[derive(Clone, Copy, Debug)]
+pub struct Route {
+    pub iface: usize,
+    /// Address of the interface, the packet's source
+    pub src: Ipv4Addr,
+    /// Neighbour the frame goes to: the destination itself or the gateway
+    pub next_hop: Ipv4Addr,
+}
+
+/// Picks the interface and next hop for `dst`
+pub fn route(dst: Ipv4Addr) -> Option<Route> {
+    let interfaces = INTERFACES.lock_irqsafe(|interfaces| *interfaces);
+    let configured = || {
+        interfaces
+            .iter()
+            .enumerate()
+            .filter_map(|(iface, i)| Some((iface, i.as_ref()?.config?)))
+    };
+    if let Some((iface, config)) =
+        configured().find(|(_, config)| config.on_link(dst) || dst == Ipv4Addr::BROADCAST)
+    {
+        return Some(Route {
+            iface,
+            src: config.addr,
+            next_hop: dst,
+        });
+    }
+    let (iface, config) = configured().find(|(_, c)| c.gateway != Ipv4Addr::UNSPECIFIED)?;
+    Some(Route {
+        iface,
+        src: config.addr,
+        next_hop: config.gateway,
+    })
+}
+
+/// Handles a frame received on interface `iface`
+///
+/// Called by drivers, outside of interrupt context. The stack owns the buffer from then on.
+pub fn receive(iface: usize, frame: PktBuf) {
+    ethernet::receive(iface, frame);
+}
+
+/// Prints the interfaces and their configuration
+pub fn dump() {
+    let interfaces = INTERFACES.lock_irqsafe(|interfaces| *interfaces);
+    for (iface, interface) in interfaces.iter().enumerate() {
+        let Some(interface) = interface else {
+            continue;
+        };
+        println!("{}: mac {}", interface.dev.name(), interface.dev.mac());
+        let Some(config) = interface.config else {
+            println!("  not configured");
+            continue;
+        };
+        println!(
+            "  inet {} netmask {} broadcast {}",
+            config.addr,
+            config.netmask,
+            config.broadcast()
+        );
+        println!("  gateway {} dns {}", config.gateway, config.dns);
+        match dhcp::lease(iface) {
+            Some(lease) => println!(
+                "  dhcp server {}, lease {} s, {} s left",
+                lease.server,
+                lease.duration_s,
+                lease.remaining_s()
+            ),
+            None => println!("  static"),
+        }
+    }
+    println!("packet buffers: {} free", pktbuf::free_count());
+}
+
+/// Prints the IPv4 configuration `iface` was given
+fn print_config(iface: usize, config: &Ipv4Config, how: &str) {
+    let name = device(iface).map_or("?", |dev| dev.name());
+    println!(
+        "net: {} is {} netmask {} gateway {} ({})",
+        name, config.addr, config.netmask, config.gateway, how
+    );
+}
+
+/// Starts the DHCP client on the first interface and the network services
+///
+/// Must run after `sched::init`.
+pub fn init() {
+    if device(0).is_none() {
+        return;
+    }
+    dhcp::start(0);
+    if let Err(e) = sched::spawn("udp-echo", udp::echo_task, 0) {
+        pr_err!("net: cannot start the UDP echo service: {:?}", e);
+    }
+    if let Err(e) = sched::spawn("tcp-echo", tcp::echo_task, 0) {
+        pr_err!("net: cannot start the TCP echo service: {:?}", e);
+    }
+    if let Err(e) = sched::spawn("telnet", telnet::server_task, 0) {
+        pr_err!("net: cannot start the telnet server: {:?}", e);
+    }
+    if let Err(e) = sched::spawn("sntp", sntp::client_task, 0) {
+        pr_err!("net: cannot start the SNTP client: {:?}", e);
+    }
+}
+initcall!(Late, "net", init, after = ["sched"]);
@@ -0,0 +1,205 @@
+//! Packet buffers
+//!
+//! A `PktBuf` is a handle on a fixed-size buffer from a dedicated pool and a window on it, the
+//! packet's bytes. The space before the window (headroom) and after it (tailroom) lets the
+//! packet grow without moving: each layer on the way down `push`es its header in front, and
+//! each layer on the way up `pull`s its header off. A received frame thus goes from the
+//! virtio-net ring up to a socket in the buffer the device wrote it to, and an echo reply can be
+//! sent back in the same buffer.
+//!
+//! ## Design
+//!
+//! - The pool is `POOL_SIZE` buffers of `BUF_SIZE` bytes in static memory, so their addresses
+//!   can be given to devices as they are. A buffer is free when its reference count is 0:
+//!   `alloc` takes one by moving a count from 0 to 1, which needs no lock and works in interrupt
+//!   context. An exhausted pool fails the allocation; the packet is dropped.
+//! - Handles are reference counted: `share` returns another handle on the same bytes, for a
+//!   layer keeping a packet while handing it on, and the buffer goes back to the pool when the
+//!   last handle is dropped. The bytes of a shared buffer are read-only: `push`, `put` and
+//!   `data_mut` fail until the other handles are gone.
+//! - Ownership moves with the handle: a driver keeps the handles of the buffers its device owns
+//!   and passes them on (or drops them) when the device gives them back.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is a much reduced `sk_buff`: `skb_push`, `skb_pull`, `skb_put` and `skb_trim` on a
+//! linear buffer, with `skb_get` as `share`. Linux allocates the data from the page allocator or
+//! page pools, supports paged fragments and clones with their own metadata, and keeps per-packet
+//! metadata (device, protocol, checksum state) in the `sk_buff` itself.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+
+/// Number of buffers in the pool
+pub const POOL_SIZE: usize = 128;
+
+/// Size of a buffer
+pub const BUF_SIZE: usize = 2048;
+
+/// Headroom of a fresh buffer: enough for the headers of every layer, device header included
+pub const HEADROOM: usize = 128;
+
+/// The buffers
+struct Pool(UnsafeCell<[[u8; BUF_SIZE]; POOL_SIZE]>);
+
+// A buffer is only written through a handle holding its only reference
+unsafe impl Sync for Pool {}
+
+static POOL: Pool = Pool(UnsafeCell::new([[0; BUF_SIZE]; POOL_SIZE]));
+
+/// Reference count of each buffer, 0 if it is free
+static REFS: [AtomicU16; POOL_SIZE] = [const { AtomicU16::new(0) }; POOL_SIZE];
+
+/// Buffer `alloc` tries first, the one after the last allocated
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// A reference to a packet buffer and the packet's bytes in it
+pub struct PktBuf {
+    index: usize,
+    /// The packet is `[start, end)` of the buffer
+    start: usize,
+    end: usize,
+}
+
+impl PktBuf {
+    /// Takes a free buffer, holding an empty packet after `HEADROOM` bytes
+    ///
+    /// Returns `None` if the pool is exhausted.
+    pub fn alloc() -> Option<PktBuf> {
+        let first = NEXT.load(Ordering::Relaxed);
+        for i in 0..POOL_SIZE {
+            let index = (first + i) % POOL_SIZE;
+            if REFS[index]
+                .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                NEXT.store((index + 1) % POOL_SIZE, Ordering::Relaxed);
+                return Some(PktBuf {
+                    index,
+                    start: HEADROOM,
+                    end: HEADROOM,
+                });
+            }
+        }
+        None
+    }
+
+    /// Takes a free buffer and copies `data` into it, after `HEADROOM` bytes
+    pub fn from_slice(data: &[u8]) -> Option<PktBuf> {
+        let mut pkt = Self::alloc()?;
+        pkt.put(data.len())?.copy_from_slice(data);
+        Some(pkt)
+    }
+
+    fn buffer(&self) -> *mut u8 {
+        unsafe { (*POOL.0.get())[self.index].as_mut_ptr() }
+    }
+
+    /// Returns true if other handles refer to the buffer
+    pub fn is_shared(&self) -> bool {
+        REFS[self.index].load(Ordering::Acquire) > 1
+    }
+
+    /// Returns another handle on the same bytes
+    pub fn share(&self) -> PktBuf {
+        REFS[self.index].fetch_add(1, Ordering::Relaxed);
+        PktBuf {
+            index: self.index,
+            start: self.start,
+            end: self.end,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Room in front of the packet
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    /// Room after the packet
+    pub fn tailroom(&self) -> usize {
+        BUF_SIZE - self.end
+    }
+
+    /// Returns the packet's bytes
+    pub fn data(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.buffer().add(self.start), self.len()) }
+    }
+
+    /// Returns the packet's bytes for writing, unless the buffer is shared
+    pub fn data_mut(&mut self) -> Option<&mut [u8]> {
+        if self.is_shared() {
+            return None;
+        }
+        let len = self.len();
+        Some(unsafe { core::slice::from_raw_parts_mut(self.buffer().add(self.start), len) })
+    }
+
+    /// Returns the tailroom for a device to write the packet's bytes to, unless the buffer is
+    /// shared; `put` then claims what it wrote
+    pub fn tailroom_mut(&mut self) -> Option<&mut [u8]> {
+        if self.is_shared() {
+            return None;
+        }
+        let room = self.tailroom();
+        Some(unsafe { core::slice::from_raw_parts_mut(self.buffer().add(self.end), room) })
+    }
+
+    /// Grows the packet by `len` bytes in front, returning them for the caller to fill
+    ///
+    /// Returns `None` if the headroom is too small or the buffer is shared.
+    pub fn push(&mut self, len: usize) -> Option<&mut [u8]> {
+        if len > self.start || self.is_shared() {
+            return None;
+        }
+        self.start -= len;
+        Some(unsafe { core::slice::from_raw_parts_mut(self.buffer().add(self.start), len) })
+    }
+
+    /// Removes `len` bytes from the front of the packet, returning them
+    ///
+    /// Returns `None` if the packet is shorter.
+    pub fn pull(&mut self, len: usize) -> Option<&[u8]> {
+        if len > self.len() {
+            return None;
+        }
+        self.start += len;
+        Some(unsafe { core::slice::from_raw_parts(self.buffer().add(self.start - len), len) })
+    }
+
+    /// Grows the packet by `len` bytes at the end, returning them for the caller to fill
+    ///
+    /// Returns `None` if the tailroom is too small or the buffer is shared.
+    pub fn put(&mut self, len: usize) -> Option<&mut [u8]> {
+        if len > self.tailroom() || self.is_shared() {
+            return None;
+        }
+        self.end += len;
+        Some(unsafe { core::slice::from_raw_parts_mut(self.buffer().add(self.end - len), len) })
+    }
+
+    /// Shortens the packet to `len` bytes, if it is longer
+    pub fn trim(&mut self, len: usize) {
+        self.end = self.end.min(self.start + len);
+    }
+}
+
+impl Drop for PktBuf {
+    fn drop(&mut self) {
+        REFS[self.index].fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Returns the number of free buffers
+pub fn free_count() -> usize {
+    REFS.iter()
+        .filter(|refs| refs.load(Ordering::Relaxed) == 0)
+        .count()
+}
@@ -0,0 +1,182 @@
+//! SNTP client
+//!
+//! Keeps `time::walltime` in step with a network time server (RFC 4330). Once the server is
+//! reachable, the client sends it a request every `POLL_S` seconds, every `RETRY_S` seconds while
+//! it doesn't answer, and steps the time by the offset the reply gives. QEMU's user networking
+//! forwards the requests to the outside world like any other UDP traffic.
+//!
+//! The server is `DEFAULT_SERVER` (`time.google.com`, there being no DNS resolver), or the
+//! address given with the `ntp=` parameter of the kernel command line; `ntp=off` disables the
+//! client.
+//!
+//! ## Design
+//!
+//! - The offset is computed from the four timestamps of the exchange, as NTP does:
+//!   `((t2 - t1) + (t3 - t4)) / 2`, `t1` and `t4` being our send and receive times and `t2` and
+//!   `t3` the server's. Only the offset is used, the round-trip delay just being printed.
+//! - A reply is only accepted from the server, answering our request (its originate timestamp is
+//!   our transmit timestamp) and synchronized (stratum 1 to 15, no alarm).
+//! - Offsets below `MIN_STEP_NS` are ignored rather than stepping the time back and forth.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux leaves this to user space daemons (ntpd, chronyd, systemd-timesyncd), which filter
+//! several samples and slew the clock through `adjtimex` instead of stepping it.
+
+use crate::ipc::waitqueue::deadline_ms;
+use crate::kernel::time::clocksource::NSEC_PER_SEC;
+use crate::kernel::time::walltime::{self, TimeSource};
+use crate::kernel::{dtb, sched};
+use crate::{pr_err, println};
+
+use super::udp::{self, UdpSocket};
+use super::{Ipv4Addr, route};
+
+/// Port of the server
+const SERVER_PORT: u16 = 123;
+
+/// `time.google.com`
+const DEFAULT_SERVER: Ipv4Addr = Ipv4Addr([216, 239, 35, 0]);
+
+/// Size of a message without authentication
+const PACKET_LEN: usize = 48;
+
+/// Seconds between the NTP era (1900) and the Unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Leap indicator 0, version 4, mode 3 (client)
+const CLIENT_HEADER: u8 = 0x23;
+
+/// Mode of a server's reply
+const MODE_SERVER: u8 = 4;
+/// Leap indicator of an unsynchronized server
+const LEAP_ALARM: u8 = 3;
+
+/// Offsets of the timestamps in a message
+const ORIGINATE_TS: usize = 24;
+const RECEIVE_TS: usize = 32;
+const TRANSMIT_TS: usize = 40;
+
+/// Time given to the server to answer
+const TIMEOUT_MS: u32 = 2000;
+
+/// Interval between two requests, and between two attempts while the server doesn't answer
+const POLL_S: u32 = 1024;
+const RETRY_S: u32 = 16;
+
+/// Interval at which the client checks whether the server is reachable yet
+const ROUTE_POLL_MS: u32 = 1000;
+
+/// Smallest offset the time is stepped by
+const MIN_STEP_NS: u64 = 1_000_000;
+
+/// Converts `ns` nanoseconds since the Unix epoch to an NTP timestamp
+fn to_ntp(ns: u64) -> u64 {
+    let secs = ns / NSEC_PER_SEC + NTP_UNIX_OFFSET;
+    let frac = ((ns % NSEC_PER_SEC) << 32) / NSEC_PER_SEC;
+    secs << 32 | frac
+}
+
+/// Converts an NTP timestamp to nanoseconds since the Unix epoch
+///
+/// Timestamps of the era starting in 2036 would need the era number; this one lasts until then.
+fn from_ntp(ts: u64) -> u64 {
+    let secs = (ts >> 32).saturating_sub(NTP_UNIX_OFFSET);
+    let frac = ((ts & 0xffff_ffff) * NSEC_PER_SEC) >> 32;
+    secs * NSEC_PER_SEC + frac
+}
+
+/// Reads the timestamp at `offset` of `msg`
+fn timestamp(msg: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(msg[offset..offset + 8].try_into().unwrap_or_default())
+}
+
+/// Returns the server given by `ntp=`, `None` if the client is disabled
+fn server_param() -> Option<Ipv4Addr> {
+    match dtb::bootarg("ntp") {
+        None => Some(DEFAULT_SERVER),
+        Some("off") => None,
+        Some(arg) => {
+            let addr = Ipv4Addr::parse(arg);
+            if addr.is_none() {
+                println!("sntp: bad ntp= address {}, using the default", arg);
+            }
+            Some(addr.unwrap_or(DEFAULT_SERVER))
+        }
+    }
+}
+
+/// Asks `server` for the time
+///
+/// Returns the offset of our time from the server's and the round-trip delay, in nanoseconds.
+fn query(socket: &UdpSocket, server: Ipv4Addr) -> Option<(i64, u64)> {
+    let mut request = [0u8; PACKET_LEN];
+    request[0] = CLIENT_HEADER;
+    let t1 = walltime::now_ns();
+    let sent = to_ntp(t1);
+    request[TRANSMIT_TS..].copy_from_slice(&sent.to_be_bytes());
+    socket.send_to(&request, server, SERVER_PORT).ok()?;
+
+    let expires = deadline_ms(TIMEOUT_MS);
+    while let Some((reply, src, port)) = socket.recv_packet_until(expires) {
+        let t4 = walltime::now_ns();
+        let msg = reply.data();
+        if src != server || port != SERVER_PORT || msg.len() < PACKET_LEN {
+            continue;
+        }
+        let (leap, mode, stratum) = (msg[0] >> 6, msg[0] & 0x7, msg[1]);
+        if mode != MODE_SERVER || timestamp(msg, ORIGINATE_TS) != sent {
+            continue;
+        }
+        if leap == LEAP_ALARM || !(1..=15).contains(&stratum) {
+            return None;
+        }
+        let t2 = from_ntp(timestamp(msg, RECEIVE_TS)) as i128;
+        let t3 = from_ntp(timestamp(msg, TRANSMIT_TS)) as i128;
+        let (t1, t4) = (t1 as i128, t4 as i128);
+        let offset = ((t2 - t1) + (t3 - t4)) / 2;
+        let delay = ((t4 - t1) - (t3 - t2)).max(0);
+        return Some((offset as i64, delay as u64));
+    }
+    None
+}
+
+/// The client
+pub fn client_task(_arg: usize) {
+    let Some(server) = server_param() else {
+        return;
+    };
+    let socket = match udp::bind(0) {
+        Ok(socket) => socket,
+        Err(e) => {
+            pr_err!("sntp: cannot bind a port: {:?}", e);
+            return;
+        }
+    };
+    // Until an interface is configured, e.g. by DHCP
+    while route(server).is_none() {
+        sched::sleep_ms(ROUTE_POLL_MS);
+    }
+    loop {
+        let Some((offset, delay)) = query(&socket, server) else {
+            sched::sleep_ms(RETRY_S * 1000);
+            continue;
+        };
+        let step = if offset.unsigned_abs() >= MIN_STEP_NS {
+            offset
+        } else {
+            0
+        };
+        // Records the synchronization even without a step
+        walltime::adjust(step, TimeSource::Ntp);
+        if step != 0 {
+            println!(
+                "sntp: stepped by {} ms from {} (delay {} ms)",
+                offset / 1_000_000,
+                server,
+                delay / 1_000_000
+            );
+        }
+        sched::sleep_ms(POLL_S * 1000);
+    }
+}
@@ -0,0 +1,240 @@
+//! Sockets
+//!
+//! TCP connections as file descriptors, for the socket system calls. A socket starts unbound,
+//! then either `listen`s (after an optional `bind`) and hands out connected sockets through
+//! `accept`, or `connect`s. A connected socket is read and written like any file; `send` and
+//! `recv` are the same operations by socket number.
+//!
+//! ## Design
+//!
+//! - Sockets live in a fixed table and are VFS nodes of `SOCKFS`, numbered by their slot. Each
+//!   counts the descriptors referring to it (`vfs::File::retain` and `release`): the socket,
+//!   and the connection or listener it holds, goes away with the last one.
+//! - The table lock is never held while sleeping: blocking operations look up the connection's
+//!   slot and wait in `tcp`, which stays valid as long as the caller holds its descriptor.
+//! - The port given to `bind` is only used by `listen`; connections always get an ephemeral
+//!   port.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux has `struct socket` and `struct sock`, an inode of the sockfs pseudo-filesystem for
+//! each socket, and protocol families behind `proto_ops`. Here TCP over IPv4 is the only kind,
+//! there are no socket options, no non-blocking mode and no `poll`.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::fs::vfs::{self, Fd, FileKind, FsError, Ino, Node, OpenMode, Stat};
+
+use super::tcp::{self, TcpListener, TcpStream};
+use super::{Ipv4Addr, NetError};
+
+/// Maximum number of sockets
+const MAX_SOCKETS: usize = 16;
+
+/// A socket, its slot in the table
+pub type SocketId = usize;
+
+/// What a socket is, as far as it got
+enum Kind {
+    /// Neither listening nor connected; `port` is the port given to `bind`, or 0
+    Unbound {
+        port: u16,
+    },
+    Listener(TcpListener),
+    Stream(TcpStream),
+}
+
+struct Socket {
+    kind: Kind,
+    /// Number of descriptors referring to the socket
+    refs: usize,
+}
+
+static SOCKETS: Mutex<[Option<Socket>; MAX_SOCKETS]> = Mutex::new([const { None }; MAX_SOCKETS]);
+
+/// Creates an entry of the table holding `kind` and opens it in the running task
+fn create(kind: Kind) -> Result<Fd, NetError> {
+    let id = SOCKETS.lock_irqsafe(|sockets| {
+        let id = sockets
+            .iter()
+            .position(|s| s.is_none())
+            .ok_or(NetError::NoSpace)?;
+        sockets[id] = Some(Socket { kind, refs: 1 });
+        Ok(id)
+    })?;
+    let node = Node {
+        inode: &SOCKFS,
+        ino: id as Ino,
+    };
+    vfs::open_node(node, OpenMode::ReadWrite).map_err(|_| {
+        let socket = SOCKETS.lock_irqsafe(|sockets| sockets[id].take());
+        drop(socket);
+        NetError::NoSpace
+    })
+}
+
+/// Creates an unbound socket, returning its descriptor in the running task
+pub fn open() -> Result<Fd, NetError> {
+    create(Kind::Unbound { port: 0 })
+}
+
+/// Returns the socket `node` is, if it is one
+pub fn id(node: Node) -> Option<SocketId> {
+    core::ptr::addr_eq(node.inode, &SOCKFS).then_some(node.ino as SocketId)
+}
+
+/// Runs `f` on the socket `id`
+fn with_socket<R>(id: SocketId, f: impl FnOnce(&mut Socket) -> R) -> Option<R> {
+    SOCKETS.lock_irqsafe(|sockets| sockets.get_mut(id)?.as_mut().map(f))
+}
+
+/// Returns the slot of the connection of socket `id`, if it is connected
+fn stream_slot(id: SocketId) -> Result<usize, NetError> {
+    with_socket(id, |socket| match &socket.kind {
+        Kind::Stream(stream) => Ok(stream.slot()),
+        _ => Err(NetError::NotConnected),
+    })
+    .ok_or(NetError::Invalid)?
+}
+
+/// Sets the port socket `id` listens on
+pub fn bind(id: SocketId, port: u16) -> Result<(), NetError> {
+    with_socket(id, |socket| match &mut socket.kind {
+        Kind::Unbound { port: bound } if *bound == 0 => {
+            *bound = port;
+            Ok(())
+        }
+        _ => Err(NetError::Invalid),
+    })
+    .ok_or(NetError::Invalid)?
+}
+
+/// Makes socket `id` listen on its port, or on an ephemeral port if it isn't bound
+pub fn listen(id: SocketId) -> Result<(), NetError> {
+    with_socket(id, |socket| match &socket.kind {
+        &Kind::Unbound { port } => {
+            socket.kind = Kind::Listener(tcp::listen(port)?);
+            Ok(())
+        }
+        Kind::Listener(_) => Ok(()),
+        Kind::Stream(_) => Err(NetError::Invalid),
+    })
+    .ok_or(NetError::Invalid)?
+}
+
+/// Sleeps until a connection to the listening socket `id` is established
+///
+/// Returns the descriptor of the connected socket, and the address and port of the peer.
+pub fn accept(id: SocketId) -> Result<(Fd, Ipv4Addr, u16), NetError> {
+    let slot = with_socket(id, |socket| match &socket.kind {
+        Kind::Listener(listener) => Ok(listener.slot()),
+        _ => Err(NetError::Invalid),
+    })
+    .ok_or(NetError::Invalid)??;
+    let stream = tcp::accept(slot)?;
+    let (addr, port) = stream.peer();
+    let fd = create(Kind::Stream(stream))?;
+    Ok((fd, addr, port))
+}
+
+/// Connects socket `id` to `port` on `addr`, sleeping until the connection is established
+pub fn connect(id: SocketId, addr: Ipv4Addr, port: u16) -> Result<(), NetError> {
+    let unbound = with_socket(id, |socket| matches!(socket.kind, Kind::Unbound { .. }));
+    if unbound != Some(true) {
+        return Err(NetError::Invalid);
+    }
+    let stream = tcp::connect(addr, port)?;
+    // Another task sharing the socket may have got there first; the stream is closed then
+    let stream = with_socket(id, |socket| match &socket.kind {
+        Kind::Unbound { .. } => {
+            socket.kind = Kind::Stream(stream);
+            None
+        }
+        _ => Some(stream),
+    })
+    .ok_or(NetError::Invalid)?;
+    match stream {
+        Some(stream) => {
+            drop(stream);
+            Err(NetError::Invalid)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Returns the address and port of the peer of the connected socket `id`
+pub fn peer(id: SocketId) -> Result<(Ipv4Addr, u16), NetError> {
+    Ok(tcp::peer(stream_slot(id)?))
+}
+
+/// Sends `data` on the connected socket `id`, see `TcpStream::send`
+pub fn send(id: SocketId, data: &[u8]) -> Result<usize, NetError> {
+    tcp::send(stream_slot(id)?, data)
+}
+
+/// Receives from the connected socket `id`, see `TcpStream::recv`
+pub fn recv(id: SocketId, buf: &mut [u8]) -> Result<usize, NetError> {
+    tcp::recv(stream_slot(id)?, buf)
+}
+
+/// Closes the sending side of the connected socket `id`
+pub fn shutdown(id: SocketId) -> Result<(), NetError> {
+    tcp::shutdown(stream_slot(id)?);
+    Ok(())
+}
+
+/// Returns the error of a file operation on a socket
+fn fs_error(e: NetError) -> FsError {
+    match e {
+        NetError::Interrupted => FsError::Interrupted,
+        _ => FsError::Io,
+    }
+}
+
+/// The sockets, as VFS nodes numbered by their slot
+struct Sockfs;
+
+static SOCKFS: Sockfs = Sockfs;
+
+impl vfs::Inode for Sockfs {
+    fn stat(&self, _ino: Ino) -> Result<Stat, FsError> {
+        Ok(Stat {
+            kind: FileKind::Socket,
+            size: 0,
+            mode: 0o777,
+        })
+    }
+
+    fn open(&self, _ino: Ino) -> Result<&'static dyn vfs::File, FsError> {
+        Ok(&SOCKFS)
+    }
+}
+
+impl vfs::File for Sockfs {
+    fn read(&self, ino: Ino, _offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        recv(ino as SocketId, buf).map_err(fs_error)
+    }
+
+    fn write(&self, ino: Ino, _offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        send(ino as SocketId, buf).map_err(fs_error)
+    }
+
+    fn seekable(&self) -> bool {
+        false
+    }
+
+    fn retain(&self, ino: Ino) {
+        with_socket(ino as SocketId, |socket| socket.refs += 1);
+    }
+
+    /// Frees the socket with its last descriptor, closing its connection or listener
+    fn release(&self, ino: Ino) {
+        let socket = SOCKETS.lock_irqsafe(|sockets| {
+            let slot = sockets.get_mut(ino as SocketId)?;
+            let socket = slot.as_mut()?;
+            socket.refs -= 1;
+            if socket.refs == 0 { slot.take() } else { None }
+        });
+        // Outside of the table's lock: closing takes the TCP lock
+        drop(socket);
+    }
+}
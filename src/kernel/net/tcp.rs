@@ -0,0 +1,1170 @@
+//! TCP
+//!
+//! Reliable byte streams for kernel tasks: `connect` opens a connection, `listen` binds a port
+//! whose incoming connections `TcpListener::accept` hands out, and a `TcpStream` exchanges bytes
+//! with `send` and `recv`, which sleep until they can make progress. Dropping a stream closes it;
+//! the connection finishes closing in the background. User tasks get the same connections as
+//! file descriptors through `socket`.
+//!
+//! `echo_task` is the echo service (RFC 862) on port 7, serving one connection at a time.
+//!
+//! ## Design
+//!
+//! - Connections live in a fixed table, each with a send and a receive buffer of its own. The
+//!   send buffer holds the bytes from the oldest unacknowledged one on, retransmissions being
+//!   built from it; received bytes wait in the receive buffer for `recv`, and the window
+//!   advertised is the room left in it.
+//! - Only in-order segments are accepted: anything else is answered with an ACK for what is
+//!   expected and dropped, for the peer to send again. Segments carrying data or a FIN are
+//!   acknowledged right away (no delayed ACKs), and data goes out as soon as it is queued (no
+//!   Nagle).
+//! - The oldest unacknowledged segment is sent again when the retransmission timeout expires,
+//!   and after every ACK until the peer has caught up. The timeout starts at `RTO_INITIAL_MS`
+//!   and doubles at each attempt; the connection is reset after `MAX_RETRIES` of them. The
+//!   same timer probes a zero window and ends TIME-WAIT. Timers are checked every `TICK_MS`,
+//!   from deferred work started by an hrtimer, while any is pending.
+//! - A SYN for a listening port creates a connection in SYN-RECEIVED, which `accept` hands out
+//!   once established; at most `BACKLOG` wait per listener, further SYNs being dropped.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux measures the round-trip time (RFC 6298), keeps out-of-order segments, runs congestion
+//! control and implements window scaling, timestamps, SACK, delayed ACKs and Nagle; here the
+//! peer's window is the only limit on what is sent. TIME-WAIT lasts `TIME_WAIT_MS` rather than
+//! twice the maximum segment lifetime, and there is no SYN cookie or simultaneous open.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::irq::softirq;
+use crate::kernel::random;
+use crate::kernel::signal;
+use crate::kernel::time::{clocksource, hrtimer};
+use crate::pr_err;
+
+use super::{Ipv4Addr, MTU, NetError, PktBuf, ipv4, route};
+
+/// Size of a header without options
+pub const HEADER_LEN: usize = 20;
+
+/// Largest segment payload received, announced in the SYN
+pub const MSS: usize = MTU - ipv4::HEADER_LEN - HEADER_LEN;
+
+/// Largest segment payload sent to a peer that doesn't announce its own (RFC 9293)
+const DEFAULT_MSS: usize = 536;
+
+/// Maximum number of connections, closing ones included
+const MAX_CONNECTIONS: usize = 8;
+
+/// Maximum number of listening ports
+const MAX_LISTENERS: usize = 4;
+
+/// Connections waiting to be accepted, per listener
+const BACKLOG: usize = 2;
+
+/// Size of the send and receive buffers of a connection
+const BUFFER_SIZE: usize = 4096;
+
+/// Retransmission timeout of a new connection, and its upper bound
+const RTO_INITIAL_MS: u64 = 1000;
+const RTO_MAX_MS: u64 = 16000;
+
+/// Retransmissions of a segment before the connection is reset
+const MAX_RETRIES: u32 = 6;
+
+/// Time spent in TIME-WAIT
+const TIME_WAIT_MS: u64 = 1000;
+
+/// Period at which the timers are checked
+const TICK_MS: u64 = 100;
+
+/// Range of the ports picked by `connect` and `listen(0)`
+const EPHEMERAL_PORTS: core::ops::Range<u16> = 49152..65535;
+
+/// Port of the echo service
+const ECHO_PORT: u16 = 7;
+
+/// Header flags
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// Option kinds; the MSS option is the only one sent
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+const OPTION_MSS_LEN: usize = 4;
+
+/// Returns true if sequence number `a` comes before `b`
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Connection states (RFC 9293), `Closed` for a connection only kept for its owner
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Closed,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+impl State {
+    /// Returns true once our FIN is sent
+    fn fin_sent(self) -> bool {
+        matches!(
+            self,
+            State::FinWait1 | State::FinWait2 | State::Closing | State::LastAck | State::TimeWait
+        )
+    }
+
+    /// Returns true while the connection accepts data from the peer
+    fn can_receive(self) -> bool {
+        matches!(self, State::Established | State::FinWait1 | State::FinWait2)
+    }
+
+    /// Returns true while the connection accepts data from its owner
+    fn can_send(self) -> bool {
+        matches!(self, State::Established | State::CloseWait)
+    }
+}
+
+/// A FIFO of bytes
+struct ByteBuffer {
+    data: [u8; BUFFER_SIZE],
+    /// `len` bytes starting at `head`
+    head: usize,
+    len: usize,
+}
+
+impl ByteBuffer {
+    const fn new() -> Self {
+        Self {
+            data: [0; BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn free(&self) -> usize {
+        BUFFER_SIZE - self.len
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Appends as much of `bytes` as fits, returning how many
+    fn push(&mut self, bytes: &[u8]) -> usize {
+        let len = bytes.len().min(self.free());
+        for (i, &byte) in bytes[..len].iter().enumerate() {
+            self.data[(self.head + self.len + i) % BUFFER_SIZE] = byte;
+        }
+        self.len += len;
+        len
+    }
+
+    /// Copies the bytes from `offset` on into `buf`, returning how many
+    fn peek(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.len.saturating_sub(offset));
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.data[(self.head + offset + i) % BUFFER_SIZE];
+        }
+        len
+    }
+
+    /// Removes `len` bytes from the front
+    fn consume(&mut self, len: usize) {
+        let len = len.min(self.len);
+        self.head = (self.head + len) % BUFFER_SIZE;
+        self.len -= len;
+    }
+}
+
+/// What a received segment says, its payload aside
+struct Segment {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    /// The peer's MSS option, in a SYN
+    mss: Option<u16>,
+}
+
+/// A connection and its buffers
+struct Connection {
+    /// False if the slot is free
+    in_use: bool,
+    /// True while a `TcpStream` refers to the connection
+    owned: bool,
+    /// Listener the connection came in on, until `accept` hands it out
+    listener: Option<usize>,
+    state: State,
+    local_addr: Ipv4Addr,
+    local_port: u16,
+    remote_addr: Ipv4Addr,
+    remote_port: u16,
+    /// Send sequence: our initial number, oldest unacknowledged, next to send
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    /// Window and MSS of the peer
+    snd_wnd: usize,
+    mss: usize,
+    /// The owner has nothing more to send: a FIN follows the send buffer
+    fin_queued: bool,
+    /// Bytes from `snd_una` on, sent or not
+    send: ByteBuffer,
+    /// Next sequence number expected from the peer
+    rcv_nxt: u32,
+    recv: ByteBuffer,
+    rto_ms: u64,
+    retries: u32,
+    /// `snd_nxt` when the timer last expired, until everything up to it is acknowledged
+    recovery: Option<u32>,
+    /// `clocksource::now_ns` value at which the timer expires, if it runs
+    deadline: Option<u64>,
+    /// Why the connection ended, for its owner
+    error: Option<NetError>,
+}
+
+impl Connection {
+    const fn new() -> Self {
+        Self {
+            in_use: false,
+            owned: false,
+            listener: None,
+            state: State::Closed,
+            local_addr: Ipv4Addr::UNSPECIFIED,
+            local_port: 0,
+            remote_addr: Ipv4Addr::UNSPECIFIED,
+            remote_port: 0,
+            iss: 0,
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_wnd: 0,
+            mss: DEFAULT_MSS,
+            fin_queued: false,
+            send: ByteBuffer::new(),
+            rcv_nxt: 0,
+            recv: ByteBuffer::new(),
+            rto_ms: RTO_INITIAL_MS,
+            retries: 0,
+            recovery: None,
+            deadline: None,
+            error: None,
+        }
+    }
+
+    /// Takes the slot for a connection from `local` to `remote`, about to send its SYN
+    ///
+    /// Fields are set one by one: a whole `Connection` is too big for a kernel stack.
+    fn open(&mut self, state: State, local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16)) {
+        let iss = random::get_random_u64() as u32;
+        self.in_use = true;
+        self.owned = false;
+        self.listener = None;
+        self.state = state;
+        (self.local_addr, self.local_port) = local;
+        (self.remote_addr, self.remote_port) = remote;
+        self.iss = iss;
+        self.snd_una = iss;
+        self.snd_nxt = iss.wrapping_add(1);
+        self.snd_wnd = 0;
+        self.mss = DEFAULT_MSS;
+        self.fin_queued = false;
+        self.send.clear();
+        self.rcv_nxt = 0;
+        self.recv.clear();
+        self.rto_ms = RTO_INITIAL_MS;
+        self.retries = 0;
+        self.recovery = None;
+        self.deadline = None;
+        self.error = None;
+    }
+
+    fn matches(&self, seg: &Segment) -> bool {
+        self.in_use
+            && self.state != State::Closed
+            && self.local_port == seg.dst_port
+            && self.remote_port == seg.src_port
+            && self.remote_addr == seg.src
+    }
+
+    /// Window to advertise
+    fn window(&self) -> u16 {
+        self.recv.free().min(u16::MAX as usize) as u16
+    }
+
+    /// Sends a segment with `flags` and sequence number `seq`, carrying `len` bytes of the
+    /// send buffer from `offset` on
+    ///
+    /// A segment that can't be sent is left to the retransmission timer.
+    fn send_segment(&self, flags: u8, seq: u32, offset: usize, len: usize) {
+        let Some(mut payload) = PktBuf::alloc() else {
+            return;
+        };
+        let Some(data) = payload.put(len) else {
+            return;
+        };
+        self.send.peek(offset, data);
+        let ack = if flags & ACK != 0 { self.rcv_nxt } else { 0 };
+        let header = Header {
+            src_port: self.local_port,
+            dst_port: self.remote_port,
+            seq,
+            ack,
+            flags,
+            window: self.window(),
+        };
+        let _ = transmit(self.local_addr, self.remote_addr, &header, payload);
+    }
+
+    fn send_ack(&self) {
+        self.send_segment(ACK, self.snd_nxt, 0, 0);
+    }
+
+    /// Starts the retransmission timer, unless it runs
+    fn arm(&mut self) {
+        if self.deadline.is_none() {
+            self.deadline = Some(clocksource::now_ns() + self.rto_ms * 1_000_000);
+        }
+    }
+
+    /// Ends the connection; the slot is freed unless a stream still refers to it
+    fn finish(&mut self) {
+        self.state = State::Closed;
+        self.deadline = None;
+        if !self.owned {
+            self.in_use = false;
+        }
+    }
+
+    /// Ends the connection on `error`, dropping the buffered data
+    fn reset(&mut self, error: NetError) {
+        self.error = Some(error);
+        self.send.clear();
+        self.recv.clear();
+        self.finish();
+    }
+
+    /// Resets the connection on our side, telling the peer
+    fn abort(&mut self, error: NetError) {
+        if !matches!(self.state, State::Closed | State::SynSent | State::TimeWait) {
+            self.send_segment(RST | ACK, self.snd_nxt, 0, 0);
+        }
+        self.reset(error);
+    }
+
+    /// Sends what the window allows of the queued data, then the FIN if it is queued
+    fn output(&mut self) {
+        if !self.state.can_send() {
+            return;
+        }
+        let mut sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        loop {
+            let len = (self.send.len - sent)
+                .min(self.snd_wnd.saturating_sub(sent))
+                .min(self.mss);
+            if len == 0 {
+                break;
+            }
+            self.send_segment(ACK | PSH, self.snd_nxt, sent, len);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+            sent += len;
+        }
+        if self.fin_queued && sent == self.send.len {
+            self.send_segment(FIN | ACK, self.snd_nxt, 0, 0);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.state = match self.state {
+                State::Established => State::FinWait1,
+                _ => State::LastAck,
+            };
+        }
+        // Unacknowledged data, or data the window holds back
+        if self.snd_nxt != self.snd_una || self.send.len > sent {
+            self.arm();
+        }
+    }
+
+    /// Sends the oldest unacknowledged segment again, or a byte past a zero window
+    fn retransmit(&mut self) {
+        match self.state {
+            State::SynSent => self.send_syn(SYN),
+            State::SynReceived => self.send_syn(SYN | ACK),
+            _ => {
+                let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+                let fin_in_flight = self.state.fin_sent() && in_flight != 0;
+                let data = in_flight - fin_in_flight as usize;
+                let len = data.min(self.mss);
+                if len != 0 {
+                    let fin = if len == data && fin_in_flight { FIN } else { 0 };
+                    self.send_segment(ACK | PSH | fin, self.snd_una, 0, len);
+                } else if fin_in_flight {
+                    self.send_segment(FIN | ACK, self.snd_una, 0, 0);
+                } else if self.send.len != 0 && self.state.can_send() {
+                    // The window is closed: probe it with the next byte
+                    self.send_segment(ACK | PSH, self.snd_nxt, 0, 1);
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                }
+            }
+        }
+    }
+
+    /// Sends our SYN, with the MSS option
+    fn send_syn(&self, flags: u8) {
+        let Some(mut options) = PktBuf::alloc() else {
+            return;
+        };
+        let Some(option) = options.put(OPTION_MSS_LEN) else {
+            return;
+        };
+        option[0] = OPTION_MSS;
+        option[1] = OPTION_MSS_LEN as u8;
+        option[2..4].copy_from_slice(&(MSS as u16).to_be_bytes());
+        let header = Header {
+            src_port: self.local_port,
+            dst_port: self.remote_port,
+            seq: self.iss,
+            ack: if flags & ACK != 0 { self.rcv_nxt } else { 0 },
+            flags,
+            window: self.window(),
+        };
+        let _ = transmit_with_options(self.local_addr, self.remote_addr, &header, options, 1);
+    }
+
+    /// Handles the expiry of the timer
+    fn timeout(&mut self) {
+        self.deadline = None;
+        if self.state == State::TimeWait {
+            self.finish();
+            return;
+        }
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.abort(NetError::TimedOut);
+            return;
+        }
+        self.rto_ms = (self.rto_ms * 2).min(RTO_MAX_MS);
+        self.recovery = Some(self.snd_nxt);
+        self.retransmit();
+        self.arm();
+    }
+
+    /// Handles a segment for this connection
+    fn input(&mut self, seg: &Segment, payload: &[u8]) {
+        if self.state == State::SynSent {
+            self.input_syn_sent(seg);
+            return;
+        }
+        if seg.flags & RST != 0 {
+            // Only a reset at the expected sequence number is believed (RFC 5961)
+            if seg.seq == self.rcv_nxt {
+                self.reset(NetError::Reset);
+            }
+            return;
+        }
+        if seg.flags & SYN != 0 {
+            // Our SYN-ACK was lost; anything else is answered with a challenge ACK (RFC 5961)
+            if self.state == State::SynReceived && seg.seq.wrapping_add(1) == self.rcv_nxt {
+                self.send_syn(SYN | ACK);
+            } else {
+                self.send_ack();
+            }
+            return;
+        }
+        if seg.flags & ACK == 0 {
+            return;
+        }
+        if seg.seq != self.rcv_nxt {
+            // Out of order, or already received: say what is expected
+            if !payload.is_empty() || seg.flags & FIN != 0 {
+                self.send_ack();
+            }
+            return;
+        }
+
+        if self.state == State::SynReceived {
+            if seg.ack != self.snd_nxt {
+                send_reset(seg, 0);
+                return;
+            }
+            self.snd_una = seg.ack;
+            self.state = State::Established;
+            self.deadline = None;
+            self.retries = 0;
+        } else if seq_lt(self.snd_nxt, seg.ack) {
+            // Acknowledges something never sent
+            self.send_ack();
+            return;
+        } else if seq_lt(self.snd_una, seg.ack) {
+            self.acknowledged(seg.ack);
+            if self.state == State::Closed {
+                return;
+            }
+        } else if seq_lt(seg.ack, self.snd_una) {
+            // An old duplicate: its window is stale too
+            return;
+        }
+        self.snd_wnd = seg.window as usize;
+
+        if !payload.is_empty() && !self.owned && self.listener.is_none() {
+            // Nobody will ever read it
+            self.abort(NetError::NotConnected);
+            return;
+        }
+        let mut need_ack = false;
+        let mut accepted = 0;
+        if !payload.is_empty() && self.state.can_receive() {
+            accepted = self.recv.push(payload);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted as u32);
+            need_ack = true;
+        }
+        // The FIN only counts once everything before it is in
+        if seg.flags & FIN != 0 && accepted == payload.len() && self.state.can_receive() {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            need_ack = true;
+            self.state = match self.state {
+                State::Established => State::CloseWait,
+                State::FinWait1 => State::Closing,
+                _ => State::TimeWait,
+            };
+            if self.state == State::TimeWait {
+                self.enter_time_wait();
+            }
+        }
+        if need_ack {
+            self.send_ack();
+        }
+        self.output();
+    }
+
+    /// Handles a segment answering our SYN
+    fn input_syn_sent(&mut self, seg: &Segment) {
+        let ack_ok = seg.flags & ACK != 0 && seg.ack == self.snd_nxt;
+        if seg.flags & ACK != 0 && !ack_ok {
+            if seg.flags & RST == 0 {
+                send_reset(seg, 0);
+            }
+            return;
+        }
+        if seg.flags & RST != 0 {
+            if ack_ok {
+                self.reset(NetError::Refused);
+            }
+            return;
+        }
+        if seg.flags & SYN == 0 || !ack_ok {
+            return;
+        }
+        self.rcv_nxt = seg.seq.wrapping_add(1);
+        self.snd_una = seg.ack;
+        self.snd_wnd = seg.window as usize;
+        self.mss = seg.mss.map_or(DEFAULT_MSS, |mss| mss as usize).min(MSS);
+        self.state = State::Established;
+        self.deadline = None;
+        self.retries = 0;
+        self.rto_ms = RTO_INITIAL_MS;
+        self.send_ack();
+        self.output();
+    }
+
+    /// Takes the bytes up to `ack` (past `snd_una`, not past `snd_nxt`) as received by the peer
+    fn acknowledged(&mut self, ack: u32) {
+        let acked = ack.wrapping_sub(self.snd_una) as usize;
+        self.send.consume(acked);
+        self.snd_una = ack;
+        self.retries = 0;
+        self.rto_ms = RTO_INITIAL_MS;
+        self.deadline = None;
+        if let Some(end) = self.recovery {
+            if seq_lt(ack, end) {
+                // Whatever followed the retransmitted segment may be lost as well
+                self.retransmit();
+            } else {
+                self.recovery = None;
+            }
+        }
+        if self.snd_una != self.snd_nxt {
+            self.arm();
+            return;
+        }
+        if !self.state.fin_sent() {
+            return;
+        }
+        // The FIN is acknowledged
+        match self.state {
+            State::FinWait1 => self.state = State::FinWait2,
+            State::Closing => self.enter_time_wait(),
+            State::LastAck => self.finish(),
+            _ => {}
+        }
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.deadline = Some(clocksource::now_ns() + TIME_WAIT_MS * 1_000_000);
+    }
+
+    /// The owner is done with the connection
+    fn close(&mut self) {
+        self.owned = false;
+        self.recv.clear();
+        match self.state {
+            State::Closed => self.in_use = false,
+            State::SynSent => self.finish(),
+            State::SynReceived | State::Established | State::CloseWait => {
+                self.fin_queued = true;
+                self.output();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Fields of a header to send
+struct Header {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+}
+
+/// One's complement sum of the pseudo-header covered by the checksum
+fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, len: usize) -> u32 {
+    let sum = ipv4::checksum_add(0, &src.0);
+    ipv4::checksum_add(sum, &dst.0) + ipv4::PROTO_TCP as u32 + len as u32
+}
+
+/// Sends `payload` from `src` to `dst` with `header` pushed in front of it
+fn transmit(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    header: &Header,
+    payload: PktBuf,
+) -> Result<(), NetError> {
+    transmit_with_options(src, dst, header, payload, 0)
+}
+
+/// Sends `segment` from `src` to `dst` with `header` pushed in front of it, the first
+/// `option_words` 32-bit words of `segment` being options
+fn transmit_with_options(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    header: &Header,
+    mut segment: PktBuf,
+    option_words: usize,
+) -> Result<(), NetError> {
+    let len = HEADER_LEN + segment.len();
+    // The header's length is even, so the rest can be summed on its own
+    let sum = ipv4::checksum_add(pseudo_header_sum(src, dst, len), segment.data());
+    let bytes = segment.push(HEADER_LEN).ok_or(NetError::NoBuffer)?;
+    bytes[0..2].copy_from_slice(&header.src_port.to_be_bytes());
+    bytes[2..4].copy_from_slice(&header.dst_port.to_be_bytes());
+    bytes[4..8].copy_from_slice(&header.seq.to_be_bytes());
+    bytes[8..12].copy_from_slice(&header.ack.to_be_bytes());
+    bytes[12] = ((HEADER_LEN / 4 + option_words) as u8) << 4;
+    bytes[13] = header.flags;
+    bytes[14..16].copy_from_slice(&header.window.to_be_bytes());
+    bytes[16..20].fill(0);
+    let sum = ipv4::checksum_finish(ipv4::checksum_add(sum, bytes));
+    bytes[16..18].copy_from_slice(&sum.to_be_bytes());
+    ipv4::send(dst, ipv4::PROTO_TCP, segment)
+}
+
+/// Answers `seg`, `len` bytes of payload, with a reset (RFC 9293, "Reset Generation")
+fn send_reset(seg: &Segment, len: usize) {
+    let Some(payload) = PktBuf::alloc() else {
+        return;
+    };
+    let header = if seg.flags & ACK != 0 {
+        Header {
+            src_port: seg.dst_port,
+            dst_port: seg.src_port,
+            seq: seg.ack,
+            ack: 0,
+            flags: RST,
+            window: 0,
+        }
+    } else {
+        let syn_fin = (seg.flags & SYN != 0) as u32 + (seg.flags & FIN != 0) as u32;
+        Header {
+            src_port: seg.dst_port,
+            dst_port: seg.src_port,
+            seq: 0,
+            ack: seg.seq.wrapping_add(len as u32 + syn_fin),
+            flags: RST | ACK,
+            window: 0,
+        }
+    };
+    let _ = transmit(seg.dst, seg.src, &header, payload);
+}
+
+/// Returns the value of the MSS option among `options`
+fn parse_mss(options: &[u8]) -> Option<u16> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            OPTION_END => break,
+            OPTION_NOP => i += 1,
+            kind => {
+                let len = *options.get(i + 1)? as usize;
+                if len < 2 || i + len > options.len() {
+                    return None;
+                }
+                if kind == OPTION_MSS && len == OPTION_MSS_LEN {
+                    return Some(u16::from_be_bytes([options[i + 2], options[i + 3]]));
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
+struct Tcp {
+    connections: [Connection; MAX_CONNECTIONS],
+    /// Listening ports, 0 if the slot is free
+    listeners: [u16; MAX_LISTENERS],
+    /// Next port tried for an ephemeral port
+    next_ephemeral: u16,
+    /// Whether the hrtimer checking the timers is pending
+    timer_running: bool,
+}
+
+impl Tcp {
+    fn port_in_use(&self, port: u16) -> bool {
+        self.listeners.contains(&port)
+            || self
+                .connections
+                .iter()
+                .any(|c| c.in_use && c.local_port == port)
+    }
+
+    /// Returns a free ephemeral port
+    fn ephemeral_port(&mut self) -> u16 {
+        // There are far more ephemeral ports than connections, so a free one is close
+        let mut port = self.next_ephemeral;
+        while self.port_in_use(port) {
+            port = next_ephemeral(port);
+        }
+        self.next_ephemeral = next_ephemeral(port);
+        port
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.connections.iter().position(|c| !c.in_use)
+    }
+
+    /// Handles a received segment, returning the connection it concerned
+    fn input(&mut self, seg: &Segment, payload: &[u8]) -> Option<usize> {
+        if let Some(slot) = self.connections.iter().position(|c| c.matches(seg)) {
+            self.connections[slot].input(seg, payload);
+            return Some(slot);
+        }
+        if seg.flags & RST != 0 {
+            return None;
+        }
+        let listener = self.listeners.iter().position(|&port| port == seg.dst_port);
+        if let Some(listener) = listener
+            && seg.flags & (SYN | ACK) == SYN
+        {
+            let waiting = self
+                .connections
+                .iter()
+                .filter(|c| c.in_use && c.listener == Some(listener))
+                .count();
+            // A SYN that doesn't fit is dropped: the peer tries again
+            let slot = self.free_slot().filter(|_| waiting < BACKLOG)?;
+            let conn = &mut self.connections[slot];
+            conn.open(
+                State::SynReceived,
+                (seg.dst, seg.dst_port),
+                (seg.src, seg.src_port),
+            );
+            conn.listener = Some(listener);
+            conn.rcv_nxt = seg.seq.wrapping_add(1);
+            conn.snd_wnd = seg.window as usize;
+            conn.mss = seg.mss.map_or(DEFAULT_MSS, |mss| mss as usize).min(MSS);
+            conn.send_syn(SYN | ACK);
+            conn.arm();
+            return Some(slot);
+        }
+        send_reset(seg, payload.len());
+        None
+    }
+
+    /// Makes sure the timers are checked while any is pending
+    fn update_timer(&mut self) {
+        if self.timer_running
+            || !self
+                .connections
+                .iter()
+                .any(|c| c.in_use && c.deadline.is_some())
+        {
+            return;
+        }
+        self.timer_running = hrtimer::start(TICK_MS * 1_000_000, timer_expired, 0).is_ok();
+    }
+}
+
+/// Returns the ephemeral port following `port`
+fn next_ephemeral(port: u16) -> u16 {
+    if port + 1 == EPHEMERAL_PORTS.end {
+        EPHEMERAL_PORTS.start
+    } else {
+        port + 1
+    }
+}
+
+static TCP: Mutex<Tcp> = Mutex::new(Tcp {
+    connections: [const { Connection::new() }; MAX_CONNECTIONS],
+    listeners: [0; MAX_LISTENERS],
+    next_ephemeral: EPHEMERAL_PORTS.start,
+    timer_running: false,
+});
+
+/// Woken when the connection in the same slot changes: data, acknowledgement or state
+static WAITERS: [WaitQueue; MAX_CONNECTIONS] = [const { WaitQueue::new() }; MAX_CONNECTIONS];
+
+/// Woken when a connection to the listener in the same slot is established
+static LISTEN_WAITERS: [WaitQueue; MAX_LISTENERS] = [const { WaitQueue::new() }; MAX_LISTENERS];
+
+/// Runs `f` on the connection table, then starts the timer if `f` armed one
+fn with_tcp<R>(f: impl FnOnce(&mut Tcp) -> R) -> R {
+    TCP.lock_irqsafe(|tcp| {
+        let result = f(tcp);
+        tcp.update_timer();
+        result
+    })
+}
+
+/// Wakes whoever waits on the connection in `slot`, or to accept it
+fn wake(slot: usize) {
+    WAITERS[slot].wake_up();
+    if let Some(listener) = TCP.lock_irqsafe(|tcp| tcp.connections[slot].listener) {
+        LISTEN_WAITERS[listener].wake_up();
+    }
+}
+
+/// Timer interrupt: the timers are checked by deferred work
+fn timer_expired(_data: usize) {
+    if softirq::schedule_work(timer_work, 0).is_err() {
+        // Try again at the next tick
+        let _ = hrtimer::start(TICK_MS * 1_000_000, timer_expired, 0);
+    }
+}
+
+/// Handles the expired timers
+fn timer_work(_arg: usize) {
+    let now = clocksource::now_ns();
+    let mut expired = [false; MAX_CONNECTIONS];
+    with_tcp(|tcp| {
+        tcp.timer_running = false;
+        for (slot, conn) in tcp.connections.iter_mut().enumerate() {
+            if conn.in_use && conn.deadline.is_some_and(|deadline| deadline <= now) {
+                conn.timeout();
+                expired[slot] = true;
+            }
+        }
+    });
+    for (slot, _) in expired.iter().enumerate().filter(|(_, expired)| **expired) {
+        wake(slot);
+    }
+}
+
+/// Handles a TCP segment sent from `src` to `dst`
+pub fn receive(src: Ipv4Addr, dst: Ipv4Addr, mut segment: PktBuf) {
+    let data = segment.data();
+    if data.len() < HEADER_LEN || dst == Ipv4Addr::BROADCAST {
+        return;
+    }
+    let header_len = (data[12] >> 4) as usize * 4;
+    if header_len < HEADER_LEN || header_len > data.len() {
+        return;
+    }
+    let sum = pseudo_header_sum(src, dst, data.len());
+    if ipv4::checksum_finish(ipv4::checksum_add(sum, data)) != 0 {
+        return;
+    }
+    let seg = Segment {
+        src,
+        dst,
+        src_port: u16::from_be_bytes([data[0], data[1]]),
+        dst_port: u16::from_be_bytes([data[2], data[3]]),
+        seq: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        ack: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+        flags: data[13],
+        window: u16::from_be_bytes([data[14], data[15]]),
+        mss: parse_mss(&data[HEADER_LEN..header_len]),
+    };
+    segment.pull(header_len);
+    if let Some(slot) = with_tcp(|tcp| tcp.input(&seg, segment.data())) {
+        wake(slot);
+    }
+}
+
+/// A TCP connection
+pub struct TcpStream {
+    /// Slot in the connection table
+    slot: usize,
+}
+
+/// A listening TCP port
+pub struct TcpListener {
+    /// Slot in the listener table
+    slot: usize,
+    port: u16,
+}
+
+/// Connects to `port` on `dst`, sleeping until the connection is established
+pub fn connect(dst: Ipv4Addr, port: u16) -> Result<TcpStream, NetError> {
+    let src = route(dst).ok_or(NetError::NoRoute)?.src;
+    let slot = with_tcp(|tcp| {
+        let slot = tcp.free_slot().ok_or(NetError::NoSpace)?;
+        let local_port = tcp.ephemeral_port();
+        let conn = &mut tcp.connections[slot];
+        conn.open(State::SynSent, (src, local_port), (dst, port));
+        conn.owned = true;
+        conn.send_syn(SYN);
+        conn.arm();
+        Ok(slot)
+    })?;
+    // Dropped on failure, which frees the slot
+    let stream = TcpStream { slot };
+    let mut state = State::SynSent;
+    WAITERS[slot].wait_event(|| {
+        state = TCP.lock_irqsafe(|tcp| tcp.connections[slot].state);
+        state != State::SynSent || signal::has_pending()
+    });
+    match state {
+        State::SynSent => Err(NetError::Interrupted),
+        State::Closed => Err(TCP
+            .lock_irqsafe(|tcp| tcp.connections[slot].error)
+            .unwrap_or(NetError::Refused)),
+        _ => Ok(stream),
+    }
+}
+
+/// Listens on `port`, or on a free ephemeral port if it is 0
+pub fn listen(port: u16) -> Result<TcpListener, NetError> {
+    TCP.lock_irqsafe(|tcp| {
+        let port = match port {
+            0 => tcp.ephemeral_port(),
+            port if tcp.listeners.contains(&port) => return Err(NetError::AddrInUse),
+            port => port,
+        };
+        let slot = tcp
+            .listeners
+            .iter()
+            .position(|&p| p == 0)
+            .ok_or(NetError::NoSpace)?;
+        tcp.listeners[slot] = port;
+        Ok(TcpListener { slot, port })
+    })
+}
+
+impl TcpListener {
+    /// Returns the local port
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sleeps until a connection is established, then returns it
+    pub fn accept(&self) -> Result<TcpStream, NetError> {
+        accept(self.slot)
+    }
+
+    /// Returns the slot of the listener, for `socket`
+    pub(super) fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+/// Sleeps until a connection to the listener in `slot` is established, then returns it
+pub(super) fn accept(slot: usize) -> Result<TcpStream, NetError> {
+    let mut accepted = None;
+    LISTEN_WAITERS[slot].wait_event(|| {
+        accepted = TCP.lock_irqsafe(|tcp| {
+            let index = tcp.connections.iter().position(|c| {
+                c.in_use && c.listener == Some(slot) && c.state != State::SynReceived
+            })?;
+            let conn = &mut tcp.connections[index];
+            conn.listener = None;
+            conn.owned = true;
+            Some(index)
+        });
+        accepted.is_some() || signal::has_pending()
+    });
+    accepted
+        .map(|slot| TcpStream { slot })
+        .ok_or(NetError::Interrupted)
+}
+
+impl Drop for TcpListener {
+    /// Stops listening, resetting the connections not accepted yet
+    fn drop(&mut self) {
+        with_tcp(|tcp| {
+            tcp.listeners[self.slot] = 0;
+            for conn in tcp.connections.iter_mut() {
+                if conn.in_use && conn.listener == Some(self.slot) {
+                    conn.listener = None;
+                    conn.abort(NetError::Reset);
+                }
+            }
+        });
+    }
+}
+
+impl TcpStream {
+    /// Returns the address and port of the peer
+    pub fn peer(&self) -> (Ipv4Addr, u16) {
+        peer(self.slot)
+    }
+
+    /// Returns the local port
+    pub fn local_port(&self) -> u16 {
+        TCP.lock_irqsafe(|tcp| tcp.connections[self.slot].local_port)
+    }
+
+    /// Queues `data` for sending, sleeping while the send buffer is full
+    ///
+    /// Returns the number of bytes queued: all of them, unless a signal interrupts the wait.
+    pub fn send(&self, data: &[u8]) -> Result<usize, NetError> {
+        send(self.slot, data)
+    }
+
+    /// Sleeps until data arrives, then copies as much as fits into `buf`
+    ///
+    /// Returns the number of bytes copied, 0 once the peer has closed its side.
+    pub fn recv(&self, buf: &mut [u8]) -> Result<usize, NetError> {
+        recv(self.slot, buf)
+    }
+
+    /// Closes the sending side: the peer reads the end of the stream once it has the data
+    pub fn shutdown(&self) {
+        shutdown(self.slot);
+    }
+
+    /// Returns the slot of the connection, for `socket`
+    pub(super) fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        with_tcp(|tcp| tcp.connections[self.slot].close());
+    }
+}
+
+/// Returns the address and port of the peer of the connection in `slot`
+pub(super) fn peer(slot: usize) -> (Ipv4Addr, u16) {
+    TCP.lock_irqsafe(|tcp| {
+        let conn = &tcp.connections[slot];
+        (conn.remote_addr, conn.remote_port)
+    })
+}
+
+/// Queues `data` on the connection in `slot`, see `TcpStream::send`
+pub(super) fn send(slot: usize, data: &[u8]) -> Result<usize, NetError> {
+    let mut done = 0;
+    while done < data.len() {
+        let mut result = Ok(0);
+        WAITERS[slot].wait_event(|| {
+            result = with_tcp(|tcp| {
+                let conn = &mut tcp.connections[slot];
+                if !conn.state.can_send() || conn.fin_queued {
+                    return Err(conn.error.unwrap_or(NetError::NotConnected));
+                }
+                let len = conn.send.push(&data[done..]);
+                conn.output();
+                Ok(len)
+            });
+            !matches!(result, Ok(0)) || signal::has_pending()
+        });
+        match result {
+            Ok(0) if done == 0 => return Err(NetError::Interrupted),
+            Ok(0) => break,
+            Ok(len) => done += len,
+            Err(e) if done == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(done)
+}
+
+/// Receives from the connection in `slot`, see `TcpStream::recv`
+pub(super) fn recv(slot: usize, buf: &mut [u8]) -> Result<usize, NetError> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    let mut result = None;
+    WAITERS[slot].wait_event(|| {
+        result = with_tcp(|tcp| {
+            let conn = &mut tcp.connections[slot];
+            let window = conn.recv.free();
+            let len = conn.recv.peek(0, buf);
+            if len != 0 {
+                conn.recv.consume(len);
+                // Tell the peer when a window too small for a segment opens up
+                if window < conn.mss && conn.recv.free() >= conn.mss && conn.state.can_receive() {
+                    conn.send_ack();
+                }
+                return Some(Ok(len));
+            }
+            match conn.state {
+                State::SynSent | State::SynReceived => None,
+                _ if conn.state.can_receive() => None,
+                _ => Some(conn.error.map_or(Ok(0), Err)),
+            }
+        });
+        result.is_some() || signal::has_pending()
+    });
+    result.unwrap_or(Err(NetError::Interrupted))
+}
+
+/// Closes the sending side of the connection in `slot`
+pub(super) fn shutdown(slot: usize) {
+    with_tcp(|tcp| {
+        let conn = &mut tcp.connections[slot];
+        conn.fin_queued = true;
+        conn.output();
+    });
+}
+
+/// The echo service
+pub fn echo_task(_arg: usize) {
+    let listener = match listen(ECHO_PORT) {
+        Ok(listener) => listener,
+        Err(e) => {
+            pr_err!("tcp: cannot listen on the echo port: {:?}", e);
+            return;
+        }
+    };
+    let mut buf = [0u8; MSS];
+    loop {
+        let Ok(stream) = listener.accept() else {
+            continue;
+        };
+        while let Ok(len) = stream.recv(&mut buf) {
+            if len == 0 || stream.send(&buf[..len]).is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! Telnet console
+//!
+//! A telnet server (RFC 854) on port 23 giving remote access to the system console. While a
+//! client is connected, the network console `netcon0` is the mirror of the active console (see
+//! `console`): the kernel log and the shell's output go to the client, and what the client types
+//! reaches the shell, Ctrl-C included. One session is served at a time; further clients wait in
+//! the listen backlog.
+//!
+//! ## Design
+//!
+//! - `netcon0` is registered as a virtual console, so it can also be made the active console
+//!   with `console=netcon0`; it is then not mirrored, the shell reading it directly.
+//! - Output is queued in a ring by `putchar`, which never sleeps or takes a lock it would wait
+//!   for, since it runs wherever the kernel prints, interrupt handlers and panics included. Bytes
+//!   are dropped while no client is connected or the ring is full. A transmit task per session
+//!   polls the ring every `FLUSH_MS` and sends it, newlines turned into CR LF as the network
+//!   virtual terminal wants.
+//! - The server task receives: it strips the protocol's commands and feeds the data to the
+//!   console's input channel, dropping bytes while the channel is full, as the keyboard does.
+//! - The only options negotiated are the server echoing (ECHO) and character-at-a-time input
+//!   (SUPPRESS-GO-AHEAD), which put clients in the raw mode the line editor expects; every other
+//!   option the client asks for is refused.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux has no telnet server: `telnetd` runs in user space on a pseudo-terminal. The closest
+//! in-kernel feature is netconsole, which sends the kernel log over UDP, output only.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::ipc::channel::Channel;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::console::{self, Console, INPUT_QUEUE_SIZE, InputReceiver};
+use crate::kernel::notifier::Deadline;
+use crate::kernel::sched;
+use crate::{pr_err, pr_info};
+
+use super::tcp::{self, TcpStream};
+
+/// Port the server listens on
+const TELNET_PORT: u16 = 23;
+
+/// Name of the network console
+const NAME: &str = "netcon0";
+
+/// Size of the output ring
+const OUTPUT_SIZE: usize = 4096;
+
+/// Interval at which the transmit task sends queued output
+const FLUSH_MS: u32 = 20;
+
+/* --- Commands --- */
+const SE: u8 = 240;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+/* --- Options --- */
+const OPT_ECHO: u8 = 1;
+const OPT_SGA: u8 = 3;
+
+/// Output waiting to be sent, `len` bytes starting at `head`
+struct Ring {
+    buf: [u8; OUTPUT_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Ring {
+    /// Appends `c`, returning false if the ring is full
+    fn push(&mut self, c: u8) -> bool {
+        if self.len == OUTPUT_SIZE {
+            return false;
+        }
+        self.buf[(self.head + self.len) % OUTPUT_SIZE] = c;
+        self.len += 1;
+        true
+    }
+
+    /// Removes the oldest `buf.len()` bytes at most into `buf`, returning how many
+    fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let len = self.len.min(buf.len());
+        for (i, c) in buf[..len].iter_mut().enumerate() {
+            *c = self.buf[(self.head + i) % OUTPUT_SIZE];
+        }
+        self.head = (self.head + len) % OUTPUT_SIZE;
+        self.len -= len;
+        len
+    }
+}
+
+/// The network console
+pub struct NetConsole {
+    output: Mutex<Ring>,
+    input: Channel<u8, INPUT_QUEUE_SIZE>,
+    /// Set while a client is connected
+    connected: AtomicBool,
+}
+
+/// `netcon0`
+pub static NETCON: NetConsole = NetConsole {
+    output: Mutex::new(Ring {
+        buf: [0; OUTPUT_SIZE],
+        head: 0,
+        len: 0,
+    }),
+    input: Channel::new(),
+    connected: AtomicBool::new(false),
+};
+
+impl Console for NetConsole {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    /// Queues `c` for the client; dropped if there is none, the ring is full or its lock is
+    /// held, e.g. by the code that was interrupted
+    fn putchar(&self, c: u8) {
+        if self.connected.load(Ordering::Acquire) {
+            let _ = self.output.try_lock_irqsafe(|ring| ring.push(c));
+        }
+    }
+
+    fn getchar(&self) -> Option<u8> {
+        self.input.receiver()?.try_recv()
+    }
+
+    fn input(&'static self) -> Option<InputReceiver> {
+        self.input.receiver()
+    }
+
+    fn flush(&self, deadline: &Deadline) -> bool {
+        while self.connected.load(Ordering::Acquire)
+            && self.output.lock_irqsafe(|ring| ring.len) != 0
+        {
+            if deadline.expired() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Sends the output ring to the connection in `slot`, for as long as a client is connected
+fn tx_task(slot: usize) {
+    let mut chunk = [0u8; 256];
+    // Room for every byte of `chunk` doubling
+    let mut out = [0u8; 512];
+    while NETCON.connected.load(Ordering::Acquire) {
+        let len = NETCON.output.lock_irqsafe(|ring| ring.pop(&mut chunk));
+        if len == 0 {
+            sched::sleep_ms(FLUSH_MS);
+            continue;
+        }
+        let mut n = 0;
+        for &c in &chunk[..len] {
+            match c {
+                b'\n' => {
+                    out[n..n + 2].copy_from_slice(b"\r\n");
+                    n += 2;
+                }
+                IAC => {
+                    out[n..n + 2].copy_from_slice(&[IAC, IAC]);
+                    n += 2;
+                }
+                _ => {
+                    out[n] = c;
+                    n += 1;
+                }
+            }
+        }
+        // Fails once the connection is closing; the server ends the session then
+        let _ = tcp::send(slot, &out[..n]);
+    }
+}
+
+/// Where the receiver is in the protocol's command stream
+#[derive(Clone, Copy)]
+enum Parser {
+    Data,
+    /// After a CR, which is followed by a NUL or LF to be dropped
+    Cr,
+    /// After IAC
+    Iac,
+    /// After IAC and the negotiation command
+    Option(u8),
+    /// Within a subnegotiation, which is skipped
+    Sub,
+    /// After IAC within a subnegotiation
+    SubIac,
+}
+
+/// Receives from the client and feeds the console input until the connection closes
+fn serve(stream: &TcpStream) {
+    let Some(mut sender) = NETCON.input.sender() else {
+        pr_err!("telnet: the input channel is claimed");
+        return;
+    };
+    let mut parser = Parser::Data;
+    let mut buf = [0u8; 256];
+    while let Ok(len) = stream.recv(&mut buf) {
+        if len == 0 {
+            break;
+        }
+        for &c in &buf[..len] {
+            parser = match (parser, c) {
+                (Parser::Data | Parser::Cr, IAC) => Parser::Iac,
+                (Parser::Cr, b'\0' | b'\n') => Parser::Data,
+                (Parser::Data | Parser::Cr, c) => {
+                    if !console::intercept(NAME, c) && sender.try_send(c).is_ok() {
+                        console::mirror_input_ready();
+                    }
+                    if c == b'\r' { Parser::Cr } else { Parser::Data }
+                }
+                // An escaped 0xff
+                (Parser::Iac, IAC) => {
+                    if sender.try_send(IAC).is_ok() {
+                        console::mirror_input_ready();
+                    }
+                    Parser::Data
+                }
+                (Parser::Iac, WILL | WONT | DO | DONT) => Parser::Option(c),
+                (Parser::Iac, SB) => Parser::Sub,
+                (Parser::Iac, _) => Parser::Data,
+                (Parser::Option(command), option) => {
+                    let reply = match command {
+                        DO if option != OPT_ECHO && option != OPT_SGA => Some(WONT),
+                        WILL => Some(DONT),
+                        _ => None,
+                    };
+                    if let Some(reply) = reply {
+                        let _ = stream.send(&[IAC, reply, option]);
+                    }
+                    Parser::Data
+                }
+                (Parser::Sub, IAC) => Parser::SubIac,
+                (Parser::Sub, _) => Parser::Sub,
+                (Parser::SubIac, SE) => Parser::Data,
+                (Parser::SubIac, _) => Parser::Sub,
+            };
+        }
+    }
+}
+
+/// Runs a session with the client on `stream`
+fn session(stream: TcpStream) {
+    let (addr, port) = stream.peer();
+    pr_info!("telnet: session from {}:{}", addr, port);
+    let _ = stream.send(&[IAC, WILL, OPT_ECHO, IAC, WILL, OPT_SGA]);
+    NETCON.output.lock_irqsafe(|ring| ring.len = 0);
+    NETCON.connected.store(true, Ordering::Release);
+    let mirrored = console::set_mirror(&NETCON).is_ok();
+    match sched::spawn_joinable("telnet-tx", tx_task, stream.slot()) {
+        Ok(tx) => {
+            serve(&stream);
+            if mirrored {
+                console::clear_mirror(&NETCON);
+            }
+            NETCON.connected.store(false, Ordering::Release);
+            stream.shutdown();
+            let _ = sched::join(tx);
+        }
+        Err(e) => {
+            pr_err!("telnet: cannot start the transmit task: {:?}", e);
+            if mirrored {
+                console::clear_mirror(&NETCON);
+            }
+            NETCON.connected.store(false, Ordering::Release);
+        }
+    }
+    pr_info!("telnet: session from {}:{} ended", addr, port);
+}
+
+/// The telnet server
+pub fn server_task(_arg: usize) {
+    if let Err(e) = console::register_virtual(&NETCON) {
+        pr_err!("telnet: cannot register {}: {:?}", NAME, e);
+        return;
+    }
+    let listener = match tcp::listen(TELNET_PORT) {
+        Ok(listener) => listener,
+        Err(e) => {
+            pr_err!("telnet: cannot listen on port {}: {:?}", TELNET_PORT, e);
+            return;
+        }
+    };
+    loop {
+        if let Ok(stream) = listener.accept() {
+            session(stream);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! UDP
+//!
+//! Kernel tasks `bind` a `UdpSocket` to a port, then exchange datagrams with `send_to` and
+//! `recv_from`, which sleeps until a datagram arrives. Each socket queues up to `QUEUE_LEN`
+//! received datagrams; further ones are dropped until the owner catches up, as are datagrams
+//! for unbound ports. Dropping the socket unbinds the port.
+//!
+//! A queued datagram is the packet buffer it arrived in, its payload left after the headers were
+//! pulled. `recv_packet` and `send_packet` exchange such buffers without copying the payload;
+//! `recv_from` and `send_to` copy it from and to the caller's memory.
+//!
+//! `echo_task` is the echo service (RFC 862): it sends every datagram received on port 7 back
+//! to where it came from, in the buffer it came in.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::pr_err;
+
+use super::{Ipv4Addr, MTU, NetError, PktBuf, Route, ipv4, route};
+
+/// Size of the header
+pub const HEADER_LEN: usize = 8;
+
+/// Largest payload of a datagram that fits in a packet
+pub const MAX_PAYLOAD: usize = MTU - ipv4::HEADER_LEN - HEADER_LEN;
+
+/// Maximum number of bound sockets
+const MAX_SOCKETS: usize = 8;
+
+/// Received datagrams queued per socket
+const QUEUE_LEN: usize = 4;
+
+/// Range of the ports picked by `bind(0)`
+const EPHEMERAL_PORTS: core::ops::Range<u16> = 49152..65535;
+
+/// Port of the echo service
+const ECHO_PORT: u16 = 7;
+
+/// A received datagram
+struct Datagram {
+    src: Ipv4Addr,
+    port: u16,
+    /// The payload
+    payload: PktBuf,
+}
+
+/// A bound port and the datagrams received on it
+struct Socket {
+    /// Local port, 0 if the slot is free
+    port: u16,
+    /// `count` datagrams starting at `head`
+    queue: [Option<Datagram>; QUEUE_LEN],
+    head: usize,
+    count: usize,
+}
+
+struct Sockets {
+    sockets: [Socket; MAX_SOCKETS],
+    /// Next port tried by `bind(0)`
+    next_ephemeral: u16,
+}
+
+static SOCKETS: Mutex<Sockets> = Mutex::new(Sockets {
+    sockets: [const {
+        Socket {
+            port: 0,
+            queue: [const { None }; QUEUE_LEN],
+            head: 0,
+            count: 0,
+        }
+    }; MAX_SOCKETS],
+    next_ephemeral: EPHEMERAL_PORTS.start,
+});
+
+/// Woken when a datagram is queued on the socket in the same slot
+static WAITERS: [WaitQueue; MAX_SOCKETS] = [const { WaitQueue::new() }; MAX_SOCKETS];
+
+/// A bound UDP port
+pub struct UdpSocket {
+    /// Slot in `SOCKETS`
+    slot: usize,
+    port: u16,
+}
+
+/// Returns the ephemeral port following `port`
+fn next_ephemeral(port: u16) -> u16 {
+    if port + 1 == EPHEMERAL_PORTS.end {
+        EPHEMERAL_PORTS.start
+    } else {
+        port + 1
+    }
+}
+
+/// Binds `port`, or a free ephemeral port if it is 0
+pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+    SOCKETS.lock_irqsafe(|s| {
+        let in_use = |sockets: &[Socket], port| sockets.iter().any(|s| s.port == port);
+        let port = if port != 0 {
+            if in_use(&s.sockets, port) {
+                return Err(NetError::AddrInUse);
+            }
+            port
+        } else {
+            // There are far more ephemeral ports than sockets, so a free one is close
+            let mut port = s.next_ephemeral;
+            while in_use(&s.sockets, port) {
+                port = next_ephemeral(port);
+            }
+            s.next_ephemeral = next_ephemeral(port);
+            port
+        };
+        let slot = s
+            .sockets
+            .iter()
+            .position(|s| s.port == 0)
+            .ok_or(NetError::NoSpace)?;
+        let socket = &mut s.sockets[slot];
+        socket.port = port;
+        socket.head = 0;
+        socket.count = 0;
+        Ok(UdpSocket { slot, port })
+    })
+}
+
+/// One's complement sum of the pseudo-header covered by the checksum
+fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, len: usize) -> u32 {
+    let sum = ipv4::checksum_add(0, &src.0);
+    ipv4::checksum_add(sum, &dst.0) + ipv4::PROTO_UDP as u32 + len as u32
+}
+
+impl UdpSocket {
+    /// Returns the local port
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Sends `data` to `port` on `dst`
+    pub fn send_to(&self, data: &[u8], dst: Ipv4Addr, port: u16) -> Result<(), NetError> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(NetError::TooBig);
+        }
+        let payload = PktBuf::from_slice(data).ok_or(NetError::NoBuffer)?;
+        self.send_packet(payload, dst, port)
+    }
+
+    /// Sends `payload` to `port` on `dst`, pushing the headers in front of it
+    pub fn send_packet(&self, payload: PktBuf, dst: Ipv4Addr, port: u16) -> Result<(), NetError> {
+        let route = route(dst).ok_or(NetError::NoRoute)?;
+        self.send_packet_via(route, payload, dst, port)
+    }
+
+    /// Sends `payload` to `port` on `dst` along `route`, see `ipv4::send_via`
+    pub fn send_packet_via(
+        &self,
+        route: Route,
+        mut payload: PktBuf,
+        dst: Ipv4Addr,
+        port: u16,
+    ) -> Result<(), NetError> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(NetError::TooBig);
+        }
+        let src = route.src;
+        let len = HEADER_LEN + payload.len();
+        // The header's length is even, so the payload can be summed on its own
+        let sum = ipv4::checksum_add(pseudo_header_sum(src, dst, len), payload.data());
+        let header = payload.push(HEADER_LEN).ok_or(NetError::NoBuffer)?;
+        header[0..2].copy_from_slice(&self.port.to_be_bytes());
+        header[2..4].copy_from_slice(&port.to_be_bytes());
+        header[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+        header[6..8].fill(0);
+        let sum = match ipv4::checksum_finish(ipv4::checksum_add(sum, header)) {
+            // 0 means "no checksum", so a computed 0 is sent as its other representation
+            0 => 0xffff,
+            sum => sum,
+        };
+        header[6..8].copy_from_slice(&sum.to_be_bytes());
+        ipv4::send_via(route, dst, ipv4::PROTO_UDP, payload)
+    }
+
+    /// Takes the oldest queued datagram
+    fn dequeue(&self) -> Option<Datagram> {
+        SOCKETS.lock_irqsafe(|s| {
+            let socket = &mut s.sockets[self.slot];
+            if socket.count == 0 {
+                return None;
+            }
+            let datagram = socket.queue[socket.head].take();
+            socket.head = (socket.head + 1) % QUEUE_LEN;
+            socket.count -= 1;
+            datagram
+        })
+    }
+
+    /// Sleeps until a datagram arrives, then returns its payload with the address and port it
+    /// came from
+    pub fn recv_packet(&self) -> (PktBuf, Ipv4Addr, u16) {
+        let mut received = None;
+        WAITERS[self.slot].wait_event(|| {
+            received = self.dequeue();
+            received.is_some()
+        });
+        // `wait_event` only returns once the condition holds
+        let datagram = received.expect("udp: woken without a datagram");
+        (datagram.payload, datagram.src, datagram.port)
+    }
+
+    /// Like `recv_packet`, but gives up when the counter reaches `expires` (see
+    /// `waitqueue::deadline_ms`), returning `None`
+    pub fn recv_packet_until(&self, expires: u64) -> Option<(PktBuf, Ipv4Addr, u16)> {
+        let mut received = None;
+        let _ = WAITERS[self.slot].wait_event_until(expires, || {
+            received = self.dequeue();
+            received.is_some()
+        });
+        received.map(|datagram| (datagram.payload, datagram.src, datagram.port))
+    }
+
+    /// Sleeps until a datagram arrives, then copies it into `buf`
+    ///
+    /// Returns the length of the datagram, truncated to `buf.len()`, with the address and port
+    /// it came from.
+    pub fn recv_from(&self, buf: &mut [u8]) -> (usize, Ipv4Addr, u16) {
+        let (payload, src, port) = self.recv_packet();
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload.data()[..len]);
+        (len, src, port)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock_irqsafe(|s| {
+            let socket = &mut s.sockets[self.slot];
+            socket.port = 0;
+            // Unread datagrams give their buffers back to the pool
+            socket
+                .queue
+                .iter_mut()
+                .for_each(|datagram| *datagram = None);
+            socket.count = 0;
+        });
+    }
+}
+
+/// Handles a UDP datagram sent from `src` to `dst`
+pub fn receive(src: Ipv4Addr, dst: Ipv4Addr, mut datagram: PktBuf) {
+    let data = datagram.data();
+    if data.len() < HEADER_LEN {
+        return;
+    }
+    let src_port = u16::from_be_bytes([data[0], data[1]]);
+    let dst_port = u16::from_be_bytes([data[2], data[3]]);
+    let len = u16::from_be_bytes([data[4], data[5]]) as usize;
+    let sum = u16::from_be_bytes([data[6], data[7]]);
+    if len < HEADER_LEN || len > data.len() || len > HEADER_LEN + MAX_PAYLOAD || dst_port == 0 {
+        return;
+    }
+    if sum != 0 {
+        let sum = pseudo_header_sum(src, dst, len);
+        if ipv4::checksum_finish(ipv4::checksum_add(sum, &data[..len])) != 0 {
+            return;
+        }
+    }
+    datagram.trim(len);
+    datagram.pull(HEADER_LEN);
+
+    let slot = SOCKETS.lock_irqsafe(|s| {
+        let slot = s.sockets.iter().position(|s| s.port == dst_port)?;
+        let socket = &mut s.sockets[slot];
+        if socket.count == QUEUE_LEN {
+            return None;
+        }
+        socket.queue[(socket.head + socket.count) % QUEUE_LEN] = Some(Datagram {
+            src,
+            port: src_port,
+            payload: datagram,
+        });
+        socket.count += 1;
+        Some(slot)
+    });
+    if let Some(slot) = slot {
+        WAITERS[slot].wake_up();
+    }
+}
+
+/// The echo service
+pub fn echo_task(_arg: usize) {
+    let socket = match bind(ECHO_PORT) {
+        Ok(socket) => socket,
+        Err(e) => {
+            pr_err!("udp: cannot bind the echo port: {:?}", e);
+            return;
+        }
+    };
+    loop {
+        let (payload, src, port) = socket.recv_packet();
+        let _ = socket.send_packet(payload, src, port);
+    }
+}
@@ -0,0 +1,176 @@
+//! Notifier chains
+//!
+//! A notifier chain is a priority-ordered list of callbacks that subsystems register with to be
+//! told about a system-wide event. The chain owner decides when the event happens and calls
+//! `call_chain`; every registered block is then invoked in turn, highest priority first.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux has several flavours (atomic, blocking, raw, SRCU) built around a linked list of
+//! `struct notifier_block`. We only need one flavour, and the chain is a fixed-size table of
+//! `Copy` blocks protected by the IRQ-safe `Mutex` rather than a list on the heap: consoles
+//! register before the heap is set up, and the panic path calls the chain without allocating.
+//!
+//! ## Timeouts
+//!
+//! Chains are called from paths that must make forward progress (reboot, panic), so each callback
+//! receives a `Deadline`. Callbacks that poll hardware (e.g. waiting for a FIFO to drain) are
+//! expected to give up once it expires and return `NotifyResult::Timeout`. The chain also checks
+//! the deadline after every callback and reports the ones that overran.
+
+use crate::drivers::timer::arch_timer;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::println;
+
+/// Value returned by a notifier callback
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NotifyResult {
+    /// The callback finished its work
+    Ok,
+    /// The callback gave up because its deadline expired
+    Timeout,
+    /// The callback finished and no lower-priority block must be called
+    Stop,
+}
+
+/// Errors returned when (un)registering a notifier block
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NotifierError {
+    /// The chain has no free slots left
+    Full,
+    /// No block with the given name is registered
+    NotFound,
+}
+
+/// A point in time, expressed in generic timer counter ticks, after which work must stop
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline {
+    /// Counter value (`CNTPCT_EL0`) at which the deadline expires
+    expires: u64,
+}
+
+impl Deadline {
+    /// Creates a deadline `ms` milliseconds from now
+    pub fn from_ms(ms: u32) -> Self {
+        let ticks = (arch_timer::get_frequency() / 1000) * ms as u64;
+        Self {
+            expires: arch_timer::get_counter().saturating_add(ticks),
+        }
+    }
+
+    /// Returns true once the deadline has passed
+    pub fn expired(&self) -> bool {
+        arch_timer::get_counter() >= self.expires
+    }
+}
+
+/// A single entry in a notifier chain
+///
+/// `E` is the event type of the chain the block is registered with (e.g. `power::RebootEvent`).
+pub struct NotifierBlock<E: Copy> {
+    /// Name used for diagnostics and to unregister the block
+    pub name: &'static str,
+    /// Blocks with higher priority are called first
+    pub priority: i32,
+    /// Callback invoked when the chain is called
+    pub notifier_call: fn(E, &Deadline) -> NotifyResult,
+}
+
+impl<E: Copy> Clone for NotifierBlock<E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<E: Copy> Copy for NotifierBlock<E> {}
+
+/// A priority-ordered chain of notifier blocks with room for `N` entries
+pub struct NotifierChain<E: Copy, const N: usize> {
+    /// Registered blocks, kept sorted by descending priority in `[0, count)`
+    blocks: Mutex<([Option<NotifierBlock<E>>; N], usize)>,
+}
+
+impl<E: Copy, const N: usize> NotifierChain<E, N> {
+    /// Creates an empty chain
+    pub const fn new() -> Self {
+        Self {
+            blocks: Mutex::new(([None; N], 0)),
+        }
+    }
+
+    /// Registers `block`, keeping the chain sorted by priority
+    ///
+    /// Blocks with the same priority are called in registration order.
+    pub fn register(&self, block: NotifierBlock<E>) -> Result<(), NotifierError> {
+        self.blocks.lock_irqsafe(|(blocks, count)| {
+            if *count == N {
+                return Err(NotifierError::Full);
+            }
+            let mut pos = *count;
+            while pos > 0 && blocks[pos - 1].is_some_and(|b| b.priority < block.priority) {
+                blocks[pos] = blocks[pos - 1];
+                pos -= 1;
+            }
+            blocks[pos] = Some(block);
+            *count += 1;
+            Ok(())
+        })
+    }
+
+    /// Removes the block registered under `name`
+    pub fn unregister(&self, name: &str) -> Result<(), NotifierError> {
+        self.blocks.lock_irqsafe(|(blocks, count)| {
+            let pos = blocks[..*count]
+                .iter()
+                .position(|b| b.is_some_and(|b| b.name == name))
+                .ok_or(NotifierError::NotFound)?;
+            blocks.copy_within(pos + 1..*count, pos);
+            *count -= 1;
+            blocks[*count] = None;
+            Ok(())
+        })
+    }
+
+    /// Calls every registered block with `event`, giving each one `timeout_ms` to finish
+    ///
+    /// The table is copied out of the lock before calling the blocks, so callbacks are free to
+    /// (un)register blocks or take other locks. Returns the number of blocks that timed out.
+    pub fn call_chain(&self, event: E, timeout_ms: u32) -> usize {
+        let snapshot = self.blocks.lock_irqsafe(|(blocks, _)| *blocks);
+        Self::run(&snapshot, event, timeout_ms)
+    }
+
+    /// Like `call_chain`, but gives up instead of spinning if the chain lock is held
+    ///
+    /// Used from the panic path, where the lock holder may be the code that panicked.
+    pub fn try_call_chain(&self, event: E, timeout_ms: u32) -> Option<usize> {
+        let snapshot = self.blocks.try_lock_irqsafe(|(blocks, _)| *blocks)?;
+        Some(Self::run(&snapshot, event, timeout_ms))
+    }
+
+    /// Walks a snapshot of the chain, enforcing the per-block deadline
+    fn run(blocks: &[Option<NotifierBlock<E>>; N], event: E, timeout_ms: u32) -> usize {
+        let mut timeouts = 0;
+        for block in blocks.iter().flatten() {
+            let deadline = Deadline::from_ms(timeout_ms);
+            let result = (block.notifier_call)(event, &deadline);
+            if result == NotifyResult::Timeout || deadline.expired() {
+                println!(
+                    "notifier: {} did not finish within {}ms",
+                    block.name, timeout_ms
+                );
+                timeouts += 1;
+            }
+            if result == NotifyResult::Stop {
+                break;
+            }
+        }
+        timeouts
+    }
+}
+
+impl<E: Copy, const N: usize> Default for NotifierChain<E, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,399 @@
+//! Performance counters
+//!
+//! The PMU (Performance Monitors Unit) counts CPU cycles in `PMCCNTR_EL0` and architectural or
+//! implementation defined events (cache refills, mispredicted branches, ...) in up to 31
+//! programmable event counters. This module keeps the cycle counter running from `init` on and
+//! hands out the event counters, so code paths can be benchmarked in the kernel:
+//!
+//! ```ignore
+//! let (_, cycles) = perf::measure(|| sched::yield_now());
+//! let (_, m) = perf::measure_event(perf::Event::L1DCacheRefill, || block::read(dev, 0, &mut buf))?;
+//! ```
+//!
+//! A counter can also raise the PMU interrupt every `period` events (`set_overflow`), which is
+//! what sampling profilers build on.
+//!
+//! ## Design
+//!
+//! - Event counters are reached through `PMSELR_EL0` and the `PMXEV*` registers, so the counter
+//!   number doesn't need to be encoded in the instruction.
+//! - Event counters are 32-bit: an overflow period is programmed by starting the counter at
+//!   `2^32 - period`, and reloading it the same way when the interrupt fires.
+//! - The cycle counter is 64-bit (`PMCR_EL0.LC`) and never overflows in practice, it isn't handed
+//!   out.
+//! - Counters are per CPU and count whatever runs, at EL0 and EL1: a measurement spanning a
+//!   context switch includes the other tasks.
+//!
+//! The overflow interrupt is the PPI of the `arm,armv8-pmuv3` DTB node.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux exposes the PMU through `perf_event_open` and the `perf_event` core, with per-task
+//! counters multiplexed on the hardware ones; this is closer to the raw `armv8pmu_*` helpers of
+//! `drivers/perf/arm_pmuv3.c`.
+
+use core::arch::asm;
+
+use crate::drivers::gic;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::device::{self, ProbeError};
+use crate::kernel::irq;
+use crate::kernel::irq::of::{IrqKind, of_irq_parse};
+use crate::{initcall, println};
+
+/// Maximum number of event counters the architecture allows
+const MAX_COUNTERS: usize = 31;
+
+/// PMCR_EL0 bits
+const PMCR_E: u64 = 1 << 0; // Enable all counters
+const PMCR_P: u64 = 1 << 1; // Reset the event counters
+const PMCR_C: u64 = 1 << 2; // Reset the cycle counter
+const PMCR_LC: u64 = 1 << 6; // 64-bit cycle counter overflow
+const PMCR_N_SHIFT: u64 = 11;
+
+/// Bit of the cycle counter in `PMCNTENSET_EL0` and friends
+const CYCLE_COUNTER_BIT: u64 = 1 << 31;
+
+/// Architectural and common microarchitectural events
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    /// Level 1 instruction cache refill
+    L1ICacheRefill,
+    /// Level 1 data cache refill
+    L1DCacheRefill,
+    /// Level 1 data cache access
+    L1DCache,
+    /// Instruction architecturally executed
+    InstRetired,
+    /// Exception taken
+    ExceptionTaken,
+    /// Mispredicted or not predicted branch
+    BranchMispredict,
+    /// Predictable branch speculatively executed
+    BranchPredicted,
+    /// Level 2 data cache refill
+    L2DCacheRefill,
+    /// Last level cache miss, read
+    LlCacheMissRead,
+    /// Any event number, as listed in the CPU's manual
+    Raw(u16),
+}
+
+impl Event {
+    /// Event number for `PMEVTYPER<n>_EL0.evtCount`
+    fn number(self) -> u16 {
+        match self {
+            Event::L1ICacheRefill => 0x01,
+            Event::L1DCacheRefill => 0x03,
+            Event::L1DCache => 0x04,
+            Event::InstRetired => 0x08,
+            Event::ExceptionTaken => 0x09,
+            Event::BranchMispredict => 0x10,
+            Event::BranchPredicted => 0x12,
+            Event::L2DCacheRefill => 0x17,
+            Event::LlCacheMissRead => 0x37,
+            Event::Raw(number) => number,
+        }
+    }
+}
+
+/// Errors returned by the performance counter functions
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PerfError {
+    /// The CPU has no PMU, or `init` hasn't run
+    NoPmu,
+    /// Every event counter is in use
+    NoCounter,
+    /// The counter isn't allocated
+    NotFound,
+    /// The overflow period is 0, or no interrupt was found in the DTB
+    BadPeriod,
+}
+
+/// Callback run when an event counter overflows, with the counter number
+///
+/// Runs in interrupt context, after the counter has been reloaded.
+pub type OverflowHandler = fn(counter: usize);
+
+/// An allocated event counter
+#[derive(Clone, Copy)]
+struct Counter {
+    event: Event,
+    /// Overflow period and callback, if the overflow interrupt is enabled
+    overflow: Option<(u32, OverflowHandler)>,
+}
+
+/// State of the PMU
+struct PerfState {
+    counters: [Option<Counter>; MAX_COUNTERS],
+    /// Number of event counters implemented by the CPU, 0 without a PMU
+    num_counters: usize,
+    /// Overflow interrupt (INTID), 0 if none was found in the DTB
+    irq: u32,
+}
+
+static STATE: Mutex<PerfState> = Mutex::new(PerfState {
+    counters: [None; MAX_COUNTERS],
+    num_counters: 0,
+    irq: 0,
+});
+
+/// Expands to a read of the system register `$reg`
+///
+/// Not `nomem`: the accesses must stay in program order with the code being measured.
+macro_rules! read_pmu_reg {
+    ($reg:literal) => {{
+        let value: u64;
+        unsafe {
+            asm!(
+                concat!("mrs {}, ", $reg),
+                out(reg) value,
+                options(nostack, preserves_flags)
+            )
+        };
+        value
+    }};
+}
+
+/// Expands to a write of `$value` to the system register `$reg`
+macro_rules! write_pmu_reg {
+    ($reg:literal, $value:expr) => {
+        unsafe {
+            asm!(
+                concat!("msr ", $reg, ", {}"),
+                in(reg) $value as u64,
+                options(nostack, preserves_flags)
+            )
+        }
+    };
+}
+
+/// Selects event counter `n` for the `PMXEV*` registers
+fn select(n: usize) {
+    write_pmu_reg!("pmselr_el0", n);
+    unsafe { asm!("isb", options(nostack, nomem, preserves_flags)) };
+}
+
+/// Writes the value of event counter `n`
+fn write_counter(n: usize, value: u32) {
+    select(n);
+    write_pmu_reg!("pmxevcntr_el0", value);
+}
+
+/// Discovers the PMU, resets the counters and starts the cycle counter
+///
+/// Does nothing on a CPU without a PMU; `cycles` then returns 0.
+pub fn init() {
+    let dfr0 = read_pmu_reg!("id_aa64dfr0_el1");
+    // PMUVer: 0 is no PMU, 0xf an IMPLEMENTATION DEFINED one
+    let version = (dfr0 >> 8) & 0xf;
+    if version == 0 || version == 0xf {
+        println!("perf: no PMUv3");
+        return;
+    }
+    // Counters are handed out explicitly, everything stays disabled until then
+    write_pmu_reg!("pmcntenclr_el0", u32::MAX);
+    write_pmu_reg!("pmintenclr_el1", u32::MAX);
+    write_pmu_reg!("pmovsclr_el0", u32::MAX);
+    let pmcr = read_pmu_reg!("pmcr_el0");
+    let num_counters = ((pmcr >> PMCR_N_SHIFT) & 0x1f) as usize;
+    // Count cycles at EL0 and EL1
+    write_pmu_reg!("pmccfiltr_el0", 0);
+    write_pmu_reg!("pmcr_el0", PMCR_E | PMCR_P | PMCR_C | PMCR_LC);
+    write_pmu_reg!("pmcntenset_el0", CYCLE_COUNTER_BIT);
+    unsafe { asm!("isb", options(nostack, nomem, preserves_flags)) };
+    STATE.lock_irqsafe(|state| state.num_counters = num_counters);
+    println!("perf: PMUv3, {} event counters", num_counters);
+}
+initcall!(Driver, "perf", init);
+
+/// Returns the number of CPU cycles counted since `init`
+pub fn cycles() -> u64 {
+    read_pmu_reg!("pmccntr_el0")
+}
+
+/// Allocates an event counter counting `event`, starting from 0
+///
+/// Returns the counter number to pass to the other functions.
+pub fn alloc_counter(event: Event) -> Result<usize, PerfError> {
+    STATE.lock_irqsafe(|state| {
+        if state.num_counters == 0 {
+            return Err(PerfError::NoPmu);
+        }
+        let n = (0..state.num_counters)
+            .find(|&n| state.counters[n].is_none())
+            .ok_or(PerfError::NoCounter)?;
+        state.counters[n] = Some(Counter {
+            event,
+            overflow: None,
+        });
+        select(n);
+        // Count at EL0 and EL1
+        write_pmu_reg!("pmxevtyper_el0", event.number());
+        write_pmu_reg!("pmxevcntr_el0", 0);
+        write_pmu_reg!("pmcntenset_el0", 1u64 << n);
+        Ok(n)
+    })
+}
+
+/// Stops and frees an event counter
+pub fn free_counter(n: usize) -> Result<(), PerfError> {
+    STATE.lock_irqsafe(|state| {
+        let slot = state.counters.get_mut(n).ok_or(PerfError::NotFound)?;
+        slot.take().ok_or(PerfError::NotFound)?;
+        write_pmu_reg!("pmcntenclr_el0", 1u64 << n);
+        write_pmu_reg!("pmintenclr_el1", 1u64 << n);
+        write_pmu_reg!("pmovsclr_el0", 1u64 << n);
+        Ok(())
+    })
+}
+
+/// Returns the event counted by counter `n`
+pub fn counter_event(n: usize) -> Option<Event> {
+    STATE.lock_irqsafe(|state| state.counters.get(n).copied().flatten().map(|c| c.event))
+}
+
+/// Returns the value of event counter `n`
+///
+/// With an overflow period set, this is the count since the last overflow plus `2^32 - period`.
+pub fn read_counter(n: usize) -> Result<u32, PerfError> {
+    STATE.lock_irqsafe(|state| {
+        if state.counters.get(n).copied().flatten().is_none() {
+            return Err(PerfError::NotFound);
+        }
+        select(n);
+        Ok(read_pmu_reg!("pmxevcntr_el0") as u32)
+    })
+}
+
+/// Resets event counter `n` to 0, or to the start of its overflow period
+pub fn reset_counter(n: usize) -> Result<(), PerfError> {
+    STATE.lock_irqsafe(|state| {
+        let counter = state
+            .counters
+            .get(n)
+            .copied()
+            .flatten()
+            .ok_or(PerfError::NotFound)?;
+        let start = counter
+            .overflow
+            .map_or(0, |(period, _)| 0u32.wrapping_sub(period));
+        write_counter(n, start);
+        Ok(())
+    })
+}
+
+/// Has `handler` called every `period` events counted by counter `n`
+///
+/// The counter restarts its period now.
+pub fn set_overflow(n: usize, period: u32, handler: OverflowHandler) -> Result<(), PerfError> {
+    STATE.lock_irqsafe(|state| {
+        if period == 0 || state.irq == 0 {
+            return Err(PerfError::BadPeriod);
+        }
+        let counter = state
+            .counters
+            .get_mut(n)
+            .and_then(|c| c.as_mut())
+            .ok_or(PerfError::NotFound)?;
+        counter.overflow = Some((period, handler));
+        write_counter(n, 0u32.wrapping_sub(period));
+        write_pmu_reg!("pmovsclr_el0", 1u64 << n);
+        write_pmu_reg!("pmintenset_el1", 1u64 << n);
+        Ok(())
+    })
+}
+
+/// Stops the overflow interrupt of counter `n`; it keeps counting
+pub fn clear_overflow(n: usize) -> Result<(), PerfError> {
+    STATE.lock_irqsafe(|state| {
+        let counter = state
+            .counters
+            .get_mut(n)
+            .and_then(|c| c.as_mut())
+            .ok_or(PerfError::NotFound)?;
+        counter.overflow = None;
+        write_pmu_reg!("pmintenclr_el1", 1u64 << n);
+        write_pmu_reg!("pmovsclr_el0", 1u64 << n);
+        Ok(())
+    })
+}
+
+/// Runs `f` and returns its result with the number of CPU cycles it took
+pub fn measure<R>(f: impl FnOnce() -> R) -> (R, u64) {
+    let start = cycles();
+    let result = f();
+    (result, cycles().wrapping_sub(start))
+}
+
+/// Result of `measure_event`
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    /// CPU cycles
+    pub cycles: u64,
+    /// Number of events counted
+    pub count: u32,
+}
+
+/// Runs `f` and returns its result with the number of cycles it took and of `event`s it caused
+///
+/// Uses a free event counter for the duration of the call.
+pub fn measure_event<R>(
+    event: Event,
+    f: impl FnOnce() -> R,
+) -> Result<(R, Measurement), PerfError> {
+    let n = alloc_counter(event)?;
+    let (result, cycles) = measure(f);
+    let count = read_counter(n);
+    let _ = free_counter(n);
+    Ok((
+        result,
+        Measurement {
+            cycles,
+            count: count?,
+        },
+    ))
+}
+
+/// PMU interrupt handler: reloads the overflowed counters and runs their callbacks
+fn handle_irq(_id: u32, _data: usize) {
+    let overflowed = read_pmu_reg!("pmovsclr_el0") & !CYCLE_COUNTER_BIT;
+    write_pmu_reg!("pmovsclr_el0", overflowed);
+    let mut handlers: [Option<OverflowHandler>; MAX_COUNTERS] = [None; MAX_COUNTERS];
+    STATE.lock_irqsafe(|state| {
+        for n in (0..MAX_COUNTERS).filter(|n| overflowed & (1 << n) != 0) {
+            if let Some((period, handler)) = state.counters[n].and_then(|c| c.overflow) {
+                write_counter(n, 0u32.wrapping_sub(period));
+                handlers[n] = Some(handler);
+            }
+        }
+    });
+    // Called without the lock, so callbacks can use the counters
+    for (n, handler) in handlers.iter().enumerate() {
+        if let Some(handler) = handler {
+            handler(n);
+        }
+    }
+}
+
+/// Driver for `arm,armv8-pmuv3` nodes
+pub struct PmuDriver;
+
+impl device::Driver for PmuDriver {
+    /// Sets up the PMU overflow interrupt from device tree properties
+    ///
+    /// The node's first interrupt is the PMU PPI.
+    fn probe(&self, dev: &device::PlatformDevice) -> Result<(), ProbeError> {
+        let spec = of_irq_parse(dev, 0).ok_or(ProbeError::NoDevice)?;
+        if spec.kind != IrqKind::Ppi {
+            println!("perf: {} has no PPI", dev.name);
+            return Err(ProbeError::NoDevice);
+        }
+        let ppi_id = gic::configure_irq(&spec);
+        if irq::request_irq(ppi_id, "pmu", handle_irq, 0).is_ok() {
+            gic::enable_irq(ppi_id);
+            STATE.lock_irqsafe(|state| state.irq = ppi_id);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+//! System reboot and power-off
+//!
+//! Before the firmware is asked to reset or power off the machine, every subsystem holding
+//! volatile state gets a chance to write it out. Subsystems register a `NotifierBlock` on the
+//! reboot chain; `reboot`, `poweroff` and the panic handler call the chain before handing control
+//! to PSCI.
+//!
+//! ## Ordering
+//!
+//! Blocks are called by descending priority. Use the `PRIO_*` constants so that data producers
+//! (filesystems, network) are flushed before the console is drained, otherwise the messages they
+//! print on the way down would be lost.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::drivers::firmware::psci;
+use crate::kernel::notifier::{NotifierBlock, NotifierChain, NotifierError};
+use crate::{pr_err, println};
+
+/// Maximum number of blocks on the reboot chain
+const MAX_REBOOT_NOTIFIERS: usize = 16;
+
+/// Time each notifier gets on an orderly reboot or power-off
+const REBOOT_TIMEOUT_MS: u32 = 1000;
+
+/// Time each notifier gets when called from the panic handler
+const PANIC_TIMEOUT_MS: u32 = 100;
+
+/// Priority for storage (flush block cache, sync filesystems)
+pub const PRIO_STORAGE: i32 = 200;
+/// Priority for network interfaces
+pub const PRIO_NETWORK: i32 = 100;
+/// Priority for consoles; called last so every other message reaches the wire
+pub const PRIO_CONSOLE: i32 = -100;
+
+/// Reason the reboot chain is being called
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RebootEvent {
+    /// The system is about to reset
+    Restart,
+    /// The system is about to power off
+    PowerOff,
+    /// The kernel panicked; only do what is strictly needed to preserve output and data
+    Panic,
+}
+
+/// Chain called before the system resets or powers off
+static REBOOT_NOTIFIER: NotifierChain<RebootEvent, MAX_REBOOT_NOTIFIERS> = NotifierChain::new();
+
+/// Set once the panic path has called the chain, so a panic inside a notifier doesn't recurse
+static PANIC_NOTIFIED: AtomicBool = AtomicBool::new(false);
+
+/// Registers a block on the reboot chain
+pub fn register_reboot_notifier(block: NotifierBlock<RebootEvent>) -> Result<(), NotifierError> {
+    REBOOT_NOTIFIER.register(block)
+}
+
+/// Removes the block registered under `name` from the reboot chain
+pub fn unregister_reboot_notifier(name: &str) -> Result<(), NotifierError> {
+    REBOOT_NOTIFIER.unregister(name)
+}
+
+/// Flushes every subsystem and resets the system through PSCI
+pub fn reboot() -> ! {
+    println!("Rebooting...");
+    REBOOT_NOTIFIER.call_chain(RebootEvent::Restart, REBOOT_TIMEOUT_MS);
+    psci::system_reset();
+    halt("reset");
+}
+
+/// Flushes every subsystem and powers the system off through PSCI
+pub fn poweroff() -> ! {
+    println!("Powering off...");
+    REBOOT_NOTIFIER.call_chain(RebootEvent::PowerOff, REBOOT_TIMEOUT_MS);
+    psci::system_off();
+    halt("power off");
+}
+
+/// Calls the reboot chain from the panic handler
+///
+/// Only runs once: a panic raised by a notifier returns immediately. If the chain lock is held
+/// (we panicked while (un)registering a block) the chain is skipped rather than deadlocking.
+pub fn panic_notify() {
+    if PANIC_NOTIFIED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if REBOOT_NOTIFIER
+        .try_call_chain(RebootEvent::Panic, PANIC_TIMEOUT_MS)
+        .is_none()
+    {
+        println!("reboot notifier chain busy, skipping");
+    }
+}
+
+/// Parks the CPU after a firmware request that should not have returned
+fn halt(what: &str) -> ! {
+    pr_err!("PSCI {} failed, halting", what);
+    loop {
+        core::hint::spin_loop();
+    }
+}
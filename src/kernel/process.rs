@@ -0,0 +1,319 @@
+//! Processes
+//!
+//! A user task is a process: it gets a process ID (PID) when it is created, by `loader::exec` or
+//! `fork`, and is known by it to user space (`getpid`, `kill`, `wait4`, the terminal's foreground
+//! process group). The first program started, `/init`, is PID 1. A process created by `fork` is
+//! a child of the caller; one started by the kernel has no parent (PPID 0).
+//!
+//! When a process exits, its task is freed as usual (see `sched::exit`) but its entry stays in
+//! the process table as a zombie, keeping the exit status until the parent collects it with
+//! `wait`. Children of an exiting process are handed to init, or released if init is gone.
+//!
+//! ## Design
+//!
+//! - The table is behind an `RwLock`: lookups (PID to task and back, on every `getpid` or
+//!   `kill`) only take it for reading, creation, exit and `wait` for writing.
+//! - PIDs are allocated in increasing order up to `PID_MAX`, then wrap around skipping those in
+//!   use, so a PID isn't reused right after its process is collected.
+//! - `wait` sleeps on `CHILD_EXIT`, woken up by every exit; it gives up when a signal arrives.
+//! - Kernel tasks are not processes: `ps` lists them by task ID only.
+//! - The CPU time and memory of a process are those of its task (see `sched::TaskInfo`); the CPU
+//!   time is kept in the zombie.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux keeps processes and threads in `task_struct` and gives kernel threads PIDs too, with
+//! `kthreadd` (PID 2) as their parent. Here a process is always a single task. The exit status
+//! only carries the exit code: a process killed by a signal exits with 128 plus the signal
+//! number, as a shell reports it, and the parent isn't sent `SIGCHLD`. There are no process
+//! groups or sessions beyond the console's foreground process.
+
+use crate::ipc::rwlock::RwLock;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::sched::{self, MAX_TASKS, TaskId, TaskState};
+use crate::kernel::signal;
+use crate::kernel::time::clocksource::NSEC_PER_SEC;
+use crate::println;
+
+/// Identifier of a process
+pub type Pid = u32;
+
+/// PID of the first process, which inherits orphans
+pub const INIT_PID: Pid = 1;
+
+/// PIDs wrap around past this value
+pub const PID_MAX: Pid = 32768;
+
+/// Maximum number of processes, zombies included
+pub const MAX_PROCESSES: usize = 2 * MAX_TASKS;
+
+/// Errors returned by the process functions
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProcessError {
+    /// The process table is full
+    NoSpace,
+    /// No process has this PID
+    NotFound,
+    /// The caller has no child to wait for
+    NoChild,
+    /// A signal arrived while waiting
+    Interrupted,
+}
+
+/// A process
+#[derive(Clone, Copy, Debug)]
+pub struct Process {
+    pub pid: Pid,
+    /// PID of the parent, 0 if none
+    pub ppid: Pid,
+    pub name: &'static str,
+    /// Task running the process, `None` once it has exited
+    pub task: Option<TaskId>,
+    /// Exit code, valid once the process has exited
+    pub exit_code: i32,
+    /// CPU time used, in nanoseconds, recorded at exit
+    pub runtime_ns: u64,
+}
+
+impl Process {
+    pub fn is_zombie(&self) -> bool {
+        self.task.is_none()
+    }
+}
+
+/// Which children `wait` collects
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WaitFor {
+    Any,
+    Pid(Pid),
+}
+
+/// The process table
+struct ProcessTable {
+    procs: [Option<Process>; MAX_PROCESSES],
+    /// Candidate for the next PID
+    next_pid: Pid,
+}
+
+impl ProcessTable {
+    fn find(&self, pid: Pid) -> Option<&Process> {
+        self.procs.iter().flatten().find(|p| p.pid == pid)
+    }
+
+    fn find_task(&self, task: TaskId) -> Option<&Process> {
+        self.procs.iter().flatten().find(|p| p.task == Some(task))
+    }
+
+    /// Returns the first free PID from `next_pid` on
+    fn alloc_pid(&mut self) -> Pid {
+        loop {
+            let pid = self.next_pid;
+            self.next_pid = if pid >= PID_MAX { INIT_PID } else { pid + 1 };
+            if self.find(pid).is_none() {
+                return pid;
+            }
+        }
+    }
+}
+
+static PROCESSES: RwLock<ProcessTable> = RwLock::new(ProcessTable {
+    procs: [None; MAX_PROCESSES],
+    next_pid: INIT_PID,
+});
+
+/// Parents waiting in `wait`
+static CHILD_EXIT: WaitQueue = WaitQueue::new();
+
+/// Makes the new user task `task` a process, child of the running process if there is one
+///
+/// Called by the scheduler when the task is spawned, before it runs.
+pub fn create(task: TaskId, name: &'static str) -> Result<Pid, ProcessError> {
+    let parent = sched::current();
+    let mut table = PROCESSES.write_irqsafe();
+    let ppid = parent.and_then(|t| table.find_task(t)).map_or(0, |p| p.pid);
+    let index = table
+        .procs
+        .iter()
+        .position(Option::is_none)
+        .ok_or(ProcessError::NoSpace)?;
+    let pid = table.alloc_pid();
+    table.procs[index] = Some(Process {
+        pid,
+        ppid,
+        name,
+        task: Some(task),
+        exit_code: 0,
+        runtime_ns: 0,
+    });
+    Ok(pid)
+}
+
+/// Turns the process of the exiting task `task` into a zombie with exit code `code`
+///
+/// Its children go to init, or are released if init is gone. A process without a parent is
+/// released right away, no one being there to wait for it. Does nothing for kernel tasks.
+pub fn exit(task: TaskId, code: i32) {
+    let runtime_ns = sched::task_info(task).map_or(0, |info| info.runtime_ns);
+    let mut table = PROCESSES.write_irqsafe();
+    let Some(pid) = table.find_task(task).map(|p| p.pid) else {
+        return;
+    };
+    let reaper = match table.find(INIT_PID) {
+        Some(init) if pid != INIT_PID && !init.is_zombie() => INIT_PID,
+        _ => 0,
+    };
+    for slot in table.procs.iter_mut() {
+        let Some(child) = slot.as_mut().filter(|p| p.ppid == pid) else {
+            continue;
+        };
+        child.ppid = reaper;
+        if reaper == 0 && child.is_zombie() {
+            *slot = None;
+        }
+    }
+    for slot in table.procs.iter_mut() {
+        if let Some(process) = slot.as_mut().filter(|p| p.pid == pid) {
+            process.task = None;
+            process.exit_code = code;
+            process.runtime_ns = runtime_ns;
+            if process.ppid == 0 {
+                *slot = None;
+            }
+        }
+    }
+    drop(table);
+    CHILD_EXIT.wake_up();
+}
+
+/// Waits for a child of the running process matching `which` to exit, and releases it
+///
+/// Returns its PID and exit code, or `None` right away with `nohang` if no matching child has
+/// exited yet. Must not be called from interrupt context.
+pub fn wait(which: WaitFor, nohang: bool) -> Result<Option<(Pid, i32)>, ProcessError> {
+    let me = current_pid().ok_or(ProcessError::NoChild)?;
+    let matches = |p: &Process| {
+        p.ppid == me
+            && match which {
+                WaitFor::Any => true,
+                WaitFor::Pid(pid) => p.pid == pid,
+            }
+    };
+    let mut result = Ok(None);
+    CHILD_EXIT.wait_event(|| {
+        let mut table = PROCESSES.write_irqsafe();
+        let mut children = table
+            .procs
+            .iter_mut()
+            .filter(|s| s.as_ref().is_some_and(matches))
+            .peekable();
+        if children.peek().is_none() {
+            result = Err(ProcessError::NoChild);
+            return true;
+        }
+        if let Some(slot) = children.find(|s| s.as_ref().is_some_and(Process::is_zombie)) {
+            result = Ok(slot.take().map(|p| (p.pid, p.exit_code)));
+            return true;
+        }
+        drop(table);
+        result = if nohang {
+            Ok(None)
+        } else if signal::has_pending() {
+            Err(ProcessError::Interrupted)
+        } else {
+            return false;
+        };
+        true
+    });
+    result
+}
+
+/// Returns the PID of the process run by task `task`
+pub fn pid_of(task: TaskId) -> Option<Pid> {
+    PROCESSES.read_irqsafe().find_task(task).map(|p| p.pid)
+}
+
+/// Returns the task running process `pid`, `None` if it doesn't exist or has exited
+pub fn task_of(pid: Pid) -> Option<TaskId> {
+    PROCESSES.read_irqsafe().find(pid).and_then(|p| p.task)
+}
+
+/// Returns the PID of the running process, `None` for kernel tasks
+pub fn current_pid() -> Option<Pid> {
+    pid_of(sched::current()?)
+}
+
+/// Returns the PID of the parent of the running process, 0 if it has none
+pub fn current_ppid() -> Option<Pid> {
+    let task = sched::current()?;
+    PROCESSES.read_irqsafe().find_task(task).map(|p| p.ppid)
+}
+
+/// Formats a CPU time as seconds with milliseconds
+fn cpu_time(ns: u64) -> (u64, u64) {
+    (ns / NSEC_PER_SEC, ns % NSEC_PER_SEC / 1_000_000)
+}
+
+/// Prints the processes, then the kernel tasks
+pub fn dump() {
+    let mut procs = PROCESSES.read_irqsafe().procs;
+    procs.sort_unstable_by_key(|p| p.map_or(Pid::MAX, |p| p.pid));
+    println!(
+        "{:>5} {:>5} {:>4} {:<8} {:>3} {:>3} {:>10} {:>6} {:>6}  NAME",
+        "PID", "PPID", "TID", "STATE", "CLS", "PRI", "TIME", "VSZ", "RSS"
+    );
+    for process in procs.iter().flatten() {
+        let info = process.task.and_then(sched::task_info);
+        let (secs, ms) = cpu_time(info.map_or(process.runtime_ns, |i| i.runtime_ns));
+        match info {
+            Some(info) => println!(
+                "{:>5} {:>5} {:>4} {:<8} {:>3} {:>3} {:>6}.{:03} {:>5}K {:>5}K  {}",
+                process.pid,
+                process.ppid,
+                info.id,
+                info.state.as_str(),
+                info.policy.as_str(),
+                info.priority,
+                secs,
+                ms,
+                info.vsize / 1024,
+                info.rss / 1024,
+                process.name
+            ),
+            None => println!(
+                "{:>5} {:>5} {:>4} {:<8} {:>3} {:>3} {:>6}.{:03} {:>6} {:>6}  {}",
+                process.pid,
+                process.ppid,
+                "-",
+                TaskState::Zombie.as_str(),
+                "-",
+                "-",
+                secs,
+                ms,
+                "-",
+                "-",
+                process.name
+            ),
+        }
+    }
+    sched::for_each_task(|info| {
+        if info.state == TaskState::Dead || procs.iter().flatten().any(|p| p.task == Some(info.id))
+        {
+            return;
+        }
+        let (secs, ms) = cpu_time(info.runtime_ns);
+        println!(
+            "{:>5} {:>5} {:>4} {:<8} {:>3} {:>3} {:>6}.{:03} {:>6} {:>6}  [{}]",
+            "-",
+            "-",
+            info.id,
+            info.state.as_str(),
+            info.policy.as_str(),
+            info.priority,
+            secs,
+            ms,
+            "-",
+            "-",
+            info.name
+        );
+    });
+}
@@ -0,0 +1,250 @@
+//! Kernel random number generator
+//!
+//! `get_random_bytes` fills a buffer from a ChaCha20-based generator whose 256-bit key is
+//! reseeded from every entropy source available:
+//!
+//! - hardware RNG drivers, which `register_source` themselves (virtio-rng);
+//! - the `RNDR` instruction, on CPUs with FEAT_RNG;
+//! - otherwise, the jitter of the generic timer counter around a fixed amount of work.
+//!
+//! ## Design
+//!
+//! - Entropy is XORed into the key, which is then replaced by a ChaCha20 block computed with it,
+//!   so every input bit affects the whole key.
+//! - Output uses "fast key erasure": each block generated gives its first 32 bytes to the next
+//!   key and the other 32 to the caller, so the current state doesn't reveal earlier outputs.
+//! - Every call reseeds before generating. The sources don't block (a hardware RNG hands out what
+//!   it already has) and callers are rare, e.g. one stack canary per task.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux also generates with ChaCha20 and fast key erasure, but collects entropy into a BLAKE2s
+//! pool, counts it before declaring the generator ready and reseeds periodically rather than on
+//! every call. Nothing here estimates entropy: before a hardware source shows up, the output is
+//! only as good as the timer jitter.
+
+use core::arch::asm;
+
+use crate::drivers::timer::arch_timer;
+use crate::ipc::init_cell::IrqSafeLazy;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::{initcall, println};
+
+/// Maximum number of registered entropy sources
+const MAX_SOURCES: usize = 4;
+
+/// Bytes taken from each source on a reseed
+const SOURCE_BYTES: usize = 32;
+
+/// Timer jitter samples taken on a reseed when the CPU has no `RNDR`
+const JITTER_SAMPLES: usize = 32;
+
+/// Timer jitter samples taken by `init`, with nothing else to go on
+const INIT_JITTER_SAMPLES: usize = 256;
+
+/// "expand 32-byte k"
+const CHACHA_CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+/// Nonce of the blocks that mix entropy into the key, keeping them apart from output blocks
+const MIX_NONCE: u64 = 1;
+
+/// A hardware random number generator
+pub trait EntropySource: Sync {
+    /// Name of the source, e.g. `virtio-rng`
+    fn name(&self) -> &'static str;
+
+    /// Copies up to `buf.len()` random bytes without waiting, returning how many were copied
+    fn read(&self, buf: &mut [u8]) -> usize;
+}
+
+/// Errors returned when registering an entropy source
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RandomError {
+    /// The source table is full
+    NoSpace,
+}
+
+/// State of the generator
+struct State {
+    key: [u32; 8],
+    /// Block counter of output blocks
+    counter: u64,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    key: [0; 8],
+    counter: 0,
+});
+
+static SOURCES: Mutex<[Option<&'static dyn EntropySource>; MAX_SOURCES]> =
+    Mutex::new([None; MAX_SOURCES]);
+
+/// Whether the CPU implements FEAT_RNG, read from the ID register on first use
+static HAS_RNDR: IrqSafeLazy<bool> = IrqSafeLazy::new(detect_rndr);
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function (RFC 8439, with a 64-bit counter and a 64-bit nonce)
+fn chacha20_block(key: &[u32; 8], counter: u64, nonce: u64) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CHACHA_CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = nonce as u32;
+    input[15] = (nonce >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(input);
+    }
+    state
+}
+
+/// Mixes `data` into the key, 32 bytes at a time
+fn mix(state: &mut State, data: &[u8]) {
+    for chunk in data.chunks(32) {
+        for (i, bytes) in chunk.chunks(4).enumerate() {
+            let mut word = [0u8; 4];
+            word[..bytes.len()].copy_from_slice(bytes);
+            state.key[i] ^= u32::from_le_bytes(word);
+        }
+        let block = chacha20_block(&state.key, 0, MIX_NONCE);
+        state.key.copy_from_slice(&block[..8]);
+    }
+}
+
+/// Returns true if the CPU implements the `RNDR` instruction
+fn detect_rndr() -> bool {
+    let isar0: u64;
+    unsafe {
+        asm!("mrs {}, id_aa64isar0_el1", out(reg) isar0, options(nostack, nomem, preserves_flags));
+    }
+    (isar0 >> 60) & 0xf != 0
+}
+
+/// Reads `RNDR`, returning `None` if the hardware couldn't produce a number in time
+fn rndr() -> Option<u64> {
+    let value: u64;
+    let ok: u64;
+    // RNDR, by encoding: the assembler only knows the name with the `rand` extension enabled
+    unsafe {
+        asm!(
+            "mrs {value}, s3_3_c2_c4_0",
+            "cset {ok}, ne",
+            value = out(reg) value,
+            ok = out(reg) ok,
+            options(nostack, nomem),
+        );
+    }
+    (ok != 0).then_some(value)
+}
+
+/// Fills `buf` with the low bits of the time taken by a ChaCha20 block, one sample per byte
+fn jitter(buf: &mut [u8]) {
+    let mut scratch = [0u32; 8];
+    for byte in buf.iter_mut() {
+        let start = arch_timer::get_counter();
+        let block = chacha20_block(&scratch, start, 0);
+        scratch.copy_from_slice(&block[8..]);
+        let end = arch_timer::get_counter();
+        *byte = (end.wrapping_sub(start) as u8) ^ (end as u8) ^ (scratch[0] as u8);
+    }
+}
+
+/// Collects entropy from every source and mixes it into the key
+fn reseed() {
+    let mut seed = [0u8; MAX_SOURCES * SOURCE_BYTES + 4 * 8 + 8];
+    let mut len = 0;
+    // The sources are read without holding `STATE`, they may take locks of their own
+    let sources = SOURCES.lock_irqsafe(|sources| *sources);
+    for source in sources.iter().flatten() {
+        len += source.read(&mut seed[len..len + SOURCE_BYTES]);
+    }
+    if *HAS_RNDR {
+        for _ in 0..4 {
+            if let Some(value) = rndr() {
+                seed[len..len + 8].copy_from_slice(&value.to_le_bytes());
+                len += 8;
+            }
+        }
+    } else {
+        jitter(&mut seed[len..len + JITTER_SAMPLES]);
+        len += JITTER_SAMPLES;
+    }
+    seed[len..len + 8].copy_from_slice(&arch_timer::get_counter().to_le_bytes());
+    len += 8;
+    STATE.lock_irqsafe(|state| mix(state, &seed[..len]));
+}
+
+/// Fills `buf` with random bytes
+pub fn get_random_bytes(buf: &mut [u8]) {
+    reseed();
+    STATE.lock_irqsafe(|state| {
+        for chunk in buf.chunks_mut(32) {
+            let block = chacha20_block(&state.key, state.counter, 0);
+            state.counter = state.counter.wrapping_add(1);
+            state.key.copy_from_slice(&block[..8]);
+            for (bytes, word) in chunk.chunks_mut(4).zip(&block[8..]) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+        }
+    });
+}
+
+/// Returns a random 64-bit number
+pub fn get_random_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    get_random_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Adds `source` to the sources read on every reseed
+pub fn register_source(source: &'static dyn EntropySource) -> Result<(), RandomError> {
+    SOURCES.lock_irqsafe(|sources| {
+        let slot = sources
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RandomError::NoSpace)?;
+        *slot = Some(source);
+        Ok(())
+    })?;
+    println!("random: using {}", source.name());
+    Ok(())
+}
+
+/// Seeds the generator
+///
+/// Only the timer is needed, so it can run first thing; sources registered later join on the
+/// next reseed.
+pub fn init() {
+    let mut seed = [0u8; INIT_JITTER_SAMPLES];
+    jitter(&mut seed);
+    STATE.lock_irqsafe(|state| mix(state, &seed));
+    reseed();
+    if *HAS_RNDR {
+        println!("random: using RNDR");
+    } else {
+        println!("random: no RNDR, seeding from timer jitter");
+    }
+}
+initcall!(Early, "random", init, after = ["clocksource"]);
@@ -0,0 +1,244 @@
+//! FP/SIMD state of user tasks
+//!
+//! User code may use the 32 128-bit vector registers and `FPCR`/`FPSR` like any AArch64 program,
+//! but most tasks never do. A task starts with FP/SIMD accesses at EL0 trapping
+//! (`CPACR_EL1.FPEN` = 0b01); its first access takes an exception (class 0x07) and `first_use`
+//! gives it a zeroed `FpState` from a small pool, then lets EL0 use the registers from then on.
+//! From that point the task's registers are saved to its area whenever it leaves EL0 (in
+//! `save_regs`), and loaded back on the way to EL0 (in `exception_exit`), so neither the kernel
+//! nor the other tasks scheduled meanwhile can clobber them.
+//!
+//! ## Design
+//!
+//! - The kernel itself is built with FP/SIMD enabled: the compiler uses the vector registers
+//!   for copies and arithmetic anywhere, and EL1 accesses can't be trapped. The user's values
+//!   can therefore not stay live in the registers while the kernel runs, as a purely lazy scheme
+//!   would have them; what is lazy is the allocation and the cost, only paid by tasks that used
+//!   FP/SIMD once. The kernel's own registers are still switched by `cpu_switch_to`.
+//! - `USER_STATE` points, for every CPU, to the area of the task it runs, 0 if that has none:
+//!   the exception code reads it without taking a lock, indexed by `TPIDR_EL1`. `switch_to`
+//!   updates it, and the EL0 trap setting, whenever a task is switched to.
+//! - A forked child gets a copy of its parent's state. An area is given back when the task is
+//!   reaped.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux builds the kernel with `-mgeneral-regs-only`, so the user's registers can stay loaded
+//! across system calls and are only saved when switching to another task (`fpsimd_thread_switch`,
+//! with `TIF_FOREIGN_FPSTATE` telling when they must be reloaded); kernel code wanting NEON
+//! brackets it with `kernel_neon_begin`/`kernel_neon_end`. Its `user_fpsimd_state` is allocated
+//! with every task, the first-use trap (`do_fpsimd_acc`) only enables SVE.
+
+use core::arch::{asm, global_asm};
+use core::cell::UnsafeCell;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use super::{SCHED, SchedError};
+use crate::kernel::smp::{self, MAX_CPUS};
+use crate::pr_err;
+
+/// Number of tasks that can have FP/SIMD state at once
+pub const MAX_FP_STATES: usize = 8;
+
+/// `CPACR_EL1.FPEN`: 0b01 traps EL0 accesses only, 0b11 traps nothing
+const CPACR_FPEN_SHIFT: u64 = 20;
+const CPACR_FPEN_MASK: u64 = 0b11 << CPACR_FPEN_SHIFT;
+const CPACR_FPEN_EL0_TRAP: u64 = 0b01 << CPACR_FPEN_SHIFT;
+const CPACR_FPEN_NO_TRAP: u64 = 0b11 << CPACR_FPEN_SHIFT;
+
+/// FP/SIMD registers of a user task
+#[derive(Clone, Copy)]
+#[repr(C, align(16))]
+pub struct FpState {
+    pub q: [u128; 32],
+    pub fpsr: u64,
+    pub fpcr: u64,
+}
+
+impl FpState {
+    const ZERO: Self = Self {
+        q: [0; 32],
+        fpsr: 0,
+        fpcr: 0,
+    };
+}
+
+// `fpsimd_save_user` and `fpsimd_restore_user` address the registers from the start of the area
+const _: () = assert!(offset_of!(FpState, q) == 0);
+
+/// The areas handed out to tasks
+struct Pool(UnsafeCell<[FpState; MAX_FP_STATES]>);
+
+// An area is only accessed by its task, or by `fork` while the parent is running
+unsafe impl Sync for Pool {}
+
+static POOL: Pool = Pool(UnsafeCell::new([FpState::ZERO; MAX_FP_STATES]));
+
+/// Bitmap of the areas in use
+static USED: AtomicU32 = AtomicU32::new(0);
+
+/// Address of the area of the task each CPU runs, 0 if it has none
+static USER_STATE: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+// `fpsimd_save_user` and `fpsimd_restore_user` scale the CPU number by 8
+const _: () = assert!(size_of::<AtomicUsize>() == 8);
+
+global_asm!(
+    r#"
+    .pushsection .text.fpsimd, "ax", %progbits
+
+    // Saves the registers to the running task's area, if it has one
+    // Called from save_regs on an exception from EL0, preserves x0
+    .global fpsimd_save_user
+    .type fpsimd_save_user, %function
+fpsimd_save_user:
+    mrs x2, tpidr_el1
+    adrp x1, {state}
+    add x1, x1, :lo12:{state}
+    ldr x1, [x1, x2, lsl #3]
+    cbz x1, 1f
+    stp q0, q1, [x1, #0]
+    stp q2, q3, [x1, #32]
+    stp q4, q5, [x1, #64]
+    stp q6, q7, [x1, #96]
+    stp q8, q9, [x1, #128]
+    stp q10, q11, [x1, #160]
+    stp q12, q13, [x1, #192]
+    stp q14, q15, [x1, #224]
+    stp q16, q17, [x1, #256]
+    stp q18, q19, [x1, #288]
+    stp q20, q21, [x1, #320]
+    stp q22, q23, [x1, #352]
+    stp q24, q25, [x1, #384]
+    stp q26, q27, [x1, #416]
+    stp q28, q29, [x1, #448]
+    stp q30, q31, [x1, #480]
+    mrs x2, fpsr
+    str x2, [x1, #{fpsr}]
+    mrs x2, fpcr
+    str x2, [x1, #{fpcr}]
+1:  ret
+    .size fpsimd_save_user, . - fpsimd_save_user
+
+    // Loads the registers from the running task's area, if it has one
+    // Called from exception_exit on the way back to EL0
+    .global fpsimd_restore_user
+    .type fpsimd_restore_user, %function
+fpsimd_restore_user:
+    mrs x2, tpidr_el1
+    adrp x1, {state}
+    add x1, x1, :lo12:{state}
+    ldr x1, [x1, x2, lsl #3]
+    cbz x1, 1f
+    ldp q0, q1, [x1, #0]
+    ldp q2, q3, [x1, #32]
+    ldp q4, q5, [x1, #64]
+    ldp q6, q7, [x1, #96]
+    ldp q8, q9, [x1, #128]
+    ldp q10, q11, [x1, #160]
+    ldp q12, q13, [x1, #192]
+    ldp q14, q15, [x1, #224]
+    ldp q16, q17, [x1, #256]
+    ldp q18, q19, [x1, #288]
+    ldp q20, q21, [x1, #320]
+    ldp q22, q23, [x1, #352]
+    ldp q24, q25, [x1, #384]
+    ldp q26, q27, [x1, #416]
+    ldp q28, q29, [x1, #448]
+    ldp q30, q31, [x1, #480]
+    ldr x2, [x1, #{fpsr}]
+    msr fpsr, x2
+    ldr x2, [x1, #{fpcr}]
+    msr fpcr, x2
+1:  ret
+    .size fpsimd_restore_user, . - fpsimd_restore_user
+
+    .popsection
+    "#,
+    state = sym USER_STATE,
+    fpsr = const offset_of!(FpState, fpsr),
+    fpcr = const offset_of!(FpState, fpcr),
+);
+
+/// Returns the area of slot `slot`
+fn area(slot: usize) -> *mut FpState {
+    unsafe { (*POOL.0.get()).as_mut_ptr().add(slot) }
+}
+
+/// Takes a free area and zeroes it, returning its slot
+fn alloc() -> Option<usize> {
+    let mut used = USED.load(Ordering::Relaxed);
+    loop {
+        let slot = (!used).trailing_zeros() as usize;
+        if slot >= MAX_FP_STATES {
+            return None;
+        }
+        match USED.compare_exchange(used, used | 1 << slot, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => {
+                unsafe { area(slot).write(FpState::ZERO) };
+                return Some(slot);
+            }
+            Err(current) => used = current,
+        }
+    }
+}
+
+/// Gives area `slot` back
+pub fn free(slot: usize) {
+    USED.fetch_and(!(1 << slot), Ordering::Release);
+}
+
+/// Makes the area `slot` the running task's and sets the EL0 trap accordingly
+///
+/// Called on the CPU switching to a task, with `None` for the tasks that never used FP/SIMD.
+pub fn switch_to(slot: Option<usize>) {
+    let (state, fpen) = match slot {
+        Some(slot) => (area(slot) as usize, CPACR_FPEN_NO_TRAP),
+        None => (0, CPACR_FPEN_EL0_TRAP),
+    };
+    USER_STATE[smp::this_cpu()].store(state, Ordering::Relaxed);
+    unsafe {
+        let mut cpacr: u64;
+        asm!("mrs {}, cpacr_el1", out(reg) cpacr, options(nostack, nomem, preserves_flags));
+        cpacr = (cpacr & !CPACR_FPEN_MASK) | fpen;
+        asm!(
+            "msr cpacr_el1, {}",
+            "isb",
+            in(reg) cpacr,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Handles the first FP/SIMD access of the running task at EL0
+///
+/// Returns false if no area is left, the task can't go on.
+pub fn first_use() -> bool {
+    SCHED.lock_irqsafe(|sched| {
+        let current = sched.current();
+        let Some(task) = sched.current_task() else {
+            return false;
+        };
+        let Some(slot) = task.fpsimd.or_else(alloc) else {
+            pr_err!("fpsimd: no FP/SIMD state left for task {}", current);
+            return false;
+        };
+        task.fpsimd = Some(slot);
+        // Loaded into the registers by `exception_exit`
+        switch_to(Some(slot));
+        true
+    })
+}
+
+/// Gives the child of a fork a copy of the running task's state, if it has one
+///
+/// The parent's registers were saved when it entered the kernel for the system call.
+pub fn fork_state() -> Result<Option<usize>, SchedError> {
+    let Some(parent) = SCHED.lock_irqsafe(|sched| sched.current_task()?.fpsimd) else {
+        return Ok(None);
+    };
+    let slot = alloc().ok_or(SchedError::NoMemory)?;
+    unsafe { area(slot).write(area(parent).read()) };
+    Ok(Some(slot))
+}
@@ -0,0 +1,1471 @@
+//! Task scheduler
+//!
+//! Kernel threads ("tasks") share the CPUs in round-robin order. Each task has its own kernel
+//! stack (with a guard area below it, see `mm::kstack`) and a saved `Context`; switching tasks
+//! saves the callee-saved registers of the current task and loads those of the next one
+//! (`cpu_switch_to` in `switch.S`).
+//!
+//! ## Design
+//!
+//! - `init` turns the boot context (`kmain`) into task 0, running on the boot stack, and spawns
+//!   the boot CPU's idle task; a secondary CPU gets its own when it comes up (`init_idle`),
+//!   running on the stack the CPU started on. An idle task only runs when no other task is ready
+//!   on its CPU. It sleeps in `WFI` until the next interrupt and accounts the time spent there
+//!   (`idle_stats`). The timer tick is stopped meanwhile (see `arch_timer::tick_nohz_enter`), so
+//!   an idle CPU isn't woken up 100 times per second for nothing.
+//! - Every CPU has its own run queue (`RunQueue`): the task it runs and those waiting for it. A
+//!   task is queued on a single CPU, chosen when it is spawned or woken up among those its
+//!   affinity allows (`select_cpu`): the one with the fewest queued tasks, preferably the one it
+//!   last ran on. It is only picked there. The queues share the task table and its lock, which is
+//!   held across the context switch: no other CPU can pick a task before its registers are
+//!   saved.
+//! - The queues are balanced by moving ready tasks (`steal`). A CPU with nothing to run takes
+//!   the best task of the busiest other queue before going idle; every `BALANCE_TICKS` ticks a
+//!   CPU also pulls one when another queue holds `BALANCE_IMBALANCE` more tasks than its own; and
+//!   a CPU with tasks waiting wakes up an idle CPU they may run on, which then steals them.
+//! - A task gives up the CPU by calling `schedule`, either to let others run (`yield_now`) or
+//!   after marking itself `Blocked` (see `ipc::waitqueue`). A blocked task is not picked again
+//!   until `wake` makes it ready. A `Sleeping` task is blocked with a timeout: `schedule_timeout`
+//!   starts a high-resolution timer that wakes it up if nothing else does first.
+//! - The timer tick (`tick`) and `wake` request a reschedule. It happens on the way out of the
+//!   IRQ exception (`preempt_irq_exit`), unless the interrupted code holds a spinlock
+//!   (`preempt_count` > 0) or deferred work is running. The flag and the preemption count are per
+//!   CPU. Another CPU is asked to reschedule with an IPI (`resched_cpu`): `wake` sends one when
+//!   the task it queued there should preempt what the CPU runs, the idle task or a lower ranked
+//!   one.
+//! - A task ends with `exit(code)` (returning from its entry function exits with 0) and becomes
+//!   a zombie: it no longer runs but keeps its slot, address space and exit code. A task spawned
+//!   with `spawn_joinable` stays a zombie until another task collects the code with `join`;
+//!   the others are freed by the reaper task. Freeing happens once the zombie has switched away
+//!   for good, so its stack and translation table are no longer in use: `join` and the reaper
+//!   skip a zombie still running on its CPU, and are woken up by the next task on that CPU once
+//!   the switch is complete (`finish_switch`).
+//! - A user task (`spawn_user`) starts like any other task, then drops to EL0 on its own address
+//!   space. Its translation table is loaded in `TTBR0_EL1` whenever it is switched to; kernel
+//!   tasks run on the kernel's identity map.
+//!
+//! - Every task has a scheduling class and a priority within it (0 to `MAX_PRIORITY`, higher runs
+//!   first), which together give its rank: any ready real-time task runs before every normal
+//!   one. The highest ranked ready task of a queue always gets its CPU. Normal and `RoundRobin`
+//!   tasks of equal rank share it round-robin, a `Fifo` task keeps it until it blocks or yields.
+//!   A task holding an `ipc::pi_mutex` runs at the rank of the highest ranked task blocked on it
+//!   (`pi_boost`), so a low priority lock holder can't be starved by medium priority tasks while a
+//!   high priority one waits for the lock.
+//! - Kernel threads that must run promptly are real-time: the deferred work worker
+//!   (`softirq`) and the watchdog heartbeat. The class and priority are given at spawn time
+//!   (`spawn_with` and `SpawnAttrs`).
+//! - The time a task runs is accounted when it is switched away from, in counter ticks, and
+//!   reported by `for_each_task` and `task_info` along with the size of its address space. Every
+//!   timer tick also notes whether it interrupted the task at EL0 or EL1; the running time is
+//!   split into user and system time in the same proportion, and so is the busy time of the CPUs
+//!   (`cpu_stats`).
+//! - Every task has a CPU affinity mask (`set_affinity`), inherited by `fork`, which must hold an
+//!   online CPU. A ready task queued on a CPU it may no longer use moves right away, a running one
+//!   at its CPU's next reschedule. When a CPU goes offline (`smp::cpu_down`), its idle task moves
+//!   its queue to the online CPUs before powering it off (`migrate_from`); the tasks left with no
+//!   online CPU in their mask fall back to every CPU.
+//! - Every user task is also a process (see `process`), registered by `spawn_task` before it
+//!   can run and turned into a zombie process by `exit`.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! The structure follows Linux: `schedule`, `set_current_state`, `wake_up_process` (here `wake`),
+//! `TIF_NEED_RESCHED` (here `NEED_RESCHED`) and the preemption counter. Each run "queue" is
+//! scanned in task order, with a one-tick time slice. The real-time class has Linux's
+//! `SCHED_FIFO` and `SCHED_RR` policies, though a preempted `Fifo` task doesn't go back to the head
+//! of its priority's queue; the normal class is not CFS but round-robin priorities, with no load
+//! tracking. A real-time task woken from thread context preempts at the next IRQ exit rather
+//! than right away. Priority inheritance is a simplified `rt_mutex`: it
+//! isn't transitive along chains of blocked lock holders. User and system times are split as
+//! Linux's `cputime_adjust` does: the precise running time in the proportion of tick samples.
+//! As in Linux, every CPU has its own run queue, balanced periodically and when the CPU goes
+//! idle by pulling tasks from the busiest one, and tasks are woken up on other CPUs with a
+//! reschedule IPI. The queues share one lock rather than each having its own, a CPU's load is
+//! the number of tasks queued rather than PELT's decayed utilization, there are no scheduling
+//! domains, and a balancing pass moves a single task. Like Linux's `finish_task_switch`, the task
+//! switched to releases the lock the switch was made with and, if the task switched away from
+//! exited, wakes up whoever frees it.
+
+pub mod fpsimd;
+pub mod task;
+
+use core::arch::asm;
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::drivers::gic;
+use crate::drivers::timer::arch_timer;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::ipc::waitqueue::WaitQueue;
+use crate::kernel::console;
+use crate::kernel::fs::vfs;
+use crate::kernel::irq::{self, Regs, SPSR_M, softirq};
+use crate::kernel::mm::addr_space::{self, AddressSpace};
+use crate::kernel::mm::kstack;
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+use crate::kernel::smp::{self, CpuId, MAX_CPUS};
+use crate::kernel::time::{clocksource, hrtimer};
+use crate::kernel::trace::latency::{self, Histogram};
+use crate::kernel::{process, signal};
+use crate::{initcall, kbug, pr_info, trace_event};
+
+pub use task::{
+    ALL_CPUS, CpuMask, DEFAULT_PRIORITY, MAX_PRIORITY, MAX_TASKS, Policy, Priority, Rank,
+    SchedClass, SpawnAttrs, TASK_STACK_SIZE, TaskEntry, TaskId, TaskState,
+};
+use task::{Context, NR_RANKS, Task};
+
+unsafe extern "C" {
+    /// Saves the current registers into `prev` and resumes the task whose state is in `next`
+    fn cpu_switch_to(prev: *mut Context, next: *const Context);
+    /// Enters EL0 at `pc` with `sp`, resetting the kernel stack pointer to `kernel_sp`
+    fn ret_to_user(pc: u64, sp: u64, kernel_sp: u64) -> !;
+    /// Restores the exception frame at `frame` and returns to EL0
+    fn ret_from_fork(frame: *const Regs) -> !;
+}
+
+/// Value of `CURRENT` before `init`, and of a CPU that is offline
+const NO_TASK: usize = usize::MAX;
+
+/// Name of the idle task of each CPU
+const IDLE_NAMES: [&str; MAX_CPUS] = ["idle0", "idle1", "idle2", "idle3"];
+
+/// Timer ticks between two periodic balancing passes of a CPU
+const BALANCE_TICKS: u64 = 10;
+
+/// How many more tasks the busiest queue must hold for the periodic balancing to pull one
+const BALANCE_IMBALANCE: u32 = 2;
+
+/// Errors returned by `spawn`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SchedError {
+    /// The task table, or the process table for a user task, is full
+    NoSpace,
+    /// `init` has not been called yet
+    NotStarted,
+    /// The priority is above `MAX_PRIORITY`
+    BadPriority,
+    /// No task has this ID
+    NotFound,
+    /// The task can't be joined: it is detached, or it is the caller
+    NotJoinable,
+    /// No frames left for the task's kernel stack
+    NoMemory,
+    /// The CPU mask holds no online CPU
+    BadAffinity,
+}
+
+/// A task as listed by `for_each_task`
+#[derive(Clone, Copy, Debug)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: TaskState,
+    pub policy: Policy,
+    /// Class and priority the task is scheduled at, including inherited boosts
+    pub class: SchedClass,
+    pub priority: Priority,
+    /// CPU the task runs on, or last ran on
+    pub cpu: CpuId,
+    pub wakeup_latency: Histogram,
+    /// Time spent running, in nanoseconds, and how it splits between EL0 and EL1
+    pub runtime_ns: u64,
+    pub user_ns: u64,
+    pub system_ns: u64,
+    /// Size of the user address space and how much of it is mapped, in bytes; 0 for kernel tasks
+    pub vsize: usize,
+    pub rss: usize,
+}
+
+impl TaskInfo {
+    /// Describes `task`, the counter reading `now`
+    fn new(task: &Task, running: bool, now: u64) -> Self {
+        let mut runtime = task.runtime;
+        if running {
+            runtime += now.saturating_sub(task.run_start);
+        }
+        let runtime_ns = clocksource::ticks_to_ns(runtime);
+        let (class, priority) = task::from_rank(task.effective_rank());
+        let user_ns = split(runtime_ns, task.user_samples, task.system_samples);
+        Self {
+            id: task.id,
+            name: task.name,
+            state: task.state,
+            policy: task.policy,
+            class,
+            priority,
+            cpu: task.cpu,
+            wakeup_latency: task.wakeup_latency,
+            runtime_ns,
+            user_ns,
+            system_ns: runtime_ns - user_ns,
+            vsize: task.mm.as_ref().map_or(0, AddressSpace::virtual_size),
+            rss: task
+                .mm
+                .as_ref()
+                .map_or(0, |mm| mm.resident_pages() * PAGE_SIZE),
+        }
+    }
+}
+
+/// Returns the share of `total` that `part` samples out of `part + other` stand for
+fn split(total: u64, part: u64, other: u64) -> u64 {
+    match part + other {
+        0 => 0,
+        samples => (total as u128 * part as u128 / samples as u128) as u64,
+    }
+}
+
+/// Tasks of a CPU
+struct RunQueue {
+    /// Bitmap of the tasks queued on the CPU: the one it runs, unless that is the idle task, and
+    /// the ready ones waiting for it
+    queued: u32,
+    /// Task the CPU runs, `NO_TASK` while it is offline
+    current: TaskId,
+    /// The CPU's idle task, never queued, `NO_TASK` until it has one
+    idle: TaskId,
+    /// Set by `yield_now`: the current task lets the tasks of its rank run, even if `Fifo`
+    yielding: bool,
+    /// Timer ticks taken by the CPU, which time the periodic balancing
+    ticks: u64,
+    /// Counter ticks spent running tasks other than the idle task, up to the last switch
+    busy: u64,
+    /// Zombie the CPU just switched away from, `NO_TASK` once whoever frees it is woken up
+    exited: TaskId,
+}
+
+impl RunQueue {
+    /// Returns the number of tasks queued
+    fn nr_queued(&self) -> u32 {
+        self.queued.count_ones()
+    }
+}
+
+/// The task table and the run queues
+struct Scheduler {
+    tasks: [Option<Task>; MAX_TASKS],
+    rqs: [RunQueue; MAX_CPUS],
+}
+
+impl Scheduler {
+    /// Returns the task the calling CPU runs
+    ///
+    /// The lock is taken with interrupts masked, so the caller can't move to another CPU.
+    fn current(&self) -> TaskId {
+        self.rqs[smp::this_cpu()].current
+    }
+
+    /// Returns the task the calling CPU runs, `None` if it is offline
+    fn current_task(&mut self) -> Option<&mut Task> {
+        let current = self.current();
+        self.tasks.get_mut(current)?.as_mut()
+    }
+
+    /// Returns true if task `id` is running on a CPU
+    fn is_running(&self, id: TaskId) -> bool {
+        self.rqs.iter().any(|rq| rq.current == id)
+    }
+
+    /// Returns true if task `id` is on the run queue of its CPU
+    fn is_queued(&self, id: TaskId) -> bool {
+        self.tasks[id]
+            .as_ref()
+            .is_some_and(|task| self.rqs[task.cpu].queued & 1 << id != 0)
+    }
+
+    /// Puts task `id` on the run queue of `cpu`
+    fn enqueue(&mut self, id: TaskId, cpu: CpuId) {
+        if let Some(task) = self.tasks[id].as_mut() {
+            task.cpu = cpu;
+            self.rqs[cpu].queued |= 1 << id;
+        }
+    }
+
+    /// Takes task `id` off the run queue of its CPU
+    fn dequeue(&mut self, id: TaskId) {
+        if let Some(task) = self.tasks[id].as_ref() {
+            self.rqs[task.cpu].queued &= !(1 << id);
+        }
+    }
+
+    /// Chooses the CPU to queue task `id` on: of the online CPUs its affinity allows, the one
+    /// with the fewest queued tasks, the one it last ran on if there is a tie
+    ///
+    /// A task allowed on no online CPU falls back to every CPU.
+    fn select_cpu(&mut self, id: TaskId) -> CpuId {
+        let online = smp::online_mask();
+        let Some(task) = self.tasks[id].as_mut() else {
+            return 0;
+        };
+        if task.affinity & online == 0 {
+            task.affinity = ALL_CPUS;
+        }
+        let (allowed, last) = (task.affinity & online, task.cpu);
+        (0..MAX_CPUS)
+            .filter(|&cpu| allowed & 1 << cpu != 0)
+            .min_by_key(|&cpu| (self.rqs[cpu].nr_queued(), cpu != last))
+            .unwrap_or(0)
+    }
+
+    /// Queues task `id`, just made ready, and has its CPU reschedule if it should run there
+    ///
+    /// A task that hasn't switched away since it blocked is still queued and stays where it is.
+    /// Another CPU is only sent an IPI if it runs its idle task or a task of a lower rank.
+    fn ready(&mut self, id: TaskId) {
+        let cpu = match self.tasks[id].as_ref() {
+            Some(task) if self.is_queued(id) => task.cpu,
+            Some(_) => {
+                let cpu = self.select_cpu(id);
+                self.enqueue(id, cpu);
+                cpu
+            }
+            None => return,
+        };
+        let rank = self.tasks[id].as_ref().map_or(0, Task::effective_rank);
+        let rq = &self.rqs[cpu];
+        let preempts = rq.current == rq.idle
+            || self
+                .tasks
+                .get(rq.current)
+                .and_then(|t| t.as_ref())
+                .is_none_or(|t| rank > t.effective_rank());
+        if cpu == smp::this_cpu() {
+            NEED_RESCHED[cpu].store(true, Ordering::Relaxed);
+        } else if preempts {
+            resched_cpu(cpu);
+        }
+    }
+
+    /// Has the CPU of task `id` reschedule, if it is queued, after its rank or affinity changed
+    fn resched_task(&self, id: TaskId) {
+        if let Some(task) = self.tasks[id].as_ref()
+            && self.is_queued(id)
+        {
+            resched_cpu(task.cpu);
+        }
+    }
+
+    /// Moves a ready task to `cpu` from the busiest other queue, if that holds at least
+    /// `imbalance` more tasks than the queue of `cpu`
+    ///
+    /// Only tasks waiting for their CPU, and allowed on `cpu`, can move: the highest ranked one
+    /// is taken, then the lowest ID. Returns the task moved.
+    fn steal(&mut self, cpu: CpuId, imbalance: u32) -> Option<TaskId> {
+        let min_load = self.rqs[cpu].nr_queued() + imbalance;
+        let (_, _, Reverse(id)) = self
+            .tasks
+            .iter()
+            .flatten()
+            .filter(|task| {
+                let rq = &self.rqs[task.cpu];
+                task.cpu != cpu
+                    && task.state == TaskState::Ready
+                    && task.affinity & 1 << cpu != 0
+                    && rq.queued & 1 << task.id != 0
+                    && rq.current != task.id
+                    && rq.nr_queued() >= min_load
+            })
+            .map(|task| {
+                let load = self.rqs[task.cpu].nr_queued();
+                (load, task.effective_rank(), Reverse(task.id))
+            })
+            .max()?;
+        let from = self.tasks[id].as_ref()?.cpu;
+        self.dequeue(id);
+        self.enqueue(id, cpu);
+        trace_event!(sched, "migrate {} from CPU {} to CPU {}", id, from, cpu);
+        Some(id)
+    }
+
+    /// Selects the task `cpu` runs after its current one and updates both states
+    ///
+    /// Returns the contexts to pass to `cpu_switch_to` and the translation table of the next
+    /// task, or `None` if the current task keeps running.
+    fn pick_next(&mut self, cpu: CpuId) -> Option<(*mut Context, *const Context, u64)> {
+        let online = smp::online_mask() & 1 << cpu != 0;
+        let (prev, idle) = (self.rqs[cpu].current, self.rqs[cpu].idle);
+        let yielding = core::mem::take(&mut self.rqs[cpu].yielding);
+        // Off the queue once blocked, or no longer allowed here
+        if let Some(task) = self.tasks.get(prev).and_then(|t| t.as_ref())
+            && prev != idle
+        {
+            let runnable = matches!(task.state, TaskState::Ready | TaskState::Running);
+            if !runnable || !online || task.affinity & 1 << cpu == 0 {
+                self.dequeue(prev);
+                if runnable {
+                    let target = self.select_cpu(prev);
+                    self.enqueue(prev, target);
+                    resched_cpu(target);
+                }
+            }
+        }
+        // Highest rank first, then the first one after the current task in ID order
+        let queued = if online { self.rqs[cpu].queued } else { 0 };
+        let mut next: Option<(TaskId, Rank)> = None;
+        for id in (1..=MAX_TASKS).map(|i| (prev + i) % MAX_TASKS) {
+            let Some(task) = self.tasks[id].as_ref() else {
+                continue;
+            };
+            if queued & 1 << id == 0
+                || !matches!(task.state, TaskState::Ready | TaskState::Running)
+                || task.affinity & 1 << cpu == 0
+            {
+                continue;
+            }
+            let rank = task.effective_rank();
+            if next.is_none_or(|(_, best)| rank > best) {
+                next = Some((id, rank));
+            }
+        }
+        // A `Fifo` task isn't preempted by the tasks of its rank
+        if let (Some(task), Some((_, best))) = (self.tasks.get(prev).and_then(|t| t.as_ref()), next)
+            && queued & 1 << prev != 0
+            && task.state == TaskState::Running
+            && task.policy == Policy::Fifo
+            && task.effective_rank() == best
+            && !yielding
+        {
+            next = Some((prev, best));
+        }
+        let next = match next {
+            Some((id, _)) => id,
+            // Nothing left here: take work from another CPU rather than idle
+            None if online => self.steal(cpu, 1).unwrap_or(idle),
+            None => idle,
+        };
+
+        let prev_task = self.tasks.get_mut(prev)?.as_mut()?;
+        if next == prev {
+            // Nothing else to run, or woken up before it got to switch away
+            prev_task.state = TaskState::Running;
+            prev_task.woken_at = 0;
+            return None;
+        }
+        if prev_task.state == TaskState::Running {
+            prev_task.state = TaskState::Ready;
+        }
+        let now = arch_timer::get_counter();
+        let ran = now.saturating_sub(prev_task.run_start);
+        prev_task.runtime += ran;
+        let prev_ctx: *mut Context = &mut prev_task.context;
+        if prev != idle {
+            self.rqs[cpu].busy += ran;
+        }
+        if prev_task.state == TaskState::Zombie {
+            self.rqs[cpu].exited = prev;
+        }
+
+        trace_event!(sched, "switch {} -> {}", prev, next);
+        let next_task = self.tasks[next].as_mut()?;
+        next_task.state = TaskState::Running;
+        next_task.run_start = now;
+        if next_task.woken_at != 0 {
+            let ticks = now.saturating_sub(next_task.woken_at);
+            let ns = clocksource::ticks_to_ns(ticks);
+            latency::wakeup(next, &mut next_task.wakeup_latency, ns);
+            next_task.woken_at = 0;
+        }
+        self.rqs[cpu].current = next;
+        CURRENT[cpu].store(next, Ordering::Relaxed);
+        fpsimd::switch_to(next_task.fpsimd);
+        let ttbr0 = next_task
+            .mm
+            .as_ref()
+            .map_or_else(addr_space::kernel_ttbr0, AddressSpace::ttbr0);
+        Some((prev_ctx, &next_task.context, ttbr0))
+    }
+
+    /// Completes a switch on the calling CPU, in the task switched to
+    ///
+    /// Returns the wait queue of whoever frees the task switched away from, if it exited: it no
+    /// longer runs on its stack.
+    fn finish_switch(&mut self) -> Option<&'static WaitQueue> {
+        let cpu = smp::this_cpu();
+        let id = core::mem::replace(&mut self.rqs[cpu].exited, NO_TASK);
+        let task = self.tasks.get(id)?.as_ref()?;
+        Some(if task.joinable {
+            &EXIT_WAIT
+        } else {
+            &REAPER_WAIT
+        })
+    }
+}
+
+static SCHED: Mutex<Scheduler> = Mutex::new(Scheduler {
+    tasks: [const { None }; MAX_TASKS],
+    rqs: [const {
+        RunQueue {
+            queued: 0,
+            current: NO_TASK,
+            idle: NO_TASK,
+            yielding: false,
+            ticks: 0,
+            busy: 0,
+            exited: NO_TASK,
+        }
+    }; MAX_CPUS],
+});
+
+/// ID of the task each CPU runs, `NO_TASK` until `init` and while the CPU is offline
+static CURRENT: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(NO_TASK) }; MAX_CPUS];
+
+/// Set when the task a CPU runs should give up the CPU at the next opportunity
+static NEED_RESCHED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// Number of nested `preempt_disable` calls on each CPU; the task it runs can't be preempted
+/// while non-zero
+static PREEMPT_COUNT: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// Tasks waiting in `join` for a joinable task to exit
+static EXIT_WAIT: WaitQueue = WaitQueue::new();
+
+/// The reaper task, waiting for detached tasks to exit
+static REAPER_WAIT: WaitQueue = WaitQueue::new();
+
+/// Returns a task in the state of a new one, not queued yet
+fn new_task(id: TaskId, name: &'static str, state: TaskState, attrs: SpawnAttrs) -> Task {
+    Task {
+        id,
+        name,
+        state,
+        context: Context::new(),
+        entry: None,
+        arg: 0,
+        mm: None,
+        user_sp: 0,
+        policy: attrs.policy,
+        priority: attrs.priority,
+        affinity: ALL_CPUS,
+        cpu: 0,
+        boosts: [0; NR_RANKS],
+        joinable: attrs.joinable,
+        exit_code: 0,
+        fpsimd: None,
+        woken_at: 0,
+        wakeup_latency: Histogram::EMPTY,
+        run_start: 0,
+        runtime: 0,
+        user_samples: 0,
+        system_samples: 0,
+    }
+}
+
+/// Turns the calling context into task 0, starts the boot CPU's idle task and sets up the
+/// reschedule IPI
+///
+/// Runs once, as a `Late` init call; the boot context keeps running on the boot stack.
+pub fn init() {
+    SCHED.lock_irqsafe(|sched| {
+        sched.tasks[0] = Some(new_task(
+            0,
+            "kmain",
+            TaskState::Running,
+            SpawnAttrs::DEFAULT,
+        ));
+        sched.enqueue(0, 0);
+        sched.rqs[0].current = 0;
+    });
+    CURRENT[0].store(0, Ordering::Relaxed);
+    if let Err(e) = add_idle(0, true) {
+        panic!("sched: cannot spawn the idle task: {:?}", e);
+    }
+    match irq::request_irq(gic::IPI_RESCHEDULE, "resched", resched_ipi, 0) {
+        Ok(()) => gic::enable_irq(gic::IPI_RESCHEDULE),
+        Err(e) => panic!("sched: cannot request the reschedule IPI: {:?}", e),
+    }
+    if let Err(e) = spawn("reaper", reaper, 0) {
+        panic!("sched: cannot spawn the reaper task: {:?}", e);
+    }
+}
+initcall!(Late, "sched", init);
+
+/// Puts the idle task of `cpu` in the task table, reusing its slot if the CPU had one before
+///
+/// With `own_stack`, the task gets a kernel stack and starts in `idle` when the CPU first
+/// switches to it; without, it stands for what the CPU runs already, and is running.
+fn add_idle(cpu: CpuId, own_stack: bool) -> Result<TaskId, SchedError> {
+    SCHED.lock_irqsafe(|sched| {
+        let previous = sched.rqs[cpu].idle;
+        let id = match sched.tasks.get(previous) {
+            Some(Some(_)) => previous,
+            _ => free_slot(sched)?,
+        };
+        let state = if own_stack {
+            TaskState::Ready
+        } else {
+            TaskState::Running
+        };
+        let mut task = new_task(id, IDLE_NAMES[cpu], state, SpawnAttrs::DEFAULT);
+        if own_stack {
+            let stack_top = kstack::alloc(id).map_err(|_| SchedError::NoMemory)?;
+            task.context.sp = stack_top as u64;
+            task.context.lr = task_start as *const () as u64;
+        }
+        task.entry = Some(idle);
+        task.priority = 0;
+        task.affinity = 1 << cpu;
+        task.cpu = cpu;
+        task.run_start = arch_timer::get_counter();
+        sched.tasks[id] = Some(task);
+        sched.rqs[cpu].idle = id;
+        Ok(id)
+    })
+}
+
+/// Makes the context about to start on `cpu` its idle task, running
+///
+/// Called by `smp::cpu_up` before it starts the CPU, which then enters `cpu_idle` on the stack
+/// it started on.
+pub fn init_idle(cpu: CpuId) -> Result<(), SchedError> {
+    let id = add_idle(cpu, false)?;
+    SCHED.lock_irqsafe(|sched| {
+        sched.rqs[cpu].current = id;
+        sched.rqs[cpu].yielding = false;
+    });
+    NEED_RESCHED[cpu].store(false, Ordering::Relaxed);
+    CURRENT[cpu].store(id, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Time the CPUs have spent idle, as returned by `idle_stats`
+#[derive(Clone, Copy, Debug)]
+pub struct IdleStats {
+    /// Counter ticks (`CNTPCT_EL0`) spent in `WFI` by the idle tasks, summed over the CPUs
+    pub idle_ticks: u64,
+    /// Number of times an idle task put its CPU to sleep
+    pub entries: u64,
+}
+
+/// Counter ticks spent idle, see `IdleStats::idle_ticks`
+static IDLE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of idle periods, see `IdleStats::entries`
+static IDLE_ENTRIES: AtomicU64 = AtomicU64::new(0);
+
+/// Body of the boot CPU's idle task, see `cpu_idle`
+fn idle(_arg: usize) {
+    cpu_idle();
+}
+
+/// Idle loop of the calling CPU: sleep until an interrupt makes another task ready
+///
+/// Interrupts are masked from the `NEED_RESCHED` check until the CPU wakes up, so a wakeup can't
+/// slip in between the check and the standby state (`smp::cpu_do_idle`); the interrupt is taken,
+/// and the reschedule done, once they are unmasked again. The time between falling asleep and
+/// waking up is accounted as idle. Once `smp::cpu_down` has taken the CPU offline, its tasks are
+/// moved away and the CPU powers off.
+pub fn cpu_idle() -> ! {
+    // The idle task is bound to its CPU
+    let cpu = smp::this_cpu();
+    loop {
+        let daif = irq::local_irq_save();
+        if smp::online_mask() & 1 << cpu == 0 {
+            migrate_from(cpu);
+            smp::cpu_die();
+        }
+        if !NEED_RESCHED[cpu].load(Ordering::Relaxed) {
+            // Nothing to preempt until a task is woken up
+            arch_timer::tick_nohz_enter();
+            let start = arch_timer::get_counter();
+            smp::cpu_do_idle();
+            IDLE_TICKS.fetch_add(
+                arch_timer::get_counter().wrapping_sub(start),
+                Ordering::Relaxed,
+            );
+            IDLE_ENTRIES.fetch_add(1, Ordering::Relaxed);
+            arch_timer::tick_nohz_exit();
+        }
+        irq::local_irq_restore(daif);
+    }
+}
+
+/// Returns the time the CPUs have spent idle since boot
+pub fn idle_stats() -> IdleStats {
+    IdleStats {
+        idle_ticks: IDLE_TICKS.load(Ordering::Relaxed),
+        entries: IDLE_ENTRIES.load(Ordering::Relaxed),
+    }
+}
+
+/// How the CPUs' time since boot was spent, as returned by `cpu_stats`, in nanoseconds
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuStats {
+    /// Running tasks at EL0
+    pub user_ns: u64,
+    /// Running tasks at EL1, interrupts and deferred work included
+    pub system_ns: u64,
+    /// Running the idle tasks
+    pub idle_ns: u64,
+}
+
+/// Timer ticks that found a task other than the idle task running at EL0 and at EL1
+static USER_SAMPLES: AtomicU64 = AtomicU64::new(0);
+static SYSTEM_SAMPLES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns how the CPUs have spent their time since boot, summed over the CPUs
+///
+/// The idle time is what the idle tasks ran for, the busy time what the other tasks did; the
+/// latter is split between user and system time in proportion to the timer ticks that found a
+/// CPU at EL0 and EL1.
+pub fn cpu_stats() -> CpuStats {
+    let now = arch_timer::get_counter();
+    let (idle, busy) = SCHED.lock_irqsafe(|sched| {
+        let (mut idle, mut busy) = (0, 0);
+        for rq in &sched.rqs {
+            let running = |id: TaskId| {
+                let task = sched.tasks.get(id).and_then(|t| t.as_ref());
+                task.map_or(0, |t| now.saturating_sub(t.run_start))
+            };
+            if let Some(task) = sched.tasks.get(rq.idle).and_then(|t| t.as_ref()) {
+                idle += task.runtime;
+            }
+            busy += rq.busy;
+            if rq.current == rq.idle {
+                idle += running(rq.idle);
+            } else {
+                busy += running(rq.current);
+            }
+        }
+        (idle, busy)
+    });
+    let idle_ns = clocksource::ticks_to_ns(idle);
+    let busy_ns = clocksource::ticks_to_ns(busy);
+    let user = USER_SAMPLES.load(Ordering::Relaxed);
+    let system = SYSTEM_SAMPLES.load(Ordering::Relaxed);
+    let user_ns = split(busy_ns, user, system);
+    CpuStats {
+        user_ns,
+        system_ns: busy_ns - user_ns,
+        idle_ns,
+    }
+}
+
+/// First code run by a new task, `cpu_switch_to` returns here
+///
+/// `schedule` switched to the task with interrupts masked and the scheduler lock held, which
+/// the task releases before unmasking interrupts and calling the entry function. The task exits
+/// when the entry function returns.
+extern "C" fn task_start() -> ! {
+    unsafe { SCHED.force_unlock() };
+    if let Some(wq) = SCHED.lock(Scheduler::finish_switch) {
+        wq.wake_up();
+    }
+    irq::local_irq_enable();
+    let (entry, arg) = SCHED.lock_irqsafe(|sched| {
+        let task = sched.current_task();
+        (
+            task.as_ref().and_then(|t| t.entry),
+            task.map_or(0, |t| t.arg),
+        )
+    });
+    if let Some(entry) = entry {
+        entry(arg);
+    }
+    exit(0);
+}
+
+/// Creates a task running `entry(arg)` on its own stack
+///
+/// The task is ready to run but only gets the CPU at the next reschedule. Slots of tasks that
+/// have exited are reused.
+pub fn spawn(name: &'static str, entry: TaskEntry, arg: usize) -> Result<TaskId, SchedError> {
+    spawn_task(name, entry, arg, None, 0, SpawnAttrs::DEFAULT)
+}
+
+/// Creates a task like `spawn`, whose exit code must be collected with `join`
+///
+/// Until then the task keeps its slot after exiting; `detach` gives up on the exit code.
+pub fn spawn_joinable(
+    name: &'static str,
+    entry: TaskEntry,
+    arg: usize,
+) -> Result<TaskId, SchedError> {
+    let attrs = SpawnAttrs {
+        joinable: true,
+        ..SpawnAttrs::DEFAULT
+    };
+    spawn_task(name, entry, arg, None, 0, attrs)
+}
+
+/// Creates a task like `spawn`, running at `priority` instead of `DEFAULT_PRIORITY`
+pub fn spawn_with_priority(
+    name: &'static str,
+    entry: TaskEntry,
+    arg: usize,
+    priority: Priority,
+) -> Result<TaskId, SchedError> {
+    let attrs = SpawnAttrs {
+        priority,
+        ..SpawnAttrs::DEFAULT
+    };
+    spawn_task(name, entry, arg, None, 0, attrs)
+}
+
+/// Creates a task like `spawn`, with the class, priority and joinability of `attrs`
+pub fn spawn_with(
+    name: &'static str,
+    entry: TaskEntry,
+    arg: usize,
+    attrs: SpawnAttrs,
+) -> Result<TaskId, SchedError> {
+    spawn_task(name, entry, arg, None, 0, attrs)
+}
+
+/// Creates a user task running at `pc` on the address space `mm`, with `sp` as stack pointer
+///
+/// The address space must already hold the program and its stack (see `loader::exec`).
+pub fn spawn_user(
+    name: &'static str,
+    mm: AddressSpace,
+    pc: u64,
+    sp: u64,
+) -> Result<TaskId, SchedError> {
+    spawn_task(
+        name,
+        enter_user,
+        pc as usize,
+        Some(mm),
+        sp,
+        SpawnAttrs::DEFAULT,
+    )
+}
+
+/// Kernel entry of a user task: drops to EL0 at `pc`
+///
+/// The task's address space was loaded when it was switched to. Exceptions taken from EL0 start
+/// again from the top of the kernel stack, which nothing below this frame needs anymore.
+fn enter_user(pc: usize) {
+    let (id, sp) = SCHED.lock_irqsafe(|sched| {
+        let current = sched.current();
+        (current, sched.current_task().map_or(0, |t| t.user_sp))
+    });
+    unsafe { ret_to_user(pc as u64, sp, kstack::top(id) as u64) }
+}
+
+/// Creates a copy of the running user task, which resumes from the exception frame `regs`
+///
+/// The child gets a copy-on-write copy of the address space (see `AddressSpace::fork`), the same
+/// open files, policy, priority, CPU affinity, thread pointer and FP/SIMD registers, and sees 0
+/// as the result of the system call.
+pub fn fork(regs: &Regs) -> Result<TaskId, SchedError> {
+    let (parent, name, attrs, affinity, mm) = SCHED.lock_irqsafe(|sched| {
+        let current = sched.current();
+        let task = sched.current_task().ok_or(SchedError::NotFound)?;
+        let mm = task.mm.as_mut().ok_or(SchedError::NotFound)?.fork();
+        let attrs = SpawnAttrs {
+            policy: task.policy,
+            priority: task.priority,
+            joinable: false,
+        };
+        Ok((current, task.name, attrs, task.affinity, mm))
+    })?;
+    let mm = mm.map_err(|_| SchedError::NoMemory)?;
+    let fp_state = fpsimd::fork_state()?;
+    let tpidr: u64;
+    unsafe { asm!("mrs {}, tpidr_el0", out(reg) tpidr, options(nostack, nomem)) };
+
+    // The child must not run before its exception frame is in place
+    let ret = create_task(name, enter_forked, 0, Some(mm), 0, attrs).inspect(|&id| {
+        let frame = (kstack::top(id) - size_of::<Regs>()) as *mut Regs;
+        let mut child_regs = *regs;
+        child_regs.x0 = 0;
+        unsafe { frame.write(child_regs) };
+        SCHED.lock_irqsafe(|sched| {
+            if let Some(task) = sched.tasks[id].as_mut() {
+                // Run below the frame until `ret_from_fork`
+                task.context.sp = frame as u64;
+                task.context.tpidr_el0 = tpidr;
+                task.fpsimd = fp_state;
+                task.affinity = affinity;
+            }
+        });
+        vfs::copy_files(parent, id);
+        signal::copy_state(parent, id);
+        start_task(id);
+    });
+    if ret.is_err()
+        && let Some(slot) = fp_state
+    {
+        fpsimd::free(slot);
+    }
+    ret
+}
+
+/// Kernel entry of a task created by `fork`: returns to EL0 through the frame `fork` copied
+fn enter_forked(_: usize) {
+    let id = current().unwrap_or(0);
+    unsafe { ret_from_fork((kstack::top(id) - size_of::<Regs>()) as *const Regs) }
+}
+
+/// Fills in a free slot of the task table and queues the task, see `spawn` and `spawn_user`
+fn spawn_task(
+    name: &'static str,
+    entry: TaskEntry,
+    arg: usize,
+    mm: Option<AddressSpace>,
+    user_sp: u64,
+    attrs: SpawnAttrs,
+) -> Result<TaskId, SchedError> {
+    let id = create_task(name, entry, arg, mm, user_sp, attrs)?;
+    start_task(id);
+    Ok(id)
+}
+
+/// Returns the first slot of the task table that is free or holds a task that was freed
+fn free_slot(sched: &Scheduler) -> Result<TaskId, SchedError> {
+    sched
+        .tasks
+        .iter()
+        .position(|t| t.as_ref().is_none_or(|t| t.state == TaskState::Dead))
+        .ok_or(SchedError::NoSpace)
+}
+
+/// Creates a task like `spawn_task`, blocked until `start_task` queues it
+fn create_task(
+    name: &'static str,
+    entry: TaskEntry,
+    arg: usize,
+    mm: Option<AddressSpace>,
+    user_sp: u64,
+    attrs: SpawnAttrs,
+) -> Result<TaskId, SchedError> {
+    if current().is_none() {
+        return Err(SchedError::NotStarted);
+    }
+    if attrs.priority > MAX_PRIORITY {
+        return Err(SchedError::BadPriority);
+    }
+    let user = mm.is_some();
+    let id = SCHED.lock_irqsafe(|sched| {
+        let id = free_slot(sched)?;
+        let stack_top = kstack::alloc(id).map_err(|_| SchedError::NoMemory)?;
+        let mut task = new_task(id, name, TaskState::Blocked, attrs);
+        task.context.sp = stack_top as u64;
+        task.context.lr = task_start as *const () as u64;
+        task.entry = Some(entry);
+        task.arg = arg;
+        task.mm = mm;
+        task.user_sp = user_sp;
+        sched.tasks[id] = Some(task);
+        Ok(id)
+    })?;
+    if user && process::create(id, name).is_err() {
+        // Never ran: nothing to undo but what `reap` frees
+        SCHED.lock_irqsafe(|sched| {
+            if let Some(task) = sched.tasks[id].as_mut() {
+                task.state = TaskState::Zombie;
+            }
+        });
+        reap(id);
+        return Err(SchedError::NoSpace);
+    }
+    Ok(id)
+}
+
+/// Makes task `id`, created by `create_task`, ready to run on one of the CPUs
+fn start_task(id: TaskId) {
+    SCHED.lock_irqsafe(|sched| {
+        if let Some(task) = sched.tasks[id].as_mut() {
+            task.state = TaskState::Ready;
+            sched.ready(id);
+        }
+    });
+}
+
+/// Returns the ID of the running task, or `None` before `init`
+pub fn current() -> Option<TaskId> {
+    // Not moved to another CPU between reading its number and its task
+    let daif = irq::local_irq_save();
+    let id = CURRENT[smp::this_cpu()].load(Ordering::Relaxed);
+    irq::local_irq_restore(daif);
+    (id != NO_TASK).then_some(id)
+}
+
+/// Returns true if task `id` is a user task that hasn't exited
+pub fn is_user_task(id: TaskId) -> bool {
+    SCHED.lock_irqsafe(|sched| match sched.tasks.get(id).and_then(|t| t.as_ref()) {
+        Some(task) => {
+            task.mm.is_some() && !matches!(task.state, TaskState::Zombie | TaskState::Dead)
+        }
+        None => false,
+    })
+}
+
+/// Runs `f` on the address space of the running task
+///
+/// Returns `None` for kernel tasks. The scheduler lock is held meanwhile, with interrupts masked:
+/// `f` must not sleep.
+pub fn with_current_mm<R>(f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    SCHED.lock_irqsafe(|sched| sched.current_task()?.mm.as_mut().map(f))
+}
+
+/// Changes the priority of task `id` within its class
+///
+/// Takes effect at the next reschedule, which is requested.
+pub fn set_priority(id: TaskId, priority: Priority) -> Result<(), SchedError> {
+    if priority > MAX_PRIORITY {
+        return Err(SchedError::BadPriority);
+    }
+    SCHED.lock_irqsafe(|sched| {
+        let task = sched
+            .tasks
+            .get_mut(id)
+            .and_then(|t| t.as_mut())
+            .ok_or(SchedError::NotFound)?;
+        task.priority = priority;
+        sched.resched_task(id);
+        Ok(())
+    })
+}
+
+/// Restricts task `id` to the CPUs of `mask`, which must hold an online CPU
+pub fn set_affinity(id: TaskId, mask: CpuMask) -> Result<(), SchedError> {
+    let mask = mask & smp::possible_mask();
+    if mask & smp::online_mask() == 0 {
+        return Err(SchedError::BadAffinity);
+    }
+    SCHED.lock_irqsafe(|sched| {
+        let task = sched
+            .tasks
+            .get_mut(id)
+            .and_then(|t| t.as_mut())
+            .filter(|t| t.state != TaskState::Dead)
+            .ok_or(SchedError::NotFound)?;
+        task.affinity = mask;
+        let cpu = task.cpu;
+        if mask & 1 << cpu != 0 || !sched.is_queued(id) {
+            return Ok(());
+        }
+        // A running task moves when its CPU reschedules, a waiting one right away
+        if sched.rqs[cpu].current == id {
+            resched_cpu(cpu);
+        } else {
+            sched.dequeue(id);
+            let target = sched.select_cpu(id);
+            sched.enqueue(id, target);
+            resched_cpu(target);
+        }
+        Ok(())
+    })
+}
+
+/// Returns the CPUs task `id` may run on
+pub fn affinity(id: TaskId) -> Option<CpuMask> {
+    SCHED.lock_irqsafe(|sched| {
+        let task = sched.tasks.get(id)?.as_ref()?;
+        (task.state != TaskState::Dead).then_some(task.affinity & smp::possible_mask())
+    })
+}
+
+/// Moves the tasks off `cpu`, which `smp::cpu_down` has taken out of the online CPUs
+///
+/// Run by the idle task of `cpu` before it powers the CPU off: the tasks queued there go to the
+/// online CPUs, and the tasks that may run on no online CPU anymore fall back to every CPU.
+fn migrate_from(cpu: CpuId) {
+    let online = smp::online_mask();
+    let mut moved: [Option<(TaskId, &'static str)>; MAX_TASKS] = [None; MAX_TASKS];
+    SCHED.lock_irqsafe(|sched| {
+        let tasks = sched.tasks.iter_mut().flatten();
+        for (task, slot) in tasks.zip(moved.iter_mut()) {
+            if task.state != TaskState::Dead && task.affinity & online == 0 {
+                task.affinity = ALL_CPUS;
+                *slot = Some((task.id, task.name));
+            }
+        }
+        for id in 0..MAX_TASKS {
+            if sched.rqs[cpu].queued & 1 << id != 0 {
+                sched.dequeue(id);
+                let target = sched.select_cpu(id);
+                sched.enqueue(id, target);
+                resched_cpu(target);
+            }
+        }
+        let idle = sched.rqs[cpu].idle;
+        if let Some(task) = sched.tasks.get_mut(idle).and_then(|t| t.as_mut()) {
+            task.runtime += arch_timer::get_counter().saturating_sub(task.run_start);
+            task.state = TaskState::Blocked;
+        }
+        sched.rqs[cpu].current = NO_TASK;
+    });
+    CURRENT[cpu].store(NO_TASK, Ordering::Relaxed);
+    for (id, name) in moved.iter().flatten() {
+        pr_info!("sched: task {} ({}) moved off CPU {}", id, name, cpu);
+    }
+}
+
+/// Returns the rank task `id` is scheduled at, including inherited boosts
+pub fn effective_rank(id: TaskId) -> Option<Rank> {
+    SCHED.lock_irqsafe(|sched| {
+        sched
+            .tasks
+            .get(id)
+            .and_then(|t| t.as_ref())
+            .map(Task::effective_rank)
+    })
+}
+
+/// Lends `rank` to task `id`, which holds a lock a task of that rank is blocked on
+///
+/// Every call must be matched by a `pi_unboost` with the same rank once the waiter stops
+/// waiting.
+pub fn pi_boost(id: TaskId, rank: Rank) {
+    SCHED.lock_irqsafe(|sched| {
+        let Some(task) = sched.tasks.get_mut(id).and_then(|t| t.as_mut()) else {
+            return;
+        };
+        let before = task.effective_rank();
+        task.boosts[(rank as usize).min(NR_RANKS - 1)] += 1;
+        if task.effective_rank() != before {
+            sched.resched_task(id);
+        }
+    });
+}
+
+/// Takes back a rank lent with `pi_boost`
+pub fn pi_unboost(id: TaskId, rank: Rank) {
+    SCHED.lock_irqsafe(|sched| {
+        let Some(task) = sched.tasks.get_mut(id).and_then(|t| t.as_mut()) else {
+            return;
+        };
+        let before = task.effective_rank();
+        let count = &mut task.boosts[(rank as usize).min(NR_RANKS - 1)];
+        *count = count.saturating_sub(1);
+        if task.effective_rank() != before {
+            sched.resched_task(id);
+        }
+    });
+}
+
+/// Sets the state of the running task
+///
+/// Setting `Blocked` before calling `schedule` puts the task to sleep until `wake` is called.
+pub fn set_current_state(state: TaskState) {
+    SCHED.lock_irqsafe(|sched| {
+        if let Some(task) = sched.current_task() {
+            task.state = state;
+        }
+    });
+}
+
+/// Makes a blocked or sleeping task ready to run again
+///
+/// Returns false if the task was neither. Safe to call from interrupt context. The task is
+/// queued on a CPU chosen by `select_cpu`, which is sent an IPI if it should run there right away.
+pub fn wake(id: TaskId) -> bool {
+    SCHED.lock_irqsafe(
+        |sched| match sched.tasks.get_mut(id).and_then(|t| t.as_mut()) {
+            Some(task) if matches!(task.state, TaskState::Blocked | TaskState::Sleeping) => {
+                task.state = TaskState::Ready;
+                task.woken_at = arch_timer::get_counter();
+                trace_event!(sched, "wakeup {}", id);
+                sched.ready(id);
+                true
+            }
+            _ => false,
+        },
+    )
+}
+
+/// Gives the CPU to the next task ready to run
+///
+/// Returns when the calling task is picked again. If it is `Running` and no other task is ready,
+/// returns immediately. Must not be called with a spinlock held.
+pub fn schedule() {
+    if current().is_none() {
+        return;
+    }
+    let daif = irq::local_irq_save();
+    let cpu = smp::this_cpu();
+    NEED_RESCHED[cpu].store(false, Ordering::Relaxed);
+    let mut sched = SCHED.lock_guard();
+    if let Some((prev, next, ttbr0)) = sched.pick_next(cpu) {
+        addr_space::activate(ttbr0);
+        // Released by `prev` once resumed, maybe on another CPU, or by `task_start`
+        unsafe { cpu_switch_to(prev, next) };
+    }
+    let exited = sched.finish_switch();
+    drop(sched);
+    if let Some(wq) = exited {
+        wq.wake_up();
+    }
+    irq::local_irq_restore(daif);
+}
+
+/// Lets the other ready tasks run before continuing
+///
+/// Only tasks of the same or a higher rank get to run; a `Fifo` task gives way to those of its
+/// rank.
+pub fn yield_now() {
+    SCHED.lock_irqsafe(|sched| sched.rqs[smp::this_cpu()].yielding = true);
+    schedule();
+}
+
+/// Timer callback of `schedule_timeout`: wakes up task `id`
+fn timeout_wake(id: usize) {
+    wake(id);
+}
+
+/// Gives up the CPU like `schedule`, with a timer waking the task up at the latest when the
+/// counter reaches `expires`
+///
+/// The caller marks itself `Sleeping` first, and finds out whether the timeout expired by
+/// checking the counter when this returns. If no timer is available the task stays ready, so it
+/// only yields: callers loop until their condition or the deadline is met anyway.
+pub fn schedule_timeout(expires: u64) {
+    let Some(me) = current() else {
+        return;
+    };
+    match hrtimer::start_at(expires, timeout_wake, me) {
+        Ok(timer) => {
+            schedule();
+            hrtimer::cancel(timer);
+        }
+        Err(_) => {
+            set_current_state(TaskState::Running);
+            schedule();
+        }
+    }
+}
+
+/// Puts the running task to sleep for `ms` milliseconds
+///
+/// Before `init`, the CPU idles until the time has passed.
+pub fn sleep_ms(ms: u32) {
+    let expires = arch_timer::get_counter()
+        .saturating_add(clocksource::ns_to_ticks(ms as u64 * 1_000_000) + 1);
+    while arch_timer::get_counter() < expires {
+        if current().is_none() {
+            irq::wait_for_interrupt();
+            continue;
+        }
+        set_current_state(TaskState::Sleeping);
+        schedule_timeout(expires);
+    }
+}
+
+/// Terminates the running task with exit code `code`
+///
+/// The task's files are closed here; its address space and slot are freed once it has switched
+/// away, by `join` or by the reaper.
+pub fn exit(code: i32) -> ! {
+    let current = SCHED.lock_irqsafe(|sched| sched.current());
+    vfs::close_all(current);
+    process::exit(current, code);
+    signal::release(current);
+    console::release_foreground(current);
+    SCHED.lock_irqsafe(|sched| {
+        if let Some(task) = sched.tasks[current].as_mut() {
+            task.state = TaskState::Zombie;
+            task.exit_code = code;
+        }
+    });
+    // Whoever frees the task is woken up by the next one, see `finish_switch`
+    schedule();
+    kbug!("sched: zombie task scheduled");
+}
+
+/// Frees zombie task `id` (stack, address space and slot), returning its exit code
+///
+/// Returns `None` if the task isn't a zombie, or still runs on its CPU.
+fn reap(id: TaskId) -> Option<i32> {
+    let (code, mm) = SCHED.lock_irqsafe(|sched| {
+        if sched.is_running(id) {
+            return None;
+        }
+        let task = sched.tasks.get_mut(id)?.as_mut()?;
+        if task.state != TaskState::Zombie {
+            return None;
+        }
+        task.state = TaskState::Dead;
+        // Before the slot can be reused by `spawn_task`
+        kstack::free(id);
+        if let Some(slot) = task.fpsimd.take() {
+            fpsimd::free(slot);
+        }
+        Some((task.exit_code, task.mm.take()))
+    })?;
+    // Switched away from for good, its table isn't loaded anymore
+    drop(mm);
+    Some(code)
+}
+
+/// Waits for joinable task `id` to exit and returns its exit code
+///
+/// Must not be called from interrupt context.
+pub fn join(id: TaskId) -> Result<i32, SchedError> {
+    let joinable = |sched: &Scheduler| match sched.tasks.get(id).and_then(|t| t.as_ref()) {
+        Some(task) if task.state == TaskState::Dead => Err(SchedError::NotFound),
+        Some(task) if !task.joinable || Some(id) == current() => Err(SchedError::NotJoinable),
+        Some(task) => Ok(task.state == TaskState::Zombie && !sched.is_running(id)),
+        None => Err(SchedError::NotFound),
+    };
+    let mut result = Ok(false);
+    EXIT_WAIT.wait_event(|| {
+        result = SCHED.lock_irqsafe(|sched| joinable(sched));
+        result != Ok(false)
+    });
+    result?;
+    // Another joiner may have reaped it meanwhile
+    reap(id).ok_or(SchedError::NotFound)
+}
+
+/// Gives up on the exit code of joinable task `id`: it is freed by the reaper once it exits
+pub fn detach(id: TaskId) -> Result<(), SchedError> {
+    let zombie = SCHED.lock_irqsafe(|sched| {
+        let task = sched
+            .tasks
+            .get_mut(id)
+            .and_then(|t| t.as_mut())
+            .filter(|t| t.state != TaskState::Dead)
+            .ok_or(SchedError::NotFound)?;
+        task.joinable = false;
+        Ok(task.state == TaskState::Zombie)
+    })?;
+    if zombie {
+        REAPER_WAIT.wake_up();
+    }
+    Ok(())
+}
+
+/// Returns a detached task that has exited, switched away and not been freed yet
+fn detached_zombie(sched: &Scheduler) -> Option<TaskId> {
+    sched
+        .tasks
+        .iter()
+        .flatten()
+        .find(|t| t.state == TaskState::Zombie && !t.joinable && !sched.is_running(t.id))
+        .map(|t| t.id)
+}
+
+/// Body of the reaper task: frees the detached tasks that have exited
+fn reaper(_arg: usize) {
+    loop {
+        REAPER_WAIT.wait_event(|| SCHED.lock_irqsafe(|sched| detached_zombie(sched)).is_some());
+        while let Some(id) = SCHED.lock_irqsafe(|sched| detached_zombie(sched)) {
+            reap(id);
+        }
+    }
+}
+
+/// Timer tick of the calling CPU: the running task has used up its time slice
+///
+/// Also samples where the tick found the running task, at EL0 or EL1, for the time accounting,
+/// and balances the run queues: every `BALANCE_TICKS` ticks the CPU pulls a task from a busier
+/// queue, and if tasks are waiting for it, an idle CPU they may run on is woken up to take one.
+pub fn tick() {
+    let cpu = smp::this_cpu();
+    NEED_RESCHED[cpu].store(true, Ordering::Relaxed);
+    let user = irq::irq_regs(|regs| regs.spsr & SPSR_M == 0).unwrap_or(false);
+    SCHED.lock(|sched| {
+        let online = smp::online_mask();
+        sched.rqs[cpu].ticks += 1;
+        if online & 1 << cpu != 0 && sched.rqs[cpu].ticks % BALANCE_TICKS == 0 {
+            sched.steal(cpu, BALANCE_IMBALANCE);
+        }
+        let rq = &sched.rqs[cpu];
+        let allowed = (0..MAX_TASKS)
+            .filter(|&id| rq.queued & 1 << id != 0 && id != rq.current)
+            .filter_map(|id| sched.tasks[id].as_ref())
+            .filter(|t| t.state == TaskState::Ready)
+            .fold(0, |mask, t| mask | t.affinity);
+        let idle_cpu = (0..MAX_CPUS).find(|&other| {
+            let rq = &sched.rqs[other];
+            other != cpu && online & allowed & 1 << other != 0 && rq.current == rq.idle
+        });
+        if let Some(other) = idle_cpu {
+            resched_cpu(other);
+        }
+        let current = sched.rqs[cpu].current;
+        if current == sched.rqs[cpu].idle {
+            return;
+        }
+        if let Some(task) = sched.tasks.get_mut(current).and_then(|t| t.as_mut()) {
+            if user {
+                task.user_samples += 1;
+                USER_SAMPLES.fetch_add(1, Ordering::Relaxed);
+            } else {
+                task.system_samples += 1;
+                SYSTEM_SAMPLES.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+/// Prevents the running task from being preempted until the matching `preempt_enable`
+#[inline(always)]
+pub fn preempt_disable() {
+    // Not moved to another CPU between reading its number and raising its count
+    let daif = irq::local_irq_save();
+    PREEMPT_COUNT[smp::this_cpu()].fetch_add(1, Ordering::Relaxed);
+    irq::local_irq_restore(daif);
+}
+
+/// Undoes one `preempt_disable`
+///
+/// A reschedule requested meanwhile happens at the next IRQ exit rather than here.
+#[inline(always)]
+pub fn preempt_enable() {
+    // Can't move to another CPU before the count drops
+    PREEMPT_COUNT[smp::this_cpu()].fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Has `cpu` reschedule at its next IRQ exit, sending it a reschedule IPI if it isn't the
+/// calling CPU
+pub fn resched_cpu(cpu: CpuId) {
+    NEED_RESCHED[cpu].store(true, Ordering::Relaxed);
+    let daif = irq::local_irq_save();
+    if cpu != smp::this_cpu() {
+        gic::send_ipi(cpu, gic::IPI_RESCHEDULE);
+    }
+    irq::local_irq_restore(daif);
+}
+
+/// Handler of the reschedule IPI: the reschedule happens on the way out of the IRQ
+fn resched_ipi(_id: u32, _data: usize) {
+    NEED_RESCHED[smp::this_cpu()].store(true, Ordering::Relaxed);
+}
+
+/// Reschedules on the way out of an IRQ exception if a reschedule is pending
+///
+/// The interrupted task's registers are in the exception frame on its own stack, so switching
+/// here and returning through `exception_exit` later resumes it where it was interrupted.
+pub fn preempt_irq_exit() {
+    let cpu = smp::this_cpu();
+    if NEED_RESCHED[cpu].load(Ordering::Relaxed)
+        && PREEMPT_COUNT[cpu].load(Ordering::Relaxed) == 0
+        && !softirq::in_softirq()
+    {
+        schedule();
+    }
+}
+
+/// Forgets the wakeup latencies of every task
+pub fn reset_wakeup_latencies() {
+    SCHED.lock_irqsafe(|sched| {
+        for task in sched.tasks.iter_mut().flatten() {
+            task.wakeup_latency = Histogram::EMPTY;
+        }
+    });
+}
+
+/// Calls `f` on every task, in ID order
+pub fn for_each_task(f: impl FnMut(&TaskInfo)) {
+    let now = arch_timer::get_counter();
+    let tasks = SCHED.lock_irqsafe(|sched| {
+        sched.tasks.each_ref().map(|t| {
+            t.as_ref()
+                .map(|t| TaskInfo::new(t, sched.is_running(t.id), now))
+        })
+    });
+    tasks.iter().flatten().for_each(f);
+}
+
+/// Describes task `id`, `None` if there is no such task
+pub fn task_info(id: TaskId) -> Option<TaskInfo> {
+    let now = arch_timer::get_counter();
+    SCHED.lock_irqsafe(|sched| {
+        let task = sched.tasks.get(id)?.as_ref()?;
+        Some(TaskInfo::new(task, sched.is_running(id), now))
+    })
+}
@@ -0,0 +1,262 @@
+//! Tasks (kernel threads) and their saved CPU context
+
+use core::mem::offset_of;
+
+use crate::kernel::mm::addr_space::AddressSpace;
+use crate::kernel::smp::{CpuId, MAX_CPUS};
+use crate::kernel::trace::latency::Histogram;
+
+/// Maximum number of tasks, the boot task and the idle task of every CPU included
+pub const MAX_TASKS: usize = 20;
+
+// A run queue is a bitmap of task IDs
+const _: () = assert!(MAX_TASKS <= u32::BITS as usize);
+
+/// Size of the kernel stack given to every spawned task (see `mm::kstack`)
+pub const TASK_STACK_SIZE: usize = 16 * 1024;
+
+/// Identifier of a task, its index in the task table
+pub type TaskId = usize;
+
+/// Scheduling priority of a task, from 0 (lowest) to `MAX_PRIORITY`
+pub type Priority = u8;
+
+/// Highest priority
+pub const MAX_PRIORITY: Priority = 31;
+
+/// Priority of the tasks started with `spawn`
+pub const DEFAULT_PRIORITY: Priority = 10;
+
+/// Scheduling class of a task: a ready real-time task always runs before normal ones
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum SchedClass {
+    Normal,
+    RealTime,
+}
+
+/// Scheduling policy of a task, giving its class and how it shares the CPU with the tasks of
+/// the same priority
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Policy {
+    /// Normal class, round-robin every tick
+    Normal,
+    /// Real-time class, keeps the CPU until it blocks or yields (`SCHED_FIFO`)
+    Fifo,
+    /// Real-time class, round-robin every tick (`SCHED_RR`)
+    RoundRobin,
+}
+
+impl Policy {
+    pub fn class(self) -> SchedClass {
+        match self {
+            Policy::Normal => SchedClass::Normal,
+            Policy::Fifo | Policy::RoundRobin => SchedClass::RealTime,
+        }
+    }
+
+    /// Short name used by the shell, as in Linux's `ps -o cls`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Policy::Normal => "TS",
+            Policy::Fifo => "FF",
+            Policy::RoundRobin => "RR",
+        }
+    }
+}
+
+/// Position of a class and priority in the scheduling order: every real-time priority ranks
+/// above every normal one
+pub type Rank = u8;
+
+/// Number of ranks, the size of `Task::boosts`
+pub const NR_RANKS: usize = 2 * (MAX_PRIORITY as usize + 1);
+
+/// Returns the rank of `priority` in `class`
+pub const fn rank(class: SchedClass, priority: Priority) -> Rank {
+    class as u8 * (MAX_PRIORITY + 1) + priority
+}
+
+/// Returns the class and priority `rank` stands for
+pub const fn from_rank(rank: Rank) -> (SchedClass, Priority) {
+    if rank > MAX_PRIORITY {
+        (SchedClass::RealTime, rank - MAX_PRIORITY - 1)
+    } else {
+        (SchedClass::Normal, rank)
+    }
+}
+
+/// How a task is created, see `sched::spawn_with`
+#[derive(Clone, Copy, Debug)]
+pub struct SpawnAttrs {
+    pub policy: Policy,
+    /// Priority within the policy's class, 0 to `MAX_PRIORITY`
+    pub priority: Priority,
+    /// See `sched::spawn_joinable`
+    pub joinable: bool,
+}
+
+impl SpawnAttrs {
+    /// Attributes of the tasks started with `spawn`
+    pub const DEFAULT: Self = Self {
+        policy: Policy::Normal,
+        priority: DEFAULT_PRIORITY,
+        joinable: false,
+    };
+
+    /// Attributes of a real-time task running with `policy` at `priority`
+    pub const fn realtime(policy: Policy, priority: Priority) -> Self {
+        Self {
+            policy,
+            priority,
+            joinable: false,
+        }
+    }
+}
+
+/// Set of CPUs, bit `n` standing for CPU `n` in the scheduler's numbering
+pub type CpuMask = u64;
+
+/// Every CPU the kernel supports, the affinity of new tasks
+pub const ALL_CPUS: CpuMask = (1 << MAX_CPUS) - 1;
+
+/// Function run by a task, receiving the argument given to `spawn`
+pub type TaskEntry = fn(arg: usize);
+
+/// Scheduling state of a task
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaskState {
+    /// Currently executing on the CPU
+    Running,
+    /// Waiting for the CPU
+    Ready,
+    /// Waiting for an event (e.g. on a wait queue), not eligible to run until woken up
+    Blocked,
+    /// Like `Blocked`, but also woken up when a timeout expires
+    Sleeping,
+    /// Exited, keeping its exit code until it is joined or reaped
+    Zombie,
+    /// Joined or reaped; the slot can be reused
+    Dead,
+}
+
+impl TaskState {
+    /// Short name used by the shell
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TaskState::Running => "running",
+            TaskState::Ready => "ready",
+            TaskState::Blocked => "blocked",
+            TaskState::Sleeping => "sleeping",
+            TaskState::Zombie => "zombie",
+            TaskState::Dead => "dead",
+        }
+    }
+}
+
+/// Registers saved by `cpu_switch_to` (see `switch.S`)
+///
+/// Only the callee-saved general-purpose registers are needed since the switch is a function
+/// call, but the whole FP/SIMD register file is kept because tasks can be preempted anywhere.
+/// `TPIDR_EL0` is user space's thread pointer, which the kernel never touches otherwise.
+#[repr(C, align(16))]
+pub struct Context {
+    /// x19 to x28
+    pub x19_x28: [u64; 10],
+    /// Frame pointer (x29)
+    pub fp: u64,
+    /// Link register (x30): where `cpu_switch_to` returns to when the task is resumed
+    pub lr: u64,
+    pub sp: u64,
+    pub fpsr: u64,
+    pub fpcr: u64,
+    pub tpidr_el0: u64,
+    pub q: [u128; 32],
+}
+
+// Keep in sync with the CTX_* offsets in switch.S
+const _: () = assert!(offset_of!(Context, x19_x28) == 0);
+const _: () = assert!(offset_of!(Context, sp) == 96);
+const _: () = assert!(offset_of!(Context, fpsr) == 104);
+const _: () = assert!(offset_of!(Context, fpcr) == 112);
+const _: () = assert!(offset_of!(Context, tpidr_el0) == 120);
+const _: () = assert!(offset_of!(Context, q) == 128);
+
+impl Context {
+    pub const fn new() -> Self {
+        Self {
+            x19_x28: [0; 10],
+            fp: 0,
+            lr: 0,
+            sp: 0,
+            fpsr: 0,
+            fpcr: 0,
+            tpidr_el0: 0,
+            q: [0; 32],
+        }
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A schedulable kernel thread
+///
+/// User tasks are kernel threads that drop to EL0 on their own address space; they come back to
+/// their kernel stack on every exception.
+pub struct Task {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: TaskState,
+    /// Saved registers while the task is not running
+    pub context: Context,
+    /// Function run by the task, `None` for the boot task
+    pub entry: Option<TaskEntry>,
+    /// Argument passed to `entry`
+    pub arg: usize,
+    /// Address space of a user task, `None` for kernel tasks
+    pub mm: Option<AddressSpace>,
+    /// Initial user stack pointer of a user task
+    pub user_sp: u64,
+    /// Scheduling policy set at spawn time
+    pub policy: Policy,
+    /// Priority within the policy's class, set at spawn time or with `set_priority`
+    pub priority: Priority,
+    /// CPUs the task may run on when they are online
+    pub affinity: CpuMask,
+    /// CPU whose run queue the task is on, or was on when it last ran
+    pub cpu: CpuId,
+    /// Number of tasks of each rank blocked on a priority inheritance mutex this task holds
+    pub boosts: [u8; NR_RANKS],
+    /// Set if another task will collect the exit code with `join`; otherwise the reaper frees
+    /// the task as soon as it exits
+    pub joinable: bool,
+    /// Value given to `exit`, valid once the task is a zombie
+    pub exit_code: i32,
+    /// Slot of the user FP/SIMD state, once the task used FP/SIMD at EL0 (see `fpsimd`)
+    pub fpsimd: Option<usize>,
+    /// Counter value when `wake` made the task ready, 0 if it isn't waiting to run after one
+    pub woken_at: u64,
+    /// Delays between a wakeup and the task getting the CPU
+    pub wakeup_latency: Histogram,
+    /// Counter value when the task was last switched to
+    pub run_start: u64,
+    /// Counter ticks spent running, up to the last switch away
+    pub runtime: u64,
+    /// Timer ticks that found the task running at EL0 and at EL1, which split `runtime` into
+    /// user and system time
+    pub user_samples: u64,
+    pub system_samples: u64,
+}
+
+impl Task {
+    /// Returns the rank the task is scheduled at: its own, or that of the highest ranked task
+    /// waiting for a lock it holds if higher
+    pub fn effective_rank(&self) -> Rank {
+        let own = rank(self.policy.class(), self.priority);
+        let boost = self.boosts.iter().rposition(|&count| count != 0);
+        boost.map_or(own, |r| own.max(r as Rank))
+    }
+}
@@ -0,0 +1,146 @@
+//! System sensors
+//!
+//! Temperatures, voltages and clock rates read from the hardware or the firmware, for the
+//! `sensors` shell command. Drivers that can read one register a sensor: a name, its kind and a
+//! read function, with a `data` word for the driver's own use as in `clk::register_provider`.
+//! Sensors come from:
+//!
+//! - `fixed-clock` nodes of the DTB (see `clk::fixed`), whose rate never changes;
+//! - the Raspberry Pi firmware, through the mailbox property interface (see
+//!   `drivers::mbox::bcm2835`): the SoC temperature, the core voltage and the ARM and core clocks.
+//!
+//! ## Design
+//!
+//! - Values are integers in fixed units: millidegrees Celsius, microvolts and hertz. `Reading`
+//!   formats them for display.
+//! - A sensor is read when asked, never polled, and the registry's lock isn't held meanwhile:
+//!   reading may sleep, as the mailbox does. Sensors must therefore not be read from interrupt
+//!   context.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux exposes sensors through hwmon (`/sys/class/hwmon`, with the same units) and the thermal
+//! framework, which also acts on the temperatures: trip points, cooling devices and governors.
+//! On Arm boards the readings often come from the firmware through the SCMI sensor protocol
+//! (`drivers/firmware/arm_scmi`), as here through the Raspberry Pi mailbox.
+
+use core::fmt;
+
+use crate::ipc::irq_safe_mutex::Mutex;
+
+/// Maximum number of registered sensors
+const MAX_SENSORS: usize = 16;
+
+/// Reads a sensor, given the `data` word it was registered with
+pub type ReadFn = fn(data: usize) -> Result<i64, SensorError>;
+
+/// What a sensor measures, and in which unit its values are
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SensorKind {
+    /// Millidegrees Celsius
+    Temperature,
+    /// Microvolts
+    Voltage,
+    /// Hertz
+    Clock,
+}
+
+/// Errors of the sensor registry and of the reads
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SensorError {
+    /// The registry is full
+    NoSpace,
+    /// A sensor with this name is already registered
+    Exists,
+    /// The hardware or firmware didn't give a value
+    Unavailable,
+}
+
+/// A registered sensor
+#[derive(Clone, Copy)]
+pub struct Sensor {
+    pub name: &'static str,
+    pub kind: SensorKind,
+    read: ReadFn,
+    data: usize,
+}
+
+impl Sensor {
+    /// Reads the current value, in the unit of the sensor's kind
+    pub fn read(&self) -> Result<Reading, SensorError> {
+        let value = (self.read)(self.data)?;
+        Ok(Reading {
+            kind: self.kind,
+            value,
+        })
+    }
+}
+
+/// A value read from a sensor
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Reading {
+    pub kind: SensorKind,
+    pub value: i64,
+}
+
+impl fmt::Display for Reading {
+    /// Shows the value in degrees, volts or megahertz
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (scale, decimals, unit) = match self.kind {
+            SensorKind::Temperature => (1000, 1, "C"),
+            SensorKind::Voltage => (1_000_000, 3, "V"),
+            SensorKind::Clock => (1_000_000, 3, "MHz"),
+        };
+        let sign = if self.value < 0 { "-" } else { "" };
+        let value = self.value.unsigned_abs();
+        let fraction = (value % scale) / (scale / 10u64.pow(decimals as u32));
+        write!(
+            f,
+            "{}{}.{:0width$} {}",
+            sign,
+            value / scale,
+            fraction,
+            unit,
+            width = decimals
+        )
+    }
+}
+
+/// Registered sensors
+static SENSORS: Mutex<[Option<Sensor>; MAX_SENSORS]> = Mutex::new([None; MAX_SENSORS]);
+
+/// Registers the sensor `name`, read by `read` with `data`
+pub fn register(
+    name: &'static str,
+    kind: SensorKind,
+    read: ReadFn,
+    data: usize,
+) -> Result<(), SensorError> {
+    SENSORS.lock_irqsafe(|sensors| {
+        if sensors.iter().flatten().any(|s| s.name == name) {
+            return Err(SensorError::Exists);
+        }
+        let slot = sensors
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(SensorError::NoSpace)?;
+        *slot = Some(Sensor {
+            name,
+            kind,
+            read,
+            data,
+        });
+        Ok(())
+    })
+}
+
+/// Returns the sensor registered as `name`
+pub fn find(name: &str) -> Option<Sensor> {
+    SENSORS.lock_irqsafe(|sensors| sensors.iter().flatten().find(|s| s.name == name).copied())
+}
+
+/// Calls `f` on every sensor, in registration order
+pub fn for_each(f: impl FnMut(&Sensor)) {
+    let sensors = SENSORS.lock_irqsafe(|sensors| *sensors);
+    sensors.iter().flatten().for_each(f);
+}
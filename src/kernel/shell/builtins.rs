@@ -0,0 +1,753 @@
+//! Built-in shell commands
+
+use core::cmp::Reverse;
+use core::fmt;
+
+use crate::drivers::firmware::psci;
+use crate::drivers::pci;
+use crate::drivers::timer::arch_timer;
+use crate::ipc::selftest;
+use crate::kernel::console::{ConsoleWriter, ansi};
+use crate::kernel::debug::kallsyms;
+use crate::kernel::log::{self, ringbuf};
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+use crate::kernel::mm::{self, heap, kasan};
+use crate::kernel::time::{clocksource, walltime};
+use crate::kernel::trace::latency::{self, Histogram};
+use crate::kernel::trace::syscalls::{self, Filter};
+use crate::kernel::trace::{events, profile};
+use crate::kernel::{block, dtb, irq, net, power, process, sched, sensor, smp, uaccess};
+use crate::{pr_err, print, println};
+
+use super::{Command, parse_number, poll_key, register_command};
+
+/// Bytes shown per `md` line
+const MD_BYTES_PER_LINE: usize = 16;
+
+/// Largest dump `md` accepts in one go
+const MD_MAX_LEN: u64 = 4096;
+
+const BUILTINS: [Command; 24] = [
+    Command {
+        name: "help",
+        help: "help - list the available commands",
+        handler: cmd_help,
+    },
+    Command {
+        name: "dt",
+        help: "dt - dump the device tree, flagging nodes bound to a driver",
+        handler: cmd_dt,
+    },
+    Command {
+        name: "md",
+        help: "md <addr> [len] - dump memory (len defaults to 64 bytes)",
+        handler: cmd_md,
+    },
+    Command {
+        name: "irq",
+        help: "irq - per-interrupt counters, like /proc/interrupts",
+        handler: cmd_irq,
+    },
+    Command {
+        name: "lsblk",
+        help: "lsblk - list disks and partitions, with block cache statistics",
+        handler: cmd_lsblk,
+    },
+    Command {
+        name: "lspci",
+        help: "lspci - list PCI functions and their BARs",
+        handler: cmd_lspci,
+    },
+    Command {
+        name: "ifconfig",
+        help: "ifconfig - list network interfaces, their configuration and DHCP lease",
+        handler: cmd_ifconfig,
+    },
+    Command {
+        name: "ps",
+        help: "ps - list processes and kernel tasks",
+        handler: cmd_ps,
+    },
+    Command {
+        name: "top",
+        help: "top [secs] - CPU usage per task, refreshed every secs (2), q to quit",
+        handler: cmd_top,
+    },
+    Command {
+        name: "cpus",
+        help: "cpus [online|offline <cpu>] - list the CPUs and their state, or hotplug one",
+        handler: cmd_cpus,
+    },
+    Command {
+        name: "ipctest",
+        help: "ipctest - hand items from a timer interrupt to a task via a semaphore and condvar",
+        handler: cmd_ipctest,
+    },
+    Command {
+        name: "sensors",
+        help: "sensors - read the temperature, voltage and clock sensors",
+        handler: cmd_sensors,
+    },
+    Command {
+        name: "uptime",
+        help: "uptime - time elapsed since the counter started",
+        handler: cmd_uptime,
+    },
+    Command {
+        name: "date",
+        help: "date - time of day (UTC) and where it was set from",
+        handler: cmd_date,
+    },
+    Command {
+        name: "dmesg",
+        help: "dmesg [-c] - show the kernel log (-c: then clear it)",
+        handler: cmd_dmesg,
+    },
+    Command {
+        name: "strace",
+        help: "strace [on|off|<task>|-<task>...] - show or set the traced system calls",
+        handler: cmd_strace,
+    },
+    Command {
+        name: "trace",
+        help: "trace [on|off|clear|raw] - show or control the tracepoint records",
+        handler: cmd_trace,
+    },
+    Command {
+        name: "latency",
+        help: "latency [reset] - IRQ and wakeup latency histograms",
+        handler: cmd_latency,
+    },
+    Command {
+        name: "profile",
+        help: "profile [start [hz]|stop|clear] - sample the kernel, show the flat profile",
+        handler: cmd_profile,
+    },
+    Command {
+        name: "memstats",
+        help: "memstats [poison on|off] - heap usage and allocations per call site",
+        handler: cmd_memstats,
+    },
+    Command {
+        name: "free",
+        help: "free - memory usage and page fault counts",
+        handler: cmd_free,
+    },
+    Command {
+        name: "clear",
+        help: "clear - clear the screen",
+        handler: cmd_clear,
+    },
+    Command {
+        name: "colors",
+        help: "colors [on|off] - show or set colored kernel messages",
+        handler: cmd_colors,
+    },
+    Command {
+        name: "reboot",
+        help: "reboot - flush every subsystem and reset the system",
+        handler: cmd_reboot,
+    },
+];
+
+/// Registers every built-in command
+pub fn register() {
+    for command in BUILTINS {
+        if let Err(e) = register_command(command) {
+            pr_err!("shell: cannot register {}: {:?}", command.name, e);
+        }
+    }
+}
+
+fn cmd_help(_args: &[&str]) {
+    super::for_each_command(|command| println!("  {}", command.help));
+}
+
+fn cmd_dt(_args: &[&str]) {
+    dtb::dump();
+}
+
+fn cmd_md(args: &[&str]) {
+    let Some(addr) = args.get(1).and_then(|a| parse_number(a)) else {
+        println!("usage: md <addr> [len]");
+        return;
+    };
+    let len = match args.get(2) {
+        Some(arg) => match parse_number(arg) {
+            Some(len) => len.min(MD_MAX_LEN),
+            None => {
+                println!("md: invalid length '{}'", arg);
+                return;
+            }
+        },
+        None => 64,
+    };
+
+    let mut offset = 0;
+    while offset < len {
+        let line_addr = addr + offset;
+        let count = (len - offset).min(MD_BYTES_PER_LINE as u64) as usize;
+        let mut data = [0u8; MD_BYTES_PER_LINE];
+        if uaccess::copy_from_nofault(&mut data[..count], line_addr as usize).is_err() {
+            println!("md: cannot access {:#x}", line_addr);
+            return;
+        }
+        print!("{:016x}: ", line_addr);
+        for (i, b) in data.iter().enumerate() {
+            if i < count {
+                print!("{:02x} ", b);
+            } else {
+                print!("   ");
+            }
+        }
+        print!(" ");
+        for &b in &data[..count] {
+            let c = if (0x20..=0x7e).contains(&b) {
+                b as char
+            } else {
+                '.'
+            };
+            print!("{}", c);
+        }
+        println!();
+        offset += count as u64;
+    }
+}
+
+fn cmd_irq(_args: &[&str]) {
+    let stats = irq::stats();
+    let freq = arch_timer::get_frequency();
+    let now = arch_timer::get_counter();
+    println!(
+        "{:>6} {:>12}  {:<4} {:>16}  NAME",
+        "INTID", "COUNT", "TYPE", "LAST"
+    );
+    for stat in stats.irqs.iter().flatten() {
+        let kind = match stat.id {
+            0..=15 => "SGI",
+            16..=31 => "PPI",
+            _ => "SPI",
+        };
+        print!("{:>6} {:>12}  {:<4} ", stat.id, stat.count, kind);
+        if stat.count == 0 {
+            print!("{:>16}", "never");
+        } else {
+            let ago_ms = (now - stat.last) * 1000 / freq;
+            print!("{:>9}ms ago", ago_ms);
+        }
+        println!("  {}", stat.name);
+    }
+    println!(
+        "{:>6} {:>12}  spurious (INTID 1020-1023)",
+        "SPU", stats.spurious
+    );
+    println!(
+        "{:>6} {:>12}  no handler registered",
+        "ERR", stats.unhandled
+    );
+}
+
+fn cmd_lsblk(_args: &[&str]) {
+    block::dump();
+}
+
+fn cmd_lspci(_args: &[&str]) {
+    pci::dump();
+}
+
+fn cmd_ifconfig(_args: &[&str]) {
+    net::dump();
+}
+
+fn cmd_ps(_args: &[&str]) {
+    process::dump();
+}
+
+/// Refresh interval of `top`, by default, in seconds
+const TOP_INTERVAL_S: u64 = 2;
+
+/// Interval at which `top` checks for a key while waiting to refresh
+const TOP_POLL_MS: u32 = 100;
+
+/// Returns `part` in thousandths of `whole`
+fn permille(part: u64, whole: u64) -> u64 {
+    (part as u128 * 1000)
+        .checked_div(whole as u128)
+        .unwrap_or(0) as u64
+}
+
+/// Shows the CPU time each task used over the last interval, the busiest first
+///
+/// The first screen covers the time since boot.
+fn cmd_top(args: &[&str]) {
+    let interval = match args.get(1).map(|arg| parse_number(arg)) {
+        None => TOP_INTERVAL_S,
+        Some(Some(secs)) if secs != 0 => secs,
+        Some(_) => {
+            println!("usage: top [secs]");
+            return;
+        }
+    };
+    let mut prev_cpu = sched::CpuStats::default();
+    // Name and running time of every task at the previous refresh
+    let mut prev_tasks: [Option<(&str, u64)>; sched::MAX_TASKS] = [None; sched::MAX_TASKS];
+    loop {
+        let cpu = sched::cpu_stats();
+        let user = cpu.user_ns - prev_cpu.user_ns;
+        let system = cpu.system_ns - prev_cpu.system_ns;
+        let idle = cpu.idle_ns - prev_cpu.idle_ns;
+        let elapsed = user + system + idle;
+        prev_cpu = cpu;
+
+        let mut tasks = [None; sched::MAX_TASKS];
+        sched::for_each_task(|task| {
+            if task.state == sched::TaskState::Dead {
+                return;
+            }
+            let since = match prev_tasks[task.id] {
+                Some((name, runtime)) if name == task.name && runtime <= task.runtime_ns => runtime,
+                _ => 0,
+            };
+            tasks[task.id] = Some((*task, task.runtime_ns - since));
+        });
+        prev_tasks = tasks.map(|t| t.map(|(task, _)| (task.name, task.runtime_ns)));
+        tasks.sort_unstable_by_key(|t| t.map(|(task, used)| (Reverse(used), task.id)));
+
+        print!("{}", ansi::CLEAR_SCREEN);
+        println!("top - every {}s, q to quit", interval);
+        let (user, system, idle) = (
+            permille(user, elapsed),
+            permille(system, elapsed),
+            permille(idle, elapsed),
+        );
+        println!(
+            "cpus: {}.{}% user, {}.{}% system, {}.{}% idle",
+            user / 10,
+            user % 10,
+            system / 10,
+            system % 10,
+            idle / 10,
+            idle % 10
+        );
+        println!();
+        println!(
+            "{:>4} {:>5} {:<8} {:>3} {:>3} {:>3} {:>6} {:>10} {:>10}  NAME",
+            "TID", "PID", "STATE", "CLS", "PRI", "CPU", "%CPU", "USER", "SYSTEM"
+        );
+        for (task, used) in tasks.iter().flatten() {
+            let share = permille(*used, elapsed);
+            let ms = |ns: u64| ns / 1_000_000;
+            print!("{:>4} ", task.id);
+            match process::pid_of(task.id) {
+                Some(pid) => print!("{:>5} ", pid),
+                None => print!("{:>5} ", "-"),
+            }
+            println!(
+                "{:<8} {:>3} {:>3} {:>3} {:>4}.{} {:>8}ms {:>8}ms  {}",
+                task.state.as_str(),
+                task.policy.as_str(),
+                task.priority,
+                task.cpu,
+                share / 10,
+                share % 10,
+                ms(task.user_ns),
+                ms(task.system_ns),
+                task.name
+            );
+        }
+
+        for _ in 0..interval * 1000 / TOP_POLL_MS as u64 {
+            // Ctrl-C reaches the shell as a byte when no user task is in the foreground
+            if let Some(b'q' | b'Q' | 0x03) = poll_key() {
+                return;
+            }
+            sched::sleep_ms(TOP_POLL_MS);
+        }
+    }
+}
+
+/// Lists the CPUs, or brings one online or offline
+fn cmd_cpus(args: &[&str]) {
+    let cpu = args.get(2).and_then(|arg| parse_number(arg));
+    let result = match (args.get(1), cpu) {
+        (None, _) => {
+            list_cpus();
+            return;
+        }
+        (Some(&"online"), Some(cpu)) => smp::cpu_up(cpu as usize),
+        (Some(&"offline"), Some(cpu)) => smp::cpu_down(cpu as usize),
+        _ => {
+            println!("usage: cpus [online|offline <cpu>]");
+            return;
+        }
+    };
+    if let Err(e) = result {
+        println!("cpus: {:?}", e);
+    }
+}
+
+fn list_cpus() {
+    let this = smp::this_cpu();
+    for cpu in 0..smp::nr_cpus() {
+        let (Some(mpidr), Some(state)) = (smp::mpidr(cpu), smp::state(cpu)) else {
+            continue;
+        };
+        let role = if cpu == this {
+            " (running the shell)"
+        } else {
+            ""
+        };
+        match psci::affinity_info(mpidr) {
+            Ok(power) => println!(
+                "  cpu{:<3} mpidr {:#08x} {:<8} {:?}{}",
+                cpu,
+                mpidr,
+                state.as_str(),
+                power,
+                role
+            ),
+            Err(e) => println!(
+                "  cpu{:<3} mpidr {:#08x} {:<8} unknown ({:?}){}",
+                cpu,
+                mpidr,
+                state.as_str(),
+                e,
+                role
+            ),
+        }
+    }
+}
+
+fn cmd_ipctest(_args: &[&str]) {
+    match selftest::run() {
+        Ok(report) => println!(
+            "ipctest: ok, semaphore {} items, condvar {} items",
+            report.semaphore, report.condvar
+        ),
+        Err(err) => println!("ipctest: FAILED: {}", err),
+    }
+}
+
+fn cmd_sensors(_args: &[&str]) {
+    sensor::for_each(|sensor| match sensor.read() {
+        Ok(reading) => println!("  {:<12} {}", sensor.name, reading),
+        Err(e) => println!("  {:<12} {:?}", sensor.name, e),
+    });
+}
+
+fn cmd_uptime(_args: &[&str]) {
+    let now_ms = clocksource::now_ns() / 1_000_000;
+    let secs = now_ms / 1000;
+    let ms = now_ms % 1000;
+    println!(
+        "up {}d {:02}:{:02}:{:02}.{:03}",
+        secs / 86400,
+        (secs / 3600) % 24,
+        (secs / 60) % 60,
+        secs % 60,
+        ms
+    );
+    let idle = sched::idle_stats();
+    let idle_ms = clocksource::ticks_to_ns(idle.idle_ticks) / 1_000_000;
+    let permille = (idle_ms * 1000).checked_div(now_ms).unwrap_or(0);
+    println!(
+        "idle {}.{:03}s ({}.{}%), {} wakeups",
+        idle_ms / 1000,
+        idle_ms % 1000,
+        permille / 10,
+        permille % 10,
+        idle.entries
+    );
+}
+
+fn cmd_date(_args: &[&str]) {
+    walltime::dump();
+}
+
+fn cmd_dmesg(args: &[&str]) {
+    let clear = match args.get(1) {
+        None => false,
+        Some(&"-c") => true,
+        Some(_) => {
+            println!("usage: dmesg [-c]");
+            return;
+        }
+    };
+    // Stop at the messages logged before the command started, other tasks may keep printing
+    let end = ringbuf::next_seq();
+    let mut seq = ringbuf::first_seq();
+    let mut text = [0; ringbuf::MAX_RECORD_LEN];
+    while let Some(record) = ringbuf::read(seq, &mut text).filter(|r| r.seq < end) {
+        let _ = log::write_record(&mut ConsoleWriter, &record, &text[..record.len]);
+        seq = record.seq + 1;
+    }
+    if clear {
+        ringbuf::clear();
+    }
+}
+
+fn cmd_strace(args: &[&str]) {
+    match args.get(1) {
+        None => match syscalls::filter() {
+            Filter::Off => println!("strace off"),
+            Filter::All => println!("strace on"),
+            Filter::Tasks(tasks) => {
+                print!("strace tasks:");
+                (0..sched::MAX_TASKS)
+                    .filter(|id| tasks & (1 << id) != 0)
+                    .for_each(|id| print!(" {}", id));
+                println!();
+            }
+        },
+        Some(&"on") => syscalls::enable_all(),
+        Some(&"off") => syscalls::disable(),
+        Some(_) => {
+            for arg in &args[1..] {
+                let (id, on) = match arg.strip_prefix('-') {
+                    Some(id) => (id, false),
+                    None => (*arg, true),
+                };
+                let set =
+                    parse_number(id).and_then(|id| syscalls::trace_task(id as usize, on).ok());
+                if set.is_none() {
+                    println!("strace: bad task {}", arg);
+                }
+            }
+        }
+    }
+}
+
+fn cmd_trace(args: &[&str]) {
+    match args.get(1) {
+        None => {
+            events::for_each(|record| {
+                let us = clocksource::ticks_to_ns(record.timestamp) / 1000;
+                println!(
+                    "[{:>5}.{:06}] cpu{} {}",
+                    us / 1_000_000,
+                    us % 1_000_000,
+                    record.cpu,
+                    events::Formatted(record)
+                );
+            });
+            if events::lost() != 0 {
+                println!("{} events lost", events::lost());
+            }
+        }
+        Some(&"on") => events::set_enabled(true),
+        Some(&"off") => events::set_enabled(false),
+        Some(&"clear") => events::clear(),
+        Some(&"raw") => {
+            println!("# trace freq={}", arch_timer::get_frequency());
+            for (id, event) in events::events().iter().enumerate() {
+                println!("# event {} {} {:?}", id, event.subsystem, event.fmt);
+            }
+            events::for_each(|record| {
+                print!("{:x} {:x} {:x}", record.cpu, record.timestamp, record.event);
+                for arg in &record.args[..record.nargs as usize] {
+                    print!(" {:x}", arg);
+                }
+                println!();
+            });
+        }
+        Some(_) => println!("usage: trace [on|off|clear|raw]"),
+    }
+}
+
+fn print_histogram_header(label: &str) {
+    println!(
+        "  {:<12} {:>8} {:>8} {:>8}  BUCKETS (us:count)",
+        label, "COUNT", "AVG us", "MAX us"
+    );
+}
+
+/// Prints a latency histogram on one line, the empty buckets left out
+fn print_histogram(label: impl fmt::Display, hist: &Histogram) {
+    print!(
+        "  {:<12} {:>8} {:>8} {:>8} ",
+        label,
+        hist.count,
+        hist.avg_ns() / 1000,
+        hist.max_ns / 1000
+    );
+    for (i, &count) in hist.buckets.iter().enumerate() {
+        match (count, Histogram::bucket_range(i)) {
+            (0, _) => {}
+            (_, (start, Some(end))) => print!(" {}-{}:{}", start, end, count),
+            (_, (start, None)) => print!(" {}+:{}", start, count),
+        }
+    }
+    println!();
+}
+
+fn cmd_latency(args: &[&str]) {
+    match args.get(1) {
+        None => {}
+        Some(&"reset") => {
+            latency::reset_irqs();
+            sched::reset_wakeup_latencies();
+            return;
+        }
+        Some(_) => {
+            println!("usage: latency [reset]");
+            return;
+        }
+    }
+    println!("IRQ entry to handler");
+    print_histogram_header("INTID");
+    latency::for_each_irq(|(id, hist)| print_histogram(id, hist));
+    println!("Wakeup to run");
+    print_histogram_header("TASK");
+    sched::for_each_task(|task| {
+        if task.wakeup_latency.count != 0 {
+            print_histogram(task.name, &task.wakeup_latency);
+        }
+    });
+}
+
+/// Sampling rate of `profile start`, by default
+const PROFILE_HZ: u64 = 1000;
+
+fn cmd_profile(args: &[&str]) {
+    match args.get(1) {
+        None => {}
+        Some(&"start") => {
+            let hz = match args.get(2) {
+                Some(arg) => parse_number(arg).unwrap_or(0),
+                None => PROFILE_HZ,
+            };
+            if let Err(e) = profile::start(hz) {
+                println!("profile: cannot start: {:?}", e);
+            }
+            return;
+        }
+        Some(&"stop") => {
+            profile::stop();
+            return;
+        }
+        Some(&"clear") => {
+            profile::clear();
+            return;
+        }
+        Some(_) => {
+            println!("usage: profile [start [hz]|stop|clear]");
+            return;
+        }
+    }
+    let report = profile::report();
+    println!(
+        "{} samples, {} in user space, {} lost{}",
+        report.samples,
+        report.user,
+        report.lost,
+        if profile::running() { " (running)" } else { "" }
+    );
+    if kallsyms::count() == 0 {
+        println!("no symbol table, the kernel was linked without one");
+    }
+    println!("{:>6}  {:>7}  FUNCTION", "%", "SAMPLES");
+    for entry in report.entries.iter().flatten() {
+        // Tenths of a percent
+        let permille = entry.samples * 1000 / report.samples.max(1);
+        let name = entry.symbol.map_or("(unknown)", |symbol| symbol.name);
+        println!(
+            "{:>4}.{}  {:>7}  {}",
+            permille / 10,
+            permille % 10,
+            entry.samples,
+            name
+        );
+    }
+}
+
+fn cmd_memstats(args: &[&str]) {
+    match (args.get(1), args.get(2)) {
+        (None, _) => {}
+        (Some(&"poison"), Some(&"on")) => {
+            heap::set_poison(true);
+            return;
+        }
+        (Some(&"poison"), Some(&"off")) => {
+            heap::set_poison(false);
+            return;
+        }
+        _ => {
+            println!("usage: memstats [poison on|off]");
+            return;
+        }
+    }
+    let stats = heap::stats();
+    println!(
+        "in use {} bytes, peak {} bytes, {} allocs, {} frees, {} failed",
+        stats.used, stats.peak, stats.allocs, stats.frees, stats.failures
+    );
+    println!(
+        "frames: {} slab, {} large; {} chunks in CPU caches; poisoning {}, {} use after free",
+        stats.slab_frames,
+        stats.large_frames,
+        stats.cached,
+        if heap::poison() { "on" } else { "off" },
+        stats.corrupted
+    );
+    if kasan::enabled() {
+        let kasan = kasan::stats();
+        println!(
+            "kasan: {} blocks ({} bytes) in quarantine, {} bad accesses",
+            kasan.quarantined, kasan.quarantined_bytes, kasan.reports
+        );
+    }
+    println!(
+        "{:>8}  {:>8}  {:>6}  {:>10}  CALL SITE",
+        "ALLOCS", "FREES", "LIVE", "LIVE BYTES"
+    );
+    heap::for_each_site(|site| {
+        println!(
+            "{:>8}  {:>8}  {:>6}  {:>10}  {}:{}",
+            site.allocs,
+            site.frees,
+            site.live(),
+            site.live_bytes,
+            site.location.file(),
+            site.location.line()
+        );
+    });
+}
+
+fn cmd_free(_args: &[&str]) {
+    let info = mm::meminfo();
+    let kib = |frames: usize| frames * PAGE_SIZE / 1024;
+    println!(
+        "{:>10}  {:>10}  {:>10}  {:>10}  {:>10}",
+        "total", "used", "free", "slab", "anon"
+    );
+    println!(
+        "{:>8}KB  {:>8}KB  {:>8}KB  {:>8}KB  {:>8}KB",
+        kib(info.total),
+        kib(info.total - info.free),
+        kib(info.free),
+        kib(info.slab),
+        kib(info.anon)
+    );
+    let faults = info.faults;
+    println!(
+        "faults: {} translation, {} permission, {} copy-on-write, {} zero-filled",
+        faults.translation, faults.permission, faults.cow_breaks, faults.zero_fills
+    );
+}
+
+fn cmd_clear(_args: &[&str]) {
+    print!("{}", ansi::CLEAR_SCREEN);
+}
+
+fn cmd_colors(args: &[&str]) {
+    match args.get(1) {
+        Some(&"on") => ansi::set_colors(true),
+        Some(&"off") => ansi::set_colors(false),
+        Some(_) => println!("usage: colors [on|off]"),
+        None => println!("colors {}", if ansi::colors() { "on" } else { "off" }),
+    }
+}
+
+fn cmd_reboot(_args: &[&str]) {
+    power::reboot();
+}
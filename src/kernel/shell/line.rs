@@ -0,0 +1,245 @@
+//! Line editor with history for the kernel shell
+
+use crate::kernel::console::{self, ansi};
+use crate::print;
+
+/// Maximum length of a command line
+pub const MAX_LINE: usize = 128;
+
+/// Number of lines kept in the history
+const HISTORY_SIZE: usize = 16;
+
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const CTRL_E: u8 = 0x05;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const DEL: u8 = 0x7f;
+
+/// State of the VT100 escape sequence decoder
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    /// Not inside an escape sequence
+    None,
+    /// `ESC` received
+    Esc,
+    /// `ESC [` received, with the number received so far, waiting for the final byte
+    Csi(u8),
+}
+
+/// Editing keys sent as escape sequences
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Delete,
+}
+
+/// A previously entered line
+#[derive(Clone, Copy)]
+struct HistoryEntry {
+    buf: [u8; MAX_LINE],
+    len: usize,
+}
+
+/// Line editor keeping the history across calls to `read_line`
+pub struct LineEditor {
+    /// History ring, `count` valid entries ending at `head - 1`
+    history: [HistoryEntry; HISTORY_SIZE],
+    /// Slot the next entered line is written to
+    head: usize,
+    /// Number of valid entries
+    count: usize,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            history: [HistoryEntry {
+                buf: [0; MAX_LINE],
+                len: 0,
+            }; HISTORY_SIZE],
+            head: 0,
+            count: 0,
+        }
+    }
+
+    /// Returns the history entry `back` lines before the most recent one
+    fn history_entry(&self, back: usize) -> &HistoryEntry {
+        &self.history[(self.head + HISTORY_SIZE - 1 - back) % HISTORY_SIZE]
+    }
+
+    /// Appends a line to the history, skipping empty lines and repeats of the previous one
+    fn push_history(&mut self, line: &[u8]) {
+        if line.is_empty() {
+            return;
+        }
+        if self.count > 0 {
+            let last = self.history_entry(0);
+            if &last.buf[..last.len] == line {
+                return;
+            }
+        }
+        let entry = &mut self.history[self.head];
+        entry.buf[..line.len()].copy_from_slice(line);
+        entry.len = line.len();
+        self.head = (self.head + 1) % HISTORY_SIZE;
+        self.count = (self.count + 1).min(HISTORY_SIZE);
+    }
+
+    /// Reads a line into `buf` using `getc`, echoing it to the console
+    ///
+    /// `prompt` has already been printed; it is reprinted when the line is redrawn. Returns the
+    /// length of the line, without the terminating newline.
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        buf: &mut [u8; MAX_LINE],
+        mut getc: impl FnMut() -> u8,
+    ) -> usize {
+        let mut len = 0;
+        // Position of the cursor in the line
+        let mut cursor = 0;
+        let mut escape = Escape::None;
+        // Position in the history while browsing it, `None` when editing a new line
+        let mut browsing: Option<usize> = None;
+
+        loop {
+            let c = getc();
+            match escape {
+                Escape::Esc => {
+                    escape = if c == b'[' {
+                        Escape::Csi(0)
+                    } else {
+                        Escape::None
+                    };
+                    continue;
+                }
+                Escape::Csi(param) if c.is_ascii_digit() => {
+                    escape = Escape::Csi(param.saturating_mul(10).saturating_add(c - b'0'));
+                    continue;
+                }
+                Escape::Csi(param) => {
+                    escape = Escape::None;
+                    let key = match (c, param) {
+                        (b'A', _) => Key::Up,
+                        (b'B', _) => Key::Down,
+                        (b'C', _) => Key::Right,
+                        (b'D', _) => Key::Left,
+                        (b'H', _) | (b'~', 1 | 7) => Key::Home,
+                        (b'F', _) | (b'~', 4 | 8) => Key::End,
+                        (b'~', 3) => Key::Delete,
+                        _ => continue,
+                    };
+                    match key {
+                        Key::Up | Key::Down => {
+                            let target = match (key, browsing) {
+                                (Key::Up, None) if self.count > 0 => Some(0),
+                                (Key::Up, Some(back)) if back + 1 < self.count => Some(back + 1),
+                                (Key::Down, Some(back)) if back > 0 => Some(back - 1),
+                                (Key::Down, Some(_)) => None,
+                                _ => continue,
+                            };
+                            browsing = target;
+                            len = match target {
+                                Some(back) => {
+                                    let entry = self.history_entry(back);
+                                    buf[..entry.len].copy_from_slice(&entry.buf[..entry.len]);
+                                    entry.len
+                                }
+                                None => 0,
+                            };
+                            cursor = len;
+                            redraw(prompt, &buf[..len]);
+                        }
+                        Key::Left if cursor > 0 => {
+                            cursor -= 1;
+                            ansi::cursor_left(1);
+                        }
+                        Key::Right if cursor < len => {
+                            cursor += 1;
+                            ansi::cursor_right(1);
+                        }
+                        Key::Home => {
+                            ansi::cursor_left(cursor);
+                            cursor = 0;
+                        }
+                        Key::End => {
+                            ansi::cursor_right(len - cursor);
+                            cursor = len;
+                        }
+                        Key::Delete if cursor < len => {
+                            buf.copy_within(cursor + 1..len, cursor);
+                            len -= 1;
+                            redraw_tail(&buf[cursor..len], 1);
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                Escape::None => {}
+            }
+
+            match c {
+                b'\r' | b'\n' => {
+                    print!("\n");
+                    self.push_history(&buf[..len]);
+                    return len;
+                }
+                BACKSPACE | DEL if cursor > 0 => {
+                    buf.copy_within(cursor..len, cursor - 1);
+                    len -= 1;
+                    cursor -= 1;
+                    ansi::cursor_left(1);
+                    redraw_tail(&buf[cursor..len], 1);
+                }
+                CTRL_A => {
+                    ansi::cursor_left(cursor);
+                    cursor = 0;
+                }
+                CTRL_C => {
+                    print!("^C\n");
+                    return 0;
+                }
+                CTRL_E => {
+                    ansi::cursor_right(len - cursor);
+                    cursor = len;
+                }
+                CTRL_U => {
+                    len = 0;
+                    cursor = 0;
+                    redraw(prompt, &buf[..len]);
+                }
+                ansi::ESC => escape = Escape::Esc,
+                0x20..=0x7e if len < MAX_LINE => {
+                    buf.copy_within(cursor..len, cursor + 1);
+                    buf[cursor] = c;
+                    len += 1;
+                    cursor += 1;
+                    console::putchar(c);
+                    redraw_tail(&buf[cursor..len], 0);
+                }
+                // Other control characters, or the line is full
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Clears the current terminal line and prints `prompt` followed by `line`
+fn redraw(prompt: &str, line: &[u8]) {
+    print!("\r{}{}", ansi::CLEAR_LINE, prompt);
+    line.iter().for_each(|&c| console::putchar(c));
+}
+
+/// Prints `tail`, the end of the line from the cursor, then blanks the `erased` cells after it
+/// and moves the cursor back
+fn redraw_tail(tail: &[u8], erased: usize) {
+    tail.iter().for_each(|&c| console::putchar(c));
+    (0..erased).for_each(|_| console::putchar(b' '));
+    ansi::cursor_left(tail.len() + erased);
+}
@@ -0,0 +1,169 @@
+//! Kernel shell
+//!
+//! A small command interpreter running on the system console once the kernel has booted. It gives
+//! every subsystem a place to expose runtime state: a subsystem registers a `Command` with a name,
+//! a one-line help text and a handler receiving the whitespace-separated arguments.
+//!
+//! ## Line Editing
+//!
+//! Input goes through a line editor supporting backspace, Ctrl-C (discard the line), Ctrl-U
+//! (erase the line) and a command history browsed with the up and down arrow keys. The left and
+//! right arrows, Home and End (or Ctrl-A and Ctrl-E) move the cursor within the line, where
+//! typing inserts and Delete removes. These keys arrive as VT100 escape sequences (`ESC [ A`,
+//! `ESC [ 3 ~`, ...), which is what serial terminals such as QEMU's `-serial stdio` send, and
+//! the editor moves the terminal's cursor with the same kind of sequences (see `console::ansi`).
+
+mod builtins;
+mod line;
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::console::{self, InputReceiver};
+use crate::{initcall, print, println};
+
+use line::{LineEditor, MAX_LINE};
+
+/// Maximum number of commands that can be registered
+const MAX_COMMANDS: usize = 32;
+
+/// Maximum number of arguments in a command line, command name included
+const MAX_ARGS: usize = 16;
+
+/// Prompt printed before each command line
+const PROMPT: &str = "> ";
+
+/// Function implementing a command
+///
+/// `args[0]` is the command name, as with `argv` in C.
+pub type CommandHandler = fn(args: &[&str]);
+
+/// A shell command
+#[derive(Clone, Copy)]
+pub struct Command {
+    /// Name typed to run the command
+    pub name: &'static str,
+    /// Usage and one-line description shown by `help`
+    pub help: &'static str,
+    /// Function run when the command is typed
+    pub handler: CommandHandler,
+}
+
+/// Errors returned by `register_command`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ShellError {
+    /// A command with the same name is already registered
+    Exists,
+    /// The command table is full
+    NoSpace,
+}
+
+/// Input channel of the active console, lent by `run` to the command it runs
+static INPUT: Mutex<Option<InputReceiver>> = Mutex::new(None);
+
+/// Registered commands
+static COMMANDS: Mutex<[Option<Command>; MAX_COMMANDS]> = Mutex::new([None; MAX_COMMANDS]);
+
+/// Registers a shell command
+pub fn register_command(command: Command) -> Result<(), ShellError> {
+    COMMANDS.lock_irqsafe(|commands| {
+        if commands.iter().flatten().any(|c| c.name == command.name) {
+            return Err(ShellError::Exists);
+        }
+        let slot = commands
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ShellError::NoSpace)?;
+        *slot = Some(command);
+        Ok(())
+    })
+}
+
+/// Returns the command registered under `name`
+fn find_command(name: &str) -> Option<Command> {
+    COMMANDS.lock_irqsafe(|commands| commands.iter().flatten().find(|c| c.name == name).copied())
+}
+
+/// Calls `f` on every registered command, in registration order
+fn for_each_command(f: impl FnMut(&Command)) {
+    let commands = COMMANDS.lock_irqsafe(|commands| *commands);
+    commands.iter().flatten().for_each(f);
+}
+
+/// Parses a number given as an argument, in hexadecimal with a `0x` prefix or in decimal
+pub fn parse_number(arg: &str) -> Option<u64> {
+    match arg.strip_prefix("0x").or_else(|| arg.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+/// Returns the next key typed while a command runs, if one is waiting
+///
+/// Lets a long-running command, such as `top`, stop when asked to.
+pub fn poll_key() -> Option<u8> {
+    INPUT.lock_irqsafe(|input| match input {
+        Some(input) => console::try_recv_input(input),
+        None => console::getchar(),
+    })
+}
+
+/// Splits `line` into arguments and runs the matching command
+fn execute(line: &str) {
+    let mut args = [""; MAX_ARGS];
+    let mut argc = 0;
+    for arg in line.split_ascii_whitespace() {
+        if argc == MAX_ARGS {
+            println!("too many arguments (max {})", MAX_ARGS - 1);
+            return;
+        }
+        args[argc] = arg;
+        argc += 1;
+    }
+    if argc == 0 {
+        return;
+    }
+    match find_command(args[0]) {
+        Some(command) => (command.handler)(&args[..argc]),
+        None => println!("{}: command not found, try 'help'", args[0]),
+    }
+}
+
+/// Registers the built-in commands
+pub fn init() {
+    builtins::register();
+}
+initcall!(Late, "shell", init);
+
+/// Entry point of the shell task
+pub fn task(_arg: usize) {
+    run();
+}
+
+/// Runs the shell on the system console, never returns
+///
+/// Input comes from the console's input channel, fed directly by its RX interrupt, and from the
+/// console's mirror if one is attached (see `console::recv_input`); consoles without a channel
+/// are read with `console::getchar_blocking`. Either way the task sleeps until a byte arrives.
+/// While a command runs, the input channel is lent to it through `poll_key`.
+pub fn run() -> ! {
+    let mut editor = LineEditor::new();
+    let mut line = [0u8; MAX_LINE];
+    let mut input = console::input();
+    loop {
+        print!("{}", PROMPT);
+        let mut read_byte = || match input.as_mut() {
+            Some(input) => loop {
+                if let Some(c) = console::recv_input(input, || false) {
+                    break c;
+                }
+            },
+            None => console::getchar_blocking(),
+        };
+        let len = editor.read_line(PROMPT, &mut line, &mut read_byte);
+        // The editor only accepts printable ASCII
+        if let Ok(line) = core::str::from_utf8(&line[..len]) {
+            INPUT.lock_irqsafe(|slot| *slot = input.take());
+            execute(line);
+            input = INPUT.lock_irqsafe(Option::take);
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! Signals
+//!
+//! A signal is an asynchronous notification sent to a user task, by another task (`kill`), by the
+//! console (Ctrl-C sends `SIGINT` to the foreground task) or by the kernel. Sending only marks the
+//! signal pending and wakes the task up; it is acted upon when the task next returns to EL0,
+//! after a system call or an interrupt:
+//!
+//! - the default action terminates the task, with exit code 128 plus the signal number, or
+//!   ignores the signal (`SIGCHLD`, `SIGURG`, `SIGWINCH`);
+//! - a handler installed with `rt_sigaction` runs on the user stack instead. `setup_frame` pushes
+//!   a `SigFrame` holding the interrupted registers, and the handler returns to a restorer that
+//!   calls `rt_sigreturn`, which restores them.
+//!
+//! ## Design
+//!
+//! - The state of every task is a slot of `SIGNALS`, indexed by task ID like the file
+//!   descriptors: pending and blocked sets plus one action per signal. `release` clears the slot
+//!   when the task exits, and `fork` copies the parent's slot.
+//! - The restorer is the caller's `sa_restorer` with `SA_RESTORER`, as C libraries do, and
+//!   otherwise a trampoline in the signal page, a read-only executable page the loader maps at
+//!   `SIGPAGE_ADDR` in every user address space.
+//! - While a handler runs, its signal is blocked (unless `SA_NODEFER`), along with `sa_mask`.
+//!   `SIGKILL` can't be caught, blocked or ignored.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is `kill`, `rt_sigaction`, `rt_sigprocmask` and `rt_sigreturn` with the arm64 delivery
+//! path (`do_notify_resume` -> `do_signal` -> `setup_rt_frame`), without `siginfo`, real-time
+//! signal queueing or the FP/SIMD state in the frame. The signal page is the arm32 `sigpage`.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::initcall;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::irq::Regs;
+use crate::kernel::mm::addr_space::{AddressSpace, MapFlags, VmError};
+use crate::kernel::mm::frame;
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+use crate::kernel::sched::{self, MAX_TASKS, TaskId};
+use crate::kernel::uaccess::{Pod, UaccessError, UserPtr};
+use crate::utilities::cache;
+
+/// Number of signals, numbered from 1
+pub const NSIG: usize = 64;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGKILL: usize = 9;
+pub const SIGSEGV: usize = 11;
+pub const SIGPIPE: usize = 13;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+/// Handler value: default action
+pub const SIG_DFL: u64 = 0;
+/// Handler value: ignore the signal
+pub const SIG_IGN: u64 = 1;
+
+/// `sa_flags`: don't block the signal while its handler runs
+pub const SA_NODEFER: u64 = 0x4000_0000;
+/// `sa_flags`: `sa_restorer` is valid
+pub const SA_RESTORER: u64 = 0x0400_0000;
+/// `sa_flags`: restore the default action once the handler is entered
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// User address of the signal page
+pub const SIGPAGE_ADDR: usize = (1 << 48) - (1 << 40);
+
+/// `mov x8, #139` (`rt_sigreturn`) then `svc #0`
+const TRAMPOLINE: [u32; 2] = [0xd280_1168, 0xd400_0001];
+
+/// SPSR bits user space may change through a signal frame: the condition flags
+const SPSR_USER_MASK: u64 = 0xf000_0000;
+
+/// Errors returned by signal operations
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SignalError {
+    /// The signal number is out of range, or its action can't be changed
+    BadSignal,
+    /// No user task has this ID
+    NotFound,
+}
+
+/// What to do when a signal is delivered, as passed to `rt_sigaction`
+#[derive(Clone, Copy, Default, Debug)]
+#[repr(C)]
+pub struct SigAction {
+    /// `SIG_DFL`, `SIG_IGN` or the user address of the handler
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    /// Signals blocked while the handler runs
+    pub mask: u64,
+}
+
+unsafe impl Pod for SigAction {}
+
+/// Registers saved on the user stack while a handler runs
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct SigFrame {
+    x: [u64; 31],
+    sp: u64,
+    pc: u64,
+    pstate: u64,
+    /// Blocked set to restore
+    blocked: u64,
+    signo: u64,
+}
+
+unsafe impl Pod for SigFrame {}
+
+// The user stack pointer stays 16-byte aligned
+const _: () = assert!(size_of::<SigFrame>().is_multiple_of(16));
+
+/// Signal state of a task
+#[derive(Clone, Copy)]
+struct SignalState {
+    pending: u64,
+    blocked: u64,
+    actions: [SigAction; NSIG],
+}
+
+impl SignalState {
+    const fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction {
+                handler: SIG_DFL,
+                flags: 0,
+                restorer: 0,
+                mask: 0,
+            }; NSIG],
+        }
+    }
+}
+
+static SIGNALS: Mutex<[SignalState; MAX_TASKS]> =
+    Mutex::new([const { SignalState::new() }; MAX_TASKS]);
+
+/// Physical address of the signal page, 0 before `init`
+static SIGPAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the bit of `sig` in a signal set
+fn bit(sig: usize) -> u64 {
+    1 << (sig - 1)
+}
+
+fn check_signal(sig: usize) -> Result<(), SignalError> {
+    if (1..=NSIG).contains(&sig) {
+        Ok(())
+    } else {
+        Err(SignalError::BadSignal)
+    }
+}
+
+/// Returns true if the default action of `sig` is to ignore it
+fn ignored_by_default(sig: usize) -> bool {
+    matches!(sig, SIGCHLD | SIGURG | SIGWINCH)
+}
+
+/// Sets up the signal page holding the restorer trampoline
+///
+/// Must run after `frame::init`.
+pub fn init() {
+    let Ok(page) = frame::alloc_zeroed_frames(1) else {
+        return;
+    };
+    let code = page as *mut u32;
+    for (i, insn) in TRAMPOLINE.iter().enumerate() {
+        unsafe { code.add(i).write(*insn) };
+    }
+    cache::clean_dcache_range_pou(page, PAGE_SIZE);
+    cache::invalidate_icache_all_is();
+    SIGPAGE.store(page, Ordering::Release);
+}
+initcall!(Late, "signal", init, after = ["frame"]);
+
+/// Maps the signal page in the new address space `mm`
+pub fn map_sigpage(mm: &mut AddressSpace) -> Result<(), VmError> {
+    match SIGPAGE.load(Ordering::Acquire) {
+        0 => Ok(()),
+        page => mm.map_page(SIGPAGE_ADDR, page, MapFlags::READ.union(MapFlags::EXEC)),
+    }
+}
+
+/// Marks `sig` pending for task `id` and wakes it up to act upon it
+///
+/// Safe to call from interrupt context. Signal 0 only checks that the task exists.
+pub fn send(id: TaskId, sig: usize) -> Result<(), SignalError> {
+    if sig != 0 {
+        check_signal(sig)?;
+    }
+    if !sched::is_user_task(id) {
+        return Err(SignalError::NotFound);
+    }
+    if sig == 0 {
+        return Ok(());
+    }
+    SIGNALS.lock_irqsafe(|signals| signals[id].pending |= bit(sig));
+    sched::wake(id);
+    Ok(())
+}
+
+/// Returns true if the running task has a signal to act upon
+///
+/// Lets blocking operations give up early, e.g. a console read interrupted by Ctrl-C.
+pub fn has_pending() -> bool {
+    let Some(id) = sched::current() else {
+        return false;
+    };
+    SIGNALS.lock_irqsafe(|signals| signals[id].pending & !signals[id].blocked != 0)
+}
+
+/// Sets the action of `sig` for the running task, returning the previous one
+pub fn set_action(sig: usize, action: Option<SigAction>) -> Result<SigAction, SignalError> {
+    check_signal(sig)?;
+    if sig == SIGKILL && action.is_some() {
+        return Err(SignalError::BadSignal);
+    }
+    let id = sched::current().ok_or(SignalError::NotFound)?;
+    Ok(SIGNALS.lock_irqsafe(|signals| {
+        let state = &mut signals[id];
+        let old = state.actions[sig - 1];
+        if let Some(action) = action {
+            state.actions[sig - 1] = action;
+            if action.handler == SIG_IGN {
+                state.pending &= !bit(sig);
+            }
+        }
+        old
+    }))
+}
+
+/// Changes the blocked set of the running task with `f`, returning the previous set
+pub fn update_blocked(f: impl FnOnce(u64) -> u64) -> Result<u64, SignalError> {
+    let id = sched::current().ok_or(SignalError::NotFound)?;
+    Ok(SIGNALS.lock_irqsafe(|signals| {
+        let old = signals[id].blocked;
+        signals[id].blocked = f(old) & !bit(SIGKILL);
+        old
+    }))
+}
+
+/// Gives task `to` the actions and blocked set of task `from`, for `fork`
+pub fn copy_state(from: TaskId, to: TaskId) {
+    SIGNALS.lock_irqsafe(|signals| {
+        signals[to] = SignalState {
+            pending: 0,
+            ..signals[from]
+        };
+    });
+}
+
+/// Resets the signal state of task `id`, when it exits
+pub fn release(id: TaskId) {
+    SIGNALS.lock_irqsafe(|signals| signals[id] = SignalState::new());
+}
+
+/// Takes the lowest pending signal task `id` doesn't block
+///
+/// Returns it with its action and the blocked set from before the action's own blocking.
+fn dequeue(id: TaskId) -> Option<(usize, SigAction, u64)> {
+    SIGNALS.lock_irqsafe(|signals| {
+        let state = &mut signals[id];
+        let ready = state.pending & !state.blocked;
+        if ready == 0 {
+            return None;
+        }
+        let sig = ready.trailing_zeros() as usize + 1;
+        let blocked = state.blocked;
+        state.pending &= !bit(sig);
+        let action = state.actions[sig - 1];
+        if action.handler > SIG_IGN {
+            if action.flags & SA_NODEFER == 0 {
+                state.blocked |= bit(sig);
+            }
+            state.blocked |= action.mask & !bit(SIGKILL);
+            if action.flags & SA_RESETHAND != 0 {
+                state.actions[sig - 1] = SigAction::default();
+            }
+        }
+        Some((sig, action, blocked))
+    })
+}
+
+/// Acts upon the pending signals of the running task, about to return to EL0 with `regs`
+///
+/// Terminates the task or diverts `regs` to a handler. Must run with IRQs unmasked, as writing
+/// the frame may fault in a stack page.
+pub fn do_signal(regs: &mut Regs) {
+    let Some(id) = sched::current() else {
+        return;
+    };
+    while let Some((sig, action, blocked)) = dequeue(id) {
+        match action.handler {
+            SIG_IGN => {}
+            SIG_DFL if ignored_by_default(sig) => {}
+            SIG_DFL => sched::exit(128 + sig as i32),
+            _ => {
+                if setup_frame(regs, sig, &action, blocked).is_err() {
+                    sched::exit(128 + SIGSEGV as i32);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Saves `regs` and the blocked set to restore on the user stack, and makes `regs` enter the
+/// handler of `sig`
+fn setup_frame(
+    regs: &mut Regs,
+    sig: usize,
+    action: &SigAction,
+    blocked: u64,
+) -> Result<(), UaccessError> {
+    let mut frame = SigFrame {
+        x: [0; 31],
+        sp: regs.sp_el0,
+        pc: regs.elr,
+        pstate: regs.spsr,
+        blocked,
+        signo: sig as u64,
+    };
+    for (n, x) in frame.x.iter_mut().enumerate() {
+        *x = regs.gpr(n);
+    }
+    let addr = (regs.sp_el0 as usize)
+        .checked_sub(size_of::<SigFrame>())
+        .ok_or(UaccessError::Overflow)?
+        & !0xf;
+    UserPtr::<SigFrame>::new(addr).write(&frame)?;
+    let restorer = if action.flags & SA_RESTORER != 0 {
+        action.restorer
+    } else {
+        SIGPAGE_ADDR as u64
+    };
+    regs.x0 = sig as u64;
+    regs.set_gpr(30, restorer);
+    regs.sp_el0 = addr as u64;
+    regs.elr = action.handler;
+    Ok(())
+}
+
+/// Restores the registers saved by `setup_frame`, at the user stack pointer in `regs`
+///
+/// Returns false if the frame can't be read, in which case the task must not go on.
+pub fn sigreturn(regs: &mut Regs) -> bool {
+    let Ok(frame) = UserPtr::<SigFrame>::new(regs.sp_el0 as usize).read() else {
+        return false;
+    };
+    for (n, &x) in frame.x.iter().enumerate() {
+        regs.set_gpr(n, x);
+    }
+    regs.sp_el0 = frame.sp;
+    regs.elr = frame.pc;
+    // Only the flags: the frame must not send the task to EL1 or unmask anything
+    regs.spsr = frame.pstate & SPSR_USER_MASK;
+    let _ = update_blocked(|_| frame.blocked);
+    true
+}
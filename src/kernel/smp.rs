@@ -0,0 +1,509 @@
+//! Secondary CPUs: bring-up, hotplug and idle
+//!
+//! The kernel boots on one CPU. `init` numbers the CPUs of the device tree, the `cpu` nodes whose
+//! `reg` holds their MPIDR affinity fields: the boot CPU is CPU 0, the others follow in tree
+//! order, up to `MAX_CPUS`. A secondary CPU stays off in the firmware until `cpu_up` starts it
+//! through `psci::cpu_on`, and `cpu_down` powers it off again through `psci::cpu_off`. `init`
+//! brings every CPU up at boot; the `cpus` shell command lists them and takes them on and off
+//! line.
+//!
+//! ```text
+//! smp: 4 CPUs, 4 online
+//! ```
+//!
+//! ## Design
+//!
+//! - Each CPU keeps its number in `TPIDR_EL1` (`this_cpu`), set by `_start` on the boot CPU and
+//!   by `secondary_entry` on the others.
+//! - A secondary CPU starts at `secondary_entry` with the MMU off, at the image's physical
+//!   address, which the identity map makes its virtual one too. It loads the boot CPU's
+//!   translation setup, exception vectors and its own stack from `BOOT_ARGS`, cleaned to the
+//!   point of coherency since it is read with the caches off, then continues in Rust
+//!   (`secondary_start`).
+//! - `cpu_up` gives the CPU its idle task (`sched::init_idle`) before starting it. A started CPU
+//!   wakes up its GIC redistributor and opens its CPU interface (`gic::init_cpu`), starts its
+//!   timer tick, marks itself online and enters its idle loop (`sched::cpu_idle`): from then on
+//!   it runs the tasks queued on it, like the boot CPU.
+//! - `cpu_down` first takes the CPU out of the online mask, so no task can be given it anymore,
+//!   and has it reschedule (`sched::resched_cpu`). The CPU switches to its idle task, which moves
+//!   its tasks away, closes its CPU interface, puts its redistributor to sleep
+//!   (`gic::disable_cpu`) and calls `psci::cpu_off` (`cpu_die`); the caller waits until the
+//!   firmware reports it off (`psci::affinity_info`). CPU 0 never goes down.
+//! - Hotplug operations are serialized: one at a time, the others fail with `SmpError::Busy`.
+//!   A CPU that doesn't change state in time is marked online or offline according to what the
+//!   firmware reports (`recover_state`) rather than left `Starting` or `Dying`.
+//! - The idle task waits for interrupts in the firmware's standby state (`cpu_do_idle`, through
+//!   `psci::cpu_suspend`), or with `WFI` if there is no PSCI or it refuses. Power-down states,
+//!   which lose the CPU's context, are not used.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! `cpu_up` and `cpu_down` stand for Linux's hotplug state machine (`kernel/cpu.c`) driving the
+//! PSCI `cpu_operations` (`cpu_psci_cpu_boot`, `cpu_psci_cpu_die`, `cpu_psci_cpu_kill`), and
+//! `secondary_entry`/`secondary_start` for `secondary_startup`/`secondary_start_kernel`. Linux
+//! runs every subsystem's callbacks (`CPUHP_*`) in order in both directions; here the steps are
+//! fixed: interrupt controller, timer, then the scheduler. `cpu_do_idle` is the standby state of
+//! the PSCI cpuidle driver, without the governor choosing deeper states.
+
+use core::arch::{asm, global_asm};
+use core::cell::UnsafeCell;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+
+use crate::drivers::firmware::psci::{self, AffinityState, PsciError};
+use crate::drivers::gic;
+use crate::drivers::timer::arch_timer;
+use crate::kernel::dtb;
+use crate::kernel::irq;
+use crate::kernel::mm::addr_space;
+use crate::kernel::mm::kstack::KSTACK_SIZE;
+use crate::kernel::sched::{self, CpuMask, SchedError};
+use crate::utilities::cache;
+use crate::{initcall, pr_err, println};
+
+/// Expands to a read of the system register `$reg`
+macro_rules! read_sysreg {
+    ($reg:literal) => {{
+        let value: u64;
+        asm!(
+            concat!("mrs {}, ", $reg),
+            out(reg) value,
+            options(nostack, nomem, preserves_flags)
+        );
+        value
+    }};
+}
+
+/// Most CPUs the kernel runs on
+pub const MAX_CPUS: usize = 4;
+
+/// Number of a CPU: 0 for the boot CPU, then in device tree order
+pub type CpuId = usize;
+
+/// Longest `cpu_up` and `cpu_down` wait for the CPU to change state
+const HOTPLUG_TIMEOUT_MS: u32 = 1000;
+
+/// `psci::cpu_suspend` power state of the standby state: the CPU keeps its context
+const PSCI_STANDBY: u32 = 0;
+
+/// State of a CPU
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum CpuState {
+    /// Off in the firmware
+    Offline = 0,
+    /// `cpu_on` was called, the CPU hasn't reached `secondary_start` yet
+    Starting = 1,
+    Online = 2,
+    /// Asked to go down by `cpu_down`
+    Dying = 3,
+}
+
+impl CpuState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            1 => Self::Starting,
+            2 => Self::Online,
+            3 => Self::Dying,
+            _ => Self::Offline,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Offline => "offline",
+            Self::Starting => "starting",
+            Self::Online => "online",
+            Self::Dying => "dying",
+        }
+    }
+}
+
+/// Errors returned by `cpu_up` and `cpu_down`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SmpError {
+    /// No CPU has this number
+    NoCpu,
+    /// Another hotplug operation is in progress
+    Busy,
+    /// The boot CPU can't go down
+    BootCpu,
+    /// The CPU is already online, or starting
+    AlreadyOnline,
+    /// The CPU is already offline
+    AlreadyOffline,
+    /// The firmware refused
+    Firmware(PsciError),
+    /// The CPU didn't change state in time
+    Timeout,
+    /// No idle task could be made for the CPU
+    Sched(SchedError),
+}
+
+/// A CPU of the device tree
+struct Cpu {
+    /// MPIDR affinity fields, as the PSCI calls take them
+    mpidr: AtomicU64,
+    /// `CpuState as u8`
+    state: AtomicU8,
+}
+
+static CPUS: [Cpu; MAX_CPUS] = [const {
+    Cpu {
+        mpidr: AtomicU64::new(0),
+        state: AtomicU8::new(CpuState::Offline as u8),
+    }
+}; MAX_CPUS];
+
+/// Number of CPUs found by `init`, 1 until then
+static NR_CPUS: AtomicUsize = AtomicUsize::new(1);
+
+/// CPUs that are online
+static ONLINE: AtomicU64 = AtomicU64::new(1);
+
+/// Set while `cpu_up` or `cpu_down` runs
+static HOTPLUG_BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Set once `psci::cpu_suspend` has failed: `cpu_do_idle` uses `WFI` from then on
+static NO_STANDBY: AtomicBool = AtomicBool::new(false);
+
+/// What a starting CPU needs before it can run Rust code, read by `secondary_entry`
+#[repr(C)]
+struct BootArgs {
+    mair: u64,
+    tcr: u64,
+    ttbr0: u64,
+    ttbr1: u64,
+    sctlr: u64,
+    vbar: u64,
+    /// Top of the CPU's stack
+    sp: u64,
+    cpu: u64,
+}
+
+/// The arguments of the CPU being started
+struct BootArgsCell(UnsafeCell<BootArgs>);
+
+// Only written by `cpu_up`, one hotplug operation running at a time, before `psci::cpu_on` starts
+// the CPU that reads them
+unsafe impl Sync for BootArgsCell {}
+
+/// Arguments of the CPU being started, written by `cpu_up`
+static BOOT_ARGS: BootArgsCell = BootArgsCell(UnsafeCell::new(BootArgs {
+    mair: 0,
+    tcr: 0,
+    ttbr0: 0,
+    ttbr1: 0,
+    sctlr: 0,
+    vbar: 0,
+    sp: 0,
+    cpu: 0,
+}));
+
+/// Stack of a secondary CPU
+#[repr(C, align(16))]
+struct Stack([u8; KSTACK_SIZE]);
+
+/// The stacks of the secondary CPUs
+struct Stacks(UnsafeCell<[Stack; MAX_CPUS - 1]>);
+
+// A stack is only used by its CPU; `cpu_up` just takes its address
+unsafe impl Sync for Stacks {}
+
+/// Stacks of the secondary CPUs, the `n - 1`th being CPU n's
+///
+/// Like the boot stack, they live in the image and have no guard.
+static STACKS: Stacks = Stacks(UnsafeCell::new(
+    [const { Stack([0; KSTACK_SIZE]) }; MAX_CPUS - 1],
+));
+
+global_asm!(
+    r#"
+    .pushsection .text.smp, "ax", %progbits
+
+    // Entry point of a secondary CPU, given to psci::cpu_on
+    // x0: physical address of the BootArgs, MMU off
+    .global secondary_entry
+    .type secondary_entry, %function
+secondary_entry:
+    msr daifset, #0b1111
+    mov x19, x0
+    // Leave EL2 as _start does
+    mrs x0, CurrentEL
+    cmp x0, #(2 << 2)
+    b.ne 1f
+    bl el2_to_el1
+1:  mov x0, #(3 << 20)
+    msr cpacr_el1, x0
+    ldr x0, [x19, #{mair}]
+    msr mair_el1, x0
+    ldr x0, [x19, #{tcr}]
+    msr tcr_el1, x0
+    ldr x0, [x19, #{ttbr0}]
+    msr ttbr0_el1, x0
+    ldr x0, [x19, #{ttbr1}]
+    msr ttbr1_el1, x0
+    isb
+    tlbi vmalle1
+    dsb nsh
+    ldr x0, [x19, #{sctlr}]
+    msr sctlr_el1, x0
+    isb
+    ic iallu
+    dsb nsh
+    isb
+    ldr x0, [x19, #{vbar}]
+    msr vbar_el1, x0
+    ldr x0, [x19, #{sp}]
+    mov sp, x0
+    ldr x0, [x19, #{cpu}]
+    msr tpidr_el1, x0
+    isb
+    bl secondary_start
+    b .
+    .size secondary_entry, . - secondary_entry
+
+    .popsection
+"#,
+    mair = const offset_of!(BootArgs, mair),
+    tcr = const offset_of!(BootArgs, tcr),
+    ttbr0 = const offset_of!(BootArgs, ttbr0),
+    ttbr1 = const offset_of!(BootArgs, ttbr1),
+    sctlr = const offset_of!(BootArgs, sctlr),
+    vbar = const offset_of!(BootArgs, vbar),
+    sp = const offset_of!(BootArgs, sp),
+    cpu = const offset_of!(BootArgs, cpu),
+);
+
+unsafe extern "C" {
+    fn secondary_entry();
+}
+
+/// Returns the number of the CPU the caller runs on
+#[inline(always)]
+pub fn this_cpu() -> CpuId {
+    let cpu: usize;
+    unsafe {
+        asm!("mrs {}, tpidr_el1", out(reg) cpu, options(nostack, nomem, preserves_flags));
+    }
+    cpu
+}
+
+/// Returns the number of CPUs in the device tree, at most `MAX_CPUS`
+pub fn nr_cpus() -> usize {
+    NR_CPUS.load(Ordering::Relaxed)
+}
+
+/// Returns the MPIDR affinity fields of `cpu`
+pub fn mpidr(cpu: CpuId) -> Option<u64> {
+    (cpu < nr_cpus()).then(|| CPUS[cpu].mpidr.load(Ordering::Relaxed))
+}
+
+/// Returns the state of `cpu`
+pub fn state(cpu: CpuId) -> Option<CpuState> {
+    (cpu < nr_cpus()).then(|| CpuState::from_u8(CPUS[cpu].state.load(Ordering::Acquire)))
+}
+
+/// Returns the CPUs that are online
+pub fn online_mask() -> CpuMask {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Returns every CPU of the device tree, online or not
+pub fn possible_mask() -> CpuMask {
+    (1 << nr_cpus()) - 1
+}
+
+fn set_state(cpu: CpuId, state: CpuState) {
+    CPUS[cpu].state.store(state as u8, Ordering::Release);
+}
+
+/// Numbers the CPUs of the device tree and brings the secondary ones up
+///
+/// Runs once, as a `Late` init call, when tasks can sleep.
+pub fn init() {
+    let boot = psci::this_cpu();
+    CPUS[0].mpidr.store(boot, Ordering::Relaxed);
+    set_state(0, CpuState::Online);
+    let mut count = 1;
+    let cpus = dtb::devices().iter().filter(|dev| {
+        dev.find_property("device_type")
+            .and_then(|prop| prop.as_str())
+            == Some("cpu")
+    });
+    for dev in cpus {
+        let Some(reg) = dev.find_property("reg") else {
+            continue;
+        };
+        let mpidr = reg.read_cells(0, dev.get_parent_cells().0);
+        if mpidr == boot {
+            continue;
+        }
+        if count == MAX_CPUS {
+            println!("smp: more than {} CPUs, {} ignored", MAX_CPUS, dev.name);
+            continue;
+        }
+        CPUS[count].mpidr.store(mpidr, Ordering::Relaxed);
+        count += 1;
+    }
+    NR_CPUS.store(count, Ordering::Release);
+    for cpu in 1..count {
+        if let Err(e) = cpu_up(cpu) {
+            pr_err!("smp: cannot bring CPU {} up: {:?}", cpu, e);
+        }
+    }
+    println!("smp: {} CPUs, {} online", count, online_mask().count_ones());
+}
+initcall!(Late, "smp", init, after = ["tick"]);
+
+/// Held while a hotplug operation runs, see `hotplug_begin`
+struct Hotplug;
+
+impl Drop for Hotplug {
+    fn drop(&mut self) {
+        HOTPLUG_BUSY.store(false, Ordering::Release);
+    }
+}
+
+fn hotplug_begin() -> Result<Hotplug, SmpError> {
+    if HOTPLUG_BUSY.swap(true, Ordering::Acquire) {
+        return Err(SmpError::Busy);
+    }
+    Ok(Hotplug)
+}
+
+/// Marks `cpu` online or offline according to the firmware, after it didn't change state in time
+///
+/// A CPU that is on, or being turned on, is online. One that is off is offline, and so is one the
+/// firmware can't report on: no task is given it.
+fn recover_state(cpu: CpuId, mpidr: u64) {
+    match psci::affinity_info(mpidr) {
+        Ok(AffinityState::On | AffinityState::OnPending) => {
+            ONLINE.fetch_or(1 << cpu, Ordering::AcqRel);
+            set_state(cpu, CpuState::Online);
+        }
+        Ok(AffinityState::Off) | Err(_) => {
+            ONLINE.fetch_and(!(1 << cpu), Ordering::AcqRel);
+            set_state(cpu, CpuState::Offline);
+        }
+    }
+}
+
+/// Sleeps until `done` returns true, failing after `HOTPLUG_TIMEOUT_MS`
+fn wait_for(mut done: impl FnMut() -> Result<bool, SmpError>) -> Result<(), SmpError> {
+    for _ in 0..HOTPLUG_TIMEOUT_MS {
+        if done()? {
+            return Ok(());
+        }
+        sched::sleep_ms(1);
+    }
+    Err(SmpError::Timeout)
+}
+
+/// Starts `cpu` and waits until it is online
+///
+/// Must not be called from interrupt context.
+pub fn cpu_up(cpu: CpuId) -> Result<(), SmpError> {
+    let _hotplug = hotplug_begin()?;
+    let mpidr = mpidr(cpu).ok_or(SmpError::NoCpu)?;
+    if state(cpu) != Some(CpuState::Offline) {
+        return Err(SmpError::AlreadyOnline);
+    }
+    set_state(cpu, CpuState::Starting);
+    if let Err(e) = sched::init_idle(cpu) {
+        set_state(cpu, CpuState::Offline);
+        return Err(SmpError::Sched(e));
+    }
+    let args = BOOT_ARGS.0.get();
+    unsafe {
+        let stack = STACKS.0.get().cast::<Stack>().add(cpu - 1) as usize;
+        args.write(BootArgs {
+            mair: read_sysreg!("mair_el1"),
+            tcr: read_sysreg!("tcr_el1"),
+            ttbr0: addr_space::kernel_ttbr0(),
+            ttbr1: read_sysreg!("ttbr1_el1"),
+            sctlr: read_sysreg!("sctlr_el1"),
+            vbar: read_sysreg!("vbar_el1"),
+            sp: (stack + KSTACK_SIZE) as u64,
+            cpu: cpu as u64,
+        });
+    }
+    // Read with the MMU, and so the caches, off
+    cache::clean_dcache_range(args as usize, size_of::<BootArgs>());
+    if let Err(e) = psci::cpu_on(mpidr, secondary_entry as *const () as usize, args as u64) {
+        set_state(cpu, CpuState::Offline);
+        return Err(SmpError::Firmware(e));
+    }
+    wait_for(|| Ok(state(cpu) == Some(CpuState::Online))).inspect_err(|&e| {
+        if e == SmpError::Timeout {
+            recover_state(cpu, mpidr);
+        }
+    })
+}
+
+/// Takes `cpu` offline: its idle task moves its tasks away and powers it off
+///
+/// Must not be called from interrupt context.
+pub fn cpu_down(cpu: CpuId) -> Result<(), SmpError> {
+    if cpu == 0 {
+        return Err(SmpError::BootCpu);
+    }
+    let _hotplug = hotplug_begin()?;
+    let mpidr = mpidr(cpu).ok_or(SmpError::NoCpu)?;
+    if state(cpu) != Some(CpuState::Online) {
+        return Err(SmpError::AlreadyOffline);
+    }
+    ONLINE.fetch_and(!(1 << cpu), Ordering::AcqRel);
+    set_state(cpu, CpuState::Dying);
+    sched::resched_cpu(cpu);
+    wait_for(|| match psci::affinity_info(mpidr) {
+        Ok(state) => Ok(state == AffinityState::Off),
+        Err(e) => Err(SmpError::Firmware(e)),
+    })
+    .inspect_err(|&e| {
+        if e == SmpError::Timeout {
+            recover_state(cpu, mpidr);
+        }
+    })?;
+    set_state(cpu, CpuState::Offline);
+    Ok(())
+}
+
+/// First Rust code run by a secondary CPU, on its own stack with the MMU on
+///
+/// Becomes the CPU's idle task, which `cpu_up` has made ready for it.
+#[unsafe(no_mangle)]
+extern "C" fn secondary_start(cpu: CpuId) -> ! {
+    gic::init_cpu();
+    arch_timer::start_cpu_tick();
+    ONLINE.fetch_or(1 << cpu, Ordering::AcqRel);
+    set_state(cpu, CpuState::Online);
+    irq::local_irq_enable();
+    sched::cpu_idle();
+}
+
+/// Powers the calling CPU off, once its idle task has moved its tasks away
+///
+/// Called by `sched::cpu_idle` with interrupts masked after `cpu_down` took the CPU offline.
+pub fn cpu_die() -> ! {
+    gic::disable_cpu();
+    let e = psci::cpu_off();
+    // `cpu_down` times out
+    pr_err!("smp: CPU {} cannot power off: {:?}", this_cpu(), e);
+    loop {
+        irq::wait_for_interrupt();
+    }
+}
+
+/// Waits for an interrupt in the firmware's standby state
+///
+/// As with `WFI`, a pending interrupt wakes the CPU up even if masked.
+pub fn cpu_do_idle() {
+    if !NO_STANDBY.load(Ordering::Relaxed) {
+        match psci::cpu_suspend(PSCI_STANDBY, 0, 0) {
+            Ok(()) => return,
+            Err(_) => NO_STANDBY.store(true, Ordering::Relaxed),
+        }
+    }
+    irq::wait_for_interrupt();
+}
@@ -0,0 +1,1032 @@
+//! System calls
+//!
+//! User tasks enter the kernel with `svc #0`, the system call number in `x8` and the arguments in
+//! `x0` to `x5`. The result is returned in `x0`, negative values being error codes.
+//!
+//! File operations go through the VFS on the calling task's file descriptors; user buffers are
+//! checked against the task's memory areas and copied through a small kernel buffer, one chunk at
+//! a time (see `uaccess`). A bad pointer fails with `EFAULT`.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Numbers, argument order, structures and error codes follow the Linux AArch64 ABI (the generic
+//! `unistd.h` table), so a static program only using the calls below runs unmodified.
+
+use crate::kernel::fs::vfs::{self, FileKind, FsError, MAX_NAME, MAX_PATH, OpenMode, Whence};
+use crate::kernel::irq::Regs;
+use crate::kernel::log::{self, ringbuf};
+use crate::kernel::mm;
+use crate::kernel::mm::addr_space::{MapFlags, VmError};
+use crate::kernel::mm::pgtable::PAGE_SIZE;
+use crate::kernel::net::socket::{self, SocketId};
+use crate::kernel::net::{Ipv4Addr, NetError};
+use crate::kernel::process::{self, Pid, ProcessError, WaitFor};
+use crate::kernel::sched::{self, CpuMask, SchedError, TaskId};
+use crate::kernel::signal::{self, SigAction, SignalError};
+use crate::kernel::time::clocksource::{self, NSEC_PER_SEC};
+use crate::kernel::time::walltime;
+use crate::kernel::trace::syscalls;
+use crate::kernel::uaccess::{self, Pod, UserPtr};
+
+const SYS_IOCTL: u64 = 29;
+const SYS_GETDENTS64: u64 = 61;
+const SYS_LSEEK: u64 = 62;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_SYSLOG: u64 = 116;
+const SYS_SCHED_SETAFFINITY: u64 = 122;
+const SYS_SCHED_GETAFFINITY: u64 = 123;
+const SYS_SCHED_YIELD: u64 = 124;
+const SYS_KILL: u64 = 129;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_RT_SIGRETURN: u64 = 139;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_SYSINFO: u64 = 179;
+const SYS_SOCKET: u64 = 198;
+const SYS_BIND: u64 = 200;
+const SYS_LISTEN: u64 = 201;
+const SYS_ACCEPT: u64 = 202;
+const SYS_CONNECT: u64 = 203;
+const SYS_SENDTO: u64 = 206;
+const SYS_RECVFROM: u64 = 207;
+const SYS_SHUTDOWN: u64 = 210;
+const SYS_MUNMAP: u64 = 215;
+const SYS_CLONE: u64 = 220;
+const SYS_MMAP: u64 = 222;
+const SYS_ACCEPT4: u64 = 242;
+const SYS_WAIT4: u64 = 260;
+
+const ENOENT: i64 = 2;
+const ESRCH: i64 = 3;
+const EINTR: i64 = 4;
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const ECHILD: i64 = 10;
+const EAGAIN: i64 = 11;
+const ENOMEM: i64 = 12;
+const EACCES: i64 = 13;
+const EFAULT: i64 = 14;
+const EBUSY: i64 = 16;
+const ENODEV: i64 = 19;
+const ENOTDIR: i64 = 20;
+const EISDIR: i64 = 21;
+const EINVAL: i64 = 22;
+const EMFILE: i64 = 24;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const EROFS: i64 = 30;
+const ENAMETOOLONG: i64 = 36;
+const ENOSYS: i64 = 38;
+const ENOTSOCK: i64 = 88;
+const EMSGSIZE: i64 = 90;
+const EPROTONOSUPPORT: i64 = 93;
+const EAFNOSUPPORT: i64 = 97;
+const EADDRINUSE: i64 = 98;
+const ENETUNREACH: i64 = 101;
+const ECONNRESET: i64 = 104;
+const ENOBUFS: i64 = 105;
+const ENOTCONN: i64 = 107;
+const ETIMEDOUT: i64 = 110;
+const ECONNREFUSED: i64 = 111;
+
+/// Access mode bits of the `openat` flags
+const O_ACCMODE: u64 = 0o3;
+/// `openat` flag: fail unless the path is a directory (AArch64 value)
+const O_DIRECTORY: u64 = 0o40000;
+
+/// `clone` flags: signal sent to the parent when the child exits, in the low byte
+const CSIGNAL: u64 = 0xff;
+
+/// `wait4` option: return 0 rather than wait if no child has exited
+const WNOHANG: u64 = 1;
+
+/// `mmap` protection bits
+const PROT_READ: u64 = 1 << 0;
+const PROT_WRITE: u64 = 1 << 1;
+const PROT_EXEC: u64 = 1 << 2;
+
+/// `mmap` flags
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+/// `clock_gettime` clocks
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+const CLOCK_MONOTONIC_RAW: u64 = 4;
+const CLOCK_REALTIME_COARSE: u64 = 5;
+const CLOCK_MONOTONIC_COARSE: u64 = 6;
+const CLOCK_BOOTTIME: u64 = 7;
+
+/// `syslog` actions
+const SYSLOG_ACTION_CLOSE: u64 = 0;
+const SYSLOG_ACTION_OPEN: u64 = 1;
+const SYSLOG_ACTION_READ_ALL: u64 = 3;
+const SYSLOG_ACTION_READ_CLEAR: u64 = 4;
+const SYSLOG_ACTION_CLEAR: u64 = 5;
+const SYSLOG_ACTION_SIZE_BUFFER: u64 = 10;
+
+/// `rt_sigprocmask` operations
+const SIG_BLOCK: u64 = 0;
+const SIG_UNBLOCK: u64 = 1;
+const SIG_SETMASK: u64 = 2;
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+/// `d_type` values of `linux_dirent64`
+const DT_CHR: u8 = 2;
+const DT_DIR: u8 = 4;
+const DT_BLK: u8 = 6;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+const DT_SOCK: u8 = 12;
+
+/// Socket address family, type and protocol: TCP over IPv4 is the only kind
+const AF_INET: u16 = 2;
+const SOCK_STREAM: u64 = 1;
+const IPPROTO_TCP: u64 = 6;
+/// Bits of the socket type giving the type, the others being flags (`SOCK_NONBLOCK`, ...)
+const SOCK_TYPE_MASK: u64 = 0xf;
+
+/// Size of the fixed part of `linux_dirent64`
+const DIRENT_HEADER: usize = 19;
+
+/// Size of the kernel buffer user data is copied through
+const CHUNK_SIZE: usize = 256;
+
+/// `struct sysinfo` of the 64-bit ABI
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct SysInfo {
+    /// Seconds since boot
+    uptime: i64,
+    /// Load averages over 1, 5 and 15 minutes, not tracked
+    loads: [u64; 3],
+    /// Memory sizes, in `mem_unit`s
+    totalram: u64,
+    freeram: u64,
+    sharedram: u64,
+    bufferram: u64,
+    totalswap: u64,
+    freeswap: u64,
+    /// Number of tasks
+    procs: u16,
+    pad: [u16; 3],
+    totalhigh: u64,
+    freehigh: u64,
+    mem_unit: u32,
+    pad2: u32,
+}
+
+const _: () = assert!(size_of::<SysInfo>() == 112);
+
+unsafe impl Pod for SysInfo {}
+
+/// `struct timespec`
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Timespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+unsafe impl Pod for Timespec {}
+
+/// `struct timeval`
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Timeval {
+    tv_sec: i64,
+    tv_usec: i64,
+}
+
+unsafe impl Pod for Timeval {}
+
+/// `struct timezone`, always UTC
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Timezone {
+    tz_minuteswest: i32,
+    tz_dsttime: i32,
+}
+
+unsafe impl Pod for Timezone {}
+
+/// `struct sockaddr_in`
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct SockAddrIn {
+    family: u16,
+    /// Port and address in network byte order
+    port: [u8; 2],
+    addr: [u8; 4],
+    zero: [u8; 8],
+}
+
+const _: () = assert!(size_of::<SockAddrIn>() == 16);
+
+unsafe impl Pod for SockAddrIn {}
+
+/// What tracing shows of a system call
+#[derive(Clone, Copy, Debug)]
+pub struct SyscallDesc {
+    pub name: &'static str,
+    /// Number of arguments
+    pub args: usize,
+    /// False for the calls that never return to the caller
+    pub returns: bool,
+}
+
+const fn desc(name: &'static str, args: usize) -> SyscallDesc {
+    SyscallDesc {
+        name,
+        args,
+        returns: true,
+    }
+}
+
+/// The system calls by number
+const DESCS: [(u64, SyscallDesc); 35] = [
+    (SYS_IOCTL, desc("ioctl", 3)),
+    (SYS_OPENAT, desc("openat", 4)),
+    (SYS_CLOSE, desc("close", 1)),
+    (SYS_GETDENTS64, desc("getdents64", 3)),
+    (SYS_LSEEK, desc("lseek", 3)),
+    (SYS_READ, desc("read", 3)),
+    (SYS_WRITE, desc("write", 3)),
+    (
+        SYS_EXIT,
+        SyscallDesc {
+            returns: false,
+            ..desc("exit", 1)
+        },
+    ),
+    (
+        SYS_EXIT_GROUP,
+        SyscallDesc {
+            returns: false,
+            ..desc("exit_group", 1)
+        },
+    ),
+    (SYS_CLOCK_GETTIME, desc("clock_gettime", 2)),
+    (SYS_SYSLOG, desc("syslog", 3)),
+    (SYS_SCHED_SETAFFINITY, desc("sched_setaffinity", 3)),
+    (SYS_SCHED_GETAFFINITY, desc("sched_getaffinity", 3)),
+    (SYS_SCHED_YIELD, desc("sched_yield", 0)),
+    (SYS_KILL, desc("kill", 2)),
+    (SYS_RT_SIGACTION, desc("rt_sigaction", 4)),
+    (SYS_RT_SIGPROCMASK, desc("rt_sigprocmask", 4)),
+    (SYS_RT_SIGRETURN, desc("rt_sigreturn", 0)),
+    (SYS_GETTIMEOFDAY, desc("gettimeofday", 2)),
+    (SYS_GETPID, desc("getpid", 0)),
+    (SYS_GETPPID, desc("getppid", 0)),
+    (SYS_SYSINFO, desc("sysinfo", 1)),
+    (SYS_SOCKET, desc("socket", 3)),
+    (SYS_BIND, desc("bind", 3)),
+    (SYS_LISTEN, desc("listen", 2)),
+    (SYS_ACCEPT, desc("accept", 3)),
+    (SYS_CONNECT, desc("connect", 3)),
+    (SYS_SENDTO, desc("sendto", 6)),
+    (SYS_RECVFROM, desc("recvfrom", 6)),
+    (SYS_SHUTDOWN, desc("shutdown", 2)),
+    (SYS_MUNMAP, desc("munmap", 2)),
+    (SYS_CLONE, desc("clone", 5)),
+    (SYS_MMAP, desc("mmap", 6)),
+    (SYS_ACCEPT4, desc("accept4", 4)),
+    (SYS_WAIT4, desc("wait4", 4)),
+];
+
+/// Returns the description of system call `nr`, `None` if it isn't implemented
+pub fn describe(nr: u64) -> Option<SyscallDesc> {
+    DESCS.iter().find(|(n, _)| *n == nr).map(|(_, desc)| *desc)
+}
+
+/// Runs the system call described by `regs` and stores its result in `x0`
+///
+/// The call is logged if its task is traced (see `trace::syscalls`).
+pub fn dispatch(regs: &mut Regs) {
+    let call = syscalls::enter(regs);
+    run(regs);
+    if let Some(call) = call {
+        syscalls::exit(call, regs.x0);
+    }
+}
+
+/// Runs the system call described by `regs`
+fn run(regs: &mut Regs) {
+    // Restores every register, `x0` included
+    if regs.x8 == SYS_RT_SIGRETURN {
+        if !signal::sigreturn(regs) {
+            sched::exit(128 + signal::SIGSEGV as i32);
+        }
+        return;
+    }
+    let (a0, a1, a2) = (regs.x0, regs.x1, regs.x2);
+    let (a3, a4, a5) = (regs.x3, regs.x4, regs.x5);
+    let ret = match regs.x8 {
+        SYS_OPENAT => sys_openat(a1 as usize, a2),
+        SYS_IOCTL => vfs::ioctl(a0 as usize, a1 as u32, a2 as usize)
+            .map(|ret| ret as u64)
+            .map_err(errno),
+        SYS_CLOSE => vfs::close(a0 as usize).map(|_| 0).map_err(errno),
+        SYS_GETDENTS64 => sys_getdents64(a0 as usize, a1 as usize, a2 as usize),
+        SYS_LSEEK => sys_lseek(a0 as usize, a1 as i64, a2),
+        SYS_READ => sys_read(a0 as usize, UserPtr::new(a1 as usize), a2 as usize),
+        SYS_WRITE => sys_write(a0 as usize, UserPtr::new(a1 as usize), a2 as usize),
+        SYS_EXIT | SYS_EXIT_GROUP => sched::exit(a0 as i32),
+        SYS_CLOCK_GETTIME => sys_clock_gettime(a0, UserPtr::new(a1 as usize)),
+        SYS_SYSLOG => sys_syslog(a0, UserPtr::new(a1 as usize), a2 as usize),
+        SYS_SCHED_SETAFFINITY => {
+            sys_sched_setaffinity(a0 as i32, a1 as usize, UserPtr::new(a2 as usize))
+        }
+        SYS_SCHED_GETAFFINITY => {
+            sys_sched_getaffinity(a0 as i32, a1 as usize, UserPtr::new(a2 as usize))
+        }
+        SYS_SCHED_YIELD => {
+            sched::yield_now();
+            Ok(0)
+        }
+        SYS_KILL => sys_kill(a0 as i32, a1 as usize),
+        SYS_RT_SIGACTION => {
+            let (act, oldact) = (UserPtr::new(a1 as usize), UserPtr::new(a2 as usize));
+            sys_rt_sigaction(a0 as usize, act, oldact, a3)
+        }
+        SYS_RT_SIGPROCMASK => {
+            let (set, oldset) = (UserPtr::new(a1 as usize), UserPtr::new(a2 as usize));
+            sys_rt_sigprocmask(a0, set, oldset, a3)
+        }
+        SYS_GETTIMEOFDAY => {
+            let (tv, tz) = (UserPtr::new(a0 as usize), UserPtr::new(a1 as usize));
+            sys_gettimeofday(tv, tz)
+        }
+        SYS_GETPID => Ok(process::current_pid().unwrap_or(0) as u64),
+        SYS_GETPPID => Ok(process::current_ppid().unwrap_or(0) as u64),
+        SYS_SYSINFO => sys_sysinfo(UserPtr::new(a0 as usize)),
+        SYS_CLONE => sys_clone(regs, a0, a1),
+        SYS_WAIT4 => sys_wait4(a0 as i32, UserPtr::new(a1 as usize), a2),
+        SYS_MMAP => sys_mmap(a0 as usize, a1 as usize, a2, a3, a4 as i32, a5 as usize),
+        SYS_MUNMAP => sys_munmap(a0 as usize, a1 as usize),
+        SYS_SOCKET => sys_socket(a0, a1, a2),
+        SYS_BIND => sys_bind(a0 as usize, UserPtr::new(a1 as usize), a2 as usize),
+        SYS_LISTEN => socket_id(a0 as usize)
+            .and_then(|id| socket::listen(id).map_err(net_errno))
+            .map(|()| 0),
+        SYS_ACCEPT | SYS_ACCEPT4 => {
+            let (addr, addrlen) = (UserPtr::new(a1 as usize), UserPtr::new(a2 as usize));
+            sys_accept(a0 as usize, addr, addrlen)
+        }
+        SYS_CONNECT => sys_connect(a0 as usize, UserPtr::new(a1 as usize), a2 as usize),
+        SYS_SENDTO => sys_sendto(a0 as usize, UserPtr::new(a1 as usize), a2 as usize),
+        SYS_RECVFROM => {
+            let (addr, addrlen) = (UserPtr::new(a4 as usize), UserPtr::new(a5 as usize));
+            sys_recvfrom(
+                a0 as usize,
+                UserPtr::new(a1 as usize),
+                a2 as usize,
+                addr,
+                addrlen,
+            )
+        }
+        SYS_SHUTDOWN => socket_id(a0 as usize)
+            .and_then(|id| socket::shutdown(id).map_err(net_errno))
+            .map(|()| 0),
+        _ => Err(ENOSYS),
+    };
+    regs.x0 = match ret {
+        Ok(value) => value,
+        Err(errno) => -errno as u64,
+    };
+}
+
+/// Returns the error code of a VFS error
+fn errno(e: FsError) -> i64 {
+    match e {
+        FsError::NotFound => ENOENT,
+        FsError::NotDirectory => ENOTDIR,
+        FsError::IsDirectory => EISDIR,
+        FsError::ReadOnly => EROFS,
+        FsError::BadMode | FsError::BadFd => EBADF,
+        FsError::TooManyOpen => EMFILE,
+        FsError::NameTooLong => ENAMETOOLONG,
+        FsError::NotSeekable => ESPIPE,
+        FsError::Busy => EBUSY,
+        FsError::Invalid => EINVAL,
+        FsError::Io => EIO,
+        FsError::BadIoctl => ENOTTY,
+        FsError::Interrupted => EINTR,
+        FsError::BadAddress => EFAULT,
+    }
+}
+
+/// Returns the error code of a network error
+fn net_errno(e: NetError) -> i64 {
+    match e {
+        NetError::NoDevice => ENODEV,
+        NetError::NoRoute => ENETUNREACH,
+        NetError::Busy | NetError::NoSpace | NetError::NoBuffer => ENOBUFS,
+        NetError::TooBig => EMSGSIZE,
+        NetError::AddrInUse => EADDRINUSE,
+        NetError::Refused => ECONNREFUSED,
+        NetError::Reset => ECONNRESET,
+        NetError::TimedOut => ETIMEDOUT,
+        NetError::NotConnected => ENOTCONN,
+        NetError::Invalid => EINVAL,
+        NetError::Interrupted => EINTR,
+    }
+}
+
+/// Returns the error code of a signal error
+fn signal_errno(e: SignalError) -> i64 {
+    match e {
+        SignalError::BadSignal => EINVAL,
+        SignalError::NotFound => ESRCH,
+    }
+}
+
+/// Returns the error code of an address space error
+fn vm_errno(e: VmError) -> i64 {
+    match e {
+        VmError::NoMemory | VmError::NoAsid | VmError::NoSpace => ENOMEM,
+        VmError::BadAddress | VmError::Exists | VmError::NotMapped => EINVAL,
+    }
+}
+
+/// Copies the NUL-terminated string at `src` into `buf`
+fn copy_path(src: usize, buf: &mut [u8; MAX_PATH]) -> Result<&str, i64> {
+    let mut len = 0;
+    while len < MAX_PATH {
+        // Stop at the page boundary: the next page may not be mapped
+        let addr = src.checked_add(len).ok_or(EFAULT)?;
+        let chunk = (PAGE_SIZE - addr % PAGE_SIZE).min(MAX_PATH - len);
+        uaccess::copy_from_user(&mut buf[len..len + chunk], addr).map_err(|_| EFAULT)?;
+        if let Some(nul) = buf[len..len + chunk].iter().position(|&b| b == 0) {
+            return core::str::from_utf8(&buf[..len + nul]).map_err(|_| EINVAL);
+        }
+        len += chunk;
+    }
+    Err(ENAMETOOLONG)
+}
+
+/// Opens a file, always relative to `/` as there is no working directory
+fn sys_openat(path: usize, flags: u64) -> Result<u64, i64> {
+    let mut buf = [0; MAX_PATH];
+    let path = copy_path(path, &mut buf)?;
+    let mode = match flags & O_ACCMODE {
+        0 => OpenMode::Read,
+        1 => OpenMode::Write,
+        2 => OpenMode::ReadWrite,
+        _ => return Err(EINVAL),
+    };
+    let node = vfs::lookup(path).map_err(errno)?;
+    if flags & O_DIRECTORY != 0 && node.stat().map_err(errno)?.kind != FileKind::Directory {
+        return Err(ENOTDIR);
+    }
+    vfs::open_node(node, mode)
+        .map(|fd| fd as u64)
+        .map_err(errno)
+}
+
+/// Creates a private mapping, anonymous or of the file `fd`; pages are filled on first access
+///
+/// Without `MAP_FIXED`, `addr` is ignored and the kernel picks the address. Shared mappings are
+/// not supported.
+fn sys_mmap(
+    addr: usize,
+    len: usize,
+    prot: u64,
+    flags: u64,
+    fd: i32,
+    offset: usize,
+) -> Result<u64, i64> {
+    if flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE || len == 0 {
+        return Err(EINVAL);
+    }
+    let mut map_flags = MapFlags::READ;
+    if prot & PROT_WRITE != 0 {
+        map_flags = map_flags.union(MapFlags::WRITE);
+    }
+    if prot & PROT_EXEC != 0 {
+        map_flags = map_flags.union(MapFlags::EXEC);
+    }
+    // Pages are always readable, so there is no `PROT_NONE`
+    if prot & (PROT_READ | PROT_WRITE | PROT_EXEC) == 0 {
+        return Err(EINVAL);
+    }
+    let addr = (flags & MAP_FIXED != 0).then_some(addr);
+    let file = if flags & MAP_ANONYMOUS == 0 {
+        let (node, mode) = vfs::fd_node(fd as usize).map_err(errno)?;
+        match node.stat().map_err(errno)?.kind {
+            FileKind::File if mode != OpenMode::Write => Some(node),
+            FileKind::File => return Err(EACCES),
+            _ => return Err(ENODEV),
+        }
+    } else {
+        None
+    };
+    sched::with_current_mm(|mm| {
+        // A fixed mapping replaces whatever was there
+        if let Some(addr) = addr {
+            mm.unmap(addr, len)?;
+        }
+        match file {
+            Some(node) => mm.map_file(addr, len, map_flags, node, offset),
+            None => mm.map_anon(addr, len, map_flags),
+        }
+    })
+    .ok_or(EINVAL)?
+    .map(|addr| addr as u64)
+    .map_err(vm_errno)
+}
+
+/// Creates a child task, only as `fork` does: no shared memory, files or threads
+///
+/// The exit signal is ignored, and so is a child stack equal to 0.
+fn sys_clone(regs: &Regs, flags: u64, stack: u64) -> Result<u64, i64> {
+    if flags & !CSIGNAL != 0 || stack != 0 {
+        return Err(EINVAL);
+    }
+    let id = sched::fork(regs).map_err(|e| match e {
+        SchedError::NoSpace => EAGAIN,
+        _ => ENOMEM,
+    })?;
+    Ok(process::pid_of(id).unwrap_or(0) as u64)
+}
+
+/// Returns the task of process `pid`, the caller's for 0
+fn pid_task(pid: i32) -> Result<TaskId, i64> {
+    match pid {
+        0 => sched::current().ok_or(ESRCH),
+        pid if pid > 0 => process::task_of(pid as Pid).ok_or(ESRCH),
+        _ => Err(EINVAL),
+    }
+}
+
+/// `sched_setaffinity(pid, len, mask)`; CPUs beyond the mask's first word are ignored
+fn sys_sched_setaffinity(pid: i32, len: usize, mask: UserPtr<CpuMask>) -> Result<u64, i64> {
+    if len < size_of::<CpuMask>() {
+        return Err(EINVAL);
+    }
+    let task = pid_task(pid)?;
+    let mask = mask.read().map_err(|_| EFAULT)?;
+    sched::set_affinity(task, mask)
+        .map(|()| 0)
+        .map_err(|e| match e {
+            SchedError::NotFound => ESRCH,
+            _ => EINVAL,
+        })
+}
+
+/// `sched_getaffinity(pid, len, mask)`, returning the size of the mask written
+fn sys_sched_getaffinity(pid: i32, len: usize, mask: UserPtr<CpuMask>) -> Result<u64, i64> {
+    if len < size_of::<CpuMask>() {
+        return Err(EINVAL);
+    }
+    let affinity = sched::affinity(pid_task(pid)?).ok_or(ESRCH)?;
+    mask.write(&affinity).map_err(|_| EFAULT)?;
+    Ok(size_of::<CpuMask>() as u64)
+}
+
+/// Sends `sig` to process `pid`; process groups (`pid` <= 0) aren't supported
+fn sys_kill(pid: i32, sig: usize) -> Result<u64, i64> {
+    if pid <= 0 {
+        return Err(EINVAL);
+    }
+    let task = process::task_of(pid as Pid).ok_or(ESRCH)?;
+    signal::send(task, sig).map(|()| 0).map_err(signal_errno)
+}
+
+/// `wait4(pid, status, options, rusage)` for a child (`pid` > 0) or any child (`pid` -1)
+///
+/// The status only encodes a normal exit, as `exit_code << 8`; the resource usage is left
+/// untouched.
+fn sys_wait4(pid: i32, status: UserPtr<i32>, options: u64) -> Result<u64, i64> {
+    if options & !WNOHANG != 0 {
+        return Err(EINVAL);
+    }
+    let which = match pid {
+        -1 => WaitFor::Any,
+        pid if pid > 0 => WaitFor::Pid(pid as Pid),
+        _ => return Err(EINVAL),
+    };
+    let waited = process::wait(which, options & WNOHANG != 0).map_err(|e| match e {
+        ProcessError::Interrupted => EINTR,
+        _ => ECHILD,
+    })?;
+    let Some((pid, code)) = waited else {
+        return Ok(0);
+    };
+    if status.addr() != 0 {
+        status.write(&((code & 0xff) << 8)).map_err(|_| EFAULT)?;
+    }
+    Ok(pid as u64)
+}
+
+fn sys_munmap(addr: usize, len: usize) -> Result<u64, i64> {
+    sched::with_current_mm(|mm| mm.unmap(addr, len))
+        .ok_or(EINVAL)?
+        .map(|()| 0)
+        .map_err(vm_errno)
+}
+
+fn sys_lseek(fd: usize, offset: i64, whence: u64) -> Result<u64, i64> {
+    let whence = match whence {
+        SEEK_SET => Whence::Set,
+        SEEK_CUR => Whence::Current,
+        SEEK_END => Whence::End,
+        _ => return Err(EINVAL),
+    };
+    vfs::seek(fd, offset, whence)
+        .map(|pos| pos as u64)
+        .map_err(errno)
+}
+
+/// Reads up to `count` bytes, stopping at the first short read
+///
+/// The whole buffer is checked first, so no data is consumed from the file for a bad buffer.
+fn sys_read(fd: usize, buf: UserPtr<u8>, count: usize) -> Result<u64, i64> {
+    if !buf.access_ok(count, true) {
+        return Err(EFAULT);
+    }
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut done = 0;
+    while done < count {
+        let len = (count - done).min(CHUNK_SIZE);
+        let read = vfs::read(fd, &mut chunk[..len]).map_err(errno)?;
+        buf.offset(done)
+            .write_slice(&chunk[..read])
+            .map_err(|_| EFAULT)?;
+        done += read;
+        if read < len {
+            break;
+        }
+    }
+    Ok(done as u64)
+}
+
+fn sys_write(fd: usize, buf: UserPtr<u8>, count: usize) -> Result<u64, i64> {
+    if !buf.access_ok(count, false) {
+        return Err(EFAULT);
+    }
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut done = 0;
+    while done < count {
+        let len = (count - done).min(CHUNK_SIZE);
+        buf.offset(done)
+            .read_slice(&mut chunk[..len])
+            .map_err(|_| EFAULT)?;
+        let written = vfs::write(fd, &chunk[..len]).map_err(errno)?;
+        done += written;
+        if written < len {
+            break;
+        }
+    }
+    Ok(done as u64)
+}
+
+/// Returns the socket open as `fd`
+fn socket_id(fd: usize) -> Result<SocketId, i64> {
+    let (node, _) = vfs::fd_node(fd).map_err(errno)?;
+    socket::id(node).ok_or(ENOTSOCK)
+}
+
+/// Reads the IPv4 address and port at `addr`, `len` bytes long
+fn read_sockaddr(addr: UserPtr<SockAddrIn>, len: usize) -> Result<(Ipv4Addr, u16), i64> {
+    if len < size_of::<SockAddrIn>() {
+        return Err(EINVAL);
+    }
+    let addr = addr.read().map_err(|_| EFAULT)?;
+    if addr.family != AF_INET {
+        return Err(EAFNOSUPPORT);
+    }
+    Ok((Ipv4Addr(addr.addr), u16::from_be_bytes(addr.port)))
+}
+
+/// Writes `ip` and `port` to `addr` and their size to `addrlen`, unless `addr` is null
+fn write_sockaddr(
+    addr: UserPtr<SockAddrIn>,
+    addrlen: UserPtr<u32>,
+    ip: Ipv4Addr,
+    port: u16,
+) -> Result<(), i64> {
+    if addr.addr() == 0 {
+        return Ok(());
+    }
+    if (addrlen.read().map_err(|_| EFAULT)? as usize) < size_of::<SockAddrIn>() {
+        return Err(EINVAL);
+    }
+    let value = SockAddrIn {
+        family: AF_INET,
+        port: port.to_be_bytes(),
+        addr: ip.0,
+        ..Default::default()
+    };
+    addr.write(&value).map_err(|_| EFAULT)?;
+    addrlen
+        .write(&(size_of::<SockAddrIn>() as u32))
+        .map_err(|_| EFAULT)
+}
+
+/// `socket(domain, type, protocol)`; the flags in `type` are ignored
+fn sys_socket(domain: u64, kind: u64, protocol: u64) -> Result<u64, i64> {
+    if domain != AF_INET as u64 {
+        return Err(EAFNOSUPPORT);
+    }
+    if kind & SOCK_TYPE_MASK != SOCK_STREAM || !matches!(protocol, 0 | IPPROTO_TCP) {
+        return Err(EPROTONOSUPPORT);
+    }
+    socket::open().map(|fd| fd as u64).map_err(net_errno)
+}
+
+/// `bind(fd, addr, addrlen)`: only the port matters, every address being local
+fn sys_bind(fd: usize, addr: UserPtr<SockAddrIn>, len: usize) -> Result<u64, i64> {
+    let id = socket_id(fd)?;
+    let (_, port) = read_sockaddr(addr, len)?;
+    socket::bind(id, port).map(|()| 0).map_err(net_errno)
+}
+
+/// `accept(fd, addr, addrlen)`, and `accept4` whose flags are ignored
+fn sys_accept(fd: usize, addr: UserPtr<SockAddrIn>, addrlen: UserPtr<u32>) -> Result<u64, i64> {
+    let id = socket_id(fd)?;
+    let (conn, ip, port) = socket::accept(id).map_err(net_errno)?;
+    if let Err(e) = write_sockaddr(addr, addrlen, ip, port) {
+        let _ = vfs::close(conn);
+        return Err(e);
+    }
+    Ok(conn as u64)
+}
+
+fn sys_connect(fd: usize, addr: UserPtr<SockAddrIn>, len: usize) -> Result<u64, i64> {
+    let id = socket_id(fd)?;
+    let (ip, port) = read_sockaddr(addr, len)?;
+    socket::connect(id, ip, port).map(|()| 0).map_err(net_errno)
+}
+
+/// `sendto(fd, buf, len, flags, dest_addr, addrlen)` on a connected socket
+///
+/// The flags and the destination are ignored, as Linux does for a connected TCP socket.
+fn sys_sendto(fd: usize, buf: UserPtr<u8>, count: usize) -> Result<u64, i64> {
+    let id = socket_id(fd)?;
+    if !buf.access_ok(count, false) {
+        return Err(EFAULT);
+    }
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut done = 0;
+    while done < count {
+        let len = (count - done).min(CHUNK_SIZE);
+        buf.offset(done)
+            .read_slice(&mut chunk[..len])
+            .map_err(|_| EFAULT)?;
+        let sent = match socket::send(id, &chunk[..len]) {
+            Ok(sent) => sent,
+            Err(_) if done != 0 => break,
+            Err(e) => return Err(net_errno(e)),
+        };
+        done += sent;
+        if sent < len {
+            break;
+        }
+    }
+    Ok(done as u64)
+}
+
+/// `recvfrom(fd, buf, len, flags, src_addr, addrlen)` on a connected socket
+///
+/// Returns what arrived, at most `CHUNK_SIZE` bytes, as soon as there is any. The flags are
+/// ignored; `src_addr` gets the peer's address.
+fn sys_recvfrom(
+    fd: usize,
+    buf: UserPtr<u8>,
+    count: usize,
+    addr: UserPtr<SockAddrIn>,
+    addrlen: UserPtr<u32>,
+) -> Result<u64, i64> {
+    let id = socket_id(fd)?;
+    if !buf.access_ok(count, true) {
+        return Err(EFAULT);
+    }
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let len = count.min(CHUNK_SIZE);
+    let received = socket::recv(id, &mut chunk[..len]).map_err(net_errno)?;
+    buf.write_slice(&chunk[..received]).map_err(|_| EFAULT)?;
+    let (ip, port) = socket::peer(id).map_err(net_errno)?;
+    write_sockaddr(addr, addrlen, ip, port)?;
+    Ok(received as u64)
+}
+
+/// Fills `dirp` with `linux_dirent64` records for the next entries of the directory `fd`
+fn sys_getdents64(fd: usize, dirp: usize, count: usize) -> Result<u64, i64> {
+    let mut record = [0u8; (DIRENT_HEADER + MAX_NAME + 1).next_multiple_of(8)];
+    let mut done = 0;
+    while let Some(entry) = vfs::read_dir(fd).map_err(errno)? {
+        let name = entry.name().as_bytes();
+        let reclen = (DIRENT_HEADER + name.len() + 1).next_multiple_of(8);
+        if done + reclen > count {
+            // Give the entry back for the next call
+            vfs::seek(fd, -1, Whence::Current).map_err(errno)?;
+            if done == 0 {
+                return Err(EINVAL);
+            }
+            break;
+        }
+        let d_type = match entry.kind {
+            FileKind::File => DT_REG,
+            FileKind::Directory => DT_DIR,
+            FileKind::CharDevice => DT_CHR,
+            FileKind::BlockDevice => DT_BLK,
+            FileKind::Symlink => DT_LNK,
+            FileKind::Socket => DT_SOCK,
+        };
+        record.fill(0);
+        // d_ino: no meaningful inode numbers across filesystems, any non-zero value will do
+        record[0..8].copy_from_slice(&1u64.to_le_bytes());
+        record[8..16].copy_from_slice(&((done + reclen) as u64).to_le_bytes());
+        record[16..18].copy_from_slice(&(reclen as u16).to_le_bytes());
+        record[18] = d_type;
+        record[DIRENT_HEADER..DIRENT_HEADER + name.len()].copy_from_slice(name);
+        uaccess::copy_to_user(dirp + done, &record[..reclen]).map_err(|_| EFAULT)?;
+        done += reclen;
+    }
+    Ok(done as u64)
+}
+
+/// `rt_sigaction(sig, act, oldact, sigsetsize)`
+fn sys_rt_sigaction(
+    sig: usize,
+    act: UserPtr<SigAction>,
+    oldact: UserPtr<SigAction>,
+    sigsetsize: u64,
+) -> Result<u64, i64> {
+    if sigsetsize != size_of::<u64>() as u64 {
+        return Err(EINVAL);
+    }
+    let action = match act.addr() {
+        0 => None,
+        _ => Some(act.read().map_err(|_| EFAULT)?),
+    };
+    let old = signal::set_action(sig, action).map_err(signal_errno)?;
+    if oldact.addr() != 0 {
+        oldact.write(&old).map_err(|_| EFAULT)?;
+    }
+    Ok(0)
+}
+
+/// `rt_sigprocmask(how, set, oldset, sigsetsize)`
+fn sys_rt_sigprocmask(
+    how: u64,
+    set: UserPtr<u64>,
+    oldset: UserPtr<u64>,
+    sigsetsize: u64,
+) -> Result<u64, i64> {
+    if sigsetsize != size_of::<u64>() as u64 {
+        return Err(EINVAL);
+    }
+    let update: Option<fn(u64, u64) -> u64> = match (set.addr(), how) {
+        (0, _) => None,
+        (_, SIG_BLOCK) => Some(|old, set| old | set),
+        (_, SIG_UNBLOCK) => Some(|old, set| old & !set),
+        (_, SIG_SETMASK) => Some(|_, set| set),
+        _ => return Err(EINVAL),
+    };
+    let old = match update {
+        Some(update) => {
+            let set = set.read().map_err(|_| EFAULT)?;
+            signal::update_blocked(|old| update(old, set))
+        }
+        None => signal::update_blocked(|old| old),
+    }
+    .map_err(signal_errno)?;
+    if oldset.addr() != 0 {
+        oldset.write(&old).map_err(|_| EFAULT)?;
+    }
+    Ok(0)
+}
+
+/// `sysinfo(info)`, memory sizes in frames
+fn sys_sysinfo(info: UserPtr<SysInfo>) -> Result<u64, i64> {
+    let mem = mm::meminfo();
+    let mut procs = 0;
+    sched::for_each_task(|_| procs += 1);
+    let value = SysInfo {
+        uptime: (clocksource::now_ns() / 1_000_000_000) as i64,
+        totalram: mem.total as u64,
+        freeram: mem.free as u64,
+        procs,
+        mem_unit: PAGE_SIZE as u32,
+        ..Default::default()
+    };
+    info.write(&value).map_err(|_| EFAULT)?;
+    Ok(0)
+}
+
+/// `clock_gettime(clockid, tp)`
+///
+/// The monotonic clocks all read `clocksource::now_ns`, there being no suspend time or
+/// frequency adjustment to tell them apart; the real-time clocks read `walltime`.
+fn sys_clock_gettime(clock: u64, tp: UserPtr<Timespec>) -> Result<u64, i64> {
+    let ns = match clock {
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => walltime::now_ns(),
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
+            clocksource::now_ns()
+        }
+        _ => return Err(EINVAL),
+    };
+    let value = Timespec {
+        tv_sec: (ns / NSEC_PER_SEC) as i64,
+        tv_nsec: (ns % NSEC_PER_SEC) as i64,
+    };
+    tp.write(&value).map_err(|_| EFAULT)?;
+    Ok(0)
+}
+
+/// `gettimeofday(tv, tz)`; either pointer may be null
+fn sys_gettimeofday(tv: UserPtr<Timeval>, tz: UserPtr<Timezone>) -> Result<u64, i64> {
+    if tv.addr() != 0 {
+        let ns = walltime::now_ns();
+        let value = Timeval {
+            tv_sec: (ns / NSEC_PER_SEC) as i64,
+            tv_usec: (ns % NSEC_PER_SEC / 1000) as i64,
+        };
+        tv.write(&value).map_err(|_| EFAULT)?;
+    }
+    if tz.addr() != 0 {
+        tz.write(&Timezone::default()).map_err(|_| EFAULT)?;
+    }
+    Ok(0)
+}
+
+/// `syslog(type, buf, len)`, the subset of actions `dmesg` uses
+fn sys_syslog(action: u64, buf: UserPtr<u8>, len: usize) -> Result<u64, i64> {
+    match action {
+        SYSLOG_ACTION_CLOSE | SYSLOG_ACTION_OPEN => Ok(0),
+        SYSLOG_ACTION_READ_ALL | SYSLOG_ACTION_READ_CLEAR => {
+            let count = syslog_read_all(buf, len)?;
+            if action == SYSLOG_ACTION_READ_CLEAR {
+                ringbuf::clear();
+            }
+            Ok(count as u64)
+        }
+        SYSLOG_ACTION_CLEAR => {
+            ringbuf::clear();
+            Ok(0)
+        }
+        SYSLOG_ACTION_SIZE_BUFFER => Ok(ringbuf::TEXT_SIZE as u64),
+        _ => Err(EINVAL),
+    }
+}
+
+/// Copies the latest log lines that fit in `len` bytes to `buf`, returning the bytes copied
+fn syslog_read_all(buf: UserPtr<u8>, len: usize) -> Result<usize, i64> {
+    let mut text = [0; ringbuf::MAX_RECORD_LEN];
+    let mut line = [0; log::SYSLOG_LINE_MAX];
+    let end = ringbuf::next_seq();
+    // Calls `f` with every record up to `end`, formatted
+    let mut for_each_line = |from: u64, f: &mut dyn FnMut(u64, &[u8]) -> bool| {
+        let mut seq = from;
+        while let Some(record) = ringbuf::read(seq, &mut text).filter(|r| r.seq < end) {
+            let count = log::syslog_line(&record, &text[..record.len], &mut line);
+            if !f(record.seq, &line[..count]) {
+                break;
+            }
+            seq = record.seq + 1;
+        }
+    };
+
+    // Skip the oldest lines until the rest fits
+    let mut total = 0;
+    for_each_line(ringbuf::first_seq(), &mut |_, line| {
+        total += line.len();
+        true
+    });
+    let mut from = end;
+    for_each_line(ringbuf::first_seq(), &mut |seq, line| {
+        if total <= len {
+            from = seq;
+            return false;
+        }
+        total -= line.len();
+        true
+    });
+
+    let mut copied = 0;
+    let mut result = Ok(());
+    for_each_line(from, &mut |_, line| {
+        if copied + line.len() > len {
+            return false;
+        }
+        result = buf.offset(copied).write_slice(line);
+        copied += line.len();
+        result.is_ok()
+    });
+    result.map(|()| copied).map_err(|_| EFAULT)
+}
@@ -0,0 +1,230 @@
+//! Clock sources and nanosecond time
+//!
+//! A clock source is a free-running counter of known frequency. The timekeeping code turns its
+//! value into nanoseconds since boot (`now_ns`); the generic timer counter (`CNTPCT_EL0`) is the
+//! default one, registered by `init`. Another counter, e.g. an emulated one driven by a test, can
+//! replace it with `register`: time carries on from where the previous source left it.
+//!
+//! ## Design
+//!
+//! - Conversions use a precomputed `mult`/`shift` pair, `value * mult >> shift`, so the hot paths
+//!   only multiply (a 64x32-bit product kept in 128 bits, which can't overflow) instead of
+//!   dividing by the frequency. `shift` is as large as `mult` allows, for precision.
+//! - A clock source may be narrower than 64 bits: deltas are taken modulo its `mask`, which is
+//!   correct as long as the source is read at least once per wrap. The scheduler tick calls
+//!   `update` to fold the elapsed time in, and the idle task doesn't stop the tick for longer
+//!   than `max_idle_ns`.
+//! - `ticks_to_ns` and `ns_to_ticks` convert generic timer counter values, the unit used by the
+//!   timer deadlines, whatever clock source is active.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is a small `struct clocksource` and timekeeper: `clocks_calc_mult_shift`,
+//! `clocksource_cyc2ns` and `clocksource_register_hz` work the same way. There is no rating (the
+//! last registered source wins) and no watchdog checking the sources against each other.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::drivers::timer::arch_timer;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::{initcall, println};
+
+/// Nanoseconds per second
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// Errors returned by `register`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClockError {
+    /// The frequency or the mask is 0
+    Invalid,
+}
+
+/// A free-running counter
+#[derive(Clone, Copy)]
+pub struct ClockSource {
+    /// Name used for diagnostics
+    pub name: &'static str,
+    /// Returns the current counter value
+    pub read: fn() -> u64,
+    /// Valid bits of the value returned by `read`
+    pub mask: u64,
+    /// Counter frequency, in Hz
+    pub freq_hz: u64,
+}
+
+/// A fixed-point conversion factor, `value * mult >> shift`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Conversion {
+    pub mult: u32,
+    pub shift: u32,
+}
+
+impl Conversion {
+    /// Computes the factor converting a quantity counted at `from` Hz to one counted at `to` Hz
+    ///
+    /// `mult` is rounded to the nearest, `shift` is the largest that keeps `mult` in 32 bits.
+    pub fn new(from: u64, to: u64) -> Self {
+        let mut shift = 32;
+        loop {
+            let mult = (((to as u128) << shift) + from as u128 / 2) / from as u128;
+            if mult <= u32::MAX as u128 || shift == 0 {
+                return Self {
+                    mult: mult.min(u32::MAX as u128) as u32,
+                    shift,
+                };
+            }
+            shift -= 1;
+        }
+    }
+
+    /// Converts `value`, saturating if the result doesn't fit in 64 bits
+    #[inline(always)]
+    pub fn convert(self, value: u64) -> u64 {
+        ((value as u128 * self.mult as u128) >> self.shift).min(u64::MAX as u128) as u64
+    }
+
+    /// Packs the factor in a `u64`, for the lock-free copies
+    const fn pack(self) -> u64 {
+        (self.mult as u64) << 32 | self.shift as u64
+    }
+
+    const fn unpack(value: u64) -> Self {
+        Self {
+            mult: (value >> 32) as u32,
+            shift: value as u32,
+        }
+    }
+}
+
+/// The active clock source and the time accumulated so far
+struct Timekeeper {
+    source: ClockSource,
+    /// Converts counter values of `source` to nanoseconds
+    to_ns: Conversion,
+    /// Counter value when the time was last folded into `base_ns`
+    cycle_last: u64,
+    /// Nanoseconds since boot at `cycle_last`
+    base_ns: u64,
+}
+
+impl Timekeeper {
+    /// Counter cycles since `cycle_last`, modulo the counter width
+    fn delta(&self, cycles: u64) -> u64 {
+        cycles.wrapping_sub(self.cycle_last) & self.source.mask
+    }
+
+    /// Folds the time elapsed since `cycle_last` into `base_ns`
+    fn accumulate(&mut self) {
+        let cycles = (self.source.read)();
+        self.base_ns += self.to_ns.convert(self.delta(cycles));
+        self.cycle_last = cycles;
+    }
+}
+
+/// The generic timer counter
+const ARCH_SOURCE: ClockSource = ClockSource {
+    name: "arch_sys_counter",
+    read: arch_timer::get_counter,
+    mask: u64::MAX,
+    freq_hz: 0,
+};
+
+static TIMEKEEPER: Mutex<Timekeeper> = Mutex::new(Timekeeper {
+    source: ARCH_SOURCE,
+    to_ns: Conversion { mult: 0, shift: 0 },
+    cycle_last: 0,
+    base_ns: 0,
+});
+
+/// Generic timer counter to nanoseconds factor, packed
+static ARCH_TO_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Nanoseconds to generic timer counter factor, packed
+static NS_TO_ARCH: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the generic timer counter as the clock source
+///
+/// Time starts at 0 when the counter does, at reset. Must run before any other function of this
+/// module.
+pub fn init() {
+    let freq = arch_timer::get_frequency();
+    let to_ns = Conversion::new(freq, NSEC_PER_SEC);
+    ARCH_TO_NS.store(to_ns.pack(), Ordering::Relaxed);
+    NS_TO_ARCH.store(
+        Conversion::new(NSEC_PER_SEC, freq).pack(),
+        Ordering::Relaxed,
+    );
+    TIMEKEEPER.lock_irqsafe(|tk| {
+        tk.source = ClockSource {
+            freq_hz: freq,
+            ..ARCH_SOURCE
+        };
+        tk.to_ns = to_ns;
+        tk.cycle_last = 0;
+        tk.base_ns = 0;
+    });
+    println!(
+        "clocksource: {} at {} Hz, mult {} shift {}",
+        ARCH_SOURCE.name, freq, to_ns.mult, to_ns.shift
+    );
+}
+initcall!(Early, "clocksource", init);
+
+/// Makes `source` the clock source
+///
+/// The time elapsed on the previous source is kept: `now_ns` carries on from its current value.
+pub fn register(source: ClockSource) -> Result<(), ClockError> {
+    if source.freq_hz == 0 || source.mask == 0 {
+        return Err(ClockError::Invalid);
+    }
+    TIMEKEEPER.lock_irqsafe(|tk| {
+        tk.accumulate();
+        tk.source = source;
+        tk.to_ns = Conversion::new(source.freq_hz, NSEC_PER_SEC);
+        tk.cycle_last = (source.read)();
+    });
+    println!("clocksource: switched to {}", source.name);
+    Ok(())
+}
+
+/// Returns the name of the active clock source
+pub fn current() -> &'static str {
+    TIMEKEEPER.lock_irqsafe(|tk| tk.source.name)
+}
+
+/// Returns the nanoseconds elapsed since boot
+pub fn now_ns() -> u64 {
+    TIMEKEEPER.lock_irqsafe(|tk| {
+        let cycles = (tk.source.read)();
+        tk.base_ns + tk.to_ns.convert(tk.delta(cycles))
+    })
+}
+
+/// Folds the time elapsed on the clock source in, so a narrow counter can't wrap unnoticed
+///
+/// Called from the scheduler tick.
+pub fn update() {
+    TIMEKEEPER.lock_irqsafe(|tk| tk.accumulate());
+}
+
+/// Returns the longest time the tick may stay stopped without the clock source wrapping
+///
+/// Half a wrap, to leave a margin for the time it takes to restart the tick.
+pub fn max_idle_ns() -> u64 {
+    TIMEKEEPER.lock_irqsafe(|tk| tk.to_ns.convert(tk.source.mask / 2))
+}
+
+/// Converts a number of generic timer counter increments to nanoseconds
+#[inline(always)]
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    Conversion::unpack(ARCH_TO_NS.load(Ordering::Relaxed)).convert(ticks)
+}
+
+/// Converts a duration in nanoseconds to generic timer counter increments
+///
+/// The result may be one increment short of the exact value; deadlines that must not expire
+/// early add one.
+#[inline(always)]
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    Conversion::unpack(NS_TO_ARCH.load(Ordering::Relaxed)).convert(ns)
+}
@@ -0,0 +1,153 @@
+//! High-resolution one-shot timers
+//!
+//! `start(ns, callback, data)` has `callback(data)` called from the timer interrupt once `ns`
+//! nanoseconds have passed, with the resolution of the generic timer counter (16 ns at QEMU's
+//! 62.5 MHz) rather than the scheduler tick. A timer fires once; `cancel` removes it before then.
+//!
+//! ```ignore
+//! let id = hrtimer::start(50_000, poll_status, dev as usize)?;
+//! ...
+//! hrtimer::cancel(id);
+//! ```
+//!
+//! ## Design
+//!
+//! - Pending timers are kept in a fixed-size table with their absolute expiry, a counter value.
+//!   The physical timer shares them with the scheduler tick: `arch_timer` programs
+//!   `CNTP_CVAL_EL0` for whichever comes first, asking `next_expiry`, and runs `run_expired` from
+//!   its interrupt handler.
+//! - The table is shared by the CPUs: every CPU programs its own timer for the next expiry, and
+//!   the first to take the interrupt runs the callback.
+//! - A deadline already in the past when the timer is started isn't run from `start`, where the
+//!   caller may hold locks the callback needs: the compare value is set to it and the interrupt
+//!   is taken right away.
+//! - Callbacks run in interrupt context, with interrupts masked and without the table lock, so
+//!   they can start or cancel timers.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's `hrtimer_start` takes an embedded `struct hrtimer` kept in a per-CPU red-black tree
+//! and programs the next expiry through the clockevents layer; here the table is scanned and the
+//! "clockevent" is the generic timer driver itself.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::drivers::timer::arch_timer;
+use crate::ipc::irq_safe_mutex::Mutex;
+
+use super::clocksource;
+
+/// Maximum number of pending timers
+pub const MAX_HRTIMERS: usize = 32;
+
+/// Identifies a started timer, to pass to `cancel`
+///
+/// IDs aren't reused, so cancelling a timer that has already fired is harmless.
+pub type HrTimerId = u64;
+
+/// Function called when a timer expires, with the `data` value given to `start`
+pub type HrTimerCallback = fn(data: usize);
+
+/// Errors returned by `start`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HrTimerError {
+    /// `MAX_HRTIMERS` timers are already pending
+    NoSpace,
+}
+
+/// A pending timer
+#[derive(Clone, Copy)]
+struct HrTimer {
+    id: HrTimerId,
+    /// Counter value (`CNTPCT_EL0`) at which the timer expires
+    expires: u64,
+    callback: HrTimerCallback,
+    data: usize,
+}
+
+static TIMERS: Mutex<[Option<HrTimer>; MAX_HRTIMERS]> = Mutex::new([None; MAX_HRTIMERS]);
+
+/// ID given to the next timer started
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Has `callback(data)` called from the timer interrupt in `ns_from_now` nanoseconds
+///
+/// A duration of 0 runs the callback from the next timer interrupt, taken almost right away.
+pub fn start(
+    ns_from_now: u64,
+    callback: HrTimerCallback,
+    data: usize,
+) -> Result<HrTimerId, HrTimerError> {
+    // One more increment, so the timer never fires early
+    let ticks = clocksource::ns_to_ticks(ns_from_now) + 1;
+    let expires = arch_timer::get_counter().saturating_add(ticks);
+    start_at(expires, callback, data)
+}
+
+/// Has `callback(data)` called from the timer interrupt once the counter reaches `expires`
+///
+/// A deadline in the past runs the callback from the next timer interrupt, taken right away.
+pub fn start_at(
+    expires: u64,
+    callback: HrTimerCallback,
+    data: usize,
+) -> Result<HrTimerId, HrTimerError> {
+    TIMERS.lock_irqsafe(|timers| {
+        let slot = timers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(HrTimerError::NoSpace)?;
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        *slot = Some(HrTimer {
+            id,
+            expires,
+            callback,
+            data,
+        });
+        // Under the lock, so a concurrent expiry can't program a later compare value after this
+        arch_timer::program_event(expires);
+        Ok(id)
+    })
+}
+
+/// Removes a pending timer
+///
+/// Returns false if the timer has already fired or been cancelled. The compare value is left
+/// as it is: the timer interrupt then finds nothing to run.
+pub fn cancel(id: HrTimerId) -> bool {
+    TIMERS.lock_irqsafe(|timers| {
+        match timers
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|t| t.id == id))
+        {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Returns the counter value at which the next pending timer expires
+pub fn next_expiry() -> Option<u64> {
+    TIMERS.lock_irqsafe(|timers| timers.iter().flatten().map(|t| t.expires).min())
+}
+
+/// Runs the callbacks of the timers that have expired
+///
+/// Called from the timer interrupt handler.
+pub fn run_expired() {
+    let now = arch_timer::get_counter();
+    let mut expired: [Option<HrTimer>; MAX_HRTIMERS] = [None; MAX_HRTIMERS];
+    TIMERS.lock_irqsafe(|timers| {
+        for (slot, run) in timers.iter_mut().zip(expired.iter_mut()) {
+            *run = slot.take_if(|t| t.expires <= now);
+        }
+    });
+    // In expiry order
+    expired.sort_unstable_by_key(|t| t.map_or(u64::MAX, |t| t.expires));
+    for timer in expired.iter().flatten() {
+        (timer.callback)(timer.data);
+    }
+}
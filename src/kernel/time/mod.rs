@@ -0,0 +1,8 @@
+//! Timekeeping
+//!
+//! Services built on the ARM generic timer (`drivers::timer::arch_timer`) for the rest of the
+//! kernel and for drivers, and the time of day kept on top of them (`walltime`).
+
+pub mod clocksource;
+pub mod hrtimer;
+pub mod walltime;
@@ -0,0 +1,187 @@
+//! Time of day
+//!
+//! The real time (`CLOCK_REALTIME`), in nanoseconds since the Unix epoch (UTC). It is kept as an
+//! offset from the monotonic clock (`clocksource::now_ns`): reading it is a single addition, and
+//! it runs at the rate of the clock source. `init` sets it from the real-time clock at boot, then
+//! `set` and `adjust` step it, e.g. when the SNTP client (`net::sntp`) gets the time from a
+//! server.
+//!
+//! ## Design
+//!
+//! - Stepping only moves the offset: the monotonic clock, and with it every timeout and timer
+//!   deadline, carries on undisturbed. The real time may go backwards.
+//! - A step is written back to the real-time clock, whole seconds only, so the next boot starts
+//!   from the corrected time.
+//! - Without a real-time clock the time starts at the epoch, at boot.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux's timekeeper keeps the real time as `xtime` plus `wall_to_monotonic`, set from the RTC
+//! by `rtc_hctosys` and stepped by `do_settimeofday64`. NTP daemons also slew it with `adjtimex`
+//! and the kernel writes it back to the RTC every 11 minutes. Here there is no slewing, no leap
+//! second handling and no time zone: the time is always UTC.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::drivers::rtc;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::{initcall, println};
+
+use super::clocksource::{self, NSEC_PER_SEC};
+
+/// Where the time was last set from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimeSource {
+    /// Never set: the time started at the epoch
+    None,
+    /// The real-time clock, at boot
+    Rtc,
+    /// A network time server
+    Ntp,
+}
+
+impl TimeSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TimeSource::None => "none",
+            TimeSource::Rtc => "rtc",
+            TimeSource::Ntp => "ntp",
+        }
+    }
+}
+
+/// When and how the time was last set
+#[derive(Clone, Copy, Debug)]
+pub struct Status {
+    pub source: TimeSource,
+    /// Monotonic time of the last step, in nanoseconds since boot
+    pub set_ns: u64,
+}
+
+/// Real time at boot, in nanoseconds since the epoch
+static OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+
+static STATUS: Mutex<Status> = Mutex::new(Status {
+    source: TimeSource::None,
+    set_ns: 0,
+});
+
+/// Sets the time from the real-time clock, if there is one
+pub fn init() {
+    match rtc::read_time() {
+        Ok(secs) => {
+            set(secs * NSEC_PER_SEC, TimeSource::Rtc);
+            println!("walltime: {} (rtc)", DateTime::from_secs(secs));
+        }
+        Err(e) => println!("walltime: no time of day: {:?}", e),
+    }
+}
+initcall!(Driver, "walltime", init);
+
+/// Returns the real time, in nanoseconds since the epoch
+pub fn now_ns() -> u64 {
+    clocksource::now_ns().wrapping_add(OFFSET_NS.load(Ordering::Acquire))
+}
+
+/// Returns when and how the time was last set
+pub fn status() -> Status {
+    STATUS.lock_irqsafe(|status| *status)
+}
+
+/// Steps the time to `epoch_ns` nanoseconds since the epoch
+pub fn set(epoch_ns: u64, source: TimeSource) {
+    let now = clocksource::now_ns();
+    OFFSET_NS.store(epoch_ns.wrapping_sub(now), Ordering::Release);
+    stepped(now, epoch_ns, source);
+}
+
+/// Steps the time by `delta_ns` nanoseconds, forwards or backwards
+///
+/// A zero step still records the time as set from `source`, now.
+pub fn adjust(delta_ns: i64, source: TimeSource) {
+    OFFSET_NS.fetch_add(delta_ns as u64, Ordering::AcqRel);
+    stepped(clocksource::now_ns(), now_ns(), source);
+}
+
+/// Records a step to `epoch_ns` made at monotonic time `now`, and saves it in the RTC
+fn stepped(now: u64, epoch_ns: u64, source: TimeSource) {
+    STATUS.lock_irqsafe(|status| {
+        *status = Status {
+            source,
+            set_ns: now,
+        }
+    });
+    if source != TimeSource::Rtc {
+        // Nothing to update without a real-time clock
+        let _ = rtc::set_time(epoch_ns / NSEC_PER_SEC);
+    }
+}
+
+/// A calendar date and time of day, UTC
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DateTime {
+    pub year: u32,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Converts `secs` seconds since the epoch
+    ///
+    /// Uses the days-to-civil algorithm on the proleptic Gregorian calendar, with 400-year eras
+    /// starting on March 1st so leap days fall at the end of a year.
+    pub fn from_secs(secs: u64) -> Self {
+        let days = secs / 86400;
+        let rem = secs % 86400;
+        // Days since 0000-03-01
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z % 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u64::from(month <= 2);
+        Self {
+            year: year as u32,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// ISO 8601 format, e.g. `2024-05-17 09:41:07 UTC`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Prints the time and where it was set from
+pub fn dump() {
+    let now = now_ns();
+    let status = status();
+    println!("{}", DateTime::from_secs(now / NSEC_PER_SEC));
+    match status.source {
+        TimeSource::None => println!("time never set"),
+        source => println!(
+            "set from {} {} s ago",
+            source.as_str(),
+            clocksource::now_ns().saturating_sub(status.set_ns) / NSEC_PER_SEC
+        ),
+    }
+}
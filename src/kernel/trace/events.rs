@@ -0,0 +1,278 @@
+//! Static tracepoints
+//!
+//! `trace_event!` marks a point of interest with a subsystem, a format string and up to
+//! `MAX_ARGS` integer arguments:
+//!
+//! ```ignore
+//! trace_event!(sched, "switch {} -> {}", prev, next);
+//! ```
+//!
+//! While tracing is on, every hit appends a fixed-size binary record (counter timestamp, CPU,
+//! event ID and the raw arguments) to the ring of the CPU it ran on. Nothing is formatted on
+//! the way: a hit costs a few stores, so the latencies between events are those of the code
+//! itself, not of the console. The format strings are only applied when the records are read,
+//! by `Formatted` or the `trace` shell command, which also prints them in a raw form meant for
+//! host-side scripts:
+//!
+//! ```text
+//! # trace freq=62500000
+//! # event 3 irq "irq {} entry"
+//! 0 1a2b3c4d 3 1e
+//! ```
+//!
+//! The header gives the counter frequency and the format of every event, then each record is
+//! `<cpu> <timestamp> <event> <args>...`, numbers in hexadecimal.
+//!
+//! ## Design
+//!
+//! - Each `trace_event!` site defines an `Event` in the `.trace_events` section; the table
+//!   `__trace_events_start`..`__trace_events_end` is built by the linker, and an event's ID is
+//!   its index in it. The event costs nothing when tracing is off: one relaxed load.
+//! - A ring holds `RING_RECORDS` records and overwrites the oldest when full. Like the log, a
+//!   writer never waits: a hit finding its ring locked (by a reader, or by the code it
+//!   interrupted) is counted as lost.
+//! - The format string is checked against the arguments at compile time, through a
+//!   `format_args!` that is never run.
+//! - Timestamps are raw counter values, as in the kernel log, converted when read.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is a small `TRACE_EVENT`/ftrace: Linux generates a structure per event with typed
+//! fields, records them in the per-CPU pages of its lockless ring buffer
+//! (`kernel/trace/ring_buffer.c`), and exposes `trace` and `trace_pipe_raw` in tracefs, the
+//! latter read by `trace-cmd` on the host.
+
+use core::fmt;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::drivers::timer::arch_timer;
+use crate::initcall;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::dtb;
+use crate::kernel::smp::{MAX_CPUS, this_cpu};
+
+/// Most arguments a `trace_event!` takes
+pub const MAX_ARGS: usize = 4;
+
+/// Number of records a ring holds
+pub const RING_RECORDS: usize = 1024;
+
+/// A `trace_event!` site
+#[repr(C)]
+pub struct Event {
+    pub subsystem: &'static str,
+    pub fmt: &'static str,
+}
+
+/// A hit of an event
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Record {
+    /// Counter value when the event was hit
+    pub timestamp: u64,
+    /// Index of the event in the event table
+    pub event: u16,
+    pub cpu: u8,
+    /// Number of arguments used
+    pub nargs: u8,
+    pub args: [u64; MAX_ARGS],
+}
+
+impl Record {
+    const EMPTY: Self = Self {
+        timestamp: 0,
+        event: 0,
+        cpu: 0,
+        nargs: 0,
+        args: [0; MAX_ARGS],
+    };
+}
+
+/// Records the events to follow
+#[macro_export]
+macro_rules! trace_event {
+    ($subsystem:ident, $fmt:literal $(, $arg:expr)* $(,)?) => {{
+        const _: () = assert!(
+            [$(stringify!($arg)),*].len() <= $crate::kernel::trace::events::MAX_ARGS,
+            "trace_event!: too many arguments"
+        );
+        #[used]
+        #[unsafe(link_section = ".trace_events")]
+        static EVENT: $crate::kernel::trace::events::Event = $crate::kernel::trace::events::Event {
+            subsystem: stringify!($subsystem),
+            fmt: $fmt,
+        };
+        if false {
+            let _ = format_args!($fmt $(, $arg as u64)*);
+        }
+        if $crate::kernel::trace::events::enabled() {
+            $crate::kernel::trace::events::record(&EVENT, &[$($arg as u64),*]);
+        }
+    }};
+}
+
+// Only their addresses matter
+unsafe extern "C" {
+    static __trace_events_start: u8;
+    static __trace_events_end: u8;
+}
+
+/// Returns the event table
+pub fn events() -> &'static [Event] {
+    unsafe {
+        let start = addr_of!(__trace_events_start) as *const Event;
+        let end = addr_of!(__trace_events_end) as *const Event;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+struct Ring {
+    records: [Record; RING_RECORDS],
+    /// Records written since the ring was cleared, the latest `RING_RECORDS` are kept
+    next: u64,
+    /// Index of the oldest record kept
+    first: u64,
+}
+
+impl Ring {
+    fn push(&mut self, record: Record) {
+        self.records[self.next as usize % RING_RECORDS] = record;
+        self.next += 1;
+        self.first = self
+            .first
+            .max(self.next.saturating_sub(RING_RECORDS as u64));
+    }
+}
+
+static RINGS: [Mutex<Ring>; MAX_CPUS] = [const {
+    Mutex::new(Ring {
+        records: [Record::EMPTY; RING_RECORDS],
+        next: 0,
+        first: 0,
+    })
+}; MAX_CPUS];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Hits dropped because their ring was busy
+static LOST: AtomicU64 = AtomicU64::new(0);
+
+/// Returns true if tracing is on
+#[inline(always)]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turns tracing on or off; the records are kept
+pub fn set_enabled(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
+/// Records a hit of `event` with `args` on the running CPU
+///
+/// Called by `trace_event!`, which checks that there are at most `MAX_ARGS` arguments.
+pub fn record(event: &'static Event, args: &[u64]) {
+    let id = unsafe { (event as *const Event).offset_from(events().as_ptr()) as u16 };
+    let cpu = this_cpu();
+    let mut record = Record {
+        timestamp: arch_timer::get_counter(),
+        event: id,
+        cpu: cpu as u8,
+        nargs: args.len() as u8,
+        args: [0; MAX_ARGS],
+    };
+    record.args[..args.len()].copy_from_slice(args);
+    if RINGS[cpu]
+        .try_lock_irqsafe(|ring| ring.push(record))
+        .is_none()
+    {
+        LOST.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of hits lost because their ring was busy
+pub fn lost() -> u64 {
+    LOST.load(Ordering::Relaxed)
+}
+
+/// Drops every record
+pub fn clear() {
+    for ring in &RINGS {
+        ring.lock_irqsafe(|ring| ring.first = ring.next);
+    }
+    LOST.store(0, Ordering::Relaxed);
+}
+
+/// Calls `f` on every record, merged from all the rings in timestamp order
+///
+/// Records written meanwhile are left out, and so are those overwritten before being reached.
+pub fn for_each(mut f: impl FnMut(&Record)) {
+    let mut ends = [0; MAX_CPUS];
+    let mut cursors = [0; MAX_CPUS];
+    for (cpu, ring) in RINGS.iter().enumerate() {
+        (cursors[cpu], ends[cpu]) = ring.lock_irqsafe(|ring| (ring.first, ring.next));
+    }
+    loop {
+        let mut oldest: Option<Record> = None;
+        for (cpu, ring) in RINGS.iter().enumerate() {
+            let record = ring.lock_irqsafe(|ring| {
+                cursors[cpu] = cursors[cpu].max(ring.first);
+                (cursors[cpu] < ends[cpu])
+                    .then(|| ring.records[cursors[cpu] as usize % RING_RECORDS])
+            });
+            if let Some(record) = record
+                && oldest.is_none_or(|oldest| record.timestamp < oldest.timestamp)
+            {
+                oldest = Some(record);
+            }
+        }
+        let Some(record) = oldest else {
+            return;
+        };
+        cursors[record.cpu as usize] += 1;
+        f(&record);
+    }
+}
+
+/// A record shown with its event's format string
+pub struct Formatted<'a>(pub &'a Record);
+
+impl fmt::Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let record = self.0;
+        let Some(event) = events().get(record.event as usize) else {
+            return write!(f, "unknown event {}", record.event);
+        };
+        write!(f, "{}: ", event.subsystem)?;
+        let mut args = record.args[..record.nargs as usize].iter();
+        let mut rest = event.fmt;
+        while let Some(start) = rest.find(['{', '}']) {
+            f.write_str(&rest[..start])?;
+            rest = &rest[start..];
+            // `{{` and `}}` are escapes
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                f.write_str(&rest[..1])?;
+                rest = &rest[2..];
+                continue;
+            }
+            let end = rest.find('}').map_or(rest.len(), |end| end + 1);
+            let arg = args.next().copied().unwrap_or(0);
+            match &rest[..end] {
+                "{:x}" => write!(f, "{:x}", arg)?,
+                "{:#x}" => write!(f, "{:#x}", arg)?,
+                _ => write!(f, "{}", arg)?,
+            }
+            rest = &rest[end..];
+        }
+        f.write_str(rest)
+    }
+}
+
+/// Turns tracing on at boot with `trace=on`
+fn init() {
+    if dtb::bootarg("trace") == Some("on") {
+        set_enabled(true);
+    }
+}
+initcall!(Early, "trace", init);
@@ -0,0 +1,144 @@
+//! Interrupt and wakeup latencies
+//!
+//! Two delays say how responsive the kernel is:
+//!
+//! - the IRQ latency, from the IRQ exception entering the kernel (`do_irq`) to the handler of
+//!   the interrupt being called, kept per interrupt ID;
+//! - the wakeup latency, from `sched::wake` making a task ready to the task being switched to,
+//!   kept per task in its `Task`.
+//!
+//! Each is a `Histogram` with power-of-two buckets, shown by the `latency` shell command, and
+//! every sample is also a tracepoint (see `events`), to find out what ran in between.
+//!
+//! ## Design
+//!
+//! - Samples are counter ticks, converted to nanoseconds when added: the conversion is a
+//!   multiplication.
+//! - The IRQ entry time is kept per CPU in `IRQ_ENTRY`, saved and restored by a nested `do_irq`,
+//!   so an outer exception handling several interrupts in a row measures all of them from its
+//!   own entry: the later ones did wait that long.
+//! - Measuring is always on: it costs a counter read per exception and per wakeup.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Linux measures these with tracers rather than counters: `irqsoff` and `preemptoff` record the
+//! longest sections with interrupts or preemption disabled, `wakeup` and `wakeup_rt` the worst
+//! wakeup latency of the highest priority task, and `cyclictest` or `rtla timerlat` build such
+//! histograms from user space.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::drivers::timer::arch_timer;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::sched::TaskId;
+use crate::kernel::smp::{self, MAX_CPUS};
+use crate::kernel::time::clocksource;
+use crate::trace_event;
+
+/// Number of buckets of a histogram
+pub const BUCKETS: usize = 16;
+
+/// Number of interrupt IDs whose latency is kept
+const MAX_IRQS: usize = 32;
+
+/// Distribution of latencies
+///
+/// Bucket 0 counts the samples under 1 µs, bucket `i` those from `2^(i-1)` to `2^i` µs, and the
+/// last one everything above.
+#[derive(Clone, Copy, Debug)]
+pub struct Histogram {
+    pub buckets: [u32; BUCKETS],
+    pub count: u64,
+    pub total_ns: u64,
+    pub max_ns: u64,
+}
+
+impl Histogram {
+    pub const EMPTY: Self = Self {
+        buckets: [0; BUCKETS],
+        count: 0,
+        total_ns: 0,
+        max_ns: 0,
+    };
+
+    /// Adds a sample of `ns` nanoseconds
+    pub fn add(&mut self, ns: u64) {
+        let us = ns / 1000;
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.total_ns += ns;
+        self.max_ns = self.max_ns.max(ns);
+    }
+
+    pub fn avg_ns(&self) -> u64 {
+        self.total_ns.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// Returns the range of bucket `i` in microseconds, `None` as the end of the last one
+    pub fn bucket_range(i: usize) -> (u64, Option<u64>) {
+        let start = if i == 0 { 0 } else { 1 << (i - 1) };
+        (start, (i < BUCKETS - 1).then_some(1 << i))
+    }
+}
+
+/// Counter value when the IRQ exception each CPU is handling entered the kernel
+static IRQ_ENTRY: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// IRQ latencies, by interrupt ID
+static IRQS: Mutex<[Option<(u32, Histogram)>; MAX_IRQS]> = Mutex::new([None; MAX_IRQS]);
+
+/// Notes that an IRQ exception entered the kernel, returning what `irq_exit` must restore
+pub fn irq_enter() -> u64 {
+    IRQ_ENTRY[smp::this_cpu()].swap(arch_timer::get_counter(), Ordering::Relaxed)
+}
+
+/// Ends the IRQ exception started by the `irq_enter` that returned `outer`
+pub fn irq_exit(outer: u64) {
+    IRQ_ENTRY[smp::this_cpu()].store(outer, Ordering::Relaxed);
+}
+
+/// Records the latency of interrupt `id`, whose handler is called at counter value `now`
+pub fn irq_dispatch(id: u32, now: u64) {
+    let entry = IRQ_ENTRY[smp::this_cpu()].load(Ordering::Relaxed);
+    if entry == 0 {
+        return;
+    }
+    let ns = clocksource::ticks_to_ns(now.saturating_sub(entry));
+    trace_event!(irq, "irq {} latency {} ns", id, ns);
+    IRQS.lock_irqsafe(|irqs| {
+        let slot = match irqs.iter().position(|s| s.is_some_and(|(i, _)| i == id)) {
+            Some(slot) => slot,
+            None => match irqs.iter().position(Option::is_none) {
+                Some(slot) => {
+                    irqs[slot] = Some((id, Histogram::EMPTY));
+                    slot
+                }
+                // The table is full: the interrupts seen first are kept
+                None => return,
+            },
+        };
+        if let Some((_, hist)) = irqs[slot].as_mut() {
+            hist.add(ns);
+        }
+    });
+}
+
+/// Records a wakeup latency of `ns` nanoseconds for task `id` in `hist`
+///
+/// Called by the scheduler when it switches to a task that was woken up.
+pub fn wakeup(id: TaskId, hist: &mut Histogram, ns: u64) {
+    trace_event!(sched, "task {} wakeup latency {} ns", id, ns);
+    hist.add(ns);
+}
+
+/// Calls `f` on the IRQ latencies of every interrupt ID, in the order they were first seen
+pub fn for_each_irq(f: impl FnMut(&(u32, Histogram))) {
+    let irqs = IRQS.lock_irqsafe(|irqs| *irqs);
+    irqs.iter().flatten().for_each(f);
+}
+
+/// Forgets the IRQ latencies
+pub fn reset_irqs() {
+    IRQS.lock_irqsafe(|irqs| *irqs = [None; MAX_IRQS]);
+}
@@ -0,0 +1,15 @@
+//! Tracing
+//!
+//! Facilities reporting what the kernel does while it runs, for debugging:
+//!
+//! - `events` records the hits of static tracepoints (`trace_event!`) in binary ring buffers,
+//!   to be formatted after the fact;
+//! - `latency` keeps histograms of the IRQ and wakeup latencies;
+//! - `profile` samples where the kernel spends its time;
+//! - `syscalls` logs the system calls of the traced tasks with their arguments, result and
+//!   duration, like `strace`.
+
+pub mod events;
+pub mod latency;
+pub mod profile;
+pub mod syscalls;
@@ -0,0 +1,281 @@
+//! Sampling profiler
+//!
+//! While running, a high-resolution timer interrupts the CPU `hz` times per second and the
+//! address the interrupted code was at (`ELR_EL1`) is recorded. Functions where the kernel
+//! spends its time collect the most samples: `report` resolves the samples against the symbol
+//! table (`debug::kallsyms`) and adds them up per function, which the `profile` shell command
+//! prints as a flat profile:
+//!
+//! ```text
+//! 1000 samples, 12 in user space, 0 lost
+//!      %  SAMPLES  FUNCTION
+//!   81.3      803  aarch64_kernel::kernel::sched::idle
+//!    6.2       61  aarch64_kernel::drivers::virtio::blk::VirtioBlk::read
+//! ```
+//!
+//! `profile=<hz>` on the command line starts it as soon as the timer tick runs, to profile the
+//! end of the boot and the first user programs.
+//!
+//! ## Design
+//!
+//! - Samples are kept per CPU in a fixed buffer; once it is full, further samples are counted
+//!   as lost rather than replacing older ones, so a profile covers a contiguous period. A
+//!   sample finding its buffer locked by `report` is lost too: the timer never waits.
+//! - The timer callback runs from the timer interrupt, and reads the interrupted registers with
+//!   `irq::irq_regs`. Code running with interrupts masked is therefore never sampled: its time
+//!   shows up at the point where it unmasks them.
+//! - Samples taken at EL0 are only counted: user addresses mean nothing to the kernel's symbol
+//!   table.
+//! - `report` sorts the sample buffers in place, which doesn't matter to a flat profile, and
+//!   keeps the `REPORT_FUNCTIONS` functions with the most samples.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is the old `profile=` boot option (`kernel/profile.c`, read through
+//! `/proc/profile` by `readprofile`), sampling from the timer. `perf record` samples from the
+//! PMU overflow interrupt instead, which also catches code running with IRQs masked when the
+//! PMU interrupt is an NMI (pseudo-NMI on arm64).
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::drivers::timer::arch_timer;
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::debug::kallsyms::{self, Symbol};
+use crate::kernel::dtb;
+use crate::kernel::irq::{self, SPSR_M};
+use crate::kernel::mm::kaslr;
+use crate::kernel::smp::{self, MAX_CPUS};
+use crate::kernel::time::{clocksource, hrtimer};
+use crate::{initcall, pr_info, pr_warn};
+
+/// Samples kept per CPU
+pub const MAX_SAMPLES: usize = 4096;
+
+/// Highest sampling rate accepted
+pub const MAX_HZ: u64 = 10_000;
+
+/// Number of functions `report` returns, at most
+pub const REPORT_FUNCTIONS: usize = 32;
+
+/// Errors of the profiler
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProfileError {
+    /// The rate is 0 or above `MAX_HZ`
+    BadRate,
+    /// The profiler is already running
+    Busy,
+    /// No high-resolution timer left
+    NoTimer,
+}
+
+struct Samples {
+    pcs: [u64; MAX_SAMPLES],
+    len: usize,
+    /// Samples taken at EL0
+    user: u64,
+}
+
+static SAMPLES: [Mutex<Samples>; MAX_CPUS] = [const {
+    Mutex::new(Samples {
+        pcs: [0; MAX_SAMPLES],
+        len: 0,
+        user: 0,
+    })
+}; MAX_CPUS];
+
+/// Samples dropped because their buffer was full or busy
+static LOST: AtomicU64 = AtomicU64::new(0);
+
+/// Sampling period in counter ticks, 0 while stopped
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+
+/// Timer of the next sample
+static TIMER: AtomicU64 = AtomicU64::new(0);
+
+/// Starts sampling `hz` times per second, adding to the samples already taken
+pub fn start(hz: u64) -> Result<(), ProfileError> {
+    if hz == 0 || hz > MAX_HZ {
+        return Err(ProfileError::BadRate);
+    }
+    let period = clocksource::ns_to_ticks(1_000_000_000 / hz).max(1);
+    if PERIOD
+        .compare_exchange(0, period, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        return Err(ProfileError::Busy);
+    }
+    let first = arch_timer::get_counter() + period;
+    match hrtimer::start_at(first, sample, first as usize) {
+        Ok(id) => {
+            TIMER.store(id, Ordering::Relaxed);
+            Ok(())
+        }
+        Err(_) => {
+            PERIOD.store(0, Ordering::Relaxed);
+            Err(ProfileError::NoTimer)
+        }
+    }
+}
+
+/// Stops sampling; the samples are kept
+pub fn stop() {
+    PERIOD.store(0, Ordering::Relaxed);
+    hrtimer::cancel(TIMER.load(Ordering::Relaxed));
+}
+
+/// Returns true while sampling
+pub fn running() -> bool {
+    PERIOD.load(Ordering::Relaxed) != 0
+}
+
+/// Drops every sample
+pub fn clear() {
+    for samples in &SAMPLES {
+        samples.lock_irqsafe(|samples| {
+            samples.len = 0;
+            samples.user = 0;
+        });
+    }
+    LOST.store(0, Ordering::Relaxed);
+}
+
+/// Timer callback: records where the interrupted code was, then starts the next period
+///
+/// `expired` is the counter value the timer was set for.
+fn sample(expired: usize) {
+    let period = PERIOD.load(Ordering::Relaxed);
+    if period == 0 {
+        return;
+    }
+    if let Some((pc, user)) = irq::irq_regs(|regs| (regs.elr, regs.spsr & SPSR_M == 0)) {
+        let kept = SAMPLES[smp::this_cpu()].try_lock_irqsafe(|samples| {
+            if user {
+                samples.user += 1;
+            } else if samples.len < MAX_SAMPLES {
+                samples.pcs[samples.len] = pc;
+                samples.len += 1;
+            } else {
+                return false;
+            }
+            true
+        });
+        if kept != Some(true) {
+            LOST.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    // Keep the rate, unless the timer fired more than a period late
+    let now = arch_timer::get_counter();
+    let next = (expired as u64 + period).max(now + 1);
+    match hrtimer::start_at(next, sample, next as usize) {
+        Ok(id) => TIMER.store(id, Ordering::Relaxed),
+        Err(_) => {
+            PERIOD.store(0, Ordering::Relaxed);
+            pr_warn!("profile: no timer left, stopped");
+        }
+    }
+}
+
+/// A function of the profile
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    /// The function, `None` for the samples outside the symbol table
+    pub symbol: Option<Symbol>,
+    pub samples: u64,
+}
+
+/// A flat profile
+pub struct Report {
+    /// The functions with the most samples, by decreasing count
+    pub entries: [Option<Entry>; REPORT_FUNCTIONS],
+    /// Kernel samples
+    pub samples: u64,
+    pub user: u64,
+    pub lost: u64,
+}
+
+impl Report {
+    /// Adds `samples` samples of the function `symbol`, if among the functions with the most
+    fn add(&mut self, symbol: Option<Symbol>, samples: u64) {
+        let same = |entry: &Entry| entry.symbol.map(|s| s.addr) == symbol.map(|s| s.addr);
+        let samples = match self
+            .entries
+            .iter()
+            .position(|e| e.as_ref().is_some_and(same))
+        {
+            // A function seen on another CPU is taken out, then put back with the sum
+            Some(i) => {
+                let entry = self.entries[i].take();
+                self.entries[i..].rotate_left(1);
+                samples + entry.map_or(0, |e| e.samples)
+            }
+            None => samples,
+        };
+        let Some(i) = self
+            .entries
+            .iter()
+            .position(|e| e.is_none_or(|e| e.samples < samples))
+        else {
+            return;
+        };
+        self.entries[i..].rotate_right(1);
+        self.entries[i] = Some(Entry { symbol, samples });
+    }
+}
+
+/// Builds the flat profile of the samples taken so far
+///
+/// The counts of a function seen on several CPUs are summed only if it stays among the
+/// `REPORT_FUNCTIONS` first on each of them.
+pub fn report() -> Report {
+    let mut report = Report {
+        entries: [None; REPORT_FUNCTIONS],
+        samples: 0,
+        user: 0,
+        lost: 0,
+    };
+    for samples in &SAMPLES {
+        // Sorting groups the samples of a function
+        samples.lock(|samples| {
+            let pcs = &mut samples.pcs[..samples.len];
+            pcs.sort_unstable();
+            let mut run: Option<(Option<Symbol>, u64)> = None;
+            for &pc in pcs.iter() {
+                let symbol = kallsyms::lookup(kaslr::to_link_address(pc));
+                match run.as_mut() {
+                    Some((current, count)) if current.map(|s| s.addr) == symbol.map(|s| s.addr) => {
+                        *count += 1;
+                    }
+                    _ => {
+                        if let Some((symbol, count)) = run.take() {
+                            report.add(symbol, count);
+                        }
+                        run = Some((symbol, 1));
+                    }
+                }
+            }
+            if let Some((symbol, count)) = run {
+                report.add(symbol, count);
+            }
+            report.samples += samples.len as u64;
+            report.user += samples.user;
+        });
+    }
+    report.lost = LOST.load(Ordering::Relaxed);
+    report
+}
+
+/// Starts profiling at boot with `profile=<hz>`
+fn init() {
+    let Some(arg) = dtb::bootarg("profile") else {
+        return;
+    };
+    match arg
+        .parse()
+        .map_err(|_| ProfileError::BadRate)
+        .and_then(start)
+    {
+        Ok(()) => pr_info!("profile: sampling {} times per second", arg),
+        Err(e) => pr_warn!("profile: cannot start at {:?}: {:?}", arg, e),
+    }
+}
+initcall!(Late, "profile", init, after = ["tick"]);
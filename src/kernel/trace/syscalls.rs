@@ -0,0 +1,199 @@
+//! System call tracing
+//!
+//! When enabled, every system call of a traced task is logged once it returns, with its name,
+//! arguments, result and the time it took:
+//!
+//! ```text
+//! strace: [3] openat(0xffffffffffffff9c, 0x1000010, 0x0, 0x0) = 3 (42 us)
+//! strace: [3] read(0x3, 0x7ffffff000, 0x100) = 256 (118 us)
+//! strace: [3] exit_group(0x0) = ?
+//! ```
+//!
+//! Tracing is turned on with `strace=all` or `strace=<task>,<task>...` on the command line, or
+//! with the `strace` shell command. Errors show as negative values, the `errno` negated.
+//!
+//! ## Design
+//!
+//! - The traced tasks are a bitmap of task IDs, checked with a single atomic load on every system
+//!   call: tracing costs nothing while it is off.
+//! - A call that doesn't return (`exit`) is logged when it starts, with `?` as its result.
+//! - The task ID is logged rather than a name, as slots are reused: a filter on an ID applies to
+//!   whichever task gets it next.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! `strace` runs in user space on top of `ptrace(PTRACE_SYSCALL)`, stopping the tracee on every
+//! entry and exit; the in-kernel equivalent is the `raw_syscalls:sys_enter`/`sys_exit`
+//! tracepoints read through ftrace or perf.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::kernel::dtb;
+use crate::kernel::irq::Regs;
+use crate::kernel::sched::{self, MAX_TASKS, TaskId};
+use crate::kernel::syscall;
+use crate::kernel::time::clocksource;
+use crate::{initcall, pr_info, pr_warn};
+
+/// Value of `TRACED` when every task is traced
+const ALL_TASKS: u64 = u64::MAX;
+
+const _: () = assert!(MAX_TASKS <= 64);
+
+/// Bitmap of the traced task IDs
+static TRACED: AtomicU64 = AtomicU64::new(0);
+
+/// Errors of the tracing controls
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceError {
+    /// No task can have this ID
+    BadTask,
+}
+
+/// Which tasks are traced, as returned by `filter`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Filter {
+    Off,
+    All,
+    /// Bitmap of task IDs
+    Tasks(u64),
+}
+
+/// Traces every task
+pub fn enable_all() {
+    TRACED.store(ALL_TASKS, Ordering::Relaxed);
+}
+
+/// Stops tracing
+pub fn disable() {
+    TRACED.store(0, Ordering::Relaxed);
+}
+
+/// Starts or stops tracing task `id`
+///
+/// Stopping one task while every task is traced leaves the others traced.
+pub fn trace_task(id: TaskId, on: bool) -> Result<(), TraceError> {
+    if id >= MAX_TASKS {
+        return Err(TraceError::BadTask);
+    }
+    let all = (1u64 << MAX_TASKS) - 1;
+    let _ = TRACED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |traced| {
+        let traced = if traced == ALL_TASKS { all } else { traced };
+        Some(if on {
+            traced | 1 << id
+        } else {
+            traced & !(1 << id)
+        })
+    });
+    Ok(())
+}
+
+/// Returns the tasks traced
+pub fn filter() -> Filter {
+    match TRACED.load(Ordering::Relaxed) {
+        0 => Filter::Off,
+        ALL_TASKS => Filter::All,
+        tasks => Filter::Tasks(tasks),
+    }
+}
+
+/// A system call being traced, from `enter` to `exit`
+pub struct Call {
+    task: TaskId,
+    nr: u64,
+    args: [u64; 6],
+    start_ns: u64,
+}
+
+/// Starts tracing the system call described by `regs`, if its task is traced
+pub fn enter(regs: &Regs) -> Option<Call> {
+    let traced = TRACED.load(Ordering::Relaxed);
+    if traced == 0 {
+        return None;
+    }
+    let task = sched::current()?;
+    if traced & (1 << task) == 0 {
+        return None;
+    }
+    let call = Call {
+        task,
+        nr: regs.x8,
+        args: [regs.x0, regs.x1, regs.x2, regs.x3, regs.x4, regs.x5],
+        start_ns: clocksource::now_ns(),
+    };
+    if syscall::describe(call.nr).is_some_and(|desc| !desc.returns) {
+        log(&call, None);
+        return None;
+    }
+    Some(call)
+}
+
+/// Logs `call`, which returned `ret`
+pub fn exit(call: Call, ret: u64) {
+    log(&call, Some(ret));
+}
+
+/// Name of system call number `nr`, `syscall_<nr>` if unknown
+struct Name(u64);
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match syscall::describe(self.0) {
+            Some(desc) => f.write_str(desc.name),
+            None => write!(f, "syscall_{}", self.0),
+        }
+    }
+}
+
+/// Arguments of a call, comma separated
+struct Args<'a>(&'a [u64]);
+
+impl fmt::Display for Args<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, arg) in self.0.iter().enumerate() {
+            let sep = if i == 0 { "" } else { ", " };
+            write!(f, "{}{:#x}", sep, arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// Logs `call` with its result, `None` if it doesn't return
+fn log(call: &Call, ret: Option<u64>) {
+    // Unknown calls show all the argument registers
+    let argc = syscall::describe(call.nr).map_or(call.args.len(), |desc| desc.args);
+    let (name, args) = (Name(call.nr), Args(&call.args[..argc]));
+    match ret {
+        Some(ret) => {
+            let us = (clocksource::now_ns() - call.start_ns) / 1000;
+            pr_info!(
+                "strace: [{}] {}({}) = {} ({} us)",
+                call.task,
+                name,
+                args,
+                ret as i64,
+                us
+            );
+        }
+        None => pr_info!("strace: [{}] {}({}) = ?", call.task, name, args),
+    }
+}
+
+/// Applies `strace=all` or `strace=<task>,...` from the command line
+fn init() {
+    let Some(arg) = dtb::bootarg("strace") else {
+        return;
+    };
+    if arg == "all" {
+        enable_all();
+        return;
+    }
+    for id in arg.split(',') {
+        match id.parse::<TaskId>() {
+            Ok(id) if trace_task(id, true).is_ok() => {}
+            _ => pr_warn!("strace: ignoring bad task {:?}", id),
+        }
+    }
+}
+initcall!(Early, "strace", init);
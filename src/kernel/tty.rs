@@ -0,0 +1,402 @@
+//! Terminal line discipline
+//!
+//! Reads of `/dev/console` (the active console, what user tasks have as standard input) go
+//! through the line discipline instead of returning the raw bytes the console received. Its
+//! behavior is set by a Linux `termios` structure, which user space gets and sets with the
+//! `TCGETS` and `TCSETS` requests:
+//!
+//! - In canonical mode (`ICANON`, the default), input is edited a line at a time and `read`
+//!   returns only complete lines: the erase character (`VERASE`, DEL) removes the last byte, the
+//!   kill character (`VKILL`, Ctrl-U) the whole line, and the end-of-file character (`VEOF`,
+//!   Ctrl-D) completes the line without a newline, so that it reads as end of file at the start
+//!   of a line.
+//! - In raw mode, `read` waits for `VMIN` bytes (none if it is 0) and returns the bytes received
+//!   so far, with no editing.
+//! - With `ISIG`, the interrupt (`VINTR`, Ctrl-C) and quit (`VQUIT`, Ctrl-\) characters send
+//!   `SIGINT` and `SIGQUIT` to the console's foreground task and discard the line being edited.
+//!   They are caught as they arrive, from the console driver's interrupt handler (see
+//!   `console::intercept`), not when the task reads.
+//! - `ECHO` echoes the input back, `ICRNL` turns carriage returns into newlines.
+//!
+//! The foreground task is the one started last by `loader::exec`; `TIOCSPGRP` hands the console
+//! over to another process, e.g. from a shell to the job it runs.
+//!
+//! ## Design
+//!
+//! - There is a single line discipline, for the active console. The per-device files
+//!   (`/dev/ttyAMA0`, ...) keep returning raw bytes, which is what a debug channel wants.
+//! - Input is processed by the reading task, one byte at a time as it pulls them from the
+//!   console, so the edited line and the settings are the only state: there is no input queue
+//!   besides the console's own.
+//! - Output is written as is: the console drivers already expand newlines, which is what the
+//!   default `OPOST | ONLCR` settings describe.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This is a small `N_TTY` (`drivers/tty/n_tty.c`): no `VTIME`, word erase, literal next,
+//! flow control or output processing, and a process ID stands for the foreground process group.
+
+use crate::ipc::irq_safe_mutex::Mutex;
+use crate::kernel::console;
+use crate::kernel::fs::vfs::FsError;
+use crate::kernel::process::{self, Pid};
+use crate::kernel::signal;
+use crate::kernel::uaccess::{Pod, UserPtr};
+
+/// Maximum length of an edited line, newline included
+pub const MAX_CANON: usize = 255;
+
+/// Number of control characters in `Termios`
+const NCCS: usize = 19;
+
+/// Indices of the control characters
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VEOL: usize = 11;
+
+/// `c_iflag`: ignore carriage returns
+pub const IGNCR: u32 = 0o200;
+/// `c_iflag`: turn carriage returns into newlines
+pub const ICRNL: u32 = 0o400;
+
+/// `c_oflag`: output processing, newlines output as CR-LF
+pub const OPOST: u32 = 0o1;
+pub const ONLCR: u32 = 0o4;
+
+/// `c_cflag`: 115200 baud, 8 data bits, receiver enabled, no modem control
+const CFLAG_DEFAULT: u32 = 0o10002 | 0o60 | 0o200 | 0o4000;
+
+/// `c_lflag` bits
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+pub const ECHOE: u32 = 0o20;
+pub const ECHOK: u32 = 0o40;
+pub const ECHONL: u32 = 0o100;
+pub const IEXTEN: u32 = 0o100000;
+
+/// `ioctl` requests
+pub const TCGETS: u32 = 0x5401;
+pub const TCSETS: u32 = 0x5402;
+pub const TCSETSW: u32 = 0x5403;
+pub const TCSETSF: u32 = 0x5404;
+pub const TIOCGPGRP: u32 = 0x540f;
+pub const TIOCSPGRP: u32 = 0x5410;
+pub const TIOCGWINSZ: u32 = 0x5413;
+
+/// Size reported by `TIOCGWINSZ`: a serial line has no way to tell
+const WINSIZE: Winsize = Winsize {
+    rows: 24,
+    cols: 80,
+    xpixel: 0,
+    ypixel: 0,
+};
+
+/// Terminal settings, the Linux `struct termios` of `TCGETS`
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; NCCS],
+}
+
+unsafe impl Pod for Termios {}
+
+impl Termios {
+    /// The settings of a terminal as Linux opens it: canonical mode with echo and signals
+    const fn new() -> Self {
+        let mut cc = [0; NCCS];
+        cc[VINTR] = 0x03;
+        cc[VQUIT] = 0x1c;
+        cc[VERASE] = 0x7f;
+        cc[VKILL] = 0x15;
+        cc[VEOF] = 0x04;
+        cc[VMIN] = 1;
+        Self {
+            iflag: ICRNL,
+            oflag: OPOST | ONLCR,
+            cflag: CFLAG_DEFAULT,
+            lflag: ISIG | ICANON | ECHO | ECHOE | ECHOK | IEXTEN,
+            line: 0,
+            cc,
+        }
+    }
+
+    /// Returns true if `c` is the control character at `index`, which 0 disables
+    fn is_char(&self, index: usize, c: u8) -> bool {
+        self.cc[index] != 0 && self.cc[index] == c
+    }
+}
+
+/// Terminal size, the Linux `struct winsize` of `TIOCGWINSZ`
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct Winsize {
+    rows: u16,
+    cols: u16,
+    xpixel: u16,
+    ypixel: u16,
+}
+
+unsafe impl Pod for Winsize {}
+
+/// What to write back to the console for an input byte
+enum Echo {
+    None,
+    Byte(u8),
+    /// Erase that many bytes from the screen
+    Erase(usize),
+}
+
+impl Echo {
+    fn write(self) {
+        match self {
+            Echo::None => {}
+            Echo::Byte(c) => console::putchar(c),
+            Echo::Erase(count) => {
+                for _ in 0..count {
+                    b"\x08 \x08".iter().for_each(|&c| console::putchar(c));
+                }
+            }
+        }
+    }
+}
+
+/// State of the line discipline
+struct Tty {
+    termios: Termios,
+    /// Line being edited, or completed and partly read
+    line: [u8; MAX_CANON],
+    len: usize,
+    /// Bytes of the completed line already returned by `read`
+    consumed: usize,
+    /// Set once the line is complete, even if empty (end of file)
+    complete: bool,
+}
+
+impl Tty {
+    const fn new() -> Self {
+        Self {
+            termios: Termios::new(),
+            line: [0; MAX_CANON],
+            len: 0,
+            consumed: 0,
+            complete: false,
+        }
+    }
+
+    /// Discards the line
+    fn flush(&mut self) {
+        self.len = 0;
+        self.consumed = 0;
+        self.complete = false;
+    }
+
+    /// Copies the unread bytes of the line to `buf`, returning how many were copied
+    ///
+    /// In canonical mode, only a complete line is returned, and `None` means there is none.
+    fn take(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.termios.lflag & ICANON != 0 && !self.complete {
+            return None;
+        }
+        let count = buf.len().min(self.len - self.consumed);
+        buf[..count].copy_from_slice(&self.line[self.consumed..self.consumed + count]);
+        self.consumed += count;
+        if self.consumed == self.len {
+            self.flush();
+        }
+        Some(count)
+    }
+
+    /// Applies the input flags to `c`, returning `None` if it is dropped
+    fn translate(&self, c: u8) -> Option<u8> {
+        match c {
+            b'\r' if self.termios.iflag & IGNCR != 0 => None,
+            b'\r' if self.termios.iflag & ICRNL != 0 => Some(b'\n'),
+            _ => Some(c),
+        }
+    }
+
+    /// Adds the received byte `c` to the line being edited
+    fn edit(&mut self, c: u8) -> Echo {
+        let termios = self.termios;
+        let echo = termios.lflag & ECHO != 0;
+        let Some(c) = self.translate(c) else {
+            return Echo::None;
+        };
+        if termios.is_char(VERASE, c) {
+            if self.len == 0 {
+                return Echo::None;
+            }
+            self.len -= 1;
+            return if echo && termios.lflag & ECHOE != 0 {
+                Echo::Erase(1)
+            } else {
+                Echo::None
+            };
+        }
+        if termios.is_char(VKILL, c) {
+            let erased = core::mem::take(&mut self.len);
+            return if echo && termios.lflag & ECHOK != 0 {
+                Echo::Erase(erased)
+            } else {
+                Echo::None
+            };
+        }
+        if termios.is_char(VEOF, c) {
+            self.complete = true;
+            return Echo::None;
+        }
+        if c == b'\n' || termios.is_char(VEOL, c) {
+            // The last byte is kept for the end of line
+            let end = self.len.min(MAX_CANON - 1);
+            self.line[end] = c;
+            self.len = end + 1;
+            self.complete = true;
+            return if echo || termios.lflag & ECHONL != 0 {
+                Echo::Byte(c)
+            } else {
+                Echo::None
+            };
+        }
+        if self.len >= MAX_CANON - 1 {
+            return Echo::None;
+        }
+        self.line[self.len] = c;
+        self.len += 1;
+        if echo { Echo::Byte(c) } else { Echo::None }
+    }
+}
+
+static TTY: Mutex<Tty> = Mutex::new(Tty::new());
+
+/// Handles the signal characters received by the active console
+///
+/// Called from interrupt context for every byte. Returns true if `c` sent a signal to the
+/// foreground task, in which case the byte is consumed.
+pub fn intercept(c: u8) -> bool {
+    let sig = TTY.lock_irqsafe(|tty| {
+        let termios = &tty.termios;
+        if termios.lflag & ISIG == 0 {
+            None
+        } else if termios.is_char(VINTR, c) {
+            Some(signal::SIGINT)
+        } else if termios.is_char(VQUIT, c) {
+            Some(signal::SIGQUIT)
+        } else {
+            None
+        }
+    });
+    let Some(sig) = sig else {
+        return false;
+    };
+    let sent = console::foreground().is_some_and(|id| signal::send(id, sig).is_ok());
+    if sent {
+        TTY.lock_irqsafe(Tty::flush);
+    }
+    sent
+}
+
+/// Reads from the console through the line discipline
+///
+/// `getc(wait)` returns the next byte received by the console, sleeping until there is one if
+/// `wait` is true and returning `None` otherwise.
+pub fn read(
+    buf: &mut [u8],
+    mut getc: impl FnMut(bool) -> Result<Option<u8>, FsError>,
+) -> Result<usize, FsError> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    let (canonical, vmin) =
+        TTY.lock_irqsafe(|tty| (tty.termios.lflag & ICANON != 0, tty.termios.cc[VMIN]));
+    if canonical {
+        loop {
+            if let Some(count) = TTY.lock_irqsafe(|tty| tty.take(buf)) {
+                return Ok(count);
+            }
+            if let Some(c) = getc(true)? {
+                TTY.lock_irqsafe(|tty| tty.edit(c)).write();
+            }
+        }
+    }
+
+    // Whatever was left from canonical mode comes first
+    let mut count = TTY.lock_irqsafe(|tty| tty.take(buf)).unwrap_or(0);
+    while count < buf.len() {
+        let c = match getc(count < vmin as usize) {
+            Ok(Some(c)) => c,
+            Ok(None) => break,
+            Err(e) if count == 0 => return Err(e),
+            Err(_) => break,
+        };
+        let (c, echo) = TTY.lock_irqsafe(|tty| {
+            let echo = tty.termios.lflag & ECHO != 0;
+            (tty.translate(c), echo)
+        });
+        let Some(c) = c else {
+            continue;
+        };
+        if echo {
+            console::putchar(c);
+        }
+        buf[count] = c;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Runs the terminal request `cmd` of `/dev/console`, see the module documentation
+pub fn ioctl(cmd: u32, arg: usize) -> Result<usize, FsError> {
+    match cmd {
+        TCGETS => {
+            let termios = TTY.lock_irqsafe(|tty| tty.termios);
+            UserPtr::new(arg)
+                .write(&termios)
+                .map_err(|_| FsError::BadAddress)?;
+        }
+        TCSETS | TCSETSW | TCSETSF => {
+            // Output is never queued, so there is nothing to drain for `TCSETSW`
+            let termios = UserPtr::<Termios>::new(arg)
+                .read()
+                .map_err(|_| FsError::BadAddress)?;
+            TTY.lock_irqsafe(|tty| {
+                tty.termios = termios;
+                if cmd == TCSETSF {
+                    tty.flush();
+                }
+            });
+        }
+        TIOCGPGRP => {
+            let id = console::foreground().ok_or(FsError::BadIoctl)?;
+            let pid = process::pid_of(id).ok_or(FsError::BadIoctl)?;
+            UserPtr::new(arg)
+                .write(&(pid as i32))
+                .map_err(|_| FsError::BadAddress)?;
+        }
+        TIOCSPGRP => {
+            let id = UserPtr::<i32>::new(arg)
+                .read()
+                .map_err(|_| FsError::BadAddress)?;
+            let pid = Pid::try_from(id).map_err(|_| FsError::Invalid)?;
+            let id = process::task_of(pid).ok_or(FsError::Invalid)?;
+            console::set_foreground(id);
+        }
+        TIOCGWINSZ => {
+            UserPtr::new(arg)
+                .write(&WINSIZE)
+                .map_err(|_| FsError::BadAddress)?;
+        }
+        _ => return Err(FsError::BadIoctl),
+    }
+    Ok(0)
+}
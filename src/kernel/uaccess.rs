@@ -0,0 +1,262 @@
+//! Fault-tolerant memory access
+//!
+//! Reading or writing through a pointer the kernel doesn't control (a DTB property, an address
+//! typed by a debugger, a user buffer) must not bring the whole system down if the address turns
+//! out to be unmapped. The functions below perform the access from assembly routines registered
+//! in the exception fixup table (see `kernel::extable`): a data abort makes them return an error
+//! instead of panicking.
+//!
+//! MMIO registers can be probed with `probe_read`/`probe_write`, which perform a single access of
+//! the requested width.
+//!
+//! ## User Memory
+//!
+//! System call arguments pointing to user memory are wrapped in `UserPtr<T>` and only accessed
+//! through `copy_from_user`/`copy_to_user`. Both check with `access_ok` that the range lies in
+//! areas of the calling task (see `mm::vma`) allowing the access, then copy with the fault-tolerant
+//! routines: a bad pointer makes the system call fail with `EFAULT` instead of taking the kernel
+//! down, and missing pages of valid areas are filled in by the fault handler on the way.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! Same roles as `access_ok`, `copy_{from,to}_user` and the `__user` annotation, which Linux
+//! checks with sparse rather than with a wrapper type.
+
+use core::marker::PhantomData;
+
+use crate::kernel::mm::addr_space::{self, MapFlags};
+use crate::kernel::sched;
+
+unsafe extern "C" {
+    fn __copy_nofault(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn __probe_read_u8(addr: usize, value: *mut u64) -> u64;
+    fn __probe_read_u16(addr: usize, value: *mut u64) -> u64;
+    fn __probe_read_u32(addr: usize, value: *mut u64) -> u64;
+    fn __probe_read_u64(addr: usize, value: *mut u64) -> u64;
+    fn __probe_write_u8(addr: usize, value: u64) -> u64;
+    fn __probe_write_u16(addr: usize, value: u64) -> u64;
+    fn __probe_write_u32(addr: usize, value: u64) -> u64;
+    fn __probe_write_u64(addr: usize, value: u64) -> u64;
+}
+
+/// Errors returned by fault-tolerant accesses
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UaccessError {
+    /// The access faulted
+    Fault,
+    /// The range wraps around the end of the address space
+    Overflow,
+}
+
+/// Integer types that can be accessed with `probe_read` and `probe_write`
+pub trait Probe: Copy {
+    #[doc(hidden)]
+    unsafe fn read_nofault(addr: usize, value: *mut u64) -> u64;
+    #[doc(hidden)]
+    unsafe fn write_nofault(addr: usize, value: u64) -> u64;
+    #[doc(hidden)]
+    fn from_u64(value: u64) -> Self;
+    #[doc(hidden)]
+    fn into_u64(self) -> u64;
+}
+
+macro_rules! impl_probe {
+    ($ty:ty, $read:ident, $write:ident) => {
+        impl Probe for $ty {
+            unsafe fn read_nofault(addr: usize, value: *mut u64) -> u64 {
+                unsafe { $read(addr, value) }
+            }
+
+            unsafe fn write_nofault(addr: usize, value: u64) -> u64 {
+                unsafe { $write(addr, value) }
+            }
+
+            fn from_u64(value: u64) -> Self {
+                value as $ty
+            }
+
+            fn into_u64(self) -> u64 {
+                self as u64
+            }
+        }
+    };
+}
+
+impl_probe!(u8, __probe_read_u8, __probe_write_u8);
+impl_probe!(u16, __probe_read_u16, __probe_write_u16);
+impl_probe!(u32, __probe_read_u32, __probe_write_u32);
+impl_probe!(u64, __probe_read_u64, __probe_write_u64);
+
+/// Reads a `T` at `addr` with a single access, returning an error if it faults
+///
+/// `addr` must be aligned to `T` if it points to device memory.
+pub fn probe_read<T: Probe>(addr: usize) -> Result<T, UaccessError> {
+    let mut value = 0u64;
+    match unsafe { T::read_nofault(addr, &mut value) } {
+        0 => Ok(T::from_u64(value)),
+        _ => Err(UaccessError::Fault),
+    }
+}
+
+/// Writes `value` at `addr` with a single access, returning an error if it faults
+pub fn probe_write<T: Probe>(addr: usize, value: T) -> Result<(), UaccessError> {
+    match unsafe { T::write_nofault(addr, value.into_u64()) } {
+        0 => Ok(()),
+        _ => Err(UaccessError::Fault),
+    }
+}
+
+/// Copies `dst.len()` bytes from `src` into `dst`
+///
+/// On a fault, the bytes before the faulting address have been copied and the rest of `dst` is
+/// left untouched.
+pub fn copy_from_nofault(dst: &mut [u8], src: usize) -> Result<(), UaccessError> {
+    check_range(src, dst.len())?;
+    let left = unsafe { __copy_nofault(dst.as_mut_ptr(), src as *const u8, dst.len()) };
+    if left == 0 {
+        Ok(())
+    } else {
+        Err(UaccessError::Fault)
+    }
+}
+
+/// Copies `src` to the memory at `dst`
+pub fn copy_to_nofault(dst: usize, src: &[u8]) -> Result<(), UaccessError> {
+    check_range(dst, src.len())?;
+    let left = unsafe { __copy_nofault(dst as *mut u8, src.as_ptr(), src.len()) };
+    if left == 0 {
+        Ok(())
+    } else {
+        Err(UaccessError::Fault)
+    }
+}
+
+/// Returns true if the running task may access `[addr, addr + len)`, for writing if `write`
+///
+/// The range must lie in the user address range, so a task can't have the kernel read or write
+/// kernel memory on its behalf, and be covered by the task's areas with the right permissions.
+pub fn access_ok(addr: usize, len: usize, write: bool) -> bool {
+    if !addr_space::is_user_range(addr, len) {
+        return false;
+    }
+    let flags = if write {
+        MapFlags::WRITE
+    } else {
+        MapFlags::READ
+    };
+    sched::with_current_mm(|mm| mm.vmas.covers(addr, len, flags)).unwrap_or(false)
+}
+
+/// Copies a buffer passed by user space into the kernel
+///
+/// The buffer is accessed through the running task's address space, after `access_ok`.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), UaccessError> {
+    if !access_ok(src, dst.len(), false) {
+        return Err(UaccessError::Fault);
+    }
+    copy_from_nofault(dst, src)
+}
+
+/// Copies a kernel buffer out to user space
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), UaccessError> {
+    if !access_ok(dst, src.len(), true) {
+        return Err(UaccessError::Fault);
+    }
+    copy_to_nofault(dst, src)
+}
+
+/// Types that can be copied from user memory as raw bytes
+///
+/// # Safety
+/// Every bit pattern must be a valid value, and the type must have no padding.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for i32 {}
+unsafe impl Pod for i64 {}
+unsafe impl Pod for usize {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A pointer to a `T` in the memory of the running user task
+///
+/// Holds the address as given by user space: nothing is known about it until an access checks
+/// it, which is why there is no way to dereference it directly.
+pub struct UserPtr<T> {
+    addr: usize,
+    _type: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: Pod> UserPtr<T> {
+    pub const fn new(addr: usize) -> Self {
+        Self {
+            addr,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn addr(self) -> usize {
+        self.addr
+    }
+
+    /// Returns the pointer to the `count`th `T` after this one
+    pub fn offset(self, count: usize) -> Self {
+        Self::new(self.addr.wrapping_add(count.wrapping_mul(size_of::<T>())))
+    }
+
+    /// Copies the `T` pointed to into the kernel
+    pub fn read(self) -> Result<T, UaccessError> {
+        let mut value = core::mem::MaybeUninit::<T>::zeroed();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        copy_from_user(bytes, self.addr)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Copies `value` to the `T` pointed to
+    pub fn write(self, value: &T) -> Result<(), UaccessError> {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        copy_to_user(self.addr, bytes)
+    }
+
+    /// Fills `dst` with the `dst.len()` values starting at the pointer
+    pub fn read_slice(self, dst: &mut [T]) -> Result<(), UaccessError> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(dst.as_mut_ptr() as *mut u8, size_of_val(dst))
+        };
+        copy_from_user(bytes, self.addr)
+    }
+
+    /// Copies `src` to the values starting at the pointer
+    pub fn write_slice(self, src: &[T]) -> Result<(), UaccessError> {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(src.as_ptr() as *const u8, size_of_val(src)) };
+        copy_to_user(self.addr, bytes)
+    }
+
+    /// Returns true if `count` values from the pointer can be accessed, see `access_ok`
+    pub fn access_ok(self, count: usize, write: bool) -> bool {
+        count
+            .checked_mul(size_of::<T>())
+            .is_some_and(|len| access_ok(self.addr, len, write))
+    }
+}
+
+/// Rejects ranges that wrap around the end of the address space
+fn check_range(addr: usize, len: usize) -> Result<(), UaccessError> {
+    addr.checked_add(len)
+        .map(|_| ())
+        .ok_or(UaccessError::Overflow)
+}
@@ -5,9 +5,11 @@
 #![no_std]
 #![no_main]
 
-use crate::drivers::timer::arch_timer;
-use crate::drivers::uart::pl011;
-use crate::kernel::{dtb, mm};
+use crate::drivers::watchdog;
+use crate::kernel::fs::vfs::FsError;
+use crate::kernel::init::{self, Stage};
+use crate::kernel::irq::vectors;
+use crate::kernel::{board, dtb, hardening, irq, loader, power, process, sched, shell};
 use core::panic::PanicInfo;
 
 // Public modules
@@ -19,21 +21,50 @@ pub mod utilities;
 /// Kernel main function
 ///
 /// This is the entry point for the Rust kernel code, called from assembly after hardware
-/// initialization. It receives the device tree address as a parameter.
+/// initialization. It receives the device tree address as a parameter, parses it, then runs the
+/// boot stages (see `kernel::init`), whose init calls set up the rest of the kernel.
 ///
 /// # Arguments
-/// * `dtb_addr` - The address of the Flattened Device Tree (currently unused)
+/// * `dtb_addr` - The address of the Flattened Device Tree
 #[unsafe(no_mangle)]
 pub extern "C" fn kmain(dtb_addr: usize) {
-    dtb::parse_dtb(dtb_addr);
-    mm::setup_mair_ranges();
-    mm::setup_identity_mapping();
+    vectors::init();
+    if let Err(e) = dtb::parse_dtb(dtb_addr) {
+        panic!("Bad DTB at {:#x}: {}", dtb_addr, e);
+    }
+    println!("Booting on {}", board::NAME);
+    vectors::self_check();
+    init::run(Stage::Early);
+    // Needs `random`, and must be inlined here rather than be an init call
+    hardening::init();
+    init::run(Stage::Mmu);
+    init::run(Stage::Irq);
+    init::run(Stage::Driver);
+    init::run(Stage::Late);
     println!("Hello, from Rust");
-    println!("Arming the timer (1000ms)");
-    arch_timer::arm_ms(1000);
-    loop {
-        if let Some(ch) = pl011::getchar() {
-            println!("You typed: {}", ch as char);
+    if !start_init()
+        && let Err(e) = sched::spawn("shell", shell::task, 0)
+    {
+        panic!("Cannot start the shell: {:?}", e);
+    }
+    // The boot context has nothing left to do
+    sched::exit(0);
+}
+
+/// Starts `/init` from the root filesystem (the initramfs, or a FAT disk) as the first user task
+///
+/// Returns false if there is no `/init` or it can't be started, the kernel shell taking over.
+fn start_init() -> bool {
+    match loader::exec_file("init", "/init") {
+        Ok(id) => {
+            let pid = process::pid_of(id).unwrap_or(0);
+            println!("Started /init as PID {} (task {})", pid, id);
+            true
+        }
+        Err(loader::ExecError::Fs(FsError::NotFound)) => false,
+        Err(e) => {
+            pr_err!("Cannot start /init: {:?}", e);
+            false
         }
     }
 }
@@ -49,5 +80,10 @@ fn panic(info: &PanicInfo) -> ! {
     } else {
         println!("Panic!");
     }
-    loop {}
+    // Armed first, so the reset still comes if a reboot notifier hangs
+    watchdog::panic();
+    power::panic_notify();
+    loop {
+        irq::wait_for_interrupt();
+    }
 }
@@ -0,0 +1,182 @@
+//! Assertions, bugs and warnings
+//!
+//! Between carrying on silently and panicking, a driver that detects something wrong can say so
+//! and go on:
+//!
+//! - `kbug!(fmt, ...)` reports a condition the kernel can't survive and panics;
+//! - `kassert!(cond)` and `kassert!(cond, fmt, ...)` call `kbug!` when `cond` is false;
+//! - `kwarn!(fmt, ...)` reports an unexpected condition and returns;
+//! - `kwarn_once!` only reports the first time its call site is reached, and
+//!   `kwarn_ratelimited!` at most `RATELIMIT_BURST` times every `RATELIMIT_INTERVAL_NS`.
+//!
+//! Every report names the file and line of the macro and prints a backtrace:
+//!
+//! ```text
+//! WARNING: src/drivers/virtio/blk.rs:212: used ring index 300 out of range
+//! Call trace:
+//!   [<0x0000000050012345>] aarch64_kernel::kernel::debug::backtrace::print_current+0x1c
+//!   ...
+//! ```
+//!
+//! ## Design
+//!
+//! - The macros expand to a call of `report` with `file!()` and `line!()`, so the location is
+//!   that of the macro, even inside closures.
+//! - The state of `kwarn_once!` and `kwarn_ratelimited!` is a static of each call site.
+//! - `RateLimit` only uses atomics: a warning can come from interrupt context, or from code
+//!   holding any lock.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! These are `BUG()`/`BUG_ON()`, `WARN()`, `WARN_ONCE()` and `printk_ratelimited` on top of
+//! `struct ratelimit_state`. Linux reaches its handlers through a `brk` instruction and a bug
+//! table, which keeps the call sites small, and also taints the kernel on a warning.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::kernel::debug::backtrace;
+use crate::kernel::time::clocksource;
+use crate::{pr_err, pr_warn};
+
+/// Warnings `kwarn_ratelimited!` prints per interval, at most
+pub const RATELIMIT_BURST: u32 = 10;
+
+/// Interval of `kwarn_ratelimited!`, 5 s
+pub const RATELIMIT_INTERVAL_NS: u64 = 5_000_000_000;
+
+/// Severity of a report
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// The kernel can't go on
+    Bug,
+    Warning,
+}
+
+/// Prints a report from `file:line` with a backtrace
+///
+/// Called by the macros of this module; `kbug!` then panics.
+pub fn report(kind: Kind, file: &str, line: u32, args: fmt::Arguments<'_>) {
+    match kind {
+        Kind::Bug => pr_err!("BUG: {}:{}: {}", file, line, args),
+        Kind::Warning => pr_warn!("WARNING: {}:{}: {}", file, line, args),
+    }
+    backtrace::print_current();
+}
+
+/// Lets through at most `burst` events per `interval_ns`
+pub struct RateLimit {
+    interval_ns: u64,
+    burst: u32,
+    /// Start of the current interval, 0 before the first event
+    begin_ns: AtomicU64,
+    /// Events let through in the current interval
+    passed: AtomicU32,
+    /// Events refused in the current interval
+    missed: AtomicU32,
+}
+
+impl RateLimit {
+    pub const fn new(interval_ns: u64, burst: u32) -> Self {
+        Self {
+            interval_ns,
+            burst,
+            begin_ns: AtomicU64::new(0),
+            passed: AtomicU32::new(0),
+            missed: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns true if one more event may go through now
+    ///
+    /// The first event of an interval reports how many were refused in the previous one.
+    pub fn allow(&self) -> bool {
+        let now = clocksource::now_ns().max(1);
+        let begin = self.begin_ns.load(Ordering::Relaxed);
+        if (begin == 0 || now - begin >= self.interval_ns)
+            && self
+                .begin_ns
+                .compare_exchange(begin, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.passed.store(0, Ordering::Relaxed);
+            let missed = self.missed.swap(0, Ordering::Relaxed);
+            if missed != 0 {
+                pr_warn!("{} reports suppressed", missed);
+            }
+        }
+        if self.passed.fetch_add(1, Ordering::Relaxed) < self.burst {
+            return true;
+        }
+        self.missed.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+}
+
+/// Reports a bug and panics
+#[macro_export]
+macro_rules! kbug {
+    ($($arg:tt)+) => {{
+        $crate::utilities::bug::report(
+            $crate::utilities::bug::Kind::Bug,
+            file!(),
+            line!(),
+            format_args!($($arg)+),
+        );
+        panic!("BUG");
+    }};
+}
+
+/// Calls `kbug!` if the condition is false, with the condition or the given message
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::kbug!("assertion failed: {}", stringify!($cond));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::kbug!($($arg)+);
+        }
+    };
+}
+
+/// Reports an unexpected condition and goes on
+#[macro_export]
+macro_rules! kwarn {
+    ($($arg:tt)+) => {
+        $crate::utilities::bug::report(
+            $crate::utilities::bug::Kind::Warning,
+            file!(),
+            line!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+/// `kwarn!` reporting only the first time the call site is reached
+#[macro_export]
+macro_rules! kwarn_once {
+    ($($arg:tt)+) => {{
+        static WARNED: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+        if !WARNED.swap(true, core::sync::atomic::Ordering::Relaxed) {
+            $crate::kwarn!($($arg)+);
+        }
+    }};
+}
+
+/// `kwarn!` reporting at most `RATELIMIT_BURST` times per `RATELIMIT_INTERVAL_NS` from the call
+/// site
+#[macro_export]
+macro_rules! kwarn_ratelimited {
+    ($($arg:tt)+) => {{
+        static LIMIT: $crate::utilities::bug::RateLimit = $crate::utilities::bug::RateLimit::new(
+            $crate::utilities::bug::RATELIMIT_INTERVAL_NS,
+            $crate::utilities::bug::RATELIMIT_BURST,
+        );
+        if LIMIT.allow() {
+            $crate::kwarn!($($arg)+);
+        }
+    }};
+}
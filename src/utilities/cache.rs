@@ -0,0 +1,199 @@
+//! Cache and TLB maintenance
+//!
+//! Wrappers around the `dc`, `ic` and `tlbi` instructions, each followed by the barriers that
+//! make the maintenance complete before returning. Range operations cover every line
+//! overlapping `[addr, addr + len)`, with the line sizes read from `CTR_EL0`.
+//!
+//! Data cache operations come in two flavours: to the point of coherency (`dc cvac`, `dc civac`,
+//! `dc ivac`), what devices doing DMA see, and to the point of unification (`dc cvau`), what
+//! instruction fetches see. Broadcast (`is`) variants are used throughout, so the maintenance
+//! reaches every CPU of the inner shareable domain.
+//!
+//! ## Linux Kernel Comparison
+//!
+//! This covers `arch/arm64/mm/cache.S` (`dcache_clean_poc`, `dcache_inval_poc`,
+//! `caches_clean_inval_pou`) and `asm/tlbflush.h` (`flush_tlb_all`, `flush_tlb_kernel_range`,
+//! `flush_tlb_page`).
+
+use core::arch::asm;
+
+/// Reads `CTR_EL0`
+fn ctr() -> u64 {
+    let ctr: u64;
+    unsafe {
+        asm!("mrs {}, ctr_el0", out(reg) ctr, options(nostack, nomem, preserves_flags));
+    }
+    ctr
+}
+
+/// Returns the size of the smallest data cache line, from `CTR_EL0.DminLine`
+pub fn dcache_line_size() -> usize {
+    4 << ((ctr() >> 16) & 0xf)
+}
+
+/// Returns the size of the smallest instruction cache line, from `CTR_EL0.IminLine`
+pub fn icache_line_size() -> usize {
+    4 << (ctr() & 0xf)
+}
+
+/// Runs `op` on the address of every `line`-sized line overlapping `[addr, addr + len)`
+fn for_each_line(addr: usize, len: usize, line: usize, mut op: impl FnMut(usize)) {
+    let end = addr + len;
+    let mut addr = addr & !(line - 1);
+    while addr < end {
+        op(addr);
+        addr += line;
+    }
+}
+
+/// Writes the dirty data cache lines of `[addr, addr + len)` back to RAM
+pub fn clean_dcache_range(addr: usize, len: usize) {
+    for_each_line(addr, len, dcache_line_size(), |line| unsafe {
+        asm!("dc cvac, {}", in(reg) line, options(nostack, preserves_flags));
+    });
+    unsafe { asm!("dsb sy", options(nostack, preserves_flags)) };
+}
+
+/// Writes the dirty data cache lines of `[addr, addr + len)` back to RAM and drops them from the
+/// cache
+pub fn clean_invalidate_dcache_range(addr: usize, len: usize) {
+    for_each_line(addr, len, dcache_line_size(), |line| unsafe {
+        asm!("dc civac, {}", in(reg) line, options(nostack, preserves_flags));
+    });
+    unsafe { asm!("dsb sy", options(nostack, preserves_flags)) };
+}
+
+/// Drops the data cache lines of `[addr, addr + len)` from the cache, dirty or not
+///
+/// Lines only partly in the range are cleaned first: the bytes around it aren't the caller's.
+pub fn invalidate_dcache_range(addr: usize, len: usize) {
+    let line = dcache_line_size();
+    let end = addr + len;
+    if !addr.is_multiple_of(line) {
+        clean_invalidate_dcache_range(addr, 1);
+    }
+    if !end.is_multiple_of(line) {
+        clean_invalidate_dcache_range(end - 1, 1);
+    }
+    let (first, last) = (addr.next_multiple_of(line), end & !(line - 1));
+    if first < last {
+        for_each_line(first, last - first, line, |line| unsafe {
+            asm!("dc ivac, {}", in(reg) line, options(nostack, preserves_flags));
+        });
+        unsafe { asm!("dsb sy", options(nostack, preserves_flags)) };
+    }
+}
+
+/// Cleans the data cache lines of `[addr, addr + len)` to the point of unification
+///
+/// Instruction fetches then see the data, once the instruction cache is invalidated.
+pub fn clean_dcache_range_pou(addr: usize, len: usize) {
+    for_each_line(addr, len, dcache_line_size(), |line| unsafe {
+        asm!("dc cvau, {}", in(reg) line, options(nostack, preserves_flags));
+    });
+    unsafe { asm!("dsb ish", options(nostack, preserves_flags)) };
+}
+
+/// Invalidates the instruction cache lines of `[addr, addr + len)` on every CPU
+pub fn invalidate_icache_range(addr: usize, len: usize) {
+    for_each_line(addr, len, icache_line_size(), |line| unsafe {
+        asm!("ic ivau, {}", in(reg) line, options(nostack, preserves_flags));
+    });
+    unsafe { asm!("dsb ish", "isb", options(nostack, preserves_flags)) };
+}
+
+/// Makes the instructions written to `[addr, addr + len)` visible to instruction fetches
+pub fn sync_icache_range(addr: usize, len: usize) {
+    clean_dcache_range_pou(addr, len);
+    invalidate_icache_range(addr, len);
+}
+
+/// Invalidates the whole instruction cache of this CPU
+pub fn invalidate_icache_all() {
+    unsafe {
+        asm!(
+            "ic iallu",
+            "dsb nsh",
+            "isb",
+            options(nostack, preserves_flags)
+        )
+    };
+}
+
+/// Invalidates the whole instruction cache of every CPU
+pub fn invalidate_icache_all_is() {
+    unsafe {
+        asm!(
+            "ic ialluis",
+            "dsb ish",
+            "isb",
+            options(nostack, preserves_flags)
+        )
+    };
+}
+
+/// Invalidates every EL1 TLB entry, on every CPU
+pub fn tlb_flush_all() {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vmalle1is",
+            "dsb ish",
+            "isb",
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Invalidates the TLB entries of the page at `va` for every ASID, on every CPU
+///
+/// For the kernel's global mappings.
+pub fn tlb_flush_page(va: usize) {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vaae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) (va >> 12) as u64,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Invalidates the TLB entries of the pages of `[va, va + len)` for every ASID, on every CPU
+pub fn tlb_flush_range(va: usize, len: usize) {
+    unsafe { asm!("dsb ishst", options(nostack, preserves_flags)) };
+    for_each_line(va, len, 1 << 12, |page| unsafe {
+        asm!("tlbi vaae1is, {}", in(reg) (page >> 12) as u64, options(nostack, preserves_flags));
+    });
+    unsafe { asm!("dsb ish", "isb", options(nostack, preserves_flags)) };
+}
+
+/// Invalidates the TLB entries of the page at `va` tagged with `asid`, on every CPU
+pub fn tlb_flush_page_asid(va: usize, asid: u16) {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vale1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) (va >> 12) as u64 | (asid as u64) << 48,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+/// Invalidates every TLB entry tagged with `asid`, on every CPU
+pub fn tlb_flush_asid(asid: u16) {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi aside1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) (asid as u64) << 48,
+            options(nostack, preserves_flags)
+        );
+    }
+}
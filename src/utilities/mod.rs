@@ -1,4 +1,6 @@
 //! Utilities and helper functions
 
+pub mod bug;
+pub mod cache;
 pub mod convert;
 pub mod mmio;